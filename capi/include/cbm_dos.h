@@ -0,0 +1,96 @@
+/*
+ * cbm_dos.h - the C API of cbm-dos.
+ *
+ * Link against libcbmdos (cdylib or staticlib, built from the `capi`
+ * crate). Functions and structures are only ever added; check
+ * cbm_api_version() for the functions a library has.
+ *
+ * Every function returning int returns CBM_OK, a DOS error code as the
+ * drive reports it (20 to 74, e.g. 62 FILE NOT FOUND), or one of the
+ * negative codes below. cbm_error_string() turns any of them into text.
+ */
+
+#ifndef CBM_DOS_H
+#define CBM_DOS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CBM_API_VERSION 1
+
+#define CBM_OK 0
+#define CBM_INVALID_ARGUMENT (-1)
+#define CBM_INVALID_IMAGE (-2)
+#define CBM_IO_ERROR (-3)
+#define CBM_BUFFER_TOO_SMALL (-4)
+
+#define CBM_SECTOR_SIZE 256
+#define CBM_NAME_LENGTH 16
+
+enum cbm_file_type {
+    CBM_DEL = 0,
+    CBM_SEQ = 1,
+    CBM_PRG = 2,
+    CBM_USR = 3,
+    CBM_REL = 4,
+};
+
+/* An open disk image (D64 or G64). */
+typedef struct CbmImage CbmImage;
+
+struct cbm_dir_entry {
+    uint8_t name[CBM_NAME_LENGTH]; /* padded with 0xA0 */
+    uint8_t file_type;             /* enum cbm_file_type */
+    uint8_t closed;                /* 0 for splat files */
+    uint8_t locked;
+    uint8_t track;                 /* first data block */
+    uint8_t sector;
+    uint8_t record_length;         /* REL files only */
+    uint16_t blocks;
+};
+
+uint32_t cbm_api_version(void);
+
+/* Opens an image from memory; the bytes are copied. */
+int cbm_image_open(const uint8_t *data, size_t length, CbmImage **out);
+/* Opens an image file. */
+int cbm_image_open_file(const char *path, CbmImage **out);
+/* Closes an image; NULL is ignored. */
+void cbm_image_free(CbmImage *image);
+
+/* The number of tracks, and of sectors on a track (0 if there is none). */
+int cbm_image_tracks(const CbmImage *image);
+int cbm_image_sectors(const CbmImage *image, uint8_t track);
+
+/* Reads CBM_SECTOR_SIZE bytes into out. */
+int cbm_read_sector(const CbmImage *image, uint8_t track, uint8_t sector, uint8_t *out);
+
+/* Reads the disk name (16 bytes), ID (2 bytes) and blocks free; any
+ * pointer may be NULL. */
+int cbm_disk_header(const CbmImage *image, uint8_t *name, uint8_t *id, uint16_t *blocks_free);
+
+/* Lists up to capacity entries and stores the number there are in
+ * *count. Returns CBM_BUFFER_TOO_SMALL if they do not fit, so a call with
+ * entries NULL and capacity 0 asks for the count. */
+int cbm_list_directory(const CbmImage *image, struct cbm_dir_entry *entries, size_t capacity,
+                       size_t *count);
+
+/* Reads the first file matching name (wildcards as LOAD takes them) into
+ * a new buffer; free it with cbm_buffer_free(). PRG files keep their load
+ * address. */
+int cbm_read_file(const CbmImage *image, const uint8_t *name, size_t name_length, uint8_t **data,
+                  size_t *length);
+void cbm_buffer_free(uint8_t *data, size_t length);
+
+/* A static string for any code the functions return. */
+const char *cbm_error_string(int code);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CBM_DOS_H */
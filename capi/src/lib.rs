@@ -0,0 +1,468 @@
+//! A C API to the disk images and the DOS layer of `cbm-dos`.
+//!
+//! Emulators and tools written in C or C++ link `libcbmdos` and include
+//! `include/cbm_dos.h`, which documents every function. The API is kept
+//! stable: functions and structures are only ever added, and
+//! [`cbm_api_version`] tells which additions a library has.
+//!
+//! Every function returning `int` returns [`CBM_OK`], a DOS error code as
+//! the drive reports it (20 to 74), or one of the negative codes of this
+//! API; [`cbm_error_string`] turns any of them into text.
+
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::sync::OnceLock;
+
+use cbm_dos::d64::D64;
+use cbm_dos::error::{DosError, message_for_code};
+use cbm_dos::fs::{self, NAME_LENGTH};
+use cbm_dos::g64::G64;
+use cbm_dos::image::{DiskImage, SECTOR_SIZE};
+
+/// The version of the API, raised whenever functions are added.
+pub const CBM_API_VERSION: u32 = 1;
+
+/// Success.
+pub const CBM_OK: c_int = 0;
+/// A pointer was null or an argument out of range.
+pub const CBM_INVALID_ARGUMENT: c_int = -1;
+/// The data is no image of a known format.
+pub const CBM_INVALID_IMAGE: c_int = -2;
+/// The file could not be read.
+pub const CBM_IO_ERROR: c_int = -3;
+/// The buffer passed is too small; the count needed was stored.
+pub const CBM_BUFFER_TOO_SMALL: c_int = -4;
+
+/// An open disk image.
+pub struct CbmImage {
+    image: Box<dyn DiskImage>,
+}
+
+/// A directory entry, `struct cbm_dir_entry` in C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CbmDirEntry {
+    /// The filename, padded with `0xA0`.
+    pub name: [u8; NAME_LENGTH],
+    /// The file type: 0 DEL, 1 SEQ, 2 PRG, 3 USR, 4 REL.
+    pub file_type: u8,
+    /// 1 if the file was closed properly.
+    pub closed: u8,
+    /// 1 if the file is locked.
+    pub locked: u8,
+    /// Track of the first data block.
+    pub track: u8,
+    /// Sector of the first data block.
+    pub sector: u8,
+    /// The record length of a REL file.
+    pub record_length: u8,
+    /// The size in blocks as the directory shows it.
+    pub blocks: u16,
+}
+
+fn code(error: DosError) -> c_int {
+    c_int::from(error.code())
+}
+
+/// Opens an image from its bytes: a G64 by its signature, a D64 by its
+/// size.
+fn open(bytes: &[u8]) -> Result<CbmImage, c_int> {
+    let image: Box<dyn DiskImage> = if bytes.starts_with(b"GCR-1541") {
+        Box::new(G64::from_bytes(bytes).map_err(|_| CBM_INVALID_IMAGE)?)
+    } else {
+        Box::new(D64::from_bytes(bytes).map_err(|_| CBM_INVALID_IMAGE)?)
+    };
+    Ok(CbmImage { image })
+}
+
+/// Stores `result` in `*out` as a new handle.
+///
+/// # Safety
+/// `out` must be null or valid for writes.
+unsafe fn store(result: Result<CbmImage, c_int>, out: *mut *mut CbmImage) -> c_int {
+    if out.is_null() {
+        return CBM_INVALID_ARGUMENT;
+    }
+    match result {
+        Ok(image) => {
+            // SAFETY: checked for null above, valid by contract.
+            unsafe { *out = Box::into_raw(Box::new(image)) };
+            CBM_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// Returns [`CBM_API_VERSION`].
+#[unsafe(no_mangle)]
+pub extern "C" fn cbm_api_version() -> u32 {
+    CBM_API_VERSION
+}
+
+/// Opens the image in the `length` bytes at `data`, which are copied.
+///
+/// # Safety
+/// `data` must be valid for `length` bytes and `out` for a write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_image_open(
+    data: *const u8,
+    length: usize,
+    out: *mut *mut CbmImage,
+) -> c_int {
+    if data.is_null() {
+        return CBM_INVALID_ARGUMENT;
+    }
+    // SAFETY: valid for `length` bytes by contract.
+    let bytes = unsafe { std::slice::from_raw_parts(data, length) };
+    unsafe { store(open(bytes), out) }
+}
+
+/// Opens the image file at `path`.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` valid for a write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_image_open_file(
+    path: *const c_char,
+    out: *mut *mut CbmImage,
+) -> c_int {
+    if path.is_null() {
+        return CBM_INVALID_ARGUMENT;
+    }
+    // SAFETY: NUL-terminated by contract.
+    let path = unsafe { CStr::from_ptr(path) };
+    let result = path
+        .to_str()
+        .map_err(|_| CBM_INVALID_ARGUMENT)
+        .and_then(|path| std::fs::read(path).map_err(|_| CBM_IO_ERROR))
+        .and_then(|bytes| open(&bytes));
+    unsafe { store(result, out) }
+}
+
+/// Closes an image; null is ignored.
+///
+/// # Safety
+/// `image` must be null or a handle not yet freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_image_free(image: *mut CbmImage) {
+    if !image.is_null() {
+        // SAFETY: a handle made by `store` by contract.
+        drop(unsafe { Box::from_raw(image) });
+    }
+}
+
+/// Returns the number of tracks of an image, 0 for null.
+///
+/// # Safety
+/// `image` must be null or a valid handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_image_tracks(image: *const CbmImage) -> c_int {
+    // SAFETY: null or valid by contract.
+    unsafe { image.as_ref() }.map_or(0, |image| c_int::from(image.image.tracks()))
+}
+
+/// Returns the number of sectors of `track`, 0 for null or tracks the
+/// image does not have.
+///
+/// # Safety
+/// `image` must be null or a valid handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_image_sectors(image: *const CbmImage, track: u8) -> c_int {
+    // SAFETY: null or valid by contract.
+    match unsafe { image.as_ref() } {
+        Some(image) if (1..=image.image.tracks()).contains(&track) => {
+            c_int::from(image.image.sectors_per_track(track))
+        }
+        _ => 0,
+    }
+}
+
+/// Reads the 256 bytes of a sector into `out`.
+///
+/// # Safety
+/// `image` must be a valid handle and `out` valid for 256 bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_read_sector(
+    image: *const CbmImage,
+    track: u8,
+    sector: u8,
+    out: *mut u8,
+) -> c_int {
+    // SAFETY: null or valid by contract.
+    let Some(image) = (unsafe { image.as_ref() }) else {
+        return CBM_INVALID_ARGUMENT;
+    };
+    if out.is_null() {
+        return CBM_INVALID_ARGUMENT;
+    }
+    if !image.image.contains(track, sector) {
+        return code(DosError::IllegalTrackSector);
+    }
+    match image.image.read_sector(track, sector) {
+        Ok(data) => {
+            // SAFETY: valid for a sector by contract.
+            unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), out, SECTOR_SIZE) };
+            CBM_OK
+        }
+        Err(error) => code(error),
+    }
+}
+
+/// Reads the disk name (16 bytes, padded with `0xA0`), the ID (2 bytes)
+/// and the blocks free; any of the pointers may be null.
+///
+/// # Safety
+/// `image` must be a valid handle and every pointer not null valid for
+/// the bytes it receives.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_disk_header(
+    image: *const CbmImage,
+    name: *mut u8,
+    id: *mut u8,
+    blocks_free: *mut u16,
+) -> c_int {
+    // SAFETY: null or valid by contract.
+    let Some(image) = (unsafe { image.as_ref() }) else {
+        return CBM_INVALID_ARGUMENT;
+    };
+    let directory = match fs::read_directory(&image.image) {
+        Ok(directory) => directory,
+        Err(error) => return code(error),
+    };
+    // SAFETY: each pointer is null or valid by contract.
+    unsafe {
+        if !name.is_null() {
+            std::ptr::copy_nonoverlapping(directory.name.as_ptr(), name, NAME_LENGTH);
+        }
+        if !id.is_null() {
+            std::ptr::copy_nonoverlapping(directory.id.as_ptr(), id, 2);
+        }
+        if !blocks_free.is_null() {
+            *blocks_free = directory.blocks_free;
+        }
+    }
+    CBM_OK
+}
+
+/// Lists the directory into `entries`, which has room for `capacity`
+/// entries, and stores the number of entries in `*count`.
+///
+/// Pass a null `entries` and a `capacity` of 0 to learn the count: the
+/// call then returns [`CBM_BUFFER_TOO_SMALL`] for a directory with files.
+///
+/// # Safety
+/// `image` must be a valid handle, `entries` valid for `capacity` entries
+/// and `count` for a write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_list_directory(
+    image: *const CbmImage,
+    entries: *mut CbmDirEntry,
+    capacity: usize,
+    count: *mut usize,
+) -> c_int {
+    // SAFETY: null or valid by contract.
+    let Some(image) = (unsafe { image.as_ref() }) else {
+        return CBM_INVALID_ARGUMENT;
+    };
+    if count.is_null() || (entries.is_null() && capacity > 0) {
+        return CBM_INVALID_ARGUMENT;
+    }
+    let directory = match fs::read_directory(&image.image) {
+        Ok(directory) => directory,
+        Err(error) => return code(error),
+    };
+    // SAFETY: valid for a write by contract.
+    unsafe { *count = directory.entries.len() };
+    if directory.entries.len() > capacity {
+        return CBM_BUFFER_TOO_SMALL;
+    }
+    for (i, entry) in directory.entries.iter().enumerate() {
+        let out = CbmDirEntry {
+            name: entry.name,
+            file_type: entry.file_type.to_byte(),
+            closed: u8::from(entry.closed),
+            locked: u8::from(entry.locked),
+            track: entry.track,
+            sector: entry.sector,
+            record_length: entry.record_length,
+            blocks: entry.blocks,
+        };
+        // SAFETY: `i` is below `capacity`, for which `entries` is valid.
+        unsafe { entries.add(i).write(out) };
+    }
+    CBM_OK
+}
+
+/// Reads the first file matching `name`, which may hold wildcards as
+/// `LOAD` takes them, into a new buffer stored in `*data` and `*length`,
+/// to be freed with [`cbm_buffer_free`]. PRG files keep their load
+/// address.
+///
+/// # Safety
+/// `image` must be a valid handle, `name` valid for `name_length` bytes
+/// and `data` and `length` for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_read_file(
+    image: *const CbmImage,
+    name: *const u8,
+    name_length: usize,
+    data: *mut *mut u8,
+    length: *mut usize,
+) -> c_int {
+    // SAFETY: null or valid by contract.
+    let Some(image) = (unsafe { image.as_ref() }) else {
+        return CBM_INVALID_ARGUMENT;
+    };
+    if name.is_null() || data.is_null() || length.is_null() {
+        return CBM_INVALID_ARGUMENT;
+    }
+    // SAFETY: valid for `name_length` bytes by contract.
+    let name = unsafe { std::slice::from_raw_parts(name, name_length) };
+    let contents = fs::find_file(&image.image, name).and_then(|entry| {
+        let entry = entry.ok_or(DosError::FileNotFound)?;
+        fs::read_file(&image.image, &entry)
+    });
+    match contents {
+        Ok(contents) => {
+            let contents = Box::into_raw(contents.into_boxed_slice());
+            // SAFETY: valid for writes by contract.
+            unsafe {
+                *length = contents.len();
+                *data = contents.cast();
+            }
+            CBM_OK
+        }
+        Err(error) => code(error),
+    }
+}
+
+/// Frees a buffer [`cbm_read_file`] returned; null is ignored.
+///
+/// # Safety
+/// `data` and `length` must be what `cbm_read_file` stored, not yet freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cbm_buffer_free(data: *mut u8, length: usize) {
+    if !data.is_null() {
+        // SAFETY: a boxed slice of `length` bytes by contract.
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, length)) });
+    }
+}
+
+/// Returns the text of a code any function returned: the drive's message
+/// for DOS error codes. The string is static.
+#[unsafe(no_mangle)]
+pub extern "C" fn cbm_error_string(code: c_int) -> *const c_char {
+    const API: [&CStr; 5] = [
+        c"OK",
+        c"INVALID ARGUMENT",
+        c"INVALID IMAGE",
+        c"I/O ERROR",
+        c"BUFFER TOO SMALL",
+    ];
+    static DOS: OnceLock<Vec<CString>> = OnceLock::new();
+    match u8::try_from(code) {
+        Ok(0) => API[0].as_ptr(),
+        Ok(code) if code < 100 => {
+            let messages = DOS.get_or_init(|| {
+                (0..100)
+                    .map(|code| CString::new(message_for_code(code)).unwrap_or_default())
+                    .collect()
+            });
+            messages[usize::from(code)].as_ptr()
+        }
+        _ => match usize::try_from(-code) {
+            Ok(index) if index < API.len() => API[index].as_ptr(),
+            _ => c"UNKNOWN ERROR".as_ptr(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbm_dos::fs::FileType;
+    use std::ptr;
+
+    fn disk() -> Vec<u8> {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"C API", Some(*b"CA")).unwrap();
+        fs::write_file(&mut image, b"HELLO", FileType::Prg, &[0x01, 0x08, 0x60]).unwrap();
+        fs::write_file(&mut image, b"DATA", FileType::Seq, b"TEXT").unwrap();
+        image.to_bytes()
+    }
+
+    #[test]
+    fn opens_images_and_reads_files() {
+        let bytes = disk();
+        let mut image = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                cbm_image_open(bytes.as_ptr(), bytes.len(), &mut image),
+                CBM_OK
+            );
+            assert_eq!(cbm_image_tracks(image), 35);
+            assert_eq!(cbm_image_sectors(image, 18), 19);
+            assert_eq!(cbm_image_sectors(image, 36), 0);
+
+            let mut sector = [0u8; SECTOR_SIZE];
+            assert_eq!(cbm_read_sector(image, 18, 0, sector.as_mut_ptr()), CBM_OK);
+            assert_eq!(sector[0..2], [18, 1]);
+            assert_eq!(cbm_read_sector(image, 18, 19, sector.as_mut_ptr()), 66);
+
+            let (mut name, mut id, mut free) = ([0u8; 16], [0u8; 2], 0u16);
+            assert_eq!(
+                cbm_disk_header(image, name.as_mut_ptr(), id.as_mut_ptr(), &mut free),
+                CBM_OK
+            );
+            assert_eq!((&name[..5], id, free), (&b"C API"[..], *b"CA", 662));
+
+            let mut count = 0;
+            let entries = ptr::null_mut();
+            let status = cbm_list_directory(image, entries, 0, &mut count);
+            assert_eq!((status, count), (CBM_BUFFER_TOO_SMALL, 2));
+            let mut entries = [CbmDirEntry::default(); 2];
+            assert_eq!(
+                cbm_list_directory(image, entries.as_mut_ptr(), 2, &mut count),
+                CBM_OK
+            );
+            assert_eq!((entries[1].file_type, entries[1].blocks), (1, 1));
+            assert_eq!(&entries[0].name[..5], b"HELLO");
+
+            let (mut data, mut length) = (ptr::null_mut(), 0);
+            assert_eq!(
+                cbm_read_file(image, b"H*".as_ptr(), 2, &mut data, &mut length),
+                CBM_OK
+            );
+            assert_eq!(std::slice::from_raw_parts(data, length), [0x01, 0x08, 0x60]);
+            cbm_buffer_free(data, length);
+            let status = cbm_read_file(image, b"NONE".as_ptr(), 4, &mut data, &mut length);
+            assert_eq!(status, 62);
+            cbm_image_free(image);
+        }
+    }
+
+    #[test]
+    fn reports_errors_as_text() {
+        let mut image = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                cbm_image_open([0u8; 10].as_ptr(), 10, &mut image),
+                CBM_INVALID_IMAGE
+            );
+            assert_eq!(
+                cbm_image_open(ptr::null(), 0, &mut image),
+                CBM_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                cbm_image_open_file(c"/nonexistent.d64".as_ptr(), &mut image),
+                CBM_IO_ERROR
+            );
+            assert!(image.is_null());
+            let text = |code| CStr::from_ptr(cbm_error_string(code)).to_str().unwrap();
+            assert_eq!(text(62), "FILE NOT FOUND");
+            assert_eq!(text(CBM_OK), "OK");
+            assert_eq!(text(CBM_BUFFER_TOO_SMALL), "BUFFER TOO SMALL");
+            assert_eq!(text(-99), "UNKNOWN ERROR");
+        }
+        assert_eq!(cbm_api_version(), CBM_API_VERSION);
+    }
+}
@@ -0,0 +1,261 @@
+//! Asynchronous access to D64 files.
+//!
+//! A network service serving disk contents cannot block its executor on
+//! every sector it reads. [`AsyncD64`] reads and writes the sectors of a
+//! D64 file one at a time through an [`AsyncStorage`], and
+//! [`AsyncD64::load`] reads the whole image to hand it to the synchronous
+//! layers of the crate, such as a directory listing through [`crate::fs`].
+//!
+//! The crate does not depend on an async runtime. [`AsyncStorage`] is
+//! implemented in a few lines for the files and streams of any runtime:
+//! with Tokio, `read_at` seeks with `AsyncSeekExt::seek` and then calls
+//! `AsyncReadExt::read_exact`.
+
+use crate::d64::{self, D64, SECTORS_35, SECTORS_40};
+use crate::error::DosError;
+use crate::image::{ImageError, SECTOR_SIZE, Sector};
+use std::io::{self, Cursor};
+
+/// Storage read and written at byte offsets, asynchronously.
+pub trait AsyncStorage {
+    /// Returns the size of the storage in bytes.
+    fn size(&mut self) -> impl Future<Output = io::Result<u64>>;
+
+    /// Fills `buf` with the bytes at `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> impl Future<Output = io::Result<()>>;
+
+    /// Writes `data` at `offset`.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> impl Future<Output = io::Result<()>>;
+}
+
+/// Storage in memory, which is always ready.
+impl AsyncStorage for Cursor<Vec<u8>> {
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = usize::try_from(offset).map_err(io::Error::other)?;
+        let source = self
+            .get_ref()
+            .get(start..start + buf.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(source);
+        Ok(())
+    }
+
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let start = usize::try_from(offset).map_err(io::Error::other)?;
+        let bytes = self.get_mut();
+        if bytes.len() < start + data.len() {
+            bytes.resize(start + data.len(), 0);
+        }
+        bytes[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A D64 file read and written sector by sector without blocking.
+#[derive(Debug)]
+pub struct AsyncD64<S> {
+    storage: S,
+    tracks: u8,
+    errors: Option<Vec<u8>>,
+}
+
+fn invalid(error: ImageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl<S: AsyncStorage> AsyncD64<S> {
+    /// Opens the D64 held by `storage`, telling its layout from its size as
+    /// [`D64::from_bytes`] does, and reads its error block if it has one.
+    ///
+    /// # Errors
+    /// Returns the error of the storage, or [`io::ErrorKind::InvalidData`]
+    /// if its size is not one of a D64.
+    pub async fn open(mut storage: S) -> io::Result<Self> {
+        let size = storage.size().await?;
+        let size = usize::try_from(size).unwrap_or(usize::MAX);
+        let (tracks, sectors) = [(35, SECTORS_35), (40, SECTORS_40)]
+            .into_iter()
+            .find(|&(_, n)| size == n * SECTOR_SIZE || size == n * (SECTOR_SIZE + 1))
+            .ok_or_else(|| invalid(ImageError::InvalidSize(size)))?;
+        let errors = if size > sectors * SECTOR_SIZE {
+            let mut errors = vec![0; sectors];
+            let offset = (sectors * SECTOR_SIZE) as u64;
+            storage.read_at(offset, &mut errors).await?;
+            Some(errors)
+        } else {
+            None
+        };
+        Ok(AsyncD64 {
+            storage,
+            tracks,
+            errors,
+        })
+    }
+
+    /// Returns the number of tracks, 35 or 40.
+    pub fn tracks(&self) -> u8 {
+        self.tracks
+    }
+
+    /// Returns the number of sectors on `track`, `0` if the disk does not
+    /// have it.
+    pub fn sectors_per_track(&self, track: u8) -> u8 {
+        if (1..=self.tracks).contains(&track) {
+            d64::sectors_per_track(track)
+        } else {
+            0
+        }
+    }
+
+    fn offset(&self, track: u8, sector: u8) -> Result<u64, DosError> {
+        if sector >= self.sectors_per_track(track) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        Ok(d64::sector_offset(track, sector) as u64)
+    }
+
+    /// Reads the sector at `track`/`sector`.
+    ///
+    /// # Errors
+    /// Returns the error of the storage, with [`io::ErrorKind::InvalidInput`]
+    /// holding [`DosError::IllegalTrackSector`] if the sector does not
+    /// exist.
+    pub async fn read_sector(&mut self, track: u8, sector: u8) -> io::Result<Sector> {
+        let offset = self
+            .offset(track, sector)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let mut data = [0; SECTOR_SIZE];
+        self.storage.read_at(offset, &mut data).await?;
+        Ok(data)
+    }
+
+    /// Writes `data` to the sector at `track`/`sector`.
+    ///
+    /// # Errors
+    /// Fails as [`AsyncD64::read_sector`] does.
+    pub async fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> io::Result<()> {
+        let offset = self
+            .offset(track, sector)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        self.storage.write_at(offset, data).await
+    }
+
+    /// Returns the read error the error block records for a sector, if any.
+    pub fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        let offset = self.offset(track, sector).ok()?;
+        let byte = self.errors.as_ref()?[offset as usize / SECTOR_SIZE];
+        d64::error_from_byte(byte)
+    }
+
+    /// Reads the whole image.
+    ///
+    /// # Errors
+    /// Returns the error of the storage.
+    pub async fn load(&mut self) -> io::Result<D64> {
+        let sectors = if self.tracks == 35 {
+            SECTORS_35
+        } else {
+            SECTORS_40
+        };
+        let mut bytes = vec![0; sectors * SECTOR_SIZE];
+        self.storage.read_at(0, &mut bytes).await?;
+        if let Some(errors) = &self.errors {
+            bytes.extend_from_slice(errors);
+        }
+        D64::from_bytes(&bytes).map_err(invalid)
+    }
+
+    /// Returns the storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, FileType};
+    use crate::image::BlockDevice;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    /// Storage that is not ready on the first poll of every read.
+    struct Slow(Cursor<Vec<u8>>);
+
+    struct Later(bool);
+
+    impl Future for Later {
+        type Output = ();
+
+        fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncStorage for Slow {
+        async fn size(&mut self) -> io::Result<u64> {
+            self.0.size().await
+        }
+
+        async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            Later(false).await;
+            self.0.read_at(offset, buf).await
+        }
+
+        async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.0.write_at(offset, data).await
+        }
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                return value;
+            }
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_sectors() {
+        let mut disk = D64::new(35);
+        fs::format(&mut disk, b"SERVED", Some(*b"01")).unwrap();
+        fs::write_file(&mut disk, b"PAGE", FileType::Seq, b"HELLO").unwrap();
+        disk.set_sector_error(20, 2, Some(DosError::DataChecksum))
+            .unwrap();
+        let storage = Slow(Cursor::new(disk.to_bytes()));
+        let mut image = block_on(AsyncD64::open(storage)).unwrap();
+        assert_eq!(image.tracks(), 35);
+        assert_eq!(image.sector_error(20, 2), Some(DosError::DataChecksum));
+        assert_eq!(
+            block_on(image.read_sector(18, 0)).unwrap(),
+            disk.read_sector(18, 0).unwrap()
+        );
+        block_on(image.write_sector(1, 0, &[7; 256])).unwrap();
+        let error = block_on(image.read_sector(18, 19)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let loaded = block_on(image.load()).unwrap();
+        assert_eq!(loaded.read_sector(1, 0), Ok([7; 256]));
+        assert!(fs::find_file(&loaded, b"PAGE").unwrap().is_some());
+        assert_eq!(loaded.sector_error(20, 2), Some(DosError::DataChecksum));
+    }
+
+    #[test]
+    fn rejects_other_sizes() {
+        let storage = Cursor::new(vec![0; 1000]);
+        let error = block_on(AsyncD64::open(storage)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
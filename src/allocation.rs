@@ -0,0 +1,248 @@
+//! How the drives pick the blocks of a new file.
+//!
+//! Every drive starts a file next to the directory track, to keep the head
+//! close to the directory, and follows the chain with an interleave that
+//! suits how fast it can take the next block, but the drives differ in the
+//! details:
+//!
+//! - The 1541 searches outward from track 18, the track below before the
+//!   one above, and steps 10 sectors ahead on a track.
+//! - The 1571 does the same with an interleave of 6, and before it moves
+//!   the head it fills the track under the other head of the same
+//!   cylinder: track 17 is followed by track 52. Track 53 holds the BAM
+//!   of the second side and is kept in use in the BAM.
+//! - The 1581 searches outward from track 40 and takes the sectors of a
+//!   track one after the other, filling a track before the next, as its
+//!   track buffer reads a whole track at once.
+//!
+//! A [`Strategy`] finds the blocks in any [`FreeMap`], so images written for
+//! each drive, through [`crate::fs::write_file_with`], have the layout the
+//! drive itself would have given them.
+
+use crate::model::DriveModel;
+use alloc::vec::Vec;
+
+/// The tracks of each side of a 1571 disk.
+const SIDE_TRACKS: u8 = 35;
+
+/// Which blocks of a disk are free.
+pub trait FreeMap {
+    /// Returns the number of tracks the map covers.
+    fn tracks(&self) -> u8;
+
+    /// Returns the number of sectors on `track`.
+    fn sectors_per_track(&self, track: u8) -> u8;
+
+    /// Returns `true` if the block is free.
+    fn is_free(&self, track: u8, sector: u8) -> bool;
+
+    /// Returns the number of free blocks on `track`.
+    fn free_on_track(&self, track: u8) -> u8 {
+        (0..self.sectors_per_track(track))
+            .filter(|&s| self.is_free(track, s))
+            .count() as u8
+    }
+}
+
+/// The allocation algorithm of a drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Strategy {
+    /// The 1541, and every drive without a strategy of its own.
+    #[default]
+    C1541,
+    /// The 1571 with a double-sided disk.
+    C1571,
+    /// The 1581.
+    C1581,
+}
+
+impl Strategy {
+    /// Returns the strategy of `model`: the 1571 and 1581 have their own,
+    /// the CMD FD allocates like the 1581 and the others like the 1541.
+    pub fn for_model(model: DriveModel) -> Self {
+        match model {
+            DriveModel::C1571 => Strategy::C1571,
+            DriveModel::C1581 | DriveModel::CmdFd => Strategy::C1581,
+            _ => Strategy::C1541,
+        }
+    }
+
+    /// Returns the track the search starts next to.
+    pub fn directory_track(self) -> u8 {
+        match self {
+            Strategy::C1541 | Strategy::C1571 => 18,
+            Strategy::C1581 => 40,
+        }
+    }
+
+    /// Returns the number of sectors a chain steps ahead on a track.
+    pub fn interleave(self) -> u8 {
+        match self {
+            Strategy::C1541 => 10,
+            Strategy::C1571 => 6,
+            Strategy::C1581 => 1,
+        }
+    }
+
+    /// Finds the first free block of a new file.
+    pub fn first_free(self, map: &impl FreeMap) -> Option<(u8, u8)> {
+        let directory = self.directory_track();
+        (1..=self.cylinders(map))
+            .flat_map(|distance| {
+                [
+                    directory.checked_sub(distance),
+                    directory.checked_add(distance),
+                ]
+            })
+            .flatten()
+            .flat_map(|cylinder| self.tracks_of(cylinder))
+            .find_map(|track| free_track(map, track, 0))
+    }
+
+    /// Finds the block following `track`/`sector` in a file chain.
+    ///
+    /// The sector advances by the interleave on the same track. Once the
+    /// track is full the search moves away from the directory track,
+    /// reversing direction when the edge of the disk is reached, and on a
+    /// 1571 first to the other side of the cylinder.
+    pub fn next_free(self, map: &impl FreeMap, track: u8, sector: u8) -> Option<(u8, u8)> {
+        let max = map.sectors_per_track(track);
+        let mut start = sector + self.interleave();
+        if start >= max {
+            start -= max;
+            // The DOS of the 1541 and 1571 steps back a sector when it
+            // wraps around the track.
+            if self != Strategy::C1581 {
+                start = start.saturating_sub(1);
+            }
+        }
+        if let Some(found) = free_track(map, track, start) {
+            return Some(found);
+        }
+        let directory = self.directory_track();
+        let cylinders = self.cylinders(map);
+        let (cylinder, other_side) = match self {
+            Strategy::C1571 if track > SIDE_TRACKS => (track - SIDE_TRACKS, None),
+            Strategy::C1571 => (track, Some(track + SIDE_TRACKS)),
+            _ => (track, None),
+        };
+        let outward: Vec<u8> = if cylinder < directory {
+            (1..cylinder)
+                .rev()
+                .chain(directory + 1..=cylinders)
+                .collect()
+        } else {
+            (cylinder + 1..=cylinders)
+                .chain((1..directory).rev())
+                .collect()
+        };
+        other_side
+            .into_iter()
+            .chain(outward.into_iter().flat_map(|c| self.tracks_of(c)))
+            .find_map(|t| free_track(map, t, start % map.sectors_per_track(t).max(1)))
+    }
+
+    /// Returns the number of head positions of the disk `map` covers.
+    fn cylinders(self, map: &impl FreeMap) -> u8 {
+        match self {
+            Strategy::C1571 => map.tracks().min(SIDE_TRACKS),
+            _ => map.tracks(),
+        }
+    }
+
+    /// Returns the tracks under the heads at `cylinder`, in the order they
+    /// are filled.
+    fn tracks_of(self, cylinder: u8) -> impl Iterator<Item = u8> {
+        let second = (self == Strategy::C1571).then_some(cylinder + SIDE_TRACKS);
+        core::iter::once(cylinder).chain(second)
+    }
+}
+
+/// Returns the first free block on `track` from `start` on, wrapping
+/// around, if the track is on the disk and holds one.
+fn free_track(map: &impl FreeMap, track: u8, start: u8) -> Option<(u8, u8)> {
+    if track == 0 || track > map.tracks() || map.free_on_track(track) == 0 {
+        return None;
+    }
+    let max = map.sectors_per_track(track);
+    (0..max)
+        .map(|i| (start + i) % max)
+        .find(|&s| map.is_free(track, s))
+        .map(|s| (track, s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A blank disk of `model` with the directory track in use.
+    struct Blank {
+        model: DriveModel,
+        used: Vec<(u8, u8)>,
+    }
+
+    impl FreeMap for Blank {
+        fn tracks(&self) -> u8 {
+            self.model.tracks()
+        }
+
+        fn sectors_per_track(&self, track: u8) -> u8 {
+            self.model.sectors_per_track(track) as u8
+        }
+
+        fn is_free(&self, track: u8, sector: u8) -> bool {
+            track != self.model.directory_track()
+                && (self.model != DriveModel::C1571 || track != 53)
+                && sector < self.sectors_per_track(track)
+                && !self.used.contains(&(track, sector))
+        }
+    }
+
+    fn chain(model: DriveModel, blocks: usize) -> Vec<(u8, u8)> {
+        let strategy = Strategy::for_model(model);
+        let mut map = Blank {
+            model,
+            used: Vec::new(),
+        };
+        let mut current = strategy.first_free(&map).unwrap();
+        for _ in 0..blocks {
+            map.used.push(current);
+            if let Some(next) = strategy.next_free(&map, current.0, current.1) {
+                current = next;
+            }
+        }
+        map.used
+    }
+
+    #[test]
+    fn follows_each_drive() {
+        assert_eq!(
+            &chain(DriveModel::C1541, 4)[..],
+            [(17, 0), (17, 10), (17, 20), (17, 8)]
+        );
+        let c1571 = chain(DriveModel::C1571, 44);
+        assert_eq!(&c1571[..4], [(17, 0), (17, 6), (17, 12), (17, 18)]);
+        assert!(c1571[..21].iter().all(|&(t, _)| t == 17));
+        assert_eq!(c1571[21].0, 52);
+        assert_eq!(c1571[42].0, 16);
+        let c1581 = chain(DriveModel::C1581, 41);
+        assert_eq!(&c1581[..3], [(39, 0), (39, 1), (39, 2)]);
+        assert_eq!(c1581[40], (38, 0));
+    }
+
+    #[test]
+    fn fills_the_disk() {
+        for model in [DriveModel::C1541, DriveModel::C1571, DriveModel::C1581] {
+            let directory = model.directory_track();
+            let free = model.total_sectors()
+                - usize::from(model.sectors_per_track(directory))
+                - if model == DriveModel::C1571 { 19 } else { 0 };
+            let mut blocks = chain(model, free);
+            assert_eq!(blocks.len(), free, "{model:?}");
+            blocks.sort_unstable();
+            blocks.dedup();
+            assert_eq!(blocks.len(), free, "{model:?}");
+            assert!(blocks.iter().all(|&(t, _)| t != directory));
+        }
+    }
+}
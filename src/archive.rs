@@ -0,0 +1,271 @@
+//! Disk and tape images inside archives.
+//!
+//! Image collections come as ZIP archives and, with the `sevenz` feature,
+//! as 7z archives, often a whole set of disks in one. [`Archive::open`]
+//! reads the directory of either, and [`Archive::images`] lists the
+//! members that are images by their extension (see [`EXTENSIONS`]) as
+//! [`ArchivedImage`] handles. A handle unpacks its member into memory when
+//! asked and opens disks through a [`Registry`], so nothing is extracted
+//! to disk:
+//!
+//! ```no_run
+//! use cbm_dos::archive::Archive;
+//! use cbm_dos::registry::Registry;
+//!
+//! let bytes = std::fs::read("collection.zip").unwrap();
+//! let archive = Archive::open(&bytes).unwrap();
+//! let registry = Registry::standard();
+//! for image in archive.images().filter(|image| !image.is_tape()) {
+//!     let disk = image.open(&registry).unwrap();
+//!     println!("{}: {} tracks", image.name, disk.tracks());
+//! }
+//! ```
+//!
+//! Archives within the archive are not opened.
+
+use crate::image::{DiskImage, ImageError};
+use crate::registry::Registry;
+#[cfg(feature = "sevenz")]
+use crate::sevenz::{self, SevenZipError};
+use crate::zip::{self, ZipError};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The extensions of the members taken for images.
+pub const EXTENSIONS: [&str; 4] = ["d64", "g64", "t64", "tap"];
+
+/// The extensions of tape images among them.
+const TAPES: [&str; 2] = ["t64", "tap"];
+
+/// An error reading an archive or an image in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveError {
+    Zip(ZipError),
+    #[cfg(feature = "sevenz")]
+    SevenZip(SevenZipError),
+    /// The member is unpacked but no image.
+    Image(ImageError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Zip(error) => error.fmt(f),
+            #[cfg(feature = "sevenz")]
+            ArchiveError::SevenZip(error) => error.fmt(f),
+            ArchiveError::Image(error) => error.fmt(f),
+        }
+    }
+}
+
+impl core::error::Error for ArchiveError {}
+
+impl From<ZipError> for ArchiveError {
+    fn from(error: ZipError) -> Self {
+        ArchiveError::Zip(error)
+    }
+}
+
+#[cfg(feature = "sevenz")]
+impl From<SevenZipError> for ArchiveError {
+    fn from(error: SevenZipError) -> Self {
+        ArchiveError::SevenZip(error)
+    }
+}
+
+impl From<ImageError> for ArchiveError {
+    fn from(error: ImageError) -> Self {
+        ArchiveError::Image(error)
+    }
+}
+
+#[derive(Debug)]
+enum Members<'a> {
+    Zip(Vec<zip::Entry<'a>>),
+    #[cfg(feature = "sevenz")]
+    SevenZip(sevenz::Archive<'a>),
+}
+
+/// An opened archive.
+#[derive(Debug)]
+pub struct Archive<'a> {
+    members: Members<'a>,
+}
+
+/// An image in an archive, not yet unpacked.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchivedImage<'r, 'a> {
+    /// The path within the archive.
+    pub name: &'r str,
+    /// The size of the unpacked image.
+    pub size: usize,
+    archive: &'r Archive<'a>,
+    index: usize,
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    name.rsplit_once('.').is_some_and(|(_, actual)| {
+        extensions
+            .iter()
+            .any(|extension| actual.eq_ignore_ascii_case(extension))
+    })
+}
+
+impl<'a> Archive<'a> {
+    /// Reads the directory of the archive `bytes`, a 7z archive if it
+    /// starts with the signature of one and a ZIP archive otherwise.
+    ///
+    /// # Errors
+    /// Returns the error of the archive format if the directory cannot be
+    /// read, and [`ZipError::NotAnArchive`] for 7z archives without the
+    /// `sevenz` feature.
+    pub fn open(bytes: &'a [u8]) -> Result<Self, ArchiveError> {
+        #[cfg(feature = "sevenz")]
+        if bytes.starts_with(sevenz::SIGNATURE) {
+            return Ok(Archive {
+                members: Members::SevenZip(sevenz::Archive::open(bytes)?),
+            });
+        }
+        Ok(Archive {
+            members: Members::Zip(zip::entries(bytes)?),
+        })
+    }
+
+    /// The images in the archive, in the order of its directory.
+    pub fn images(&self) -> impl Iterator<Item = ArchivedImage<'_, 'a>> {
+        let members: Vec<(&str, usize, bool)> = match &self.members {
+            Members::Zip(entries) => entries
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.size, entry.is_dir()))
+                .collect(),
+            #[cfg(feature = "sevenz")]
+            Members::SevenZip(archive) => archive
+                .entries()
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.size, entry.is_dir()))
+                .collect(),
+        };
+        members
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (name, _, dir))| !dir && has_extension(name, &EXTENSIONS))
+            .map(move |(index, (name, size, _))| ArchivedImage {
+                name,
+                size,
+                archive: self,
+                index,
+            })
+    }
+}
+
+impl ArchivedImage<'_, '_> {
+    /// Whether the image is one of a tape rather than a disk.
+    pub fn is_tape(&self) -> bool {
+        has_extension(self.name, &TAPES)
+    }
+
+    /// Unpacks the image.
+    ///
+    /// # Errors
+    /// Returns the error of the archive format if the member cannot be
+    /// unpacked or fails its checksum.
+    pub fn data(&self) -> Result<Vec<u8>, ArchiveError> {
+        match &self.archive.members {
+            Members::Zip(entries) => Ok(entries[self.index].data()?),
+            #[cfg(feature = "sevenz")]
+            Members::SevenZip(archive) => Ok(archive.data(&archive.entries()[self.index])?),
+        }
+    }
+
+    /// Unpacks the image and opens it as a disk with the format `registry`
+    /// detects.
+    ///
+    /// # Errors
+    /// Returns the error of [`data`](Self::data), or
+    /// [`ArchiveError::Image`] if the image cannot be opened, as tapes
+    /// cannot.
+    pub fn open(&self, registry: &Registry) -> Result<Box<dyn DiskImage>, ArchiveError> {
+        Ok(registry.open(&self.data()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::hash::crc32;
+
+    /// Builds a ZIP archive of stored members.
+    fn zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let (mut out, mut central) = (Vec::new(), Vec::new());
+        for &(name, data) in members {
+            let mut header = vec![20, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            header.extend(crc32(data).to_le_bytes());
+            header.extend((data.len() as u32).to_le_bytes());
+            header.extend((data.len() as u32).to_le_bytes());
+            header.extend((name.len() as u16).to_le_bytes());
+            header.extend([0, 0]);
+            central.extend(b"PK\x01\x02\x14\x00");
+            central.extend(&header);
+            central.extend([0; 10]);
+            central.extend((out.len() as u32).to_le_bytes());
+            central.extend(name.as_bytes());
+            out.extend(b"PK\x03\x04");
+            out.extend(&header);
+            out.extend(name.as_bytes());
+            out.extend(data);
+        }
+        let offset = out.len() as u32;
+        let count = members.len() as u8;
+        out.extend(&central);
+        out.extend([0x50, 0x4B, 0x05, 0x06, 0, 0, 0, 0, count, 0, count, 0]);
+        out.extend((central.len() as u32).to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn lists_and_opens_zipped_images() {
+        let disk = D64::new(40).to_bytes();
+        let bytes = zip(&[
+            ("SET/", b""),
+            ("SET/SIDE A.D64", &disk),
+            ("SET/README.TXT", b"hello"),
+            ("SET/MUSIC.TAP", b"C64-TAPE-RAW"),
+            ("SET/MORE.ZIP", b"PK"),
+        ]);
+        let archive = Archive::open(&bytes).unwrap();
+        let images: Vec<_> = archive.images().collect();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].name, "SET/SIDE A.D64");
+        assert_eq!(images[0].size, disk.len());
+        assert!(!images[0].is_tape() && images[1].is_tape());
+
+        let registry = Registry::standard();
+        assert_eq!(images[0].open(&registry).unwrap().tracks(), 40);
+        assert_eq!(images[1].data().unwrap(), b"C64-TAPE-RAW");
+        assert_eq!(
+            images[1].open(&registry).err(),
+            Some(ArchiveError::Image(ImageError::Unsupported("image format")))
+        );
+    }
+
+    #[test]
+    fn reports_broken_archives_and_members() {
+        assert_eq!(
+            Archive::open(b"not an archive").err(),
+            Some(ArchiveError::Zip(ZipError::NotAnArchive))
+        );
+        let mut bytes = zip(&[("GAME.D64", b"broken")]);
+        bytes[30 + 8] ^= 1;
+        let archive = Archive::open(&bytes).unwrap();
+        let image = archive.images().next().unwrap();
+        assert_eq!(image.data(), Err(ArchiveError::Zip(ZipError::Checksum)));
+        assert_eq!(
+            image.open(&Registry::standard()).err().unwrap().to_string(),
+            "zip member fails its checksum"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Images kept in a file.
+//!
+//! A [`Backed`] image is read from its file once and then edited in
+//! memory. It remembers which sectors were written, and
+//! [`Backed::flush`] writes only those back, at their place in the file,
+//! instead of the whole image; on slow media saving a few changed sectors
+//! is then as quick as the write itself. Whether a flush also waits until
+//! the data is on the medium is up to its [`SyncPolicy`].
+
+use crate::d64::{self, D64};
+use crate::error::DosError;
+use crate::image::{BlockDevice, DiskImage, Sector};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+/// Where a [`Backed`] image is stored.
+pub trait Storage: Read + Write + Seek {
+    /// Waits until the data written is on the medium. The default does
+    /// nothing, for storage in memory.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl<T> Storage for Cursor<T> where Cursor<T>: Read + Write + Seek {}
+
+/// When a flush waits for the medium.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system when to write the data out.
+    #[default]
+    Never,
+    /// Sync the storage at the end of every flush that wrote anything.
+    OnFlush,
+}
+
+/// A D64 edited in memory and saved to its file sector by sector.
+///
+/// Changes are not written back when the image is dropped; call
+/// [`Backed::flush`] or [`Backed::into_inner`].
+///
+/// # Example
+/// ```
+/// use cbm_dos::backed::Backed;
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::fs::{self, FileType};
+/// use std::io::Cursor;
+///
+/// let mut blank = D64::new(35);
+/// fs::format(&mut blank, b"SAVES", Some(*b"01")).unwrap();
+/// let mut image = Backed::open(Cursor::new(blank.to_bytes())).unwrap();
+/// fs::write_file(&mut image, b"GAME", FileType::Prg, &[1, 8]).unwrap();
+/// assert_eq!(image.flush().unwrap(), 3);
+///
+/// let saved = D64::from_bytes(image.into_inner().unwrap().get_ref()).unwrap();
+/// assert!(fs::find_file(&saved, b"GAME").unwrap().is_some());
+/// ```
+#[derive(Debug)]
+pub struct Backed<S> {
+    storage: S,
+    image: D64,
+    dirty: BTreeSet<(u8, u8)>,
+    policy: SyncPolicy,
+}
+
+impl<S: Storage> Backed<S> {
+    /// Reads the D64 held by `storage`, from its start.
+    ///
+    /// # Errors
+    /// Returns the error of the storage, or [`io::ErrorKind::InvalidData`]
+    /// if it does not hold a D64.
+    pub fn open(mut storage: S) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        storage.seek(SeekFrom::Start(0))?;
+        storage.read_to_end(&mut bytes)?;
+        let image = D64::from_bytes(&bytes)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Ok(Backed {
+            storage,
+            image,
+            dirty: BTreeSet::new(),
+            policy: SyncPolicy::default(),
+        })
+    }
+
+    /// Sets when a flush syncs the storage.
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the image as edited.
+    pub fn image(&self) -> &D64 {
+        &self.image
+    }
+
+    /// Returns `true` if sectors were written since the last flush.
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Returns the sectors written since the last flush, in track order.
+    pub fn dirty(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.dirty.iter().copied()
+    }
+
+    /// Writes the sectors written since the last flush to the storage and
+    /// returns how many there were.
+    ///
+    /// # Errors
+    /// Returns the error of the storage. The sectors not written yet stay
+    /// dirty, so the flush can be tried again.
+    pub fn flush(&mut self) -> io::Result<usize> {
+        let count = self.dirty.len();
+        while let Some(&(track, sector)) = self.dirty.first() {
+            let offset = d64::sector_offset(track, sector);
+            let data = self
+                .image
+                .read_sector(track, sector)
+                .map_err(io::Error::other)?;
+            self.storage.seek(SeekFrom::Start(offset as u64))?;
+            self.storage.write_all(&data)?;
+            self.dirty.remove(&(track, sector));
+        }
+        self.storage.flush()?;
+        if count > 0 && self.policy == SyncPolicy::OnFlush {
+            self.storage.sync()?;
+        }
+        Ok(count)
+    }
+
+    /// Flushes the image and returns its storage.
+    ///
+    /// # Errors
+    /// Returns the error of the flush.
+    pub fn into_inner(mut self) -> io::Result<S> {
+        self.flush()?;
+        Ok(self.storage)
+    }
+}
+
+impl<S: Storage> BlockDevice for Backed<S> {
+    fn tracks(&self) -> u8 {
+        self.image.tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        self.image.sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        self.image.read_sector(track, sector)
+    }
+
+    /// Writes the sector in memory and marks it dirty, unless it already
+    /// holds `data`.
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        if self.image.read_sector(track, sector)? != *data {
+            self.image.write_sector(track, sector, data)?;
+            self.dirty.insert((track, sector));
+        }
+        Ok(())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.image.sector_error(track, sector)
+    }
+}
+
+impl<S: Storage> DiskImage for Backed<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs;
+
+    /// Storage that records the writes made to it.
+    struct Recording {
+        data: Cursor<Vec<u8>>,
+        writes: Vec<(u64, usize)>,
+        syncs: usize,
+    }
+
+    impl Read for Recording {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push((self.data.position(), buf.len()));
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recording {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    impl Storage for Recording {
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushes_only_written_sectors() {
+        let mut blank = D64::new(35);
+        fs::format(&mut blank, b"DISK", Some(*b"01")).unwrap();
+        let data = Cursor::new(blank.to_bytes());
+        let storage = Recording {
+            data,
+            writes: Vec::new(),
+            syncs: 0,
+        };
+        let mut image = Backed::open(storage).unwrap();
+        image.set_sync_policy(SyncPolicy::OnFlush);
+        let bam = image.read_sector(18, 0).unwrap();
+        image.write_sector(18, 0, &bam).unwrap();
+        assert!(!image.is_dirty());
+        image.write_sector(1, 1, &[0x55; 256]).unwrap();
+        image.write_sector(35, 0, &[0xAA; 256]).unwrap();
+        assert_eq!(image.dirty().collect::<Vec<_>>(), [(1, 1), (35, 0)]);
+        assert_eq!(image.flush().unwrap(), 2);
+        assert_eq!(image.flush().unwrap(), 0);
+
+        let storage = image.into_inner().unwrap();
+        assert_eq!(storage.writes, [(256, 256), (170496, 256)]);
+        assert_eq!(storage.syncs, 1);
+        let saved = D64::from_bytes(storage.data.get_ref()).unwrap();
+        assert_eq!(saved.read_sector(35, 0), Ok([0xAA; 256]));
+    }
+
+    #[test]
+    fn rejects_files_of_other_sizes() {
+        let error = Backed::open(Cursor::new(vec![0; 1000])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,923 @@
+//! Tokenized BASIC programs.
+//!
+//! BASIC stores a program as a chain of lines in memory, and a PRG file of
+//! it is that memory behind its load address:
+//!
+//! ```plaintext
+//! $00-$01  address of the next line, $0000 after the last
+//! $02-$03  line number
+//! $04-     text, keywords as tokens, ended by $00
+//! ```
+//!
+//! [`tokenize`] crunches source text into this form as the screen editor
+//! does when a line is entered, and [`detokenize`] lists it again. Which
+//! keywords become which tokens is up to the [`Dialect`].
+
+use crate::petscii::{self, Charset};
+use crate::prg::Prg;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The load address of BASIC programs on the C64.
+pub const BASIC_START: u16 = 0x0801;
+/// The addresses BASIC programs start at on the Commodore machines: the
+/// PET, the C64, the VIC-20 unexpanded and expanded, the Plus/4 and the
+/// C128.
+pub const BASIC_STARTS: [u16; 5] = [0x0401, 0x0801, 0x1001, 0x1201, 0x1C01];
+/// The highest line number BASIC accepts.
+pub const MAX_LINE_NUMBER: u16 = 63999;
+/// A table of keywords and the tokens they crunch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Table {
+    /// The byte before every token of the table, for the two-byte tokens
+    /// of extended versions.
+    pub prefix: Option<u8>,
+    /// The token of the first keyword.
+    pub first: u8,
+    /// The keywords in PETSCII, in the order of their tokens. An empty
+    /// keyword leaves its token unused.
+    pub keywords: &'static [&'static [u8]],
+}
+
+/// A token of a [`Table`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token {
+    /// The prefix byte of a two-byte token.
+    pub prefix: Option<u8>,
+    /// The token byte.
+    pub code: u8,
+}
+
+/// The keywords of BASIC V2, tokens `$80`–`$CB`.
+const V2_KEYWORDS: &[&[u8]] = &[
+    b"END", b"FOR", b"NEXT", b"DATA", b"INPUT#", b"INPUT", b"DIM", b"READ", b"LET", b"GOTO",
+    b"RUN", b"IF", b"RESTORE", b"GOSUB", b"RETURN", b"REM", b"STOP", b"ON", b"WAIT", b"LOAD",
+    b"SAVE", b"VERIFY", b"DEF", b"POKE", b"PRINT#", b"PRINT", b"CONT", b"LIST", b"CLR", b"CMD",
+    b"SYS", b"OPEN", b"CLOSE", b"GET", b"NEW", b"TAB(", b"TO", b"FN", b"SPC(", b"THEN", b"NOT",
+    b"STEP", b"+", b"-", b"*", b"/", b"^", b"AND", b"OR", b">", b"=", b"<", b"SGN", b"INT", b"ABS",
+    b"USR", b"FRE", b"POS", b"SQR", b"RND", b"LOG", b"EXP", b"COS", b"SIN", b"TAN", b"ATN",
+    b"PEEK", b"LEN", b"STR$", b"VAL", b"ASC", b"CHR$", b"LEFT$", b"RIGHT$", b"MID$", b"GO",
+];
+
+/// The keywords BASIC 3.5 adds, tokens `$CC`–`$FD`. BASIC 7.0 has all but
+/// `RLUM`, whose token is a prefix there.
+const V3_5_KEYWORDS: &[&[u8]] = &[
+    b"RGR",
+    b"RCLR",
+    b"RLUM",
+    b"JOY",
+    b"RDOT",
+    b"DEC",
+    b"HEX$",
+    b"ERR$",
+    b"INSTR",
+    b"ELSE",
+    b"RESUME",
+    b"TRAP",
+    b"TRON",
+    b"TROFF",
+    b"SOUND",
+    b"VOL",
+    b"AUTO",
+    b"PUDEF",
+    b"GRAPHIC",
+    b"PAINT",
+    b"CHAR",
+    b"BOX",
+    b"CIRCLE",
+    b"GSHAPE",
+    b"SSHAPE",
+    b"DRAW",
+    b"LOCATE",
+    b"COLOR",
+    b"SCNCLR",
+    b"SCALE",
+    b"HELP",
+    b"DO",
+    b"LOOP",
+    b"EXIT",
+    b"DIRECTORY",
+    b"DSAVE",
+    b"DLOAD",
+    b"HEADER",
+    b"SCRATCH",
+    b"COLLECT",
+    b"COPY",
+    b"RENAME",
+    b"BACKUP",
+    b"DELETE",
+    b"RENUMBER",
+    b"KEY",
+    b"MONITOR",
+    b"USING",
+    b"UNTIL",
+    b"WHILE",
+];
+
+/// The functions of BASIC 7.0 behind `$CE`, tokens `$02`–`$0A`.
+const V7_CE_KEYWORDS: &[&[u8]] = &[
+    b"POT",
+    b"BUMP",
+    b"PEN",
+    b"RSPPOS",
+    b"RSPRITE",
+    b"RSPCOLOR",
+    b"XOR",
+    b"RWINDOW",
+    b"POINTER",
+];
+
+/// The statements of BASIC 7.0 behind `$FE`, tokens `$02`–`$26`.
+const V7_FE_KEYWORDS: &[&[u8]] = &[
+    b"BANK",
+    b"FILTER",
+    b"PLAY",
+    b"TEMPO",
+    b"MOVSPR",
+    b"SPRITE",
+    b"SPRCOLOR",
+    b"RREG",
+    b"ENVELOPE",
+    b"SLEEP",
+    b"CATALOG",
+    b"DOPEN",
+    b"APPEND",
+    b"DCLOSE",
+    b"BSAVE",
+    b"BLOAD",
+    b"RECORD",
+    b"CONCAT",
+    b"DVERIFY",
+    b"DCLEAR",
+    b"SPRSAV",
+    b"COLLISION",
+    b"BEGIN",
+    b"BEND",
+    b"WINDOW",
+    b"BOOT",
+    b"WIDTH",
+    b"SPRDEF",
+    b"QUIT",
+    b"STASH",
+    b"",
+    b"FETCH",
+    b"",
+    b"SWAP",
+    b"OFF",
+    b"FAST",
+    b"SLOW",
+];
+
+/// The keywords of Simons' BASIC behind `$64`, tokens `$01`–`$7F`.
+const SIMONS_KEYWORDS: &[&[u8]] = &[
+    b"HIRES",
+    b"PLOT",
+    b"LINE",
+    b"BLOCK",
+    b"FCHR",
+    b"FCOL",
+    b"FILL",
+    b"REC",
+    b"ROT",
+    b"DRAW",
+    b"CHAR",
+    b"HI COL",
+    b"INV",
+    b"FRAC",
+    b"MOVE",
+    b"PLACE",
+    b"UPB",
+    b"UPW",
+    b"LEFTW",
+    b"LEFTB",
+    b"DOWNB",
+    b"DOWNW",
+    b"RIGHTB",
+    b"RIGHTW",
+    b"MULTI",
+    b"COLOUR",
+    b"MMOB",
+    b"BFLASH",
+    b"MOB SET",
+    b"MUSIC",
+    b"FLASH",
+    b"REPEAT",
+    b"PLAY",
+    b"",
+    b"CENTRE",
+    b"ENVELOPE",
+    b"CGOTO",
+    b"WAVE",
+    b"FETCH",
+    b"AT(",
+    b"UNTIL",
+    b"",
+    b"",
+    b"USE",
+    b"",
+    b"GLOBAL",
+    b"",
+    b"RESET",
+    b"PROC",
+    b"CALL",
+    b"EXEC",
+    b"END PROC",
+    b"EXIT",
+    b"END LOOP",
+    b"ON KEY",
+    b"DISABLE",
+    b"RESUME",
+    b"LOOP",
+    b"DELAY",
+    b"",
+    b"",
+    b"",
+    b"",
+    b"SECURE",
+    b"DISAPA",
+    b"CIRCLE",
+    b"ON ERROR",
+    b"NO ERROR",
+    b"LOCAL",
+    b"RCOMP",
+    b"ELSE",
+    b"RETRACE",
+    b"TRACE",
+    b"DIR",
+    b"PAGE",
+    b"DUMP",
+    b"FIND",
+    b"OPTION",
+    b"AUTO",
+    b"OLD",
+    b"JOY",
+    b"MOD",
+    b"DIV",
+    b"",
+    b"DUP",
+    b"INKEY",
+    b"INST",
+    b"TEST",
+    b"LIN",
+    b"EXOR",
+    b"INSERT",
+    b"POT",
+    b"PENX",
+    b"",
+    b"PENY",
+    b"SOUND",
+    b"GRAPHICS",
+    b"DESIGN",
+    b"RLOCMOB",
+    b"CMOB",
+    b"BCKGNDS",
+    b"PAUSE",
+    b"NRM",
+    b"MOB OFF",
+    b"OFF",
+    b"ANGL",
+    b"ARC",
+    b"COLD",
+    b"SCRSV",
+    b"SCRLD",
+    b"TEXT",
+    b"CSET",
+    b"VOL",
+    b"DISK",
+    b"HRDCPY",
+    b"KEY",
+    b"PAINT",
+    b"LOW COL",
+    b"COPY",
+    b"MERGE",
+    b"RENUMBER",
+    b"MEM",
+    b"DETECT",
+    b"CHECK",
+    b"DISPLAY",
+    b"ERR",
+    b"OUT",
+];
+
+const V2_TABLE: Table = Table {
+    prefix: None,
+    first: 0x80,
+    keywords: V2_KEYWORDS,
+};
+
+/// The keyword set of a BASIC version.
+///
+/// The presets cover the versions programs on disks and tapes are most
+/// often in; a [`Dialect`] of other tables decodes others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dialect {
+    /// The name of the version.
+    pub name: &'static str,
+    /// The tables, in the order crunching tries them.
+    pub tables: &'static [Table],
+}
+
+impl Dialect {
+    /// Commodore BASIC V2 of the C64 and VIC-20.
+    pub const V2: Dialect = Dialect {
+        name: "BASIC V2",
+        tables: &[V2_TABLE],
+    };
+
+    /// BASIC 3.5 of the C16 and Plus/4.
+    pub const V3_5: Dialect = Dialect {
+        name: "BASIC 3.5",
+        tables: &[
+            V2_TABLE,
+            Table {
+                prefix: None,
+                first: 0xCC,
+                keywords: V3_5_KEYWORDS,
+            },
+        ],
+    };
+
+    /// BASIC 7.0 of the C128. Its two-byte tokens are tried first, so that
+    /// `DOPEN` does not crunch to `DO` and `PEN`.
+    pub const V7: Dialect = Dialect {
+        name: "BASIC 7.0",
+        tables: &[
+            Table {
+                prefix: Some(0xCE),
+                first: 0x02,
+                keywords: V7_CE_KEYWORDS,
+            },
+            Table {
+                prefix: Some(0xFE),
+                first: 0x02,
+                keywords: V7_FE_KEYWORDS,
+            },
+            V2_TABLE,
+            Table {
+                prefix: None,
+                first: 0xCC,
+                keywords: V3_5_KEYWORDS.split_at(2).0,
+            },
+            Table {
+                prefix: None,
+                first: 0xCF,
+                keywords: V3_5_KEYWORDS.split_at(3).1,
+            },
+        ],
+    };
+
+    /// Simons' BASIC, the cartridge extending BASIC V2 on the C64. Its
+    /// keywords are tried first, so that `END PROC` is not `END`.
+    pub const SIMONS: Dialect = Dialect {
+        name: "Simons' BASIC",
+        tables: &[
+            Table {
+                prefix: Some(0x64),
+                first: 0x01,
+                keywords: SIMONS_KEYWORDS,
+            },
+            V2_TABLE,
+        ],
+    };
+
+    /// Returns the token of `keyword`.
+    pub fn token(&self, keyword: &[u8]) -> Option<Token> {
+        self.tables.iter().find_map(|table| {
+            let index = table.keywords.iter().position(|&k| k == keyword)?;
+            Some(Token {
+                prefix: table.prefix,
+                code: table.first + index as u8,
+            })
+        })
+    }
+
+    /// Returns the keyword of `token`.
+    pub fn keyword(&self, token: Token) -> Option<&'static [u8]> {
+        self.tables
+            .iter()
+            .filter(|table| table.prefix == token.prefix)
+            .find_map(|table| {
+                let index = token.code.checked_sub(table.first)?;
+                table.keywords.get(usize::from(index)).copied()
+            })
+            .filter(|keyword| !keyword.is_empty())
+    }
+
+    /// Returns the token of the keyword `text` starts with and the length
+    /// of the keyword in `text`.
+    ///
+    /// Like BASIC, this takes the first keyword of the tables that fits,
+    /// and takes a keyword abbreviated by shifting its last typed letter.
+    fn match_keyword(&self, text: &[u8]) -> Option<(Token, usize)> {
+        self.tables.iter().find_map(|table| {
+            table
+                .keywords
+                .iter()
+                .enumerate()
+                .find_map(|(index, keyword)| {
+                    let length = abbreviation(keyword, text)?;
+                    let token = Token {
+                        prefix: table.prefix,
+                        code: table.first + index as u8,
+                    };
+                    Some((token, length))
+                })
+        })
+    }
+}
+
+/// Returns how much of `text` spells `keyword`, in full or abbreviated.
+fn abbreviation(keyword: &[u8], text: &[u8]) -> Option<usize> {
+    if keyword.is_empty() {
+        return None;
+    }
+    for (i, &letter) in keyword.iter().enumerate() {
+        match text.get(i) {
+            Some(&typed) if typed == letter => {}
+            Some(&typed) if i > 0 && typed == letter | 0x80 => return Some(i + 1),
+            _ => return None,
+        }
+    }
+    Some(keyword.len())
+}
+
+/// A line of a program, its keywords spelled out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Line {
+    /// The line number.
+    pub number: u16,
+    /// The text in PETSCII, without the line number.
+    pub text: Vec<u8>,
+}
+
+/// An error raised while tokenizing or listing a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BasicError {
+    /// A source line, counted from 1, lacks a valid line number.
+    Syntax(usize),
+    /// The source holds a character PETSCII lacks.
+    Character(char),
+    /// The program does not fit in memory behind its load address.
+    OutOfMemory,
+    /// The program ends inside a line.
+    Truncated,
+}
+
+impl fmt::Display for BasicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BasicError::Syntax(line) => write!(f, "syntax error in line {line}"),
+            BasicError::Character(c) => write!(f, "no PETSCII character for {c:?}"),
+            BasicError::OutOfMemory => write!(f, "out of memory"),
+            BasicError::Truncated => write!(f, "program is truncated"),
+        }
+    }
+}
+
+impl core::error::Error for BasicError {}
+
+/// Crunches the text of a line, without its line number, into tokens.
+///
+/// Text between quotes stays as it is, and so does the rest of a `DATA`
+/// statement and everything after `REM`. `?` stands for `PRINT`.
+pub fn crunch(text: &[u8], dialect: &Dialect) -> Vec<u8> {
+    let rem = dialect.token(b"REM");
+    let data = dialect.token(b"DATA");
+    let print = dialect.token(b"PRINT");
+    let mut out = Vec::with_capacity(text.len());
+    let (mut quote, mut verbatim) = (false, false);
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        i += 1;
+        if c == b'"' {
+            quote = !quote;
+        } else if c == b':' && !quote {
+            verbatim = false;
+        }
+        if quote || verbatim || c == b'"' || c >= 0x80 || (b'0'..=b';').contains(&c) {
+            out.push(c);
+        } else if let Some(print) = print.filter(|_| c == b'?') {
+            push_token(&mut out, print);
+        } else if let Some((token, length)) = dialect.match_keyword(&text[i - 1..]) {
+            push_token(&mut out, token);
+            i += length - 1;
+            if Some(token) == rem {
+                out.extend_from_slice(&text[i..]);
+                break;
+            }
+            verbatim = Some(token) == data;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn push_token(out: &mut Vec<u8>, token: Token) {
+    out.extend(token.prefix);
+    out.push(token.code);
+}
+
+/// Tokenizes PETSCII source into a PRG file loading at `load`.
+///
+/// Lines end with a carriage return or a line feed and start with their
+/// number. As in the editor, lines are stored in the order of their
+/// numbers, a line replaces an earlier one of the same number, and a bare
+/// number deletes it. Blank lines are skipped.
+///
+/// # Errors
+/// Returns [`BasicError::Syntax`] for a line without a number or with one
+/// above [`MAX_LINE_NUMBER`], and [`BasicError::OutOfMemory`] if the
+/// program would run past `$FFFF`.
+pub fn tokenize(source: &[u8], load: u16, dialect: &Dialect) -> Result<Vec<u8>, BasicError> {
+    let mut lines = BTreeMap::new();
+    for (index, line) in source.split(|&c| c == 0x0D || c == b'\n').enumerate() {
+        let line = trim_spaces(line);
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line.iter().take_while(|c| c.is_ascii_digit()).count();
+        let number = core::str::from_utf8(&line[..digits])
+            .ok()
+            .and_then(|digits| digits.parse::<u16>().ok())
+            .filter(|&number| number <= MAX_LINE_NUMBER)
+            .ok_or(BasicError::Syntax(index + 1))?;
+        let text = trim_spaces(&line[digits..]);
+        if text.is_empty() {
+            lines.remove(&number);
+        } else {
+            lines.insert(number, crunch(text, dialect));
+        }
+    }
+
+    let mut prg = load.to_le_bytes().to_vec();
+    for (number, text) in lines {
+        let next = usize::from(load) + prg.len() - 2 + text.len() + 5;
+        let next = u16::try_from(next).map_err(|_| BasicError::OutOfMemory)?;
+        prg.extend_from_slice(&next.to_le_bytes());
+        prg.extend_from_slice(&number.to_le_bytes());
+        prg.extend(text);
+        prg.push(0);
+    }
+    prg.extend_from_slice(&[0, 0]);
+    if usize::from(load) + prg.len() - 2 > 0x10000 {
+        return Err(BasicError::OutOfMemory);
+    }
+    Ok(prg)
+}
+
+/// Tokenizes source text, as typed on a host, into a PRG file loading at
+/// `load`. See [`tokenize`].
+///
+/// The text is in the unshifted set, but upper and lower case letters
+/// both stand for its letters; graphics characters are taken in their
+/// private-use code points, see [`petscii`].
+///
+/// # Errors
+/// Returns [`BasicError::Character`] for a character the set lacks, and
+/// the errors of [`tokenize`].
+pub fn tokenize_str(source: &str, load: u16, dialect: &Dialect) -> Result<Vec<u8>, BasicError> {
+    let source = source
+        .chars()
+        .map(|c| match c {
+            '\n' => Ok(b'\n'),
+            'a'..='z' => Ok(c.to_ascii_uppercase() as u8),
+            _ => petscii::from_char(c, Charset::Unshifted).ok_or(BasicError::Character(c)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    tokenize(&source, load, dialect)
+}
+
+/// Lists the lines of a PRG file, spelling out keywords outside quotes as
+/// `LIST` does.
+///
+/// Lines are read one after the other; their link addresses are not
+/// followed, so a program saved from another address lists all the same.
+/// Tokens the dialect lacks stay as they are.
+///
+/// # Errors
+/// Returns [`BasicError::Truncated`] if the file ends before the end of
+/// the program.
+pub fn detokenize(prg: &[u8], dialect: &Dialect) -> Result<Vec<Line>, BasicError> {
+    let mut lines = Vec::new();
+    let mut rest = prg.get(2..).ok_or(BasicError::Truncated)?;
+    loop {
+        match rest {
+            [0, 0, ..] => return Ok(lines),
+            [_, _, low, high, text @ ..] => {
+                let end = text
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or(BasicError::Truncated)?;
+                lines.push(Line {
+                    number: u16::from_le_bytes([*low, *high]),
+                    text: expand(&text[..end], dialect),
+                });
+                rest = &text[end + 1..];
+            }
+            _ => return Err(BasicError::Truncated),
+        }
+    }
+}
+
+/// Spells out the tokens of crunched text.
+fn expand(text: &[u8], dialect: &Dialect) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len() * 2);
+    let mut quote = false;
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        if c == b'"' {
+            quote = !quote;
+        }
+        let extended = text.get(i + 1).and_then(|&code| {
+            dialect.keyword(Token {
+                prefix: Some(c),
+                code,
+            })
+        });
+        let single = dialect.keyword(Token {
+            prefix: None,
+            code: c,
+        });
+        match (extended, single) {
+            _ if quote => out.push(c),
+            (Some(keyword), _) => {
+                out.extend_from_slice(keyword);
+                i += 1;
+            }
+            (None, Some(keyword)) => out.extend_from_slice(keyword),
+            (None, None) => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// How a program is started, as far as its first bytes tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Start {
+    /// A BASIC program, started with `RUN`.
+    Basic,
+    /// A BASIC line calling machine code at the address with `SYS`, as
+    /// packers and crunchers write: started with `RUN`.
+    Sys(u16),
+    /// Machine code, loaded to its address and started with `SYS` there,
+    /// also where BASIC programs start but not a BASIC line.
+    MachineCode(u16),
+    /// Machine code loaded over the stack or the system vectors, which
+    /// starts itself as soon as it is loaded.
+    Autostart,
+    /// Too short to be a program.
+    Unknown,
+}
+
+impl Start {
+    /// Classifies the PRG file `prg` by its load address and first line.
+    pub fn of(prg: &[u8]) -> Start {
+        let Ok(prg) = Prg::from_bytes(prg) else {
+            return Start::Unknown;
+        };
+        if prg.body.is_empty() {
+            return Start::Unknown;
+        }
+        if prg.covers(0x0100..=0x01FF) || prg.covers(0x0300..=0x0333) {
+            return Start::Autostart;
+        }
+        if BASIC_STARTS.contains(&prg.load)
+            && let Some(text) = first_line(prg.load, &prg.body)
+        {
+            return sys_address(text).map_or(Start::Basic, Start::Sys);
+        }
+        Start::MachineCode(prg.load)
+    }
+
+    /// Returns whether the program can be started.
+    pub fn is_runnable(&self) -> bool {
+        *self != Start::Unknown
+    }
+
+    /// Returns what to type to load the program `name` from `device` and
+    /// start it, in PETSCII, or `None` if it cannot be started.
+    pub fn commands(&self, name: &[u8], device: u8) -> Option<Vec<u8>> {
+        let mut keys = b"LOAD\"".to_vec();
+        keys.extend_from_slice(name);
+        keys.extend_from_slice(format!("\",{device}").as_bytes());
+        match self {
+            Start::Basic | Start::Sys(_) => keys.extend_from_slice(b"\rRUN\r"),
+            Start::MachineCode(address) => {
+                keys.extend_from_slice(format!(",1\rSYS{address}\r").as_bytes());
+            }
+            Start::Autostart => keys.extend_from_slice(b",1\r"),
+            Start::Unknown => return None,
+        }
+        Some(keys)
+    }
+}
+
+/// Returns the crunched text of the first line of a program loaded at
+/// `load`, or `None` if `body` does not start with a line whose link
+/// points past it into the program.
+fn first_line(load: u16, body: &[u8]) -> Option<&[u8]> {
+    let [low, high, number_low, number_high, text @ ..] = body else {
+        return None;
+    };
+    let end = text.iter().position(|&c| c == 0)?;
+    let link = usize::from(u16::from_le_bytes([*low, *high]));
+    let number = u16::from_le_bytes([*number_low, *number_high]);
+    let next = usize::from(load) + 5 + end;
+    (end > 0
+        && number <= MAX_LINE_NUMBER
+        && (next..=usize::from(load) + body.len()).contains(&link))
+    .then_some(&text[..end])
+}
+
+/// Returns the address a crunched line calls with `SYS`, outside quotes.
+fn sys_address(text: &[u8]) -> Option<u16> {
+    let sys = Dialect::V2.token(b"SYS")?.code;
+    let mut quote = false;
+    let at = text.iter().position(|&c| {
+        quote ^= c == b'"';
+        c == sys && !quote
+    })?;
+    let digits: Vec<u8> = text[at + 1..]
+        .iter()
+        .skip_while(|&&c| c == b' ' || c == b'(')
+        .take_while(|c| c.is_ascii_digit())
+        .copied()
+        .collect();
+    core::str::from_utf8(&digits).ok()?.parse().ok()
+}
+
+fn trim_spaces(text: &[u8]) -> &[u8] {
+    let start = text.iter().position(|&c| c != b' ').unwrap_or(text.len());
+    let end = text
+        .iter()
+        .rposition(|&c| c != b' ')
+        .map_or(start, |p| p + 1);
+    &text[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_and_lists_programs() {
+        let source = "20 goto 10\n10 ?\"Hello\":rem print\n\n30 data for,to\n30 data 1 , 2:end\n";
+        let prg = tokenize_str(source, BASIC_START, &Dialect::V2).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            prg,
+            [
+                0x01, 0x08,
+                0x16, 0x08, 10, 0, 0x99, b'"', b'H', b'E', b'L', b'L', b'O', b'"', b':', 0x8F,
+                b' ', b'P', b'R', b'I', b'N', b'T', 0,
+                0x1F, 0x08, 20, 0, 0x89, b' ', b'1', b'0', 0,
+                0x2D, 0x08, 30, 0, 0x83, b' ', b'1', b' ', b',', b' ', b'2', b':', 0x80, 0,
+                0, 0,
+            ]
+        );
+
+        let lines = detokenize(&prg, &Dialect::V2).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].number, 10);
+        assert_eq!(lines[0].text, b"PRINT\"HELLO\":REM PRINT");
+        assert_eq!(lines[2].text, b"DATA 1 , 2:END");
+
+        let relocated = tokenize_str(source, 0x1001, &Dialect::V2).unwrap();
+        assert_eq!(relocated[..4], [0x01, 0x10, 0x16, 0x10]);
+        assert_eq!(detokenize(&relocated, &Dialect::V2).unwrap(), lines);
+        assert_eq!(
+            detokenize(&prg[..prg.len() - 4], &Dialect::V2),
+            Err(BasicError::Truncated)
+        );
+    }
+
+    #[test]
+    fn crunches_like_the_editor() {
+        let v2 = &Dialect::V2;
+        assert_eq!(
+            crunch(b"FORT=ATOB", v2),
+            [0x81, b'T', 0xB2, b'A', 0xA4, b'B']
+        );
+        assert_eq!(
+            crunch(b"P\xCFA,1:G\xCF10", v2),
+            [0x97, b'A', b',', b'1', b':', 0x89, b'1', b'0']
+        );
+        assert_eq!(
+            crunch(b"PRINT#1,\"IF\"", v2),
+            [0x98, b'1', b',', b'"', b'I', b'F', b'"']
+        );
+        assert_eq!(crunch(b"X=\xFF", v2), [b'X', 0xB2, 0xFF]);
+        let go = Token {
+            prefix: None,
+            code: 0xCB,
+        };
+        assert_eq!(v2.keyword(go), Some(&b"GO"[..]));
+        assert_eq!(v2.token(b"GO"), Some(go));
+
+        assert_eq!(
+            tokenize(b"10 PRINT\r64000 END", BASIC_START, v2),
+            Err(BasicError::Syntax(2))
+        );
+        assert_eq!(
+            tokenize(b"PRINT", BASIC_START, v2),
+            Err(BasicError::Syntax(1))
+        );
+        assert_eq!(
+            tokenize(b"10 PRINT", 0xFFFA, v2),
+            Err(BasicError::OutOfMemory)
+        );
+        assert_eq!(
+            tokenize_str("10 PRINT \"\u{1F600}\"", BASIC_START, v2),
+            Err(BasicError::Character('\u{1F600}'))
+        );
+    }
+
+    #[test]
+    fn crunches_extended_dialects() {
+        let source = b"10 DOPEN#1,\"DATA\":GRAPHIC 1:X=POT(1)+JOY(2)\r20 IF X THEN ELSE PRINT";
+        let prg = tokenize(source, 0x1C01, &Dialect::V7).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            prg[6..prg.len() - 3],
+            [
+                0xFE, 0x0D, b'#', b'1', b',', b'"', b'D', b'A', b'T', b'A', b'"', b':',
+                0xDE, b' ', b'1', b':', b'X', 0xB2, 0xCE, 0x02, b'(', b'1', b')', 0xAA, 0xCF,
+                b'(', b'2', b')', 0,
+                0x30, 0x1C, 20, 0, 0x8B, b' ', b'X', b' ', 0xA7, b' ', 0xD5, b' ', 0x99,
+            ]
+        );
+        let lines = detokenize(&prg, &Dialect::V7).unwrap();
+        assert_eq!(lines[0].text, b"DOPEN#1,\"DATA\":GRAPHIC 1:X=POT(1)+JOY(2)");
+        assert_eq!(lines[1].text, b"IF X THEN ELSE PRINT");
+
+        // BASIC 3.5 lacks the two-byte tokens, and has RLUM where BASIC 7.0
+        // has a prefix.
+        let lines = detokenize(&prg, &Dialect::V3_5).unwrap();
+        assert_eq!(lines[0].text[..3], [0xFE, 0x0D, b'#']);
+        assert!(lines[0].text.ends_with(b"+JOY(2)"));
+        assert_eq!(crunch(b"RLUM(1)", &Dialect::V3_5)[0], 0xCE);
+        assert_eq!(crunch(b"RLUM(1)", &Dialect::V7)[0], b'R');
+        assert_eq!(crunch(b"RLUM(1)", &Dialect::V2)[0], b'R');
+
+        let simons = &Dialect::SIMONS;
+        let text = crunch(b"END PROC:HIRES 0,1:END", simons);
+        assert_eq!(
+            text,
+            [
+                0x64, 0x34, b':', 0x64, 0x01, b' ', b'0', b',', b'1', b':', 0x80
+            ]
+        );
+        assert_eq!(expand(&text, simons), b"END PROC:HIRES 0,1:END");
+        assert_eq!(
+            expand(&text, &Dialect::V2),
+            [
+                0x64, b'4', b':', 0x64, 0x01, b' ', b'0', b',', b'1', b':', b'E', b'N', b'D'
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_how_programs_start() {
+        let basic = tokenize(b"10 PRINT \"SYS 64738\"", BASIC_START, &Dialect::V2).unwrap();
+        assert_eq!(Start::of(&basic), Start::Basic);
+        let mut packed = tokenize(b"1987 SYS(2061):REM", BASIC_START, &Dialect::V2).unwrap();
+        packed.extend_from_slice(&[0x78, 0xA9, 0x00, 0x60]);
+        assert_eq!(Start::of(&packed), Start::Sys(2061));
+        let vic = tokenize(b"0 SYS 4109", 0x1001, &Dialect::V2).unwrap();
+        assert_eq!(Start::of(&vic), Start::Sys(4109));
+
+        let code = [0x01, 0x08, 0x78, 0xA9, 0x00, 0x8D, 0x20, 0xD0, 0x60];
+        assert_eq!(Start::of(&code), Start::MachineCode(0x0801));
+        assert_eq!(Start::of(&[0x00, 0xC0, 0x60]), Start::MachineCode(0xC000));
+        let mut vectors = vec![0xA7, 0x02];
+        vectors.resize(0x60, 0xEA);
+        assert_eq!(Start::of(&vectors), Start::Autostart);
+        assert_eq!(Start::of(&[0xFE, 0x01, 0x00, 0x08]), Start::Autostart);
+        assert_eq!(Start::of(&[0x01, 0x08]), Start::Unknown);
+    }
+
+    #[test]
+    fn types_the_commands_to_start() {
+        assert_eq!(
+            Start::Sys(2061).commands(b"GAME", 8).unwrap(),
+            b"LOAD\"GAME\",8\rRUN\r"
+        );
+        assert_eq!(
+            Start::MachineCode(0xC000).commands(b"TOOL", 9).unwrap(),
+            b"LOAD\"TOOL\",9,1\rSYS49152\r"
+        );
+        assert_eq!(
+            Start::Autostart.commands(b"*", 8).unwrap(),
+            b"LOAD\"*\",8,1\r"
+        );
+        assert_eq!(Start::Unknown.commands(b"*", 8), None);
+        assert!(!Start::Unknown.is_runnable());
+        assert!(Start::Basic.is_runnable());
+    }
+}
@@ -0,0 +1,476 @@
+//! The command layer shared by the Commodore buses.
+//!
+//! The serial IEC bus, IEEE-488 and the 1551's TCBM interface move bytes
+//! differently, but address devices the same way. With ATN asserted the
+//! computer sends commands:
+//!
+//! ```plaintext
+//! $20+n  LISTEN device n        $3F  UNLISTEN
+//! $40+n  TALK device n          $5F  UNTALK
+//! $60+s  DATA on secondary s    $E0+s  CLOSE s    $F0+s  OPEN s
+//! ```
+//!
+//! after which the addressed listener receives bytes or the addressed
+//! talker sends them. [`BusDevice`] implements this on top of a
+//! [`VirtualDrive`]; the bus front ends only deliver and fetch bytes.
+//!
+//! The lines of both buses are open-collector, each pulled by whoever
+//! asserts it. [`WiredBus`] emulates such a bus for any set of
+//! [`WiredLines`].
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::drive::{COMMAND_CHANNEL, VirtualDrive};
+
+/// A transfer failure seen by the computer side of a bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BusError {
+    /// No device answered the attention request.
+    DeviceNotPresent,
+    /// The other side stopped responding during a handshake, as when a
+    /// talker has nothing to send.
+    Timeout,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::DeviceNotPresent => write!(f, "device not present"),
+            BusError::Timeout => write!(f, "bus timeout"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// A command byte sent under ATN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BusCommand {
+    /// `LISTEN`: the device is to receive data.
+    Listen(u8),
+    /// `UNLISTEN`: all listeners stop receiving.
+    Unlisten,
+    /// `TALK`: the device is to send data.
+    Talk(u8),
+    /// `UNTALK`: the talker stops sending.
+    Untalk,
+    /// Secondary address for data on a channel.
+    Data(u8),
+    /// Secondary address closing a channel.
+    Close(u8),
+    /// Secondary address opening a channel; the filename follows as data.
+    Open(u8),
+}
+
+impl BusCommand {
+    /// Decodes a command byte. Returns `None` for bytes with no meaning,
+    /// such as `$80`-`$DF`.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let low = byte & 0x1F;
+        match byte & 0xE0 {
+            0x20 if low == 0x1F => Some(BusCommand::Unlisten),
+            0x20 => Some(BusCommand::Listen(low)),
+            0x40 if low == 0x1F => Some(BusCommand::Untalk),
+            0x40 => Some(BusCommand::Talk(low)),
+            0x60 => Some(BusCommand::Data(byte & 0x0F)),
+            0xE0 if byte & 0x10 == 0 => Some(BusCommand::Close(byte & 0x0F)),
+            0xE0 => Some(BusCommand::Open(byte & 0x0F)),
+            _ => None,
+        }
+    }
+
+    /// Returns the command byte.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            BusCommand::Listen(device) => 0x20 | (device & 0x1F),
+            BusCommand::Unlisten => 0x3F,
+            BusCommand::Talk(device) => 0x40 | (device & 0x1F),
+            BusCommand::Untalk => 0x5F,
+            BusCommand::Data(secondary) => 0x60 | (secondary & 0x0F),
+            BusCommand::Close(secondary) => 0xE0 | (secondary & 0x0F),
+            BusCommand::Open(secondary) => 0xF0 | (secondary & 0x0F),
+        }
+    }
+}
+
+/// The progress of a byte handshake on one of the buses.
+pub(crate) enum Step<T> {
+    Pending,
+    Done(T),
+    Failed(BusError),
+}
+
+/// A step of a computer-side transaction.
+#[derive(Debug, Clone)]
+pub(crate) enum Operation {
+    /// Bytes sent under ATN.
+    Attention(Vec<u8>),
+    /// Data bytes, the last one with EOI.
+    Send(Vec<u8>),
+    /// Bytes received from the talker up to EOI.
+    Receive,
+}
+
+/// The transactions a computer queues for a bus, spelled out as commands.
+///
+/// Front ends with steps of their own queue an `O` that wraps [`Operation`].
+#[derive(Debug, Clone)]
+pub(crate) struct Operations<O = Operation>(VecDeque<O>);
+
+impl<O> Default for Operations<O> {
+    fn default() -> Self {
+        Operations(VecDeque::new())
+    }
+}
+
+impl<O: From<Operation>> Operations<O> {
+    pub(crate) fn listen(&mut self, device: u8, secondary: u8) {
+        self.attention(&[BusCommand::Listen(device), BusCommand::Data(secondary)]);
+    }
+
+    pub(crate) fn unlisten(&mut self) {
+        self.attention(&[BusCommand::Unlisten]);
+    }
+
+    pub(crate) fn talk(&mut self, device: u8, secondary: u8) {
+        self.attention(&[BusCommand::Talk(device), BusCommand::Data(secondary)]);
+        self.0.push_back(Operation::Receive.into());
+    }
+
+    pub(crate) fn untalk(&mut self) {
+        self.attention(&[BusCommand::Untalk]);
+    }
+
+    pub(crate) fn open(&mut self, device: u8, secondary: u8, name: &[u8]) {
+        self.attention(&[BusCommand::Listen(device), BusCommand::Open(secondary)]);
+        self.send(name);
+        self.unlisten();
+    }
+
+    pub(crate) fn close(&mut self, device: u8, secondary: u8) {
+        self.attention(&[
+            BusCommand::Listen(device),
+            BusCommand::Close(secondary),
+            BusCommand::Unlisten,
+        ]);
+    }
+
+    pub(crate) fn send(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.0.push_back(Operation::Send(data.to_vec()).into());
+        }
+    }
+
+    pub(crate) fn push(&mut self, operation: O) {
+        self.0.push_back(operation);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<O> {
+        self.0.pop_front()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn attention(&mut self, commands: &[BusCommand]) {
+        let bytes = commands.iter().map(|c| c.to_byte()).collect();
+        self.0.push_back(Operation::Attention(bytes).into());
+    }
+}
+
+/// What a device does on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Not addressed.
+    Idle,
+    /// Receiving bytes.
+    Listener,
+    /// Sending bytes.
+    Talker,
+}
+
+/// A set of open-collector lines, asserted if any participant pulls them.
+pub trait WiredLines: Copy + Default {
+    /// Combines the lines two participants pull.
+    fn or(self, other: Self) -> Self;
+}
+
+/// An emulated bus joining any number of participants.
+///
+/// [`crate::iec`] and [`crate::ieee488`] name it for their lines, and
+/// implement their pins for its [`Port`].
+#[derive(Debug, Clone)]
+pub struct WiredBus<L> {
+    ports: Vec<L>,
+}
+
+impl<L> Default for WiredBus<L> {
+    fn default() -> Self {
+        WiredBus { ports: Vec::new() }
+    }
+}
+
+impl<L: WiredLines> WiredBus<L> {
+    /// Creates a bus with nothing attached.
+    pub fn new() -> Self {
+        WiredBus::default()
+    }
+
+    /// Attaches a participant and returns the index of its port.
+    pub fn attach(&mut self) -> usize {
+        self.ports.push(L::default());
+        self.ports.len() - 1
+    }
+
+    /// Returns the state of the bus.
+    pub fn lines(&self) -> L {
+        self.ports.iter().fold(L::default(), |a, &b| a.or(b))
+    }
+
+    /// Returns the port at `index`.
+    ///
+    /// # Panics
+    /// Panics if no participant was attached under `index`.
+    pub fn port(&mut self, index: usize) -> Port<'_, L> {
+        assert!(index < self.ports.len(), "no port {index}");
+        Port { bus: self, index }
+    }
+}
+
+/// One participant's connection to a [`WiredBus`].
+#[derive(Debug)]
+pub struct Port<'a, L> {
+    bus: &'a mut WiredBus<L>,
+    index: usize,
+}
+
+impl<L: WiredLines> Port<'_, L> {
+    /// Returns the state of the bus, including the lines of this port.
+    pub fn lines(&self) -> L {
+        self.bus.lines()
+    }
+
+    /// Sets the lines this port pulls.
+    pub fn pull(&mut self, lines: L) {
+        self.bus.ports[self.index] = lines;
+    }
+}
+
+/// A drive answering to the bus commands at one device address.
+///
+/// Like a real drive it never reports errors over the bus itself: failing
+/// operations leave their error in the drive status, to be read through
+/// channel 15.
+///
+/// # Example
+/// ```
+/// use cbm_dos::bus::{BusCommand, BusDevice};
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::Drive;
+///
+/// let mut device = BusDevice::new(Drive::new(D64::new(35)), 8);
+/// device.attention(BusCommand::Talk(8).to_byte());
+/// device.attention(BusCommand::Data(15).to_byte());
+/// assert_eq!(device.send(), Some((b'7', false)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct BusDevice<D> {
+    drive: D,
+    address: u8,
+    role: Role,
+    secondary: Option<u8>,
+    name: Option<Vec<u8>>,
+}
+
+impl<D: VirtualDrive> BusDevice<D> {
+    /// Attaches `drive` under device number `address`.
+    pub fn new(drive: D, address: u8) -> Self {
+        BusDevice {
+            drive,
+            address,
+            role: Role::Idle,
+            secondary: None,
+            name: None,
+        }
+    }
+
+    /// Returns the device number.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Returns the drive.
+    pub fn drive(&self) -> &D {
+        &self.drive
+    }
+
+    /// Returns the drive for modification.
+    pub fn drive_mut(&mut self) -> &mut D {
+        &mut self.drive
+    }
+
+    /// Detaches the drive.
+    pub fn into_inner(self) -> D {
+        self.drive
+    }
+
+    /// Returns the current role.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the secondary address of the current transfer.
+    pub fn secondary(&self) -> Option<u8> {
+        self.secondary
+    }
+
+    /// Processes a byte received under ATN.
+    ///
+    /// Secondary addresses only apply while the device is addressed, and
+    /// unknown bytes are ignored.
+    pub fn attention(&mut self, byte: u8) {
+        let Some(command) = BusCommand::from_byte(byte) else {
+            return;
+        };
+        match command {
+            BusCommand::Listen(device) if device == self.address => {
+                self.role = Role::Listener;
+                self.secondary = None;
+            }
+            BusCommand::Talk(device) if device == self.address => {
+                self.role = Role::Talker;
+                self.secondary = None;
+            }
+            BusCommand::Talk(_) if self.role == Role::Talker => self.role = Role::Idle,
+            BusCommand::Listen(_) | BusCommand::Talk(_) => {}
+            BusCommand::Unlisten if self.role == Role::Listener => {
+                self.finish_listening();
+                self.role = Role::Idle;
+            }
+            BusCommand::Untalk if self.role == Role::Talker => self.role = Role::Idle,
+            BusCommand::Unlisten | BusCommand::Untalk => {}
+            _ if self.role == Role::Idle => {}
+            BusCommand::Data(secondary) => self.secondary = Some(secondary),
+            BusCommand::Open(secondary) => {
+                self.secondary = Some(secondary);
+                self.name = Some(Vec::new());
+            }
+            BusCommand::Close(secondary) => {
+                let _ = self.drive.close(secondary);
+                self.secondary = None;
+            }
+        }
+    }
+
+    /// Accepts a data byte while listening.
+    pub fn receive(&mut self, byte: u8) {
+        if self.role != Role::Listener {
+            return;
+        }
+        let Some(secondary) = self.secondary else {
+            return;
+        };
+        if let Some(name) = &mut self.name {
+            name.push(byte);
+            return;
+        }
+        let _ = self.drive.write_byte(secondary, byte);
+    }
+
+    /// Returns the next byte to send while talking, with the EOI flag set on
+    /// the last one, or `None` if the channel has nothing to send.
+    pub fn send(&mut self) -> Option<(u8, bool)> {
+        if self.role != Role::Talker {
+            return None;
+        }
+        self.drive.read_byte(self.secondary?)
+    }
+
+    /// Opens the channel whose name was sent, or executes a command sent
+    /// without carriage return, as the drive does at the end of a listen.
+    fn finish_listening(&mut self) {
+        match (self.name.take(), self.secondary) {
+            (Some(name), Some(secondary)) => {
+                let _ = self.drive.open(secondary, &name);
+            }
+            (None, Some(COMMAND_CHANNEL)) => {
+                let _ = self.drive.close(COMMAND_CHANNEL);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::Drive;
+    use crate::error::DosError;
+    use crate::fs::{self, FileType};
+
+    #[test]
+    fn command_bytes_round_trip() {
+        for byte in 0x20..=0xFF {
+            if let Some(command) = BusCommand::from_byte(byte) {
+                let expected = if matches!(command, BusCommand::Data(_)) {
+                    byte & 0x6F
+                } else {
+                    byte
+                };
+                assert_eq!(command.to_byte(), expected);
+            }
+        }
+        assert_eq!(BusCommand::from_byte(0x28), Some(BusCommand::Listen(8)));
+        assert_eq!(BusCommand::from_byte(0xF2), Some(BusCommand::Open(2)));
+        assert_eq!(BusCommand::from_byte(0xE2), Some(BusCommand::Close(2)));
+        assert_eq!(BusCommand::from_byte(0x90), None);
+    }
+
+    #[test]
+    fn save_and_load_through_commands() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"BUS", Some(*b"01")).unwrap();
+        let mut device = BusDevice::new(Drive::new(image), 8);
+
+        let attention = |device: &mut BusDevice<_>, commands: &[BusCommand]| {
+            commands.iter().for_each(|c| device.attention(c.to_byte()));
+        };
+        attention(&mut device, &[BusCommand::Listen(8), BusCommand::Open(1)]);
+        b"PROG".iter().for_each(|&b| device.receive(b));
+        attention(&mut device, &[BusCommand::Unlisten]);
+        attention(&mut device, &[BusCommand::Listen(8), BusCommand::Data(1)]);
+        [0x01, 0x08, 0x60].iter().for_each(|&b| device.receive(b));
+        attention(&mut device, &[BusCommand::Unlisten]);
+        attention(&mut device, &[BusCommand::Listen(8), BusCommand::Close(1)]);
+        attention(&mut device, &[BusCommand::Unlisten]);
+        assert_eq!(device.role(), Role::Idle);
+        let stored = fs::find_file(device.drive().image(), b"PROG")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.file_type, FileType::Prg);
+
+        attention(&mut device, &[BusCommand::Listen(9), BusCommand::Open(0)]);
+        assert_eq!(device.role(), Role::Idle);
+        attention(&mut device, &[BusCommand::Listen(8), BusCommand::Data(15)]);
+        b"S:PROG".iter().for_each(|&b| device.receive(b));
+        attention(&mut device, &[BusCommand::Unlisten]);
+        assert_eq!(device.drive().status().code, 1);
+
+        attention(&mut device, &[BusCommand::Listen(8), BusCommand::Open(0)]);
+        b"PROG".iter().for_each(|&b| device.receive(b));
+        attention(&mut device, &[BusCommand::Unlisten]);
+        attention(&mut device, &[BusCommand::Talk(8), BusCommand::Data(0)]);
+        assert_eq!(device.send(), None);
+        assert_eq!(
+            device.drive().status().error(),
+            Some(DosError::FileNotFound)
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Catalogs of image collections.
+//!
+//! A collection is a tree of directories holding disk and tape images,
+//! many of them zipped. [`scan`] walks such a tree, opens every image it
+//! recognizes, in ZIP archives too, and hands a [`Record`] of each to the
+//! caller as soon as it is made: the name and ID of the disk, its files,
+//! its CRC-32, the SHA-1 and SHA-256 of each file and each track, and what
+//! is wrong with it. Nothing is kept between images,
+//! so collections of any size are catalogued in constant memory; a
+//! [`Catalog`] collects the records for those who want them all, and is
+//! written as JSON through [`ToJson`].
+//!
+//! Files are picked by their extension (see [`EXTENSIONS`]) before they
+//! are read, and images by their contents after. [`record`] and
+//! [`archive`] do the same for images and archives already in memory.
+//!
+//! Sector errors are reported from the error blocks of D64 files. The
+//! tracks of G64 files are decoded once, for the hashes, but not searched
+//! for errors; their directory is read like that of any disk.
+
+use crate::d64::{self, D64};
+use crate::error::{DosError, DosStatus};
+use crate::fs::{self, FileType};
+use crate::g64::{self, G64};
+use crate::hash::{self, Hashes, crc32};
+use crate::image::{BlockDevice, SECTOR_SIZE};
+use crate::json::{ToJson, Writer};
+use crate::t64;
+use crate::tap::kernal::Thresholds;
+use crate::tap::turbo::Registry;
+use crate::tap::{self, Tap, extract};
+use crate::zip;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// The extensions of the files [`scan`] reads, in lower case.
+pub const EXTENSIONS: [&str; 5] = ["d64", "g64", "t64", "tap", "zip"];
+
+/// The format of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    D64,
+    G64,
+    T64,
+    Tap,
+}
+
+impl Format {
+    /// Returns the format of the image `bytes`, if it is one: G64, TAP and
+    /// T64 files by their signatures, D64 files by their size.
+    pub fn recognize(bytes: &[u8]) -> Option<Format> {
+        if bytes.starts_with(g64::SIGNATURE) {
+            Some(Format::G64)
+        } else if bytes.starts_with(tap::SIGNATURE) {
+            Some(Format::Tap)
+        } else if bytes.starts_with(b"C64") {
+            Some(Format::T64)
+        } else {
+            let sectors = [d64::SECTORS_35, d64::SECTORS_40];
+            sectors
+                .iter()
+                .any(|&n| bytes.len() == n * SECTOR_SIZE || bytes.len() == n * (SECTOR_SIZE + 1))
+                .then_some(Format::D64)
+        }
+    }
+
+    /// Returns the usual extension of the format in upper case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::D64 => "D64",
+            Format::G64 => "G64",
+            Format::T64 => "T64",
+            Format::Tap => "TAP",
+        }
+    }
+}
+
+/// A file on a catalogued image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogFile {
+    /// The name, without padding.
+    pub name: Vec<u8>,
+    /// The type as [`fs::Directory::type_name`] gives it, `PRG` for the
+    /// programs on tapes.
+    pub file_type: &'static str,
+    /// The size in blocks, for tapes the blocks the file would take on a
+    /// disk.
+    pub blocks: u16,
+    /// The hashes of the contents, unless the file cannot be read.
+    pub hashes: Option<Hashes>,
+}
+
+/// What a catalog records about an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The path of the file, and for members of archives the path within
+    /// the archive behind it.
+    pub path: String,
+    pub format: Format,
+    /// The size of the file in bytes.
+    pub size: usize,
+    /// The CRC-32 of the whole file.
+    pub crc32: u32,
+    /// The disk or tape name, without padding; empty for TAP files.
+    pub name: Vec<u8>,
+    /// The disk ID, empty for tapes.
+    pub id: Vec<u8>,
+    /// The blocks free of disks.
+    pub blocks_free: Option<u16>,
+    pub files: Vec<CatalogFile>,
+    /// The hashes of every track of disks, counted from track 1, see
+    /// [`hash::tracks`]; empty for tapes.
+    pub tracks: Vec<Hashes>,
+    /// What is wrong with the image: sector errors as the error channel
+    /// reports them, defects of the container and files that fail their
+    /// checksums.
+    pub errors: Vec<String>,
+}
+
+/// A file that could not be catalogued.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub path: String,
+    pub error: String,
+}
+
+/// What a scan found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Image(Record),
+    Failure(Failure),
+}
+
+fn trim(name: &[u8]) -> Vec<u8> {
+    let end = name
+        .iter()
+        .rposition(|&b| b != b' ' && b != 0xA0)
+        .map_or(0, |i| i + 1);
+    name[..end].to_vec()
+}
+
+fn tape_blocks(size: usize) -> u16 {
+    size.div_ceil(SECTOR_SIZE - 2).min(usize::from(u16::MAX)) as u16
+}
+
+fn dos_error(error: DosError) -> String {
+    DosStatus::from_error(error, 0, 0).to_string()
+}
+
+fn catalog_disk<I: BlockDevice>(image: &I, record: &mut Record) {
+    let provenance = hash::provenance(image);
+    record.tracks = provenance.tracks;
+    match fs::read_directory(image) {
+        Ok(directory) => {
+            record.name = fs::trim_name(&directory.name).to_vec();
+            record.id = directory.id.to_vec();
+            record.blocks_free = Some(directory.blocks_free);
+            record.files = directory
+                .entries
+                .iter()
+                .map(|entry| CatalogFile {
+                    name: entry.name().to_vec(),
+                    file_type: directory.type_name(entry),
+                    blocks: entry.blocks,
+                    hashes: provenance
+                        .files
+                        .iter()
+                        .find(|(hashed, _)| hashed == entry)
+                        .map(|&(_, hashes)| hashes),
+                })
+                .collect();
+        }
+        Err(error) => record.errors.push(dos_error(error)),
+    }
+}
+
+/// Returns the record of the image `bytes` found at `path`, or `None` if
+/// it is no image [`Format::recognize`] knows.
+pub fn record(path: &str, bytes: &[u8]) -> Option<Record> {
+    let format = Format::recognize(bytes)?;
+    let mut record = Record {
+        path: path.to_string(),
+        format,
+        size: bytes.len(),
+        crc32: crc32(bytes),
+        name: Vec::new(),
+        id: Vec::new(),
+        blocks_free: None,
+        files: Vec::new(),
+        tracks: Vec::new(),
+        errors: Vec::new(),
+    };
+    match format {
+        Format::D64 => match D64::from_bytes(bytes) {
+            Ok(image) => {
+                if image.error_bytes().is_some() {
+                    for track in 1..=image.tracks() {
+                        for sector in 0..image.sectors_per_track(track) {
+                            if let Some(error) = image.sector_error(track, sector) {
+                                let status = DosStatus::from_error(error, track, sector);
+                                record.errors.push(status.to_string());
+                            }
+                        }
+                    }
+                }
+                catalog_disk(&image, &mut record);
+            }
+            Err(error) => record.errors.push(error.to_string()),
+        },
+        Format::G64 => match G64::from_bytes(bytes) {
+            Ok(image) => catalog_disk(&image, &mut record),
+            Err(error) => record.errors.push(error.to_string()),
+        },
+        Format::T64 => match t64::repair(bytes) {
+            Ok((archive, fixes)) => {
+                record.name = trim(&archive.name);
+                record.errors.extend(fixes.iter().map(ToString::to_string));
+                record.files = archive
+                    .files
+                    .iter()
+                    .map(|file| CatalogFile {
+                        name: trim(&file.name),
+                        // Many archives leave the type zero; they hold programs.
+                        file_type: FileType::from_byte(file.file_type)
+                            .filter(|&t| t != FileType::Del)
+                            .map_or("PRG", FileType::as_str),
+                        blocks: tape_blocks(file.data.len() + 2),
+                        hashes: Some(Hashes::of(&file.prg().to_bytes())),
+                    })
+                    .collect();
+            }
+            Err(error) => record.errors.push(error.to_string()),
+        },
+        Format::Tap => match Tap::from_bytes(bytes) {
+            Ok(tape) => {
+                let files = extract::extract(&tape, &Thresholds::KERNAL, &Registry::standard());
+                for file in &files {
+                    let name = trim(&file.name);
+                    if !file.verified {
+                        let shown = String::from_utf8_lossy(&name);
+                        record.errors.push(format!("{shown}: checksum mismatch"));
+                    }
+                    record.files.push(CatalogFile {
+                        name,
+                        file_type: "PRG",
+                        blocks: tape_blocks(file.prg.body.len() + 2),
+                        hashes: Some(Hashes::of(&file.prg.to_bytes())),
+                    });
+                }
+            }
+            Err(error) => record.errors.push(error.to_string()),
+        },
+    }
+    Some(record)
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    path.rsplit_once('.')
+        .is_some_and(|(_, actual)| actual.eq_ignore_ascii_case(extension))
+}
+
+/// Whether `path` has one of the [`EXTENSIONS`].
+pub fn is_candidate(path: &str) -> bool {
+    EXTENSIONS
+        .iter()
+        .any(|extension| has_extension(path, extension))
+}
+
+/// Passes the records of the images in the ZIP archive `bytes` found at
+/// `path` to `found`, with the path of each member behind that of the
+/// archive. Members that cannot be unpacked are reported as failures;
+/// archives within the archive are not opened.
+///
+/// # Errors
+/// Returns the [`zip::ZipError`] if the archive cannot be read at all.
+pub fn archive(path: &str, bytes: &[u8], found: &mut dyn FnMut(Item)) -> Result<(), zip::ZipError> {
+    for entry in zip::entries(bytes)? {
+        if entry.is_dir() || !is_candidate(&entry.name) || has_extension(&entry.name, "zip") {
+            continue;
+        }
+        let member = format!("{path}/{}", entry.name);
+        match entry.data() {
+            Ok(data) => {
+                if let Some(record) = record(&member, &data) {
+                    found(Item::Image(record));
+                }
+            }
+            Err(error) => found(Item::Failure(Failure {
+                path: member,
+                error: error.to_string(),
+            })),
+        }
+    }
+    Ok(())
+}
+
+/// Walks the directory tree under `root`, in file name order, and passes
+/// what it finds to `found`: the record of every image, in ZIP archives
+/// too, and every candidate file that could not be read. Paths are given
+/// relative to `root`. Symbolic links to directories are not followed.
+///
+/// # Errors
+/// Returns the error if `root` cannot be listed; errors further down the
+/// tree are passed on as failures.
+#[cfg(feature = "std")]
+pub fn scan(root: &std::path::Path, mut found: impl FnMut(Item)) -> std::io::Result<()> {
+    let mut pending = alloc::vec![root.to_path_buf()];
+    let mut first = true;
+    while let Some(directory) = pending.pop() {
+        let relative = |path: &std::path::Path| {
+            path.strip_prefix(root)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let listing = std::fs::read_dir(&directory).and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let mut paths = match listing {
+            Ok(paths) => paths,
+            Err(error) if first => return Err(error),
+            Err(error) => {
+                found(Item::Failure(Failure {
+                    path: relative(&directory),
+                    error: error.to_string(),
+                }));
+                continue;
+            }
+        };
+        first = false;
+        paths.sort();
+        // Subdirectories are pushed in reverse so the walk visits them in
+        // order.
+        let mut subdirectories = Vec::new();
+        for path in paths {
+            let Ok(file_type) = std::fs::symlink_metadata(&path).map(|m| m.file_type()) else {
+                continue;
+            };
+            if file_type.is_dir() {
+                subdirectories.push(path);
+                continue;
+            }
+            let name = relative(&path);
+            if !is_candidate(&name) || !path.is_file() {
+                continue;
+            }
+            let bytes = match std::fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    let error = error.to_string();
+                    found(Item::Failure(Failure { path: name, error }));
+                    continue;
+                }
+            };
+            if has_extension(&name, "zip") {
+                if let Err(error) = archive(&name, &bytes, &mut found) {
+                    let error = error.to_string();
+                    found(Item::Failure(Failure { path: name, error }));
+                }
+            } else if let Some(record) = record(&name, &bytes) {
+                found(Item::Image(record));
+            }
+        }
+        pending.extend(subdirectories.into_iter().rev());
+    }
+    Ok(())
+}
+
+/// The records of a collection, and what could not be catalogued.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    pub images: Vec<Record>,
+    pub failures: Vec<Failure>,
+}
+
+impl Catalog {
+    /// Adds what a scan found.
+    pub fn push(&mut self, item: Item) {
+        match item {
+            Item::Image(record) => self.images.push(record),
+            Item::Failure(failure) => self.failures.push(failure),
+        }
+    }
+
+    /// Catalogs the tree under `root`, see [`scan`].
+    ///
+    /// # Errors
+    /// Returns the error if `root` cannot be listed.
+    #[cfg(feature = "std")]
+    pub fn scan(root: &std::path::Path) -> std::io::Result<Self> {
+        let mut catalog = Catalog::default();
+        scan(root, |item| catalog.push(item))?;
+        Ok(catalog)
+    }
+}
+
+impl ToJson for Format {
+    fn write_json(&self, json: &mut Writer) {
+        json.string(self.as_str());
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The SHA-1 and SHA-256 as lower-case hex, `null` for files that cannot
+/// be read.
+impl ToJson for CatalogFile {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object();
+        json.key("name").name(&self.name);
+        json.field("type", self.file_type)
+            .field("blocks", &self.blocks)
+            .field("sha1", &self.hashes.map(|h| hex(&h.sha1)))
+            .field("sha256", &self.hashes.map(|h| hex(&h.sha256)))
+            .end_object();
+    }
+}
+
+/// The CRC-32 is written as eight hex digits, as DAT files list it, and
+/// tracks as objects with their number, SHA-1 and SHA-256.
+impl ToJson for Record {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("path", &self.path)
+            .field("format", &self.format)
+            .field("size", &self.size)
+            .field("crc32", &format!("{:08x}", self.crc32));
+        json.key("name").name(&self.name);
+        json.key("id").name(&self.id);
+        json.field("blocks_free", &self.blocks_free)
+            .field("files", &self.files);
+        json.key("tracks").begin_array();
+        for (track, hashes) in (1u8..).zip(&self.tracks) {
+            json.begin_object()
+                .field("track", &track)
+                .field("sha1", &hex(&hashes.sha1))
+                .field("sha256", &hex(&hashes.sha256))
+                .end_object();
+        }
+        json.end_array().field("errors", &self.errors).end_object();
+    }
+}
+
+impl ToJson for Failure {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("path", &self.path)
+            .field("error", &self.error)
+            .end_object();
+    }
+}
+
+impl ToJson for Catalog {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("images", &self.images)
+            .field("failures", &self.failures)
+            .end_object();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prg::Prg;
+    use crate::t64::{T64, T64File};
+
+    fn disk() -> D64 {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"COLLECTION", Some(*b"C1")).unwrap();
+        fs::write_file(&mut image, b"GAME", FileType::Prg, &[1, 8, 0x60]).unwrap();
+        image
+    }
+
+    #[test]
+    fn records_disks_and_tapes() {
+        let mut image = disk();
+        image
+            .set_sector_error(18, 5, Some(DosError::DataChecksum))
+            .unwrap();
+        let bytes = image.to_bytes();
+        let record = record("games/a.d64", &bytes).unwrap();
+        assert_eq!((record.format, record.size), (Format::D64, 175531));
+        assert_eq!(
+            (&record.name[..], &record.id[..]),
+            (&b"COLLECTION"[..], &b"C1"[..])
+        );
+        assert_eq!(record.crc32, crc32(&bytes));
+        assert_eq!(
+            record.files,
+            [CatalogFile {
+                name: b"GAME".to_vec(),
+                file_type: "PRG",
+                blocks: 1,
+                hashes: Some(Hashes::of(&[1, 8, 0x60]))
+            }]
+        );
+        assert_eq!(record.errors, ["23,READ ERROR,18,05"]);
+
+        let tape = T64 {
+            version: 0x0100,
+            name: *b"TAPE                    ",
+            files: alloc::vec![T64File::from_prg(
+                b"INTRO",
+                &Prg::new(0x0801, alloc::vec![0; 300])
+            )],
+        };
+        let record = super::record("a.t64", &tape.to_bytes()).unwrap();
+        assert_eq!(
+            (record.format, &record.name[..]),
+            (Format::T64, &b"TAPE"[..])
+        );
+        assert_eq!(
+            (&record.files[0].name[..], record.files[0].blocks),
+            (&b"INTRO"[..], 2)
+        );
+        assert_eq!(super::record("readme.txt", b"text"), None);
+        assert!(is_candidate("GAMES/A.D64") && !is_candidate("a.nfo"));
+    }
+
+    #[test]
+    fn catalogs_zipped_images_and_reports_failures() {
+        let bytes = disk().to_bytes();
+        let mut zip = Vec::new();
+        let mut central = Vec::new();
+        for (name, data, crc) in [
+            ("A.D64", &bytes[..], crc32(&bytes)),
+            ("B.D64", &bytes[..], 0),
+            ("INFO.NFO", &b"x"[..], crc32(b"x")),
+        ] {
+            let mut header = alloc::vec![20, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            header.extend_from_slice(&crc.to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0, 0]);
+            central.extend_from_slice(b"PK\x01\x02\x14\x00");
+            central.extend_from_slice(&header);
+            central.extend_from_slice(&[0; 10]);
+            central.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(b"PK\x03\x04");
+            zip.extend_from_slice(&header);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+        }
+        let offset = zip.len() as u32;
+        zip.extend_from_slice(&central);
+        zip.extend_from_slice(b"PK\x05\x06\0\0\0\0\x03\x00\x03\x00");
+        zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&offset.to_le_bytes());
+        zip.extend_from_slice(&[0, 0]);
+
+        let mut catalog = Catalog::default();
+        archive("set.zip", &zip, &mut |item| catalog.push(item)).unwrap();
+        assert_eq!(catalog.images.len(), 1);
+        assert_eq!(catalog.images[0].path, "set.zip/A.D64");
+        assert_eq!(
+            catalog.failures,
+            [Failure {
+                path: "set.zip/B.D64".into(),
+                error: "zip member fails its checksum".into()
+            }]
+        );
+        let json = catalog.to_json();
+        assert!(json.starts_with(r#"{"images":[{"path":"set.zip/A.D64","format":"D64","#));
+        assert!(json.contains(r#""name":"COLLECTION","id":"C1","blocks_free":663,"#));
+        let game = Hashes::of(&[1, 8, 0x60]);
+        let file = format!(
+            r#""files":[{{"name":"GAME","type":"PRG","blocks":1,"sha1":"{}","sha256":"{}"}}],"#,
+            hex(&game.sha1),
+            hex(&game.sha256)
+        );
+        assert!(json.contains(&file));
+        let tracks = &catalog.images[0].tracks;
+        assert_eq!(tracks.len(), 35);
+        let track = format!(r#"{{"track":35,"sha1":"{}","#, hex(&tracks[34].sha1));
+        assert!(json.contains(&format!(
+            r#"{track}"sha256":"{}"}}],"errors":[]"#,
+            hex(&tracks[34].sha256)
+        )));
+        assert_eq!(
+            archive("bad.zip", b"nothing", &mut |_| ()),
+            Err(zip::ZipError::NotAnArchive)
+        );
+    }
+}
@@ -0,0 +1,813 @@
+//! Parsing of DOS commands sent over the command channel.
+//!
+//! A command string such as `S0:OLD*` or `U1:2,0,18,1` is turned into a
+//! [`Command`] value. Like the drive, only the characters that distinguish a
+//! command are significant: `I`, `INIT` and `INITIALIZE` all parse to
+//! [`Command::Initialize`].
+
+use crate::error::DosError;
+use alloc::vec::Vec;
+
+/// Longest command the 1541 accepts in its command buffer.
+pub const MAX_COMMAND_LENGTH: usize = 58;
+
+/// A parsed DOS command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `I`: re-read the BAM and reset the drive's disk state.
+    Initialize { drive: Option<u8> },
+    /// `V`: rebuild the BAM from the directory.
+    Validate { drive: Option<u8> },
+    /// `N:NAME[,ID]`: format the disk, or clear the directory if no ID is
+    /// given.
+    New {
+        drive: Option<u8>,
+        name: Vec<u8>,
+        id: Option<[u8; 2]>,
+    },
+    /// `S:PATTERN[,PATTERN...]`: scratch matching files.
+    Scratch {
+        drive: Option<u8>,
+        patterns: Vec<Vec<u8>>,
+    },
+    /// `R:NEW=OLD`: rename a file.
+    Rename {
+        drive: Option<u8>,
+        new_name: Vec<u8>,
+        old_name: Vec<u8>,
+    },
+    /// `C:NEW=OLD[,OLD...]`: copy a file, concatenating several sources in
+    /// order.
+    Copy {
+        drive: Option<u8>,
+        new_name: Vec<u8>,
+        sources: Vec<Vec<u8>>,
+    },
+    /// `D:TARGET=SOURCE`: duplicate the disk in one drive unit to the other.
+    Duplicate { target: u8, source: u8 },
+    /// `B-R:channel,drive,track,sector`: read a block into a buffer,
+    /// honouring the length byte at its start.
+    BlockRead(BlockAddress),
+    /// `B-W:channel,drive,track,sector`: write a buffer to a block,
+    /// storing the buffer pointer as length byte.
+    BlockWrite(BlockAddress),
+    /// `U1`/`UA`: read a full block into a buffer.
+    UserRead(BlockAddress),
+    /// `U2`/`UB`: write a full buffer to a block.
+    UserWrite(BlockAddress),
+    /// `B-E:channel,drive,track,sector`: load a block and execute it.
+    BlockExecute(BlockAddress),
+    /// `B-A:drive,track,sector`: allocate a block in the BAM.
+    BlockAllocate { drive: u8, track: u8, sector: u8 },
+    /// `B-F:drive,track,sector`: free a block in the BAM.
+    BlockFree { drive: u8, track: u8, sector: u8 },
+    /// `B-P:channel,position`: set the buffer pointer of a channel.
+    BufferPointer { channel: u8, position: u8 },
+    /// `P`: position a relative file channel on a record.
+    ///
+    /// `record` and `offset` are 1-based as sent; the drive treats 0 like 1.
+    Position {
+        channel: u8,
+        record: u16,
+        offset: u8,
+    },
+    /// `M-R`: read drive memory through the command channel.
+    MemoryRead { address: u16, length: u8 },
+    /// `M-W`: write drive memory.
+    MemoryWrite { address: u16, data: Vec<u8> },
+    /// `M-E`: execute code in drive memory.
+    MemoryExecute { address: u16 },
+    /// `U3`-`U8` (`UC`-`UH`): jump through the user vector table.
+    UserJump(u8),
+    /// `U9`/`UI`: warm reset through the NMI vector.
+    WarmReset,
+    /// `U:`/`UJ`: cold reset of the drive.
+    Reset,
+    /// `U0` followed by a command byte: a 1571/1581 burst command.
+    Burst(BurstCommand),
+    /// `CD[partition]:PATH`: change the current directory; `←` goes to the
+    /// parent and `//` to the root directory.
+    ChangeDirectory {
+        partition: Option<u8>,
+        path: Vec<u8>,
+    },
+    /// `MD[partition]:NAME`: create a subdirectory.
+    MakeDirectory {
+        partition: Option<u8>,
+        path: Vec<u8>,
+    },
+    /// `RD[partition]:NAME`: remove an empty subdirectory.
+    RemoveDirectory {
+        partition: Option<u8>,
+        path: Vec<u8>,
+    },
+    /// `T-R` plus format letter: read the real-time clock.
+    TimeRead(TimeFormat),
+    /// `T-W` plus format letter: set the real-time clock. `data` is the
+    /// time in that format as sent.
+    TimeWrite { format: TimeFormat, data: Vec<u8> },
+    /// `X`: an SD2IEC configuration command.
+    Config(ConfigCommand),
+    /// `E-R`: read the SD2IEC's configuration EEPROM.
+    EepromRead { address: u16, length: u8 },
+    /// `E-W`: write the SD2IEC's configuration EEPROM.
+    EepromWrite { address: u16, data: Vec<u8> },
+}
+
+/// The representation of the time used by `T-R` and `T-W`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeFormat {
+    /// `A`: text such as `SUN. 01/20/08 01:23:45 PM`.
+    Ascii,
+    /// `B`: weekday, year, month, day, hour, minute, second and AM/PM as
+    /// BCD bytes.
+    Bcd,
+    /// `D`: the same eight fields as binary bytes.
+    Decimal,
+    /// `I`: ISO 8601 text such as `2008-01-20T13:23:45 SUN` (SD2IEC only).
+    Iso,
+}
+
+/// An SD2IEC `X` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// `X` alone: report the configuration in the status.
+    Show,
+    /// `XE0`-`XE4`: how PC file extensions map to CBM file types.
+    ExtensionMode(u8),
+    /// `XE+`/`XE-`: whether x00 extensions are written for new files.
+    ExtensionHiding(bool),
+    /// `XR:NAME`: the ROM file loaded for fast loader detection.
+    RomFile(Vec<u8>),
+    /// `XW`: store the configuration in the EEPROM.
+    Store,
+    /// Any other `X` command, with the bytes following the `X`.
+    Other(Vec<u8>),
+}
+
+/// A burst command as sent by the C128 to a 1571 or 1581.
+///
+/// Burst commands consist of `U0`, a command byte and its parameters. The
+/// command byte carries the command number in bits 1-4, the drive number in
+/// bit 0 and command-specific flags in bits 5-7.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BurstCommand {
+    /// Read `count` sectors starting at `track`/`sector`.
+    Read {
+        side: u8,
+        track: u8,
+        sector: u8,
+        count: u8,
+        /// Transfer data even if a sector reports an error.
+        ignore_errors: bool,
+    },
+    /// Write `count` sectors starting at `track`/`sector`.
+    Write {
+        side: u8,
+        track: u8,
+        sector: u8,
+        count: u8,
+        ignore_errors: bool,
+    },
+    /// Check whether a disk is present and readable.
+    InquireDisk { side: u8 },
+    /// Format the disk; the parameter bytes depend on `mfm`.
+    Format {
+        mfm: bool,
+        side: u8,
+        parameters: Vec<u8>,
+    },
+    /// Set the interleave used by fast loads, or report it if `value` is
+    /// `None`.
+    Interleave { value: Option<u8> },
+    /// Report the physical format of the disk.
+    QueryDiskFormat { side: u8, force: bool },
+    /// Report, and optionally change, the last controller status.
+    InquireStatus { parameters: Vec<u8> },
+    /// `CHGUTL`: a utility sub-command such as a device number change.
+    Utility { parameters: Vec<u8> },
+    /// Load a file using burst transfers.
+    FastLoad { name: Vec<u8>, sequential: bool },
+}
+
+/// Channel, drive and location arguments of the block commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockAddress {
+    pub channel: u8,
+    pub drive: u8,
+    pub track: u8,
+    pub sector: u8,
+}
+
+/// Parses a command string as received on channel 15.
+///
+/// A trailing carriage return is ignored.
+///
+/// # Errors
+/// - [`DosError::LongLine`] if the command exceeds 58 characters.
+/// - [`DosError::InvalidCommand`] if the command letter is unknown.
+/// - [`DosError::Syntax`] or [`DosError::NoFileGiven`] for malformed
+///   arguments.
+///
+/// # Example
+/// ```
+/// use cbm_dos::command::{parse, Command};
+///
+/// assert_eq!(
+///     parse(b"S0:OLD*\r").unwrap(),
+///     Command::Scratch { drive: Some(0), patterns: vec![b"OLD*".to_vec()] }
+/// );
+/// ```
+pub fn parse(input: &[u8]) -> Result<Command, DosError> {
+    let input = input.strip_suffix(b"\r").unwrap_or(input);
+    if input.len() > MAX_COMMAND_LENGTH {
+        return Err(DosError::LongLine);
+    }
+    let Some(&first) = input.first() else {
+        return Err(DosError::InvalidCommand);
+    };
+    match first {
+        b'C' | b'M' | b'R' if input.get(1) == Some(&b'D') => parse_directory(input),
+        b'I' => Ok(Command::Initialize {
+            drive: drive_suffix(input),
+        }),
+        b'V' => Ok(Command::Validate {
+            drive: drive_suffix(input),
+        }),
+        b'N' => parse_new(input),
+        b'S' => parse_scratch(input),
+        b'R' => parse_rename(input),
+        b'C' => parse_copy(input),
+        b'D' => parse_duplicate(input),
+        b'B' => parse_block(input),
+        b'M' => parse_memory(input),
+        b'P' => parse_position(input),
+        b'U' => parse_user(input),
+        b'T' => parse_time(input),
+        b'X' => parse_config(input),
+        b'E' => parse_eeprom(input),
+        _ => Err(DosError::InvalidCommand),
+    }
+}
+
+/// Splits `input` at its first colon into the drive number in front of it
+/// and the argument text behind it.
+pub(crate) fn split_drive(input: &[u8]) -> (Option<u8>, &[u8]) {
+    match input.iter().position(|&b| b == b':') {
+        Some(colon) => {
+            let drive = colon
+                .checked_sub(1)
+                .map(|i| input[i])
+                .filter(u8::is_ascii_digit)
+                .map(|d| d - b'0');
+            (drive, &input[colon + 1..])
+        }
+        None => (None, &[]),
+    }
+}
+
+fn drive_suffix(input: &[u8]) -> Option<u8> {
+    input.iter().find(|b| b.is_ascii_digit()).map(|d| d - b'0')
+}
+
+/// Strips a leading `0:`-style drive prefix from a filename.
+pub(crate) fn strip_drive(name: &[u8]) -> (Option<u8>, &[u8]) {
+    match name {
+        [d, b':', rest @ ..] if d.is_ascii_digit() => (Some(d - b'0'), rest),
+        [b':', rest @ ..] => (None, rest),
+        _ => (None, name),
+    }
+}
+
+fn parse_new(input: &[u8]) -> Result<Command, DosError> {
+    let (drive, args) = split_drive(input);
+    if args.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let name = parts.next().unwrap_or_default().to_vec();
+    let id = match parts.next() {
+        Some([a, b, ..]) => Some([*a, *b]),
+        Some([a]) => Some([*a, b' ']),
+        _ => None,
+    };
+    Ok(Command::New { drive, name, id })
+}
+
+fn parse_scratch(input: &[u8]) -> Result<Command, DosError> {
+    let (drive, args) = split_drive(input);
+    if args.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    let patterns = args
+        .split(|&b| b == b',')
+        .map(|p| strip_drive(p).1.to_vec())
+        .collect();
+    Ok(Command::Scratch { drive, patterns })
+}
+
+fn parse_rename(input: &[u8]) -> Result<Command, DosError> {
+    let (drive, args) = split_drive(input);
+    let eq = args
+        .iter()
+        .position(|&b| b == b'=')
+        .ok_or(DosError::NoFileGiven)?;
+    let new_name = args[..eq].to_vec();
+    let old_name = strip_drive(&args[eq + 1..]).1.to_vec();
+    if new_name.is_empty() || old_name.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    Ok(Command::Rename {
+        drive,
+        new_name,
+        old_name,
+    })
+}
+
+fn parse_copy(input: &[u8]) -> Result<Command, DosError> {
+    let (drive, args) = split_drive(input);
+    let eq = args
+        .iter()
+        .position(|&b| b == b'=')
+        .ok_or(DosError::NoFileGiven)?;
+    let new_name = args[..eq].to_vec();
+    let sources: Vec<_> = args[eq + 1..]
+        .split(|&b| b == b',')
+        .map(|s| strip_drive(s).1.to_vec())
+        .collect();
+    if new_name.is_empty() || sources.iter().any(Vec::is_empty) {
+        return Err(DosError::NoFileGiven);
+    }
+    Ok(Command::Copy {
+        drive,
+        new_name,
+        sources,
+    })
+}
+
+fn parse_duplicate(input: &[u8]) -> Result<Command, DosError> {
+    let eq = input
+        .iter()
+        .position(|&b| b == b'=')
+        .ok_or(DosError::Syntax)?;
+    let target = input[..eq].iter().rev().find(|b| b.is_ascii_digit());
+    let source = input[eq + 1..].iter().find(|b| b.is_ascii_digit());
+    match (target, source) {
+        (Some(t), Some(s)) => Ok(Command::Duplicate {
+            target: t - b'0',
+            source: s - b'0',
+        }),
+        _ => Err(DosError::Syntax),
+    }
+}
+
+/// Extracts the numeric parameters of a block or user command.
+///
+/// Parameters follow the colon, or the command word if there is none, and
+/// may be separated by spaces, commas or cursor-right characters.
+fn numeric_args(input: &[u8], word_len: usize) -> Result<Vec<u8>, DosError> {
+    let args = match input.iter().position(|&b| b == b':') {
+        Some(colon) => &input[colon + 1..],
+        None => {
+            let end = input
+                .iter()
+                .skip(word_len)
+                .position(|&b| matches!(b, b' ' | b',' | 0x1D))
+                .map_or(input.len(), |p| p + word_len);
+            &input[end..]
+        }
+    };
+    args.split(|&b| matches!(b, b' ' | b',' | 0x1D))
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            core::str::from_utf8(p)
+                .ok()
+                .and_then(|s| s.parse::<u8>().ok())
+                .ok_or(DosError::Syntax)
+        })
+        .collect()
+}
+
+fn block_address(input: &[u8], word_len: usize) -> Result<BlockAddress, DosError> {
+    match numeric_args(input, word_len)?[..] {
+        [channel, drive, track, sector, ..] => Ok(BlockAddress {
+            channel,
+            drive,
+            track,
+            sector,
+        }),
+        _ => Err(DosError::Syntax),
+    }
+}
+
+fn parse_block(input: &[u8]) -> Result<Command, DosError> {
+    let dash = input
+        .iter()
+        .position(|&b| b == b'-')
+        .ok_or(DosError::InvalidCommand)?;
+    let kind = *input.get(dash + 1).ok_or(DosError::InvalidCommand)?;
+    let word_len = dash + 2;
+    match kind {
+        b'R' => Ok(Command::BlockRead(block_address(input, word_len)?)),
+        b'W' => Ok(Command::BlockWrite(block_address(input, word_len)?)),
+        b'E' => Ok(Command::BlockExecute(block_address(input, word_len)?)),
+        b'A' | b'F' => match numeric_args(input, word_len)?[..] {
+            [drive, track, sector, ..] if kind == b'A' => Ok(Command::BlockAllocate {
+                drive,
+                track,
+                sector,
+            }),
+            [drive, track, sector, ..] => Ok(Command::BlockFree {
+                drive,
+                track,
+                sector,
+            }),
+            _ => Err(DosError::Syntax),
+        },
+        b'P' => match numeric_args(input, word_len)?[..] {
+            [channel, position, ..] => Ok(Command::BufferPointer { channel, position }),
+            _ => Err(DosError::Syntax),
+        },
+        _ => Err(DosError::InvalidCommand),
+    }
+}
+
+fn parse_memory(input: &[u8]) -> Result<Command, DosError> {
+    let (kind, args) = match input {
+        [b'M', b'-', kind, args @ ..] => (*kind, args),
+        _ => return Err(DosError::InvalidCommand),
+    };
+    let address = transfer_address(args)?;
+    match kind {
+        b'R' => Ok(Command::MemoryRead {
+            address,
+            length: args.get(2).copied().unwrap_or(1),
+        }),
+        b'W' => Ok(Command::MemoryWrite {
+            address,
+            data: transfer_data(args)?,
+        }),
+        b'E' => Ok(Command::MemoryExecute { address }),
+        _ => Err(DosError::InvalidCommand),
+    }
+}
+
+/// Returns the little-endian address that starts the binary arguments of
+/// `M-` and `E-` commands.
+fn transfer_address(args: &[u8]) -> Result<u16, DosError> {
+    match args {
+        [lo, hi, ..] => Ok(u16::from_le_bytes([*lo, *hi])),
+        _ => Err(DosError::Syntax),
+    }
+}
+
+/// Returns the bytes of a memory write: a count after the address, then
+/// that many bytes.
+fn transfer_data(args: &[u8]) -> Result<Vec<u8>, DosError> {
+    let count = *args.get(2).ok_or(DosError::Syntax)? as usize;
+    Ok(args.get(3..3 + count).ok_or(DosError::Syntax)?.to_vec())
+}
+
+fn parse_eeprom(input: &[u8]) -> Result<Command, DosError> {
+    let (kind, args) = match input {
+        [b'E', b'-', kind, args @ ..] => (*kind, args),
+        _ => return Err(DosError::InvalidCommand),
+    };
+    let address = transfer_address(args)?;
+    match kind {
+        b'R' => Ok(Command::EepromRead {
+            address,
+            length: args.get(2).copied().unwrap_or(1),
+        }),
+        b'W' => Ok(Command::EepromWrite {
+            address,
+            data: transfer_data(args)?,
+        }),
+        _ => Err(DosError::InvalidCommand),
+    }
+}
+
+fn parse_directory(input: &[u8]) -> Result<Command, DosError> {
+    let (partition, path) = match input.iter().position(|&b| b == b':') {
+        Some(_) => split_drive(input),
+        None => (None, &input[2..]),
+    };
+    let path = path.to_vec();
+    if path.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    Ok(match input[0] {
+        b'C' => Command::ChangeDirectory { partition, path },
+        b'M' => Command::MakeDirectory { partition, path },
+        _ => Command::RemoveDirectory { partition, path },
+    })
+}
+
+fn parse_time(input: &[u8]) -> Result<Command, DosError> {
+    let [b'T', b'-', kind, letter, data @ ..] = input else {
+        return Err(DosError::InvalidCommand);
+    };
+    let format = match letter {
+        b'A' => TimeFormat::Ascii,
+        b'B' => TimeFormat::Bcd,
+        b'D' => TimeFormat::Decimal,
+        b'I' => TimeFormat::Iso,
+        _ => return Err(DosError::Syntax),
+    };
+    match kind {
+        b'R' => Ok(Command::TimeRead(format)),
+        b'W' if !data.is_empty() => Ok(Command::TimeWrite {
+            format,
+            data: data.to_vec(),
+        }),
+        b'W' => Err(DosError::Syntax),
+        _ => Err(DosError::InvalidCommand),
+    }
+}
+
+fn parse_config(input: &[u8]) -> Result<Command, DosError> {
+    let config = match &input[1..] {
+        [] => ConfigCommand::Show,
+        [b'E', b'+'] => ConfigCommand::ExtensionHiding(true),
+        [b'E', b'-'] => ConfigCommand::ExtensionHiding(false),
+        [b'E', mode @ b'0'..=b'4'] => ConfigCommand::ExtensionMode(mode - b'0'),
+        [b'E', ..] => return Err(DosError::Syntax),
+        [b'R', rest @ ..] => {
+            let name = strip_drive(rest).1;
+            if name.is_empty() {
+                return Err(DosError::NoFileGiven);
+            }
+            ConfigCommand::RomFile(name.to_vec())
+        }
+        [b'W'] => ConfigCommand::Store,
+        rest => ConfigCommand::Other(rest.to_vec()),
+    };
+    Ok(Command::Config(config))
+}
+
+fn parse_position(input: &[u8]) -> Result<Command, DosError> {
+    let channel = *input.get(1).ok_or(DosError::Syntax)?;
+    let byte = |i: usize| input.get(i).copied().unwrap_or(0);
+    Ok(Command::Position {
+        channel: channel & 0x0F,
+        record: u16::from_le_bytes([byte(2), byte(3)]),
+        offset: byte(4),
+    })
+}
+
+fn parse_burst(args: &[u8]) -> Result<Command, DosError> {
+    let (&command, rest) = args.split_first().ok_or(DosError::Syntax)?;
+    let side = (command >> 5) & 1;
+    let flag = command & 0x80 != 0;
+    let burst = match command & 0x1F {
+        0x1E => BurstCommand::Utility {
+            parameters: rest.to_vec(),
+        },
+        0x1F => BurstCommand::FastLoad {
+            name: rest.to_vec(),
+            sequential: flag,
+        },
+        selector => match selector >> 1 {
+            0 | 1 => {
+                let [track, sector, ..] = rest[..] else {
+                    return Err(DosError::Syntax);
+                };
+                let count = rest.get(2).copied().unwrap_or(1);
+                if selector >> 1 == 0 {
+                    BurstCommand::Read {
+                        side,
+                        track,
+                        sector,
+                        count,
+                        ignore_errors: flag,
+                    }
+                } else {
+                    BurstCommand::Write {
+                        side,
+                        track,
+                        sector,
+                        count,
+                        ignore_errors: flag,
+                    }
+                }
+            }
+            2 => BurstCommand::InquireDisk { side },
+            3 => BurstCommand::Format {
+                mfm: flag,
+                side,
+                parameters: rest.to_vec(),
+            },
+            4 if flag => BurstCommand::Interleave { value: None },
+            4 => BurstCommand::Interleave {
+                value: Some(*rest.first().ok_or(DosError::Syntax)?),
+            },
+            5 => BurstCommand::QueryDiskFormat { side, force: flag },
+            6 => BurstCommand::InquireStatus {
+                parameters: rest.to_vec(),
+            },
+            _ => return Err(DosError::InvalidCommand),
+        },
+    };
+    Ok(Command::Burst(burst))
+}
+
+fn parse_user(input: &[u8]) -> Result<Command, DosError> {
+    let Some(&selector) = input.get(1) else {
+        return Err(DosError::InvalidCommand);
+    };
+    if selector == b'0' {
+        return parse_burst(&input[2..]);
+    }
+    let number = match selector {
+        b'1'..=b'9' => selector - b'0',
+        b'A'..=b'J' => selector - b'A' + 1,
+        b':' => 10,
+        _ => return Err(DosError::InvalidCommand),
+    };
+    match number {
+        1 => Ok(Command::UserRead(block_address(input, 2)?)),
+        2 => Ok(Command::UserWrite(block_address(input, 2)?)),
+        3..=8 => Ok(Command::UserJump(number)),
+        9 => Ok(Command::WarmReset),
+        _ => Ok(Command::Reset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_file_commands() {
+        assert_eq!(
+            parse(b"INITIALIZE").unwrap(),
+            Command::Initialize { drive: None }
+        );
+        assert_eq!(
+            parse(b"N0:GAMES,G1").unwrap(),
+            Command::New {
+                drive: Some(0),
+                name: b"GAMES".to_vec(),
+                id: Some(*b"G1")
+            }
+        );
+        assert_eq!(
+            parse(b"R:NEW=0:OLD").unwrap(),
+            Command::Rename {
+                drive: None,
+                new_name: b"NEW".to_vec(),
+                old_name: b"OLD".to_vec()
+            }
+        );
+        assert_eq!(
+            parse(b"C0:ALL=0:PART1,PART2").unwrap(),
+            Command::Copy {
+                drive: Some(0),
+                new_name: b"ALL".to_vec(),
+                sources: vec![b"PART1".to_vec(), b"PART2".to_vec()]
+            }
+        );
+        assert_eq!(
+            parse(b"D:1=0").unwrap(),
+            Command::Duplicate {
+                target: 1,
+                source: 0
+            }
+        );
+        assert_eq!(parse(b"Q"), Err(DosError::InvalidCommand));
+        assert_eq!(parse(b"S"), Err(DosError::NoFileGiven));
+        assert_eq!(parse(b"C:COPY="), Err(DosError::NoFileGiven));
+    }
+
+    #[test]
+    fn parses_block_commands() {
+        let address = BlockAddress {
+            channel: 2,
+            drive: 0,
+            track: 18,
+            sector: 1,
+        };
+        assert_eq!(parse(b"U1:2,0,18,1").unwrap(), Command::UserRead(address));
+        assert_eq!(parse(b"U1 2 0 18 1").unwrap(), Command::UserRead(address));
+        assert_eq!(parse(b"B-R 2 0 18 1").unwrap(), Command::BlockRead(address));
+        assert_eq!(
+            parse(b"B-P:2,0").unwrap(),
+            Command::BufferPointer {
+                channel: 2,
+                position: 0
+            }
+        );
+        assert_eq!(
+            parse(&[b'M', b'-', b'W', 0x00, 0x05, 2, 0xEA, 0x60]).unwrap(),
+            Command::MemoryWrite {
+                address: 0x0500,
+                data: vec![0xEA, 0x60]
+            }
+        );
+        assert_eq!(
+            parse(&[b'P', 0x62, 0x2C, 0x01, 5]).unwrap(),
+            Command::Position {
+                channel: 2,
+                record: 300,
+                offset: 5
+            }
+        );
+        assert_eq!(parse(b"UJ").unwrap(), Command::Reset);
+    }
+
+    #[test]
+    fn parses_extension_commands() {
+        assert_eq!(
+            parse(b"CD:GAMES").unwrap(),
+            Command::ChangeDirectory {
+                partition: None,
+                path: b"GAMES".to_vec()
+            }
+        );
+        assert_eq!(
+            parse(b"CD\x5F").unwrap(),
+            Command::ChangeDirectory {
+                partition: None,
+                path: b"\x5F".to_vec()
+            }
+        );
+        assert_eq!(
+            parse(b"MD1:NEW").unwrap(),
+            Command::MakeDirectory {
+                partition: Some(1),
+                path: b"NEW".to_vec()
+            }
+        );
+        assert_eq!(parse(b"RD:"), Err(DosError::NoFileGiven));
+        assert!(matches!(parse(b"R:NEW=OLD"), Ok(Command::Rename { .. })));
+        assert!(matches!(parse(b"C:NEW=OLD"), Ok(Command::Copy { .. })));
+
+        assert_eq!(parse(b"T-RI").unwrap(), Command::TimeRead(TimeFormat::Iso));
+        assert_eq!(
+            parse(&[b'T', b'-', b'W', b'D', 0, 8, 1, 20, 1, 23, 45, 1]).unwrap(),
+            Command::TimeWrite {
+                format: TimeFormat::Decimal,
+                data: vec![0, 8, 1, 20, 1, 23, 45, 1]
+            }
+        );
+        assert_eq!(parse(b"T-RX"), Err(DosError::Syntax));
+
+        assert_eq!(
+            parse(b"XE+").unwrap(),
+            Command::Config(ConfigCommand::ExtensionHiding(true))
+        );
+        assert_eq!(
+            parse(b"XE2").unwrap(),
+            Command::Config(ConfigCommand::ExtensionMode(2))
+        );
+        assert_eq!(
+            parse(b"XR:DOS1541").unwrap(),
+            Command::Config(ConfigCommand::RomFile(b"DOS1541".to_vec()))
+        );
+        assert_eq!(parse(b"X").unwrap(), Command::Config(ConfigCommand::Show));
+
+        assert_eq!(
+            parse(&[b'E', b'-', b'R', 0x10, 0x00, 4]).unwrap(),
+            Command::EepromRead {
+                address: 0x0010,
+                length: 4
+            }
+        );
+        assert_eq!(
+            parse(&[b'E', b'-', b'W', 0x10, 0x00, 2, 0xAA, 0xBB]).unwrap(),
+            Command::EepromWrite {
+                address: 0x0010,
+                data: vec![0xAA, 0xBB]
+            }
+        );
+    }
+
+    #[test]
+    fn parses_burst_commands() {
+        assert_eq!(
+            parse(&[b'U', b'0', 0x80, 18, 0, 3]).unwrap(),
+            Command::Burst(BurstCommand::Read {
+                side: 0,
+                track: 18,
+                sector: 0,
+                count: 3,
+                ignore_errors: true
+            })
+        );
+        assert_eq!(
+            parse(&[b'U', b'0', 0x1F, b'G', b'A', b'M', b'E']).unwrap(),
+            Command::Burst(BurstCommand::FastLoad {
+                name: b"GAME".to_vec(),
+                sequential: false
+            })
+        );
+        assert_eq!(
+            parse(&[b'U', b'0', 0x0A]).unwrap(),
+            Command::Burst(BurstCommand::QueryDiskFormat {
+                side: 0,
+                force: false
+            })
+        );
+        assert_eq!(parse(&[b'U', b'0', 0x0E]), Err(DosError::InvalidCommand));
+    }
+}
@@ -0,0 +1,899 @@
+//! Converting whole collections.
+//!
+//! [`convert_many`] turns a collection of images, tapes and flux dumps
+//! into D64 or G64 files on a pool of threads, calling back as each one
+//! is done and reporting on every item in the order given. A single
+//! image converts with [`convert`].
+//!
+//! Disks go to a G64 as [`G64::from_image`] writes them and come back
+//! from one by their sectors, with the errors a drive would read in the
+//! error block. NIB files are read as the G64 [`Nib::to_g64`] makes of
+//! them, and flux goes through a [`Pipeline`] without stages first. What
+//! a D64 cannot hold, such as half tracks, tracks beyond 40 or errors the
+//! error block has no byte for, is reported as a [`Loss`] by
+//! [`convert_reporting`] and in the [`Report`]s of [`convert_many`].
+//! Tapes, T64 and TAP files, go to a D64 holding their programs, as
+//! [`extract::to_d64`] writes them.
+//!
+//! Single files, archives and the files of a disk convert with
+//! [`convert_file`], between any two of the [`Container`]s tools such as
+//! cbmconvert handle: raw host files, PC64 files, T64, Lynx and LBR
+//! archives and D64 images.
+
+use crate::catalog::Format;
+use crate::d64::{self, D64};
+use crate::error::DosError;
+use crate::flux::FluxSource;
+use crate::flux::pipeline::Pipeline;
+use crate::fs::{self, FileType};
+use crate::g64::{G64, Normalized};
+use crate::image::{BlockDevice, ImageError};
+use crate::lbr::{Lbr, LbrFile};
+use crate::lynx::{Lynx, LynxFile};
+use crate::nib::{self, Nib};
+use crate::p00::{self, P00};
+use crate::petscii;
+use crate::prg::Prg;
+use crate::t64::{self, T64, T64File};
+use crate::tap::Tap;
+use crate::tap::extract::{self, Loader, TapeFile};
+use crate::tap::kernal::Thresholds;
+use crate::tap::turbo::Registry;
+use crate::track;
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// The format to convert to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    D64,
+    G64,
+}
+
+/// How to convert.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The number of threads, or `0` for as many as the machine runs at
+    /// once.
+    pub threads: usize,
+    /// Whether to pass G64 output through [`G64::normalize`].
+    pub normalize: bool,
+}
+
+/// Where an item comes from.
+pub enum Source {
+    /// The bytes of a D64, G64, NIB, T64 or TAP file.
+    File(Vec<u8>),
+    /// A flux dump or device.
+    Flux(Box<dyn FluxSource + Send>),
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(bytes) => write!(f, "File({} bytes)", bytes.len()),
+            Source::Flux(source) => write!(f, "Flux({} half tracks)", source.half_tracks()),
+        }
+    }
+}
+
+/// An item to convert.
+#[derive(Debug)]
+pub struct Input {
+    /// The name the progress and the report give the item.
+    pub name: String,
+    pub source: Source,
+}
+
+/// Why an item could not be converted.
+#[derive(Debug)]
+pub enum ConvertError {
+    /// The file is none of the formats read.
+    Unrecognized,
+    /// The file is broken.
+    Image(ImageError),
+    /// The contents do not fit the target, such as the programs of a tape
+    /// that do not fit on a disk.
+    Dos(DosError),
+    /// The flux could not be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::Unrecognized => write!(f, "unrecognized format"),
+            ConvertError::Image(error) => error.fmt(f),
+            ConvertError::Dos(error) => error.fmt(f),
+            ConvertError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<ImageError> for ConvertError {
+    fn from(error: ImageError) -> Self {
+        ConvertError::Image(error)
+    }
+}
+
+impl From<DosError> for ConvertError {
+    fn from(error: DosError) -> Self {
+        ConvertError::Dos(error)
+    }
+}
+
+/// What a conversion to D64 could not carry over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Loss {
+    /// A sector error the error block has no byte for. The sector holds
+    /// what was read.
+    Error {
+        track: u8,
+        sector: u8,
+        error: DosError,
+    },
+    /// A sector beyond those DOS formats the track with, as copy
+    /// protections add them.
+    Sector { track: u8, sector: u8 },
+    /// A track beyond the 40 of a D64.
+    Track(u8),
+    /// A half track between two tracks, by its index as
+    /// [`G64::half_track`] counts them.
+    HalfTrack(usize),
+}
+
+/// An item done, as the progress callback of [`convert_many`] sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub name: &'a str,
+    /// How many items are done, this one included.
+    pub done: usize,
+    pub total: usize,
+    pub ok: bool,
+}
+
+/// What became of an item.
+#[derive(Debug)]
+pub struct Report {
+    pub name: String,
+    /// The bytes of the converted file.
+    pub result: Result<Vec<u8>, ConvertError>,
+    /// What the converted file could not hold.
+    pub lost: Vec<Loss>,
+}
+
+enum Disk {
+    D64(D64),
+    G64(G64),
+}
+
+fn tape_d64(files: &[TapeFile], name: &[u8]) -> Result<Disk, ConvertError> {
+    let end = name.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let name = &name[..end.min(16)];
+    Ok(Disk::D64(extract::to_d64(files, name, *b"00")?))
+}
+
+fn read(source: Source) -> Result<Disk, ConvertError> {
+    let bytes = match source {
+        Source::File(bytes) => bytes,
+        Source::Flux(mut source) => {
+            let g64 = Pipeline::new()
+                .to_g64(&mut source)
+                .map_err(ConvertError::Io)?;
+            return Ok(Disk::G64(g64));
+        }
+    };
+    if bytes.starts_with(nib::SIGNATURE) {
+        return Ok(Disk::G64(Nib::from_bytes(&bytes)?.to_g64()));
+    }
+    match Format::recognize(&bytes).ok_or(ConvertError::Unrecognized)? {
+        Format::D64 => Ok(Disk::D64(D64::from_bytes(&bytes)?)),
+        Format::G64 => Ok(Disk::G64(G64::from_bytes(&bytes)?)),
+        Format::T64 => {
+            let (archive, _) = t64::repair(&bytes)?;
+            let files: Vec<TapeFile> = archive
+                .files
+                .iter()
+                .map(|file| TapeFile {
+                    name: file.name,
+                    loader: Loader::Kernal,
+                    pulse: 0,
+                    prg: file.prg(),
+                    verified: true,
+                })
+                .collect();
+            tape_d64(&files, &archive.name)
+        }
+        Format::Tap => {
+            let tape = Tap::from_bytes(&bytes)?;
+            let files = extract::extract(&tape, &Thresholds::KERNAL, &Registry::standard());
+            let name = files.first().map_or(&b"TAPE"[..], |file| &file.name[..]);
+            tape_d64(&files, name)
+        }
+    }
+}
+
+/// Returns a D64 of the sectors of `image`, with the errors it reports in
+/// the error block, and the errors the block has no byte for.
+fn to_d64<I: BlockDevice + ?Sized>(image: &I) -> (D64, Vec<Loss>) {
+    let tracks = (1..=40)
+        .filter(|&track| image.sectors_per_track(track) > 0)
+        .last()
+        .unwrap_or(35);
+    let mut d64 = D64::new(if tracks > 35 { 40 } else { 35 });
+    let mut lost = Vec::new();
+    for track in 1..=d64.tracks() {
+        for sector in 0..d64.sectors_per_track(track) {
+            if let Ok(data) = image.read_sector(track, sector) {
+                let _ = d64.write_sector(track, sector, &data);
+            }
+            if let Some(error) = image.sector_error(track, sector) {
+                if d64::error_to_byte(error).is_none() {
+                    lost.push(Loss::Error {
+                        track,
+                        sector,
+                        error,
+                    });
+                }
+                let _ = d64.set_sector_error(track, sector, Some(error));
+            }
+        }
+    }
+    (d64, lost)
+}
+
+/// Returns what of `g64` other than its sectors a D64 cannot hold, by
+/// half track: those [`G64::normalize`] keeps between tracks and beyond
+/// track 40, and the sectors beyond the standard ones on the tracks it
+/// does not rewrite.
+fn g64_losses(g64: &G64) -> Vec<Loss> {
+    let mut lost = Vec::new();
+    for (index, normalized) in g64.normalize().1 {
+        let track = (index / 2 + 1) as u8;
+        match normalized {
+            Normalized::Removed => {}
+            _ if index % 2 == 1 => lost.push(Loss::HalfTrack(index)),
+            _ if track > 40 => lost.push(Loss::Track(track)),
+            Normalized::Rewritten => {}
+            Normalized::Aligned => {
+                let data = g64.half_track(index).unwrap_or_default();
+                let count = d64::sectors_per_track(track);
+                lost.extend(
+                    track::logical_order(data, track)
+                        .filter(|placement| placement.sector >= count)
+                        .map(|placement| Loss::Sector {
+                            track,
+                            sector: placement.sector,
+                        }),
+                );
+            }
+        }
+    }
+    lost
+}
+
+/// Converts one item to `target`, returning the bytes of the new file.
+///
+/// # Errors
+/// Returns the [`ConvertError`] telling why the item cannot be read or
+/// does not fit the target.
+///
+/// # Example
+/// ```
+/// use cbm_dos::convert::{Options, Source, Target, convert};
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::g64::G64;
+///
+/// let d64 = D64::new(35).to_bytes();
+/// let g64 = convert(Source::File(d64), Target::G64, &Options::default()).unwrap();
+/// assert!(G64::from_bytes(&g64).is_ok());
+/// ```
+pub fn convert(source: Source, target: Target, options: &Options) -> Result<Vec<u8>, ConvertError> {
+    convert_reporting(source, target, options).map(|(bytes, _)| bytes)
+}
+
+/// Converts one item to `target` like [`convert`], returning what the new
+/// file could not hold with its bytes.
+///
+/// # Errors
+/// Returns the [`ConvertError`] telling why the item cannot be read or
+/// does not fit the target.
+pub fn convert_reporting(
+    source: Source,
+    target: Target,
+    options: &Options,
+) -> Result<(Vec<u8>, Vec<Loss>), ConvertError> {
+    let disk = read(source)?;
+    Ok(match (disk, target) {
+        (Disk::D64(d64), Target::D64) => (d64.to_bytes(), Vec::new()),
+        (Disk::G64(g64), Target::D64) => {
+            let (d64, mut lost) = to_d64(&g64);
+            lost.extend(g64_losses(&g64));
+            (d64.to_bytes(), lost)
+        }
+        (disk, Target::G64) => {
+            let g64 = match disk {
+                Disk::D64(d64) => G64::from_image(&d64),
+                Disk::G64(g64) => g64,
+            };
+            let bytes = if options.normalize {
+                g64.normalize().0.to_bytes()
+            } else {
+                g64.to_bytes()
+            };
+            (bytes, Vec::new())
+        }
+    })
+}
+
+/// Converts every item of `inputs` to `target` on a pool of threads and
+/// returns a report on each, in the order of `inputs`.
+///
+/// `progress` is called from the threads of the pool as each item is
+/// done, in the order they finish.
+pub fn convert_many(
+    inputs: Vec<Input>,
+    target: Target,
+    options: &Options,
+    progress: &(dyn Fn(Progress<'_>) + Sync),
+) -> Vec<Report> {
+    let total = inputs.len();
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        threads => threads,
+    }
+    .min(total.max(1));
+    let queue = Mutex::new(inputs.into_iter().enumerate());
+    let reports: Mutex<Vec<Option<Report>>> = Mutex::new((0..total).map(|_| None).collect());
+    let done = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let Some((index, input)) = next else {
+                        break;
+                    };
+                    let (result, lost) = match convert_reporting(input.source, target, options) {
+                        Ok((bytes, lost)) => (Ok(bytes), lost),
+                        Err(error) => (Err(error), Vec::new()),
+                    };
+                    progress(Progress {
+                        name: &input.name,
+                        done: done.fetch_add(1, Ordering::SeqCst) + 1,
+                        total,
+                        ok: result.is_ok(),
+                    });
+                    let report = Report {
+                        name: input.name,
+                        result,
+                        lost,
+                    };
+                    reports.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(report);
+                }
+            });
+        }
+    });
+    reports
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// The containers [`convert_file`] converts between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Container {
+    /// One file as it is, its name and type in the host name, such as
+    /// `game.prg`.
+    Raw,
+    /// One PC64 file, such as `game.p00`.
+    P00,
+    T64,
+    Lnx,
+    Lbr,
+    D64,
+}
+
+impl Container {
+    /// Returns the container a host name stands for by its extension.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+        let extension = extension.to_ascii_lowercase();
+        Some(match extension.as_str() {
+            "prg" | "seq" | "usr" | "del" => Container::Raw,
+            "t64" => Container::T64,
+            "lnx" => Container::Lnx,
+            "lbr" => Container::Lbr,
+            "d64" => Container::D64,
+            _ if p00::file_type(&extension).is_some() => Container::P00,
+            _ => return None,
+        })
+    }
+
+    /// Returns the extension of the container in lower case, or `None`
+    /// for those whose extension depends on the type of the file.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Container::Raw | Container::P00 => None,
+            Container::T64 => Some("t64"),
+            Container::Lnx => Some("lnx"),
+            Container::Lbr => Some("lbr"),
+            Container::D64 => Some("d64"),
+        }
+    }
+}
+
+/// A file on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostFile {
+    /// The name, with its extension.
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+/// A file on its way between containers.
+struct Member {
+    name: Vec<u8>,
+    file_type: FileType,
+    data: Vec<u8>,
+}
+
+/// Converts the host name `name` to PETSCII, taking the characters it
+/// cannot as they are.
+fn petscii_name(name: &str) -> Vec<u8> {
+    petscii::from_host_name(name).unwrap_or_else(|| {
+        name.bytes()
+            .map(|b| b.to_ascii_uppercase())
+            .take(fs::NAME_LENGTH)
+            .collect()
+    })
+}
+
+/// Splits a host name into its stem and its extension, which is empty if
+/// there is none.
+fn split_extension(name: &str) -> (&str, &str) {
+    name.rsplit_once('.').unwrap_or((name, ""))
+}
+
+fn trim_spaces(name: &[u8]) -> &[u8] {
+    let end = name
+        .iter()
+        .rposition(|&b| b != b' ' && b != fs::PAD)
+        .map_or(0, |i| i + 1);
+    &name[..end]
+}
+
+/// Reads the members of `file`, and the name of the disk or archive.
+fn unpack(file: &HostFile, from: Container) -> Result<(Vec<u8>, Vec<Member>), ConvertError> {
+    let (stem, extension) = split_extension(&file.name);
+    let title = petscii_name(stem);
+    let bytes = &file.bytes;
+    let members = match from {
+        Container::Raw => {
+            let file_type = match extension.to_ascii_lowercase().as_str() {
+                "seq" => FileType::Seq,
+                "usr" => FileType::Usr,
+                "del" => FileType::Del,
+                _ => FileType::Prg,
+            };
+            vec![Member {
+                name: title.clone(),
+                file_type,
+                data: bytes.clone(),
+            }]
+        }
+        Container::P00 => {
+            let file = P00::from_bytes(bytes)?;
+            let file_type = p00::file_type(extension).unwrap_or(FileType::Prg);
+            if file_type == FileType::Rel || file.record_length != 0 {
+                return Err(ImageError::Unsupported("REL file").into());
+            }
+            vec![Member {
+                name: file.name,
+                file_type,
+                data: file.data,
+            }]
+        }
+        Container::T64 => {
+            let (archive, _) = t64::repair(bytes)?;
+            let members = archive
+                .files
+                .iter()
+                .map(|file| Member {
+                    name: trim_spaces(&file.name).to_vec(),
+                    file_type: FileType::Prg,
+                    data: file.prg().to_bytes(),
+                })
+                .collect();
+            return Ok((trim_spaces(&archive.name).to_vec(), members));
+        }
+        Container::Lnx => Lynx::from_bytes(bytes)?
+            .files
+            .into_iter()
+            .map(|file| Member {
+                name: file.name,
+                file_type: file.file_type,
+                data: file.data,
+            })
+            .collect(),
+        Container::Lbr => Lbr::from_bytes(bytes)?
+            .files
+            .into_iter()
+            .map(|file| Member {
+                name: file.name,
+                file_type: file.file_type,
+                data: file.data,
+            })
+            .collect(),
+        Container::D64 => {
+            let image = D64::from_bytes(bytes)?;
+            let directory = fs::read_directory(&image)?;
+            let mut members = Vec::new();
+            for entry in &directory.entries {
+                if entry.closed && entry.file_type != FileType::Rel {
+                    members.push(Member {
+                        name: entry.name().to_vec(),
+                        file_type: entry.file_type,
+                        data: fs::read_file(&image, entry)?,
+                    });
+                }
+            }
+            return Ok((fs::trim_name(&directory.name).to_vec(), members));
+        }
+    };
+    Ok((title, members))
+}
+
+/// Writes `members` into the files of `to`, archives named `stem`.
+fn pack(
+    stem: &str,
+    title: &[u8],
+    members: Vec<Member>,
+    to: Container,
+) -> Result<Vec<HostFile>, ConvertError> {
+    let single = |bytes: Vec<u8>| {
+        let extension = to.extension().unwrap_or_default();
+        vec![HostFile {
+            name: format!("{stem}.{extension}"),
+            bytes,
+        }]
+    };
+    Ok(match to {
+        Container::Raw | Container::P00 => {
+            let mut files: Vec<HostFile> = Vec::with_capacity(members.len());
+            for member in members {
+                let base = petscii::to_host_name(&member.name);
+                let name = if to == Container::Raw {
+                    let extension = member.file_type.as_str().to_lowercase();
+                    let name = format!("{base}.{extension}");
+                    if files.iter().any(|file| file.name == name) {
+                        return Err(DosError::FileExists.into());
+                    }
+                    name
+                } else {
+                    (0..=99)
+                        .map(|n| p00::extension(member.file_type, n).to_lowercase())
+                        .map(|extension| format!("{base}.{extension}"))
+                        .find(|name| files.iter().all(|file| file.name != *name))
+                        .ok_or(DosError::FileExists)?
+                };
+                let bytes = match to {
+                    Container::Raw => member.data,
+                    _ => P00 {
+                        name: member.name,
+                        record_length: 0,
+                        data: member.data,
+                    }
+                    .to_bytes(),
+                };
+                files.push(HostFile { name, bytes });
+            }
+            files
+        }
+        Container::T64 => {
+            let mut name = [b' '; 24];
+            let length = title.len().min(24);
+            name[..length].copy_from_slice(&title[..length]);
+            let mut archive = T64 {
+                version: 0x0101,
+                name,
+                files: Vec::with_capacity(members.len()),
+            };
+            for member in members {
+                if member.file_type != FileType::Prg {
+                    return Err(DosError::FileTypeMismatch.into());
+                }
+                let prg = Prg::from_bytes(&member.data)?;
+                archive.files.push(T64File::from_prg(&member.name, &prg));
+            }
+            single(archive.to_bytes())
+        }
+        Container::Lnx => {
+            let files = members
+                .into_iter()
+                .map(|member| LynxFile {
+                    name: member.name,
+                    file_type: member.file_type,
+                    data: member.data,
+                })
+                .collect();
+            single(Lynx { files }.to_bytes()?)
+        }
+        Container::Lbr => {
+            let files = members
+                .into_iter()
+                .map(|member| LbrFile {
+                    name: member.name,
+                    file_type: member.file_type,
+                    data: member.data,
+                })
+                .collect();
+            single(Lbr { files }.to_bytes())
+        }
+        Container::D64 => {
+            let mut image = D64::new(35);
+            let name = &title[..title.len().min(fs::NAME_LENGTH)];
+            fs::format(&mut image, name, Some(*b"00"))?;
+            for member in members {
+                fs::write_file(&mut image, &member.name, member.file_type, &member.data)?;
+            }
+            single(image.to_bytes())
+        }
+    })
+}
+
+/// Converts `file` from the container `from` to `to`, returning the host
+/// files written: one for archives and disks, one per file for raw and
+/// PC64 files.
+///
+/// Raw files take their name from the host name and their type from its
+/// extension, PRG for extensions that are none. Archives and disks are
+/// named after `file`, and a D64 gets the name of the archive or disk it
+/// is made from. PC64 files of the same name are numbered, `.p00`, `.p01`
+/// and on, as PC64 numbered them. REL files are left out of D64 images
+/// and refused elsewhere.
+///
+/// # Errors
+/// - [`ConvertError::Image`] if `file` is not of `from` or is broken, and
+///   for REL files read from or written to a container that cannot hold
+///   them.
+/// - [`ConvertError::Dos`] if the files do not fit: files other than
+///   programs written to a T64, raw files of the same name, and more files
+///   than fit on a disk.
+///
+/// # Example
+/// ```
+/// use cbm_dos::convert::{Container, HostFile, convert_file};
+///
+/// let prg = HostFile {
+///     name: String::from("HELLO.prg"),
+///     bytes: vec![0x01, 0x08, 0x60],
+/// };
+/// let p00 = convert_file(&prg, Container::Raw, Container::P00).unwrap();
+/// assert_eq!(p00[0].name, "HELLO.p00");
+/// let back = convert_file(&p00[0], Container::P00, Container::Raw).unwrap();
+/// assert_eq!(back, [prg]);
+/// ```
+pub fn convert_file(
+    file: &HostFile,
+    from: Container,
+    to: Container,
+) -> Result<Vec<HostFile>, ConvertError> {
+    let (title, members) = unpack(file, from)?;
+    pack(split_extension(&file.name).0, &title, members, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, FileType};
+    use crate::prg::Prg;
+    use crate::t64::{T64, T64File};
+
+    #[test]
+    fn converts_disks_both_ways() {
+        let mut d64 = D64::new(35);
+        fs::format(&mut d64, b"ROUND TRIP", Some(*b"RT")).unwrap();
+        fs::write_file(&mut d64, b"GAME", FileType::Prg, &[1, 8, 2]).unwrap();
+        d64.set_sector_error(3, 4, Some(DosError::DataChecksum))
+            .unwrap();
+        let options = Options::default();
+        let g64 = convert(Source::File(d64.to_bytes()), Target::G64, &options).unwrap();
+        let back = convert(Source::File(g64.clone()), Target::D64, &options).unwrap();
+        let back = D64::from_bytes(&back).unwrap();
+        assert_eq!(back.sector_error(3, 4), Some(DosError::DataChecksum));
+        assert_eq!(fs::read_directory(&back), fs::read_directory(&d64));
+        let flux = Source::Flux(Box::new(G64::from_bytes(&g64).unwrap()));
+        let again = convert(flux, Target::D64, &options).unwrap();
+        assert_eq!(
+            D64::from_bytes(&again).unwrap().read_sector(18, 1),
+            d64.read_sector(18, 1)
+        );
+    }
+
+    #[test]
+    fn reports_what_a_d64_cannot_hold() {
+        let mut d64 = D64::new(35);
+        fs::format(&mut d64, b"PROTECTED", Some(*b"PR")).unwrap();
+        d64.set_sector_error(20, 5, Some(DosError::HeaderChecksum))
+            .unwrap();
+        let mut g64 = G64::from_image(&d64);
+        let id = *b"PR";
+        let sectors = |n: u8, fill: u8| vec![[fill; 256]; usize::from(n)];
+        g64.set_track(20, track::encode_track(20, &sectors(20, 0), id, |_| None));
+        g64.set_half_track(
+            35,
+            track::encode_track(18, &sectors(19, 0xAA), id, |_| None),
+            2,
+        );
+        g64.set_track(
+            41,
+            track::encode_track(41, &sectors(17, 0x41), id, |_| None),
+        );
+        g64.set_track(
+            39,
+            track::encode_track(39, &sectors(17, 0x39), id, |_| None),
+        );
+        let options = Options::default();
+        let (bytes, lost) =
+            convert_reporting(Source::File(g64.to_bytes()), Target::D64, &options).unwrap();
+        assert_eq!(
+            lost,
+            [
+                Loss::HalfTrack(35),
+                Loss::Sector {
+                    track: 20,
+                    sector: 19
+                },
+                Loss::Track(41)
+            ]
+        );
+        let back = D64::from_bytes(&bytes).unwrap();
+        assert_eq!(back.tracks(), 40);
+        assert_eq!(back.read_sector(39, 16).unwrap(), [0x39; 256]);
+        assert_eq!(back.sector_error(36, 0), Some(DosError::NoSync));
+
+        // A NIB dump, every track read a little beyond its revolution.
+        let g64 = G64::from_image(&d64);
+        let nib = Nib {
+            tracks: (1..=35)
+                .map(|t| {
+                    let data = g64.track(t).unwrap();
+                    nib::NibTrack {
+                        half_track: t * 2,
+                        density: g64.speed_zone(t),
+                        data: data.iter().cycle().take(nib::TRACK_SIZE).copied().collect(),
+                    }
+                })
+                .collect(),
+        };
+        let (bytes, lost) =
+            convert_reporting(Source::File(nib.to_bytes()), Target::D64, &options).unwrap();
+        assert_eq!(lost, []);
+        let back = D64::from_bytes(&bytes).unwrap();
+        assert_eq!(back.sector_error(20, 5), Some(DosError::HeaderChecksum));
+        assert_eq!(back.sector_error(20, 4), None);
+        assert_eq!(fs::read_directory(&back), fs::read_directory(&d64));
+    }
+
+    #[test]
+    fn converts_collections_in_order() {
+        let tape = T64 {
+            version: 0x0101,
+            name: *b"COMPILATION             ",
+            files: vec![
+                T64File::from_prg(b"ONE", &Prg::new(0x0801, vec![1; 300])),
+                T64File::from_prg(b"TWO", &Prg::new(0x0801, vec![2; 30])),
+            ],
+        };
+        let inputs = vec![
+            Input {
+                name: String::from("tape.t64"),
+                source: Source::File(tape.to_bytes()),
+            },
+            Input {
+                name: String::from("junk.bin"),
+                source: Source::File(vec![0; 10]),
+            },
+            Input {
+                name: String::from("blank.d64"),
+                source: Source::File(D64::new(35).to_bytes()),
+            },
+        ];
+        let seen = Mutex::new(Vec::new());
+        let options = Options {
+            threads: 2,
+            normalize: false,
+        };
+        let reports = convert_many(inputs, Target::D64, &options, &|p| {
+            seen.lock().unwrap().push((p.done, p.total, p.ok));
+        });
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen.iter().map(|s| s.0).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(seen.iter().filter(|s| !s.2).count(), 1);
+
+        let names: Vec<_> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["tape.t64", "junk.bin", "blank.d64"]);
+        let disk = D64::from_bytes(reports[0].result.as_ref().unwrap()).unwrap();
+        let directory = fs::read_directory(&disk).unwrap();
+        assert_eq!(fs::trim_name(&directory.name), b"COMPILATION");
+        assert_eq!(directory.entries.len(), 2);
+        assert!(matches!(reports[1].result, Err(ConvertError::Unrecognized)));
+    }
+
+    #[test]
+    fn converts_between_containers() {
+        let mut d64 = D64::new(35);
+        fs::format(&mut d64, b"MATRIX", Some(*b"MX")).unwrap();
+        fs::write_file(&mut d64, b"GAME", FileType::Prg, &[0x01, 0x08, 1, 2, 3]).unwrap();
+        fs::write_file(&mut d64, b"NOTES", FileType::Seq, b"TEXT").unwrap();
+        let disk = HostFile {
+            name: String::from("matrix.d64"),
+            bytes: d64.to_bytes(),
+        };
+        assert_eq!(Container::from_name(&disk.name), Some(Container::D64));
+        assert_eq!(Container::from_name("NOTES.S01"), Some(Container::P00));
+
+        let raw = convert_file(&disk, Container::D64, Container::Raw).unwrap();
+        let names: Vec<_> = raw.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["GAME.prg", "NOTES.seq"]);
+        assert_eq!(raw[1].bytes, b"TEXT");
+
+        let lynx = convert_file(&disk, Container::D64, Container::Lnx).unwrap();
+        assert_eq!(lynx[0].name, "matrix.lnx");
+        let lbr = convert_file(&lynx[0], Container::Lnx, Container::Lbr).unwrap();
+        let back = convert_file(&lbr[0], Container::Lbr, Container::D64).unwrap();
+        let back = D64::from_bytes(&back[0].bytes).unwrap();
+        let directory = fs::read_directory(&back).unwrap();
+        assert_eq!(fs::trim_name(&directory.name), b"MATRIX");
+        assert_eq!(directory.entries.len(), 2);
+        assert_eq!(
+            fs::read_file(&back, &directory.entries[1]).unwrap(),
+            b"TEXT"
+        );
+
+        assert!(matches!(
+            convert_file(&disk, Container::D64, Container::T64),
+            Err(ConvertError::Dos(DosError::FileTypeMismatch))
+        ));
+        let game = HostFile {
+            name: String::from("game.prg"),
+            bytes: vec![0x01, 0x08, 1, 2, 3],
+        };
+        let tape = convert_file(&game, Container::Raw, Container::T64).unwrap();
+        let p00 = convert_file(&tape[0], Container::T64, Container::P00).unwrap();
+        assert_eq!(p00[0].name, "GAME.p00");
+        assert_eq!(P00::from_bytes(&p00[0].bytes).unwrap().data, game.bytes);
+        let twice = HostFile {
+            name: String::from("twice.lbr"),
+            bytes: Lbr {
+                files: vec![
+                    LbrFile {
+                        name: b"SAME".to_vec(),
+                        file_type: FileType::Usr,
+                        data: vec![1],
+                    };
+                    2
+                ],
+            }
+            .to_bytes(),
+        };
+        let p00 = convert_file(&twice, Container::Lbr, Container::P00).unwrap();
+        let names: Vec<_> = p00.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["SAME.u00", "SAME.u01"]);
+        assert!(matches!(
+            convert_file(&twice, Container::Lbr, Container::Raw),
+            Err(ConvertError::Dos(DosError::FileExists))
+        ));
+    }
+}
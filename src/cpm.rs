@@ -0,0 +1,354 @@
+//! The CP/M 2.2 filesystem as the C128 lays it out on 1541, 1571 and 1581
+//! disks.
+//!
+//! CP/M knows nothing of tracks and sectors: it sees the disk as a row of
+//! allocation blocks of 1 or 2 KiB, the first of which hold the directory.
+//! A [`Layout`] maps that row onto the 256-byte sectors of a
+//! [`BlockDevice`], skipping the tracks the CBM DOS structures occupy and the
+//! boot sector, and ordering the sectors of each track by the CP/M skew.
+//!
+//! Each directory entry, an extent, lists up to 16 KiB of blocks of one
+//! file; a file consists of all extents of the same user number and name,
+//! in the order of their extent numbers.
+
+use crate::error::DosError;
+use crate::image::{BlockDevice, SECTOR_SIZE};
+use alloc::{vec, vec::Vec};
+
+/// The size of a directory entry.
+pub const ENTRY_SIZE: usize = 32;
+/// The size of a CP/M record.
+pub const RECORD_SIZE: usize = 128;
+/// The user number marking a free directory entry.
+pub const FREE: u8 = 0xE5;
+
+const EXTENT_RECORDS: usize = 128;
+
+/// How a CP/M filesystem lies on a disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Layout {
+    /// The size of an allocation block, 1024 or 2048 bytes.
+    pub block_size: usize,
+    /// The number of directory entries.
+    pub directory_entries: usize,
+    /// The number of sectors in front of block 0, holding the boot sector.
+    pub reserved: usize,
+    /// Tracks CP/M leaves to the CBM DOS.
+    pub skip_tracks: &'static [u8],
+    /// The skew between successive CP/M sectors of a track.
+    pub skew: u8,
+}
+
+impl Layout {
+    /// The single-sided C128 format on the 1541.
+    pub const C1541: Layout = Layout {
+        block_size: 1024,
+        directory_entries: 64,
+        reserved: 1,
+        skip_tracks: &[18],
+        skew: 5,
+    };
+
+    /// The double-sided C128 format on the 1571.
+    pub const C1571: Layout = Layout {
+        block_size: 2048,
+        directory_entries: 128,
+        reserved: 1,
+        skip_tracks: &[18, 53],
+        skew: 5,
+    };
+
+    /// The C128 format on the 1581.
+    pub const C1581: Layout = Layout {
+        block_size: 2048,
+        directory_entries: 128,
+        reserved: 1,
+        skip_tracks: &[40],
+        skew: 1,
+    };
+
+    /// Returns the sectors of the data area of `image` in CP/M order.
+    fn sectors<I: BlockDevice + ?Sized>(&self, image: &I) -> Vec<(u8, u8)> {
+        let mut order = Vec::with_capacity(image.total_sectors());
+        for track in (1..=image.tracks()).filter(|t| !self.skip_tracks.contains(t)) {
+            let count = usize::from(image.sectors_per_track(track));
+            let mut used = vec![false; count];
+            let mut next = 0;
+            for _ in 0..count {
+                while used[next] {
+                    next = (next + 1) % count;
+                }
+                used[next] = true;
+                order.push((track, next as u8));
+                next = (next + usize::from(self.skew)) % count;
+            }
+        }
+        order.split_off(self.reserved.min(order.len()))
+    }
+
+    /// Returns the number of allocation blocks on `image`.
+    pub fn blocks<I: BlockDevice + ?Sized>(&self, image: &I) -> usize {
+        self.sectors(image).len() / (self.block_size / SECTOR_SIZE)
+    }
+
+    /// Returns whether the directory stores block numbers in two bytes,
+    /// as it does for more than 256 blocks.
+    fn wide<I: BlockDevice + ?Sized>(&self, image: &I) -> bool {
+        self.blocks(image) > 256
+    }
+
+    /// Returns the sectors of allocation block `block`.
+    pub fn block_sectors<I: BlockDevice + ?Sized>(
+        &self,
+        image: &I,
+        block: usize,
+    ) -> Option<Vec<(u8, u8)>> {
+        let per_block = self.block_size / SECTOR_SIZE;
+        let sectors = self.sectors(image);
+        sectors
+            .get(block * per_block..(block + 1) * per_block)
+            .map(<[_]>::to_vec)
+    }
+
+    /// Reads allocation block `block`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::IllegalTrackSector`] past the last block.
+    pub fn read_block<I: BlockDevice + ?Sized>(
+        &self,
+        image: &I,
+        block: usize,
+    ) -> Result<Vec<u8>, DosError> {
+        let sectors = self
+            .block_sectors(image, block)
+            .ok_or(DosError::IllegalTrackSector)?;
+        let mut data = Vec::with_capacity(self.block_size);
+        for (track, sector) in sectors {
+            data.extend_from_slice(&image.read_sector(track, sector)?);
+        }
+        Ok(data)
+    }
+}
+
+/// A file in a CP/M directory, gathered from all its extents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpmFile {
+    /// The user area, 0 to 15.
+    pub user: u8,
+    /// The name, padded with spaces, without the attribute bits.
+    pub name: [u8; 8],
+    /// The extension, padded with spaces, without the attribute bits.
+    pub extension: [u8; 3],
+    /// Whether the file is read-only, attribute `t1'`.
+    pub read_only: bool,
+    /// Whether the file is a system file hidden from `DIR`, attribute `t2'`.
+    pub system: bool,
+    /// The number of 128-byte records in the file.
+    pub records: usize,
+    /// The allocation blocks of the file, in order.
+    pub blocks: Vec<u16>,
+}
+
+impl CpmFile {
+    /// Returns the name as CP/M prints it, `NAME.EXT`.
+    pub fn file_name(&self) -> Vec<u8> {
+        let trim = |part: &[u8]| part.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        let mut name = self.name[..trim(&self.name)].to_vec();
+        let extension = &self.extension[..trim(&self.extension)];
+        if !extension.is_empty() {
+            name.push(b'.');
+            name.extend_from_slice(extension);
+        }
+        name
+    }
+
+    /// Returns the size of the file in bytes, a multiple of the record
+    /// size.
+    pub fn size(&self) -> usize {
+        self.records * RECORD_SIZE
+    }
+}
+
+/// Reads the directory of the CP/M filesystem on `image`.
+///
+/// Free entries and those of user numbers above 15, which CP/M 3 uses for
+/// labels and time stamps, are passed over. The files come in the order of
+/// their first extent in the directory.
+///
+/// # Errors
+/// Returns the error of a directory sector that cannot be read.
+pub fn read_directory<I: BlockDevice + ?Sized>(
+    image: &I,
+    layout: &Layout,
+) -> Result<Vec<CpmFile>, DosError> {
+    let wide = layout.wide(image);
+    let size = layout.directory_entries * ENTRY_SIZE;
+    let mut directory = Vec::with_capacity(size);
+    for block in 0..size.div_ceil(layout.block_size) {
+        directory.extend(layout.read_block(image, block)?);
+    }
+
+    // The entries of each file, with their extent numbers.
+    type Extents<'a> = Vec<(usize, &'a [u8])>;
+    let mut files: Vec<(CpmFile, Extents)> = Vec::new();
+    for entry in directory[..size].chunks_exact(ENTRY_SIZE) {
+        let user = entry[0];
+        if user > 15 {
+            continue;
+        }
+        let mut name = [0; 8];
+        let mut extension = [0; 3];
+        for (to, &from) in name.iter_mut().zip(&entry[1..9]) {
+            *to = from & 0x7F;
+        }
+        for (to, &from) in extension.iter_mut().zip(&entry[9..12]) {
+            *to = from & 0x7F;
+        }
+        let extent = usize::from(entry[14] & 0x3F) << 5 | usize::from(entry[12] & 0x1F);
+        let file = files.iter_mut().position(|(file, _)| {
+            file.user == user && file.name == name && file.extension == extension
+        });
+        match file {
+            Some(index) => files[index].1.push((extent, entry)),
+            None => files.push((
+                CpmFile {
+                    user,
+                    name,
+                    extension,
+                    read_only: entry[9] & 0x80 != 0,
+                    system: entry[10] & 0x80 != 0,
+                    records: 0,
+                    blocks: Vec::new(),
+                },
+                vec![(extent, entry)],
+            )),
+        }
+    }
+
+    Ok(files
+        .into_iter()
+        .map(|(mut file, mut extents)| {
+            extents.sort_by_key(|&(extent, _)| extent);
+            for &(_, entry) in &extents {
+                let pointers = &entry[16..32];
+                let blocks: Vec<u16> = if wide {
+                    pointers
+                        .chunks_exact(2)
+                        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                        .collect()
+                } else {
+                    pointers.iter().map(|&b| u16::from(b)).collect()
+                };
+                file.blocks.extend(blocks.into_iter().filter(|&b| b != 0));
+            }
+            if let Some(&(extent, entry)) = extents.last() {
+                // The logical extents of 16 KiB before the last one are
+                // full; the record count tells how much of it is used.
+                let records = usize::from(entry[15]).min(EXTENT_RECORDS);
+                file.records = extent * EXTENT_RECORDS + records;
+            }
+            file
+        })
+        .collect())
+}
+
+/// Reads the contents of `file`, all its records.
+///
+/// # Errors
+/// Returns [`DosError::IllegalTrackSector`] if the file points past the
+/// last block, or the error of a sector that cannot be read.
+pub fn read_file<I: BlockDevice + ?Sized>(
+    image: &I,
+    layout: &Layout,
+    file: &CpmFile,
+) -> Result<Vec<u8>, DosError> {
+    let mut data = Vec::with_capacity(file.blocks.len() * layout.block_size);
+    for &block in &file.blocks {
+        data.extend(layout.read_block(image, usize::from(block))?);
+    }
+    data.truncate(file.size());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn write_block(image: &mut D64, layout: &Layout, block: usize, data: &[u8]) {
+        let sectors = layout.block_sectors(image, block).unwrap();
+        for ((track, sector), chunk) in sectors.into_iter().zip(data.chunks(SECTOR_SIZE)) {
+            let mut buffer = [0; SECTOR_SIZE];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            image.write_sector(track, sector, &buffer).unwrap();
+        }
+    }
+
+    fn entry(user: u8, name: &[u8; 11], extent: u8, records: u8, blocks: &[u8]) -> Vec<u8> {
+        let mut entry = vec![user];
+        entry.extend_from_slice(name);
+        entry.extend_from_slice(&[extent, 0, 0, records]);
+        entry.extend_from_slice(blocks);
+        entry.resize(ENTRY_SIZE, 0);
+        entry
+    }
+
+    #[test]
+    fn maps_blocks_onto_sectors() {
+        let image = D64::new(35);
+        let layout = Layout::C1541;
+        assert_eq!(layout.blocks(&image), (683 - 19 - 1) / 4);
+        // The boot sector 1/0 comes first, then the skew of 5.
+        assert_eq!(
+            layout.block_sectors(&image, 0).unwrap(),
+            [(1, 5), (1, 10), (1, 15), (1, 20)]
+        );
+        assert_eq!(
+            layout.block_sectors(&image, 1).unwrap()[..2],
+            [(1, 4), (1, 9)]
+        );
+        let after = layout.block_sectors(&image, 85).unwrap();
+        assert!(after.iter().all(|&(track, _)| track != 18));
+        assert_eq!(layout.block_sectors(&image, 165), None);
+    }
+
+    #[test]
+    fn lists_and_extracts_files() {
+        let mut image = D64::new(35);
+        let layout = Layout::C1541;
+        let text: Vec<u8> = (0..19968u32).map(|i| (i % 251) as u8).collect();
+        for (i, chunk) in text.chunks(1024).enumerate() {
+            write_block(&mut image, &layout, 2 + i, chunk);
+        }
+        write_block(&mut image, &layout, 30, &[0x1A; 1024]);
+
+        let first: Vec<u8> = (2..18).collect();
+        let second: Vec<u8> = (18..22).collect();
+        let mut directory = vec![FREE; 2048];
+        let entries = [
+            entry(0, b"BIG     TXT", 1, 28, &second),
+            entry(FREE, b"GONE    COM", 0, 8, &[40]),
+            entry(3, b"PIP     C\xCFM", 0, 8, &[30]),
+            entry(0, b"BIG     TXT", 0, 128, &first),
+            entry(0x21, b"LABEL      ", 0, 0, &[]),
+        ];
+        for (slot, entry) in directory.chunks_mut(ENTRY_SIZE).zip(&entries) {
+            slot.copy_from_slice(entry);
+        }
+        write_block(&mut image, &layout, 0, &directory[..1024]);
+        write_block(&mut image, &layout, 1, &directory[1024..]);
+
+        let files = read_directory(&image, &layout).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].file_name(), b"BIG.TXT");
+        assert_eq!(files[0].records, 156);
+        assert_eq!(files[0].blocks, (2..22).collect::<Vec<u16>>());
+        assert_eq!(read_file(&image, &layout, &files[0]).unwrap(), text);
+        assert_eq!(
+            (files[1].user, files[1].file_name()),
+            (3, b"PIP.COM".to_vec())
+        );
+        assert!(files[1].system && !files[1].read_only);
+        assert_eq!(read_file(&image, &layout, &files[1]).unwrap(), [0x1A; 1024]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::DosError;
+use crate::image::{BlockDevice, DiskImage, ImageError, SECTOR_SIZE, Sector};
+use alloc::{vec, vec::Vec};
+
+/// Number of sectors on a standard 35-track disk.
+pub const SECTORS_35: usize = 683;
+/// Number of sectors on an extended 40-track disk.
+pub const SECTORS_40: usize = 768;
+
+/// Returns the number of sectors the 1541 places on `track`.
+///
+/// The 1541 divides the disk into four speed zones:
+/// tracks 1-17 hold 21 sectors, 18-24 hold 19, 25-30 hold 18 and 31 onwards
+/// hold 17. Track `0` and tracks beyond 42 return `0`.
+pub fn sectors_per_track(track: u8) -> u8 {
+    match track {
+        1..=17 => 21,
+        18..=24 => 19,
+        25..=30 => 18,
+        31..=42 => 17,
+        _ => 0,
+    }
+}
+
+/// Returns the byte offset of `track`/`sector` inside a D64 file.
+pub fn sector_offset(track: u8, sector: u8) -> usize {
+    let preceding: usize = (1..track).map(|t| sectors_per_track(t) as usize).sum();
+    (preceding + sector as usize) * SECTOR_SIZE
+}
+
+/// Maps an error byte from a D64 error block to the DOS error it stands for.
+///
+/// Error bytes `0` and `1` (no error) map to `None`.
+pub fn error_from_byte(byte: u8) -> Option<DosError> {
+    match byte {
+        0x02 => Some(DosError::HeaderNotFound),
+        0x03 => Some(DosError::NoSync),
+        0x04 => Some(DosError::DataBlockNotPresent),
+        0x05 => Some(DosError::DataChecksum),
+        0x06 => Some(DosError::ByteDecoding),
+        0x07 => Some(DosError::WriteVerify),
+        0x08 => Some(DosError::WriteProtect),
+        0x09 => Some(DosError::HeaderChecksum),
+        0x0A => Some(DosError::LongDataBlock),
+        0x0B => Some(DosError::DiskIdMismatch),
+        0x0F => Some(DosError::DriveNotReady),
+        _ => None,
+    }
+}
+
+/// Maps a DOS error to the byte a D64 error block stores for it.
+///
+/// Errors that cannot be represented in an error block return `None`.
+pub fn error_to_byte(error: DosError) -> Option<u8> {
+    (0x02..=0x0F).find(|&b| error_from_byte(b) == Some(error))
+}
+
+/// An in-memory D64 image of a 1541 disk.
+///
+/// Both the 35-track and the 40-track layout are supported, each with or
+/// without the trailing error block of one byte per sector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct D64 {
+    data: Vec<u8>,
+    tracks: u8,
+    errors: Option<Vec<u8>>,
+}
+
+impl D64 {
+    /// Creates a blank, unformatted image with `tracks` tracks (35 or 40).
+    ///
+    /// # Panics
+    /// Panics if `tracks` is neither 35 nor 40.
+    pub fn new(tracks: u8) -> Self {
+        let sectors = match tracks {
+            35 => SECTORS_35,
+            40 => SECTORS_40,
+            _ => panic!("unsupported track count {tracks}"),
+        };
+        D64 {
+            data: vec![0; sectors * SECTOR_SIZE],
+            tracks,
+            errors: None,
+        }
+    }
+
+    /// Parses a D64 file.
+    ///
+    /// The layout is derived from the file size: 174848 or 175531 bytes for
+    /// 35 tracks and 196608 or 197376 bytes for 40 tracks, the larger sizes
+    /// carrying an error block.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::InvalidSize`] for any other size.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let (tracks, sectors) = match bytes.len() {
+            n if n == SECTORS_35 * SECTOR_SIZE || n == SECTORS_35 * (SECTOR_SIZE + 1) => {
+                (35, SECTORS_35)
+            }
+            n if n == SECTORS_40 * SECTOR_SIZE || n == SECTORS_40 * (SECTOR_SIZE + 1) => {
+                (40, SECTORS_40)
+            }
+            n => return Err(ImageError::InvalidSize(n)),
+        };
+        let split = sectors * SECTOR_SIZE;
+        let errors = (bytes.len() > split).then(|| bytes[split..].to_vec());
+        Ok(D64 {
+            data: bytes[..split].to_vec(),
+            tracks,
+            errors,
+        })
+    }
+
+    /// Serializes the image back into D64 file form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.data.clone();
+        if let Some(errors) = &self.errors {
+            out.extend_from_slice(errors);
+        }
+        out
+    }
+
+    /// Returns the error block, if the image has one.
+    pub fn error_bytes(&self) -> Option<&[u8]> {
+        self.errors.as_deref()
+    }
+
+    /// Records a read error for a sector, creating the error block if needed.
+    ///
+    /// Passing `None` marks the sector as error free.
+    ///
+    /// # Errors
+    /// Returns [`DosError::IllegalTrackSector`] if the sector does not exist.
+    pub fn set_sector_error(
+        &mut self,
+        track: u8,
+        sector: u8,
+        error: Option<DosError>,
+    ) -> Result<(), DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        let byte = error.and_then(error_to_byte).unwrap_or(0x01);
+        let index = sector_offset(track, sector) / SECTOR_SIZE;
+        let total = self.total_sectors();
+        let errors = self.errors.get_or_insert_with(|| vec![0x01; total]);
+        errors[index] = byte;
+        Ok(())
+    }
+}
+
+impl BlockDevice for D64 {
+    fn tracks(&self) -> u8 {
+        self.tracks
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        if track <= self.tracks {
+            sectors_per_track(track)
+        } else {
+            0
+        }
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        let offset = sector_offset(track, sector);
+        let mut out = [0u8; SECTOR_SIZE];
+        out.copy_from_slice(&self.data[offset..offset + SECTOR_SIZE]);
+        Ok(out)
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        let offset = sector_offset(track, sector);
+        self.data[offset..offset + SECTOR_SIZE].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        let errors = self.errors.as_ref()?;
+        if !self.contains(track, sector) {
+            return None;
+        }
+        error_from_byte(errors[sector_offset(track, sector) / SECTOR_SIZE])
+    }
+}
+
+impl DiskImage for D64 {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_matches_file_sizes() {
+        assert_eq!(D64::new(35).to_bytes().len(), 174848);
+        assert_eq!(D64::new(40).to_bytes().len(), 196608);
+        assert_eq!(sector_offset(18, 0), 0x16500);
+        assert!(D64::from_bytes(&[0; 1000]).is_err());
+    }
+
+    #[test]
+    fn error_block_round_trips() {
+        let mut image = D64::new(35);
+        image
+            .set_sector_error(18, 4, Some(DosError::DataChecksum))
+            .unwrap();
+        let bytes = image.to_bytes();
+        assert_eq!(bytes.len(), 175531);
+
+        let parsed = D64::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.sector_error(18, 4), Some(DosError::DataChecksum));
+        assert_eq!(parsed.sector_error(18, 5), None);
+    }
+}
@@ -0,0 +1,500 @@
+//! DAT files, the checksum lists of preservation projects.
+//!
+//! TOSEC, No-Intro and others describe their collections in DAT files: a
+//! list of games, each with the files ("roms") that make it up and their
+//! sizes and checksums. [`Dat::parse`] reads both forms they come in, the
+//! Logiqx XML most are published in today and the older ClrMamePro text:
+//!
+//! ```plaintext
+//! <game name="Archon (1984)(Electronic Arts)">
+//!     <rom name="Archon (1984)(Electronic Arts).d64" size="174848" crc="..." sha1="..."/>
+//! </game>
+//!
+//! game ( name "Archon (1984)(Electronic Arts)"
+//!     rom ( name "Archon (1984)(Electronic Arts).d64" size 174848 crc ... ) )
+//! ```
+//!
+//! [`Dat::find`] looks up the entries whose checksums a file has, so
+//! that an image can be verified and given its proper name, and
+//! [`Dat::missing`] lists what a collection lacks. Only the elements and
+//! fields named here are read; the rest of a DAT is skipped.
+
+use crate::hash::Hashes;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// An error parsing a DAT file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatError {
+    /// The text is malformed in the given line, counting from 1.
+    Syntax(usize),
+    /// A size or checksum in the given line is not a number of the
+    /// expected length.
+    InvalidValue(usize),
+}
+
+impl fmt::Display for DatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatError::Syntax(line) => write!(f, "syntax error in line {line}"),
+            DatError::InvalidValue(line) => write!(f, "invalid size or checksum in line {line}"),
+        }
+    }
+}
+
+impl core::error::Error for DatError {}
+
+/// A file of a game as the DAT lists it. Any of the checksums may be
+/// missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rom {
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc32: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl Rom {
+    /// Whether a file of `hashes` is this one: the size, if listed, and
+    /// every checksum listed must agree, and at least one must be.
+    pub fn matches(&self, hashes: &Hashes) -> bool {
+        let listed = self.crc32.is_some() || self.md5.is_some() || self.sha1.is_some();
+        listed
+            && self.size.is_none_or(|size| size == hashes.size)
+            && self.crc32.is_none_or(|crc| crc == hashes.crc32)
+            && self.md5.is_none_or(|md5| md5 == hashes.md5)
+            && self.sha1.is_none_or(|sha1| sha1 == hashes.sha1)
+    }
+}
+
+/// A game, a disk set or whatever else the DAT groups files into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Game {
+    pub name: String,
+    pub roms: Vec<Rom>,
+}
+
+/// A parsed DAT file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dat {
+    /// The name in the header.
+    pub name: String,
+    pub games: Vec<Game>,
+    /// The games and roms by CRC-32, for the roms that list one.
+    by_crc: BTreeMap<u32, Vec<(usize, usize)>>,
+    /// The roms listing no CRC-32.
+    unindexed: Vec<(usize, usize)>,
+}
+
+/// A rom of a DAT a file matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'a> {
+    pub game: &'a Game,
+    pub rom: &'a Rom,
+}
+
+impl Dat {
+    /// Makes a DAT of `games`, named `name`.
+    pub fn new(name: &str, games: Vec<Game>) -> Self {
+        let mut dat = Dat {
+            name: name.to_string(),
+            games,
+            ..Dat::default()
+        };
+        for (g, game) in dat.games.iter().enumerate() {
+            for (r, rom) in game.roms.iter().enumerate() {
+                match rom.crc32 {
+                    Some(crc) => dat.by_crc.entry(crc).or_default().push((g, r)),
+                    None => dat.unindexed.push((g, r)),
+                }
+            }
+        }
+        dat
+    }
+
+    /// Parses a DAT in Logiqx XML or ClrMamePro form, told apart by their
+    /// first character.
+    ///
+    /// # Errors
+    /// Returns [`DatError::Syntax`] for text that is neither, and
+    /// [`DatError::InvalidValue`] for sizes and checksums that are no
+    /// numbers.
+    pub fn parse(text: &str) -> Result<Self, DatError> {
+        if text.trim_start().starts_with('<') {
+            xml(text)
+        } else {
+            clrmamepro(text)
+        }
+    }
+
+    fn rom(&self, (g, r): (usize, usize)) -> Match<'_> {
+        let game = &self.games[g];
+        Match {
+            game,
+            rom: &game.roms[r],
+        }
+    }
+
+    /// Returns the roms a file of `hashes` matches.
+    pub fn find(&self, hashes: &Hashes) -> Vec<Match<'_>> {
+        let candidates = self.by_crc.get(&hashes.crc32).into_iter().flatten();
+        candidates
+            .chain(&self.unindexed)
+            .map(|&at| self.rom(at))
+            .filter(|found| found.rom.matches(hashes))
+            .collect()
+    }
+
+    /// Returns the roms none of `have` matches, in the order of the DAT.
+    pub fn missing<'a>(&self, have: impl IntoIterator<Item = &'a Hashes>) -> Vec<Match<'_>> {
+        let mut found = BTreeSet::new();
+        for hashes in have {
+            let candidates = self.by_crc.get(&hashes.crc32).into_iter().flatten();
+            for &at in candidates.chain(&self.unindexed) {
+                if self.rom(at).rom.matches(hashes) {
+                    found.insert(at);
+                }
+            }
+        }
+        let mut missing = Vec::new();
+        for (g, game) in self.games.iter().enumerate() {
+            for r in 0..game.roms.len() {
+                if !found.contains(&(g, r)) {
+                    missing.push(self.rom((g, r)));
+                }
+            }
+        }
+        missing
+    }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset].matches('\n').count() + 1
+}
+
+fn hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Sets the field `key` of `rom` from `value`; unknown keys are ignored.
+fn set_field(rom: &mut Rom, key: &str, value: &str) -> Option<()> {
+    match key {
+        "name" => rom.name = value.to_string(),
+        "size" => rom.size = Some(value.parse().ok()?),
+        "crc" if !value.is_empty() => {
+            if value.len() > 8 {
+                return None;
+            }
+            rom.crc32 = Some(u32::from_str_radix(value, 16).ok()?);
+        }
+        "md5" if !value.is_empty() => rom.md5 = Some(hex(value)?),
+        "sha1" if !value.is_empty() => rom.sha1 = Some(hex(value)?),
+        _ => {}
+    }
+    Some(())
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let entity = [
+            ("&amp;", '&'),
+            ("&lt;", '<'),
+            ("&gt;", '>'),
+            ("&quot;", '"'),
+            ("&apos;", '\''),
+        ]
+        .into_iter()
+        .find(|(name, _)| rest.starts_with(name));
+        match entity {
+            Some((name, c)) => {
+                out.push(c);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns the attributes of the tag `tag`, the text between `<` and `>`
+/// without the element name.
+fn attributes(tag: &str) -> Option<Vec<(&str, String)>> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.trim_end_matches('/').trim();
+    while !rest.is_empty() {
+        let equals = rest.find('=')?;
+        let key = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let end = value[1..].find(quote)? + 1;
+        attributes.push((key, unescape(&value[1..end])));
+        rest = value[end + 1..].trim_start();
+    }
+    Some(attributes)
+}
+
+fn xml(text: &str) -> Result<Dat, DatError> {
+    let (mut name, mut games) = (String::new(), Vec::new());
+    let mut game: Option<Game> = None;
+    let mut in_header = false;
+    let mut position = 0;
+    while let Some(start) = text[position..].find('<').map(|at| position + at) {
+        let syntax = DatError::Syntax(line_of(text, start));
+        if text[start..].starts_with("<!--") {
+            position = start + text[start..].find("-->").ok_or(syntax)? + 3;
+            continue;
+        }
+        let end = start + text[start..].find('>').ok_or(syntax)?;
+        let tag = &text[start + 1..end];
+        position = end + 1;
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        let (element, rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let element = element.trim_end_matches('/');
+        match element {
+            "header" => in_header = true,
+            "/header" => in_header = false,
+            "name" if in_header => {
+                let close = text[position..].find("</name>").ok_or(syntax)?;
+                name = unescape(text[position..position + close].trim());
+                position += close;
+            }
+            "game" | "machine" | "software" => {
+                let attributes = attributes(rest).ok_or(syntax)?;
+                let mut new = Game::default();
+                if let Some((_, value)) = attributes.into_iter().find(|(key, _)| *key == "name") {
+                    new.name = value;
+                }
+                if let Some(done) = game.replace(new) {
+                    games.push(done);
+                }
+                if tag.ends_with('/') {
+                    games.extend(game.take());
+                }
+            }
+            "/game" | "/machine" | "/software" => games.extend(game.take()),
+            "rom" => {
+                let invalid = DatError::InvalidValue(line_of(text, start));
+                let attributes = attributes(rest).ok_or(syntax)?;
+                let mut rom = Rom::default();
+                for (key, value) in &attributes {
+                    set_field(&mut rom, key, value).ok_or(invalid)?;
+                }
+                game.as_mut().ok_or(syntax)?.roms.push(rom);
+            }
+            _ => {}
+        }
+    }
+    games.extend(game);
+    Ok(Dat::new(&name, games))
+}
+
+/// A token of ClrMamePro text, with the offset it starts at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Word(&'a str),
+}
+
+fn tokens(text: &str) -> Result<Vec<(usize, Token<'_>)>, DatError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    let bytes = text.as_bytes();
+    while position < bytes.len() {
+        let start = position;
+        match bytes[position] {
+            b if b.is_ascii_whitespace() => position += 1,
+            b'(' => {
+                tokens.push((start, Token::Open));
+                position += 1;
+            }
+            b')' => {
+                tokens.push((start, Token::Close));
+                position += 1;
+            }
+            b'"' => {
+                let end = text[start + 1..]
+                    .find('"')
+                    .ok_or(DatError::Syntax(line_of(text, start)))?;
+                tokens.push((start, Token::Word(&text[start + 1..start + 1 + end])));
+                position = start + end + 2;
+            }
+            _ => {
+                let end = bytes[start..]
+                    .iter()
+                    .position(|&b| b.is_ascii_whitespace() || b == b'(' || b == b')')
+                    .map_or(bytes.len(), |length| start + length);
+                tokens.push((start, Token::Word(&text[start..end])));
+                position = end;
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn clrmamepro(text: &str) -> Result<Dat, DatError> {
+    let tokens = tokens(text)?;
+    let syntax = |at: usize| {
+        let offset = tokens.get(at).map_or(text.len(), |&(offset, _)| offset);
+        DatError::Syntax(line_of(text, offset))
+    };
+    let (mut name, mut games) = (String::new(), Vec::new());
+    let mut at = 0;
+    while at < tokens.len() {
+        let (Token::Word(block), Some(Token::Open)) =
+            (tokens[at].1, tokens.get(at + 1).map(|t| t.1))
+        else {
+            return Err(syntax(at));
+        };
+        at += 2;
+        let mut game = Game::default();
+        loop {
+            match (tokens.get(at).map(|t| t.1), tokens.get(at + 1).map(|t| t.1)) {
+                (Some(Token::Close), _) => {
+                    at += 1;
+                    break;
+                }
+                (Some(Token::Word("rom")), Some(Token::Open)) => {
+                    let mut rom = Rom::default();
+                    at += 2;
+                    while let (Some(Token::Word(key)), Some(Token::Word(value))) =
+                        (tokens.get(at).map(|t| t.1), tokens.get(at + 1).map(|t| t.1))
+                    {
+                        let line = line_of(text, tokens[at].0);
+                        set_field(&mut rom, key, value).ok_or(DatError::InvalidValue(line))?;
+                        at += 2;
+                    }
+                    if tokens.get(at).map(|t| t.1) != Some(Token::Close) {
+                        return Err(syntax(at));
+                    }
+                    at += 1;
+                    game.roms.push(rom);
+                }
+                (Some(Token::Word(_)), Some(Token::Open)) => {
+                    // Blocks other than roms, such as disks and samples.
+                    let mut depth = 0;
+                    at += 1;
+                    loop {
+                        match tokens.get(at).map(|t| t.1) {
+                            Some(Token::Open) => depth += 1,
+                            Some(Token::Close) if depth == 1 => break,
+                            Some(Token::Close) => depth -= 1,
+                            Some(Token::Word(_)) => {}
+                            None => return Err(syntax(at)),
+                        }
+                        at += 1;
+                    }
+                    at += 1;
+                }
+                (Some(Token::Word(key)), Some(Token::Word(value))) => {
+                    if key == "name" {
+                        game.name = value.to_string();
+                    }
+                    at += 2;
+                }
+                _ => return Err(syntax(at)),
+            }
+        }
+        match block {
+            "clrmamepro" => name = game.name,
+            "game" | "machine" | "resource" => games.push(game),
+            _ => {}
+        }
+    }
+    Ok(Dat::new(&name, games))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK: &[u8] = b"just the bytes of an image";
+
+    fn sha1_hex() -> String {
+        let sha1 = Hashes::of(DISK).sha1;
+        sha1.iter().map(|b| alloc::format!("{b:02X}")).collect()
+    }
+
+    #[test]
+    fn reads_logiqx_xml() {
+        let hashes = Hashes::of(DISK);
+        let text = alloc::format!(
+            r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "">
+<datafile>
+    <header>
+        <name>Commodore C64 - Games - [D64]</name>
+    </header>
+    <!-- a comment <game name="x"> -->
+    <game name="Archon (1984)(Electronic Arts)">
+        <description>Archon</description>
+        <rom name="Archon &amp; Co.d64" size="{}" crc="{:08x}" sha1="{}"/>
+    </game>
+    <game name="Other">
+        <rom name="Other.d64" size="174848" crc="deadbeef"/>
+    </game>
+</datafile>"#,
+            DISK.len(),
+            hashes.crc32,
+            sha1_hex()
+        );
+        let dat = Dat::parse(&text).unwrap();
+        assert_eq!(dat.name, "Commodore C64 - Games - [D64]");
+        assert_eq!(dat.games.len(), 2);
+        let found = dat.find(&hashes);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].game.name, "Archon (1984)(Electronic Arts)");
+        assert_eq!(found[0].rom.name, "Archon & Co.d64");
+        let mut altered = hashes;
+        altered.sha1[0] ^= 1;
+        assert!(dat.find(&altered).is_empty());
+        let missing = dat.missing([&hashes]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].rom.name, "Other.d64");
+
+        let broken = text.replace("crc=\"deadbeef\"", "crc=\"nothex\"");
+        assert_eq!(Dat::parse(&broken), Err(DatError::InvalidValue(13)));
+    }
+
+    #[test]
+    fn reads_clrmamepro_text() {
+        let hashes = Hashes::of(DISK);
+        let text = alloc::format!(
+            "clrmamepro (\n\tname \"C64 Tapes\"\n\tversion 2024\n)\n\n\
+             game (\n\tname \"Tape\"\n\tdisk ( name x sha1 0 )\n\
+             \trom ( name \"Tape (Side A).tap\" size {} crc {:08X} sha1 {} )\n)\n",
+            DISK.len(),
+            hashes.crc32,
+            sha1_hex()
+        );
+        let dat = Dat::parse(&text).unwrap();
+        assert_eq!(dat.name, "C64 Tapes");
+        let found = dat.find(&hashes);
+        assert_eq!(
+            (found[0].game.name.as_str(), found[0].rom.size),
+            ("Tape", Some(DISK.len() as u64))
+        );
+        assert!(dat.missing([&hashes]).is_empty());
+        assert_eq!(Dat::parse("game ( name \"x\" "), Err(DatError::Syntax(1)));
+    }
+}
@@ -0,0 +1,297 @@
+//! Differences between two images.
+//!
+//! [`diff`] compares two disks sector by sector, to review what an edit
+//! changed or how two dumps of the same disk differ. When both hold a
+//! directory, it also tells which files were added, removed or modified,
+//! and which of their blocks changed.
+//!
+//! The images may be of any kind: a D64 compares with the G64 it was
+//! written from by what a drive reads off the disk.
+
+use crate::error::DosError;
+use crate::fs::{self, DirEntry};
+use crate::image::{BlockDevice, Sector};
+use alloc::vec::Vec;
+
+/// How a sector differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectorChange {
+    /// The sector is on the first disk only, which has more tracks.
+    OnlyInFirst,
+    /// The sector is on the second disk only.
+    OnlyInSecond,
+    /// Both disks read the sector, with different contents.
+    Contents,
+    /// The disks read the sector with different errors, `None` where it
+    /// reads without one.
+    Error {
+        first: Option<DosError>,
+        second: Option<DosError>,
+    },
+}
+
+/// A sector that differs between two disks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorDifference {
+    pub track: u8,
+    pub sector: u8,
+    pub change: SectorChange,
+}
+
+/// How a file differs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChange {
+    /// The file is on the second disk only.
+    Added,
+    /// The file is on the first disk only.
+    Removed,
+    /// The file is on both disks, with different contents or a different
+    /// directory entry.
+    Modified {
+        /// The blocks of the file on either disk that differ between them,
+        /// in the order of the file on the first disk, then of the second.
+        sectors: Vec<(u8, u8)>,
+    },
+}
+
+/// A file that differs between two disks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDifference {
+    /// The entry of the file on the second disk, or on the first for
+    /// [`FileChange::Removed`].
+    pub entry: DirEntry,
+    pub change: FileChange,
+}
+
+/// The differences between two disks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// The sectors that differ, in track order.
+    pub sectors: Vec<SectorDifference>,
+    /// The files that differ, those of the first disk in directory order,
+    /// then those added on the second; `None` unless both directories can
+    /// be read.
+    pub files: Option<Vec<FileDifference>>,
+}
+
+impl Diff {
+    /// Whether the disks read the same.
+    pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    /// Whether the sector at `track`/`sector` differs.
+    pub fn contains(&self, track: u8, sector: u8) -> bool {
+        self.sectors
+            .binary_search_by_key(&(track, sector), |d| (d.track, d.sector))
+            .is_ok()
+    }
+}
+
+fn read<I: BlockDevice + ?Sized>(image: &I, track: u8, sector: u8) -> Result<Sector, DosError> {
+    match image.sector_error(track, sector) {
+        Some(error) => Err(error),
+        None => image.read_sector(track, sector),
+    }
+}
+
+fn sector_change<A, B>(first: &A, second: &B, track: u8, sector: u8) -> Option<SectorChange>
+where
+    A: BlockDevice + ?Sized,
+    B: BlockDevice + ?Sized,
+{
+    match (
+        first.contains(track, sector),
+        second.contains(track, sector),
+    ) {
+        (false, false) => None,
+        (true, false) => Some(SectorChange::OnlyInFirst),
+        (false, true) => Some(SectorChange::OnlyInSecond),
+        (true, true) => match (read(first, track, sector), read(second, track, sector)) {
+            (Ok(a), Ok(b)) => (a != b).then_some(SectorChange::Contents),
+            (a, b) if a.as_ref().err() == b.as_ref().err() => None,
+            (a, b) => Some(SectorChange::Error {
+                first: a.err(),
+                second: b.err(),
+            }),
+        },
+    }
+}
+
+/// Compares `first` with `second`.
+///
+/// Sectors are compared by their contents and by the errors they read
+/// with, see [`BlockDevice::sector_error`]. Files are matched by name, a
+/// name given twice in order, and count as modified if their contents,
+/// type or flags differ.
+pub fn diff<A, B>(first: &A, second: &B) -> Diff
+where
+    A: BlockDevice + ?Sized,
+    B: BlockDevice + ?Sized,
+{
+    let mut sectors = Vec::new();
+    for track in 1..=first.tracks().max(second.tracks()) {
+        let count = first
+            .sectors_per_track(track)
+            .max(second.sectors_per_track(track));
+        for sector in 0..count {
+            if let Some(change) = sector_change(first, second, track, sector) {
+                sectors.push(SectorDifference {
+                    track,
+                    sector,
+                    change,
+                });
+            }
+        }
+    }
+    let mut diff = Diff {
+        sectors,
+        files: None,
+    };
+    if let (Ok(a), Ok(b)) = (fs::read_directory(first), fs::read_directory(second)) {
+        diff.files = Some(files(&diff, first, second, a.entries, b.entries));
+    }
+    diff
+}
+
+fn files<A, B>(
+    diff: &Diff,
+    first: &A,
+    second: &B,
+    old: Vec<DirEntry>,
+    new: Vec<DirEntry>,
+) -> Vec<FileDifference>
+where
+    A: BlockDevice + ?Sized,
+    B: BlockDevice + ?Sized,
+{
+    let mut matched = alloc::vec![false; new.len()];
+    let mut out = Vec::new();
+    for entry in old {
+        let found = (0..new.len()).find(|&i| !matched[i] && new[i].name == entry.name);
+        let Some(index) = found else {
+            out.push(FileDifference {
+                entry,
+                change: FileChange::Removed,
+            });
+            continue;
+        };
+        matched[index] = true;
+        let other = &new[index];
+        let contents = fs::read_file(first, &entry).ok();
+        let same = contents.is_some()
+            && contents == fs::read_file(second, other).ok()
+            && (
+                entry.file_type,
+                entry.closed,
+                entry.locked,
+                entry.record_length,
+            ) == (
+                other.file_type,
+                other.closed,
+                other.locked,
+                other.record_length,
+            );
+        if same {
+            continue;
+        }
+        let mut blocks = fs::chain(first, entry.track, entry.sector).unwrap_or_default();
+        blocks.extend(fs::chain(second, other.track, other.sector).unwrap_or_default());
+        let mut sectors = Vec::new();
+        for block in blocks {
+            if diff.contains(block.0, block.1) && !sectors.contains(&block) {
+                sectors.push(block);
+            }
+        }
+        out.push(FileDifference {
+            entry: other.clone(),
+            change: FileChange::Modified { sectors },
+        });
+    }
+    out.extend(
+        new.into_iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(entry, _)| FileDifference {
+                entry,
+                change: FileChange::Added,
+            }),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::FileType;
+
+    #[test]
+    fn finds_changed_sectors_and_files() {
+        let mut first = D64::new(35);
+        fs::format(&mut first, b"DIFF", Some(*b"01")).unwrap();
+        fs::write_file(&mut first, b"KEEP", FileType::Prg, &[1, 8, 1]).unwrap();
+        fs::write_file(&mut first, b"EDIT", FileType::Seq, &[7; 300]).unwrap();
+        fs::write_file(&mut first, b"GONE", FileType::Prg, &[1, 8]).unwrap();
+        let mut second = first.clone();
+        assert!(diff(&first, &second).is_empty());
+
+        let edit = fs::find_file(&second, b"EDIT").unwrap().unwrap();
+        let blocks = fs::chain(&second, edit.track, edit.sector).unwrap();
+        let mut data = second.read_sector(blocks[1].0, blocks[1].1).unwrap();
+        data[10] ^= 0xFF;
+        second
+            .write_sector(blocks[1].0, blocks[1].1, &data)
+            .unwrap();
+        fs::scratch(&mut second, b"GONE").unwrap();
+        fs::write_file(&mut second, b"NEW", FileType::Usr, &[2]).unwrap();
+
+        let diff = diff(&first, &second);
+        assert!(diff.contains(blocks[1].0, blocks[1].1));
+        assert!(diff.contains(18, 1));
+        assert!(!diff.contains(blocks[0].0, blocks[0].1));
+        let files = diff.files.unwrap();
+        let changes: Vec<_> = files
+            .iter()
+            .map(|f| (f.entry.name(), f.change.clone()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    &b"EDIT"[..],
+                    FileChange::Modified {
+                        sectors: alloc::vec![blocks[1]]
+                    }
+                ),
+                (&b"GONE"[..], FileChange::Removed),
+                (&b"NEW"[..], FileChange::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_disks_of_different_sizes() {
+        let first = D64::new(35);
+        let mut second = D64::new(40);
+        let mut data = [0; 256];
+        data[0] = 1;
+        second.write_sector(1, 0, &data).unwrap();
+        let diff = diff(&first, &second);
+        assert_eq!(diff.files, Some(alloc::vec![]));
+        assert_eq!(
+            diff.sectors[0],
+            SectorDifference {
+                track: 1,
+                sector: 0,
+                change: SectorChange::Contents,
+            }
+        );
+        assert_eq!(diff.sectors.len(), 1 + 5 * 17);
+        assert!(
+            diff.sectors[1..]
+                .iter()
+                .all(|d| d.track > 35 && d.change == SectorChange::OnlyInSecond)
+        );
+    }
+}
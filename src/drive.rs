@@ -0,0 +1,1659 @@
+//! A virtual Commodore disk drive.
+//!
+//! [`VirtualDrive`] is the channel-level interface an emulator or IEC server
+//! talks to: open a secondary address with a filename, move bytes through
+//! it and close it again, with channel 15 carrying commands and status.
+//! [`Drive`] implements it on top of any [`DiskImage`].
+
+use crate::command::{self, BlockAddress, BurstCommand, Command};
+use crate::error::{DosError, DosStatus};
+use crate::fs::{self, Bam, FileType};
+use crate::image::{DiskImage, SECTOR_SIZE, Sector};
+use crate::job::{CURRENT_TRACK, DISK_ID, JOB_HEADERS, JOB_QUEUE, Job, ReturnCode};
+use crate::model::DriveModel;
+use crate::open::{self, FileSpec, Mode, OpenRequest};
+use crate::rel::{self, RelativeFile};
+use alloc::{format, vec, vec::Vec};
+
+mod burst;
+mod snapshot;
+
+pub use snapshot::{DiskReference, Snapshot};
+
+/// The command and error channel.
+pub const COMMAND_CHANNEL: u8 = 15;
+
+const RAM_SIZE: usize = 0x0800;
+const BUFFER_BASE: usize = 0x0300;
+const BUFFER_COUNT: usize = 5;
+
+/// Channel-level access to a disk drive.
+///
+/// The methods mirror what a computer does on the bus: `OPEN` a channel
+/// (secondary address 0-15) with a filename, read or write bytes and `CLOSE`
+/// it. Errors are reported both as return values and, like on real hardware,
+/// through the drive status readable on channel 15.
+pub trait VirtualDrive {
+    /// Opens `channel` with the given filename or, for channel 15, command.
+    fn open(&mut self, channel: u8, name: &[u8]) -> Result<(), DosError>;
+
+    /// Closes `channel`, committing any data written to it.
+    fn close(&mut self, channel: u8) -> Result<(), DosError>;
+
+    /// Reads the next byte from `channel`.
+    ///
+    /// Returns the byte together with an end-of-information flag that is set
+    /// on the last byte, or `None` if the channel has nothing to send.
+    fn read_byte(&mut self, channel: u8) -> Option<(u8, bool)>;
+
+    /// Sends a byte to `channel`.
+    ///
+    /// Bytes sent to channel 15 are collected and executed as a command once
+    /// a carriage return arrives or the channel is closed.
+    fn write_byte(&mut self, channel: u8, byte: u8) -> Result<(), DosError>;
+
+    /// Returns the current drive status without consuming it.
+    fn status(&self) -> DosStatus;
+
+    /// Loads `name` the way `LOAD` does: opens channel 0, reads up to the
+    /// byte flagged end-of-information and closes it again.
+    ///
+    /// `LOAD "$"` returns the directory as a BASIC program, starting with
+    /// its load address and with every line linked to the next.
+    ///
+    /// # Errors
+    /// Returns the error the drive reports when opening, reading or
+    /// closing fails.
+    fn load(&mut self, name: &[u8]) -> Result<Vec<u8>, DosError> {
+        self.open(0, name)?;
+        let mut data = Vec::new();
+        while let Some((byte, eoi)) = self.read_byte(0) {
+            data.push(byte);
+            if eoi {
+                break;
+            }
+        }
+        if let Some(error) = self.status().error() {
+            let _ = self.close(0);
+            return Err(error);
+        }
+        self.close(0)?;
+        Ok(data)
+    }
+
+    /// Executes a burst command received over a fast serial bus, with the
+    /// `data` the computer sent after it, and returns the bytes to send
+    /// back.
+    ///
+    /// Returns `None` if the drive has no burst mode; the command then goes
+    /// through channel 15 like any other. That is the default.
+    fn burst_command(&mut self, _command: &BurstCommand, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Channel {
+    /// A file or directory being read.
+    ///
+    /// With buffer emulation `pending` is the next block of the file that
+    /// has not been read from disk yet.
+    Read {
+        data: Vec<u8>,
+        position: usize,
+        pending: Option<(u8, u8)>,
+    },
+    /// A file being written; stored when the channel is closed.
+    Write {
+        name: Vec<u8>,
+        file_type: FileType,
+        data: Vec<u8>,
+        replace: bool,
+    },
+    /// A relative file; stored when the channel is closed if modified.
+    ///
+    /// `record` counts from 1 and `offset` from 0, as set by `P`.
+    Relative {
+        name: Vec<u8>,
+        file: RelativeFile,
+        record: u16,
+        offset: usize,
+        modified: bool,
+    },
+    /// A direct-access buffer opened with `#`.
+    Buffer {
+        buffer: usize,
+        pointer: u8,
+        limit: Option<u8>,
+    },
+}
+
+/// A 1541-style drive serving a mounted [`DiskImage`].
+///
+/// Every file, block and job access goes to the disk in unit 0. The dual
+/// drive models can hold a second disk in unit 1, see
+/// [`Drive::insert_second`], which only the `D` command copies to and
+/// from.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::{Drive, VirtualDrive};
+///
+/// let mut drive = Drive::new(D64::new(35));
+/// drive.open(15, b"N:DEMO,01").unwrap();
+///
+/// drive.open(1, b"HELLO").unwrap();
+/// for &b in &[0x01, 0x08, 0xEA] {
+///     drive.write_byte(1, b).unwrap();
+/// }
+/// drive.close(1).unwrap();
+///
+/// drive.open(0, b"HEL*").unwrap();
+/// assert_eq!(drive.read_byte(0), Some((0x01, false)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Drive<I> {
+    image: I,
+    second: Option<I>,
+    model: DriveModel,
+    ram: Vec<u8>,
+    channels: [Option<Channel>; COMMAND_CHANNEL as usize],
+    buffers: [bool; BUFFER_COUNT],
+    status: DosStatus,
+    output: Vec<u8>,
+    output_position: usize,
+    command: Vec<u8>,
+    burst: burst::BurstState,
+    disk_changed: bool,
+    disk_present: bool,
+    write_protected: bool,
+    dos_type: [u8; 2],
+    dos_check: bool,
+    buffer_emulation: bool,
+}
+
+impl<I: DiskImage> Drive<I> {
+    /// Creates a 1541 with `image` inserted.
+    ///
+    /// The drive starts with the power-up status `73,CBM DOS V2.6 1541` and
+    /// the disk initialized.
+    pub fn new(image: I) -> Self {
+        Self::with_model(image, DriveModel::C1541)
+    }
+
+    /// Creates a drive of the given model with `image` inserted.
+    ///
+    /// The model decides which commands are available and the texts of the
+    /// status messages; the power-up status reports its DOS version. The
+    /// disk is read with the 1541 layout whatever the model.
+    pub fn with_model(image: I, model: DriveModel) -> Self {
+        let mut drive = Drive {
+            image,
+            second: None,
+            model,
+            ram: vec![0; RAM_SIZE],
+            channels: Default::default(),
+            buffers: [false; BUFFER_COUNT],
+            status: DosStatus::new(73, 0, 0),
+            output: Vec::new(),
+            output_position: 0,
+            command: Vec::new(),
+            burst: burst::BurstState::default(),
+            disk_changed: true,
+            disk_present: true,
+            write_protected: false,
+            dos_type: [0; 2],
+            dos_check: true,
+            buffer_emulation: false,
+        };
+        let _ = drive.initialize();
+        drive.status = DosStatus::new(73, 0, 0);
+        drive
+    }
+
+    /// Returns the model the drive emulates.
+    pub fn model(&self) -> DriveModel {
+        self.model
+    }
+
+    /// Returns the mounted image.
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+
+    /// Returns the mounted image for modification.
+    pub fn image_mut(&mut self) -> &mut I {
+        &mut self.image
+    }
+
+    /// Swaps the disk in the drive, returning the one taken out.
+    ///
+    /// Like a real drive noticing the write-protect sensor toggle, the next
+    /// file-level operation initializes the new disk. Block and job access
+    /// before that still expects the old disk ID and fails with 29, disk ID
+    /// mismatch, if the new disk has a different one.
+    ///
+    /// The new disk is not write protected, unless the image is
+    /// [read-only](DiskImage::is_read_only).
+    pub fn insert(&mut self, image: I) -> I {
+        self.disk_changed = true;
+        self.disk_present = true;
+        self.write_protected = false;
+        core::mem::replace(&mut self.image, image)
+    }
+
+    /// Opens the drive door, leaving the drive without a disk.
+    ///
+    /// Every disk access then fails with 74, drive not ready, until a disk
+    /// is inserted again. The image stays attached to the drive and is
+    /// still reachable through [`Drive::image`].
+    pub fn eject(&mut self) {
+        self.disk_changed = true;
+        self.disk_present = false;
+    }
+
+    /// Returns `true` if a disk is in the drive.
+    pub fn has_disk(&self) -> bool {
+        self.disk_present
+    }
+
+    /// Puts a disk into unit 1 of a dual drive, returning the one taken
+    /// out.
+    ///
+    /// `D1=0` then duplicates the disk in unit 0 onto it and `D0=1` the
+    /// other way. Other commands and channels do not reach unit 1, and
+    /// models with one unit refuse the `D` command.
+    pub fn insert_second(&mut self, image: I) -> Option<I> {
+        self.second.replace(image)
+    }
+
+    /// Takes the disk out of unit 1.
+    pub fn eject_second(&mut self) -> Option<I> {
+        self.second.take()
+    }
+
+    /// Returns the disk in unit 1, if any.
+    pub fn second_image(&self) -> Option<&I> {
+        self.second.as_ref()
+    }
+
+    /// Covers or uncovers the write-protect notch of the disk.
+    ///
+    /// Attempts to change a protected disk fail with 26, write protect on,
+    /// whether they come from a command, a file opened for writing or a
+    /// write job.
+    pub fn set_write_protect(&mut self, protected: bool) {
+        self.write_protected = protected;
+    }
+
+    /// Returns `true` if the disk is write protected, by
+    /// [`Drive::set_write_protect`] or because the image is
+    /// [read-only](DiskImage::is_read_only).
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protected || self.image.is_read_only()
+    }
+
+    /// Turns emulation of the drive's block buffering on data channels on or
+    /// off. It is off by default.
+    ///
+    /// Without it files are read completely when opened and written when
+    /// closed, independent of the disk in the drive. With it a file being
+    /// read is fetched block by block, one block ahead of the byte being
+    /// sent, and data written stays in the drive buffers until the channel is
+    /// closed. Swapping the disk in between then behaves like on hardware:
+    /// reading goes on with the blocks already buffered and then fails, and
+    /// closing a file written to the old disk fails with 29, disk ID
+    /// mismatch, losing its data.
+    pub fn set_buffer_emulation(&mut self, enabled: bool) {
+        self.buffer_emulation = enabled;
+    }
+
+    /// Returns `true` if block buffering on data channels is emulated.
+    pub fn buffer_emulation(&self) -> bool {
+        self.buffer_emulation
+    }
+
+    /// Returns the disk ID the drive has in memory from the last
+    /// initialization.
+    pub fn disk_id(&self) -> [u8; 2] {
+        [self.ram[DISK_ID], self.ram[DISK_ID + 1]]
+    }
+
+    /// Returns the DOS type, e.g. `2A`, read from the disk header at the
+    /// last initialization.
+    pub fn dos_type(&self) -> [u8; 2] {
+        self.dos_type
+    }
+
+    /// Turns the DOS type check on or off. It is on by default.
+    ///
+    /// Like the real DOS, the drive then refuses to change a disk whose
+    /// header names a different DOS type than the model formats, e.g. a
+    /// 1581 disk in a 1541 or a `2A` disk in a 1581. Such writes fail with
+    /// 73, DOS mismatch, while reading stays possible and `N` reformats the
+    /// disk. Tools that repair or convert disks can turn the check off.
+    pub fn set_dos_check(&mut self, enabled: bool) {
+        self.dos_check = enabled;
+    }
+
+    /// Returns `true` if writes are refused on disks of another DOS type.
+    pub fn dos_check(&self) -> bool {
+        self.dos_check
+    }
+
+    /// Consumes the drive and returns its image.
+    pub fn into_image(self) -> I {
+        self.image
+    }
+
+    /// Returns the drive's 2 KiB of RAM, which holds the five buffers at
+    /// `$0300`-`$07FF`.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Executes a DOS command and updates the status accordingly.
+    pub fn execute(&mut self, input: &[u8]) -> Result<(), DosError> {
+        span!("command {}", input.escape_ascii());
+        self.output.clear();
+        self.output_position = 0;
+        let result = command::parse(input).and_then(|cmd| self.run(cmd));
+        if let Err(error) = result
+            && self.status.is_ok()
+        {
+            self.status = DosStatus::from(error);
+        }
+        event!("status {}", self.status);
+        result
+    }
+
+    fn fail<T>(&mut self, error: DosError, track: u8, sector: u8) -> Result<T, DosError> {
+        self.status = DosStatus::from_error(error, track, sector);
+        Err(error)
+    }
+
+    /// Reads the BAM of the disk and takes over its ID, like the `I`
+    /// command.
+    ///
+    /// Resets the head to the directory track, clears the error status and
+    /// the disk changed flag.
+    fn initialize(&mut self) -> Result<(), DosError> {
+        if !self.disk_present {
+            return Err(DosError::DriveNotReady);
+        }
+        let bam = Bam::read(&self.image)?;
+        self.ram[DISK_ID..DISK_ID + 2].copy_from_slice(&bam.disk_id());
+        self.dos_type = bam.dos_type();
+        self.ram[CURRENT_TRACK] = fs::DIR_TRACK;
+        self.disk_changed = false;
+        self.status = DosStatus::ok();
+        Ok(())
+    }
+
+    /// Initializes the disk if it was changed since the last access through
+    /// the file system.
+    fn check_disk(&mut self) -> Result<(), DosError> {
+        if self.disk_changed || !self.disk_present {
+            self.initialize()?;
+        }
+        Ok(())
+    }
+
+    /// Fails unless a disk that may be written is in the drive.
+    fn writable(&self) -> Result<(), DosError> {
+        if !self.disk_present {
+            Err(DosError::DriveNotReady)
+        } else if self.is_write_protected() {
+            Err(DosError::WriteProtect)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Copies the disk of the other unit onto the one in unit `target`,
+    /// like the `D` command, initializing unit 0 if it was written.
+    fn duplicate(&mut self, target: u8) -> Result<(), DosError> {
+        if !self.disk_present {
+            return Err(DosError::DriveNotReady);
+        }
+        match target {
+            0 => {
+                self.writable()?;
+                let second = self.second.as_ref().ok_or(DosError::DriveNotReady)?;
+                fs::duplicate(second, &mut self.image)?;
+                self.initialize()
+            }
+            1 => {
+                let second = self.second.as_mut().ok_or(DosError::DriveNotReady)?;
+                if second.is_read_only() {
+                    return Err(DosError::WriteProtect);
+                }
+                fs::duplicate(&self.image, second)
+            }
+            _ => Err(DosError::DriveNotReady),
+        }
+    }
+
+    /// Fails if the DOS type check is on and the disk was formatted for
+    /// another DOS.
+    fn check_dos_type(&self) -> Result<(), DosError> {
+        if self.dos_check && self.dos_type != self.model.dos_type() {
+            Err(DosError::DosMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn run(&mut self, cmd: Command) -> Result<(), DosError> {
+        self.status = DosStatus::ok();
+        if !self.model.supports(&cmd) {
+            return self.fail(DosError::InvalidCommand, 0, 0);
+        }
+        let file_level = matches!(
+            cmd,
+            Command::Validate { .. }
+                | Command::Scratch { .. }
+                | Command::Rename { .. }
+                | Command::Copy { .. }
+                | Command::BlockAllocate { .. }
+                | Command::BlockFree { .. }
+        );
+        if file_level {
+            self.check_disk()?;
+            self.writable()?;
+            self.check_dos_type()?;
+        }
+        if let Command::New { .. } = cmd {
+            self.writable()?;
+        }
+        match cmd {
+            Command::Initialize { .. } => self.initialize(),
+            Command::Validate { .. } => fs::validate(&mut self.image),
+            Command::New { name, id, .. } => {
+                fs::format(&mut self.image, &name, id)?;
+                let mut bam = Bam::read(&self.image)?;
+                bam.set_dos_type(self.model.dos_type());
+                bam.write(&mut self.image)?;
+                self.initialize()
+            }
+            Command::Scratch { patterns, .. } => {
+                let mut count = 0u8;
+                for pattern in patterns {
+                    count = count.saturating_add(fs::scratch(&mut self.image, &pattern)?);
+                }
+                self.status = DosStatus::new(1, count, 0);
+                Ok(())
+            }
+            Command::Rename {
+                new_name, old_name, ..
+            } => fs::rename(&mut self.image, &new_name, &old_name),
+            Command::Copy {
+                new_name, sources, ..
+            } => fs::copy(&mut self.image, &new_name, &sources).map(|_| ()),
+            Command::Duplicate { target, source } if target == source => {
+                self.fail(DosError::Syntax, 0, 0)
+            }
+            Command::Duplicate { target, .. } => self.duplicate(target),
+            Command::BlockRead(address) => self.read_block(address, true),
+            Command::UserRead(address) => self.read_block(address, false),
+            Command::BlockExecute(address) => self.read_block(address, false),
+            Command::BlockWrite(address) => self.write_block(address, true),
+            Command::UserWrite(address) => self.write_block(address, false),
+            Command::BlockAllocate { track, sector, .. } => self.allocate_block(track, sector),
+            Command::BlockFree { track, sector, .. } => {
+                if !self.image.contains(track, sector) {
+                    return self.fail(DosError::IllegalTrackSector, track, sector);
+                }
+                let mut bam = Bam::read(&self.image)?;
+                bam.free(track, sector);
+                bam.write(&mut self.image)
+            }
+            Command::BufferPointer { channel, position } => match self.buffer_channel(channel) {
+                Some(Channel::Buffer { pointer, limit, .. }) => {
+                    *pointer = position;
+                    *limit = None;
+                    Ok(())
+                }
+                _ => self.fail(DosError::NoChannel, 0, 0),
+            },
+            Command::Position {
+                channel,
+                record,
+                offset,
+            } => {
+                let result = match self
+                    .channels
+                    .get_mut(channel as usize)
+                    .and_then(Option::as_mut)
+                {
+                    Some(Channel::Relative {
+                        file,
+                        record: current,
+                        offset: position,
+                        ..
+                    }) => {
+                        *current = record.max(1);
+                        *position = offset.max(1) as usize - 1;
+                        if *position >= file.record_length() as usize {
+                            Err(DosError::OverflowInRecord)
+                        } else if *current > file.records() {
+                            Err(DosError::RecordNotPresent)
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    Some(_) => Err(DosError::FileTypeMismatch),
+                    None => Err(DosError::NoChannel),
+                };
+                result.or_else(|error| self.fail(error, 0, 0))
+            }
+            Command::MemoryRead { address, length } => {
+                let length = if length == 0 { 256 } else { length as usize };
+                self.output = (0..length)
+                    .map(|i| {
+                        let a = address.wrapping_add(i as u16) as usize;
+                        self.ram.get(a).copied().unwrap_or(0)
+                    })
+                    .collect();
+                Ok(())
+            }
+            Command::MemoryWrite { address, data } => {
+                for (i, byte) in data.into_iter().enumerate() {
+                    if let Some(cell) = self.ram.get_mut(address as usize + i) {
+                        *cell = byte;
+                    }
+                }
+                Ok(())
+            }
+            Command::MemoryExecute { .. } | Command::UserJump(_) => Ok(()),
+            Command::WarmReset | Command::Reset => {
+                self.channels = Default::default();
+                self.buffers = [false; BUFFER_COUNT];
+                self.burst = burst::BurstState::default();
+                self.disk_changed = true;
+                self.status = DosStatus::new(73, 0, 0);
+                Ok(())
+            }
+            Command::Burst(burst) => {
+                self.output = self.burst(&burst, &[]);
+                Ok(())
+            }
+            // The mounted images have neither subdirectories nor a clock,
+            // and the SD2IEC extensions are not emulated.
+            Command::ChangeDirectory { .. }
+            | Command::MakeDirectory { .. }
+            | Command::RemoveDirectory { .. }
+            | Command::TimeRead(_)
+            | Command::TimeWrite { .. }
+            | Command::Config(_)
+            | Command::EepromRead { .. }
+            | Command::EepromWrite { .. } => Err(DosError::InvalidCommand),
+        }
+    }
+
+    fn buffer_channel(&mut self, channel: u8) -> Option<&mut Channel> {
+        self.channels
+            .get_mut(channel as usize)
+            .and_then(Option::as_mut)
+            .filter(|c| matches!(c, Channel::Buffer { .. }))
+    }
+
+    fn buffer_range(buffer: usize) -> core::ops::Range<usize> {
+        let start = BUFFER_BASE + buffer * SECTOR_SIZE;
+        start..start + SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, address: BlockAddress, length_byte: bool) -> Result<(), DosError> {
+        let BlockAddress { track, sector, .. } = address;
+        let Some(Channel::Buffer { buffer, .. }) = self.buffer_channel(address.channel) else {
+            return self.fail(DosError::NoChannel, 0, 0);
+        };
+        let buffer = *buffer;
+        if !self.image.contains(track, sector) {
+            return self.fail(DosError::IllegalTrackSector, track, sector);
+        }
+        let code = self.post_job(buffer as u8, Job::Read, track, sector);
+        let length = self.ram[Self::buffer_range(buffer).start];
+        if let Some(Channel::Buffer { pointer, limit, .. }) = self.buffer_channel(address.channel) {
+            *pointer = if length_byte { 1 } else { 0 };
+            *limit = length_byte.then_some(length);
+        }
+        match code.error() {
+            Some(error) => self.fail(error, track, sector),
+            None => Ok(()),
+        }
+    }
+
+    fn write_block(&mut self, address: BlockAddress, length_byte: bool) -> Result<(), DosError> {
+        let BlockAddress { track, sector, .. } = address;
+        let Some(Channel::Buffer {
+            buffer, pointer, ..
+        }) = self.buffer_channel(address.channel)
+        else {
+            return self.fail(DosError::NoChannel, 0, 0);
+        };
+        let (buffer, pointer) = (*buffer, *pointer);
+        if !self.image.contains(track, sector) {
+            return self.fail(DosError::IllegalTrackSector, track, sector);
+        }
+        if length_byte {
+            self.ram[Self::buffer_range(buffer).start] = pointer.wrapping_sub(1);
+        }
+        match self
+            .post_job(buffer as u8, Job::Write, track, sector)
+            .error()
+        {
+            Some(error) => self.fail(error, track, sector),
+            None => Ok(()),
+        }
+    }
+
+    /// Places a job for `buffer` in the queue and runs the controller.
+    ///
+    /// This is the path the DOS itself uses for every disk access. The job
+    /// code and the track/sector are stored in drive RAM exactly where the
+    /// 1541 keeps them, so the queue can equally be driven through `M-W`.
+    /// Jobs that would run 6502 code in the buffer complete without effect;
+    /// use [`Drive::run_jobs_with`] to supply an executor.
+    ///
+    /// # Parameters
+    /// - `buffer`: the buffer (0-4) the job works on.
+    /// - `job`: the job to perform.
+    /// - `track`, `sector`: the location the job refers to.
+    ///
+    /// # Returns
+    /// The return code the controller left in the queue slot.
+    pub fn post_job(&mut self, buffer: u8, job: Job, track: u8, sector: u8) -> ReturnCode {
+        let slot = buffer as usize % BUFFER_COUNT;
+        self.ram[JOB_HEADERS + 2 * slot] = track;
+        self.ram[JOB_HEADERS + 2 * slot + 1] = sector;
+        self.ram[JOB_QUEUE + slot] = job.code();
+        self.run_jobs();
+        ReturnCode(self.ram[JOB_QUEUE + slot])
+    }
+
+    /// Runs every job pending in the queue.
+    pub fn run_jobs(&mut self) {
+        self.run_jobs_with(|_, _| ReturnCode::OK);
+    }
+
+    /// Runs every job pending in the queue, handing `Jump` and `Execute`
+    /// jobs to `execute`.
+    ///
+    /// The closure receives the drive RAM and the buffer number and returns
+    /// the code to leave in the queue slot.
+    pub fn run_jobs_with<F>(&mut self, mut execute: F)
+    where
+        F: FnMut(&mut [u8], u8) -> ReturnCode,
+    {
+        for slot in 0..BUFFER_COUNT {
+            let code = self.ram[JOB_QUEUE + slot];
+            if code & 0x80 == 0 {
+                continue;
+            }
+            let track = self.ram[JOB_HEADERS + 2 * slot];
+            let sector = self.ram[JOB_HEADERS + 2 * slot + 1];
+            let result = match Job::from_code(code) {
+                Some(job) => self.perform(slot, job, track, sector, &mut execute),
+                None => ReturnCode::from_error(DosError::HeaderNotFound),
+            };
+            self.ram[JOB_QUEUE + slot] = result.0;
+        }
+    }
+
+    /// Returns the track the head is positioned on.
+    pub fn head_track(&self) -> u8 {
+        self.ram[CURRENT_TRACK]
+    }
+
+    fn perform<F>(
+        &mut self,
+        slot: usize,
+        job: Job,
+        track: u8,
+        sector: u8,
+        execute: &mut F,
+    ) -> ReturnCode
+    where
+        F: FnMut(&mut [u8], u8) -> ReturnCode,
+    {
+        let range = Self::buffer_range(slot);
+        match job {
+            Job::Bump => {
+                self.ram[CURRENT_TRACK] = 1;
+                return ReturnCode::OK;
+            }
+            Job::Jump => return execute(&mut self.ram, slot as u8),
+            _ => {}
+        }
+        if track == 0 || track > self.image.tracks() {
+            return ReturnCode::from_error(DosError::HeaderNotFound);
+        }
+        self.ram[CURRENT_TRACK] = track;
+        match job {
+            Job::Seek => return ReturnCode::OK,
+            Job::Execute => return execute(&mut self.ram, slot as u8),
+            _ => {}
+        }
+        let (data, error) = match self.fetch(track, sector) {
+            Ok(found) => found,
+            Err(code) => return code,
+        };
+        match job {
+            Job::Read => {
+                if error != Some(DosError::DataBlockNotPresent) {
+                    self.ram[range].copy_from_slice(&data);
+                }
+                error.map_or(ReturnCode::OK, ReturnCode::from_error)
+            }
+            Job::Write if self.is_write_protected() => {
+                ReturnCode::from_error(DosError::WriteProtect)
+            }
+            Job::Write => {
+                let mut block = [0u8; SECTOR_SIZE];
+                block.copy_from_slice(&self.ram[range]);
+                match self.image.write_sector(track, sector, &block) {
+                    Ok(()) => ReturnCode::OK,
+                    Err(error) => ReturnCode::from_error(error),
+                }
+            }
+            Job::Verify if self.ram[range] != data[..] => {
+                ReturnCode::from_error(DosError::WriteVerify)
+            }
+            _ => ReturnCode::OK,
+        }
+    }
+
+    /// Locates a sector the way the controller does.
+    ///
+    /// Fails with the return code of a header-level error, leaving nothing
+    /// the job could work on; otherwise returns the sector data and the error
+    /// reading its data block would produce.
+    fn fetch(&self, track: u8, sector: u8) -> Result<(Sector, Option<DosError>), ReturnCode> {
+        if !self.disk_present {
+            return Err(ReturnCode::from_error(DosError::DriveNotReady));
+        }
+        let Ok(data) = self.image.read_sector(track, sector) else {
+            return Err(ReturnCode::from_error(DosError::HeaderNotFound));
+        };
+        match self.image.sector_error(track, sector) {
+            Some(
+                error @ (DosError::HeaderNotFound
+                | DosError::NoSync
+                | DosError::HeaderChecksum
+                | DosError::DiskIdMismatch),
+            ) => Err(ReturnCode::from_error(error)),
+            _ if self.header_id() != self.disk_id() => {
+                Err(ReturnCode::from_error(DosError::DiskIdMismatch))
+            }
+            error => Ok((data, error)),
+        }
+    }
+
+    /// Returns the ID written into the sector headers of the disk, which is
+    /// the ID stored in its BAM.
+    fn header_id(&self) -> [u8; 2] {
+        self.image
+            .read_sector(fs::DIR_TRACK, fs::BAM_SECTOR)
+            .map(|bam| [bam[0xA2], bam[0xA3]])
+            .unwrap_or([0, 0])
+    }
+
+    fn allocate_block(&mut self, track: u8, sector: u8) -> Result<(), DosError> {
+        if !self.image.contains(track, sector) {
+            return self.fail(DosError::IllegalTrackSector, track, sector);
+        }
+        let mut bam = Bam::read(&self.image)?;
+        if bam.allocate(track, sector) {
+            return bam.write(&mut self.image);
+        }
+        let next = (sector..self.image.sectors_per_track(track))
+            .map(|s| (track, s))
+            .chain(
+                (track + 1..=self.image.tracks())
+                    .flat_map(|t| (0..self.image.sectors_per_track(t)).map(move |s| (t, s))),
+            )
+            .find(|&(t, s)| t != fs::DIR_TRACK && bam.is_free(t, s))
+            .unwrap_or((0, 0));
+        self.fail(DosError::NoBlock, next.0, next.1)
+    }
+
+    fn open_buffer(&mut self, channel: u8, requested: Option<u8>) -> Result<(), DosError> {
+        let buffer = match requested.map(usize::from) {
+            Some(n) if n < BUFFER_COUNT && !self.buffers[n] => Some(n),
+            Some(_) => None,
+            None => self.buffers.iter().position(|used| !used),
+        };
+        let Some(buffer) = buffer else {
+            return self.fail(DosError::NoChannel, 0, 0);
+        };
+        self.buffers[buffer] = true;
+        self.channels[channel as usize] = Some(Channel::Buffer {
+            buffer,
+            pointer: 0,
+            limit: None,
+        });
+        Ok(())
+    }
+
+    /// Opens the directory. Channel 0 sends the listing `LOAD` expects,
+    /// limited to the entries selected by `patterns` and `file_type`; other
+    /// channels read the raw directory blocks.
+    fn open_directory(
+        &mut self,
+        channel: u8,
+        patterns: &[Vec<u8>],
+        file_type: Option<FileType>,
+    ) -> Result<(), DosError> {
+        self.check_disk()?;
+        let data = if channel == 0 {
+            let mut directory = fs::read_directory(&self.image)?;
+            directory.filter(patterns, file_type);
+            directory.listing()
+        } else {
+            fs::read_chain(&self.image, fs::DIR_TRACK, fs::BAM_SECTOR)?
+        };
+        self.channels[channel as usize] = Some(Channel::Read {
+            data,
+            position: 0,
+            pending: None,
+        });
+        Ok(())
+    }
+
+    fn open_file(&mut self, channel: u8, spec: FileSpec) -> Result<(), DosError> {
+        self.check_disk()?;
+        let existing = fs::find_file(&self.image, &spec.name)?;
+        if let Some(entry) = existing.as_ref().filter(|e| e.file_type == FileType::Rel) {
+            let file = RelativeFile::read(&self.image, entry)?;
+            if spec
+                .record_length
+                .is_some_and(|l| l != file.record_length())
+            {
+                return self.fail(DosError::RecordNotPresent, 0, 0);
+            }
+            self.channels[channel as usize] = Some(Channel::Relative {
+                name: entry.name().to_vec(),
+                file,
+                record: 1,
+                offset: 0,
+                modified: false,
+            });
+            return Ok(());
+        }
+        if let Some(length) = spec.record_length {
+            if existing.is_some() {
+                return self.fail(DosError::FileTypeMismatch, 0, 0);
+            }
+            if let Err(error) = self.writable().and_then(|()| self.check_dos_type()) {
+                return self.fail(error, 0, 0);
+            }
+            if spec.is_pattern() {
+                return self.fail(DosError::InvalidFilename, 0, 0);
+            }
+            let file = match RelativeFile::new(length) {
+                Ok(file) => file,
+                Err(error) => return self.fail(error, 0, 0),
+            };
+            self.channels[channel as usize] = Some(Channel::Relative {
+                name: spec.name,
+                file,
+                record: 1,
+                offset: 0,
+                modified: true,
+            });
+            return Ok(());
+        }
+        let file_type = spec.type_for(channel);
+        let mode = spec.mode_for(channel);
+        if matches!(mode, Mode::Write | Mode::Append)
+            && let Err(error) = self.writable().and_then(|()| self.check_dos_type())
+        {
+            return self.fail(error, 0, 0);
+        }
+        match mode {
+            Mode::Read | Mode::Modify => {
+                let Some(entry) = existing else {
+                    return self.fail(DosError::FileNotFound, 0, 0);
+                };
+                if file_type.is_some_and(|t| t != entry.file_type) {
+                    return self.fail(DosError::FileTypeMismatch, 0, 0);
+                }
+                let (data, pending) = if self.buffer_emulation {
+                    (Vec::new(), Some((entry.track, entry.sector)))
+                } else {
+                    (fs::read_file(&self.image, &entry)?, None)
+                };
+                self.channels[channel as usize] = Some(Channel::Read {
+                    data,
+                    position: 0,
+                    pending,
+                });
+                self.read_ahead(channel);
+            }
+            Mode::Write => {
+                if spec.is_pattern() {
+                    return self.fail(DosError::InvalidFilename, 0, 0);
+                }
+                if existing.is_some() && !spec.replace {
+                    return self.fail(DosError::FileExists, 0, 0);
+                }
+                self.channels[channel as usize] = Some(Channel::Write {
+                    name: spec.name,
+                    file_type: file_type.unwrap_or(FileType::Seq),
+                    data: Vec::new(),
+                    replace: spec.replace,
+                });
+            }
+            Mode::Append => {
+                let Some(entry) = existing else {
+                    return self.fail(DosError::FileNotFound, 0, 0);
+                };
+                let data = fs::read_file(&self.image, &entry)?;
+                self.channels[channel as usize] = Some(Channel::Write {
+                    name: entry.name().to_vec(),
+                    file_type: entry.file_type,
+                    data,
+                    replace: true,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads blocks of a file channel until one block beyond the current one
+    /// is buffered.
+    ///
+    /// A block that cannot be read ends the file and leaves the error in the
+    /// status.
+    fn read_ahead(&mut self, channel: u8) {
+        loop {
+            let Some(Some(Channel::Read {
+                data,
+                position,
+                pending: Some((track, sector)),
+            })) = self.channels.get(channel as usize)
+            else {
+                return;
+            };
+            if data.len() >= position + 2 * fs::BLOCK_PAYLOAD {
+                return;
+            }
+            let (track, sector) = (*track, *sector);
+            let fetched = self.fetch(track, sector);
+            self.ram[CURRENT_TRACK] = track;
+            let (payload, next) = match fetched {
+                Ok((block, None)) if block[0] == 0 => {
+                    let last = (block[1] as usize).max(1);
+                    (block[2..=last].to_vec(), None)
+                }
+                Ok((block, None)) if self.image.contains(block[0], block[1]) => {
+                    (block[2..].to_vec(), Some((block[0], block[1])))
+                }
+                Ok((_, None)) => {
+                    self.status =
+                        DosStatus::from_error(DosError::IllegalTrackSector, track, sector);
+                    (Vec::new(), None)
+                }
+                Ok((_, Some(error))) => {
+                    self.status = DosStatus::from_error(error, track, sector);
+                    (Vec::new(), None)
+                }
+                Err(code) => {
+                    let error = code.error().unwrap_or(DosError::HeaderNotFound);
+                    self.status = DosStatus::from_error(error, track, sector);
+                    (Vec::new(), None)
+                }
+            };
+            if let Some(Some(Channel::Read { data, pending, .. })) =
+                self.channels.get_mut(channel as usize)
+            {
+                data.extend_from_slice(&payload);
+                *pending = next;
+            }
+        }
+    }
+
+    fn commit(&mut self, channel: Channel) -> Result<(), DosError> {
+        if matches!(
+            channel,
+            Channel::Write { .. } | Channel::Relative { modified: true, .. }
+        ) {
+            self.writable()?;
+        }
+        if self.buffer_emulation
+            && matches!(
+                channel,
+                Channel::Write { .. } | Channel::Relative { modified: true, .. }
+            )
+            && self.header_id() != self.disk_id()
+        {
+            return Err(DosError::DiskIdMismatch);
+        }
+        match channel {
+            Channel::Write {
+                name,
+                file_type,
+                data,
+                replace,
+            } => {
+                if replace {
+                    fs::replace_file(&mut self.image, &name, file_type, &data).map(|_| ())
+                } else {
+                    fs::write_file(&mut self.image, &name, file_type, &data).map(|_| ())
+                }
+            }
+            Channel::Relative {
+                name,
+                file,
+                modified: true,
+                ..
+            } => {
+                file.replace(&mut self.image, &name).map(|_| ())
+            }
+            Channel::Buffer { buffer, .. } => {
+                self.buffers[buffer] = false;
+                Ok(())
+            }
+            Channel::Read { .. } | Channel::Relative { .. } => Ok(()),
+        }
+    }
+
+    fn status_byte(&mut self) -> Option<(u8, bool)> {
+        if self.output.is_empty() {
+            self.output = format!("{}\r", self.status()).into_bytes();
+            self.output_position = 0;
+            self.status = DosStatus::ok();
+        }
+        let byte = *self.output.get(self.output_position)?;
+        self.output_position += 1;
+        let last = self.output_position == self.output.len();
+        if last {
+            self.output.clear();
+            self.output_position = 0;
+        }
+        Some((byte, last))
+    }
+}
+
+impl<I: DiskImage> VirtualDrive for Drive<I> {
+    fn open(&mut self, channel: u8, name: &[u8]) -> Result<(), DosError> {
+        if channel == COMMAND_CHANNEL {
+            return if name.is_empty() {
+                Ok(())
+            } else {
+                self.execute(name)
+            };
+        }
+        if channel > COMMAND_CHANNEL {
+            return self.fail(DosError::NoChannel, 0, 0);
+        }
+        if let Some(previous) = self.channels[channel as usize].take() {
+            self.commit(previous)?;
+        }
+        self.status = DosStatus::ok();
+        let result = match open::parse(name) {
+            Ok(OpenRequest::Buffer(buffer)) => self.open_buffer(channel, buffer),
+            Ok(OpenRequest::Directory {
+                patterns,
+                file_type,
+                ..
+            }) => self.open_directory(channel, &patterns, file_type),
+            Ok(OpenRequest::File(spec)) => self.open_file(channel, spec),
+            Err(error) => self.fail(error, 0, 0),
+        };
+        if let Err(error) = result
+            && self.status.is_ok()
+        {
+            self.status = error.into();
+        }
+        result
+    }
+
+    fn close(&mut self, channel: u8) -> Result<(), DosError> {
+        if channel == COMMAND_CHANNEL {
+            if !self.command.is_empty() {
+                let command = core::mem::take(&mut self.command);
+                self.execute(&command)?;
+            }
+            return Ok(());
+        }
+        let Some(open) = self
+            .channels
+            .get_mut(channel as usize)
+            .and_then(Option::take)
+        else {
+            return Ok(());
+        };
+        self.commit(open).inspect_err(|&e| self.status = e.into())
+    }
+
+    fn read_byte(&mut self, channel: u8) -> Option<(u8, bool)> {
+        if channel == COMMAND_CHANNEL {
+            return self.status_byte();
+        }
+        self.read_ahead(channel);
+        match self.channels.get_mut(channel as usize)?.as_mut()? {
+            Channel::Read {
+                data,
+                position,
+                pending,
+            } => {
+                let byte = *data.get(*position)?;
+                *position += 1;
+                Some((byte, *position == data.len() && pending.is_none()))
+            }
+            Channel::Buffer {
+                buffer,
+                pointer,
+                limit,
+            } => {
+                let byte = self.ram[Self::buffer_range(*buffer)][*pointer as usize];
+                let last = match limit {
+                    Some(limit) => *pointer >= *limit,
+                    None => *pointer == u8::MAX,
+                };
+                *pointer = pointer.wrapping_add(1);
+                Some((byte, last))
+            }
+            Channel::Relative {
+                file,
+                record,
+                offset,
+                ..
+            } => {
+                // A write that filled the record leaves the channel at its
+                // end, where the drive goes on with the next one.
+                if file.record(*record).is_some_and(|data| *offset >= data.len()) {
+                    *record = record.saturating_add(1);
+                    *offset = 0;
+                }
+                let Some(data) = file.record(*record) else {
+                    self.status = DosStatus::from(DosError::RecordNotPresent);
+                    return None;
+                };
+                let end = rel::record_contents(data).len().max(*offset + 1);
+                let byte = data[*offset];
+                *offset += 1;
+                let last = *offset >= end;
+                if last {
+                    *record = record.saturating_add(1);
+                    *offset = 0;
+                }
+                Some((byte, last))
+            }
+            Channel::Write { .. } => None,
+        }
+    }
+
+    fn write_byte(&mut self, channel: u8, byte: u8) -> Result<(), DosError> {
+        if channel == COMMAND_CHANNEL {
+            if byte == b'\r' {
+                let command = core::mem::take(&mut self.command);
+                return self.execute(&command);
+            }
+            self.command.push(byte);
+            return Ok(());
+        }
+        let protected = self.is_write_protected();
+        let result = match self
+            .channels
+            .get_mut(channel as usize)
+            .and_then(Option::as_mut)
+        {
+            Some(Channel::Write { data, .. }) => {
+                data.push(byte);
+                Ok(())
+            }
+            Some(Channel::Buffer {
+                buffer, pointer, ..
+            }) => {
+                let index = Self::buffer_range(*buffer).start + *pointer as usize;
+                self.ram[index] = byte;
+                *pointer = pointer.wrapping_add(1);
+                Ok(())
+            }
+            Some(Channel::Relative { .. }) if protected => Err(DosError::WriteProtect),
+            Some(Channel::Relative {
+                file,
+                record,
+                offset,
+                modified,
+                ..
+            }) => write_record(file, *record, offset, byte).map(|()| *modified = true),
+            _ => Err(DosError::FileNotOpen),
+        };
+        result.or_else(|error| self.fail(error, 0, 0))
+    }
+
+    fn status(&self) -> DosStatus {
+        DosStatus {
+            message: self.model.message(self.status.code),
+            ..self.status.clone()
+        }
+    }
+
+    fn burst_command(&mut self, command: &BurstCommand, data: &[u8]) -> Option<Vec<u8>> {
+        self.model.has_burst().then(|| self.burst(command, data))
+    }
+}
+
+/// Stores `byte` at `offset` of a relative file record, growing the file if
+/// the record does not exist yet.
+///
+/// The rest of the record is cleared, so that a record always ends with
+/// what was written to it last.
+fn write_record(
+    file: &mut RelativeFile,
+    record: u16,
+    offset: &mut usize,
+    byte: u8,
+) -> Result<(), DosError> {
+    file.expand(record)?;
+    let data = file.record_mut(record).ok_or(DosError::RecordNotPresent)?;
+    if *offset >= data.len() {
+        return Err(DosError::OverflowInRecord);
+    }
+    data[*offset] = byte;
+    data[*offset + 1..].fill(0);
+    *offset += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::g64::G64;
+    use crate::image::{BlockDevice, ReadOnly};
+
+    fn drive() -> Drive<D64> {
+        let mut drive = Drive::new(D64::new(35));
+        drive.open(15, b"N:TEST,01").unwrap();
+        drive
+    }
+
+    fn read_all(drive: &mut Drive<D64>, channel: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some((byte, eoi)) = drive.read_byte(channel) {
+            out.push(byte);
+            if eoi {
+                break;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn file_round_trip_and_status() {
+        let mut drive = Drive::with_model(D64::new(35), DriveModel::C1571);
+        assert_eq!(read_all(&mut drive, 15), b"73,CBM DOS V3.0 1571,00,00\r");
+        drive.execute(b"N:TEST,01").unwrap();
+        drive.open(2, b"0:NOTES,S,W").unwrap();
+        for &b in b"HELLO" {
+            drive.write_byte(2, b).unwrap();
+        }
+        drive.close(2).unwrap();
+
+        drive.open(3, b"NOTES,S,R").unwrap();
+        assert_eq!(read_all(&mut drive, 3), b"HELLO");
+        drive.close(3).unwrap();
+
+        assert_eq!(drive.open(4, b"MISSING"), Err(DosError::FileNotFound));
+        assert_eq!(read_all(&mut drive, 15), b"62,FILE NOT FOUND,00,00\r");
+        assert_eq!(read_all(&mut drive, 15), b"00, OK,00,00\r");
+
+        for &b in b"S:NOTES\r" {
+            let _ = drive.write_byte(15, b);
+        }
+        assert_eq!(drive.status().to_string(), "01,FILES SCRATCHED,01,00");
+    }
+
+    #[test]
+    fn block_access_through_buffers() {
+        let mut drive = drive();
+        drive.open(2, b"#").unwrap();
+        drive.execute(b"U1:2,0,18,0").unwrap();
+        assert_eq!(read_all(&mut drive, 2)[..3], [18, 1, b'A']);
+
+        drive.execute(b"B-P:2,0").unwrap();
+        drive.write_byte(2, 0x42).unwrap();
+        drive.execute(b"U2:2,0,1,0").unwrap();
+        assert_eq!(drive.image().read_sector(1, 0).unwrap()[0], 0x42);
+
+        drive.execute(b"B-A:0,1,0").unwrap();
+        assert_eq!(drive.execute(b"B-A:0,1,0"), Err(DosError::NoBlock));
+        assert_eq!(drive.status().to_string(), "65,NO BLOCK,01,01");
+    }
+
+    #[test]
+    fn relative_records_through_position() {
+        let mut drive = drive();
+        drive.open(2, b"ADDRESSES,L,\x40").unwrap();
+        drive.execute(&[b'P', 0x62, 3, 0, 1]).unwrap();
+        for &b in b"ALICE\r" {
+            drive.write_byte(2, b).unwrap();
+        }
+        assert_eq!(
+            drive.execute(&[b'P', 0x62, 10, 0]),
+            Err(DosError::RecordNotPresent)
+        );
+        for &b in b"BOB" {
+            drive.write_byte(2, b).unwrap();
+        }
+        drive.close(2).unwrap();
+
+        drive.open(2, b"ADDRESSES").unwrap();
+        drive.execute(&[b'P', 0x62, 3, 0, 2]).unwrap();
+        assert_eq!(read_all(&mut drive, 2), b"LICE\r");
+        assert_eq!(read_all(&mut drive, 2), [0xFF]);
+        drive.execute(&[b'P', 0x62, 10, 0]).unwrap();
+        assert_eq!(read_all(&mut drive, 2), b"BOB");
+        assert_eq!(
+            drive.execute(&[b'P', 0x62, 1, 0, 65]),
+            Err(DosError::OverflowInRecord)
+        );
+        assert_eq!(drive.execute(b"P\x63"), Err(DosError::NoChannel));
+    }
+
+    #[test]
+    fn duplicates_between_the_units_of_a_dual_drive() {
+        let mut master = D64::new(35);
+        fs::format(&mut master, b"MASTER", Some(*b"MA")).unwrap();
+        fs::write_file(&mut master, b"GAME", FileType::Prg, &[1, 8, 0x60]).unwrap();
+        let mut drive = Drive::with_model(master.clone(), DriveModel::C4040);
+        assert_eq!(drive.execute(b"D:1=0"), Err(DosError::DriveNotReady));
+        assert_eq!(drive.status().code, 74);
+        assert_eq!(drive.insert_second(D64::new(35)), None);
+        drive.execute(b"D:1=0").unwrap();
+        assert!(drive.second_image() == Some(&master));
+        assert_eq!(drive.execute(b"D:1=1"), Err(DosError::Syntax));
+
+        let mut other = D64::new(35);
+        fs::format(&mut other, b"OTHER", Some(*b"OT")).unwrap();
+        drive.insert_second(other.clone());
+        drive.set_write_protect(true);
+        assert_eq!(drive.execute(b"D:0=1"), Err(DosError::WriteProtect));
+        drive.set_write_protect(false);
+        drive.execute(b"D:0=1").unwrap();
+        assert!(*drive.image() == other);
+        assert_eq!(drive.disk_id(), *b"OT");
+        assert!(drive.eject_second() == Some(other));
+
+        let mut single = Drive::new(master);
+        assert_eq!(single.execute(b"D:1=0"), Err(DosError::InvalidCommand));
+    }
+
+    #[test]
+    fn reads_on_after_filling_a_record() {
+        let mut drive = drive();
+        drive.open(2, b"PAIRS,L,\x04").unwrap();
+        drive.execute(&[b'P', 0x62, 2, 0]).unwrap();
+        for &b in b"ABCD" {
+            drive.write_byte(2, b).unwrap();
+        }
+        drive.execute(&[b'P', 0x62, 1, 0]).unwrap();
+        for &b in b"WXYZ" {
+            drive.write_byte(2, b).unwrap();
+        }
+        assert_eq!(read_all(&mut drive, 2), b"ABCD");
+        assert_eq!(read_all(&mut drive, 2), [0xFF]);
+    }
+
+    #[test]
+    fn failed_replace_keeps_the_old_file() {
+        let mut drive = drive();
+        fs::write_file(drive.image_mut(), b"NOTES", FileType::Seq, b"OLD").unwrap();
+        drive.open(2, b"@:NOTES,S,W").unwrap();
+        for _ in 0..700 * fs::BLOCK_PAYLOAD {
+            drive.write_byte(2, 0x42).unwrap();
+        }
+        assert_eq!(drive.close(2), Err(DosError::DiskFull));
+        let entry = fs::find_file(drive.image(), b"NOTES").unwrap().unwrap();
+        assert_eq!(fs::read_file(drive.image(), &entry).unwrap(), b"OLD");
+        assert!(crate::integrity::check(drive.image()).issues.is_empty());
+
+        drive.open(2, b"@:NOTES,S,W").unwrap();
+        drive.write_byte(2, b'N').unwrap();
+        drive.close(2).unwrap();
+        let entry = fs::find_file(drive.image(), b"NOTES").unwrap().unwrap();
+        assert_eq!(fs::read_file(drive.image(), &entry).unwrap(), b"N");
+        assert_eq!(fs::read_directory(drive.image()).unwrap().entries.len(), 1);
+        assert_eq!(Bam::read(drive.image()).unwrap().blocks_free(), 663);
+    }
+
+    #[test]
+    fn failed_relative_update_keeps_the_file() {
+        let mut drive = drive();
+        drive.open(2, b"DATA,L,\xFE").unwrap();
+        for &b in b"OLD" {
+            drive.write_byte(2, b).unwrap();
+        }
+        drive.close(2).unwrap();
+
+        // Growing the file beyond the disk fails when it is closed.
+        drive.open(2, b"DATA").unwrap();
+        assert_eq!(
+            drive.execute(&[b'P', 0x62, 0xBC, 2]),
+            Err(DosError::RecordNotPresent)
+        );
+        drive.write_byte(2, b'X').unwrap();
+        assert_eq!(drive.close(2), Err(DosError::DiskFull));
+        drive.open(2, b"DATA").unwrap();
+        assert_eq!(read_all(&mut drive, 2), b"OLD");
+        drive.close(2).unwrap();
+        assert!(crate::integrity::check(drive.image()).issues.is_empty());
+    }
+
+    #[test]
+    fn buffered_channels_notice_disk_swaps() {
+        let mut drive = drive();
+        drive.set_buffer_emulation(true);
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        fs::write_file(drive.image_mut(), b"LONG", FileType::Seq, &data).unwrap();
+        drive.open(2, b"LONG,S").unwrap();
+        drive.open(3, b"NEW,S,W").unwrap();
+        drive.write_byte(3, 0x42).unwrap();
+        assert_eq!(drive.read_byte(2), Some((0, false)));
+
+        let mut other = D64::new(35);
+        fs::format(&mut other, b"OTHER", Some(*b"02")).unwrap();
+        drive.insert(other);
+        assert_eq!(read_all(&mut drive, 2), data[1..2 * fs::BLOCK_PAYLOAD]);
+        assert_eq!(drive.status().code, 29);
+        assert_eq!(drive.close(3), Err(DosError::DiskIdMismatch));
+        assert_eq!(fs::find_file(drive.image(), b"NEW"), Ok(None));
+
+        drive.set_buffer_emulation(false);
+        drive.open(3, b"NEW,S,W").unwrap();
+        drive.close(3).unwrap();
+        assert_eq!(
+            fs::find_file(drive.image(), b"NEW")
+                .unwrap()
+                .unwrap()
+                .blocks,
+            1
+        );
+    }
+
+    #[test]
+    fn swapped_disk_needs_initialize() {
+        let mut drive = drive();
+        let mut other = D64::new(35);
+        fs::format(&mut other, b"OTHER", Some(*b"02")).unwrap();
+        drive.insert(other);
+
+        drive.open(2, b"#").unwrap();
+        assert_eq!(drive.execute(b"U1:2,0,18,0"), Err(DosError::DiskIdMismatch));
+        assert_eq!(drive.status().to_string(), "29,DISK ID MISMATCH,18,00");
+        drive.execute(b"I0").unwrap();
+        assert_eq!(drive.disk_id(), *b"02");
+        drive.execute(b"U1:2,0,18,0").unwrap();
+
+        drive.insert(D64::new(35));
+        assert_eq!(drive.post_job(0, Job::Read, 1, 0), ReturnCode(0x0B));
+        assert_eq!(drive.open(3, b"$"), Ok(()));
+        assert_eq!(drive.disk_id(), [0, 0]);
+    }
+
+    #[test]
+    fn write_protect_and_missing_disk() {
+        let mut drive = drive();
+        drive.set_write_protect(true);
+        assert_eq!(drive.execute(b"S:*"), Err(DosError::WriteProtect));
+        assert_eq!(drive.status().to_string(), "26,WRITE PROTECT ON,00,00");
+        assert_eq!(drive.open(1, b"NEW"), Err(DosError::WriteProtect));
+        assert_eq!(drive.post_job(0, Job::Write, 1, 0), ReturnCode(0x08));
+        assert_eq!(drive.post_job(0, Job::Read, 1, 0), ReturnCode::OK);
+        drive.set_write_protect(false);
+        drive.open(1, b"NEW").unwrap();
+        drive.close(1).unwrap();
+
+        drive.eject();
+        assert!(!drive.has_disk());
+        assert_eq!(drive.open(0, b"$"), Err(DosError::DriveNotReady));
+        assert_eq!(drive.status().to_string(), "74,DRIVE NOT READY,00,00");
+        assert_eq!(drive.post_job(0, Job::Read, 18, 0), ReturnCode(0x0F));
+        assert_eq!(drive.execute(b"I"), Err(DosError::DriveNotReady));
+
+        let image = drive.insert(D64::new(35));
+        drive.insert(image);
+        assert!(drive.has_disk() && !drive.is_write_protected());
+        assert_eq!(drive.open(0, b"NEW"), Ok(()));
+    }
+
+    #[test]
+    fn read_only_images_are_write_protected() {
+        let master = drive().image().clone();
+        let mut drive = Drive::new(ReadOnly::new(master.clone()));
+        assert!(drive.is_write_protected());
+        assert_eq!(drive.execute(b"N:WIPED"), Err(DosError::WriteProtect));
+        assert_eq!(drive.status().to_string(), "26,WRITE PROTECT ON,00,00");
+        assert_eq!(drive.open(1, b"NEW"), Err(DosError::WriteProtect));
+        assert_eq!(drive.post_job(0, Job::Write, 1, 0), ReturnCode(0x08));
+        drive.open(2, b"#").unwrap();
+        assert_eq!(drive.execute(b"U2:2,0,1,0"), Err(DosError::WriteProtect));
+        drive.set_write_protect(false);
+        assert!(drive.is_write_protected());
+        assert_eq!(drive.open(0, b"$"), Ok(()));
+
+        let mut image = drive.image().clone();
+        assert_eq!(
+            fs::write_file(&mut image, b"NEW", FileType::Prg, &[1, 8]),
+            Err(DosError::WriteProtect)
+        );
+        assert_eq!(image.into_inner(), master);
+    }
+
+    #[test]
+    fn foreign_dos_type_refuses_writes() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"OLD", Some(*b"01")).unwrap();
+        let mut drive = Drive::with_model(image, DriveModel::C1581);
+        assert_eq!(drive.dos_type(), *b"2A");
+        assert_eq!(drive.open(1, b"NEW"), Err(DosError::DosMismatch));
+        assert_eq!(drive.execute(b"V"), Err(DosError::DosMismatch));
+        assert_eq!(drive.open(0, b"$"), Ok(()));
+
+        drive.set_dos_check(false);
+        drive.open(1, b"NEW").unwrap();
+        drive.close(1).unwrap();
+        drive.set_dos_check(true);
+        drive.execute(b"N:NEW").unwrap();
+        assert_eq!(drive.dos_type(), *b"3D");
+        assert_eq!(fs::read_directory(drive.image()).unwrap().dos_type, *b"3D");
+        assert_eq!(drive.open(1, b"NEW"), Ok(()));
+    }
+
+    #[test]
+    fn load_serves_directory_listing() {
+        let mut drive = drive();
+        fs::write_file(drive.image_mut(), b"ALPHA", FileType::Prg, b"A").unwrap();
+        fs::write_file(drive.image_mut(), b"BETA", FileType::Seq, b"B").unwrap();
+
+        let listing = drive.load(b"$").unwrap();
+        assert_eq!(listing[..2], [0x01, 0x04]);
+        let mut address = 0x0401;
+        let mut lines = 0;
+        loop {
+            let at = address - 0x0401 + 2;
+            let link = u16::from_le_bytes([listing[at], listing[at + 1]]) as usize;
+            if link == 0 {
+                assert_eq!(at + 2, listing.len());
+                break;
+            }
+            assert_eq!(listing[link - 0x0401 + 1], 0);
+            address = link;
+            lines += 1;
+        }
+        assert_eq!(lines, 4);
+
+        let listing = drive.load(b"$:A*,B*=S").unwrap();
+        assert_eq!(listing.windows(4).filter(|w| w == b"SEQ ").count(), 1);
+        assert!(!listing.windows(5).any(|w| w == b"ALPHA"));
+        assert_eq!(drive.load(b"MISSING"), Err(DosError::FileNotFound));
+    }
+
+    #[test]
+    fn g64_read_errors_reach_error_channel() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"PROTECTED", Some(*b"PR")).unwrap();
+        let errors = [
+            (1, 0, DosError::HeaderNotFound),
+            (1, 1, DosError::DataBlockNotPresent),
+            (1, 2, DosError::DataChecksum),
+            (1, 3, DosError::ByteDecoding),
+            (1, 4, DosError::HeaderChecksum),
+            (1, 5, DosError::DiskIdMismatch),
+            (2, 0, DosError::NoSync),
+        ];
+        for &(track, sector, error) in &errors {
+            image.set_sector_error(track, sector, Some(error)).unwrap();
+        }
+        let mut drive = Drive::new(G64::from_image(&image));
+
+        drive.open(2, b"#").unwrap();
+        for (track, sector, error) in errors {
+            let command = format!("U1:2,0,{track},{sector}");
+            assert_eq!(drive.execute(command.as_bytes()), Err(error));
+            assert_eq!(drive.status().code, error.code());
+            assert_eq!(
+                (drive.status().track, drive.status().sector),
+                (track, sector)
+            );
+        }
+        drive.execute(b"U1:2,0,1,6").unwrap();
+        assert_eq!(drive.open(3, b"$"), Ok(()));
+    }
+
+    #[test]
+    fn job_queue_reports_controller_codes() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"JOBS", Some(*b"01")).unwrap();
+        image
+            .set_sector_error(1, 3, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut drive = Drive::new(image);
+
+        assert_eq!(drive.post_job(0, Job::Read, 18, 0), ReturnCode::OK);
+        assert_eq!(drive.ram()[0x0300..0x0302], [18, 1]);
+        assert_eq!(drive.head_track(), 18);
+        assert_eq!(drive.post_job(0, Job::Verify, 18, 0), ReturnCode::OK);
+        assert_eq!(drive.post_job(0, Job::Verify, 18, 1), ReturnCode(0x07));
+        assert_eq!(drive.post_job(1, Job::Read, 1, 3), ReturnCode(0x05));
+        assert_eq!(drive.post_job(1, Job::Seek, 36, 0), ReturnCode(0x02));
+        assert_eq!(drive.post_job(1, Job::Bump, 0, 0), ReturnCode::OK);
+        assert_eq!(drive.head_track(), 1);
+
+        drive
+            .execute(&[b'M', b'-', b'W', 0x00, 0x00, 1, 0xD0])
+            .unwrap();
+        let mut called = None;
+        drive.run_jobs_with(|_, buffer| {
+            called = Some(buffer);
+            ReturnCode(0x01)
+        });
+        assert_eq!(called, Some(0));
+    }
+}
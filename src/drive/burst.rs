@@ -0,0 +1,332 @@
+//! The 1571/1581 burst command set.
+//!
+//! Burst commands are sent as `U0` plus a command byte on channel 15. Their
+//! replies are byte streams rather than status strings: every sector or
+//! block is preceded by a burst status byte whose low nibble is the
+//! controller return code, so `$x1` means success and `$x5` a data checksum
+//! error. Bits 4 and 5 give the sector size, which is always 256 bytes here,
+//! and bit 7 is clear because the images carry GCR data.
+
+use super::Drive;
+use crate::command::BurstCommand;
+use crate::error::DosError;
+use crate::fs::{self, BLOCK_PAYLOAD};
+use crate::image::{DiskImage, SECTOR_SIZE, Sector};
+use crate::job::{CURRENT_TRACK, ReturnCode};
+use alloc::vec::Vec;
+
+/// Sector size bits of a status byte for 256-byte sectors.
+const SIZE_256: u8 = 0x10;
+/// Controller status for a format the drive cannot write.
+const FORMAT_ERROR: u8 = 0x06;
+/// Controller status for a malformed command or missing data.
+const SYNTAX_ERROR: u8 = 0x0E;
+/// Status byte announcing the last block of a fast load.
+const END_OF_FILE: u8 = 0x1F;
+
+/// Settings and results kept between burst commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BurstState {
+    pub(super) interleave: u8,
+    pub(super) status: u8,
+}
+
+impl Default for BurstState {
+    fn default() -> Self {
+        BurstState {
+            interleave: 1,
+            status: SIZE_256 | ReturnCode::OK.0,
+        }
+    }
+}
+
+fn status_byte(code: ReturnCode) -> u8 {
+    SIZE_256 | (code.0 & 0x0F)
+}
+
+impl<I: DiskImage> Drive<I> {
+    /// Executes a burst command and returns the bytes the drive sends back.
+    ///
+    /// `data` holds the bytes the computer transfers after the command,
+    /// which only sector writes use. Commands sent as `U0` through channel 15
+    /// run with no data, and their reply is read from channel 15 in place of
+    /// the status.
+    ///
+    /// Supported are sector reads and writes, GCR formatting, disk and status
+    /// inquiries, the interleave setting and fast loads. Multi-sector
+    /// transfers continue with sector 0 of the next track after the last
+    /// sector of a track. Utility commands are accepted without effect.
+    ///
+    /// # Example
+    /// ```
+    /// use cbm_dos::command::BurstCommand;
+    /// use cbm_dos::d64::D64;
+    /// use cbm_dos::drive::Drive;
+    ///
+    /// let mut drive = Drive::new(D64::new(35));
+    /// drive.execute(b"N:BURST,01").unwrap();
+    /// let reply = drive.burst(
+    ///     &BurstCommand::Read { side: 0, track: 18, sector: 0, count: 1, ignore_errors: false },
+    ///     &[],
+    /// );
+    /// assert_eq!(reply.len(), 257);
+    /// assert_eq!(reply[0], 0x11);
+    /// assert_eq!(reply[1..3], [18, 1]);
+    /// ```
+    pub fn burst(&mut self, command: &BurstCommand, data: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+        let status = match *command {
+            BurstCommand::Read {
+                track,
+                sector,
+                count,
+                ignore_errors,
+                ..
+            } => self.burst_read(track, sector, count, ignore_errors, &mut reply),
+            BurstCommand::Write {
+                track,
+                sector,
+                count,
+                ignore_errors,
+                ..
+            } => self.burst_write(track, sector, count, ignore_errors, data, &mut reply),
+            BurstCommand::InquireDisk { .. } | BurstCommand::QueryDiskFormat { .. } => {
+                let status = self.inquire();
+                reply.push(status);
+                status
+            }
+            BurstCommand::Format {
+                mfm,
+                ref parameters,
+                ..
+            } => {
+                let status = match (mfm, parameters.as_slice()) {
+                    (false, [a, b, ..]) => match self
+                        .writable()
+                        .and_then(|()| fs::format(&mut self.image, b"", Some([*a, *b])))
+                    {
+                        Ok(()) => status_byte(ReturnCode::OK),
+                        Err(error) => status_byte(ReturnCode::from_error(error)),
+                    },
+                    (false, _) => SIZE_256 | SYNTAX_ERROR,
+                    (true, _) => SIZE_256 | FORMAT_ERROR,
+                };
+                reply.push(status);
+                status
+            }
+            BurstCommand::Interleave { value } => {
+                match value {
+                    Some(value) => self.burst.interleave = value,
+                    None => reply.push(self.burst.interleave),
+                }
+                return reply;
+            }
+            BurstCommand::InquireStatus { .. } => {
+                reply.push(self.burst.status);
+                return reply;
+            }
+            BurstCommand::Utility { .. } => return reply,
+            BurstCommand::FastLoad { ref name, .. } => self.fast_load(name, &mut reply),
+        };
+        self.burst.status = status;
+        reply
+    }
+
+    fn burst_sectors(&self, track: u8, sector: u8, count: u8) -> Vec<(u8, u8)> {
+        let mut location = (track, sector);
+        let mut sectors = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            sectors.push(location);
+            location.1 = location.1.wrapping_add(1);
+            if location.1 >= self.image.sectors_per_track(location.0) {
+                location = (location.0.wrapping_add(1), 0);
+            }
+        }
+        sectors
+    }
+
+    fn burst_read(
+        &mut self,
+        track: u8,
+        sector: u8,
+        count: u8,
+        ignore_errors: bool,
+        reply: &mut Vec<u8>,
+    ) -> u8 {
+        let mut status = status_byte(ReturnCode::OK);
+        for (t, s) in self.burst_sectors(track, sector, count) {
+            let (code, data) = match self.fetch(t, s) {
+                Ok((data, error)) => (
+                    error.map_or(ReturnCode::OK, ReturnCode::from_error),
+                    (error != Some(DosError::DataBlockNotPresent)).then_some(data),
+                ),
+                Err(code) => (code, None),
+            };
+            self.ram[CURRENT_TRACK] = t;
+            status = status_byte(code);
+            reply.push(status);
+            if !code.is_ok() && !ignore_errors {
+                break;
+            }
+            reply.extend_from_slice(&data.unwrap_or([0; SECTOR_SIZE]));
+        }
+        status
+    }
+
+    fn burst_write(
+        &mut self,
+        track: u8,
+        sector: u8,
+        count: u8,
+        ignore_errors: bool,
+        data: &[u8],
+        reply: &mut Vec<u8>,
+    ) -> u8 {
+        let mut status = status_byte(ReturnCode::OK);
+        let mut blocks = data.chunks(SECTOR_SIZE);
+        for (t, s) in self.burst_sectors(track, sector, count) {
+            let Some(block) = blocks.next().and_then(|b| Sector::try_from(b).ok()) else {
+                status = SIZE_256 | SYNTAX_ERROR;
+                reply.push(status);
+                break;
+            };
+            let code = match self.fetch(t, s) {
+                Ok(_) if self.is_write_protected() => {
+                    ReturnCode::from_error(DosError::WriteProtect)
+                }
+                Ok(_) => match self.image.write_sector(t, s, &block) {
+                    Ok(()) => ReturnCode::OK,
+                    Err(error) => ReturnCode::from_error(error),
+                },
+                Err(code) => code,
+            };
+            self.ram[CURRENT_TRACK] = t;
+            status = status_byte(code);
+            reply.push(status);
+            if !code.is_ok() && !ignore_errors {
+                break;
+            }
+        }
+        status
+    }
+
+    /// Checks that the directory header can be read, as the drive does to
+    /// detect a disk.
+    fn inquire(&self) -> u8 {
+        match self.fetch(fs::DIR_TRACK, fs::BAM_SECTOR) {
+            Ok(_) => status_byte(ReturnCode::OK),
+            Err(code) => status_byte(code),
+        }
+    }
+
+    /// Sends a file as a sequence of blocks, each preceded by a status byte.
+    ///
+    /// The last block is announced with `$1F` followed by its length.
+    fn fast_load(&mut self, name: &[u8], reply: &mut Vec<u8>) -> u8 {
+        let data = fs::find_file(&self.image, name)
+            .and_then(|entry| entry.ok_or(DosError::FileNotFound))
+            .and_then(|entry| fs::read_file(&self.image, &entry));
+        let data = match data {
+            Ok(data) => data,
+            Err(error) => {
+                let status = status_byte(ReturnCode::from_error(error));
+                reply.push(status);
+                return status;
+            }
+        };
+        let mut blocks = data.chunks(BLOCK_PAYLOAD).peekable();
+        while let Some(block) = blocks.next() {
+            if blocks.peek().is_some() {
+                reply.push(status_byte(ReturnCode::OK));
+            } else {
+                reply.push(END_OF_FILE);
+                reply.push(block.len() as u8);
+            }
+            reply.extend_from_slice(block);
+        }
+        if data.is_empty() {
+            reply.extend_from_slice(&[END_OF_FILE, 0]);
+        }
+        status_byte(ReturnCode::OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::VirtualDrive;
+    use crate::fs::FileType;
+    use crate::image::BlockDevice;
+    use crate::model::DriveModel;
+
+    fn drive() -> Drive<D64> {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"BURST", Some(*b"01")).unwrap();
+        image
+            .set_sector_error(1, 1, Some(DosError::DataChecksum))
+            .unwrap();
+        Drive::with_model(image, DriveModel::C1571)
+    }
+
+    #[test]
+    fn sector_transfers_report_controller_status() {
+        let mut drive = drive();
+        let read = BurstCommand::Read {
+            side: 0,
+            track: 1,
+            sector: 0,
+            count: 3,
+            ignore_errors: false,
+        };
+        let reply = drive.burst(&read, &[]);
+        assert_eq!(reply.len(), 258);
+        assert_eq!((reply[0], reply[257]), (0x11, 0x15));
+
+        let write = BurstCommand::Write {
+            side: 0,
+            track: 2,
+            sector: 20,
+            count: 2,
+            ignore_errors: false,
+        };
+        let data = [[0xAA; SECTOR_SIZE], [0xBB; SECTOR_SIZE]].concat();
+        assert_eq!(drive.burst(&write, &data), [0x11, 0x11]);
+        assert_eq!(drive.image().read_sector(3, 0).unwrap()[0], 0xBB);
+        assert_eq!(drive.burst(&write, &data[..SECTOR_SIZE]), [0x11, 0x1E]);
+
+        drive.open(15, &[b'U', b'0', 0x0C]).unwrap();
+        assert_eq!(drive.read_byte(15), Some((0x1E, true)));
+
+        let mut c1541 = Drive::new(D64::new(35));
+        assert_eq!(
+            c1541.open(15, &[b'U', b'0', 0x0C]),
+            Err(DosError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn fast_load_sends_blocks() {
+        let mut drive = drive();
+        let program: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        fs::write_file(drive.image_mut(), b"GAME", FileType::Prg, &program).unwrap();
+
+        let reply = drive.burst(
+            &BurstCommand::FastLoad {
+                name: b"GA*".to_vec(),
+                sequential: false,
+            },
+            &[],
+        );
+        assert_eq!(reply[0], 0x11);
+        assert_eq!(reply[1..255], program[..254]);
+        assert_eq!(reply[255..257], [0x1F, 46]);
+        assert_eq!(reply[257..], program[254..]);
+
+        let missing = BurstCommand::FastLoad {
+            name: b"NONE".to_vec(),
+            sequential: false,
+        };
+        assert_eq!(drive.burst(&missing, &[]), [0x12]);
+    }
+}
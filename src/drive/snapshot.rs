@@ -0,0 +1,568 @@
+//! Save states of a drive.
+//!
+//! A [`Snapshot`] holds everything a [`Drive`] keeps apart from the disk:
+//! RAM with the job queue and buffers, open channels with their data and
+//! positions, the status with the error pending on channel 15, the
+//! pending command and the disk state flags. The disk image is saved by
+//! its own format; the snapshot refers to it by a [`DiskReference`], so
+//! an emulator restoring a save state can tell whether the disk it has
+//! inserted is the one the state was saved with. Snapshots serialize to a
+//! compact binary form, for embedding into the save states of emulators:
+//!
+//! ```plaintext
+//! $00  "CBMDSNAP"           signature
+//! $08  version (2)
+//! $09  model, in the order of DriveModel::ALL
+//! $0A  flags                bit 0 disk changed, 1 disk present,
+//!                           2 write protected, 3 DOS check,
+//!                           4 buffer emulation
+//! $0B  DOS type (2 bytes)
+//! $0D  status code, track, sector
+//! $10  burst interleave, burst status
+//! $12  allocated buffers    bit n for buffer n
+//! $13  RAM, output, command and channels
+//! ...  disk                 0 without a disk, else 1, the name, the size
+//!                           (u64), the CRC-32 and the SHA-1 of its sectors
+//! ```
+//!
+//! Variable-length fields are stored with a little-endian `u32` length.
+//! Snapshots of version 1, which end behind the channels, are read
+//! without a disk reference.
+
+use super::{BUFFER_COUNT, COMMAND_CHANNEL, Channel, Drive, RAM_SIZE, burst::BurstState};
+use crate::error::DosStatus;
+use crate::fs::FileType;
+use crate::hash;
+use crate::image::{BlockDevice, DiskImage, ImageError};
+use crate::job::CURRENT_TRACK;
+use crate::model::DriveModel;
+use crate::rel::RelativeFile;
+use alloc::vec::Vec;
+
+/// The signature at the start of a serialized snapshot.
+pub const SIGNATURE: &[u8; 8] = b"CBMDSNAP";
+
+const VERSION: u8 = 2;
+
+/// What identifies the disk a snapshot was taken with: the hashes of its
+/// sectors, as [`hash::sectors`] reads them, and the name the host knows
+/// its image by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskReference {
+    /// The name of the image, such as its path, empty unless set with
+    /// [`Snapshot::set_disk_name`].
+    pub name: Vec<u8>,
+    /// The number of bytes of the sectors.
+    pub size: u64,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl DiskReference {
+    /// Returns the reference of the disk `image`, without a name.
+    pub fn of<I: BlockDevice + ?Sized>(image: &I) -> Self {
+        let hashes = hash::sectors(image);
+        DiskReference {
+            name: Vec::new(),
+            size: hashes.size,
+            crc32: hashes.crc32,
+            sha1: hashes.sha1,
+        }
+    }
+
+    /// Returns `true` if `image` holds the disk referred to, whatever its
+    /// name and format.
+    pub fn matches<I: BlockDevice + ?Sized>(&self, image: &I) -> bool {
+        let other = DiskReference::of(image);
+        (self.size, self.crc32, self.sha1) == (other.size, other.crc32, other.sha1)
+    }
+}
+
+/// The state of a drive without its disk.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::{Drive, Snapshot, VirtualDrive};
+///
+/// let mut drive = Drive::new(D64::new(35));
+/// drive.open(15, b"N:SAVE,01").unwrap();
+/// drive.open(2, b"#").unwrap();
+/// let mut snapshot = drive.snapshot();
+/// snapshot.set_disk_name(b"games/save.d64");
+/// let saved = snapshot.to_bytes();
+///
+/// drive.close(2).unwrap();
+/// let snapshot = Snapshot::from_bytes(&saved).unwrap();
+/// let disk = snapshot.disk().unwrap();
+/// assert_eq!(disk.name, b"games/save.d64");
+/// assert!(disk.matches(drive.image()));
+/// drive.restore(&snapshot);
+/// assert_eq!(drive.snapshot().open_channels(), [2]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    model: DriveModel,
+    ram: Vec<u8>,
+    channels: Vec<Option<Channel>>,
+    buffers: [bool; BUFFER_COUNT],
+    status: (u8, u8, u8),
+    output: Vec<u8>,
+    output_position: usize,
+    command: Vec<u8>,
+    burst: BurstState,
+    disk_changed: bool,
+    disk_present: bool,
+    write_protected: bool,
+    dos_type: [u8; 2],
+    dos_check: bool,
+    buffer_emulation: bool,
+    disk: Option<DiskReference>,
+}
+
+impl Snapshot {
+    /// Returns the model of the drive.
+    pub fn model(&self) -> DriveModel {
+        self.model
+    }
+
+    /// Returns the drive RAM.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Returns the status the drive reports on channel 15.
+    pub fn status(&self) -> DosStatus {
+        let (code, track, sector) = self.status;
+        DosStatus {
+            message: self.model.message(code),
+            ..DosStatus::new(code, track, sector)
+        }
+    }
+
+    /// Returns the track the head is on.
+    pub fn head_track(&self) -> u8 {
+        self.ram[CURRENT_TRACK]
+    }
+
+    /// Returns the secondary addresses with an open channel, in order.
+    pub fn open_channels(&self) -> Vec<u8> {
+        (0..COMMAND_CHANNEL)
+            .filter(|&c| self.channels[c as usize].is_some())
+            .collect()
+    }
+
+    /// Returns `true` if the disk was swapped since the last
+    /// initialization.
+    pub fn disk_changed(&self) -> bool {
+        self.disk_changed
+    }
+
+    /// Returns the reference of the disk that was inserted, if one was.
+    pub fn disk(&self) -> Option<&DiskReference> {
+        self.disk.as_ref()
+    }
+
+    /// Sets the name the reference of the disk gives its image, if a disk
+    /// was inserted.
+    pub fn set_disk_name(&mut self, name: &[u8]) {
+        if let Some(disk) = &mut self.disk {
+            disk.name = name.to_vec();
+        }
+    }
+
+    /// Serializes the snapshot.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        out.push(VERSION);
+        out.push(
+            DriveModel::ALL
+                .iter()
+                .position(|&m| m == self.model)
+                .unwrap_or(0) as u8,
+        );
+        let flags = [
+            self.disk_changed,
+            self.disk_present,
+            self.write_protected,
+            self.dos_check,
+            self.buffer_emulation,
+        ];
+        out.push(flags.iter().rev().fold(0, |acc, &f| acc << 1 | f as u8));
+        out.extend_from_slice(&self.dos_type);
+        out.extend_from_slice(&[self.status.0, self.status.1, self.status.2]);
+        out.extend_from_slice(&[self.burst.interleave, self.burst.status]);
+        out.push(
+            self.buffers
+                .iter()
+                .rev()
+                .fold(0, |acc, &b| acc << 1 | b as u8),
+        );
+        put_bytes(&mut out, &self.ram);
+        put_bytes(&mut out, &self.output);
+        put_u32(&mut out, self.output_position);
+        put_bytes(&mut out, &self.command);
+        for channel in &self.channels {
+            put_channel(&mut out, channel.as_ref());
+        }
+        match &self.disk {
+            None => out.push(0),
+            Some(disk) => {
+                out.push(1);
+                put_bytes(&mut out, &disk.name);
+                out.extend_from_slice(&disk.size.to_le_bytes());
+                out.extend_from_slice(&disk.crc32.to_le_bytes());
+                out.extend_from_slice(&disk.sha1);
+            }
+        }
+        out
+    }
+
+    /// Parses a serialized snapshot.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] if the data does not start with
+    ///   `CBMDSNAP`.
+    /// - [`ImageError::Unsupported`] for versions other than 1 and 2.
+    /// - [`ImageError::Truncated`] if the data ends early.
+    /// - [`ImageError::InvalidSize`] if the RAM size is wrong.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if bytes.get(..8) != Some(&SIGNATURE[..]) {
+            return Err(ImageError::InvalidSignature);
+        }
+        let mut input = Reader { bytes, at: 8 };
+        let version = input.byte()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(ImageError::Unsupported("snapshot version"));
+        }
+        let model = *DriveModel::ALL
+            .get(input.byte()? as usize)
+            .ok_or(ImageError::Unsupported("drive model"))?;
+        let flags = input.byte()?;
+        let flag = |bit: u8| flags & (1 << bit) != 0;
+        let dos_type = [input.byte()?, input.byte()?];
+        let status = (input.byte()?, input.byte()?, input.byte()?);
+        let burst = BurstState {
+            interleave: input.byte()?,
+            status: input.byte()?,
+        };
+        let allocated = input.byte()?;
+        let ram = input.bytes()?;
+        if ram.len() != RAM_SIZE {
+            return Err(ImageError::InvalidSize(ram.len()));
+        }
+        let output = input.bytes()?;
+        let output_position = input.u32()?;
+        let command = input.bytes()?;
+        let channels = (0..COMMAND_CHANNEL)
+            .map(|_| input.channel())
+            .collect::<Result<_, _>>()?;
+        let disk = if version >= 2 && input.byte()? != 0 {
+            let name = input.bytes()?;
+            let size = input.take(8)?;
+            let size = u64::from_le_bytes(core::array::from_fn(|i| size[i]));
+            let crc32 = input.u32()? as u32;
+            let mut sha1 = [0; 20];
+            sha1.copy_from_slice(input.take(20)?);
+            Some(DiskReference {
+                name,
+                size,
+                crc32,
+                sha1,
+            })
+        } else {
+            None
+        };
+        Ok(Snapshot {
+            model,
+            ram,
+            channels,
+            buffers: core::array::from_fn(|b| allocated & (1 << b) != 0),
+            status,
+            output,
+            output_position,
+            command,
+            burst,
+            disk_changed: flag(0),
+            disk_present: flag(1),
+            write_protected: flag(2),
+            dos_check: flag(3),
+            dos_type,
+            buffer_emulation: flag(4),
+            disk,
+        })
+    }
+}
+
+impl<I: DiskImage> Drive<I> {
+    /// Captures the state of the drive, leaving out the disk but for its
+    /// [`DiskReference`], which reads every sector.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            model: self.model,
+            ram: self.ram.clone(),
+            channels: self.channels.to_vec(),
+            buffers: self.buffers,
+            status: (self.status.code, self.status.track, self.status.sector),
+            output: self.output.clone(),
+            output_position: self.output_position,
+            command: self.command.clone(),
+            burst: self.burst.clone(),
+            disk_changed: self.disk_changed,
+            disk_present: self.disk_present,
+            write_protected: self.write_protected,
+            dos_type: self.dos_type,
+            dos_check: self.dos_check,
+            buffer_emulation: self.buffer_emulation,
+            disk: self.disk_present.then(|| DiskReference::of(&self.image)),
+        }
+    }
+
+    /// Puts the drive back into the state captured by `snapshot`, keeping
+    /// the disk that is inserted.
+    ///
+    /// Restore the disk first if the state refers to data on it, such as a
+    /// file being read with buffer emulation, and check it with
+    /// [`DiskReference::matches`].
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let (code, track, sector) = snapshot.status;
+        self.model = snapshot.model;
+        self.ram.clone_from(&snapshot.ram);
+        for (channel, saved) in self.channels.iter_mut().zip(&snapshot.channels) {
+            channel.clone_from(saved);
+        }
+        self.buffers = snapshot.buffers;
+        self.status = DosStatus::new(code, track, sector);
+        self.output.clone_from(&snapshot.output);
+        self.output_position = snapshot.output_position;
+        self.command.clone_from(&snapshot.command);
+        self.burst = snapshot.burst.clone();
+        self.disk_changed = snapshot.disk_changed;
+        self.disk_present = snapshot.disk_present;
+        self.write_protected = snapshot.write_protected;
+        self.dos_type = snapshot.dos_type;
+        self.dos_check = snapshot.dos_check;
+        self.buffer_emulation = snapshot.buffer_emulation;
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn put_channel(out: &mut Vec<u8>, channel: Option<&Channel>) {
+    match channel {
+        None => out.push(0),
+        Some(Channel::Read {
+            data,
+            position,
+            pending,
+        }) => {
+            out.push(1);
+            put_bytes(out, data);
+            put_u32(out, *position);
+            match pending {
+                Some((track, sector)) => out.extend_from_slice(&[1, *track, *sector]),
+                None => out.extend_from_slice(&[0, 0, 0]),
+            }
+        }
+        Some(Channel::Write {
+            name,
+            file_type,
+            data,
+            replace,
+        }) => {
+            out.push(2);
+            put_bytes(out, name);
+            out.push(file_type.to_byte());
+            put_bytes(out, data);
+            out.push(*replace as u8);
+        }
+        Some(Channel::Relative {
+            name,
+            file,
+            record,
+            offset,
+            modified,
+        }) => {
+            out.push(3);
+            put_bytes(out, name);
+            out.push(file.record_length());
+            put_bytes(out, file.as_bytes());
+            out.extend_from_slice(&record.to_le_bytes());
+            put_u32(out, *offset);
+            out.push(*modified as u8);
+        }
+        Some(Channel::Buffer {
+            buffer,
+            pointer,
+            limit,
+        }) => {
+            out.push(4);
+            out.extend_from_slice(&[*buffer as u8, *pointer]);
+            match limit {
+                Some(limit) => out.extend_from_slice(&[1, *limit]),
+                None => out.extend_from_slice(&[0, 0]),
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], ImageError> {
+        let taken = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or(ImageError::Truncated)?;
+        self.at += len;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, ImageError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, ImageError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, ImageError> {
+        let len = self.u32()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn channel(&mut self) -> Result<Option<Channel>, ImageError> {
+        let channel = match self.byte()? {
+            0 => return Ok(None),
+            1 => {
+                let data = self.bytes()?;
+                let position = self.u32()?;
+                let [flag, track, sector] = self.take(3)? else {
+                    unreachable!()
+                };
+                Channel::Read {
+                    data,
+                    position,
+                    pending: (*flag != 0).then_some((*track, *sector)),
+                }
+            }
+            2 => Channel::Write {
+                name: self.bytes()?,
+                file_type: FileType::from_byte(self.byte()?)
+                    .ok_or(ImageError::Unsupported("file type"))?,
+                data: self.bytes()?,
+                replace: self.byte()? != 0,
+            },
+            3 => {
+                let name = self.bytes()?;
+                let record_length = self.byte()?;
+                let file = RelativeFile::from_bytes(record_length, self.bytes()?)
+                    .ok_or(ImageError::Unsupported("record length"))?;
+                let record = u16::from_le_bytes([self.byte()?, self.byte()?]);
+                Channel::Relative {
+                    name,
+                    file,
+                    record,
+                    offset: self.u32()?,
+                    modified: self.byte()? != 0,
+                }
+            }
+            4 => {
+                let [buffer, pointer, flag, limit] = self.take(4)? else {
+                    unreachable!()
+                };
+                if *buffer as usize >= BUFFER_COUNT {
+                    return Err(ImageError::Unsupported("buffer number"));
+                }
+                Channel::Buffer {
+                    buffer: *buffer as usize,
+                    pointer: *pointer,
+                    limit: (*flag != 0).then_some(*limit),
+                }
+            }
+            _ => return Err(ImageError::Unsupported("channel type")),
+        };
+        Ok(Some(channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::VirtualDrive;
+    use crate::fs;
+
+    #[test]
+    fn round_trips_open_channels() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"SNAP", Some(*b"01")).unwrap();
+        fs::write_file(&mut image, b"DATA", FileType::Seq, b"HELLO").unwrap();
+        let mut drive = Drive::new(image);
+        drive.open(3, b"DATA").unwrap();
+        drive.read_byte(3).unwrap();
+        drive.open(4, b"NEW,S,W").unwrap();
+        drive.write_byte(4, b'X').unwrap();
+        drive.open(5, b"RECORDS,L,\x10").unwrap();
+        drive.open(6, b"#").unwrap();
+        drive.open(7, b"MISSING").unwrap_err();
+        drive.write_byte(15, b'I').unwrap();
+        drive.set_write_protect(true);
+
+        let snapshot = drive.snapshot();
+        let parsed = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(parsed, snapshot);
+        assert_eq!(parsed.open_channels(), [3, 4, 5, 6]);
+        assert_eq!(parsed.status().to_string(), "62,FILE NOT FOUND,00,00");
+
+        let disk = parsed.disk().unwrap();
+        assert!(disk.matches(drive.image()) && disk.name.is_empty());
+        assert!(!disk.matches(&D64::new(35)));
+
+        let mut other = Drive::new(drive.image().clone());
+        other.restore(&parsed);
+        assert_eq!(other.snapshot(), snapshot);
+        assert_eq!(other.read_byte(3), Some((b'E', false)));
+        assert!(other.is_write_protected());
+    }
+
+    #[test]
+    fn rejects_damaged_data() {
+        let bytes = Drive::new(D64::new(35)).snapshot().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(b"CBMDSNAQ"),
+            Err(ImageError::InvalidSignature)
+        );
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ImageError::Truncated)
+        );
+        let mut version = bytes.clone();
+        version[8] = 3;
+        assert!(matches!(
+            Snapshot::from_bytes(&version),
+            Err(ImageError::Unsupported(_))
+        ));
+
+        // Version 1 ends behind the channels, without a disk.
+        let mut drive = Drive::new(D64::new(35));
+        drive.eject();
+        let snapshot = drive.snapshot();
+        assert_eq!(snapshot.disk(), None);
+        let mut old = snapshot.to_bytes();
+        assert_eq!(old.pop(), Some(0));
+        old[8] = 1;
+        assert_eq!(Snapshot::from_bytes(&old).unwrap(), snapshot);
+    }
+}
@@ -0,0 +1,286 @@
+use core::fmt;
+
+/// An error condition reported by CBM DOS.
+///
+/// Each variant corresponds to one of the numeric error codes a Commodore
+/// drive reports on its command channel (channel 15). The numeric code is
+/// available through [`DosError::code`] and the drive's message text through
+/// [`DosError::message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DosError {
+    /// 20: the header block of the requested sector could not be found.
+    HeaderNotFound,
+    /// 21: no sync mark was found on the track.
+    NoSync,
+    /// 22: the data block of the requested sector could not be found.
+    DataBlockNotPresent,
+    /// 23: the checksum of the data block does not match.
+    DataChecksum,
+    /// 24: a byte could not be decoded from GCR.
+    ByteDecoding,
+    /// 25: the data written could not be verified.
+    WriteVerify,
+    /// 26: the disk is write protected.
+    WriteProtect,
+    /// 27: the checksum of the header block does not match.
+    HeaderChecksum,
+    /// 28: the data block was longer than expected.
+    LongDataBlock,
+    /// 29: the disk ID in the header does not match the ID in memory.
+    DiskIdMismatch,
+    /// 30: general syntax error.
+    Syntax,
+    /// 31: the command is not recognised.
+    InvalidCommand,
+    /// 32: the command is longer than 58 characters.
+    LongLine,
+    /// 33: the filename contains wildcards where they are not allowed.
+    InvalidFilename,
+    /// 34: no filename was given where one is required.
+    NoFileGiven,
+    /// 39: the command could not be resolved.
+    CommandNotFound,
+    /// 50: the requested record lies beyond the end of a relative file.
+    RecordNotPresent,
+    /// 51: more data was written than fits into a record.
+    OverflowInRecord,
+    /// 52: the relative file cannot grow any further.
+    FileTooLarge,
+    /// 60: a file that is still open for writing was opened for reading.
+    WriteFileOpen,
+    /// 61: the channel is not open.
+    FileNotOpen,
+    /// 62: no file matches the given name.
+    FileNotFound,
+    /// 63: a file with the given name already exists.
+    FileExists,
+    /// 64: the file type does not match the request.
+    FileTypeMismatch,
+    /// 65: the requested block is already allocated.
+    NoBlock,
+    /// 66: the track or sector does not exist on the disk.
+    IllegalTrackSector,
+    /// 67: the track or sector is reserved for the system.
+    IllegalSystemTrackSector,
+    /// 70: no free channel or buffer is available.
+    NoChannel,
+    /// 71: the BAM does not match the directory.
+    DirectoryError,
+    /// 72: no free blocks or directory entries are left.
+    DiskFull,
+    /// 73: the disk was formatted by an incompatible DOS version.
+    DosMismatch,
+    /// 74: no disk is present.
+    DriveNotReady,
+}
+
+impl DosError {
+    /// Returns the numeric error code the drive reports for this error.
+    pub fn code(&self) -> u8 {
+        match self {
+            DosError::HeaderNotFound => 20,
+            DosError::NoSync => 21,
+            DosError::DataBlockNotPresent => 22,
+            DosError::DataChecksum => 23,
+            DosError::ByteDecoding => 24,
+            DosError::WriteVerify => 25,
+            DosError::WriteProtect => 26,
+            DosError::HeaderChecksum => 27,
+            DosError::LongDataBlock => 28,
+            DosError::DiskIdMismatch => 29,
+            DosError::Syntax => 30,
+            DosError::InvalidCommand => 31,
+            DosError::LongLine => 32,
+            DosError::InvalidFilename => 33,
+            DosError::NoFileGiven => 34,
+            DosError::CommandNotFound => 39,
+            DosError::RecordNotPresent => 50,
+            DosError::OverflowInRecord => 51,
+            DosError::FileTooLarge => 52,
+            DosError::WriteFileOpen => 60,
+            DosError::FileNotOpen => 61,
+            DosError::FileNotFound => 62,
+            DosError::FileExists => 63,
+            DosError::FileTypeMismatch => 64,
+            DosError::NoBlock => 65,
+            DosError::IllegalTrackSector => 66,
+            DosError::IllegalSystemTrackSector => 67,
+            DosError::NoChannel => 70,
+            DosError::DirectoryError => 71,
+            DosError::DiskFull => 72,
+            DosError::DosMismatch => 73,
+            DosError::DriveNotReady => 74,
+        }
+    }
+
+    /// Looks up the error belonging to a numeric DOS error code.
+    ///
+    /// Returns `None` for codes that do not denote an error (`00`, `01`) and
+    /// for codes the drive never reports.
+    pub fn from_code(code: u8) -> Option<DosError> {
+        ALL_ERRORS.iter().copied().find(|e| e.code() == code)
+    }
+
+    /// Returns the message text the drive reports for this error.
+    pub fn message(&self) -> &'static str {
+        message_for_code(self.code())
+    }
+}
+
+const ALL_ERRORS: [DosError; 32] = [
+    DosError::HeaderNotFound,
+    DosError::NoSync,
+    DosError::DataBlockNotPresent,
+    DosError::DataChecksum,
+    DosError::ByteDecoding,
+    DosError::WriteVerify,
+    DosError::WriteProtect,
+    DosError::HeaderChecksum,
+    DosError::LongDataBlock,
+    DosError::DiskIdMismatch,
+    DosError::Syntax,
+    DosError::InvalidCommand,
+    DosError::LongLine,
+    DosError::InvalidFilename,
+    DosError::NoFileGiven,
+    DosError::CommandNotFound,
+    DosError::RecordNotPresent,
+    DosError::OverflowInRecord,
+    DosError::FileTooLarge,
+    DosError::WriteFileOpen,
+    DosError::FileNotOpen,
+    DosError::FileNotFound,
+    DosError::FileExists,
+    DosError::FileTypeMismatch,
+    DosError::NoBlock,
+    DosError::IllegalTrackSector,
+    DosError::IllegalSystemTrackSector,
+    DosError::NoChannel,
+    DosError::DirectoryError,
+    DosError::DiskFull,
+    DosError::DosMismatch,
+    DosError::DriveNotReady,
+];
+
+/// Returns the 1541 message text for a numeric status code.
+///
+/// The texts are reproduced as the drive reports them, including the leading
+/// space of `" OK"`.
+pub fn message_for_code(code: u8) -> &'static str {
+    match code {
+        0 => " OK",
+        1 => "FILES SCRATCHED",
+        20..=24 | 27 => "READ ERROR",
+        25 | 28 => "WRITE ERROR",
+        26 => "WRITE PROTECT ON",
+        29 => "DISK ID MISMATCH",
+        30..=34 | 39 => "SYNTAX ERROR",
+        50 => "RECORD NOT PRESENT",
+        51 => "OVERFLOW IN RECORD",
+        52 => "FILE TOO LARGE",
+        60 => "WRITE FILE OPEN",
+        61 => "FILE NOT OPEN",
+        62 => "FILE NOT FOUND",
+        63 => "FILE EXISTS",
+        64 => "FILE TYPE MISMATCH",
+        65 => "NO BLOCK",
+        66 | 67 => "ILLEGAL TRACK OR SECTOR",
+        70 => "NO CHANNEL",
+        71 => "DIRECTORY ERROR",
+        72 => "DISK FULL",
+        73 => "CBM DOS V2.6 1541",
+        74 => "DRIVE NOT READY",
+        _ => "UNKNOWN ERROR",
+    }
+}
+
+impl fmt::Display for DosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02},{}", self.code(), self.message())
+    }
+}
+
+impl core::error::Error for DosError {}
+
+/// The contents of a drive's error channel.
+///
+/// A status consists of the numeric code, the message text and the track and
+/// sector the condition refers to. Its `Display` form is the exact line the
+/// drive sends when channel 15 is read, e.g. `62,FILE NOT FOUND,00,00`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DosStatus {
+    pub code: u8,
+    pub message: &'static str,
+    pub track: u8,
+    pub sector: u8,
+}
+
+impl DosStatus {
+    /// The `00, OK,00,00` status.
+    pub fn ok() -> Self {
+        Self::new(0, 0, 0)
+    }
+
+    /// Builds a status for a numeric code using the 1541 message text.
+    pub fn new(code: u8, track: u8, sector: u8) -> Self {
+        DosStatus {
+            code,
+            message: message_for_code(code),
+            track,
+            sector,
+        }
+    }
+
+    /// Builds the status describing `error` at the given track and sector.
+    pub fn from_error(error: DosError, track: u8, sector: u8) -> Self {
+        Self::new(error.code(), track, sector)
+    }
+
+    /// Returns `true` for the non-error codes `00` and `01`.
+    pub fn is_ok(&self) -> bool {
+        self.code < 20
+    }
+
+    /// Returns the error this status reports, if any.
+    pub fn error(&self) -> Option<DosError> {
+        DosError::from_code(self.code)
+    }
+}
+
+impl fmt::Display for DosStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02},{},{:02},{:02}",
+            self.code, self.message, self.track, self.sector
+        )
+    }
+}
+
+impl From<DosError> for DosStatus {
+    fn from(error: DosError) -> Self {
+        DosStatus::from_error(error, 0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_formats_like_the_drive() {
+        assert_eq!(DosStatus::ok().to_string(), "00, OK,00,00");
+        assert_eq!(
+            DosStatus::from_error(DosError::DataChecksum, 18, 4).to_string(),
+            "23,READ ERROR,18,04"
+        );
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for error in ALL_ERRORS {
+            assert_eq!(DosError::from_code(error.code()), Some(error));
+        }
+        assert_eq!(DosError::from_code(0), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Decoders for the transfers of common fastloaders.
+//!
+//! Cartridge and disk fastloaders replace the serial bus handshake with
+//! drive code that clocks bytes out two bits at a time on CLK and DATA in
+//! fixed time windows. Once the computer gives the sync edge for a byte,
+//! the drive puts a bit pair on the lines in each of four windows; nothing
+//! on the bus says where a window is, so a trace can only be decoded
+//! knowing the loader's timing and bit order, which its [`Protocol`]
+//! describes.
+//!
+//! [`Trace`] collects the bus lines as seen by any participant, for
+//! instance while polling an [`IecHost`](crate::iec::IecHost) on a
+//! [`WiredBus`](crate::iec::WiredBus), or converted from a logic
+//! analyser capture. [`decode`] then reconstructs the bytes, and
+//! [`encode`] produces the trace a loader would, for reimplementing one
+//! or testing the other direction.
+//!
+//! The [`Loader`] presets give the nominal timing of each loader's drive
+//! code in microseconds; a drive running at a slightly different speed
+//! shifts the windows, so the samples are taken in the middle of each.
+
+use crate::iec::Lines;
+
+/// The edge the computer starts each byte with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sync {
+    /// ATN is released.
+    AtnReleased,
+    /// CLK is released.
+    ClkReleased,
+    /// DATA is released.
+    DataReleased,
+}
+
+impl Sync {
+    fn pulled(self, lines: Lines) -> bool {
+        match self {
+            Sync::AtnReleased => lines.atn,
+            Sync::ClkReleased => lines.clk,
+            Sync::DataReleased => lines.data,
+        }
+    }
+
+    fn set(self, lines: &mut Lines, pulled: bool) {
+        match self {
+            Sync::AtnReleased => lines.atn = pulled,
+            Sync::ClkReleased => lines.clk = pulled,
+            Sync::DataReleased => lines.data = pulled,
+        }
+    }
+}
+
+/// The framing of a two-bit transfer from the drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Protocol {
+    /// The edge starting each byte.
+    pub sync: Sync,
+    /// Time from the sync edge to the first window.
+    pub lead: u64,
+    /// The length of a window.
+    pub window: u64,
+    /// The bits on (CLK, DATA) in each window.
+    pub order: [(u8, u8); 4],
+    /// Whether a pulled line means 1 rather than 0.
+    pub inverted: bool,
+}
+
+impl Protocol {
+    /// Returns the time from the sync edge to the end of the last window.
+    fn length(&self) -> u64 {
+        self.lead + 4 * self.window
+    }
+}
+
+/// The fastloaders this module has presets for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Loader {
+    /// The Epyx FastLoad cartridge.
+    EpyxFastLoad,
+    /// The Final Cartridge III.
+    FinalCartridge3,
+    /// The Action Replay cartridges.
+    ActionReplay,
+}
+
+impl Loader {
+    /// Returns the loader's transfer framing.
+    pub fn protocol(self) -> Protocol {
+        match self {
+            Loader::EpyxFastLoad => Protocol {
+                sync: Sync::DataReleased,
+                lead: 14,
+                window: 10,
+                order: [(0, 1), (2, 3), (4, 5), (6, 7)],
+                inverted: true,
+            },
+            Loader::FinalCartridge3 => Protocol {
+                sync: Sync::AtnReleased,
+                lead: 10,
+                window: 8,
+                order: [(7, 6), (5, 4), (3, 2), (1, 0)],
+                inverted: false,
+            },
+            Loader::ActionReplay => Protocol {
+                sync: Sync::ClkReleased,
+                lead: 12,
+                window: 10,
+                order: [(4, 5), (6, 7), (0, 1), (2, 3)],
+                inverted: true,
+            },
+        }
+    }
+}
+
+/// The bus lines over time, kept as the changes only.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    samples: Vec<(u64, Lines)>,
+    /// The last recorded time, changed or not.
+    end: u64,
+}
+
+impl Trace {
+    /// Creates an empty trace.
+    pub fn new() -> Self {
+        Trace::default()
+    }
+
+    /// Records `lines` at `now` microseconds, which must not be before
+    /// the last recorded time.
+    ///
+    /// # Panics
+    /// Panics if `now` goes back in time.
+    pub fn record(&mut self, now: u64, lines: Lines) {
+        assert!(now >= self.end, "trace went back to {now}");
+        self.end = now;
+        if self.samples.last().is_none_or(|&(_, last)| last != lines) {
+            self.samples.push((now, lines));
+        }
+    }
+
+    /// Returns the recorded changes.
+    pub fn samples(&self) -> &[(u64, Lines)] {
+        &self.samples
+    }
+
+    /// Returns the lines at `time`.
+    pub fn at(&self, time: u64) -> Lines {
+        match self.samples.partition_point(|&(t, _)| t <= time) {
+            0 => Lines::default(),
+            n => self.samples[n - 1].1,
+        }
+    }
+}
+
+/// Reconstructs the bytes a drive sent with `protocol` in `trace`.
+///
+/// Every sync edge after the previous byte starts a byte. A byte cut off
+/// by the end of the trace is dropped.
+pub fn decode(trace: &Trace, protocol: &Protocol) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut free = 0;
+    let mut pulled = false;
+    for &(time, lines) in &trace.samples {
+        let released = pulled && !protocol.sync.pulled(lines);
+        pulled = protocol.sync.pulled(lines);
+        if !released || time <= free {
+            continue;
+        }
+        if time + protocol.length() > trace.end {
+            break;
+        }
+        let mut byte = 0;
+        for (n, &(clk, data)) in (0u64..).zip(&protocol.order) {
+            let lines = trace.at(time + protocol.lead + n * protocol.window + protocol.window / 2);
+            let bit = |pulled: bool| u8::from(pulled == protocol.inverted);
+            byte |= bit(lines.clk) << clk | bit(lines.data) << data;
+        }
+        bytes.push(byte);
+        free = time + protocol.length();
+    }
+    bytes
+}
+
+/// Returns the trace of `bytes` sent with `protocol` from `start` on.
+///
+/// The computer holds the sync line for one window before each byte and
+/// releases the lines after the last.
+pub fn encode(bytes: &[u8], protocol: &Protocol, start: u64) -> Trace {
+    let mut trace = Trace::new();
+    let mut time = start;
+    for &byte in bytes {
+        let mut lines = Lines::default();
+        protocol.sync.set(&mut lines, true);
+        trace.record(time, lines);
+        time += protocol.window;
+        trace.record(time, Lines::default());
+        for (n, &(clk, data)) in (0u64..).zip(&protocol.order) {
+            let pulled = |bit: u8| (byte >> bit & 1 == 1) == protocol.inverted;
+            let lines = Lines {
+                clk: pulled(clk),
+                data: pulled(data),
+                ..Lines::default()
+            };
+            trace.record(time + protocol.lead + n * protocol.window, lines);
+        }
+        time += protocol.length();
+        trace.record(time, Lines::default());
+    }
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOADERS: [Loader; 3] = [
+        Loader::EpyxFastLoad,
+        Loader::FinalCartridge3,
+        Loader::ActionReplay,
+    ];
+
+    #[test]
+    fn decodes_what_each_loader_sends() {
+        let data: Vec<u8> = (0..=255).collect();
+        for loader in LOADERS {
+            let protocol = loader.protocol();
+            let trace = encode(&data, &protocol, 100);
+            assert_eq!(decode(&trace, &protocol), data, "{loader:?}");
+            let others = LOADERS.iter().filter(|&&other| other != loader);
+            for other in others {
+                assert_ne!(decode(&trace, &other.protocol()), data, "{other:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn tolerates_drift_and_gaps() {
+        let protocol = Loader::FinalCartridge3.protocol();
+        let data = [0x00, 0xA5, 0xFF, 0x3C];
+        let mut trace = Trace::new();
+        let mut now = 0;
+        for &byte in &data {
+            // Each byte as sent by a drive running 10% slow.
+            let stretched = encode(&[byte], &protocol, 0);
+            for &(time, lines) in stretched.samples() {
+                trace.record(now + time + time / 10, lines);
+            }
+            now += 1000;
+        }
+        trace.record(now, Lines::default());
+        assert_eq!(decode(&trace, &protocol), data);
+
+        let cut = encode(&data, &protocol, 0);
+        let mut short = Trace::new();
+        for &(time, lines) in cut.samples().iter().take(cut.samples().len() - 3) {
+            short.record(time, lines);
+        }
+        assert_eq!(decode(&short, &protocol), data[..3]);
+    }
+}
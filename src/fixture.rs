@@ -0,0 +1,420 @@
+//! Disks to test against.
+//!
+//! Emulators and disk tools need disks that go wrong in known ways, and
+//! the same ones on every run. A [`Generator`] lists what a disk holds,
+//! files and relative files with the records given, and what is wrong
+//! with it: chains that loop or run into another file, a directory with
+//! all 144 entries, REL files whose side sectors lie, and read errors. It
+//! builds a D64 with the errors in its error block, or a G64 that carries
+//! them as defects in the GCR, mastered with any [`Interleave`].
+//!
+//! Data made by [`Generator::random_file`] comes from a fixed generator
+//! seeded by the name, so the fixtures are the same on every machine.
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::fs::{self, DirEntry, FileType};
+use crate::g64::G64;
+use crate::image::BlockDevice;
+use crate::inject::{ErrorMap, WithErrors};
+use crate::rel::{RelativeFile, SIDE_HEADER};
+use crate::track::Interleave;
+
+/// Entries a directory of the 1541 holds: eight in each of the 18 sectors
+/// of the directory track after the BAM.
+pub const FULL_DIRECTORY: usize = 144;
+
+/// Something wrong with a generated disk, done after every file is
+/// written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Defect {
+    /// The last block of the file links back to its first one.
+    Cycle(Vec<u8>),
+    /// The last block of file `from` links to the first block of file
+    /// `into`, whose blocks then belong to both.
+    CrossLink { from: Vec<u8>, into: Vec<u8> },
+    /// The first side sector of the relative file lists its first two data
+    /// blocks the wrong way round.
+    SwappedSideSector(Vec<u8>),
+    /// The file is left unclosed, as if the computer had been switched off
+    /// while saving it.
+    Unclosed(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    File {
+        name: Vec<u8>,
+        file_type: FileType,
+        data: Vec<u8>,
+    },
+    Rel {
+        name: Vec<u8>,
+        file: RelativeFile,
+    },
+}
+
+/// A description of a disk to build.
+///
+/// # Example
+/// ```
+/// use cbm_dos::error::DosError;
+/// use cbm_dos::fixture::{Defect, Generator};
+/// use cbm_dos::fs::{self, FileType};
+/// use cbm_dos::image::BlockDevice;
+///
+/// let disk = Generator::new(b"FIXTURE", *b"FX")
+///     .random_file(b"LOOPING", FileType::Prg, 1000)
+///     .defect(Defect::Cycle(b"LOOPING".to_vec()))
+///     .error(17, 0, DosError::DataChecksum)
+///     .build()
+///     .unwrap();
+/// let entry = fs::find_file(&disk, b"LOOPING").unwrap().unwrap();
+/// assert_eq!(fs::read_file(&disk, &entry), Err(DosError::IllegalTrackSector));
+/// assert_eq!(disk.sector_error(17, 0), Some(DosError::DataChecksum));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Generator {
+    name: Vec<u8>,
+    id: [u8; 2],
+    tracks: u8,
+    items: Vec<Item>,
+    fill: bool,
+    defects: Vec<Defect>,
+    errors: ErrorMap,
+    interleave: Interleave,
+}
+
+impl Generator {
+    /// Starts a blank 35 track disk formatted with `name` and `id`.
+    pub fn new(name: &[u8], id: [u8; 2]) -> Self {
+        Generator {
+            name: name.to_vec(),
+            id,
+            tracks: 35,
+            items: Vec::new(),
+            fill: false,
+            defects: Vec::new(),
+            errors: ErrorMap::new(),
+            interleave: Interleave::STANDARD,
+        }
+    }
+
+    /// Makes the disk 35 or 40 tracks long.
+    pub fn tracks(mut self, tracks: u8) -> Self {
+        self.tracks = tracks;
+        self
+    }
+
+    /// Adds a file holding `data`.
+    pub fn file(mut self, name: &[u8], file_type: FileType, data: &[u8]) -> Self {
+        self.items.push(Item::File {
+            name: name.to_vec(),
+            file_type,
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    /// Adds a file of `len` bytes made up from `name`, the same on every
+    /// run.
+    pub fn random_file(self, name: &[u8], file_type: FileType, len: usize) -> Self {
+        let data = random(name, len);
+        self.file(name, file_type, &data)
+    }
+
+    /// Adds a relative file of `records` empty records of `record_length`
+    /// bytes, filled up to the end of its last block as the drive does.
+    /// Record lengths of 1 and 254, records that straddle blocks and files
+    /// needing several side sectors are the cases worth testing.
+    ///
+    /// # Panics
+    /// Panics unless `record_length` is 1-254.
+    pub fn rel(mut self, name: &[u8], record_length: u8, records: u16) -> Self {
+        let mut file = RelativeFile::new(record_length).expect("record length is 1-254");
+        let _ = file.expand(records);
+        for number in 1..=file.records() {
+            if let Some(record) = file.record_mut(number) {
+                record[0] = (number % 255) as u8 + 1;
+            }
+        }
+        self.items.push(Item::Rel {
+            name: name.to_vec(),
+            file,
+        });
+        self
+    }
+
+    /// Fills the directory up to [`FULL_DIRECTORY`] entries with one block
+    /// files after the files added.
+    pub fn full_directory(mut self) -> Self {
+        self.fill = true;
+        self
+    }
+
+    /// Adds a defect, done in the order given.
+    pub fn defect(mut self, defect: Defect) -> Self {
+        self.defects.push(defect);
+        self
+    }
+
+    /// Makes `track`/`sector` report `error`, see [`ErrorMap::sector`].
+    pub fn error(mut self, track: u8, sector: u8, error: DosError) -> Self {
+        self.errors = self.errors.sector(track, sector, error);
+        self
+    }
+
+    /// Makes every sector of `track` report `error`.
+    pub fn track_error(mut self, track: u8, error: DosError) -> Self {
+        self.errors = self.errors.track(track, error);
+        self
+    }
+
+    /// Lays out the sectors of the G64 by `interleave` rather than in the
+    /// order of their numbers.
+    pub fn interleave(mut self, interleave: Interleave) -> Self {
+        self.interleave = interleave;
+        self
+    }
+
+    /// Builds the disk without its read errors.
+    fn contents(&self) -> Result<D64, DosError> {
+        let mut image = D64::new(self.tracks);
+        fs::format(&mut image, &self.name, Some(self.id))?;
+        let mut count = 0;
+        for item in &self.items {
+            match item {
+                Item::File {
+                    name,
+                    file_type,
+                    data,
+                } => fs::write_file(&mut image, name, *file_type, data)?,
+                Item::Rel { name, file } => file.write(&mut image, name)?,
+            };
+            count += 1;
+        }
+        if self.fill {
+            for number in count..FULL_DIRECTORY {
+                let name = format!("FILL{number:03}");
+                fs::write_file(&mut image, name.as_bytes(), FileType::Seq, name.as_bytes())?;
+            }
+        }
+        for defect in &self.defects {
+            apply(&mut image, defect)?;
+        }
+        Ok(image)
+    }
+
+    /// Builds the disk as a D64 with an error block if errors are given.
+    ///
+    /// # Errors
+    /// - [`DosError::DiskFull`] if the files do not fit.
+    /// - [`DosError::FileExists`] if two files have the same name.
+    /// - [`DosError::FileNotFound`] if a defect names a file the disk does
+    ///   not have, or [`DosError::FileTypeMismatch`] a file of the wrong
+    ///   type.
+    /// - The errors of [`ErrorMap::apply`].
+    pub fn build(&self) -> Result<D64, DosError> {
+        let mut image = self.contents()?;
+        if !self.errors.is_empty() {
+            self.errors.apply(&mut image)?;
+        }
+        Ok(image)
+    }
+
+    /// Builds the disk as a G64 whose tracks carry the read errors, laid
+    /// out by the interleave given.
+    ///
+    /// # Errors
+    /// Fails as [`Generator::build`] does.
+    pub fn build_g64(&self) -> Result<G64, DosError> {
+        let image = self.contents()?;
+        let disk = WithErrors {
+            image: &image,
+            errors: &self.errors,
+        };
+        Ok(G64::from_image_interleaved(&disk, &self.interleave))
+    }
+}
+
+/// Returns `len` bytes of a xorshift generator seeded with `seed`.
+fn random(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut state = seed.iter().fold(0x811C_9DC5u32, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    }) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn find(image: &D64, name: &[u8]) -> Result<(DirEntry, Vec<(u8, u8)>), DosError> {
+    let entry = fs::read_directory(image)?
+        .entries
+        .into_iter()
+        .find(|e| fs::same_name(name, &e.name))
+        .ok_or(DosError::FileNotFound)?;
+    let blocks = fs::chain(image, entry.track, entry.sector)?;
+    Ok((entry, blocks))
+}
+
+fn link(image: &mut D64, (track, sector): (u8, u8), to: (u8, u8)) -> Result<(), DosError> {
+    let mut data = image.read_sector(track, sector)?;
+    (data[0], data[1]) = to;
+    image.write_sector(track, sector, &data)
+}
+
+fn apply(image: &mut D64, defect: &Defect) -> Result<(), DosError> {
+    match defect {
+        Defect::Cycle(name) => {
+            let (entry, blocks) = find(image, name)?;
+            let last = *blocks.last().ok_or(DosError::FileNotFound)?;
+            link(image, last, (entry.track, entry.sector))
+        }
+        Defect::CrossLink { from, into } => {
+            let (_, blocks) = find(image, from)?;
+            let (target, _) = find(image, into)?;
+            let last = *blocks.last().ok_or(DosError::FileNotFound)?;
+            link(image, last, (target.track, target.sector))
+        }
+        Defect::SwappedSideSector(name) => {
+            let (entry, _) = find(image, name)?;
+            if entry.file_type != FileType::Rel {
+                return Err(DosError::FileTypeMismatch);
+            }
+            let (track, sector) = (entry.side_track, entry.side_sector);
+            let mut data = image.read_sector(track, sector)?;
+            let (first, second) = data[SIDE_HEADER..SIDE_HEADER + 4].split_at_mut(2);
+            first.swap_with_slice(second);
+            image.write_sector(track, sector, &data)
+        }
+        Defect::Unclosed(name) => {
+            let (mut entry, _) = find(image, name)?;
+            entry.closed = false;
+            fs::write_entry(image, &entry)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::{self, Issue, Owner};
+
+    #[test]
+    fn builds_pathological_disks() {
+        let generator = Generator::new(b"BROKEN", *b"BR")
+            .random_file(b"FIRST", FileType::Prg, 600)
+            .random_file(b"SECOND", FileType::Seq, 300)
+            .random_file(b"SPLAT", FileType::Prg, 10)
+            .full_directory()
+            .defect(Defect::CrossLink {
+                from: b"FIRST".to_vec(),
+                into: b"SECOND".to_vec(),
+            })
+            .defect(Defect::Unclosed(b"SPLAT".to_vec()));
+        let disk = generator.build().unwrap();
+        assert_eq!(generator.build().unwrap(), disk);
+        let directory = fs::read_directory(&disk).unwrap();
+        assert_eq!(directory.entries.len(), FULL_DIRECTORY);
+        assert!(!directory.entries[2].closed);
+
+        let report = integrity::check(&disk);
+        let [first, second] = [0, 1].map(|i| Owner::File {
+            name: directory.entries[i].name,
+            slot: directory.entries[i].slot,
+        });
+        let second_start = (directory.entries[1].track, directory.entries[1].sector);
+        assert!(report.issues.contains(&Issue::CrossLink {
+            track: second_start.0,
+            sector: second_start.1,
+            first,
+            second,
+        }));
+        assert!(report.issues.contains(&Issue::Unclosed {
+            owner: Owner::File {
+                name: directory.entries[2].name,
+                slot: directory.entries[2].slot,
+            }
+        }));
+
+        let missing = Generator::new(b"X", *b"XX").defect(Defect::Cycle(b"NONE".to_vec()));
+        assert_eq!(missing.build(), Err(DosError::FileNotFound));
+    }
+
+    #[test]
+    fn builds_rel_edge_cases_with_gcr_errors() {
+        let generator = Generator::new(b"RECORDS", *b"RC")
+            .rel(b"BYTES", 1, 300)
+            .rel(b"WIDE", 254, 130)
+            .rel(b"ODD", 100, 5)
+            .defect(Defect::SwappedSideSector(b"ODD".to_vec()))
+            .error(1, 0, DosError::DataChecksum);
+        let disk = generator.build().unwrap();
+        let wide = fs::find_file(&disk, b"WIDE").unwrap().unwrap();
+        let file = RelativeFile::read(&disk, &wide).unwrap();
+        assert_eq!((file.records(), file.record(130).unwrap()[0]), (130, 131));
+        assert_eq!(wide.blocks, 132);
+        let bytes = fs::find_file(&disk, b"BYTES").unwrap().unwrap();
+        assert_eq!(RelativeFile::read(&disk, &bytes).unwrap().records(), 508);
+
+        let odd = fs::find_file(&disk, b"ODD").unwrap().unwrap();
+        let side = disk.read_sector(odd.side_track, odd.side_sector).unwrap();
+        let blocks = fs::chain(&disk, odd.track, odd.sector).unwrap();
+        assert_eq!(
+            side[SIDE_HEADER..SIDE_HEADER + 4],
+            [blocks[1].0, blocks[1].1, blocks[0].0, blocks[0].1]
+        );
+        assert_eq!(
+            Generator::new(b"X", *b"XX")
+                .file(b"PRG", FileType::Prg, &[1, 8])
+                .defect(Defect::SwappedSideSector(b"PRG".to_vec()))
+                .build(),
+            Err(DosError::FileTypeMismatch)
+        );
+
+        let g64 = generator.build_g64().unwrap();
+        assert_eq!(g64.sector_error(1, 0), Some(DosError::DataChecksum));
+        assert_eq!(g64.sector_error(1, 1), None);
+    }
+
+    #[test]
+    fn masters_g64_with_interleave_per_zone() {
+        use crate::track::physical_order;
+
+        let generator = Generator::new(b"SKEWED", *b"SK")
+            .random_file(b"DATA", FileType::Prg, 5000)
+            .error(20, 4, DosError::DataChecksum)
+            .interleave(Interleave {
+                zones: [1, 4, 10, 6],
+                directory: 3,
+            });
+        let g64 = generator.build_g64().unwrap();
+        // How many slots after sector 0 `sector` passes the head.
+        let slot = |track, sector| {
+            physical_order(g64.track(track).unwrap(), track)
+                .position(|p| p.sector == sector)
+                .unwrap()
+        };
+        assert_eq!([1, 2].map(|sector| slot(1, sector)), [6, 12]);
+        assert_eq!([1, 2].map(|sector| slot(18, sector)), [3, 6]);
+        assert_eq!([1, 2].map(|sector| slot(20, sector)), [10, 1]);
+        assert_eq!([1, 2].map(|sector| slot(25, sector)), [4, 8]);
+        assert_eq!([1, 2].map(|sector| slot(31, sector)), [1, 2]);
+
+        let d64 = generator.build().unwrap();
+        for track in 1..=35 {
+            for sector in 0..d64.sectors_per_track(track) {
+                assert_eq!(
+                    g64.read_sector(track, sector),
+                    d64.read_sector(track, sector)
+                );
+            }
+        }
+        assert_eq!(g64.sector_error(20, 4), Some(DosError::DataChecksum));
+    }
+}
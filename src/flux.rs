@@ -0,0 +1,554 @@
+//! Flux transitions, and the devices and files that hold them.
+//!
+//! Below the GCR bitstream a disk holds flux transitions: the drive
+//! electronics see a one bit wherever the magnetisation flips, and zero
+//! bits in between according to how many bit cells the gap lasts. Flux
+//! readers such as the Greaseweazle, SuperCard Pro or KryoFlux sample the
+//! time between transitions, and their files store those samples for
+//! several revolutions of each track.
+//!
+//! [`FluxSource`] is anything flux can be read from, [`FluxSink`] anything
+//! it can be written to. Both count half tracks as [`G64`] does, 0 for
+//! track 1 and 1 for track 1.5, and give intervals in nanoseconds, so the
+//! decode pipeline works the same on devices and on files. [`G64`] is a
+//! source and sink itself, synthesising the flux of its bitstreams.
+//!
+//! [`to_bits`] and [`from_bits`] convert between flux and bitstream for a
+//! given cell length, and [`read_track`] reads the bitstream of a track
+//! from any source in the track's speed zone.
+//!
+//! A marginal disk reads differently on every revolution. [`merge`] lines
+//! several revolutions up with each other and takes a vote on every bit
+//! cell, which recovers bits that only some revolutions got right and
+//! shows where the revolutions disagree: weak bits, as copy protections
+//! write on purpose or a worn disk develops.
+//!
+//! The cell lengths assume a drive turning at exactly 300 RPM. The time
+//! from index to index tells how fast the reading drive really turned,
+//! see [`FluxTrack::rpm`], and [`FluxTrack::normalized`] scales every
+//! revolution to the nominal speed, so a drive a few percent off still
+//! decodes with the nominal cells.
+//!
+//! Writing goes the other way. Transitions close together push each
+//! other apart on the medium, the more so on the short inner tracks, so
+//! flux meant for a real disk can be passed through [`Precompensated`],
+//! which writes such transitions early or late to make up for it.
+//!
+//! Custom processing, filters, other decoders or recognizers for copy
+//! protections, plugs into a [`pipeline`] between the flux and the
+//! bitstream.
+//! [`remaster`] uses one to turn dumps of protected disks into images
+//! for emulators.
+
+pub mod pipeline;
+pub mod remaster;
+
+use std::cmp::Ordering;
+use std::io;
+use std::ops::Range;
+
+use crate::g64::G64;
+use crate::timing::{DEFAULT_RPM, bit_rate, speed_zone};
+
+/// The flux of one track.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FluxTrack {
+    /// The intervals between transitions in nanoseconds, per revolution
+    /// from the index hole on.
+    pub revolutions: Vec<Vec<u32>>,
+}
+
+impl FluxTrack {
+    /// Returns the time revolution `index` took in nanoseconds.
+    pub fn revolution_time(&self, index: usize) -> Option<u64> {
+        let intervals = self.revolutions.get(index)?;
+        Some(intervals.iter().map(|&interval| u64::from(interval)).sum())
+    }
+
+    /// Returns how fast the drive turned during revolution `index`, in
+    /// revolutions per minute.
+    ///
+    /// The revolutions have to run from index hole to index hole, as those
+    /// of flux readers do; a revolution that took no time at all has no
+    /// speed.
+    pub fn rpm(&self, index: usize) -> Option<f64> {
+        let time = self.revolution_time(index).filter(|&time| time > 0)?;
+        Some(60e9 / time as f64)
+    }
+
+    /// Returns the mean speed over all revolutions.
+    pub fn mean_rpm(&self) -> Option<f64> {
+        let speeds: Vec<f64> = (0..self.revolutions.len())
+            .filter_map(|index| self.rpm(index))
+            .collect();
+        (!speeds.is_empty()).then(|| speeds.iter().sum::<f64>() / speeds.len() as f64)
+    }
+
+    /// Returns the flux as a drive at exactly 300 RPM would have read it,
+    /// scaling each revolution by its own speed.
+    pub fn normalized(&self) -> FluxTrack {
+        let revolutions = (0..self.revolutions.len())
+            .map(|index| {
+                let scale = self.rpm(index).map_or(1.0, |rpm| rpm / DEFAULT_RPM);
+                self.revolutions[index]
+                    .iter()
+                    .map(|&interval| (f64::from(interval) * scale).round() as u32)
+                    .collect()
+            })
+            .collect();
+        FluxTrack { revolutions }
+    }
+}
+
+/// A device or file flux can be read from.
+pub trait FluxSource {
+    /// Returns how many half tracks the source can reach.
+    fn half_tracks(&self) -> usize;
+
+    /// Reads `revolutions` revolutions of half track `index`, or returns
+    /// `None` if the source has no flux there. Files may return fewer
+    /// revolutions than asked for.
+    ///
+    /// # Errors
+    /// Returns the error of the device or file.
+    fn read_flux(&mut self, index: usize, revolutions: usize) -> io::Result<Option<FluxTrack>>;
+}
+
+impl<T: FluxSource + ?Sized> FluxSource for Box<T> {
+    fn half_tracks(&self) -> usize {
+        (**self).half_tracks()
+    }
+
+    fn read_flux(&mut self, index: usize, revolutions: usize) -> io::Result<Option<FluxTrack>> {
+        (**self).read_flux(index, revolutions)
+    }
+}
+
+/// A device or file flux can be written to.
+pub trait FluxSink {
+    /// Writes one revolution of `flux` to half track `index`.
+    ///
+    /// # Errors
+    /// Returns the error of the device or file.
+    fn write_flux(&mut self, index: usize, flux: &[u32]) -> io::Result<()>;
+}
+
+/// Returns the cell length in nanoseconds of speed zone `zone`.
+pub fn cell_length(zone: u8) -> u32 {
+    1_000_000_000 / bit_rate(zone)
+}
+
+/// Converts `flux` to a bitstream, MSB first, with cells of `cell`
+/// nanoseconds.
+///
+/// Every interval is rounded to whole cells, at least one, which yields
+/// that many bits ending in the one bit of the transition. The last byte
+/// is padded with zero bits.
+pub fn to_bits(flux: &[u32], cell: u32) -> Vec<u8> {
+    pack(&cells(flux, cell))
+}
+
+/// Returns the bits of `flux` as [`to_bits`] does, one per element.
+fn cells(flux: &[u32], cell: u32) -> Vec<bool> {
+    let mut bits = Vec::new();
+    for &interval in flux {
+        let cells = ((interval + cell / 2) / cell).max(1) as usize;
+        bits.resize(bits.len() + cells - 1, false);
+        bits.push(true);
+    }
+    bits
+}
+
+/// Packs `bits` MSB first, padding the last byte with zero bits.
+fn pack(bits: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0; bits.len().div_ceil(8)];
+    for (i, _) in bits.iter().enumerate().filter(|&(_, &bit)| bit) {
+        bytes[i / 8] |= 0x80 >> (i % 8);
+    }
+    bytes
+}
+
+/// Converts the bitstream `data` to flux with cells of `cell` nanoseconds.
+///
+/// The zero bits after the last one bit lead into the first transition,
+/// as they do on the rotating disk, so a track starting with a one bit
+/// and ending with one converts back unchanged.
+pub fn from_bits(data: &[u8], cell: u32) -> Vec<u32> {
+    let ones: Vec<usize> = (0..data.len() * 8)
+        .filter(|&bit| data[bit / 8] & 0x80 >> (bit % 8) != 0)
+        .collect();
+    let Some(&last) = ones.last() else {
+        return Vec::new();
+    };
+    let mut previous = last as isize - (data.len() * 8) as isize;
+    ones.iter()
+        .map(|&one| {
+            let cells = one as isize - previous;
+            previous = one as isize;
+            cells as u32 * cell
+        })
+        .collect()
+}
+
+/// Reads the bitstream of the first revolution of half track `index`
+/// from `source`, with the cell length of the 1541's speed zone for it.
+///
+/// # Errors
+/// Returns the error of the source.
+pub fn read_track(source: &mut impl FluxSource, index: usize) -> io::Result<Option<Vec<u8>>> {
+    let zone = speed_zone(index as u8 / 2 + 1);
+    let flux = source.read_flux(index, 1)?;
+    let revolution = flux.and_then(|flux| flux.revolutions.into_iter().next());
+    Ok(revolution.map(|flux| to_bits(&flux, cell_length(zone))))
+}
+
+/// How many bits [`merge`] aligns at a time.
+const SEGMENT: usize = 256;
+/// How far [`merge`] looks for a segment from where the last one was.
+const SEARCH: isize = 16;
+
+/// The bitstream voted from several revolutions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergedTrack {
+    /// The bitstream, MSB first. The last byte is padded with zero bits.
+    pub data: Vec<u8>,
+    /// The number of bits in `data`.
+    pub bits: usize,
+    /// The bits on which the revolutions disagreed, packed like `data`.
+    pub weak: Vec<u8>,
+}
+
+impl MergedTrack {
+    /// Returns whether the revolutions disagreed on `bit`.
+    pub fn is_weak(&self, bit: usize) -> bool {
+        self.weak
+            .get(bit / 8)
+            .is_some_and(|&byte| byte & 0x80 >> (bit % 8) != 0)
+    }
+
+    /// Returns the runs of weak bits.
+    pub fn weak_regions(&self) -> Vec<Range<usize>> {
+        let mut regions: Vec<Range<usize>> = Vec::new();
+        for bit in (0..self.bits).filter(|&bit| self.is_weak(bit)) {
+            match regions.last_mut() {
+                Some(region) if region.end == bit => region.end += 1,
+                _ => regions.push(bit..bit + 1),
+            }
+        }
+        regions
+    }
+}
+
+/// Votes the bitstream of `flux` from all its revolutions, with cells of
+/// `cell` nanoseconds, or returns `None` if it has none.
+///
+/// The first revolution gives the length and is the reference the others
+/// are aligned to: in segments of 256 bits, each revolution is shifted by
+/// up to 16 bits from where its last segment matched best, so a drive
+/// speeding up or slowing down over a revolution stays in step. Every bit
+/// then takes the value most revolutions have, ties going to the first
+/// revolution; bits not all revolutions agree on are marked weak.
+pub fn merge(flux: &FluxTrack, cell: u32) -> Option<MergedTrack> {
+    let (reference, others) = flux.revolutions.split_first()?;
+    let reference = cells(reference, cell);
+    let mut ones: Vec<usize> = reference.iter().map(|&bit| usize::from(bit)).collect();
+    let mut votes = vec![1usize; reference.len()];
+    for other in others {
+        let other = cells(other, cell);
+        let at = |i: usize, offset: isize| {
+            i.checked_add_signed(offset)
+                .and_then(|j| other.get(j))
+                .copied()
+        };
+        let mut offset = 0isize;
+        for start in (0..reference.len()).step_by(SEGMENT) {
+            let segment = start..(start + SEGMENT).min(reference.len());
+            let score = |shift: isize| {
+                segment
+                    .clone()
+                    .filter(|&i| at(i, offset + shift) == Some(reference[i]))
+                    .count()
+            };
+            // The smallest shift wins a tie, so a segment without
+            // transitions leaves the alignment alone.
+            let shift = (0..=SEARCH)
+                .flat_map(|d| [d, -d])
+                .max_by_key(|&d| (score(d), -d.abs()))
+                .unwrap_or(0);
+            offset += shift;
+            for i in segment {
+                if let Some(bit) = at(i, offset) {
+                    ones[i] += usize::from(bit);
+                    votes[i] += 1;
+                }
+            }
+        }
+    }
+
+    let (bits, weak): (Vec<bool>, Vec<bool>) = (0..reference.len())
+        .map(|i| {
+            let zeros = votes[i] - ones[i];
+            let bit = ones[i] > zeros || ones[i] == zeros && reference[i];
+            (bit, ones[i] != 0 && zeros != 0)
+        })
+        .unzip();
+    Some(MergedTrack {
+        data: pack(&bits),
+        bits: bits.len(),
+        weak: pack(&weak),
+    })
+}
+
+/// Returns `flux` with write precompensation of `shift` nanoseconds.
+///
+/// A transition closer to the one before than to the one after is read
+/// late, pushed away from its near neighbour, so it is written `shift`
+/// early, and the other way round; a transition with equal intervals on
+/// both sides stays. The flux is taken as a whole revolution, so the last
+/// transition is judged against the first and the total time is kept.
+pub fn precompensate(flux: &[u32], shift: u32) -> Vec<u32> {
+    let n = flux.len();
+    let moves: Vec<i64> = (0..n)
+        .map(|i| match flux[i].cmp(&flux[(i + 1) % n]) {
+            Ordering::Less => -i64::from(shift),
+            Ordering::Greater => i64::from(shift),
+            Ordering::Equal => 0,
+        })
+        .collect();
+    (0..n)
+        .map(|i| {
+            let moved = i64::from(flux[i]) + moves[i] - moves[(i + n - 1) % n];
+            moved.max(1) as u32
+        })
+        .collect()
+}
+
+/// A sink applying write precompensation to the inner tracks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Precompensated<S> {
+    sink: S,
+    /// The shift in nanoseconds.
+    pub shift: u32,
+    /// The first track precompensated, as a 1541 track number.
+    pub from_track: u8,
+}
+
+impl<S: FluxSink> Precompensated<S> {
+    /// Writes to `sink`, shifting transitions by `shift` nanoseconds on
+    /// `from_track` and the tracks inside it.
+    pub fn new(sink: S, shift: u32, from_track: u8) -> Self {
+        Precompensated {
+            sink,
+            shift,
+            from_track,
+        }
+    }
+
+    /// Returns the sink.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S: FluxSink> FluxSink for Precompensated<S> {
+    fn write_flux(&mut self, index: usize, flux: &[u32]) -> io::Result<()> {
+        if index / 2 + 1 >= usize::from(self.from_track) {
+            self.sink
+                .write_flux(index, &precompensate(flux, self.shift))
+        } else {
+            self.sink.write_flux(index, flux)
+        }
+    }
+}
+
+impl FluxSource for G64 {
+    fn half_tracks(&self) -> usize {
+        G64::half_tracks(self)
+    }
+
+    fn read_flux(&mut self, index: usize, revolutions: usize) -> io::Result<Option<FluxTrack>> {
+        let Some(data) = self.half_track(index) else {
+            return Ok(None);
+        };
+        let flux = from_bits(data, cell_length(self.half_track_zone(index)));
+        Ok(Some(FluxTrack {
+            revolutions: vec![flux; revolutions],
+        }))
+    }
+}
+
+impl FluxSink for G64 {
+    /// Stores the bitstream of `flux` in the speed zone the 1541 uses for
+    /// the track.
+    fn write_flux(&mut self, index: usize, flux: &[u32]) -> io::Result<()> {
+        let zone = speed_zone(index as u8 / 2 + 1);
+        self.set_half_track(index, to_bits(flux, cell_length(zone)), zone);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::image::BlockDevice;
+    use crate::track::read_sector;
+
+    #[test]
+    fn decodes_sectors_from_any_source() {
+        let mut d64 = D64::new(35);
+        d64.write_sector(17, 3, &[0xA7; 256]).unwrap();
+        d64.write_sector(35, 16, &[0x3C; 256]).unwrap();
+        let mut g64 = G64::from_image(&d64);
+
+        let mut copy = G64::new();
+        for index in [32, 68] {
+            let flux = g64.read_flux(index, 2).unwrap().unwrap();
+            assert_eq!(flux.revolutions.len(), 2);
+            assert_eq!(flux.revolutions[0], flux.revolutions[1]);
+            copy.write_flux(index, &flux.revolutions[0]).unwrap();
+            assert_eq!(copy.half_track(index), g64.half_track(index));
+        }
+        // One revolution of a zone 3 track takes 200ms at 300 RPM.
+        let time = g64.read_flux(32, 1).unwrap().unwrap().revolution_time(0);
+        assert!(time.unwrap().abs_diff(200_000_000) < 200_000);
+
+        let bits = read_track(&mut g64, 32).unwrap().unwrap();
+        assert_eq!(read_sector(&bits, 17, 3, None).data, Some([0xA7; 256]));
+        let bits = read_track(&mut copy, 68).unwrap().unwrap();
+        assert_eq!(read_sector(&bits, 35, 16, None).data, Some([0x3C; 256]));
+        assert_eq!(read_track(&mut copy, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn rounds_jittered_intervals_to_cells() {
+        let cell = cell_length(3);
+        let data = [0xFF, 0x52, 0x94, 0xA5, 0x6B];
+        let flux = from_bits(&data, cell);
+        assert_eq!(flux[8], 2 * cell);
+        let jittered: Vec<u32> = (0..)
+            .zip(&flux)
+            .map(|(i, &interval)| match i % 3 {
+                0 => interval + cell / 3,
+                1 => interval - cell / 3,
+                _ => interval,
+            })
+            .collect();
+        assert_eq!(to_bits(&jittered, cell), data);
+        assert_eq!(from_bits(&[0; 4], cell), []);
+    }
+
+    #[test]
+    fn merges_revolutions_and_finds_weak_bits() {
+        let mut d64 = D64::new(35);
+        for sector in 0..21 {
+            d64.write_sector(5, sector, &[sector; 256]).unwrap();
+        }
+        let mut g64 = G64::from_image(&d64);
+        let clean = g64.read_flux(8, 1).unwrap().unwrap().revolutions.remove(0);
+        let cell = cell_length(3);
+        let bits = to_bits(&clean, cell);
+        let data_bit = |sector| read_sector(&bits, 5, sector, None).data_bit.unwrap();
+        let mut spots = Vec::new();
+
+        // Every revolution has a transition moved by a cell in another
+        // sector and is jittered by up to a quarter cell either way; the
+        // second starts two transitions late.
+        let mut revolutions = Vec::new();
+        for turn in 0..3 {
+            let mut flux = clean.clone();
+            let bit = data_bit(3 + 5 * turn as u8) + 100;
+            spots.push(bit);
+            let mut total = 0;
+            let at = (0..).find(|&i| {
+                total += clean[i] / cell;
+                total as usize > bit && clean[i + 1] >= 2 * cell
+            });
+            let at = at.unwrap();
+            flux[at] += cell;
+            flux[at + 1] -= cell;
+            for (i, interval) in flux.iter_mut().enumerate() {
+                *interval = *interval + cell / 4 - (i * 7919 + turn * 31) as u32 % (cell / 2);
+            }
+            if turn == 1 {
+                flux.rotate_left(2);
+            }
+            revolutions.push(flux);
+        }
+        let track = FluxTrack { revolutions };
+        for (turn, revolution) in track.revolutions.iter().enumerate() {
+            let read = read_sector(&to_bits(revolution, cell), 5, 3 + 5 * turn as u8, None);
+            assert!(read.error.is_some());
+        }
+
+        let merged = merge(&track, cell).unwrap();
+        for sector in 0..21 {
+            let read = read_sector(&merged.data, 5, sector, None);
+            assert_eq!(read.data, Some([sector; 256]), "sector {sector}");
+        }
+        let regions = merged.weak_regions();
+        assert_eq!(regions.len(), 3);
+        for (region, spot) in regions.iter().zip(spots) {
+            assert!(region.start.abs_diff(spot) < 20 && region.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn measures_and_compensates_speed() {
+        let mut d64 = D64::new(35);
+        d64.write_sector(1, 7, &[0x6D; 256]).unwrap();
+        let mut g64 = G64::from_image(&d64);
+        let nominal = g64.read_flux(0, 1).unwrap().unwrap();
+        assert!((nominal.rpm(0).unwrap() - DEFAULT_RPM).abs() < 0.1);
+
+        // A drive 5% slow, whose intervals also wander by 0.4 cells.
+        let cell = cell_length(3);
+        let slow: Vec<u32> = (0..)
+            .zip(&nominal.revolutions[0])
+            .map(|(i, &interval)| {
+                let jitter = if i % 2 == 0 { 0.4 } else { -0.4 } * f64::from(cell);
+                (f64::from(interval) * 1.05 + jitter) as u32
+            })
+            .collect();
+        let track = FluxTrack {
+            revolutions: vec![slow; 2],
+        };
+        assert!((track.mean_rpm().unwrap() - DEFAULT_RPM / 1.05).abs() < 0.5);
+        let recovered =
+            |track: &FluxTrack| read_sector(&to_bits(&track.revolutions[1], cell), 1, 7, None).data;
+        assert_ne!(recovered(&track), Some([0x6D; 256]));
+        assert_eq!(recovered(&track.normalized()), Some([0x6D; 256]));
+        assert_eq!(FluxTrack::default().mean_rpm(), None);
+    }
+
+    #[test]
+    fn precompensates_inner_tracks() {
+        let cell = cell_length(0);
+        let flux = [cell, 2 * cell, 2 * cell, cell, 3 * cell];
+        let shifted = precompensate(&flux, 100);
+        assert_eq!(
+            shifted,
+            [
+                cell - 200,
+                2 * cell + 100,
+                2 * cell + 100,
+                cell - 200,
+                3 * cell + 200
+            ]
+        );
+        assert_eq!(shifted.iter().sum::<u32>(), flux.iter().sum::<u32>());
+
+        let d64 = D64::new(35);
+        let mut source = G64::from_image(&d64);
+        let mut sink = Precompensated::new(G64::new(), 300, 31);
+        for index in [58, 60, 68] {
+            let flux = source.read_flux(index, 1).unwrap().unwrap();
+            sink.write_flux(index, &flux.revolutions[0]).unwrap();
+        }
+        // The shift stays well inside a cell, so the bits are unchanged.
+        let written = sink.into_inner();
+        for index in [58, 60, 68] {
+            assert_eq!(written.half_track(index), source.half_track(index));
+        }
+    }
+}
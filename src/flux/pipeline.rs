@@ -0,0 +1,278 @@
+//! Pluggable processing between flux and bitstream.
+//!
+//! A [`Pipeline`] reads the flux of a track and hands it through its
+//! [`Analyzer`] stages in order before it becomes a bitstream. A stage
+//! sees the whole [`TrackState`]: a filter rewrites the flux, a decoder
+//! such as a PLL of its own sets the bits, and a recognizer looks at the
+//! bits the stages before it left and notes what it finds. Whatever no
+//! stage decoded is converted with the fixed cells of [`to_bits`], from
+//! the first revolution.
+//!
+//! Closures taking the state are stages too:
+//!
+//! ```
+//! use cbm_dos::d64::D64;
+//! use cbm_dos::flux::pipeline::{Normalize, Pipeline, TrackState};
+//! use cbm_dos::g64::G64;
+//!
+//! let mut source = G64::from_image(&D64::new(35));
+//! let mut pipeline = Pipeline::new()
+//!     .stage(Normalize)
+//!     .stage(|track: &mut TrackState| {
+//!         if track.flux.revolutions.iter().all(Vec::is_empty) {
+//!             track.notes.push("unformatted".to_string());
+//!         }
+//!         Ok(())
+//!     });
+//! let track = pipeline.run(&mut source, 0).unwrap().unwrap();
+//! assert!(track.notes.is_empty());
+//! assert_eq!(track.bits.as_deref(), source.half_track(0));
+//! ```
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+use super::{FluxSource, FluxTrack, cell_length, merge, to_bits};
+use crate::g64::G64;
+use crate::timing::speed_zone;
+
+/// A track on its way through a [`Pipeline`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackState {
+    /// The half track, counted as [`G64`] does.
+    pub index: usize,
+    /// The speed zone the track is decoded and stored in.
+    pub zone: u8,
+    /// The cell length in nanoseconds the track is decoded with.
+    pub cell: u32,
+    /// The flux as read, or as the stages so far left it.
+    pub flux: FluxTrack,
+    /// The bitstream, MSB first, once a stage has decoded it.
+    pub bits: Option<Vec<u8>>,
+    /// Bit ranges the decoding is unsure of.
+    pub weak: Vec<Range<usize>>,
+    /// What the stages found out about the track.
+    pub notes: Vec<String>,
+}
+
+/// A stage of a [`Pipeline`].
+pub trait Analyzer {
+    /// Processes `track`.
+    ///
+    /// # Errors
+    /// Returns an error to abort the pipeline.
+    fn process(&mut self, track: &mut TrackState) -> io::Result<()>;
+}
+
+impl<F: FnMut(&mut TrackState) -> io::Result<()>> Analyzer for F {
+    fn process(&mut self, track: &mut TrackState) -> io::Result<()> {
+        self(track)
+    }
+}
+
+/// Scales every revolution to the nominal speed, see
+/// [`FluxTrack::normalized`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Normalize;
+
+impl Analyzer for Normalize {
+    fn process(&mut self, track: &mut TrackState) -> io::Result<()> {
+        track.flux = track.flux.normalized();
+        Ok(())
+    }
+}
+
+/// Decodes the track by a vote over all revolutions, see [`merge`], and
+/// reports the weak bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Merge;
+
+impl Analyzer for Merge {
+    fn process(&mut self, track: &mut TrackState) -> io::Result<()> {
+        if track.bits.is_none()
+            && let Some(merged) = merge(&track.flux, track.cell)
+        {
+            track.weak = merged.weak_regions();
+            track.bits = Some(merged.data);
+        }
+        Ok(())
+    }
+}
+
+/// Stages from flux to bitstream.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Analyzer>>,
+    revolutions: usize,
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("stages", &self.stages.len())
+            .field("revolutions", &self.revolutions)
+            .finish()
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            stages: Vec::new(),
+            revolutions: 1,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Creates a pipeline without stages, reading one revolution.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads `revolutions` revolutions of each track.
+    pub fn revolutions(mut self, revolutions: usize) -> Self {
+        self.revolutions = revolutions;
+        self
+    }
+
+    /// Appends `analyzer` to the stages.
+    pub fn stage(mut self, analyzer: impl Analyzer + 'static) -> Self {
+        self.stages.push(Box::new(analyzer));
+        self
+    }
+
+    /// Runs half track `index` of `source` through the stages, or returns
+    /// `None` if the source has no flux there.
+    ///
+    /// The track starts out in the 1541's speed zone for it, and ends up
+    /// with bits: if no stage decoded it, the first revolution is.
+    ///
+    /// # Errors
+    /// Returns the error of the source or of a stage.
+    pub fn run(
+        &mut self,
+        source: &mut impl FluxSource,
+        index: usize,
+    ) -> io::Result<Option<TrackState>> {
+        span!("half track {index}");
+        let Some(flux) = source.read_flux(index, self.revolutions)? else {
+            event!("no flux");
+            return Ok(None);
+        };
+        let zone = speed_zone(index as u8 / 2 + 1);
+        let mut track = TrackState {
+            index,
+            zone,
+            cell: cell_length(zone),
+            flux,
+            ..TrackState::default()
+        };
+        for stage in &mut self.stages {
+            stage.process(&mut track)?;
+        }
+        event!(
+            "{} revolutions, zone {} after {} stages",
+            track.flux.revolutions.len(),
+            track.zone,
+            self.stages.len()
+        );
+        if track.bits.is_none() {
+            event!("no stage decoded the track, taking the first revolution");
+            let first = track.flux.revolutions.first();
+            track.bits = Some(first.map_or_else(Vec::new, |flux| to_bits(flux, track.cell)));
+        }
+        Ok(Some(track))
+    }
+
+    /// Runs every half track of `source` through the stages and stores
+    /// the bitstreams in a G64, each in the zone its state ended up with.
+    ///
+    /// # Errors
+    /// Returns the error of the source or of a stage.
+    pub fn to_g64(&mut self, source: &mut impl FluxSource) -> io::Result<G64> {
+        let mut g64 = G64::new();
+        for index in 0..source.half_tracks().min(g64.half_tracks()) {
+            if let Some(track) = self.run(source, index)? {
+                g64.set_half_track(index, track.bits.unwrap_or_default(), track.zone);
+            }
+        }
+        Ok(g64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    /// Splits the first interval of every revolution with a spike.
+    struct Spiky(G64);
+
+    impl FluxSource for Spiky {
+        fn half_tracks(&self) -> usize {
+            self.0.half_tracks()
+        }
+
+        fn read_flux(&mut self, index: usize, revs: usize) -> io::Result<Option<FluxTrack>> {
+            let mut flux = self.0.read_flux(index, revs)?;
+            for revolution in flux.iter_mut().flat_map(|flux| &mut flux.revolutions) {
+                let first = revolution[0];
+                revolution.splice(0..1, [100, first - 100]);
+            }
+            Ok(flux)
+        }
+    }
+
+    #[test]
+    fn filters_flux_before_decoding() {
+        let g64 = G64::from_image(&D64::new(35));
+        let mut source = Spiky(g64.clone());
+        let despike = |track: &mut TrackState| {
+            for revolution in &mut track.flux.revolutions {
+                let mut merged = Vec::with_capacity(revolution.len());
+                let mut carry = 0;
+                for &interval in revolution.iter() {
+                    if interval < 1000 {
+                        carry += interval;
+                    } else {
+                        merged.push(interval + carry);
+                        carry = 0;
+                    }
+                }
+                *revolution = merged;
+            }
+            Ok(())
+        };
+        let mut pipeline = Pipeline::new().stage(despike);
+        let rebuilt = pipeline.to_g64(&mut source).unwrap();
+        for index in 0..g64.half_tracks() {
+            assert_eq!(rebuilt.half_track(index), g64.half_track(index));
+        }
+        assert_eq!(rebuilt.half_track_zone(70), g64.half_track_zone(70));
+    }
+
+    #[test]
+    fn recognizes_after_decoding() {
+        let mut source = G64::from_image(&D64::new(35));
+        let recognizer = |track: &mut TrackState| {
+            let bits = track.bits.as_deref().unwrap_or_default();
+            if bits.windows(5).any(|w| w == [0xFF; 5]) {
+                track.notes.push(format!("sync on {}", track.index));
+            }
+            Ok(())
+        };
+        let mut pipeline = Pipeline::new()
+            .revolutions(3)
+            .stage(Merge)
+            .stage(recognizer)
+            .stage(|_: &mut TrackState| Err(io::Error::other("stop")));
+        assert!(pipeline.run(&mut source, 0).is_err());
+
+        let mut pipeline = Pipeline::new().stage(Merge).stage(recognizer);
+        let track = pipeline.run(&mut source, 2).unwrap().unwrap();
+        assert_eq!(track.notes, ["sync on 2"]);
+        assert!(track.weak.is_empty());
+        assert_eq!(pipeline.run(&mut source, 1).unwrap(), None);
+    }
+}
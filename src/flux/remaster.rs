@@ -0,0 +1,350 @@
+//! Remastering flux dumps of protected disks for emulators.
+//!
+//! A flux dump holds everything the disk does, and a good deal that only
+//! the dump does: gaps and syncs as long as the mastering drive happened
+//! to write them, tracks starting wherever the index hole was, half
+//! tracks that only pick up their neighbours. [`G64::normalize`] does
+//! away with all that, and with what copy protections check: it rotates
+//! the tracks it cannot rewrite and loses how far they are turned against
+//! each other, and it drops tracks without data.
+//!
+//! [`remaster`] decodes a dump through a [`Pipeline`], such as the one
+//! [`pipeline`] puts together, and normalizes what carries nothing of the
+//! protection, keeping what does:
+//!
+//! - tracks DOS can read in full are rewritten in the standard format;
+//! - the other tracks are kept as decoded, neither rotated nor cut, so
+//!   their length and their position against the index and each other
+//!   stay as dumped;
+//! - killer tracks, sync all around, are kept wherever they are;
+//! - weak bits are written as a run without flux transitions, which a
+//!   drive reads as random bits, like the weak bits themselves;
+//! - half tracks holding more than their neighbours are kept.
+//!
+//! The [`Remastered`] image comes with what was done with every half
+//! track and the anomalies kept, so the result can be checked against
+//! what the protection is known to rely on, see [`crate::protection`].
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+use super::FluxSource;
+use super::pipeline::{Merge, Normalize, Pipeline};
+use crate::d64::sectors_per_track;
+use crate::error::DosError;
+use crate::g64::{G64, Normalized};
+use crate::timing::{DEFAULT_RPM, SYNC_BITS, find_syncs, track_capacity};
+use crate::track;
+
+/// What [`remaster`] did with a half track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Treatment {
+    /// Every sector read without error and the track held nothing else,
+    /// so it was laid out anew in the standard format.
+    Rewritten,
+    /// The track was kept as decoded, weak bits aside.
+    Kept,
+    /// The half track held no sync, or only blocks of the tracks next to
+    /// it, and was left out.
+    Removed,
+}
+
+/// Something of a kept track that emulators must see as dumped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The track holds more than a revolution at its speed: `length`
+    /// bytes where a drive writes `capacity`.
+    Long {
+        half_track: usize,
+        length: usize,
+        capacity: usize,
+    },
+    /// The track is sync all around, which hangs a drive waiting for the
+    /// end of a sync.
+    Killer(usize),
+    /// Revolutions disagreed on these bits, now written without flux
+    /// transitions.
+    Weak {
+        half_track: usize,
+        bits: Vec<Range<usize>>,
+    },
+    /// Some sectors of the track cannot be read, with the errors the
+    /// drive reports for them.
+    SectorErrors {
+        half_track: usize,
+        errors: Vec<(u8, DosError)>,
+    },
+    /// No sector of the track can be read; it keeps the bit its longest
+    /// sync starts at, if it has one.
+    Foreign {
+        half_track: usize,
+        sync: Option<usize>,
+    },
+    /// A half track holds blocks of its own.
+    HalfTrack(usize),
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let track = |index: usize| (index + 2) as f64 / 2.0;
+        match self {
+            Anomaly::Long {
+                half_track,
+                length,
+                capacity,
+            } => write!(
+                f,
+                "track {}: {length} bytes, {capacity} fit a revolution",
+                track(*half_track)
+            ),
+            Anomaly::Killer(index) => write!(f, "track {}: killer track", track(*index)),
+            Anomaly::Weak { half_track, bits } => {
+                let count: usize = bits.iter().map(ExactSizeIterator::len).sum();
+                write!(
+                    f,
+                    "track {}: {count} weak bits in {} runs",
+                    track(*half_track),
+                    bits.len()
+                )
+            }
+            Anomaly::SectorErrors { half_track, errors } => {
+                write!(f, "track {}: errors", track(*half_track))?;
+                for (sector, error) in errors {
+                    write!(f, " {sector}:{}", error.code())?;
+                }
+                Ok(())
+            }
+            Anomaly::Foreign { half_track, sync } => {
+                write!(f, "track {}: no DOS sectors", track(*half_track))?;
+                match sync {
+                    Some(bit) => write!(f, ", longest sync at bit {bit}"),
+                    None => write!(f, ", no sync"),
+                }
+            }
+            Anomaly::HalfTrack(index) => write!(f, "track {}: data of its own", track(*index)),
+        }
+    }
+}
+
+/// The result of [`remaster`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remastered {
+    pub g64: G64,
+    /// What was done with each half track the dump holds, by index.
+    pub tracks: Vec<(usize, Treatment)>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// Returns the pipeline [`remaster`] is meant to be fed by: three
+/// revolutions, scaled to the nominal speed and merged, so weak bits show.
+pub fn pipeline() -> Pipeline {
+    Pipeline::new().revolutions(3).stage(Normalize).stage(Merge)
+}
+
+/// Decodes `source` through `pipeline` and remasters it as described in
+/// the [module documentation](self).
+///
+/// # Errors
+/// Returns the error of the source or of a stage.
+pub fn remaster(source: &mut impl FluxSource, pipeline: &mut Pipeline) -> io::Result<Remastered> {
+    let mut dump = G64::new();
+    let mut weak = Vec::new();
+    for index in 0..source.half_tracks().min(dump.half_tracks()) {
+        if let Some(track) = pipeline.run(source, index)? {
+            dump.set_half_track(index, track.bits.unwrap_or_default(), track.zone);
+            if !track.weak.is_empty() {
+                weak.push((index, track.weak));
+            }
+        }
+    }
+
+    let (mut g64, report) = dump.normalize();
+    let mut tracks = Vec::with_capacity(report.len());
+    let mut anomalies = Vec::new();
+    for (index, normalized) in report {
+        let data = dump.half_track(index).unwrap_or_default();
+        let killer = !data.is_empty() && data.iter().all(|&b| b == 0xFF);
+        let treatment = match normalized {
+            Normalized::Rewritten => Treatment::Rewritten,
+            Normalized::Removed if !killer => Treatment::Removed,
+            Normalized::Aligned | Normalized::Removed => Treatment::Kept,
+        };
+        tracks.push((index, treatment));
+        if treatment != Treatment::Kept {
+            continue;
+        }
+
+        let mut data = data.to_vec();
+        let zone = dump.half_track_zone(index);
+        if let Some((_, bits)) = weak.iter().find(|(i, _)| *i == index) {
+            for bit in bits.iter().cloned().flatten() {
+                if let Some(byte) = data.get_mut(bit / 8) {
+                    *byte &= !(0x80 >> (bit % 8));
+                }
+            }
+            anomalies.push(Anomaly::Weak {
+                half_track: index,
+                bits: bits.clone(),
+            });
+        }
+        let capacity = track_capacity(zone, DEFAULT_RPM);
+        if data.len() > capacity + capacity / 25 {
+            anomalies.push(Anomaly::Long {
+                half_track: index,
+                length: data.len(),
+                capacity,
+            });
+        }
+        if killer {
+            anomalies.push(Anomaly::Killer(index));
+        } else if index % 2 == 1 {
+            anomalies.push(Anomaly::HalfTrack(index));
+        } else {
+            let number = (index / 2 + 1) as u8;
+            let errors: Vec<(u8, DosError)> = (0..sectors_per_track(number))
+                .filter_map(|sector| {
+                    let error = track::read_sector(&data, number, sector, None).error?;
+                    Some((sector, error))
+                })
+                .collect();
+            if errors.len() == usize::from(sectors_per_track(number)) {
+                anomalies.push(Anomaly::Foreign {
+                    half_track: index,
+                    sync: longest_sync(&data),
+                });
+            } else if !errors.is_empty() {
+                anomalies.push(Anomaly::SectorErrors {
+                    half_track: index,
+                    errors,
+                });
+            }
+        }
+        g64.set_half_track(index, data, zone);
+    }
+    Ok(Remastered {
+        g64,
+        tracks,
+        anomalies,
+    })
+}
+
+/// Returns the bit the longest sync of the circular track `data` starts
+/// at.
+fn longest_sync(data: &[u8]) -> Option<usize> {
+    let bits = data.len() * 8;
+    find_syncs(data)
+        .into_iter()
+        .max_by_key(|&(detected, end)| ((end + bits - detected) % bits, usize::MAX - detected))
+        .map(|(detected, _)| (detected + bits - (SYNC_BITS - 1)) % bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::flux::{FluxTrack, cell_length, from_bits};
+    use crate::timing::speed_zone;
+
+    /// Reads a G64, with the bits of `weak` read differently on every
+    /// revolution.
+    struct Wobbly {
+        g64: G64,
+        weak: (usize, Range<usize>),
+    }
+
+    impl FluxSource for Wobbly {
+        fn half_tracks(&self) -> usize {
+            self.g64.half_tracks()
+        }
+
+        fn read_flux(&mut self, index: usize, revolutions: usize) -> io::Result<Option<FluxTrack>> {
+            let Some(data) = self.g64.half_track(index) else {
+                return Ok(None);
+            };
+            let cell = cell_length(self.g64.half_track_zone(index));
+            let revolutions = (0..revolutions)
+                .map(|revolution| {
+                    let mut data = data.to_vec();
+                    if index == self.weak.0 {
+                        for byte in &mut data[self.weak.1.clone()] {
+                            *byte ^= [0x00, 0x22, 0x44][revolution % 3];
+                        }
+                    }
+                    from_bits(&data, cell)
+                })
+                .collect();
+            Ok(Some(FluxTrack { revolutions }))
+        }
+    }
+
+    #[test]
+    fn rewrites_standard_tracks() {
+        let mut image = D64::new(35);
+        crate::fs::format(&mut image, b"PLAIN", Some(*b"01")).unwrap();
+        let mut g64 = G64::from_image(&image);
+        let mut turned = g64.track(5).unwrap().to_vec();
+        turned.rotate_left(1234);
+        g64.set_track(5, turned);
+        g64.set_half_track(9, g64.track(5).unwrap().to_vec(), speed_zone(5));
+
+        let remastered = remaster(&mut g64, &mut Pipeline::new()).unwrap();
+        assert_eq!(remastered.anomalies, []);
+        assert_eq!(remastered.tracks[4], (8, Treatment::Rewritten));
+        assert_eq!(remastered.tracks[5], (9, Treatment::Removed));
+        assert_eq!(remastered.g64, G64::from_image(&image));
+    }
+
+    #[test]
+    fn keeps_what_protections_check() {
+        let mut image = D64::new(35);
+        image
+            .set_sector_error(20, 3, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut g64 = G64::from_image(&image);
+        let capacity = track_capacity(speed_zone(10), DEFAULT_RPM);
+        let mut long: Vec<u8> = (0..capacity * 11 / 10)
+            .map(|i| if i % 400 < 5 { 0xFF } else { 0x49 })
+            .collect();
+        long.rotate_left(77);
+        g64.set_track(10, long.clone());
+        g64.set_half_track(70, vec![0xFF; 6250], 0);
+        let mut source = Wobbly {
+            g64,
+            weak: (18, 1000..1010),
+        };
+
+        let mut pipeline = Pipeline::new().revolutions(3).stage(Merge);
+        let remastered = remaster(&mut source, &mut pipeline).unwrap();
+        let kept = remastered.g64.track(10).unwrap();
+        assert_eq!(kept.len(), long.len());
+        assert_eq!(kept[..1000], long[..1000]);
+        assert_eq!(kept[1000], 0x49 & !0x66);
+        let anomalies = &remastered.anomalies;
+        assert!(
+            anomalies.contains(&Anomaly::Weak {
+                half_track: 18,
+                bits: (1000..1010)
+                    .flat_map(|byte| [byte * 8 + 1..byte * 8 + 3, byte * 8 + 5..byte * 8 + 7])
+                    .collect(),
+            })
+        );
+        assert!(anomalies.contains(&Anomaly::Long {
+            half_track: 18,
+            length: long.len(),
+            capacity,
+        }));
+        assert!(anomalies.contains(&Anomaly::Foreign {
+            half_track: 18,
+            sync: Some((400 - 77) * 8 - 1),
+        }));
+        assert!(anomalies.contains(&Anomaly::Killer(70)));
+        assert!(anomalies.contains(&Anomaly::SectorErrors {
+            half_track: 38,
+            errors: vec![(3, DosError::DataChecksum)],
+        }));
+        let line = anomalies[0].to_string();
+        assert!(line.starts_with("track 10: "), "{line}");
+        assert_eq!(remastered.g64.track(1), G64::from_image(&image).track(1));
+    }
+}
@@ -0,0 +1,1508 @@
+//! The CBM DOS filesystem as laid out by the 1541.
+//!
+//! All functions operate on any [`BlockDevice`] and implement the on-disk
+//! structures of DOS 2.6: the block availability map (BAM) in sector 18/0,
+//! the directory chain starting at 18/1 and files stored as linked lists of
+//! 256-byte blocks whose first two bytes point to the next block.
+
+use crate::allocation::{FreeMap, Strategy};
+use crate::d64::sectors_per_track;
+use crate::error::DosError;
+use crate::image::{BlockDevice, SECTOR_SIZE, Sector};
+use crate::petscii::{self, Charset, Controls};
+use alloc::{format, string::String, vec, vec::Vec};
+
+/// The track holding the BAM and directory.
+pub const DIR_TRACK: u8 = 18;
+/// The sector holding the BAM and disk header.
+pub const BAM_SECTOR: u8 = 0;
+/// The first directory sector.
+pub const DIR_SECTOR: u8 = 1;
+/// Maximum length of a filename or disk name.
+pub const NAME_LENGTH: usize = 16;
+/// The shifted space used to pad names.
+pub const PAD: u8 = 0xA0;
+/// Number of payload bytes in a file block.
+pub const BLOCK_PAYLOAD: usize = SECTOR_SIZE - 2;
+
+const DIR_INTERLEAVE: u8 = 3;
+pub(crate) const ENTRIES_PER_SECTOR: usize = 8;
+pub(crate) const ENTRY_SIZE: usize = 32;
+const BAM_TRACKS: u8 = 35;
+const BAM_EXTRA: usize = 0xAB;
+const GEOS_SIGNATURE: &[u8] = b"GEOS format";
+const GEOS_CLASS: usize = 0x4D;
+const GEOS_CLASS_LENGTH: usize = 20;
+
+/// The type of a file as stored in the low bits of its directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Del,
+    Seq,
+    Prg,
+    Usr,
+    Rel,
+}
+
+impl FileType {
+    /// Extracts the file type from a directory entry type byte.
+    ///
+    /// Returns `None` for the undefined type values 5-15.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte & 0x0F {
+            0 => Some(FileType::Del),
+            1 => Some(FileType::Seq),
+            2 => Some(FileType::Prg),
+            3 => Some(FileType::Usr),
+            4 => Some(FileType::Rel),
+            _ => None,
+        }
+    }
+
+    /// Returns the type value stored in the low bits of the type byte.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            FileType::Del => 0,
+            FileType::Seq => 1,
+            FileType::Prg => 2,
+            FileType::Usr => 3,
+            FileType::Rel => 4,
+        }
+    }
+
+    /// Returns the three-letter name shown in directory listings.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileType::Del => "DEL",
+            FileType::Seq => "SEQ",
+            FileType::Prg => "PRG",
+            FileType::Usr => "USR",
+            FileType::Rel => "REL",
+        }
+    }
+}
+
+/// The kind of a GEOS file, stored at `$18` of its directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeosType {
+    NonGeos,
+    Basic,
+    Assembler,
+    Data,
+    System,
+    DeskAccessory,
+    Application,
+    ApplicationData,
+    Font,
+    PrinterDriver,
+    InputDriver,
+    DiskDriver,
+    SystemBoot,
+    Temporary,
+    AutoExecute,
+    /// A value GEOS does not define.
+    Other(u8),
+}
+
+impl GeosType {
+    const TYPES: [GeosType; 15] = [
+        GeosType::NonGeos,
+        GeosType::Basic,
+        GeosType::Assembler,
+        GeosType::Data,
+        GeosType::System,
+        GeosType::DeskAccessory,
+        GeosType::Application,
+        GeosType::ApplicationData,
+        GeosType::Font,
+        GeosType::PrinterDriver,
+        GeosType::InputDriver,
+        GeosType::DiskDriver,
+        GeosType::SystemBoot,
+        GeosType::Temporary,
+        GeosType::AutoExecute,
+    ];
+
+    /// Returns the type stored as `byte`.
+    pub fn from_byte(byte: u8) -> Self {
+        GeosType::TYPES
+            .get(usize::from(byte))
+            .copied()
+            .unwrap_or(GeosType::Other(byte))
+    }
+
+    /// Returns the byte stored for the type.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            GeosType::Other(byte) => byte,
+            _ => GeosType::TYPES.iter().position(|&t| t == self).unwrap_or(0) as u8,
+        }
+    }
+
+    /// Returns the name the GEOS desktop shows for the type.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GeosType::NonGeos => "Non-GEOS file",
+            GeosType::Basic => "BASIC",
+            GeosType::Assembler => "Assembler",
+            GeosType::Data => "Data file",
+            GeosType::System => "System file",
+            GeosType::DeskAccessory => "Desk accessory",
+            GeosType::Application => "Application",
+            GeosType::ApplicationData => "Application data",
+            GeosType::Font => "Font file",
+            GeosType::PrinterDriver => "Printer driver",
+            GeosType::InputDriver => "Input driver",
+            GeosType::DiskDriver => "Disk driver",
+            GeosType::SystemBoot => "System boot file",
+            GeosType::Temporary => "Temporary",
+            GeosType::AutoExecute => "Auto-execute file",
+            GeosType::Other(_) => "Unknown",
+        }
+    }
+}
+
+/// What the directory entry of a GEOS file tells about it.
+///
+/// GEOS reuses the REL fields of the entry: the side sector link points to
+/// the file's info block, and the record length tells whether the file is
+/// a VLIR file, whose first block lists the chains of its records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GeosFile {
+    pub file_type: GeosType,
+    /// Whether the file is a VLIR file rather than a single chain.
+    pub vlir: bool,
+    /// Track of the info block, `0` if the file has none.
+    pub info_track: u8,
+    /// Sector of the info block.
+    pub info_sector: u8,
+    /// The time stamp: year (since 1900), month, day, hour and minute.
+    pub date: [u8; 5],
+}
+
+/// The position of a directory entry on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DirSlot {
+    pub track: u8,
+    pub sector: u8,
+    /// Index of the entry within its directory sector (0-7).
+    pub index: u8,
+}
+
+/// One file entry of the directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub file_type: FileType,
+    /// `false` for "splat" files that were never properly closed.
+    pub closed: bool,
+    pub locked: bool,
+    /// The filename, padded with `0xA0`.
+    pub name: [u8; NAME_LENGTH],
+    /// Track of the first data block.
+    pub track: u8,
+    /// Sector of the first data block.
+    pub sector: u8,
+    /// Track of the first side sector (REL files only).
+    pub side_track: u8,
+    /// Sector of the first side sector (REL files only).
+    pub side_sector: u8,
+    /// Record length (REL files only).
+    pub record_length: u8,
+    /// Size in blocks as stored in the directory.
+    pub blocks: u16,
+    /// The GEOS file type and time stamp at `$18`-`$1D`, zeros for files
+    /// not written by GEOS.
+    pub geos: [u8; 6],
+    pub slot: DirSlot,
+}
+
+impl DirEntry {
+    /// Returns the filename without its `0xA0` padding.
+    pub fn name(&self) -> &[u8] {
+        trim_name(&self.name)
+    }
+
+    pub(crate) fn parse(bytes: &[u8], slot: DirSlot) -> Option<Self> {
+        let type_byte = bytes[2];
+        if type_byte == 0 {
+            return None;
+        }
+        let mut name = [0u8; NAME_LENGTH];
+        name.copy_from_slice(&bytes[5..5 + NAME_LENGTH]);
+        Some(DirEntry {
+            file_type: FileType::from_byte(type_byte)?,
+            closed: type_byte & 0x80 != 0,
+            locked: type_byte & 0x40 != 0,
+            name,
+            track: bytes[3],
+            sector: bytes[4],
+            side_track: bytes[0x15],
+            side_sector: bytes[0x16],
+            record_length: bytes[0x17],
+            blocks: u16::from_le_bytes([bytes[0x1E], bytes[0x1F]]),
+            geos: bytes[0x18..0x1E].try_into().unwrap(),
+            slot,
+        })
+    }
+
+    pub(crate) fn store(&self, bytes: &mut [u8]) {
+        let mut type_byte = self.file_type.to_byte();
+        if self.closed {
+            type_byte |= 0x80;
+        }
+        if self.locked {
+            type_byte |= 0x40;
+        }
+        bytes[2] = type_byte;
+        bytes[3] = self.track;
+        bytes[4] = self.sector;
+        bytes[5..5 + NAME_LENGTH].copy_from_slice(&self.name);
+        bytes[0x15] = self.side_track;
+        bytes[0x16] = self.side_sector;
+        bytes[0x17] = self.record_length;
+        bytes[0x18..0x1E].copy_from_slice(&self.geos);
+        bytes[0x1E..0x20].copy_from_slice(&self.blocks.to_le_bytes());
+    }
+}
+
+/// Pads `name` to 16 bytes with `0xA0`, truncating longer names.
+pub fn pad_name(name: &[u8]) -> [u8; NAME_LENGTH] {
+    let mut out = [PAD; NAME_LENGTH];
+    let len = name.len().min(NAME_LENGTH);
+    out[..len].copy_from_slice(&name[..len]);
+    out
+}
+
+/// Strips the trailing `0xA0` padding from a stored name.
+pub fn trim_name(name: &[u8]) -> &[u8] {
+    let end = name.iter().rposition(|&b| b != PAD).map_or(0, |p| p + 1);
+    &name[..end]
+}
+
+/// Matches a filename against a DOS pattern, as the drive compares them.
+///
+/// `?` matches any single character and `*` matches the remainder of the
+/// name; like the drive, anything following a `*` is ignored. A stored name
+/// ends at its first `0xA0`, so `ABC` matches a name stored as `ABC`, a
+/// shifted space and `DEF`, whose tail listings hide after the closing
+/// quote; the pattern with the shifted space and the tail matches it too.
+/// `?` matches the padding as well. Padding at the end of the pattern is
+/// ignored.
+pub fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    compare(pattern, name, true)
+}
+
+/// Returns whether the drive takes `name` for the file stored as `stored`,
+/// as when it refuses to save under a name that is taken.
+///
+/// This is [`matches()`] without wildcards: `ABC` is taken by a file stored
+/// as `ABC`, a shifted space and `DEF`, but that name does not take `ABC`.
+pub fn same_name(name: &[u8], stored: &[u8]) -> bool {
+    compare(name, stored, false)
+}
+
+fn compare(pattern: &[u8], name: &[u8], wildcards: bool) -> bool {
+    let pattern = trim_name(pattern);
+    let at = |i: usize| name.get(i).copied().unwrap_or(PAD);
+    for (i, &p) in pattern.iter().take(NAME_LENGTH).enumerate() {
+        match p {
+            b'*' if wildcards => return true,
+            b'?' if wildcards => {}
+            _ if at(i) == p => {}
+            _ => return false,
+        }
+    }
+    pattern.len() >= NAME_LENGTH || at(pattern.len()) == PAD
+}
+
+/// The block availability map stored in sector 18/0.
+///
+/// Besides the allocation bitmap the sector carries the disk header: name,
+/// ID and DOS type. The map covers the 35 standard tracks; sectors on
+/// extended tracks are never reported as free.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bam {
+    sector: Sector,
+}
+
+impl Bam {
+    /// Takes the BAM from the bytes of its sector.
+    pub(crate) fn from_sector(sector: Sector) -> Self {
+        Bam { sector }
+    }
+
+    /// Reads the BAM from `image`.
+    pub fn read<I: BlockDevice + ?Sized>(image: &I) -> Result<Self, DosError> {
+        Ok(Bam {
+            sector: image.read_sector(DIR_TRACK, BAM_SECTOR)?,
+        })
+    }
+
+    /// Writes the BAM back to `image`.
+    pub fn write<I: BlockDevice + ?Sized>(&self, image: &mut I) -> Result<(), DosError> {
+        image.write_sector(DIR_TRACK, BAM_SECTOR, &self.sector)
+    }
+
+    /// Builds a fresh BAM with every block free except 18/0 and 18/1.
+    pub fn empty(name: &[u8], id: [u8; 2]) -> Self {
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[0] = DIR_TRACK;
+        sector[1] = DIR_SECTOR;
+        sector[2] = b'A';
+        sector[0x90..0xAB].fill(PAD);
+        sector[0x90..0xA0].copy_from_slice(&pad_name(name));
+        sector[0xA2..0xA4].copy_from_slice(&id);
+        sector[0xA5] = b'2';
+        sector[0xA6] = b'A';
+        let mut bam = Bam { sector };
+        for track in 1..=BAM_TRACKS {
+            for s in 0..sectors_per_track(track) {
+                bam.free(track, s);
+            }
+        }
+        bam.allocate(DIR_TRACK, BAM_SECTOR);
+        bam.allocate(DIR_TRACK, DIR_SECTOR);
+        bam
+    }
+
+    /// Returns the raw BAM sector.
+    pub fn as_bytes(&self) -> &Sector {
+        &self.sector
+    }
+
+    fn entry(track: u8) -> Option<usize> {
+        (1..=BAM_TRACKS)
+            .contains(&track)
+            .then(|| 4 * track as usize)
+    }
+
+    /// Returns `true` if the block is marked free.
+    pub fn is_free(&self, track: u8, sector: u8) -> bool {
+        match Self::entry(track) {
+            Some(e) if sector < sectors_per_track(track) => {
+                self.sector[e + 1 + sector as usize / 8] & (1 << (sector % 8)) != 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks a block as used. Returns `false` if it was not free.
+    pub fn allocate(&mut self, track: u8, sector: u8) -> bool {
+        if !self.is_free(track, sector) {
+            return false;
+        }
+        let e = 4 * track as usize;
+        self.sector[e + 1 + sector as usize / 8] &= !(1 << (sector % 8));
+        self.sector[e] = self.sector[e].saturating_sub(1);
+        true
+    }
+
+    /// Marks a block as free.
+    pub fn free(&mut self, track: u8, sector: u8) {
+        let Some(e) = Self::entry(track) else {
+            return;
+        };
+        if sector >= sectors_per_track(track) || self.is_free(track, sector) {
+            return;
+        }
+        self.sector[e + 1 + sector as usize / 8] |= 1 << (sector % 8);
+        self.sector[e] = self.sector[e].saturating_add(1);
+    }
+
+    /// Returns the free-block count stored for `track`.
+    pub fn free_on_track(&self, track: u8) -> u8 {
+        Self::entry(track).map_or(0, |e| self.sector[e])
+    }
+
+    /// Returns the number of free blocks outside the directory track, as
+    /// shown by `BLOCKS FREE.`
+    pub fn blocks_free(&self) -> u16 {
+        (1..=BAM_TRACKS)
+            .filter(|&t| t != DIR_TRACK)
+            .map(|t| self.free_on_track(t) as u16)
+            .sum()
+    }
+
+    /// Returns the padded disk name.
+    pub fn disk_name(&self) -> [u8; NAME_LENGTH] {
+        let mut name = [0u8; NAME_LENGTH];
+        name.copy_from_slice(&self.sector[0x90..0xA0]);
+        name
+    }
+
+    /// Returns the two-character disk ID.
+    pub fn disk_id(&self) -> [u8; 2] {
+        [self.sector[0xA2], self.sector[0xA3]]
+    }
+
+    /// Returns the two-character DOS type, normally `2A`.
+    pub fn dos_type(&self) -> [u8; 2] {
+        [self.sector[0xA5], self.sector[0xA6]]
+    }
+
+    /// Sets the DOS type shown in the directory header.
+    pub fn set_dos_type(&mut self, dos_type: [u8; 2]) {
+        self.sector[0xA5..0xA7].copy_from_slice(&dos_type);
+    }
+
+    /// Returns whether GEOS formatted the disk, which it marks with its
+    /// signature at `$AD`.
+    pub fn is_geos(&self) -> bool {
+        self.sector[0xAD..].starts_with(GEOS_SIGNATURE)
+    }
+
+    /// Returns the bytes following the disk header, `$AB`-`$FF`.
+    ///
+    /// DOS 2.6 leaves them unused, so disks put all kinds of things there:
+    /// BAM entries for tracks 36-40 (SpeedDOS at `$C0`, DolphinDOS at
+    /// `$AC`), the GEOS signature at `$AD` or a message from the author.
+    /// Every operation short of formatting keeps them as they are.
+    pub fn extra(&self) -> &[u8] {
+        &self.sector[BAM_EXTRA..]
+    }
+
+    /// Overwrites the bytes following the disk header, starting at `$AB`.
+    /// Bytes that do not fit are ignored.
+    pub fn set_extra(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(SECTOR_SIZE - BAM_EXTRA);
+        self.sector[BAM_EXTRA..BAM_EXTRA + len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// Returns the text in the bytes following the header if they hold
+    /// nothing but printable characters, padded with zeros or shifted
+    /// spaces.
+    pub fn message(&self) -> Option<&[u8]> {
+        let blank = |b: &u8| *b == 0 || *b == PAD;
+        let start = self.extra().iter().position(|b| !blank(b))?;
+        let end = self.extra().iter().rposition(|b| !blank(b))? + 1;
+        let text = &self.extra()[start..end];
+        text.iter()
+            .all(|&b| matches!(b, 0x20..=0x7E | PAD | 0xC1..=0xDA))
+            .then_some(text)
+    }
+
+    /// Finds the first free block of a new file.
+    ///
+    /// Like the 1541, tracks are searched outwards from the directory track,
+    /// trying the track below before the one above at each distance.
+    pub fn first_free(&self) -> Option<(u8, u8)> {
+        Strategy::C1541.first_free(self)
+    }
+
+    /// Finds the block following `track`/`sector` in a file chain.
+    ///
+    /// The sector advances by the 1541's interleave of 10 on the same track,
+    /// moving away from the directory track once a track is full and
+    /// reversing direction when the edge of the disk is reached.
+    pub fn next_free(&self, track: u8, sector: u8) -> Option<(u8, u8)> {
+        Strategy::C1541.next_free(self, track, sector)
+    }
+}
+
+impl FreeMap for Bam {
+    fn tracks(&self) -> u8 {
+        BAM_TRACKS
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        sectors_per_track(track)
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        Bam::is_free(self, track, sector)
+    }
+
+    fn free_on_track(&self, track: u8) -> u8 {
+        Bam::free_on_track(self, track)
+    }
+}
+
+/// The parsed directory of a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directory {
+    /// The padded disk name.
+    pub name: [u8; NAME_LENGTH],
+    pub id: [u8; 2],
+    pub dos_type: [u8; 2],
+    pub entries: Vec<DirEntry>,
+    pub blocks_free: u16,
+    /// Whether the disk is a GEOS disk; see [`Directory::geos`].
+    pub geos: bool,
+}
+
+impl Directory {
+    /// Returns what the entry of a GEOS file tells about it, or `None` for
+    /// files GEOS did not write and every file of a disk that is not a GEOS
+    /// disk.
+    ///
+    /// GEOS stores its files as `USR` (and a few as `PRG` or `SEQ`), so this
+    /// is what tells an application from a plain `USR` file. Other disks
+    /// leave whatever they like in the bytes GEOS uses, which is why they
+    /// are only trusted on GEOS disks.
+    pub fn geos(&self, entry: &DirEntry) -> Option<GeosFile> {
+        let file_type = GeosType::from_byte(entry.geos[0]);
+        if !self.geos || file_type == GeosType::NonGeos || entry.file_type == FileType::Rel {
+            return None;
+        }
+        Some(GeosFile {
+            file_type,
+            vlir: entry.record_length == 1,
+            info_track: entry.side_track,
+            info_sector: entry.side_sector,
+            date: entry.geos[1..].try_into().unwrap(),
+        })
+    }
+
+    /// Returns the name of the type of an entry for catalogs: the GEOS type
+    /// of GEOS files, the three letters of the listing for all others.
+    pub fn type_name(&self, entry: &DirEntry) -> &'static str {
+        match self.geos(entry) {
+            Some(geos) => geos.file_type.as_str(),
+            None => entry.file_type.as_str(),
+        }
+    }
+
+    /// Keeps the entries matching any of `patterns` and, if `file_type` is
+    /// given, of that type, as `LOAD "$:A*,B*=P"` selects them. Without
+    /// patterns every name matches.
+    pub fn filter<S: AsRef<[u8]>>(&mut self, patterns: &[S], file_type: Option<FileType>) {
+        self.entries.retain(|entry| {
+            file_type.is_none_or(|t| entry.file_type == t)
+                && (patterns.is_empty()
+                    || patterns.iter().any(|p| matches(p.as_ref(), &entry.name)))
+        });
+    }
+
+    /// Renders the directory as the BASIC program `LOAD "$",8` produces.
+    ///
+    /// The result starts with the load address `$0401` and contains a header
+    /// line, one line per entry (the block count serving as line number) and
+    /// the closing `BLOCKS FREE.` line.
+    pub fn listing(&self) -> Vec<u8> {
+        let mut out = vec![0x01, 0x04];
+        let mut address: u16 = 0x0401;
+        for (number, text) in self.lines() {
+            address += (text.len() + 5) as u16;
+            out.extend_from_slice(&address.to_le_bytes());
+            out.extend_from_slice(&number.to_le_bytes());
+            out.extend_from_slice(&text);
+            out.push(0);
+        }
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    /// Renders the directory as the C64 lists it, one string per line.
+    ///
+    /// The header shows in reverse characters, so a font with the C64's
+    /// private-use code points shows the look of the screen; see
+    /// [`petscii::render`].
+    pub fn render(&self, charset: Charset, controls: Controls) -> Vec<String> {
+        self.lines()
+            .into_iter()
+            .map(|(number, text)| {
+                format!("{number} {}", petscii::render(&text, charset, controls))
+            })
+            .collect()
+    }
+
+    fn lines(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut header = vec![0x12, b'"'];
+        header.extend(self.name.iter().map(|&b| if b == PAD { b' ' } else { b }));
+        header.extend_from_slice(&[b'"', b' ', self.id[0], self.id[1], b' ']);
+        header.extend_from_slice(&self.dos_type);
+
+        let mut lines = vec![(0, header)];
+        for entry in &self.entries {
+            lines.push((entry.blocks, entry_line(entry)));
+        }
+        let mut footer = b"BLOCKS FREE.".to_vec();
+        footer.resize(25, b' ');
+        lines.push((self.blocks_free, footer));
+        lines
+    }
+}
+
+fn entry_line(entry: &DirEntry) -> Vec<u8> {
+    let mut text = Vec::with_capacity(27);
+    let padding = match entry.blocks {
+        0..=9 => 3,
+        10..=99 => 2,
+        100..=999 => 1,
+        _ => 0,
+    };
+    text.resize(padding, b' ');
+    text.push(b'"');
+    let end = entry.name.iter().position(|&b| b == PAD).unwrap_or(NAME_LENGTH);
+    text.extend_from_slice(&entry.name[..end]);
+    text.push(b'"');
+    text.extend(
+        entry.name[end..]
+            .iter()
+            .map(|&b| if b == PAD { b' ' } else { b }),
+    );
+    text.push(if entry.closed { b' ' } else { b'*' });
+    text.extend_from_slice(entry.file_type.as_str().as_bytes());
+    text.push(if entry.locked { b'<' } else { b' ' });
+    text.resize(text.len().max(27), b' ');
+    text
+}
+
+/// Follows the directory chain and returns its sectors with their locations.
+pub(crate) fn directory_sectors<I: BlockDevice + ?Sized>(
+    image: &I,
+) -> Result<Vec<(u8, u8, Sector)>, DosError> {
+    let mut out = Vec::new();
+    let (mut track, mut sector) = (DIR_TRACK, DIR_SECTOR);
+    while track != 0 {
+        if out.len() >= image.sectors_per_track(DIR_TRACK) as usize
+            || out.iter().any(|&(t, s, _)| (t, s) == (track, sector))
+        {
+            return Err(DosError::DirectoryError);
+        }
+        let data = image.read_sector(track, sector)?;
+        out.push((track, sector, data));
+        (track, sector) = (data[0], data[1]);
+    }
+    Ok(out)
+}
+
+/// Reads the disk header and all directory entries.
+///
+/// Entries with a type byte of `0` (scratched or unused slots) are skipped.
+///
+/// # Errors
+/// Fails if a sector cannot be read or the directory chain loops.
+pub fn read_directory<I: BlockDevice + ?Sized>(image: &I) -> Result<Directory, DosError> {
+    let bam = Bam::read(image)?;
+    let mut entries = Vec::new();
+    for (track, sector, data) in directory_sectors(image)? {
+        for index in 0..ENTRIES_PER_SECTOR {
+            let bytes = &data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            let slot = DirSlot {
+                track,
+                sector,
+                index: index as u8,
+            };
+            entries.extend(DirEntry::parse(bytes, slot));
+        }
+    }
+    Ok(Directory {
+        name: bam.disk_name(),
+        id: bam.disk_id(),
+        dos_type: bam.dos_type(),
+        entries,
+        blocks_free: bam.blocks_free(),
+        geos: bam.is_geos(),
+    })
+}
+
+/// Reads the class of a GEOS file from its info block: the name and version
+/// of the application that made it, or of the application itself.
+///
+/// Returns an empty name if the file has no info block.
+///
+/// # Errors
+/// Fails if the info block cannot be read.
+pub fn geos_class<I: BlockDevice + ?Sized>(
+    image: &I,
+    file: &GeosFile,
+) -> Result<Vec<u8>, DosError> {
+    if file.info_track == 0 {
+        return Ok(Vec::new());
+    }
+    let block = image.read_sector(file.info_track, file.info_sector)?;
+    let class = &block[GEOS_CLASS..GEOS_CLASS + GEOS_CLASS_LENGTH];
+    let end = class.iter().position(|&b| b == 0).unwrap_or(class.len());
+    Ok(class[..end].to_vec())
+}
+
+/// Returns the first directory entry whose name matches `pattern`.
+///
+/// Scratched (`DEL` with an open type byte) entries are not considered.
+pub fn find_file<I: BlockDevice + ?Sized>(
+    image: &I,
+    pattern: &[u8],
+) -> Result<Option<DirEntry>, DosError> {
+    Ok(read_directory(image)?
+        .entries
+        .into_iter()
+        .find(|e| matches(pattern, &e.name)))
+}
+
+/// Returns the locations of every block in the chain starting at
+/// `track`/`sector`.
+///
+/// # Errors
+/// Returns [`DosError::IllegalTrackSector`] if a link points outside the disk
+/// or the chain loops.
+pub fn chain<I: BlockDevice + ?Sized>(
+    image: &I,
+    mut track: u8,
+    mut sector: u8,
+) -> Result<Vec<(u8, u8)>, DosError> {
+    let mut out = Vec::new();
+    while track != 0 {
+        if !image.contains(track, sector) || out.len() >= image.total_sectors() {
+            return Err(DosError::IllegalTrackSector);
+        }
+        out.push((track, sector));
+        let data = image.read_sector(track, sector)?;
+        (track, sector) = (data[0], data[1]);
+    }
+    Ok(out)
+}
+
+/// Reads the contents of the block chain starting at `track`/`sector`.
+pub fn read_chain<I: BlockDevice + ?Sized>(
+    image: &I,
+    track: u8,
+    sector: u8,
+) -> Result<Vec<u8>, DosError> {
+    let mut out = Vec::new();
+    for (t, s) in chain(image, track, sector)? {
+        let data = image.read_sector(t, s)?;
+        if data[0] == 0 {
+            let last = (data[1] as usize).max(1);
+            out.extend_from_slice(&data[2..=last]);
+        } else {
+            out.extend_from_slice(&data[2..]);
+        }
+    }
+    Ok(out)
+}
+
+/// Reads the contents of a file.
+///
+/// For PRG files the result includes the two-byte load address.
+pub fn read_file<I: BlockDevice + ?Sized>(
+    image: &I,
+    entry: &DirEntry,
+) -> Result<Vec<u8>, DosError> {
+    read_chain(image, entry.track, entry.sector)
+}
+
+/// Finds a free directory slot, extending the directory chain if needed.
+pub(crate) fn free_slot<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    bam: &mut Bam,
+) -> Result<DirSlot, DosError> {
+    let sectors = directory_sectors(image)?;
+    for &(track, sector, data) in &sectors {
+        for index in 0..ENTRIES_PER_SECTOR {
+            if data[index * ENTRY_SIZE + 2] == 0 {
+                return Ok(DirSlot {
+                    track,
+                    sector,
+                    index: index as u8,
+                });
+            }
+        }
+    }
+    let &(last_track, last_sector, mut last) = sectors.last().ok_or(DosError::DirectoryError)?;
+    let max = sectors_per_track(DIR_TRACK);
+    let next = (0..max)
+        .map(|i| (last_sector + DIR_INTERLEAVE + i) % max)
+        .find(|&s| bam.is_free(DIR_TRACK, s))
+        .ok_or(DosError::DiskFull)?;
+    bam.allocate(DIR_TRACK, next);
+    last[0] = DIR_TRACK;
+    last[1] = next;
+    image.write_sector(last_track, last_sector, &last)?;
+    let mut fresh = [0u8; SECTOR_SIZE];
+    fresh[1] = 0xFF;
+    image.write_sector(DIR_TRACK, next, &fresh)?;
+    Ok(DirSlot {
+        track: DIR_TRACK,
+        sector: next,
+        index: 0,
+    })
+}
+
+/// Writes a directory entry into the slot it records.
+pub fn write_entry<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    entry: &DirEntry,
+) -> Result<(), DosError> {
+    let slot = entry.slot;
+    let mut data = image.read_sector(slot.track, slot.sector)?;
+    let offset = slot.index as usize * ENTRY_SIZE;
+    entry.store(&mut data[offset..offset + ENTRY_SIZE]);
+    image.write_sector(slot.track, slot.sector, &data)
+}
+
+/// Allocates and writes a block chain holding `data`.
+///
+/// Returns the location of the first block and the number of blocks used.
+/// The BAM is updated in memory only.
+pub fn write_chain<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    bam: &mut Bam,
+    data: &[u8],
+) -> Result<((u8, u8), u16), DosError> {
+    write_chain_with(image, bam, data, Strategy::C1541)
+}
+
+/// Allocates and writes a block chain holding `data`, picking the blocks
+/// with `strategy`, like [`write_chain`] does with the one of the 1541.
+pub fn write_chain_with<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    bam: &mut Bam,
+    data: &[u8],
+    strategy: Strategy,
+) -> Result<((u8, u8), u16), DosError> {
+    let blocks = data.len().div_ceil(BLOCK_PAYLOAD).max(1);
+    if blocks > bam.blocks_free() as usize {
+        return Err(DosError::DiskFull);
+    }
+    let mut locations = Vec::with_capacity(blocks);
+    let mut current = strategy.first_free(bam).ok_or(DosError::DiskFull)?;
+    for i in 0..blocks {
+        bam.allocate(current.0, current.1);
+        locations.push(current);
+        if i + 1 < blocks {
+            current = strategy
+                .next_free(bam, current.0, current.1)
+                .ok_or(DosError::DiskFull)?;
+        }
+    }
+    for (i, &(track, sector)) in locations.iter().enumerate() {
+        let start = i * BLOCK_PAYLOAD;
+        let end = (start + BLOCK_PAYLOAD).min(data.len());
+        let mut block = [0u8; SECTOR_SIZE];
+        match locations.get(i + 1) {
+            Some(&(t, s)) => block[..2].copy_from_slice(&[t, s]),
+            None => block[1] = (end - start + 1) as u8,
+        }
+        block[2..2 + end - start].copy_from_slice(&data[start..end]);
+        image.write_sector(track, sector, &block)?;
+    }
+    Ok((locations[0], blocks as u16))
+}
+
+/// Stores a new file on the disk.
+///
+/// # Errors
+/// Returns [`DosError::FileExists`] if a file with the same name exists and
+/// [`DosError::DiskFull`] if there is not enough room for the data or the
+/// directory entry.
+pub fn write_file<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    name: &[u8],
+    file_type: FileType,
+    data: &[u8],
+) -> Result<DirEntry, DosError> {
+    write_file_with(image, name, file_type, data, Strategy::C1541)
+}
+
+/// Stores a new file on the disk like [`write_file`], with its blocks
+/// picked by `strategy`, so the file lies where the drive the strategy
+/// belongs to would put it.
+///
+/// # Errors
+/// As [`write_file`].
+pub fn write_file_with<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    name: &[u8],
+    file_type: FileType,
+    data: &[u8],
+    strategy: Strategy,
+) -> Result<DirEntry, DosError> {
+    event!("writing {}, {} bytes", name.escape_ascii(), data.len());
+    store_file(image, name, false, |image, bam| {
+        chain_entry(image, bam, name, file_type, data, strategy)
+    })
+}
+
+/// Stores a file on the disk in place of the file `name`, like saving with
+/// `@:`, or as a new file if there is none.
+///
+/// The new file is written before the blocks of the old one are released,
+/// so if it cannot be written the old file stays as it was.
+///
+/// # Errors
+/// Returns [`DosError::FileExists`] if the old file is locked, and the
+/// errors of [`write_file`].
+pub fn replace_file<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    name: &[u8],
+    file_type: FileType,
+    data: &[u8],
+) -> Result<DirEntry, DosError> {
+    event!("replacing {}, {} bytes", name.escape_ascii(), data.len());
+    store_file(image, name, true, |image, bam| {
+        chain_entry(image, bam, name, file_type, data, Strategy::C1541)
+    })
+}
+
+/// Writes the chain of a new file and returns its entry, with the slot
+/// left for [`store_file`] to fill in.
+fn chain_entry<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    bam: &mut Bam,
+    name: &[u8],
+    file_type: FileType,
+    data: &[u8],
+    strategy: Strategy,
+) -> Result<DirEntry, DosError> {
+    let ((track, sector), blocks) = write_chain_with(image, bam, data, strategy)?;
+    event!("{blocks} blocks from {track}/{sector}");
+    Ok(DirEntry {
+        file_type,
+        closed: true,
+        locked: false,
+        name: pad_name(name),
+        track,
+        sector,
+        side_track: 0,
+        side_sector: 0,
+        record_length: 0,
+        blocks,
+        geos: [0; 6],
+        slot: DirSlot::default(),
+    })
+}
+
+/// Enters the file `store` writes in the directory and writes the BAM.
+///
+/// `store` writes the blocks of the file, allocating them in the BAM it is
+/// given, and returns the entry. The entry goes into a free slot, or with
+/// `replace` into that of the file `name`, whose blocks are released only
+/// once `store` succeeded. A full disk fails in `store` before the
+/// directory is extended, so it leaves no directory sector linked.
+pub(crate) fn store_file<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    name: &[u8],
+    replace: bool,
+    store: impl FnOnce(&mut I, &mut Bam) -> Result<DirEntry, DosError>,
+) -> Result<DirEntry, DosError> {
+    let old = read_directory(image)?
+        .entries
+        .into_iter()
+        .find(|e| same_name(name, &e.name));
+    if old.as_ref().is_some_and(|old| !replace || old.locked) {
+        return Err(DosError::FileExists);
+    }
+    let mut bam = Bam::read(image)?;
+    let mut entry = store(image, &mut bam)?;
+    entry.slot = match &old {
+        Some(old) => {
+            free_blocks(image, &mut bam, old);
+            old.slot
+        }
+        None => free_slot(image, &mut bam)?,
+    };
+    write_entry(image, &entry)?;
+    bam.write(image)?;
+    event!("entry at {:?}", entry.slot);
+    Ok(entry)
+}
+
+/// Scratches every unlocked file matching `pattern`, returning the number of
+/// files removed.
+///
+/// As on the drive, only the type byte of the entry is cleared and the blocks
+/// are released in the BAM; the data itself stays on disk.
+pub fn scratch<I: BlockDevice + ?Sized>(image: &mut I, pattern: &[u8]) -> Result<u8, DosError> {
+    let mut bam = Bam::read(image)?;
+    let mut count = 0;
+    for entry in read_directory(image)?.entries {
+        if entry.locked || !matches(pattern, &entry.name) {
+            continue;
+        }
+        release(image, &mut bam, &entry)?;
+        count += 1;
+    }
+    bam.write(image)?;
+    event!("scratched {count} files");
+    Ok(count)
+}
+
+/// Scratches the file of `entry`, whether locked or not and whatever its
+/// name matches, the way [`scratch`] does.
+pub fn scratch_entry<I: BlockDevice + ?Sized>(image: &mut I, entry: &DirEntry) -> Result<(), DosError> {
+    let mut bam = Bam::read(image)?;
+    release(image, &mut bam, entry)?;
+    bam.write(image)
+}
+
+/// Frees the blocks of the file of `entry` in `bam`, unless it is unclosed.
+fn free_blocks<I: BlockDevice + ?Sized>(image: &I, bam: &mut Bam, entry: &DirEntry) {
+    if entry.closed {
+        for (t, s) in chain(image, entry.track, entry.sector).unwrap_or_default() {
+            bam.free(t, s);
+        }
+        if entry.side_track != 0 {
+            for (t, s) in chain(image, entry.side_track, entry.side_sector).unwrap_or_default() {
+                bam.free(t, s);
+            }
+        }
+    }
+}
+
+/// Frees the blocks of a file in `bam` and clears the type byte of its
+/// entry.
+fn release<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    bam: &mut Bam,
+    entry: &DirEntry,
+) -> Result<(), DosError> {
+    free_blocks(image, bam, entry);
+    let slot = entry.slot;
+    let mut data = image.read_sector(slot.track, slot.sector)?;
+    data[slot.index as usize * ENTRY_SIZE + 2] = 0;
+    image.write_sector(slot.track, slot.sector, &data)
+}
+
+/// Renames the file `old` to `new`.
+///
+/// # Errors
+/// Returns [`DosError::FileExists`] if `new` is taken and
+/// [`DosError::FileNotFound`] if `old` does not exist.
+pub fn rename<I: BlockDevice + ?Sized>(image: &mut I, new: &[u8], old: &[u8]) -> Result<(), DosError> {
+    let entries = read_directory(image)?.entries;
+    if entries.iter().any(|e| same_name(new, &e.name)) {
+        return Err(DosError::FileExists);
+    }
+    let mut entry = entries
+        .into_iter()
+        .find(|e| same_name(old, &e.name))
+        .ok_or(DosError::FileNotFound)?;
+    entry.name = pad_name(new);
+    event!("renaming {} to {}", old.escape_ascii(), new.escape_ascii());
+    write_entry(image, &entry)
+}
+
+/// Copies the files matching `sources` into the new file `new`, like the
+/// `C` command. Several sources are concatenated in order and the copy takes
+/// the type of the first one.
+///
+/// # Errors
+/// - [`DosError::InvalidFilename`] if `new` contains wildcards.
+/// - [`DosError::FileExists`] if `new` is taken.
+/// - [`DosError::FileNotFound`] if a source does not exist.
+/// - [`DosError::FileTypeMismatch`] if a source is a relative file, which
+///   cannot be joined with others.
+pub fn copy<I, S>(image: &mut I, new: &[u8], sources: &[S]) -> Result<DirEntry, DosError>
+where
+    I: BlockDevice + ?Sized,
+    S: AsRef<[u8]>,
+{
+    if new.iter().any(|&b| b == b'*' || b == b'?') {
+        return Err(DosError::InvalidFilename);
+    }
+    let mut data = Vec::new();
+    let mut file_type = None;
+    for source in sources {
+        let entry = find_file(image, source.as_ref())?.ok_or(DosError::FileNotFound)?;
+        if entry.file_type == FileType::Rel {
+            return Err(DosError::FileTypeMismatch);
+        }
+        file_type.get_or_insert(entry.file_type);
+        data.extend(read_file(image, &entry)?);
+    }
+    let file_type = file_type.ok_or(DosError::NoFileGiven)?;
+    write_file(image, new, file_type, &data)
+}
+
+/// Copies every sector of `source` to `target`, like the `D` command of the
+/// dual drives.
+///
+/// # Errors
+/// Returns [`DosError::IllegalTrackSector`] if `target` lacks a sector of
+/// `source`, in which case `target` is left untouched.
+pub fn duplicate<S, T>(source: &S, target: &mut T) -> Result<(), DosError>
+where
+    S: BlockDevice + ?Sized,
+    T: BlockDevice + ?Sized,
+{
+    let fits = (1..=source.tracks())
+        .all(|t| target.sectors_per_track(t) >= source.sectors_per_track(t));
+    if !fits {
+        return Err(DosError::IllegalTrackSector);
+    }
+    for track in 1..=source.tracks() {
+        for sector in 0..source.sectors_per_track(track) {
+            target.write_sector(track, sector, &source.read_sector(track, sector)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats the disk.
+///
+/// With an `id` every sector is cleared and a new BAM and directory are
+/// written. Without an `id` only the BAM and the first directory sector are
+/// rewritten, keeping the existing ID, which mirrors the drive's quick
+/// `N:NAME` format.
+pub fn format<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    name: &[u8],
+    id: Option<[u8; 2]>,
+) -> Result<(), DosError> {
+    event!(
+        "formatting {}, {}",
+        name.escape_ascii(),
+        if id.is_some() { "all sectors" } else { "keeping the ID" }
+    );
+    let id = match id {
+        Some(id) => {
+            let blank = [0u8; SECTOR_SIZE];
+            for track in 1..=image.tracks() {
+                for sector in 0..image.sectors_per_track(track) {
+                    image.write_sector(track, sector, &blank)?;
+                }
+            }
+            id
+        }
+        None => Bam::read(image)?.disk_id(),
+    };
+    Bam::empty(name, id).write(image)?;
+    let mut dir = [0u8; SECTOR_SIZE];
+    dir[1] = 0xFF;
+    image.write_sector(DIR_TRACK, DIR_SECTOR, &dir)
+}
+
+/// Returns the sectors of the directory track that the BAM marks as used
+/// although the directory does not occupy them.
+///
+/// Copy protections and loaders hide data there. [`validate`] frees them
+/// like the drive does, so tools that want to keep them have to allocate
+/// them again afterwards.
+pub fn hidden_directory_blocks<I: BlockDevice + ?Sized>(image: &I) -> Result<Vec<u8>, DosError> {
+    let bam = Bam::read(image)?;
+    let used = directory_sectors(image)?;
+    Ok((0..sectors_per_track(DIR_TRACK))
+        .filter(|&s| s != BAM_SECTOR && !bam.is_free(DIR_TRACK, s))
+        .filter(|&s| !used.iter().any(|&(t, u, _)| (t, u) == (DIR_TRACK, s)))
+        .collect())
+}
+
+/// Rebuilds the BAM from the directory, like the `V` command.
+///
+/// Blocks not reachable from a closed file are freed and unclosed ("splat")
+/// entries are removed from the directory.
+pub fn validate<I: BlockDevice + ?Sized>(image: &mut I) -> Result<(), DosError> {
+    let old = Bam::read(image)?;
+    let mut bam = Bam::empty(&old.disk_name(), old.disk_id());
+    let mut header = *old.as_bytes();
+    header[4..0x90].copy_from_slice(&bam.as_bytes()[4..0x90]);
+    bam.sector = header;
+    for (track, sector, _) in directory_sectors(image)? {
+        bam.allocate(track, sector);
+    }
+    for entry in read_directory(image)?.entries {
+        if !entry.closed {
+            event!("removing unclosed {}", trim_name(&entry.name).escape_ascii());
+            let slot = entry.slot;
+            let mut data = image.read_sector(slot.track, slot.sector)?;
+            data[slot.index as usize * ENTRY_SIZE + 2] = 0;
+            image.write_sector(slot.track, slot.sector, &data)?;
+            continue;
+        }
+        for (t, s) in chain(image, entry.track, entry.sector)? {
+            bam.allocate(t, s);
+        }
+        if entry.side_track != 0 {
+            for (t, s) in chain(image, entry.side_track, entry.side_sector)? {
+                bam.allocate(t, s);
+            }
+        }
+    }
+    event!("{} blocks free after validating", bam.blocks_free());
+    bam.write(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn formatted() -> D64 {
+        let mut image = D64::new(35);
+        format(&mut image, b"TEST DISK", Some(*b"01")).unwrap();
+        image
+    }
+
+    #[test]
+    fn write_read_and_scratch() {
+        let mut image = formatted();
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 664);
+
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let entry = write_file(&mut image, b"HELLO", FileType::Prg, &data).unwrap();
+        assert_eq!((entry.track, entry.sector, entry.blocks), (17, 0, 4));
+        assert_eq!(
+            chain(&image, 17, 0).unwrap(),
+            vec![(17, 0), (17, 10), (17, 20), (17, 8)]
+        );
+        assert_eq!(read_file(&image, &entry).unwrap(), data);
+        assert_eq!(
+            write_file(&mut image, b"HELLO", FileType::Prg, &[]),
+            Err(DosError::FileExists)
+        );
+
+        assert_eq!(scratch(&mut image, b"H*").unwrap(), 1);
+        assert!(read_directory(&image).unwrap().entries.is_empty());
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 664);
+    }
+
+    #[test]
+    fn full_disks_stay_consistent() {
+        let mut image = formatted();
+        for i in 0..ENTRIES_PER_SECTOR {
+            write_file(&mut image, &[b'A' + i as u8], FileType::Seq, b"x").unwrap();
+        }
+        // The next entry needs a new directory sector, the data a disk
+        // more than there is.
+        let before = image.clone();
+        let data = vec![0; 700 * BLOCK_PAYLOAD];
+        assert_eq!(
+            write_file(&mut image, b"HUGE", FileType::Prg, &data),
+            Err(DosError::DiskFull)
+        );
+        assert!(image == before);
+
+        let mut count = ENTRIES_PER_SECTOR;
+        let result = loop {
+            let name = format!("FILL{count}");
+            match write_file(&mut image, name.as_bytes(), FileType::Seq, &data[..1000]) {
+                Ok(_) => count += 1,
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(result, DosError::DiskFull);
+        let bam = Bam::read(&image).unwrap();
+        validate(&mut image).unwrap();
+        assert_eq!(Bam::read(&image).unwrap(), bam);
+        assert_eq!(read_directory(&image).unwrap().entries.len(), count);
+        assert!(crate::integrity::check(&image).issues.is_empty());
+    }
+
+    #[test]
+    fn writes_with_the_strategy_of_a_drive() {
+        let data = vec![0x42; 3 * BLOCK_PAYLOAD];
+        let mut image = formatted();
+        let entry =
+            write_file_with(&mut image, b"A", FileType::Prg, &data, Strategy::C1571).unwrap();
+        assert_eq!(
+            chain(&image, entry.track, entry.sector).unwrap(),
+            vec![(17, 0), (17, 6), (17, 12)]
+        );
+        // Without tracks beyond 35 the 1581 starts at the last one.
+        let entry =
+            write_file_with(&mut image, b"B", FileType::Prg, &data, Strategy::C1581).unwrap();
+        assert_eq!(
+            chain(&image, entry.track, entry.sector).unwrap(),
+            vec![(35, 0), (35, 1), (35, 2)]
+        );
+        assert_eq!(read_file(&image, &entry).unwrap(), data);
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 658);
+    }
+
+    #[test]
+    fn listing_matches_drive_output() {
+        let mut image = formatted();
+        write_file(&mut image, b"A", FileType::Seq, &[1, 2, 3]).unwrap();
+        let listing = read_directory(&image).unwrap().listing();
+
+        assert_eq!(&listing[..2], &[0x01, 0x04]);
+        let first_line = &listing[2..];
+        let next = u16::from_le_bytes([first_line[0], first_line[1]]);
+        assert_eq!(next, 0x0401 + 30);
+        assert_eq!(&first_line[4..8], &[0x12, b'"', b'T', b'E']);
+        let mut expected = b"   \"A\"".to_vec();
+        expected.extend_from_slice(&[b' '; 16]);
+        expected.extend_from_slice(b"SEQ  \0");
+        assert_eq!(&listing[2 + 30 + 4..2 + 30 + 4 + 28], &expected[..]);
+        assert!(listing.ends_with(b"BLOCKS FREE.             \0\0\0"));
+
+        let mut file = pad_name(b"\x1CART");
+        file[5] = 0x9E;
+        write_file(&mut image, &file, FileType::Prg, &[0x01, 0x08]).unwrap();
+        let lines = read_directory(&image).unwrap().render(Charset::Unshifted, Controls::Print);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("0 \u{E0A2}\u{E094}\u{E085}"));
+        assert_eq!(lines[1], "1    \"A\"                SEQ  ");
+        // The color code after the name vanishes, as on the screen.
+        let spaces = " ".repeat(11);
+        assert_eq!(lines[2], format!("1    \"\u{E09C}ART\"{spaces} PRG  "));
+        assert_eq!(lines[3], "662 BLOCKS FREE.             ");
+        let lines = read_directory(&image).unwrap().render(Charset::Shifted, Controls::Escape);
+        assert!(lines[0].starts_with("0 {$12}\"test disk"));
+        assert_eq!(lines[2], format!("1    \"{{$1C}}art\" {{$9E}}{spaces}prg  "));
+
+        let mut directory = read_directory(&image).unwrap();
+        directory.filter(&[b"B*"], None);
+        assert!(directory.entries.is_empty());
+        let mut directory = read_directory(&image).unwrap();
+        directory.filter::<&[u8]>(&[], Some(FileType::Seq));
+        assert_eq!(directory.entries.len(), 1);
+    }
+
+    #[test]
+    fn compares_names_as_the_drive_does() {
+        let hidden = pad_name(b"ABC\xA0DEF");
+        assert!(matches(b"ABC", &hidden));
+        assert!(matches(b"ABC\xA0DEF", &hidden));
+        assert!(matches(b"ABC\xA0D*", &hidden));
+        assert!(!matches(b"ABC\xA0D", &hidden));
+        assert!(!matches(b"ABCD*", &hidden));
+        assert!(matches(b"AB?", &pad_name(b"AB")));
+        assert!(matches(b"A?C", &hidden));
+        assert!(matches(&pad_name(b"ABC"), &hidden));
+        assert!(matches(b"0123456789ABCDEFGH", b"0123456789ABCDEF"));
+
+        assert!(same_name(b"ABC", &hidden));
+        assert!(!same_name(b"AB?", &hidden));
+        assert!(!same_name(&hidden, &pad_name(b"ABC")));
+
+        let mut image = formatted();
+        write_file(&mut image, &hidden, FileType::Prg, &[1]).unwrap();
+        assert_eq!(
+            write_file(&mut image, b"ABC", FileType::Prg, &[2]),
+            Err(DosError::FileExists)
+        );
+        write_file(&mut image, b"AB", FileType::Prg, &[3]).unwrap();
+        assert_eq!(
+            rename(&mut image, b"AB", b"ABC"),
+            Err(DosError::FileExists)
+        );
+        rename(&mut image, b"XYZ", b"ABC").unwrap();
+        let found = find_file(&image, b"XYZ").unwrap().unwrap();
+        assert_eq!(read_file(&image, &found).unwrap(), [1]);
+    }
+
+    #[test]
+    fn tells_geos_files_from_plain_usr() {
+        let mut image = formatted();
+        let mut entry = write_file(&mut image, b"GEOWRITE", FileType::Usr, &[0; 300]).unwrap();
+        write_file(&mut image, b"NOTES", FileType::Usr, &[1]).unwrap();
+        let mut info = [0u8; SECTOR_SIZE];
+        info[1] = 0xFF;
+        info[GEOS_CLASS..GEOS_CLASS + 16].copy_from_slice(b"geoWrite    V2.1");
+        image.write_sector(19, 0, &info).unwrap();
+        (entry.side_track, entry.side_sector, entry.record_length) = (19, 0, 1);
+        entry.geos = [6, 88, 4, 20, 12, 30];
+        write_entry(&mut image, &entry).unwrap();
+
+        let plain = read_directory(&image).unwrap();
+        assert!(!plain.geos && plain.geos(&plain.entries[0]).is_none());
+        assert_eq!(plain.type_name(&plain.entries[0]), "USR");
+
+        let mut bam = Bam::read(&image).unwrap();
+        bam.set_extra(b"\0\0GEOS format V1.0");
+        bam.write(&mut image).unwrap();
+        let dir = read_directory(&image).unwrap();
+        let geos = dir.geos(&dir.entries[0]).unwrap();
+        assert_eq!((geos.file_type, geos.vlir), (GeosType::Application, true));
+        assert_eq!(geos.date, [88, 4, 20, 12, 30]);
+        assert_eq!(geos_class(&image, &geos).unwrap(), b"geoWrite    V2.1");
+        assert_eq!(dir.type_name(&dir.entries[0]), "Application");
+        assert_eq!(dir.geos(&dir.entries[1]), None);
+        assert_eq!(dir.type_name(&dir.entries[1]), "USR");
+        assert!(dir.listing().windows(3).filter(|w| w == b"USR").count() == 2);
+
+        rename(&mut image, b"GEOWRITE 2.1", b"GEOWRITE").unwrap();
+        assert_eq!(read_directory(&image).unwrap().entries[0].geos, entry.geos);
+        assert_eq!(GeosType::from_byte(0x42), GeosType::Other(0x42));
+        assert_eq!(GeosType::Font.to_byte(), 8);
+    }
+
+    #[test]
+    fn bam_extras_survive_updates() {
+        let mut image = formatted();
+        let mut bam = Bam::read(&image).unwrap();
+        bam.set_extra(b"\0\0HELLO FROM 1986\0");
+        bam.allocate(DIR_TRACK, 5);
+        bam.write(&mut image).unwrap();
+        assert_eq!(hidden_directory_blocks(&image).unwrap(), [5]);
+
+        write_file(&mut image, b"A", FileType::Seq, &[1]).unwrap();
+        scratch(&mut image, b"A").unwrap();
+        validate(&mut image).unwrap();
+        let bam = Bam::read(&image).unwrap();
+        assert_eq!(bam.message(), Some(&b"HELLO FROM 1986"[..]));
+        assert_eq!(bam.extra().len(), 0x55);
+        assert!(hidden_directory_blocks(&image).unwrap().is_empty());
+
+        let mut bam = Bam::read(&image).unwrap();
+        bam.set_extra(&[0x12, 0x34]);
+        assert_eq!(bam.message(), None);
+
+        // A corrupt free count does not wrap around.
+        let mut raw = image.read_sector(DIR_TRACK, BAM_SECTOR).unwrap();
+        raw[4] = 0xFF;
+        raw[5] &= !1;
+        image.write_sector(DIR_TRACK, BAM_SECTOR, &raw).unwrap();
+        let mut bam = Bam::read(&image).unwrap();
+        bam.free(1, 0);
+        assert!(bam.is_free(1, 0));
+        assert_eq!(bam.free_on_track(1), 0xFF);
+    }
+
+    #[test]
+    fn copy_and_duplicate() {
+        let mut image = formatted();
+        write_file(&mut image, b"PART1", FileType::Seq, b"HELLO ").unwrap();
+        write_file(&mut image, b"PART2", FileType::Prg, b"WORLD").unwrap();
+        let entry = copy(&mut image, b"BOTH", &[b"PART1", b"PART2"]).unwrap();
+        assert_eq!(entry.file_type, FileType::Seq);
+        assert_eq!(read_file(&image, &entry).unwrap(), b"HELLO WORLD");
+        assert_eq!(
+            copy(&mut image, b"MORE", &[b"MISSING"]),
+            Err(DosError::FileNotFound)
+        );
+
+        let mut target = D64::new(40);
+        duplicate(&image, &mut target).unwrap();
+        assert!(find_file(&target, b"BOTH").unwrap().is_some());
+        assert_eq!(
+            duplicate(&target, &mut D64::new(35)),
+            Err(DosError::IllegalTrackSector)
+        );
+    }
+
+    /// A device with nothing but blocks in the order of a D64.
+    struct Blocks(Vec<Sector>);
+
+    impl BlockDevice for Blocks {
+        fn tracks(&self) -> u8 {
+            35
+        }
+
+        fn sectors_per_track(&self, track: u8) -> u8 {
+            sectors_per_track(track)
+        }
+
+        fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+            if !self.contains(track, sector) {
+                return Err(DosError::IllegalTrackSector);
+            }
+            Ok(self.0[crate::d64::sector_offset(track, sector) / SECTOR_SIZE])
+        }
+
+        fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+            if !self.contains(track, sector) {
+                return Err(DosError::IllegalTrackSector);
+            }
+            self.0[crate::d64::sector_offset(track, sector) / SECTOR_SIZE] = *data;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn files_live_on_any_block_device() {
+        let mut device = Blocks(vec![[0; SECTOR_SIZE]; 683]);
+        format(&mut device, b"BLOCKS", Some(*b"BD")).unwrap();
+        let entry = write_file(&mut device, b"DATA", FileType::Seq, &[7; 600]).unwrap();
+        assert_eq!(entry.blocks, 3);
+        validate(&mut device).unwrap();
+        assert_eq!(Bam::read(&device).unwrap().blocks_free(), 661);
+
+        let mut image = D64::new(35);
+        duplicate(&device, &mut image).unwrap();
+        let found = find_file(&image, b"DATA").unwrap().unwrap();
+        assert_eq!(read_file(&image, &found).unwrap(), [7; 600]);
+    }
+}
@@ -0,0 +1,584 @@
+//! Disk images mounted as directories, through FUSE.
+//!
+//! With the `fuse` feature the crate links against `libfuse3` and
+//! [`mount()`] serves a [`Mount`] on a directory until it is unmounted, so
+//! file managers, shells and editors work on the files of an image. Names,
+//! buffering and attributes are those of [`crate::mount`]; this module
+//! only translates the calls of the FUSE high-level API.
+//!
+//! Linux on `x86_64` and `aarch64` is supported, whose `struct stat` this
+//! module lays out itself.
+
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::DosError;
+use crate::fs::{FileType, NAME_LENGTH};
+use crate::image::DiskImage;
+use crate::mount::{self, Mount};
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+compile_error!("the `fuse` feature supports Linux on x86_64 and aarch64 only");
+
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uint, c_void};
+
+    #[cfg(target_arch = "x86_64")]
+    #[repr(C)]
+    pub struct Stat {
+        pub st_dev: u64,
+        pub st_ino: u64,
+        pub st_nlink: u64,
+        pub st_mode: u32,
+        pub st_uid: u32,
+        pub st_gid: u32,
+        pad: i32,
+        pub st_rdev: u64,
+        pub st_size: i64,
+        pub st_blksize: i64,
+        pub st_blocks: i64,
+        times: [i64; 6],
+        unused: [i64; 3],
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[repr(C)]
+    pub struct Stat {
+        pub st_dev: u64,
+        pub st_ino: u64,
+        pub st_mode: u32,
+        pub st_nlink: u32,
+        pub st_uid: u32,
+        pub st_gid: u32,
+        pub st_rdev: u64,
+        pad: u64,
+        pub st_size: i64,
+        pub st_blksize: i32,
+        pad2: i32,
+        pub st_blocks: i64,
+        times: [i64; 6],
+        unused: [i32; 2],
+    }
+
+    #[repr(C)]
+    pub struct StatVfs {
+        pub f_bsize: u64,
+        pub f_frsize: u64,
+        pub f_blocks: u64,
+        pub f_bfree: u64,
+        pub f_bavail: u64,
+        pub f_files: u64,
+        pub f_ffree: u64,
+        pub f_favail: u64,
+        pub f_fsid: u64,
+        pub f_flag: u64,
+        pub f_namemax: u64,
+        spare: [c_int; 6],
+    }
+
+    /// `struct fuse_file_info`, only ever passed on.
+    #[repr(C)]
+    pub struct FileInfo {
+        _opaque: [u8; 0],
+    }
+
+    pub type Filler = unsafe extern "C" fn(
+        buf: *mut c_void,
+        name: *const c_char,
+        stat: *const Stat,
+        offset: i64,
+        flags: c_int,
+    ) -> c_int;
+
+    /// A callback this module leaves to libfuse.
+    pub type Unused = Option<unsafe extern "C" fn()>;
+
+    /// `struct fuse_operations` of libfuse 3 up to `utimens`; libfuse
+    /// takes the size passed and leaves the callbacks behind it unset.
+    #[repr(C)]
+    pub struct Operations {
+        pub getattr: Option<unsafe extern "C" fn(*const c_char, *mut Stat, *mut FileInfo) -> c_int>,
+        pub readlink: Unused,
+        pub mknod: Unused,
+        pub mkdir: Unused,
+        pub unlink: Option<unsafe extern "C" fn(*const c_char) -> c_int>,
+        pub rmdir: Unused,
+        pub symlink: Unused,
+        pub rename: Option<unsafe extern "C" fn(*const c_char, *const c_char, c_uint) -> c_int>,
+        pub link: Unused,
+        pub chmod: Option<unsafe extern "C" fn(*const c_char, u32, *mut FileInfo) -> c_int>,
+        pub chown: Option<unsafe extern "C" fn(*const c_char, u32, u32, *mut FileInfo) -> c_int>,
+        pub truncate: Option<unsafe extern "C" fn(*const c_char, i64, *mut FileInfo) -> c_int>,
+        pub open: Option<unsafe extern "C" fn(*const c_char, *mut FileInfo) -> c_int>,
+        pub read: Option<
+            unsafe extern "C" fn(*const c_char, *mut c_char, usize, i64, *mut FileInfo) -> c_int,
+        >,
+        pub write: Option<
+            unsafe extern "C" fn(*const c_char, *const c_char, usize, i64, *mut FileInfo) -> c_int,
+        >,
+        pub statfs: Option<unsafe extern "C" fn(*const c_char, *mut StatVfs) -> c_int>,
+        pub flush: Option<unsafe extern "C" fn(*const c_char, *mut FileInfo) -> c_int>,
+        pub release: Option<unsafe extern "C" fn(*const c_char, *mut FileInfo) -> c_int>,
+        pub fsync: Unused,
+        pub setxattr: Option<
+            unsafe extern "C" fn(
+                *const c_char,
+                *const c_char,
+                *const c_char,
+                usize,
+                c_int,
+            ) -> c_int,
+        >,
+        pub getxattr:
+            Option<unsafe extern "C" fn(*const c_char, *const c_char, *mut c_char, usize) -> c_int>,
+        pub listxattr: Option<unsafe extern "C" fn(*const c_char, *mut c_char, usize) -> c_int>,
+        pub removexattr: Unused,
+        pub opendir: Unused,
+        pub readdir: Option<
+            unsafe extern "C" fn(
+                *const c_char,
+                *mut c_void,
+                Filler,
+                i64,
+                *mut FileInfo,
+                c_int,
+            ) -> c_int,
+        >,
+        pub releasedir: Unused,
+        pub fsyncdir: Unused,
+        pub init: Unused,
+        pub destroy: Unused,
+        pub access: Unused,
+        pub create: Option<unsafe extern "C" fn(*const c_char, u32, *mut FileInfo) -> c_int>,
+        pub lock: Unused,
+        pub utimens:
+            Option<unsafe extern "C" fn(*const c_char, *const c_void, *mut FileInfo) -> c_int>,
+    }
+
+    /// `struct fuse_context`.
+    #[repr(C)]
+    pub struct Context {
+        pub fuse: *mut c_void,
+        pub uid: u32,
+        pub gid: u32,
+        pub pid: c_int,
+        pub private_data: *mut c_void,
+        pub umask: u32,
+    }
+
+    #[link(name = "fuse3")]
+    unsafe extern "C" {
+        pub fn fuse_main_real(
+            argc: c_int,
+            argv: *mut *mut c_char,
+            op: *const Operations,
+            op_size: usize,
+            private_data: *mut c_void,
+        ) -> c_int;
+        pub fn fuse_get_context() -> *mut Context;
+    }
+}
+
+const EPERM: c_int = 1;
+const ENOENT: c_int = 2;
+const EIO: c_int = 5;
+const EBADF: c_int = 9;
+const EEXIST: c_int = 17;
+const EINVAL: c_int = 22;
+const ENOSPC: c_int = 28;
+const ERANGE: c_int = 34;
+const ENAMETOOLONG: c_int = 36;
+const ENODATA: c_int = 61;
+const ENOTSUP: c_int = 95;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Returns the negated `errno` FUSE expects for `error`.
+fn errno(error: DosError) -> c_int {
+    -match error {
+        DosError::FileNotFound => ENOENT,
+        DosError::FileExists => EEXIST,
+        DosError::DiskFull => ENOSPC,
+        DosError::WriteProtect => EPERM,
+        DosError::FileNotOpen => EBADF,
+        DosError::InvalidFilename | DosError::Syntax | DosError::FileTypeMismatch => EINVAL,
+        DosError::CommandNotFound => ENOTSUP,
+        _ => EIO,
+    }
+}
+
+/// Runs `f` on the mount FUSE serves, returning what it returns or its
+/// error as `errno`.
+///
+/// # Safety
+/// Must be called from a callback of a filesystem [`mount()`] started for
+/// the same `I`.
+unsafe fn with<I, F>(f: F) -> c_int
+where
+    I: DiskImage,
+    F: FnOnce(&mut Mount<I>) -> Result<c_int, DosError>,
+{
+    // SAFETY: the private data is the mutex `mount` passed to libfuse,
+    // which outlives the callbacks.
+    let state = unsafe { &*((*ffi::fuse_get_context()).private_data as *const Mutex<Mount<I>>) };
+    match state.lock() {
+        Ok(mut mount) => f(&mut mount).unwrap_or_else(errno),
+        Err(_) => -EIO,
+    }
+}
+
+/// Returns the host name of the file at `path`, `Some("")` for the root.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string.
+unsafe fn file_name<'a>(path: *const c_char) -> Result<&'a str, DosError> {
+    // SAFETY: libfuse passes NUL-terminated paths.
+    let path = unsafe { CStr::from_ptr(path) }.to_str();
+    match path.ok().and_then(|path| path.strip_prefix('/')) {
+        Some(name) if !name.contains('/') => Ok(name),
+        _ => Err(DosError::FileNotFound),
+    }
+}
+
+/// Copies `value` to a buffer of `size` bytes, or returns the size it
+/// needs if `size` is zero, as the xattr calls do.
+///
+/// # Safety
+/// `buffer` must have room for `size` bytes.
+unsafe fn copy_out(value: &[u8], buffer: *mut c_char, size: usize) -> c_int {
+    if size == 0 {
+        return value.len() as c_int;
+    }
+    if size < value.len() {
+        return -ERANGE;
+    }
+    // SAFETY: the caller gives room for `size` bytes.
+    unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), buffer.cast(), value.len()) };
+    value.len() as c_int
+}
+
+unsafe extern "C" fn getattr<I: DiskImage>(
+    path: *const c_char,
+    stat: *mut ffi::Stat,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let stat = &mut *stat;
+            match file_name(path)? {
+                "" => {
+                    stat.st_mode = S_IFDIR | 0o755;
+                    stat.st_nlink = 2;
+                }
+                name => {
+                    let node = mount.lookup(name)?;
+                    let permissions = if node.entry.locked { 0o444 } else { 0o644 };
+                    stat.st_mode = S_IFREG | permissions;
+                    stat.st_nlink = 1;
+                    stat.st_size = node.size as i64;
+                    stat.st_blocks = node.size.div_ceil(512) as i64;
+                }
+            }
+            Ok(0)
+        })
+    }
+}
+
+unsafe extern "C" fn readdir<I: DiskImage>(
+    path: *const c_char,
+    buf: *mut c_void,
+    filler: ffi::Filler,
+    _: i64,
+    _: *mut ffi::FileInfo,
+    _: c_int,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            if !file_name(path)?.is_empty() {
+                return Err(DosError::FileNotFound);
+            }
+            let nodes = mount.list()?;
+            let names = [".", ".."].into_iter().map(String::from);
+            for name in names.chain(nodes.into_iter().map(|node| node.name)) {
+                let name = CString::new(name).map_err(|_| DosError::InvalidFilename)?;
+                if filler(buf, name.as_ptr(), std::ptr::null(), 0, 0) != 0 {
+                    break;
+                }
+            }
+            Ok(0)
+        })
+    }
+}
+
+unsafe extern "C" fn open<I: DiskImage>(path: *const c_char, _: *mut ffi::FileInfo) -> c_int {
+    unsafe { with::<I, _>(|mount| mount.open(file_name(path)?).map(|()| 0)) }
+}
+
+unsafe extern "C" fn create<I: DiskImage>(
+    path: *const c_char,
+    _: u32,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let name = file_name(path)?;
+            if crate::petscii::from_host_name(name).is_some_and(|n| n.len() > NAME_LENGTH) {
+                return Ok(-ENAMETOOLONG);
+            }
+            mount.create(name, FileType::Prg).map(|()| 0)
+        })
+    }
+}
+
+unsafe extern "C" fn read<I: DiskImage>(
+    path: *const c_char,
+    buf: *mut c_char,
+    size: usize,
+    offset: i64,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let data = mount.read(file_name(path)?, offset as usize, size)?;
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buf.cast(), data.len());
+            Ok(data.len() as c_int)
+        })
+    }
+}
+
+unsafe extern "C" fn write<I: DiskImage>(
+    path: *const c_char,
+    buf: *const c_char,
+    size: usize,
+    offset: i64,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let data = std::slice::from_raw_parts(buf.cast(), size);
+            mount.write(file_name(path)?, offset as usize, data)?;
+            Ok(size as c_int)
+        })
+    }
+}
+
+unsafe extern "C" fn truncate<I: DiskImage>(
+    path: *const c_char,
+    size: i64,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    unsafe { with::<I, _>(|mount| mount.truncate(file_name(path)?, size as usize).map(|()| 0)) }
+}
+
+unsafe extern "C" fn flush<I: DiskImage>(path: *const c_char, _: *mut ffi::FileInfo) -> c_int {
+    unsafe { with::<I, _>(|mount| mount.flush(file_name(path)?).map(|()| 0)) }
+}
+
+unsafe extern "C" fn release<I: DiskImage>(path: *const c_char, _: *mut ffi::FileInfo) -> c_int {
+    unsafe { with::<I, _>(|mount| mount.release(file_name(path)?).map(|()| 0)) }
+}
+
+unsafe extern "C" fn unlink<I: DiskImage>(path: *const c_char) -> c_int {
+    unsafe { with::<I, _>(|mount| mount.remove(file_name(path)?).map(|()| 0)) }
+}
+
+unsafe extern "C" fn rename<I: DiskImage>(
+    from: *const c_char,
+    to: *const c_char,
+    flags: std::ffi::c_uint,
+) -> c_int {
+    if flags != 0 {
+        return -EINVAL;
+    }
+    unsafe { with::<I, _>(|mount| mount.rename(file_name(from)?, file_name(to)?).map(|()| 0)) }
+}
+
+/// Locks a file whose write permission is taken away and unlocks it when
+/// it is given back.
+unsafe extern "C" fn chmod<I: DiskImage>(
+    path: *const c_char,
+    mode: u32,
+    _: *mut ffi::FileInfo,
+) -> c_int {
+    let locked: &[u8] = if mode & 0o200 == 0 { b"1" } else { b"0" };
+    unsafe {
+        with::<I, _>(|mount| {
+            mount
+                .set_attribute(file_name(path)?, mount::LOCKED_ATTRIBUTE, locked)
+                .map(|()| 0)
+        })
+    }
+}
+
+unsafe extern "C" fn chown(_: *const c_char, _: u32, _: u32, _: *mut ffi::FileInfo) -> c_int {
+    0
+}
+
+unsafe extern "C" fn utimens(_: *const c_char, _: *const c_void, _: *mut ffi::FileInfo) -> c_int {
+    0
+}
+
+unsafe extern "C" fn statfs<I: DiskImage>(_: *const c_char, stat: *mut ffi::StatVfs) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let stat = &mut *stat;
+            let free = u64::from(mount.blocks_free()?);
+            stat.f_bsize = crate::fs::BLOCK_PAYLOAD as u64;
+            stat.f_frsize = crate::fs::BLOCK_PAYLOAD as u64;
+            stat.f_blocks = mount.image().total_sectors() as u64;
+            stat.f_bfree = free;
+            stat.f_bavail = free;
+            stat.f_namemax = NAME_LENGTH as u64;
+            Ok(0)
+        })
+    }
+}
+
+unsafe fn attribute_name<'a>(name: *const c_char) -> Result<&'a str, DosError> {
+    // SAFETY: libfuse passes NUL-terminated names.
+    unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .map_err(|_| DosError::CommandNotFound)
+}
+
+unsafe extern "C" fn getxattr<I: DiskImage>(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut c_char,
+    size: usize,
+) -> c_int {
+    unsafe {
+        with::<I, _>(
+            |mount| match mount.attribute(file_name(path)?, attribute_name(name)?)? {
+                Some(text) => Ok(copy_out(text.as_bytes(), value, size)),
+                None => Ok(-ENODATA),
+            },
+        )
+    }
+}
+
+unsafe extern "C" fn listxattr<I: DiskImage>(
+    path: *const c_char,
+    list: *mut c_char,
+    size: usize,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let host = file_name(path)?;
+            let mut names = Vec::new();
+            for key in mount::ATTRIBUTES {
+                if mount.attribute(host, key)?.is_some() {
+                    names.extend_from_slice(key.as_bytes());
+                    names.push(0);
+                }
+            }
+            Ok(copy_out(&names, list, size))
+        })
+    }
+}
+
+unsafe extern "C" fn setxattr<I: DiskImage>(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const c_char,
+    size: usize,
+    _: c_int,
+) -> c_int {
+    unsafe {
+        with::<I, _>(|mount| {
+            let value = std::slice::from_raw_parts(value.cast(), size);
+            mount
+                .set_attribute(file_name(path)?, attribute_name(name)?, value)
+                .map(|()| 0)
+        })
+    }
+}
+
+/// Serves `mount` on the directory `mountpoint` until it is unmounted,
+/// then returns it with the changes made, for the caller to save.
+///
+/// `options` are passed to libfuse as `-o` options, such as `ro` or
+/// `allow_other`. The filesystem runs in the foreground, on one thread.
+///
+/// # Errors
+/// Fails if a path or option contains a NUL byte or libfuse cannot mount
+/// the filesystem.
+pub fn mount<I: DiskImage + Send>(
+    mount: Mount<I>,
+    mountpoint: &Path,
+    options: &[&str],
+) -> io::Result<Mount<I>> {
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidInput, "NUL byte in argument");
+    let mut args = vec![
+        CString::new("cbm-dos").map_err(invalid)?,
+        CString::new(mountpoint.as_os_str().as_bytes()).map_err(invalid)?,
+        CString::new("-f").map_err(invalid)?,
+        CString::new("-s").map_err(invalid)?,
+    ];
+    for option in options {
+        args.push(CString::new("-o").map_err(invalid)?);
+        args.push(CString::new(*option).map_err(invalid)?);
+    }
+    let mut argv: Vec<*mut c_char> = args.iter().map(|arg| arg.as_ptr().cast_mut()).collect();
+    let operations = ffi::Operations {
+        getattr: Some(getattr::<I>),
+        readlink: None,
+        mknod: None,
+        mkdir: None,
+        unlink: Some(unlink::<I>),
+        rmdir: None,
+        symlink: None,
+        rename: Some(rename::<I>),
+        link: None,
+        chmod: Some(chmod::<I>),
+        chown: Some(chown),
+        truncate: Some(truncate::<I>),
+        open: Some(open::<I>),
+        read: Some(read::<I>),
+        write: Some(write::<I>),
+        statfs: Some(statfs::<I>),
+        flush: Some(flush::<I>),
+        release: Some(release::<I>),
+        fsync: None,
+        setxattr: Some(setxattr::<I>),
+        getxattr: Some(getxattr::<I>),
+        listxattr: Some(listxattr::<I>),
+        removexattr: None,
+        opendir: None,
+        readdir: Some(readdir::<I>),
+        releasedir: None,
+        fsyncdir: None,
+        init: None,
+        destroy: None,
+        access: None,
+        create: Some(create::<I>),
+        lock: None,
+        utimens: Some(utimens),
+    };
+    let state = Mutex::new(mount);
+    // SAFETY: the arguments, the operations and the state outlive the call,
+    // which returns only after the filesystem is unmounted.
+    let status = unsafe {
+        ffi::fuse_main_real(
+            argv.len() as c_int,
+            argv.as_mut_ptr(),
+            &operations,
+            size_of::<ffi::Operations>(),
+            (&state as *const Mutex<Mount<I>>).cast_mut().cast(),
+        )
+    };
+    let mount = state.into_inner().unwrap_or_else(PoisonError::into_inner);
+    match status {
+        0 => Ok(mount),
+        status => Err(io::Error::other(format!(
+            "libfuse failed with status {status}"
+        ))),
+    }
+}
@@ -0,0 +1,611 @@
+//! The G64 raw GCR image format.
+//!
+//! A G64 file stores the GCR bitstream of every half track of a disk
+//! together with the speed zone it was recorded in:
+//!
+//! ```plaintext
+//! $0000  "GCR-1541"           signature
+//! $0008  version (0)
+//! $0009  number of half tracks (usually 84)
+//! $000A  maximum track size (u16, little endian)
+//! $000C  track offset table   one u32 per half track, 0 = not present
+//! ....   speed zone table     one u32 per half track
+//! ....   track data           u16 length followed by the GCR bytes
+//! ```
+//!
+//! Dumps of the same disk rarely are the same file: tracks start wherever
+//! the drive was, gaps and syncs have the lengths the mastering drive gave
+//! them, and some tools store half tracks that only repeat their
+//! neighbours. [`G64::normalize`] rewrites what is standard in the
+//! standard format and aligns the rest, and [`compare`] tells whether two
+//! files hold the same disk.
+
+use crate::d64;
+use crate::error::DosError;
+use crate::fs::{BAM_SECTOR, DIR_TRACK};
+use crate::image::{BlockDevice, DiskImage, ImageError, SECTOR_SIZE, Sector};
+use crate::timing::speed_zone;
+use crate::track::{self, Interleave, Origin, SectorRead, encode_interleaved, encode_track};
+use alloc::{vec, vec::Vec};
+
+/// The signature at the start of every G64 file.
+pub const SIGNATURE: &[u8; 8] = b"GCR-1541";
+/// Number of half tracks in a standard G64 file.
+pub const HALF_TRACKS: usize = 84;
+/// Default maximum track size written into new files.
+pub const MAX_TRACK_SIZE: u16 = 7928;
+
+const HEADER_SIZE: usize = 12;
+/// The half track index of the directory track.
+const DIR_INDEX: usize = (DIR_TRACK as usize - 1) * 2;
+
+/// A G64 image held in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct G64 {
+    tracks: Vec<Option<Vec<u8>>>,
+    speeds: Vec<u8>,
+    max_track_size: u16,
+    /// The ID of [`G64::disk_id`], kept up to date with track 18.
+    disk_id: Option<[u8; 2]>,
+}
+
+impl Default for G64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl G64 {
+    /// Creates an image with 84 empty half tracks.
+    pub fn new() -> Self {
+        G64 {
+            tracks: vec![None; HALF_TRACKS],
+            speeds: vec![0; HALF_TRACKS],
+            max_track_size: MAX_TRACK_SIZE,
+            disk_id: None,
+        }
+    }
+
+    /// Parses a G64 file.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] if the file does not start with
+    ///   `GCR-1541`.
+    /// - [`ImageError::Truncated`] if a table or track lies beyond the end of
+    ///   the file.
+    /// - [`ImageError::Unsupported`] for per-byte speed zone maps.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if bytes.len() < HEADER_SIZE || &bytes[..8] != SIGNATURE {
+            return Err(ImageError::InvalidSignature);
+        }
+        let count = bytes[9] as usize;
+        let max_track_size = u16::from_le_bytes([bytes[10], bytes[11]]);
+        let table_end = HEADER_SIZE + 8 * count;
+        if bytes.len() < table_end {
+            return Err(ImageError::Truncated);
+        }
+        let word = |i: usize| {
+            u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as usize
+        };
+
+        let mut image = G64 {
+            tracks: vec![None; count],
+            speeds: vec![0; count],
+            max_track_size,
+            disk_id: None,
+        };
+        for half in 0..count {
+            let speed = word(HEADER_SIZE + 4 * (count + half));
+            if speed > 3 {
+                return Err(ImageError::Unsupported("speed zone maps"));
+            }
+            image.speeds[half] = speed as u8;
+
+            let offset = word(HEADER_SIZE + 4 * half);
+            if offset == 0 {
+                continue;
+            }
+            let length = bytes
+                .get(offset..offset + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .ok_or(ImageError::Truncated)?;
+            let data = bytes
+                .get(offset + 2..offset + 2 + length)
+                .ok_or(ImageError::Truncated)?;
+            image.tracks[half] = Some(data.to_vec());
+        }
+        image.find_disk_id();
+        Ok(image)
+    }
+
+    /// Serializes the image into G64 file form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let count = self.tracks.len();
+        let slot = 2 + self.max_track_size as usize;
+        let mut out = Vec::new();
+        out.extend_from_slice(SIGNATURE);
+        out.push(0);
+        out.push(count as u8);
+        out.extend_from_slice(&self.max_track_size.to_le_bytes());
+
+        let mut offset = HEADER_SIZE + 8 * count;
+        let mut offsets = Vec::with_capacity(count);
+        for track in &self.tracks {
+            match track {
+                Some(_) => {
+                    offsets.push(offset as u32);
+                    offset += slot;
+                }
+                None => offsets.push(0),
+            }
+        }
+        for o in offsets {
+            out.extend_from_slice(&o.to_le_bytes());
+        }
+        for &speed in &self.speeds {
+            out.extend_from_slice(&(speed as u32).to_le_bytes());
+        }
+        for data in self.tracks.iter().flatten() {
+            out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            out.extend_from_slice(data);
+            out.resize(out.len() + slot - 2 - data.len(), 0);
+        }
+        out
+    }
+
+    /// Builds a G64 from the sectors of a disk image.
+    ///
+    /// Every track is laid out in the standard 1541 format using the disk ID
+    /// from the BAM. Read errors the image records for a sector, such as the
+    /// error block of a D64, are reproduced physically as described for
+    /// [`encode_track`].
+    pub fn from_image<I: DiskImage + ?Sized>(image: &I) -> Self {
+        G64::from_image_interleaved(image, &Interleave::STANDARD)
+    }
+
+    /// Builds a G64 from the sectors of a disk image as
+    /// [`from_image`](Self::from_image) does, with the sectors of every
+    /// track laid out by `interleave`.
+    pub fn from_image_interleaved<I: DiskImage + ?Sized>(
+        image: &I,
+        interleave: &Interleave,
+    ) -> Self {
+        let id = image
+            .read_sector(DIR_TRACK, BAM_SECTOR)
+            .map(|bam| [bam[0xA2], bam[0xA3]])
+            .unwrap_or([0, 0]);
+        let mut g64 = G64::new();
+        for track in 1..=image.tracks().min((HALF_TRACKS / 2) as u8) {
+            let sectors: Vec<_> = (0..image.sectors_per_track(track))
+                .map(|s| image.read_sector(track, s).unwrap_or([0; SECTOR_SIZE]))
+                .collect();
+            let data = encode_interleaved(track, &sectors, id, interleave.of(track), |s| {
+                image.sector_error(track, s)
+            });
+            g64.set_track(track, data);
+        }
+        g64
+    }
+
+    /// Returns the number of half tracks the image has room for.
+    pub fn half_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Returns the GCR data of half track `index`, where index 0 is track 1,
+    /// index 1 track 1.5 and so on.
+    pub fn half_track(&self, index: usize) -> Option<&[u8]> {
+        self.tracks.get(index)?.as_deref()
+    }
+
+    /// Returns the GCR data of full track `track` (starting at 1).
+    pub fn track(&self, track: u8) -> Option<&[u8]> {
+        self.half_track((track as usize).checked_sub(1)? * 2)
+    }
+
+    /// Returns the speed zone recorded for full track `track`.
+    pub fn speed_zone(&self, track: u8) -> u8 {
+        (track as usize)
+            .checked_sub(1)
+            .and_then(|t| self.speeds.get(t * 2).copied())
+            .unwrap_or(0)
+    }
+
+    /// Returns the speed zone recorded for half track `index`.
+    pub fn half_track_zone(&self, index: usize) -> u8 {
+        self.speeds.get(index).copied().unwrap_or(0)
+    }
+
+    /// Stores the GCR data of full track `track`, using the speed zone the
+    /// 1541 would select for it.
+    pub fn set_track(&mut self, track: u8, data: Vec<u8>) {
+        self.set_half_track((track as usize - 1) * 2, data, speed_zone(track));
+    }
+
+    /// Stores the GCR data and speed zone of half track `index`.
+    ///
+    /// The maximum track size grows as needed to hold `data`.
+    pub fn set_half_track(&mut self, index: usize, data: Vec<u8>, zone: u8) {
+        if index >= self.tracks.len() {
+            self.tracks.resize(index + 1, None);
+            self.speeds.resize(index + 1, 0);
+        }
+        self.max_track_size = self.max_track_size.max(data.len() as u16);
+        self.tracks[index] = Some(data);
+        self.speeds[index] = zone & 3;
+        if index == DIR_INDEX {
+            self.find_disk_id();
+        }
+    }
+
+    /// Removes half track `index`.
+    pub fn clear_half_track(&mut self, index: usize) {
+        if let Some(track) = self.tracks.get_mut(index) {
+            *track = None;
+        }
+        if index == DIR_INDEX {
+            self.disk_id = None;
+        }
+    }
+
+    /// Returns the disk ID in the header of sector 18/0, which the drive
+    /// expects on every other sector after initializing.
+    pub fn disk_id(&self) -> Option<[u8; 2]> {
+        self.disk_id
+    }
+
+    /// Reads the disk ID anew from track 18, as writing it may change it.
+    fn find_disk_id(&mut self) {
+        self.disk_id = self
+            .track(DIR_TRACK)
+            .and_then(|data| track::read_sector(data, DIR_TRACK, BAM_SECTOR, None).header_id);
+    }
+
+    fn read(&self, track: u8, sector: u8) -> SectorRead {
+        match self.track(track) {
+            Some(data) => track::read_sector(data, track, sector, self.disk_id),
+            None => SectorRead {
+                data: None,
+                error: Some(DosError::NoSync),
+                header_id: None,
+                data_bit: None,
+            },
+        }
+    }
+}
+
+/// What [`G64::normalize`] did with a half track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Normalized {
+    /// Every sector read without error and the track held nothing else, so
+    /// it was laid out anew in the standard format.
+    Rewritten,
+    /// The track holds more than standard sectors, or sector errors, and
+    /// was kept as it is, rotated to start at the sync of sector 0 or else
+    /// at its longest sync.
+    Aligned,
+    /// The half track held no sync, or only blocks of the tracks next to
+    /// it, and was left out.
+    Removed,
+}
+
+/// Returns the sectors of a standard track: one where every sector of
+/// `track` reads without error and nothing but their headers and data
+/// blocks follows a sync.
+fn standard_sectors(data: &[u8], track: u8, id: Option<[u8; 2]>) -> Option<Vec<Sector>> {
+    let count = d64::sectors_per_track(track);
+    if count == 0 || track::blocks(data).len() != usize::from(count) * 2 {
+        return None;
+    }
+    (0..count)
+        .map(|sector| {
+            let read = track::read_sector(data, track, sector, id);
+            if read.error.is_some() {
+                return None;
+            }
+            read.data
+        })
+        .collect()
+}
+
+/// Whether the blocks `a` and `b` are the same, starting anywhere.
+fn same_blocks(a: &[Vec<u8>], b: &[Vec<u8>]) -> bool {
+    a.len() == b.len()
+        && (a.is_empty()
+            || (0..a.len()).any(|shift| (0..a.len()).all(|i| a[(i + shift) % a.len()] == b[i])))
+}
+
+impl G64 {
+    /// Returns the image with its standard tracks rewritten in the
+    /// standard format, as [`G64::from_image`] lays them out, with the
+    /// others aligned, and without redundant half tracks, together with
+    /// what was done with each half track present, by index.
+    ///
+    /// Tracks are read with the disk ID of [`G64::disk_id`]. A half track
+    /// is redundant if it holds no sync, as do unformatted half tracks
+    /// and tracks beyond 35, or if every block on it is a block of a
+    /// neighbouring track. Full tracks up to 35 without sync are kept, as
+    /// they make the drive report error 21.
+    pub fn normalize(&self) -> (G64, Vec<(usize, Normalized)>) {
+        let id = self.disk_id();
+        let mut out = G64::new();
+        let mut report = Vec::new();
+        let blocks = |index: usize| self.half_track(index).map(track::blocks);
+        for index in 0..self.half_tracks() {
+            let Some(data) = self.half_track(index) else {
+                continue;
+            };
+            let full = index % 2 == 0;
+            let track = (index / 2 + 1) as u8;
+            if full && let Some(sectors) = standard_sectors(data, track, id) {
+                let id = id
+                    .or_else(|| track::read_sector(data, track, 0, None).header_id)
+                    .unwrap_or([0, 0]);
+                out.set_track(track, encode_track(track, &sectors, id, |_| None));
+                report.push((index, Normalized::Rewritten));
+                continue;
+            }
+            let own = track::blocks(data);
+            let neighbours: Vec<Vec<u8>> = [index.checked_sub(1), Some(index + 1)]
+                .into_iter()
+                .flatten()
+                .filter_map(blocks)
+                .flatten()
+                .collect();
+            let repeated = !full && own.iter().all(|block| neighbours.contains(block));
+            if own.is_empty() && (!full || track > 35) || repeated {
+                report.push((index, Normalized::Removed));
+                continue;
+            }
+            let aligned = track::align(data, Origin::SectorZero)
+                .or_else(|| track::align(data, Origin::LongestSync))
+                .unwrap_or_else(|| data.to_vec());
+            out.set_half_track(index, aligned, self.half_track_zone(index));
+            report.push((index, Normalized::Aligned));
+        }
+        (out, report)
+    }
+}
+
+/// How a half track differs between two images, see [`compare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DifferenceKind {
+    /// Only the first image has the half track.
+    OnlyInFirst,
+    /// Only the second image has the half track.
+    OnlyInSecond,
+    /// The blocks on the half track differ.
+    Contents,
+    /// The blocks are the same, but recorded in another speed zone.
+    SpeedZone,
+}
+
+/// A half track two images differ in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Difference {
+    /// The index of the half track, 0 for track 1.
+    pub half_track: usize,
+    pub kind: DifferenceKind,
+}
+
+/// Compares two images as disks: both are [normalized](G64::normalize)
+/// and their half tracks compared by their [blocks](track::blocks), so
+/// that alignment, gaps, sync lengths, the bit offsets of blocks and
+/// redundant half tracks do not count. No differences mean the images hold
+/// the same disk.
+pub fn compare(first: &G64, second: &G64) -> Vec<Difference> {
+    let (first, _) = first.normalize();
+    let (second, _) = second.normalize();
+    (0..first.half_tracks().max(second.half_tracks()))
+        .filter_map(|index| {
+            let kind = match (first.half_track(index), second.half_track(index)) {
+                (None, None) => return None,
+                (Some(_), None) => DifferenceKind::OnlyInFirst,
+                (None, Some(_)) => DifferenceKind::OnlyInSecond,
+                (Some(a), Some(b)) => {
+                    if !same_blocks(&track::blocks(a), &track::blocks(b)) {
+                        DifferenceKind::Contents
+                    } else if first.half_track_zone(index) != second.half_track_zone(index) {
+                        DifferenceKind::SpeedZone
+                    } else {
+                        return None;
+                    }
+                }
+            };
+            Some(Difference {
+                half_track: index,
+                kind,
+            })
+        })
+        .collect()
+}
+
+/// Reads the tracks like a 1541 does.
+///
+/// The disk has as many tracks as the highest full track present, each with
+/// the standard number of sectors. Sectors are decoded from the GCR data on
+/// every access, and [`BlockDevice::sector_error`] reports what the drive
+/// would find, such as 23 for a bad data checksum or 21 for a track
+/// without sync. Writes replace the data block of a sector in place and
+/// fail if its header cannot be found.
+impl BlockDevice for G64 {
+    fn tracks(&self) -> u8 {
+        (1..=(self.tracks.len() / 2) as u8)
+            .rev()
+            .find(|&t| self.track(t).is_some())
+            .unwrap_or(0)
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        if track <= self.tracks() {
+            d64::sectors_per_track(track)
+        } else {
+            0
+        }
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        Ok(self.read(track, sector).data.unwrap_or([0; SECTOR_SIZE]))
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        let read = self.read(track, sector);
+        let bit = match read.error {
+            Some(
+                error @ (DosError::NoSync
+                | DosError::HeaderNotFound
+                | DosError::HeaderChecksum
+                | DosError::DiskIdMismatch),
+            ) => return Err(error),
+            _ => read.data_bit.ok_or(DosError::HeaderNotFound)?,
+        };
+        let index = (track as usize - 1) * 2;
+        if let Some(Some(gcr)) = self.tracks.get_mut(index) {
+            track::write_data(gcr, bit, data);
+        }
+        Ok(())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        if !self.contains(track, sector) {
+            return None;
+        }
+        self.read(track, sector).error
+    }
+}
+
+impl DiskImage for G64 {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut g64 = G64::new();
+        g64.set_track(1, vec![0x55; 7692]);
+        g64.set_track(35, vec![0xFF; 6250]);
+        let bytes = g64.to_bytes();
+        assert_eq!(&bytes[..8], SIGNATURE);
+
+        let parsed = G64::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, g64);
+        assert_eq!(parsed.speed_zone(1), 3);
+        assert_eq!(parsed.speed_zone(35), 0);
+        assert!(parsed.track(2).is_none());
+        assert_eq!(
+            G64::from_bytes(b"GCR-1571"),
+            Err(ImageError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn converts_from_d64() {
+        let g64 = G64::from_image(&D64::new(35));
+        assert_eq!(g64.track(1).unwrap().len(), 7692);
+        assert_eq!(g64.track(35).unwrap().len(), 6250);
+        assert!(g64.track(36).is_none());
+    }
+
+    #[test]
+    fn reads_and_writes_sectors() {
+        let mut image = D64::new(35);
+        crate::fs::format(&mut image, b"G64", Some(*b"AB")).unwrap();
+        image.write_sector(5, 3, &[0x42; SECTOR_SIZE]).unwrap();
+        image
+            .set_sector_error(5, 4, Some(DosError::HeaderChecksum))
+            .unwrap();
+        let mut g64 = G64::from_image(&image);
+
+        assert_eq!(g64.tracks(), 35);
+        assert_eq!(g64.disk_id(), Some(*b"AB"));
+        assert_eq!(g64.read_sector(5, 3).unwrap(), [0x42; SECTOR_SIZE]);
+        assert_eq!(g64.read_sector(18, 0), image.read_sector(18, 0));
+        assert_eq!(g64.sector_error(5, 4), Some(DosError::HeaderChecksum));
+        assert_eq!(
+            g64.write_sector(5, 4, &[0; SECTOR_SIZE]),
+            Err(DosError::HeaderChecksum)
+        );
+        g64.write_sector(5, 3, &[0x24; SECTOR_SIZE]).unwrap();
+        assert_eq!(g64.read_sector(5, 3).unwrap(), [0x24; SECTOR_SIZE]);
+        assert_eq!(g64.sector_error(5, 3), None);
+        assert_eq!(g64.read_sector(36, 0), Err(DosError::IllegalTrackSector));
+
+        // The ID follows track 18 as it is replaced.
+        let sectors = vec![[0; SECTOR_SIZE]; 19];
+        g64.set_track(18, encode_track(18, &sectors, *b"CD", |_| None));
+        assert_eq!(g64.disk_id(), Some(*b"CD"));
+        assert_eq!(g64.sector_error(5, 3), Some(DosError::DiskIdMismatch));
+        let mut g64 = G64::from_bytes(&g64.to_bytes()).unwrap();
+        assert_eq!(g64.disk_id(), Some(*b"CD"));
+        g64.clear_half_track(DIR_INDEX);
+        assert_eq!(g64.disk_id(), None);
+        assert_eq!(g64.sector_error(5, 3), None);
+    }
+
+    #[test]
+    fn normalizes_dumps_of_the_same_disk() {
+        let mut image = D64::new(35);
+        crate::fs::format(&mut image, b"SAME", Some(*b"SD")).unwrap();
+        image.write_sector(3, 1, &[0x33; SECTOR_SIZE]).unwrap();
+        image
+            .set_sector_error(20, 2, Some(DosError::DataChecksum))
+            .unwrap();
+        let canonical = G64::from_image(&image);
+
+        // A dump: track 1 starts mid-track with longer gaps and syncs, track
+        // 20 (with its error) starts a few bits late, a half track repeats
+        // track 2 and another is unformatted, and track 36 is empty noise.
+        let mut dump = canonical.clone();
+        let mut stretched = Vec::new();
+        for sector in 0..21 {
+            stretched.extend_from_slice(&[0xFF; 7]);
+            stretched.extend(track::encode_header(1, sector, *b"SD"));
+            stretched.extend_from_slice(&[track::GAP_BYTE; 12]);
+            stretched.extend_from_slice(&[0xFF; 6]);
+            stretched.extend(track::encode_data(&image.read_sector(1, sector).unwrap()));
+            stretched.extend_from_slice(&[track::GAP_BYTE; 4]);
+        }
+        stretched.rotate_left(1234);
+        dump.set_track(1, stretched);
+        let mut late = canonical.track(20).unwrap().to_vec();
+        late.rotate_left(700);
+        dump.set_track(20, late);
+        dump.set_half_track(3, canonical.track(2).unwrap().to_vec(), 3);
+        dump.set_half_track(5, vec![0x00; 7000], 3);
+        dump.set_track(36, vec![0x55; 6250]);
+        assert_ne!(dump, canonical);
+
+        let (normalized, report) = dump.normalize();
+        assert!(report.contains(&(0, Normalized::Rewritten)));
+        assert!(report.contains(&(38, Normalized::Aligned)));
+        assert!(report.contains(&(3, Normalized::Removed)));
+        assert!(report.contains(&(5, Normalized::Removed)));
+        assert!(report.contains(&(70, Normalized::Removed)));
+        assert_eq!(normalized.track(1), canonical.track(1));
+        assert_eq!(normalized.track(20), canonical.track(20));
+        assert_eq!(normalized.read_sector(3, 1).unwrap(), [0x33; SECTOR_SIZE]);
+        assert_eq!(normalized.sector_error(20, 2), Some(DosError::DataChecksum));
+        assert!(compare(&dump, &canonical).is_empty());
+
+        image.write_sector(3, 1, &[0x34; SECTOR_SIZE]).unwrap();
+        let mut other = G64::from_image(&image);
+        other.set_half_track(69, vec![0xFF, 0xFF, 0x52, 0x94, 0x00, 0x00], 0);
+        let differences = compare(&dump, &other);
+        let kinds: Vec<_> = differences.iter().map(|d| (d.half_track, d.kind)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (4, DifferenceKind::Contents),
+                (69, DifferenceKind::OnlyInSecond)
+            ]
+        );
+    }
+}
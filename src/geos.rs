@@ -0,0 +1,593 @@
+//! GEOS files: VLIR records, info blocks, fonts and resources.
+//!
+//! A VLIR file is a block of track/sector links, one pair per record,
+//! with a chain behind each: `$00/$FF` marks an empty record and `$00/$00`
+//! the end of the list. [`read_records`] reads them all. The info block
+//! a GEOS file links to in its entry holds its icon, addresses, class and
+//! description, which [`InfoBlock`] takes apart.
+//!
+//! Fonts are VLIR files with a record for each point size, record `n`
+//! holding the font `n` pixels high:
+//!
+//! ```plaintext
+//! $00  baseline
+//! $01  bytes per line of the bitstream (u16)
+//! $03  height in pixels
+//! $04  offset of the index table (u16)
+//! $06  offset of the bitstream (u16)
+//!      index table: the left edge of each character from $20 on in the
+//!      bitstream, in pixels, and the end of the last one (u16 each)
+//!      bitstream: the rows of all characters side by side
+//! ```
+//!
+//! Applications keep their icons and menus as tables in their code.
+//! [`IconTable::parse`] and [`Menu::parse`] read them from a [`Memory`],
+//! a record at the address it loads to, following the pointers to the
+//! bitmaps, compacted as `BitmapUp` takes them, and to the texts.
+
+use crate::error::DosError;
+use crate::fs::{self, DirEntry, GeosFile, GeosType};
+use crate::image::{BlockDevice, ImageError, SECTOR_SIZE};
+use alloc::vec::Vec;
+
+/// The number of records a VLIR index block holds.
+pub const RECORDS: usize = 127;
+/// The first character of a font.
+pub const FIRST_CHAR: u8 = 0x20;
+
+/// Reads the records of the VLIR file of `entry`, up to the end of its
+/// index block, `None` for empty records.
+///
+/// # Errors
+/// Fails if the index block or a record cannot be read.
+pub fn read_records<I: BlockDevice + ?Sized>(
+    image: &I,
+    entry: &DirEntry,
+) -> Result<Vec<Option<Vec<u8>>>, DosError> {
+    let index = image.read_sector(entry.track, entry.sector)?;
+    let mut records = Vec::new();
+    for link in index[2..].chunks(2).take(RECORDS) {
+        match (link[0], link[1]) {
+            (0, 0) => break,
+            (0, _) => records.push(None),
+            (track, sector) => records.push(Some(fs::read_chain(image, track, sector)?)),
+        }
+    }
+    Ok(records)
+}
+
+/// A bitmap, one bit per pixel, the leftmost in the top bit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    /// The width in bytes of eight pixels.
+    pub width: u8,
+    /// The height in pixels.
+    pub height: u8,
+    /// The lines from the top down.
+    pub data: Vec<u8>,
+}
+
+impl Bitmap {
+    /// Returns `true` if the pixel at `x`/`y` is set.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let byte = self.data.get(y * usize::from(self.width) + x / 8);
+        byte.is_some_and(|byte| byte << (x % 8) & 0x80 != 0)
+    }
+
+    /// Unpacks a bitmap of `width` bytes and `height` lines compacted as
+    /// `BitmapUp` takes it, returning it and the number of bytes read.
+    ///
+    /// # Errors
+    /// - [`ImageError::Truncated`] if the data ends early.
+    /// - [`ImageError::Unsupported`] if it unpacks beyond the bitmap.
+    pub fn decompact(data: &[u8], width: u8, height: u8) -> Result<(Self, usize), ImageError> {
+        let size = usize::from(width) * usize::from(height);
+        let mut out = Vec::with_capacity(size);
+        let mut at = 0;
+        while out.len() < size {
+            at += unpack(data, at, &mut out, true)?;
+        }
+        if out.len() > size {
+            return Err(ImageError::Unsupported("GEOS bitmap size"));
+        }
+        let bitmap = Bitmap {
+            width,
+            height,
+            data: out,
+        };
+        Ok((bitmap, at))
+    }
+}
+
+/// Unpacks the compaction group at `at`, returning its length. Groups of
+/// repeated patterns, which hold groups themselves, are only taken when
+/// `big`.
+fn unpack(data: &[u8], at: usize, out: &mut Vec<u8>, big: bool) -> Result<usize, ImageError> {
+    let byte = |i: usize| data.get(at + i).copied().ok_or(ImageError::Truncated);
+    let count = byte(0)?;
+    match count {
+        0..=127 => {
+            out.extend(core::iter::repeat_n(byte(1)?, usize::from(count)));
+            Ok(2)
+        }
+        128..=219 => {
+            let length = usize::from(count - 128);
+            let bytes = data
+                .get(at + 1..at + 1 + length)
+                .ok_or(ImageError::Truncated)?;
+            out.extend_from_slice(bytes);
+            Ok(1 + length)
+        }
+        _ if big => {
+            let (length, repeat) = (usize::from(count - 220), byte(1)?);
+            let mut pattern = Vec::new();
+            let mut read = 0;
+            while read < length {
+                read += unpack(data, at + 2 + read, &mut pattern, false)?;
+            }
+            for _ in 0..repeat {
+                out.extend_from_slice(&pattern);
+            }
+            Ok(2 + length)
+        }
+        _ => Err(ImageError::Unsupported("nested GEOS bitmap pattern")),
+    }
+}
+
+/// Returns the bytes of `bytes` up to the first zero byte.
+fn string(bytes: &[u8]) -> Vec<u8> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end].to_vec()
+}
+
+/// The info block of a GEOS file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfoBlock {
+    /// The icon the desktop shows, 24 by 21 pixels.
+    pub icon: Bitmap,
+    /// The type byte of the directory entry.
+    pub dos_type: u8,
+    pub file_type: GeosType,
+    /// Whether the file is a VLIR file.
+    pub vlir: bool,
+    /// The address the file, or its first record, loads at.
+    pub load: u16,
+    /// The address behind the last byte loaded.
+    pub end: u16,
+    /// The address an application starts at.
+    pub start: u16,
+    /// The name and version of the application, or of the one that made a
+    /// data file.
+    pub class: Vec<u8>,
+    pub author: Vec<u8>,
+    /// The class of the application a data file belongs to.
+    pub parent: Vec<u8>,
+    /// The text the desktop shows in the info box.
+    pub description: Vec<u8>,
+}
+
+impl InfoBlock {
+    /// Parses an info block.
+    ///
+    /// # Errors
+    /// Returns the errors of [`Bitmap::decompact`] for a broken icon.
+    pub fn from_block(block: &[u8; SECTOR_SIZE]) -> Result<Self, ImageError> {
+        let word = |at: usize| u16::from_le_bytes([block[at], block[at + 1]]);
+        let (icon, _) = Bitmap::decompact(&block[4..0x44], block[2], block[3])?;
+        Ok(InfoBlock {
+            icon,
+            dos_type: block[0x44],
+            file_type: GeosType::from_byte(block[0x45]),
+            vlir: block[0x46] == 1,
+            load: word(0x47),
+            end: word(0x49),
+            start: word(0x4B),
+            class: string(&block[0x4D..0x61]),
+            author: string(&block[0x61..0x75]),
+            parent: string(&block[0x75..0x89]),
+            description: string(&block[0xA0..]),
+        })
+    }
+}
+
+/// Reads the info block of `file`, or `None` if it has none.
+///
+/// # Errors
+/// - [`DosError::IllegalTrackSector`] if the block cannot be taken apart.
+/// - The errors of reading the block.
+pub fn read_info<I: BlockDevice + ?Sized>(
+    image: &I,
+    file: &GeosFile,
+) -> Result<Option<InfoBlock>, DosError> {
+    if file.info_track == 0 {
+        return Ok(None);
+    }
+    let block = image.read_sector(file.info_track, file.info_sector)?;
+    InfoBlock::from_block(&block)
+        .map(Some)
+        .map_err(|_| DosError::IllegalTrackSector)
+}
+
+/// A font of one point size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Font {
+    /// The line of the baseline, from the top.
+    pub baseline: u8,
+    /// The height in pixels.
+    pub height: u8,
+    /// The bytes of a line of the bitstream.
+    pub line: u16,
+    /// The left edge of each character in the bitstream and the end of the
+    /// last one.
+    pub edges: Vec<u16>,
+    /// The lines of the bitstream, from the top down.
+    pub bits: Vec<u8>,
+}
+
+impl Font {
+    /// Parses the record of a font.
+    ///
+    /// # Errors
+    /// - [`ImageError::Truncated`] if the index table or the bitstream
+    ///   extends beyond the record.
+    /// - [`ImageError::Unsupported`] if the offsets of the header do not
+    ///   leave room for the index table.
+    pub fn from_record(record: &[u8]) -> Result<Self, ImageError> {
+        let header = record.get(..8).ok_or(ImageError::Truncated)?;
+        let word = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let line = word(header, 1);
+        let height = header[3];
+        let (index, data) = (usize::from(word(header, 4)), usize::from(word(header, 6)));
+        if index < 8 || data < index + 4 {
+            return Err(ImageError::Unsupported("GEOS font header"));
+        }
+        let table = record.get(index..data).ok_or(ImageError::Truncated)?;
+        let size = usize::from(line) * usize::from(height);
+        let bits = record.get(data..data + size).ok_or(ImageError::Truncated)?;
+        Ok(Font {
+            baseline: header[0],
+            height,
+            line,
+            edges: table.chunks_exact(2).map(|pair| word(pair, 0)).collect(),
+            bits: bits.to_vec(),
+        })
+    }
+
+    /// Returns the width of `c` in pixels, if the font has it.
+    pub fn width(&self, c: u8) -> Option<u16> {
+        let i = usize::from(c.checked_sub(FIRST_CHAR)?);
+        let (left, right) = (*self.edges.get(i)?, *self.edges.get(i + 1)?);
+        right.checked_sub(left)
+    }
+
+    /// Returns `true` if the pixel at `x`/`y` of `c` is set.
+    pub fn pixel(&self, c: u8, x: u16, y: u8) -> bool {
+        if self.width(c).is_none_or(|width| x >= width) || y >= self.height {
+            return false;
+        }
+        let bit = usize::from(self.edges[usize::from(c - FIRST_CHAR)] + x);
+        let byte = self
+            .bits
+            .get(usize::from(y) * usize::from(self.line) + bit / 8);
+        byte.is_some_and(|byte| byte << (bit % 8) & 0x80 != 0)
+    }
+
+    /// Returns the bitmap of `c`, if the font has it.
+    pub fn glyph(&self, c: u8) -> Option<Bitmap> {
+        let width = self.width(c)?;
+        let bytes = width.div_ceil(8);
+        let mut data = alloc::vec![0; usize::from(bytes) * usize::from(self.height)];
+        for y in 0..self.height {
+            for x in (0..width).filter(|&x| self.pixel(c, x, y)) {
+                data[usize::from(y) * usize::from(bytes) + usize::from(x / 8)] |= 0x80 >> (x % 8);
+            }
+        }
+        Some(Bitmap {
+            width: bytes as u8,
+            height: self.height,
+            data,
+        })
+    }
+}
+
+/// Reads the fonts of the font file of `entry`, each with its point size,
+/// leaving out records that hold no font.
+///
+/// # Errors
+/// The errors of [`read_records`].
+pub fn read_fonts<I: BlockDevice + ?Sized>(
+    image: &I,
+    entry: &DirEntry,
+) -> Result<Vec<(u8, Font)>, DosError> {
+    Ok(read_records(image, entry)?
+        .iter()
+        .enumerate()
+        .filter_map(|(size, record)| Some((size as u8, Font::from_record(record.as_ref()?).ok()?)))
+        .collect())
+}
+
+/// A record or file as it lies in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memory<'a> {
+    pub bytes: &'a [u8],
+    /// The address of the first byte.
+    pub load: u16,
+}
+
+impl<'a> Memory<'a> {
+    /// Returns the bytes from `address` on.
+    fn at(&self, address: u16) -> Result<&'a [u8], ImageError> {
+        let offset = address
+            .checked_sub(self.load)
+            .ok_or(ImageError::Truncated)?;
+        self.bytes
+            .get(usize::from(offset)..)
+            .ok_or(ImageError::Truncated)
+    }
+
+    fn byte(&self, address: u16) -> Result<u8, ImageError> {
+        self.at(address)?
+            .first()
+            .copied()
+            .ok_or(ImageError::Truncated)
+    }
+
+    fn word(&self, address: u16) -> Result<u16, ImageError> {
+        Ok(u16::from_le_bytes([
+            self.byte(address)?,
+            self.byte(address.wrapping_add(1))?,
+        ]))
+    }
+}
+
+/// An icon of an icon table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Icon {
+    /// The picture, `None` for an icon switched off.
+    pub bitmap: Option<Bitmap>,
+    /// The left edge in bytes of eight pixels.
+    pub x: u8,
+    /// The top edge in pixels.
+    pub y: u8,
+    /// The address of the routine a click calls.
+    pub service: u16,
+}
+
+/// The table of icons `DoIcons` puts on the screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconTable {
+    /// Where the mouse is put, in pixels.
+    pub mouse: (u16, u8),
+    pub icons: Vec<Icon>,
+}
+
+impl IconTable {
+    /// Parses the icon table at `address` of `memory`.
+    ///
+    /// # Errors
+    /// - [`ImageError::Truncated`] if the table or a bitmap lies beyond
+    ///   `memory`.
+    /// - The errors of [`Bitmap::decompact`].
+    pub fn parse(memory: &Memory, address: u16) -> Result<Self, ImageError> {
+        let count = memory.byte(address)?;
+        let mouse = (
+            memory.word(address.wrapping_add(1))?,
+            memory.byte(address.wrapping_add(3))?,
+        );
+        let mut icons = Vec::with_capacity(usize::from(count));
+        for i in 0..u16::from(count) {
+            let icon = address.wrapping_add(4 + i * 8);
+            let pointer = memory.word(icon)?;
+            let width = memory.byte(icon.wrapping_add(4))?;
+            let height = memory.byte(icon.wrapping_add(5))?;
+            let bitmap = match pointer {
+                0 => None,
+                _ => Some(Bitmap::decompact(memory.at(pointer)?, width, height)?.0),
+            };
+            icons.push(Icon {
+                bitmap,
+                x: memory.byte(icon.wrapping_add(2))?,
+                y: memory.byte(icon.wrapping_add(3))?,
+                service: memory.word(icon.wrapping_add(6))?,
+            });
+        }
+        Ok(IconTable { mouse, icons })
+    }
+}
+
+/// What choosing a menu item does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MenuAction {
+    /// Calls the routine at the address.
+    Action(u16),
+    /// Opens the menu at the address, which [`Menu::parse`] reads.
+    SubMenu(u16),
+    /// Calls the routine at the address, which returns the menu to open.
+    Dynamic(u16),
+}
+
+/// An item of a menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuItem {
+    /// The text, with the style codes GEOS puts in it.
+    pub text: Vec<u8>,
+    pub action: MenuAction,
+}
+
+/// A menu, as `DoMenu` takes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Menu {
+    pub top: u8,
+    pub bottom: u8,
+    pub left: u16,
+    pub right: u16,
+    /// Whether the items are below each other rather than side by side.
+    pub vertical: bool,
+    /// Whether the mouse is kept within the menu.
+    pub constrained: bool,
+    pub items: Vec<MenuItem>,
+}
+
+impl Menu {
+    /// Parses the menu at `address` of `memory`, without its submenus.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::Truncated`] if the menu or a text lies beyond
+    /// `memory`.
+    pub fn parse(memory: &Memory, address: u16) -> Result<Self, ImageError> {
+        let kind = memory.byte(address.wrapping_add(6))?;
+        let mut items = Vec::new();
+        for i in 0..u16::from(kind & 0x1F) {
+            let item = address.wrapping_add(7 + i * 5);
+            let text = string(memory.at(memory.word(item)?)?);
+            let target = memory.word(item.wrapping_add(3))?;
+            let action = match memory.byte(item.wrapping_add(2))? {
+                0x80 => MenuAction::SubMenu(target),
+                0x40 => MenuAction::Dynamic(target),
+                _ => MenuAction::Action(target),
+            };
+            items.push(MenuItem { text, action });
+        }
+        Ok(Menu {
+            top: memory.byte(address)?,
+            bottom: memory.byte(address.wrapping_add(1))?,
+            left: memory.word(address.wrapping_add(2))?,
+            right: memory.word(address.wrapping_add(4))?,
+            vertical: kind & 0x80 != 0,
+            constrained: kind & 0x40 != 0,
+            items,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::{Bam, FileType};
+
+    /// A font of two characters, `!` two pixels wide and `"` three, in a
+    /// bitstream of one byte per line.
+    fn tiny_font() -> Vec<u8> {
+        let mut record = vec![4, 1, 0, 5, 8, 0, 16, 0];
+        // The edges of $20, which is empty, $21 and $22, and the end.
+        record.extend([0, 0, 0, 0, 2, 0, 5, 0]);
+        record.extend([0b1111_1000, 0b0011_1000, 0b1100_0000, 0b0011_1000, 0]);
+        record
+    }
+
+    #[test]
+    fn reads_fonts_and_bitmaps() {
+        let font = Font::from_record(&tiny_font()).unwrap();
+        assert_eq!((font.baseline, font.height), (4, 5));
+        assert_eq!(font.width(b' '), Some(0));
+        assert_eq!(font.width(b'!'), Some(2));
+        assert_eq!(font.width(b'"'), Some(3));
+        assert_eq!(font.width(b'#'), None);
+        assert!(font.pixel(b'!', 0, 0) && font.pixel(b'!', 1, 0));
+        assert!(!font.pixel(b'!', 2, 0) && font.pixel(b'"', 0, 0));
+        let glyph = font.glyph(b'"').unwrap();
+        assert_eq!(glyph.data, [0b1110_0000, 0b1110_0000, 0, 0b1110_0000, 0]);
+        assert_eq!(
+            Font::from_record(&tiny_font()[..20]),
+            Err(ImageError::Truncated)
+        );
+
+        // Three of $FF, two bytes as they are, and twice the pattern of one
+        // $00 and one $AA.
+        let packed = [3, 0xFF, 130, 1, 2, 224, 2, 1, 0, 1, 0xAA];
+        let (bitmap, read) = Bitmap::decompact(&packed, 3, 3).unwrap();
+        assert_eq!(read, packed.len());
+        assert_eq!(bitmap.data, [0xFF, 0xFF, 0xFF, 1, 2, 0, 0xAA, 0, 0xAA]);
+        assert!(bitmap.pixel(7, 1) && !bitmap.pixel(6, 1) && bitmap.pixel(16, 2));
+        assert_eq!(
+            Bitmap::decompact(&packed, 3, 2),
+            Err(ImageError::Unsupported("GEOS bitmap size"))
+        );
+        assert_eq!(
+            Bitmap::decompact(&packed[..4], 3, 3),
+            Err(ImageError::Truncated)
+        );
+    }
+
+    #[test]
+    fn reads_records_info_and_resources() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"GEOS", Some(*b"01")).unwrap();
+        let mut entry = fs::write_file(&mut image, b"FONT", FileType::Usr, &[0]).unwrap();
+        let mut bam = Bam::read(&image).unwrap();
+        let (first, _) = fs::write_chain(&mut image, &mut bam, &tiny_font()).unwrap();
+        let (second, _) = fs::write_chain(&mut image, &mut bam, &[1; 300]).unwrap();
+        let mut index = [0; SECTOR_SIZE];
+        index[1] = 0xFF;
+        index[2..8].copy_from_slice(&[0, 0xFF, first.0, first.1, second.0, second.1]);
+        image
+            .write_sector(entry.track, entry.sector, &index)
+            .unwrap();
+
+        let mut info = [0; SECTOR_SIZE];
+        info[1] = 0xFF;
+        info[2..5].copy_from_slice(&[3, 21, 0xBF]);
+        info[5..0x44].fill(0x55);
+        info[0x44..0x47].copy_from_slice(&[0x83, 8, 1]);
+        info[0x4D..0x5D].copy_from_slice(b"Tiny        V1.0");
+        info[0x61..0x65].copy_from_slice(b"ANON");
+        info[0xA0..0xA5].copy_from_slice(b"Small");
+        image.write_sector(19, 0, &info).unwrap();
+        bam.allocate(19, 0);
+        bam.write(&mut image).unwrap();
+        (entry.side_track, entry.side_sector, entry.record_length) = (19, 0, 1);
+        entry.geos = [8, 88, 1, 1, 0, 0];
+        fs::write_entry(&mut image, &entry).unwrap();
+
+        let records = read_records(&image, &entry).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], None);
+        assert_eq!(records[2].as_deref(), Some(&[1; 300][..]));
+        let fonts = read_fonts(&image, &entry).unwrap();
+        assert_eq!(fonts.len(), 1);
+        assert_eq!((fonts[0].0, fonts[0].1.height), (1, 5));
+
+        let file = GeosFile {
+            file_type: GeosType::Font,
+            vlir: true,
+            info_track: 19,
+            info_sector: 0,
+            date: [88, 1, 1, 0, 0],
+        };
+        let info = read_info(&image, &file).unwrap().unwrap();
+        assert_eq!((info.file_type, info.vlir), (GeosType::Font, true));
+        assert_eq!(info.class, b"Tiny        V1.0");
+        assert_eq!(
+            (&info.author[..], &info.description[..]),
+            (&b"ANON"[..], &b"Small"[..])
+        );
+        assert!(info.icon.pixel(1, 20) && !info.icon.pixel(0, 20));
+
+        // An icon table at $0400 with one icon, its bitmap at $0410, and a
+        // menu of two items at $0420 with their texts at $0440.
+        let mut code = vec![0; 0x50];
+        code[..12].copy_from_slice(&[1, 0x10, 0, 0x20, 0x10, 0x04, 2, 8, 1, 2, 0x00, 0x50]);
+        code[0x10..0x12].copy_from_slice(&[2, 0xF0]);
+        code[0x20..0x27].copy_from_slice(&[0, 14, 0, 0, 60, 0, 0x42]);
+        code[0x27..0x31].copy_from_slice(&[0x40, 4, 0, 0x00, 0x60, 0x45, 4, 0x80, 0x00, 0x70]);
+        code[0x40..0x49].copy_from_slice(b"geos\0file");
+        let memory = Memory {
+            bytes: &code,
+            load: 0x0400,
+        };
+        let icons = IconTable::parse(&memory, 0x0400).unwrap();
+        assert_eq!(icons.mouse, (0x10, 0x20));
+        let icon = &icons.icons[0];
+        assert_eq!((icon.x, icon.y, icon.service), (2, 8, 0x5000));
+        assert_eq!(icon.bitmap.as_ref().unwrap().data, [0xF0, 0xF0]);
+        let menu = Menu::parse(&memory, 0x0420).unwrap();
+        assert!(menu.constrained && !menu.vertical);
+        assert_eq!((menu.bottom, menu.right), (14, 60));
+        assert_eq!(menu.items[0].text, b"geos");
+        assert_eq!(menu.items[0].action, MenuAction::Action(0x6000));
+        assert_eq!(menu.items[1].text, b"file");
+        assert_eq!(menu.items[1].action, MenuAction::SubMenu(0x7000));
+        assert_eq!(Menu::parse(&memory, 0x0300), Err(ImageError::Truncated));
+    }
+}
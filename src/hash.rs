@@ -0,0 +1,645 @@
+//! Checksums of images and files.
+//!
+//! Collections and the DAT files describing them identify images by their
+//! checksums. [`crc32`] is the one ZIP archives store and the one DAT
+//! files list first; [`Hashes`] holds it together with the MD5 and SHA-1
+//! DAT files list next to it, and the SHA-256 archives keep today.
+//!
+//! The same disk comes in many files: as a D64 with or without an error
+//! block, as a G64 written by one tool or the other, with gaps and syncs of
+//! every length and tracks starting anywhere. [`Hashes::of`] tells files
+//! apart, [`sectors`] hashes what a drive reads off the disk, the 256
+//! bytes of every sector in track order, which is the same for all of them.
+//! [`files`] hashes each file on a disk, to find a program again on
+//! whatever disk it was copied to, and [`tracks`] each track, to tell
+//! which part of a disk two dumps disagree on. [`provenance`] does all
+//! three while reading every sector once.
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::fs::{self, DirEntry};
+use crate::image::BlockDevice;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A CRC-32 computed piece by piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    /// Starts a checksum.
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    /// Adds `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ u32::from(byte)) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Returns the checksum of the data added so far.
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Returns the CRC-32 of `data`, as ZIP, PNG and DAT files use it.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.value()
+}
+
+/// Feeds `data` through the 64-byte blocks of an MD5, SHA-1 or SHA-256.
+fn feed(
+    buffer: &mut [u8; 64],
+    length: &mut u64,
+    mut data: &[u8],
+    mut compress: impl FnMut(&[u8; 64]),
+) {
+    let mut filled = (*length % 64) as usize;
+    *length += data.len() as u64;
+    while !data.is_empty() {
+        let take = (64 - filled).min(data.len());
+        buffer[filled..filled + take].copy_from_slice(&data[..take]);
+        filled += take;
+        data = &data[take..];
+        if filled == 64 {
+            compress(buffer);
+            filled = 0;
+        }
+    }
+}
+
+/// The padding of an MD5, SHA-1 or SHA-256 over `length` bytes, without the length
+/// field: `0x80`, then zeros up to 8 bytes before the end of a block.
+fn padding(length: u64) -> Vec<u8> {
+    let mut padding = alloc::vec![0x80];
+    padding.resize(1 + ((119 - length % 64) % 64) as usize, 0);
+    padding
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+const MD5_CONSTANTS: [u32; 64] = [
+    0xD76AA478, 0xE8C7B756, 0x242070DB, 0xC1BDCEEE, 0xF57C0FAF, 0x4787C62A, 0xA8304613, 0xFD469501,
+    0x698098D8, 0x8B44F7AF, 0xFFFF5BB1, 0x895CD7BE, 0x6B901122, 0xFD987193, 0xA679438E, 0x49B40821,
+    0xF61E2562, 0xC040B340, 0x265E5A51, 0xE9B6C7AA, 0xD62F105D, 0x02441453, 0xD8A1E681, 0xE7D3FBC8,
+    0x21E1CDE6, 0xC33707D6, 0xF4D50D87, 0x455A14ED, 0xA9E3E905, 0xFCEFA3F8, 0x676F02D9, 0x8D2A4C8A,
+    0xFFFA3942, 0x8771F681, 0x6D9D6122, 0xFDE5380C, 0xA4BEEA44, 0x4BDECFA9, 0xF6BB4B60, 0xBEBFBC70,
+    0x289B7EC6, 0xEAA127FA, 0xD4EF3085, 0x04881D05, 0xD9D4D039, 0xE6DB99E5, 0x1FA27CF8, 0xC4AC5665,
+    0xF4292244, 0x432AFF97, 0xAB9423A7, 0xFC93A039, 0x655B59C3, 0x8F0CCC92, 0xFFEFF47D, 0x85845DD1,
+    0x6FA87E4F, 0xFE2CE6E0, 0xA3014314, 0x4E0811A1, 0xF7537E82, 0xBD3AF235, 0x2AD7D2BB, 0xEB86D391,
+];
+
+/// An MD5 computed piece by piece.
+#[derive(Debug, Clone)]
+pub struct Md5 {
+    state: [u32; 4],
+    buffer: [u8; 64],
+    length: u64,
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Md5 {
+    /// Starts a digest.
+    pub fn new() -> Self {
+        Md5 {
+            state: [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476],
+            buffer: [0; 64],
+            length: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 4], block: &[u8; 64]) {
+        let words: [u32; 16] = core::array::from_fn(|i| {
+            u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+        });
+        let [mut a, mut b, mut c, mut d] = *state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    /// Adds `data` to the digest.
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        feed(&mut self.buffer, &mut self.length, data, |block| {
+            Md5::compress(state, block)
+        });
+    }
+
+    /// Returns the digest of the data added.
+    pub fn finish(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&padding(self.length));
+        self.update(&bits.to_le_bytes());
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+/// A SHA-1 computed piece by piece.
+#[derive(Debug, Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    buffer: [u8; 64],
+    length: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha1 {
+    /// Starts a digest.
+    pub fn new() -> Self {
+        Sha1 {
+            state: [
+                0x6745_2301,
+                0xEFCD_AB89,
+                0x98BA_DCFE,
+                0x1032_5476,
+                0xC3D2_E1F0,
+            ],
+            buffer: [0; 64],
+            length: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 5], block: &[u8; 64]) {
+        let mut words = [0u32; 80];
+        for i in 0..16 {
+            words[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = *state;
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5A82_7999),
+                1 => (b ^ c ^ d, 0x6ED9_EBA1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    /// Adds `data` to the digest.
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        feed(&mut self.buffer, &mut self.length, data, |block| {
+            Sha1::compress(state, block)
+        });
+    }
+
+    /// Returns the digest of the data added.
+    pub fn finish(mut self) -> [u8; 20] {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&padding(self.length));
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0; 20];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+const SHA256_CONSTANTS: [u32; 64] = [
+    0x428A2F98, 0x71374491, 0xB5C0FBCF, 0xE9B5DBA5, 0x3956C25B, 0x59F111F1, 0x923F82A4, 0xAB1C5ED5,
+    0xD807AA98, 0x12835B01, 0x243185BE, 0x550C7DC3, 0x72BE5D74, 0x80DEB1FE, 0x9BDC06A7, 0xC19BF174,
+    0xE49B69C1, 0xEFBE4786, 0x0FC19DC6, 0x240CA1CC, 0x2DE92C6F, 0x4A7484AA, 0x5CB0A9DC, 0x76F988DA,
+    0x983E5152, 0xA831C66D, 0xB00327C8, 0xBF597FC7, 0xC6E00BF3, 0xD5A79147, 0x06CA6351, 0x14292967,
+    0x27B70A85, 0x2E1B2138, 0x4D2C6DFC, 0x53380D13, 0x650A7354, 0x766A0ABB, 0x81C2C92E, 0x92722C85,
+    0xA2BFE8A1, 0xA81A664B, 0xC24B8B70, 0xC76C51A3, 0xD192E819, 0xD6990624, 0xF40E3585, 0x106AA070,
+    0x19A4C116, 0x1E376C08, 0x2748774C, 0x34B0BCB5, 0x391C0CB3, 0x4ED8AA4A, 0x5B9CCA4F, 0x682E6FF3,
+    0x748F82EE, 0x78A5636F, 0x84C87814, 0x8CC70208, 0x90BEFFFA, 0xA4506CEB, 0xBEF9A3F7, 0xC67178F2,
+];
+
+/// A SHA-256 computed piece by piece.
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; 64],
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    /// Starts a digest.
+    pub fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6A09_E667,
+                0xBB67_AE85,
+                0x3C6E_F372,
+                0xA54F_F53A,
+                0x510E_527F,
+                0x9B05_688C,
+                0x1F83_D9AB,
+                0x5BE0_CD19,
+            ],
+            buffer: [0; 64],
+            length: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+        let mut words = [0u32; 64];
+        for i in 0..16 {
+            words[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 =
+                words[i - 15].rotate_right(7) ^ words[i - 15].rotate_right(18) ^ words[i - 15] >> 3;
+            let s1 =
+                words[i - 2].rotate_right(17) ^ words[i - 2].rotate_right(19) ^ words[i - 2] >> 10;
+            words[i] = words[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(words[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (&word, &k) in words.iter().zip(&SHA256_CONSTANTS) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    /// Adds `data` to the digest.
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        feed(&mut self.buffer, &mut self.length, data, |block| {
+            Sha256::compress(state, block)
+        });
+    }
+
+    /// Returns the digest of the data added.
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&padding(self.length));
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// The size and checksums DAT files identify a file by.
+///
+/// Hashes order and compare by all of their fields, so they serve as the
+/// key of a map to find duplicates in a collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hashes {
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+    pub sha256: [u8; 32],
+}
+
+/// Computes [`Hashes`] piece by piece.
+#[derive(Debug, Clone, Default)]
+pub struct Hasher {
+    size: u64,
+    crc32: Crc32,
+    md5: Md5,
+    sha1: Sha1,
+    sha256: Sha256,
+}
+
+impl Hasher {
+    /// Starts the hashes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `data` to the hashes.
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.crc32.update(data);
+        self.md5.update(data);
+        self.sha1.update(data);
+        self.sha256.update(data);
+    }
+
+    /// Returns the hashes of the data added.
+    pub fn finish(self) -> Hashes {
+        Hashes {
+            size: self.size,
+            crc32: self.crc32.value(),
+            md5: self.md5.finish(),
+            sha1: self.sha1.finish(),
+            sha256: self.sha256.finish(),
+        }
+    }
+}
+
+impl Hashes {
+    /// Returns the hashes of `data`, a whole image file for instance.
+    pub fn of(data: &[u8]) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+/// Written as DAT files list them: the size, then the checksums in
+/// lower-case hex, SHA-256 last.
+impl fmt::Display for Hashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "size {} crc {:08x} md5 ", self.size, self.crc32)?;
+        self.md5.iter().try_for_each(|b| write!(f, "{b:02x}"))?;
+        write!(f, " sha1 ")?;
+        self.sha1.iter().try_for_each(|b| write!(f, "{b:02x}"))?;
+        write!(f, " sha256 ")?;
+        self.sha256.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// Returns the hashes of the contents of `image`: the 256 bytes of every
+/// sector, track by track, as a D64 holds them.
+///
+/// Sector errors are left out, so this is the same for a D64 with an
+/// error block and one without, and for a G64 of the disk, whatever its
+/// gaps, syncs and track alignment. Sectors that cannot be read count as
+/// zeros, since a D64 made of the disk holds them so.
+pub fn sectors<I: BlockDevice + ?Sized>(image: &I) -> Hashes {
+    let mut hasher = Hasher::new();
+    for track in 1..=image.tracks() {
+        for sector in 0..image.sectors_per_track(track) {
+            let data = image.read_sector(track, sector).unwrap_or([0; 256]);
+            hasher.update(&data);
+        }
+    }
+    hasher.finish()
+}
+
+/// Returns the hashes of the contents of every file on `image`, with its
+/// directory entry, in directory order. The contents are what `LOAD`
+/// reads, so the hashes of a `PRG` cover its load address; `REL` files
+/// are hashed by the records of their data blocks.
+///
+/// # Errors
+/// Returns the [`DosError`] if the directory cannot be read. Files whose
+/// chain is broken are left out.
+pub fn files<I: BlockDevice + ?Sized>(image: &I) -> Result<Vec<(DirEntry, Hashes)>, DosError> {
+    let directory = fs::read_directory(image)?;
+    Ok(directory
+        .entries
+        .into_iter()
+        .filter_map(|entry| {
+            let contents = fs::read_file(image, &entry).ok()?;
+            Some((entry, Hashes::of(&contents)))
+        })
+        .collect())
+}
+
+/// Returns the hashes of every track of `image`, the 256 bytes of its
+/// sectors in order, counted from track 1 and taken as [`sectors`] takes
+/// them.
+pub fn tracks<I: BlockDevice + ?Sized>(image: &I) -> Vec<Hashes> {
+    provenance(image).tracks
+}
+
+/// The hashes of a disk, its tracks and its files, see [`provenance`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// The hashes of [`sectors`].
+    pub sectors: Hashes,
+    /// The hashes of every track, counted from track 1.
+    pub tracks: Vec<Hashes>,
+    /// The hashes of [`files`], none if the directory cannot be read.
+    pub files: Vec<(DirEntry, Hashes)>,
+}
+
+/// Returns the hashes of the contents of `image`, of each of its tracks
+/// and of each of its files, reading every sector once. For a G64 that
+/// is decoding every track once, where [`sectors`] and [`files`] each
+/// decode what they read.
+pub fn provenance<I: BlockDevice + ?Sized>(image: &I) -> Provenance {
+    let tracks = image.tracks().min(40);
+    let mut copy = D64::new(if tracks > 35 { 40 } else { 35 });
+    let mut disk = Hasher::new();
+    let mut hashes = Vec::with_capacity(usize::from(tracks));
+    for track in 1..=tracks {
+        let mut hasher = Hasher::new();
+        for sector in 0..image.sectors_per_track(track) {
+            let data = image.read_sector(track, sector).unwrap_or([0; 256]);
+            disk.update(&data);
+            hasher.update(&data);
+            // Both have the layout of DOS, so every sector has its place.
+            let _ = copy.write_sector(track, sector, &data);
+        }
+        hashes.push(hasher.finish());
+    }
+    Provenance {
+        sectors: disk.finish(),
+        tracks: hashes,
+        files: files(&copy).unwrap_or_default(),
+    }
+}
+
+/// Returns the keys of `items` that share their hashes with another, in
+/// groups of equal hashes ordered by them, each in the order given.
+///
+/// With the hashes of [`sectors`] this finds the disks of a collection
+/// that are kept in more than one file.
+pub fn duplicates<K>(items: impl IntoIterator<Item = (K, Hashes)>) -> Vec<Vec<K>> {
+    let mut groups: BTreeMap<Hashes, Vec<K>> = BTreeMap::new();
+    for (key, hashes) in items {
+        groups.entry(hashes).or_default().push(key);
+    }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        let mut crc = Crc32::new();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.value(), crc32(b"123456789"));
+
+        let hex = |digest: &[u8]| {
+            digest
+                .iter()
+                .map(|b| alloc::format!("{b:02x}"))
+                .collect::<alloc::string::String>()
+        };
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            hex(&Hashes::of(b"").md5),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(
+            hex(&Hashes::of(b"abc").sha1),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&Hashes::of(b"abc").sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let hashes = Hashes::of(two_blocks);
+        assert_eq!(
+            hex(&hashes.sha256),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(&hashes.sha1),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(hex(&hashes.md5), "8215ef0796a20bcaaae116d3876c664a");
+        let mut hasher = Hasher::new();
+        for chunk in two_blocks.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finish(), hashes);
+    }
+
+    #[test]
+    fn hashes_disks_by_contents() {
+        use crate::d64::D64;
+        use crate::fs::FileType;
+        use crate::g64::G64;
+
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"HASHED", Some(*b"HH")).unwrap();
+        fs::write_file(&mut image, b"GAME", FileType::Prg, &[1, 8, 0x60]).unwrap();
+        let plain = image.clone();
+        image
+            .set_sector_error(30, 1, Some(DosError::DataChecksum))
+            .unwrap();
+        assert_ne!(Hashes::of(&image.to_bytes()), Hashes::of(&plain.to_bytes()));
+        assert_eq!(sectors(&image), sectors(&plain));
+        assert_eq!(sectors(&G64::from_image(&plain)), sectors(&plain));
+        assert_eq!(sectors(&plain), Hashes::of(&plain.to_bytes()));
+        let collection = [
+            ("with errors.d64", sectors(&image)),
+            ("empty", Hashes::of(b"")),
+            ("plain.d64", sectors(&plain)),
+        ];
+        assert_eq!(duplicates(collection), [["with errors.d64", "plain.d64"]]);
+
+        let files = files(&plain).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            (files[0].0.name(), files[0].1),
+            (&b"GAME"[..], Hashes::of(&[1, 8, 0x60]))
+        );
+        assert!(files[0].1.to_string().starts_with("size 3 crc "));
+
+        let g64 = G64::from_image(&image);
+        let provenance = provenance(&g64);
+        assert_eq!(provenance.sectors, sectors(&plain));
+        assert_eq!(provenance.files, files);
+        assert_eq!(provenance.tracks.len(), 35);
+        let thirty: Vec<u8> = (0..plain.sectors_per_track(30))
+            .flat_map(|sector| plain.read_sector(30, sector).unwrap())
+            .collect();
+        assert_eq!(provenance.tracks[29], Hashes::of(&thirty));
+        assert_eq!(tracks(&plain), provenance.tracks);
+    }
+}
@@ -0,0 +1,394 @@
+//! Images on web servers.
+//!
+//! A web frontend browsing an archive should not download a whole disk to
+//! list its directory. [`HttpImage`] is a read-only [`DiskImage`] of a D64
+//! on an HTTP server: it asks for the bytes of each sector it reads with a
+//! range request and keeps the sectors read last in a cache, so that the
+//! directory of a disk takes a handful of small requests.
+//!
+//! Only plain `http://` URLs are served; the crate has no TLS, so HTTPS
+//! archives are reached through a local proxy.
+
+use crate::d64::{self, SECTORS_35, SECTORS_40};
+use crate::error::DosError;
+use crate::image::{BlockDevice, DiskImage, SECTOR_SIZE, Sector};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// The number of sectors an [`HttpImage`] caches unless told otherwise,
+/// a third of a 35-track disk.
+pub const DEFAULT_CACHE: usize = 256;
+
+/// The sectors read last, the most recent at the back.
+#[derive(Debug)]
+struct Cache {
+    capacity: usize,
+    sectors: VecDeque<((u8, u8), Sector)>,
+}
+
+impl Cache {
+    fn get(&mut self, at: (u8, u8)) -> Option<Sector> {
+        let index = self.sectors.iter().position(|(a, _)| *a == at)?;
+        let entry = self.sectors.remove(index)?;
+        self.sectors.push_back(entry);
+        Some(entry.1)
+    }
+
+    fn insert(&mut self, at: (u8, u8), data: Sector) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.sectors.len() == self.capacity {
+            self.sectors.pop_front();
+        }
+        self.sectors.push_back((at, data));
+    }
+}
+
+/// The parts of an `http://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> io::Result<Url> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let rest = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not an http URL",
+            ));
+        }
+        None => return Err(invalid("not a URL")),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid("invalid port"))?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("no host"));
+    }
+    Ok(Url {
+        host: String::from(host),
+        port,
+        path: String::from(path),
+    })
+}
+
+/// An HTTP response, with the names of its headers in lower case.
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn parse_response(response: &[u8]) -> io::Result<Response> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| malformed())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(malformed)?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), String::from(value.trim())))
+        .collect();
+    let body = &response[end + 4..];
+    let mut response = Response {
+        status,
+        headers,
+        body: body.to_vec(),
+    };
+    let chunked = response.header("transfer-encoding");
+    if chunked.is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        response.body = dechunk(body).ok_or_else(malformed)?;
+    }
+    Ok(response)
+}
+
+/// Joins the chunks of a body sent with `Transfer-Encoding: chunked`.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// A D64 on an HTTP server, read sector by sector with range requests.
+///
+/// The image cannot be written; writes fail with
+/// [`DosError::WriteProtect`]. Sectors it cannot fetch read as
+/// [`DosError::DriveNotReady`].
+#[derive(Debug)]
+pub struct HttpImage {
+    url: Url,
+    tracks: u8,
+    errors: Option<Vec<u8>>,
+    cache: RefCell<Cache>,
+}
+
+impl HttpImage {
+    /// Opens the D64 at `url`, with a cache of [`DEFAULT_CACHE`] sectors.
+    ///
+    /// The size of the file, asked for with a first range request, tells
+    /// its layout as for [`crate::d64::D64::from_bytes`]; the error block,
+    /// if there is one, is fetched with it.
+    ///
+    /// # Errors
+    /// - [`io::ErrorKind::Unsupported`] for URLs other than `http://`, and
+    ///   for servers that do not answer range requests.
+    /// - [`io::ErrorKind::InvalidData`] if the file is not the size of a
+    ///   D64 or the server does not answer as one.
+    /// - The error of the connection.
+    pub fn open(url: &str) -> io::Result<Self> {
+        Self::with_cache(url, DEFAULT_CACHE)
+    }
+
+    /// Opens the D64 at `url`, caching up to `capacity` sectors.
+    ///
+    /// # Errors
+    /// Fails as [`HttpImage::open`] does.
+    pub fn with_cache(url: &str, capacity: usize) -> io::Result<Self> {
+        let mut image = HttpImage {
+            url: parse_url(url)?,
+            tracks: 0,
+            errors: None,
+            cache: RefCell::new(Cache {
+                capacity,
+                sectors: VecDeque::new(),
+            }),
+        };
+        let (_, size) = image.fetch(0, 1)?;
+        let size = usize::try_from(size).unwrap_or(usize::MAX);
+        let (tracks, sectors) = [(35, SECTORS_35), (40, SECTORS_40)]
+            .into_iter()
+            .find(|&(_, n)| size == n * SECTOR_SIZE || size == n * (SECTOR_SIZE + 1))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a D64"))?;
+        image.tracks = tracks;
+        if size > sectors * SECTOR_SIZE {
+            let (errors, _) = image.fetch((sectors * SECTOR_SIZE) as u64, sectors)?;
+            image.errors = Some(errors);
+        }
+        Ok(image)
+    }
+
+    /// Fetches `length` bytes at `offset`, returning them with the size of
+    /// the whole file.
+    fn fetch(&self, offset: u64, length: usize) -> io::Result<(Vec<u8>, u64)> {
+        let Url { host, port, path } = &self.url;
+        let mut stream = TcpStream::connect((host.as_str(), *port))?;
+        let last = offset + length as u64 - 1;
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nRange: bytes={offset}-{last}\r\n\
+             User-Agent: cbm-dos\r\nConnection: close\r\n\r\n"
+        )?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = parse_response(&response)?;
+        match response.status {
+            206 => {}
+            200 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the server does not answer range requests",
+                ));
+            }
+            status => return Err(io::Error::other(format!("HTTP status {status}"))),
+        }
+        let size = response
+            .header("content-range")
+            .and_then(|value| value.rsplit_once('/')?.1.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no content range"))?;
+        if response.body.len() != length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short range"));
+        }
+        Ok((response.body, size))
+    }
+}
+
+impl BlockDevice for HttpImage {
+    fn tracks(&self) -> u8 {
+        self.tracks
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        if (1..=self.tracks).contains(&track) {
+            d64::sectors_per_track(track)
+        } else {
+            0
+        }
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        if let Some(data) = self.cache.borrow_mut().get((track, sector)) {
+            return Ok(data);
+        }
+        let offset = d64::sector_offset(track, sector) as u64;
+        let (body, _) = self
+            .fetch(offset, SECTOR_SIZE)
+            .map_err(|_| DosError::DriveNotReady)?;
+        let data: Sector = body.try_into().map_err(|_| DosError::DriveNotReady)?;
+        self.cache.borrow_mut().insert((track, sector), data);
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, _track: u8, _sector: u8, _data: &Sector) -> Result<(), DosError> {
+        Err(DosError::WriteProtect)
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        if !self.contains(track, sector) {
+            return None;
+        }
+        let index = d64::sector_offset(track, sector) / SECTOR_SIZE;
+        d64::error_from_byte(self.errors.as_ref()?[index])
+    }
+}
+
+impl DiskImage for HttpImage {
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::{self, FileType};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Serves `file` over HTTP, answering range requests in chunks,
+    /// counting the requests in `count`.
+    fn serve(file: Vec<u8>, count: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                count.fetch_add(1, Ordering::SeqCst);
+                let request = String::from_utf8(request).unwrap();
+                let range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Range: bytes="))
+                    .unwrap();
+                let (first, last) = range.split_once('-').unwrap();
+                let (first, last): (usize, usize) = (first.parse().unwrap(), last.parse().unwrap());
+                let body = &file[first..=last];
+                write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {first}-{last}/{}\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n",
+                    file.len()
+                )
+                .unwrap();
+                for chunk in body.chunks(100) {
+                    write!(stream, "{:x}\r\n", chunk.len()).unwrap();
+                    stream.write_all(chunk).unwrap();
+                    stream.write_all(b"\r\n").unwrap();
+                }
+                stream.write_all(b"0\r\n\r\n").unwrap();
+            }
+        });
+        format!("http://{address}/archive/disk.d64")
+    }
+
+    #[test]
+    fn reads_sectors_with_range_requests() {
+        let mut disk = D64::new(35);
+        fs::format(&mut disk, b"REMOTE", Some(*b"01")).unwrap();
+        fs::write_file(&mut disk, b"README", FileType::Seq, b"HELLO").unwrap();
+        disk.set_sector_error(30, 1, Some(DosError::NoSync))
+            .unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let url = serve(disk.to_bytes(), count.clone());
+
+        let mut image = HttpImage::with_cache(&url, 4).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(image.sector_error(30, 1), Some(DosError::NoSync));
+        let entry = fs::find_file(&image, b"README").unwrap().unwrap();
+        assert_eq!(fs::read_file(&image, &entry).unwrap(), b"HELLO");
+        let fetched = count.load(Ordering::SeqCst);
+        fs::read_directory(&image).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), fetched);
+        assert_eq!(image.read_sector(1, 0), disk.read_sector(1, 0));
+        assert_eq!(count.load(Ordering::SeqCst), fetched + 1);
+        assert_eq!(
+            image.write_sector(1, 0, &[0; 256]),
+            Err(DosError::WriteProtect)
+        );
+    }
+
+    #[test]
+    fn parses_urls_and_responses() {
+        assert_eq!(
+            parse_url("http://example.org:8080/a/b.d64").unwrap(),
+            Url {
+                host: String::from("example.org"),
+                port: 8080,
+                path: String::from("/a/b.d64"),
+            }
+        );
+        assert_eq!(parse_url("http://example.org").unwrap().path, "/");
+        let https = parse_url("https://example.org/").unwrap_err();
+        assert_eq!(https.kind(), io::ErrorKind::Unsupported);
+
+        let response = b"HTTP/1.1 206 Partial\r\nContent-Range: bytes 0-1/9\r\n\r\nAB";
+        let response = parse_response(response).unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("content-range"), Some("bytes 0-1/9"));
+        assert_eq!(response.body, b"AB");
+        assert_eq!(
+            dechunk(b"2\r\nAB\r\n1;x\r\nC\r\n0\r\n\r\n").unwrap(),
+            b"ABC"
+        );
+    }
+}
@@ -0,0 +1,1294 @@
+//! The Commodore serial (IEC) bus.
+//!
+//! The bus has three open-collector lines, ATN, CLK and DATA, plus SRQ, each
+//! pulled low by any participant that asserts it (wired-AND). Bytes travel
+//! LSB first with a handshake per byte:
+//!
+//! 1. The talker releases CLK when it is ready to send; every listener
+//!    releases DATA when it is ready to receive.
+//! 2. A talker that waits longer than 200µs before pulling CLK signals EOI;
+//!    the listener acknowledges by pulling DATA for at least 60µs.
+//! 3. For each bit the talker pulls CLK, puts the bit on DATA (released is
+//!    1) and releases CLK while the bit is valid.
+//! 4. The listener acknowledges the byte by pulling DATA within 1ms.
+//!
+//! With ATN pulled the computer talks and every device listens, so it can
+//! send the commands of [`crate::bus`]. After `TALK` the roles turn around:
+//! the computer pulls DATA and releases CLK, the device takes CLK and
+//! starts talking.
+//!
+//! [`IecHost`] and [`IecDevice`] implement both sides as state machines
+//! driven by [`poll`](IecHost::poll) with the current time in
+//! microseconds. They see the lines through [`IecPins`], which a hardware
+//! adapter implements for real ports and [`WiredBus`] for emulation. Every
+//! participant must be polled at least every 20µs.
+//!
+//! Both sides can also speak the JiffyDOS protocol, see [`jiffy`], and the
+//! fast serial protocol of the 1571 and 1581 with its burst transfers, see
+//! [`fast`].
+
+pub mod fast;
+pub mod jiffy;
+
+use std::collections::VecDeque;
+
+use self::fast::{FastReceiver, FastSender};
+use self::jiffy::{JiffyReceiver, JiffySender};
+use crate::bus::{
+    self, BusCommand, BusDevice, BusError, Operation, Operations, Role, Step, WiredLines,
+};
+use crate::command::{self, BurstCommand, Command};
+use crate::drive::{COMMAND_CHANNEL, VirtualDrive};
+use crate::image::SECTOR_SIZE;
+
+/// How long a talker waits for a listener to respond.
+const RESPONSE_TIMEOUT: u64 = 1000;
+/// How long a talker withholds CLK to signal EOI.
+const EOI_DELAY: u64 = 200;
+/// How long a listener pulls DATA to acknowledge EOI.
+const EOI_ACK: u64 = 60;
+/// How long each bit is set up and then held valid.
+const BIT_TIME: u64 = 60;
+/// How long the computer keeps CLK after releasing ATN.
+const ATN_RELEASE: u64 = 20;
+/// How long a device holds CLK after turnaround before talking.
+const TALK_DELAY: u64 = 80;
+
+/// The state of the bus lines, `true` meaning the line is asserted (low).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Lines {
+    /// Attention, driven by the computer.
+    pub atn: bool,
+    /// Clock, driven by the talker.
+    pub clk: bool,
+    /// Data, driven by the talker during bits and the listeners otherwise.
+    pub data: bool,
+    /// Service request, used by fast serial devices.
+    pub srq: bool,
+}
+
+impl WiredLines for Lines {
+    fn or(self, other: Lines) -> Lines {
+        Lines {
+            atn: self.atn || other.atn,
+            clk: self.clk || other.clk,
+            data: self.data || other.data,
+            srq: self.srq || other.srq,
+        }
+    }
+}
+
+/// Access to the bus lines from one participant.
+pub trait IecPins {
+    /// Returns the state of the bus, including the lines this side pulls.
+    fn read(&mut self) -> Lines;
+
+    /// Sets the lines this side pulls.
+    fn write(&mut self, lines: Lines);
+}
+
+/// An emulated serial bus joining any number of participants.
+///
+/// # Example
+/// ```
+/// use cbm_dos::iec::{IecPins, Lines, WiredBus};
+///
+/// let mut bus = WiredBus::new();
+/// let (a, b) = (bus.attach(), bus.attach());
+/// bus.port(a).write(Lines { clk: true, ..Lines::default() });
+/// assert!(bus.port(b).read().clk);
+/// ```
+pub type WiredBus = bus::WiredBus<Lines>;
+
+/// One participant's connection to a [`WiredBus`].
+pub type Port<'a> = bus::Port<'a, Lines>;
+
+impl IecPins for Port<'_> {
+    fn read(&mut self) -> Lines {
+        self.lines()
+    }
+
+    fn write(&mut self, lines: Lines) {
+        self.pull(lines);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendPhase {
+    /// CLK released, waiting for the listeners to release DATA.
+    Ready,
+    /// Withholding CLK until the listener acknowledges EOI.
+    Eoi,
+    /// Waiting for the end of the EOI acknowledgement.
+    EoiAcknowledged,
+    /// CLK pulled with DATA released before bit 7, for JiffyDOS drives to
+    /// answer.
+    Probe,
+    /// CLK pulled with the bit on DATA.
+    Setup(u8),
+    /// CLK released with the bit valid.
+    Valid(u8),
+    /// CLK pulled, waiting for the listener to take the byte.
+    Frame,
+}
+
+/// The talker's half of one byte.
+#[derive(Debug, Clone)]
+struct Sender {
+    byte: u8,
+    eoi: bool,
+    phase: SendPhase,
+    since: u64,
+    probe: bool,
+    /// Whether a drive answered the probe.
+    probed: bool,
+}
+
+impl Sender {
+    fn new(byte: u8, eoi: bool, now: u64) -> Self {
+        Sender {
+            byte,
+            eoi,
+            phase: SendPhase::Ready,
+            since: now,
+            probe: false,
+            probed: false,
+        }
+    }
+
+    /// Pauses before bit 7 for the JiffyDOS probe.
+    fn probing(self) -> Self {
+        Sender {
+            probe: true,
+            ..self
+        }
+    }
+
+    /// Returns whether CLK and DATA are pulled.
+    fn output(&self) -> (bool, bool) {
+        match self.phase {
+            SendPhase::Probe => (true, false),
+            SendPhase::Ready | SendPhase::Eoi | SendPhase::EoiAcknowledged => (false, false),
+            SendPhase::Setup(bit) | SendPhase::Valid(bit) => (
+                self.phase == SendPhase::Setup(bit),
+                self.byte >> bit & 1 == 0,
+            ),
+            SendPhase::Frame => (true, false),
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Step<()> {
+        let elapsed = now.saturating_sub(self.since);
+        if self.phase == SendPhase::Probe && lines.data {
+            self.probed = true;
+        }
+        let next = match self.phase {
+            SendPhase::Ready if !lines.data && self.eoi => SendPhase::Eoi,
+            SendPhase::Ready if !lines.data => SendPhase::Setup(0),
+            SendPhase::Eoi if lines.data => SendPhase::EoiAcknowledged,
+            SendPhase::EoiAcknowledged if !lines.data => SendPhase::Setup(0),
+            SendPhase::Eoi | SendPhase::EoiAcknowledged if elapsed > RESPONSE_TIMEOUT => {
+                return Step::Failed(BusError::Timeout);
+            }
+            SendPhase::Setup(bit) if elapsed >= BIT_TIME => SendPhase::Valid(bit),
+            SendPhase::Valid(7) if elapsed >= BIT_TIME => SendPhase::Frame,
+            SendPhase::Valid(6) if elapsed >= BIT_TIME && self.probe => SendPhase::Probe,
+            SendPhase::Probe if elapsed >= jiffy::PROBE_PAUSE => SendPhase::Setup(7),
+            SendPhase::Valid(bit) if elapsed >= BIT_TIME => SendPhase::Setup(bit + 1),
+            SendPhase::Frame if lines.data => return Step::Done(()),
+            SendPhase::Frame if elapsed > RESPONSE_TIMEOUT => {
+                return Step::Failed(BusError::Timeout);
+            }
+            _ => return Step::Pending,
+        };
+        self.phase = next;
+        self.since = now;
+        Step::Pending
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReceivePhase {
+    /// DATA pulled, waiting for the talker to release CLK.
+    Busy,
+    /// DATA released, timing the talker for EOI.
+    Ready,
+    /// DATA pulled to acknowledge EOI.
+    EoiAcknowledge,
+    /// DATA released after EOI, waiting for the first bit.
+    EoiReady,
+    /// Waiting for CLK to be released with bit `count` valid.
+    Bit,
+    /// Waiting for CLK to be pulled after bit `count`.
+    BitTaken,
+    /// DATA pulled to answer the JiffyDOS probe.
+    ProbeAnswer,
+}
+
+/// The listener's half of one byte.
+#[derive(Debug, Clone)]
+struct Receiver {
+    phase: ReceivePhase,
+    since: u64,
+    value: u8,
+    count: u8,
+    eoi: bool,
+    /// The device number to answer the JiffyDOS probe for.
+    probe: Option<u8>,
+    /// Whether the probe was answered.
+    probed: bool,
+}
+
+impl Receiver {
+    fn new(now: u64) -> Self {
+        Receiver {
+            phase: ReceivePhase::Busy,
+            since: now,
+            value: 0,
+            count: 0,
+            eoi: false,
+            probe: None,
+            probed: false,
+        }
+    }
+
+    /// Answers the JiffyDOS probe in an address byte for `device`.
+    fn probing(self, device: u8) -> Self {
+        Receiver {
+            probe: Some(device),
+            ..self
+        }
+    }
+
+    /// Returns whether DATA is pulled.
+    fn output(&self) -> bool {
+        matches!(
+            self.phase,
+            ReceivePhase::Busy | ReceivePhase::EoiAcknowledge | ReceivePhase::ProbeAnswer
+        )
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Step<(u8, bool)> {
+        let elapsed = now.saturating_sub(self.since);
+        let next = match self.phase {
+            ReceivePhase::Busy if !lines.clk => ReceivePhase::Ready,
+            ReceivePhase::Ready | ReceivePhase::EoiReady if lines.clk => ReceivePhase::Bit,
+            ReceivePhase::Ready if elapsed > EOI_DELAY => {
+                self.eoi = true;
+                ReceivePhase::EoiAcknowledge
+            }
+            ReceivePhase::EoiAcknowledge if elapsed >= EOI_ACK => ReceivePhase::EoiReady,
+            ReceivePhase::Bit if !lines.clk => {
+                self.value |= u8::from(!lines.data) << self.count;
+                ReceivePhase::BitTaken
+            }
+            ReceivePhase::BitTaken if lines.clk && self.count == 7 => {
+                return Step::Done((self.value, self.eoi));
+            }
+            ReceivePhase::BitTaken if lines.clk => {
+                self.count += 1;
+                ReceivePhase::Bit
+            }
+            ReceivePhase::Bit
+                if self.count == 7
+                    && !self.probed
+                    && elapsed > jiffy::PROBE_DETECT
+                    && self.probe == Some(self.value & 0x1F)
+                    && jiffy::is_address(self.value) =>
+            {
+                self.probed = true;
+                ReceivePhase::ProbeAnswer
+            }
+            ReceivePhase::ProbeAnswer if elapsed >= jiffy::PROBE_ANSWER => ReceivePhase::Bit,
+            ReceivePhase::EoiReady | ReceivePhase::Bit | ReceivePhase::BitTaken
+                if elapsed > RESPONSE_TIMEOUT =>
+            {
+                return Step::Failed(BusError::Timeout);
+            }
+            _ => return Step::Pending,
+        };
+        self.phase = next;
+        self.since = now;
+        Step::Pending
+    }
+}
+
+/// A step of the host's queue.
+#[derive(Debug, Clone)]
+enum IecOperation {
+    Bus(Operation),
+    /// Fast bytes sent after a burst command.
+    BurstSend(Vec<u8>),
+    /// Fast bytes received after a burst command.
+    BurstReceive(usize),
+}
+
+impl From<Operation> for IecOperation {
+    fn from(operation: Operation) -> Self {
+        IecOperation::Bus(operation)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum HostState {
+    Idle,
+    /// ATN and CLK pulled, clocking the fast serial announcement.
+    Announcing(FastSender),
+    /// ATN and CLK pulled, waiting for the devices to pull DATA.
+    Attention {
+        since: u64,
+    },
+    Sending(Sender),
+    /// ATN released, CLK still held.
+    ReleaseAttention {
+        since: u64,
+    },
+    /// DATA pulled and CLK released, waiting for the talker to take CLK.
+    Turnaround {
+        since: u64,
+    },
+    Receiving(Receiver),
+    JiffySending(JiffySender),
+    JiffyReceiving(JiffyReceiver),
+    FastSending(FastSender),
+    /// Keeping CLK steady before asking for the next of `remaining` burst
+    /// bytes.
+    BurstRequest {
+        since: u64,
+        remaining: usize,
+    },
+    BurstReceiving {
+        receiver: FastReceiver,
+        since: u64,
+        remaining: usize,
+    },
+}
+
+/// The computer side of the bus.
+///
+/// Operations are queued and carried out by [`poll`](IecHost::poll); the
+/// host is done when [`is_idle`](IecHost::is_idle) returns `true`. A failed
+/// operation discards the rest of the queue and is reported by
+/// [`take_error`](IecHost::take_error).
+///
+/// With [`set_jiffy`](IecHost::set_jiffy) the host probes for JiffyDOS
+/// drives and uses the fast transfer with those that answer. With
+/// [`set_burst`](IecHost::set_burst) it announces fast serial mode like a
+/// C128 and can move burst data with
+/// [`burst_send`](IecHost::burst_send) and
+/// [`burst_receive`](IecHost::burst_receive).
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::Drive;
+/// use cbm_dos::iec::{IecDevice, IecHost, WiredBus};
+///
+/// let mut bus = WiredBus::new();
+/// let (h, d) = (bus.attach(), bus.attach());
+/// let mut host = IecHost::new();
+/// let mut drive = IecDevice::new(Drive::new(D64::new(35)), 8);
+///
+/// host.talk(8, 15);
+/// host.untalk();
+/// let mut now = 0;
+/// while !host.is_idle() {
+///     host.poll(&mut bus.port(h), now);
+///     drive.poll(&mut bus.port(d), now);
+///     now += 10;
+/// }
+/// assert!(host.take_received().starts_with(b"73,"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct IecHost {
+    queue: Operations<IecOperation>,
+    state: HostState,
+    pending: VecDeque<u8>,
+    attention: bool,
+    hold: Lines,
+    received: Vec<u8>,
+    error: Option<BusError>,
+    jiffy: bool,
+    fast: bool,
+    burst: bool,
+    /// Whether the addressed drive answered the fast serial announcement.
+    serial: bool,
+    /// The level of CLK while receiving burst bytes.
+    clock: bool,
+}
+
+impl Default for IecHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IecHost {
+    /// Creates a host with all lines released.
+    pub fn new() -> Self {
+        IecHost {
+            queue: Operations::default(),
+            state: HostState::Idle,
+            pending: VecDeque::new(),
+            attention: false,
+            hold: Lines::default(),
+            received: Vec::new(),
+            error: None,
+            jiffy: false,
+            fast: false,
+            burst: false,
+            serial: false,
+            clock: false,
+        }
+    }
+
+    /// Enables or disables the JiffyDOS probe. It is off by default.
+    pub fn set_jiffy(&mut self, enabled: bool) {
+        self.jiffy = enabled;
+    }
+
+    /// Returns whether the addressed drive answered the JiffyDOS probe and
+    /// data bytes use the fast transfer.
+    pub fn jiffy_active(&self) -> bool {
+        self.fast
+    }
+
+    /// Enables or disables the fast serial announcement. It is off by
+    /// default, as on a C128 in 1541 mode.
+    ///
+    /// Fast serial mode takes precedence over JiffyDOS with drives that
+    /// answer both.
+    pub fn set_burst(&mut self, enabled: bool) {
+        self.burst = enabled;
+    }
+
+    /// Returns whether the last drive sent `LISTEN` or `TALK` answered the
+    /// fast serial announcement, so that data bytes for it and burst
+    /// transfers are sent fast.
+    pub fn burst_active(&self) -> bool {
+        self.serial
+    }
+
+    /// Queues `LISTEN device` for data on `secondary`.
+    pub fn listen(&mut self, device: u8, secondary: u8) {
+        self.queue.listen(device, secondary);
+    }
+
+    /// Queues `UNLISTEN`.
+    pub fn unlisten(&mut self) {
+        self.queue.unlisten();
+    }
+
+    /// Queues `TALK device` on `secondary` and the reception of everything
+    /// the device sends up to EOI.
+    pub fn talk(&mut self, device: u8, secondary: u8) {
+        self.queue.talk(device, secondary);
+    }
+
+    /// Queues `UNTALK`.
+    pub fn untalk(&mut self) {
+        self.queue.untalk();
+    }
+
+    /// Queues opening `secondary` on `device` with `name`.
+    pub fn open(&mut self, device: u8, secondary: u8, name: &[u8]) {
+        self.queue.open(device, secondary, name);
+    }
+
+    /// Queues closing `secondary` on `device`.
+    pub fn close(&mut self, device: u8, secondary: u8) {
+        self.queue.close(device, secondary);
+    }
+
+    /// Queues data for the listening devices, signalling EOI on the last
+    /// byte.
+    pub fn send(&mut self, data: &[u8]) {
+        self.queue.send(data);
+    }
+
+    /// Queues fast bytes for a burst command sent before, such as the
+    /// sectors of a burst write.
+    ///
+    /// Burst transfers fail with [`BusError::Timeout`] unless
+    /// [`burst_active`](IecHost::burst_active) is set when they start.
+    pub fn burst_send(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.queue.push(IecOperation::BurstSend(data.to_vec()));
+        }
+    }
+
+    /// Queues the reception of `count` fast bytes of a burst reply.
+    pub fn burst_receive(&mut self, count: usize) {
+        if count > 0 {
+            self.queue.push(IecOperation::BurstReceive(count));
+        }
+    }
+
+    /// Returns `true` once every queued operation has finished.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && matches!(self.state, HostState::Idle)
+    }
+
+    /// Returns and clears the bytes received so far.
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Returns and clears the error that stopped the queue.
+    pub fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+
+    /// Advances the host to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl IecPins, now: u64) {
+        let lines = pins.read();
+        if let Err(error) = self.step(lines, now) {
+            self.queue.clear();
+            self.pending.clear();
+            self.state = HostState::Idle;
+            self.hold = Lines::default();
+            self.fast = false;
+            self.serial = false;
+            self.error = Some(error);
+        }
+        pins.write(self.output());
+    }
+
+    fn output(&self) -> Lines {
+        match &self.state {
+            HostState::Idle => self.hold,
+            HostState::Announcing(sender) => {
+                let (srq, data) = sender.output();
+                Lines {
+                    atn: true,
+                    clk: true,
+                    data,
+                    srq,
+                }
+            }
+            HostState::Attention { .. } => Lines {
+                atn: true,
+                clk: true,
+                ..Lines::default()
+            },
+            HostState::Sending(sender) => {
+                let (clk, data) = sender.output();
+                Lines {
+                    atn: self.attention,
+                    clk,
+                    data,
+                    srq: false,
+                }
+            }
+            HostState::ReleaseAttention { .. } => Lines {
+                clk: true,
+                ..Lines::default()
+            },
+            HostState::Turnaround { .. } => Lines {
+                data: true,
+                ..Lines::default()
+            },
+            HostState::Receiving(receiver) => Lines {
+                data: receiver.output(),
+                ..Lines::default()
+            },
+            HostState::JiffySending(sender) => {
+                let (clk, data) = sender.output();
+                Lines {
+                    clk,
+                    data,
+                    ..Lines::default()
+                }
+            }
+            HostState::JiffyReceiving(receiver) => Lines {
+                data: receiver.output(),
+                ..Lines::default()
+            },
+            HostState::FastSending(sender) => {
+                let (srq, data) = sender.output();
+                Lines {
+                    atn: false,
+                    clk: true,
+                    data,
+                    srq,
+                }
+            }
+            HostState::BurstRequest { .. } | HostState::BurstReceiving { .. } => Lines {
+                clk: self.clock,
+                ..Lines::default()
+            },
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Result<(), BusError> {
+        // A fast drive answers after its address, while the host goes on
+        // under ATN.
+        if self.burst
+            && self.attention
+            && lines.srq
+            && matches!(
+                self.state,
+                HostState::Sending(_) | HostState::ReleaseAttention { .. }
+            )
+        {
+            self.serial = true;
+        }
+        match &mut self.state {
+            HostState::Idle => match self.queue.pop() {
+                Some(IecOperation::Bus(Operation::Attention(bytes))) => {
+                    self.pending = bytes.into();
+                    self.attention = true;
+                    self.clock = false;
+                    self.state = if self.burst {
+                        HostState::Announcing(FastSender::new(fast::ANNOUNCE))
+                    } else {
+                        HostState::Attention { since: now }
+                    };
+                }
+                Some(IecOperation::Bus(Operation::Send(bytes))) => {
+                    self.pending = bytes.into();
+                    self.attention = false;
+                    self.next_byte(now);
+                }
+                Some(IecOperation::Bus(Operation::Receive)) => {
+                    self.state = HostState::Turnaround { since: now };
+                }
+                Some(IecOperation::BurstSend(_) | IecOperation::BurstReceive(_))
+                    if !self.serial =>
+                {
+                    return Err(BusError::Timeout);
+                }
+                Some(IecOperation::BurstSend(bytes)) => {
+                    self.pending = bytes.into();
+                    self.attention = false;
+                    self.next_byte(now);
+                }
+                Some(IecOperation::BurstReceive(remaining)) => {
+                    self.state = HostState::BurstRequest {
+                        since: now,
+                        remaining,
+                    };
+                }
+                None => {}
+            },
+            HostState::Announcing(sender) => {
+                if let Step::Done(()) = sender.step(lines, now) {
+                    self.state = HostState::Attention { since: now };
+                }
+            }
+            HostState::Attention { .. } if lines.data => self.next_byte(now),
+            HostState::Attention { since } if now - *since > RESPONSE_TIMEOUT => {
+                return Err(BusError::DeviceNotPresent);
+            }
+            HostState::Sending(sender) => match sender.step(lines, now) {
+                Step::Done(()) => self.next_byte(now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::JiffySending(sender) => match sender.step(lines, now) {
+                Step::Done(()) => self.next_byte(now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::ReleaseAttention { since } if now - *since >= ATN_RELEASE => {
+                self.state = HostState::Idle;
+            }
+            HostState::Turnaround { .. } if lines.clk => self.receive(now),
+            HostState::Turnaround { since } if now - *since > RESPONSE_TIMEOUT => {
+                return Err(BusError::Timeout);
+            }
+            HostState::Receiving(receiver) => match receiver.step(lines, now) {
+                Step::Done((byte, eoi)) => self.received(byte, eoi, now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::JiffyReceiving(receiver) => match receiver.step(lines, now) {
+                Step::Done((byte, eoi)) => self.received(byte, eoi, now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::FastSending(sender) => match sender.step(lines, now) {
+                Step::Done(()) => self.next_byte(now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::BurstRequest { since, remaining } if now - *since >= fast::SETTLE => {
+                // Every change of CLK asks the drive for one byte.
+                self.clock = !self.clock;
+                self.state = HostState::BurstReceiving {
+                    receiver: FastReceiver::new(),
+                    since: now,
+                    remaining: *remaining,
+                };
+            }
+            HostState::BurstReceiving {
+                receiver,
+                since,
+                remaining,
+            } => match receiver.step(lines, now) {
+                Step::Done(byte) => {
+                    self.received.push(byte);
+                    self.state = match *remaining - 1 {
+                        0 => {
+                            self.hold = Lines {
+                                clk: self.clock,
+                                ..Lines::default()
+                            };
+                            HostState::Idle
+                        }
+                        remaining => HostState::BurstRequest {
+                            since: now,
+                            remaining,
+                        },
+                    };
+                }
+                _ if now - *since > RESPONSE_TIMEOUT => return Err(BusError::Timeout),
+                _ => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Starts receiving the next byte from the talker.
+    fn receive(&mut self, now: u64) {
+        self.state = if self.fast && !self.serial {
+            HostState::JiffyReceiving(JiffyReceiver::new(jiffy::FROM_DRIVE))
+        } else {
+            HostState::Receiving(Receiver::new(now))
+        };
+    }
+
+    /// Stores a received byte and goes on until EOI.
+    fn received(&mut self, byte: u8, eoi: bool, now: u64) {
+        self.received.push(byte);
+        if eoi {
+            self.hold = Lines {
+                data: true,
+                ..Lines::default()
+            };
+            self.state = HostState::Idle;
+        } else {
+            self.receive(now);
+        }
+    }
+
+    /// Starts the next pending byte, or finishes the current operation.
+    fn next_byte(&mut self, now: u64) {
+        if let HostState::Sending(sender) = &self.state
+            && self.attention
+        {
+            match BusCommand::from_byte(sender.byte) {
+                Some(BusCommand::Listen(_) | BusCommand::Talk(_)) => {
+                    self.fast = sender.probed;
+                    self.serial = false;
+                }
+                Some(BusCommand::Unlisten | BusCommand::Untalk) => self.fast = false,
+                _ => {}
+            }
+        }
+        if let Some(byte) = self.pending.pop_front() {
+            let eoi = !self.attention && self.pending.is_empty();
+            self.state = match Sender::new(byte, eoi, now) {
+                _ if self.serial && !self.attention => {
+                    HostState::FastSending(FastSender::new(byte).handshaking())
+                }
+                _ if self.fast && !self.attention => {
+                    HostState::JiffySending(JiffySender::new(byte, eoi, jiffy::TO_DRIVE))
+                }
+                sender if self.jiffy && self.attention && jiffy::is_address(byte) => {
+                    HostState::Sending(sender.probing())
+                }
+                sender => HostState::Sending(sender),
+            };
+            return;
+        }
+        let Some(last) = self.last_sent() else {
+            return;
+        };
+        // After UNLISTEN or UNTALK nobody is addressed; otherwise the host
+        // stays talker until the next command.
+        let released = self.attention && matches!(last, 0x3F | 0x5F);
+        self.hold = Lines {
+            clk: !released,
+            ..Lines::default()
+        };
+        self.state = if self.attention {
+            HostState::ReleaseAttention { since: now }
+        } else {
+            HostState::Idle
+        };
+    }
+
+    fn last_sent(&self) -> Option<u8> {
+        match &self.state {
+            HostState::Sending(sender) => Some(sender.byte),
+            HostState::JiffySending(sender) => Some(sender.byte),
+            HostState::FastSending(sender) => Some(sender.byte),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DeviceState {
+    Idle,
+    /// ATN pulled: receiving commands.
+    Attention(Receiver),
+    Listening(Receiver),
+    /// Addressed as talker, waiting for the host to release CLK.
+    Turnaround,
+    /// Holding CLK before the first byte.
+    TalkStart {
+        since: u64,
+    },
+    Talking(Sender),
+    JiffyListening(JiffyReceiver),
+    JiffyTalking(JiffySender),
+    /// Answering the fast serial announcement under ATN.
+    Answering(FastSender),
+    FastListening(FastReceiver),
+    /// Waiting for CLK to change, which asks for the next burst byte. While
+    /// `level` is `None` DATA is held, which acknowledges the last byte of a
+    /// write, until the host releases CLK.
+    BurstReady {
+        level: Option<bool>,
+    },
+    BurstTalking {
+        sender: FastSender,
+        level: bool,
+    },
+}
+
+/// A burst write waiting for its sectors.
+#[derive(Debug, Clone)]
+struct BurstWrite {
+    /// The command as received, to be run on channel 15 if the drive has no
+    /// burst mode.
+    bytes: Vec<u8>,
+    command: BurstCommand,
+    data: Vec<u8>,
+    length: usize,
+}
+
+/// A drive attached to the serial bus.
+///
+/// The device answers ATN, receives commands and data and sends the
+/// addressed channel as talker; what the commands mean is up to the
+/// [`BusDevice`] inside. With [`set_jiffy`](IecDevice::set_jiffy) it also
+/// answers the JiffyDOS probe, and with [`set_burst`](IecDevice::set_burst)
+/// the fast serial announcement.
+#[derive(Debug, Clone)]
+pub struct IecDevice<D> {
+    device: BusDevice<D>,
+    state: DeviceState,
+    jiffy: bool,
+    fast: bool,
+    burst: bool,
+    /// Whether the host announced fast serial mode in this ATN sequence.
+    host_fast: bool,
+    /// Whether data bytes from the host come fast.
+    serial: bool,
+    /// Bytes for channel 15 received fast, held back until `UNLISTEN`.
+    command: Vec<u8>,
+    write: Option<BurstWrite>,
+    reply: VecDeque<u8>,
+}
+
+impl<D: VirtualDrive> IecDevice<D> {
+    /// Attaches `drive` under device number `address`.
+    pub fn new(drive: D, address: u8) -> Self {
+        IecDevice {
+            device: BusDevice::new(drive, address),
+            state: DeviceState::Idle,
+            jiffy: false,
+            fast: false,
+            burst: false,
+            host_fast: false,
+            serial: false,
+            command: Vec::new(),
+            write: None,
+            reply: VecDeque::new(),
+        }
+    }
+
+    /// Returns the command layer.
+    pub fn device(&self) -> &BusDevice<D> {
+        &self.device
+    }
+
+    /// Returns the command layer for modification.
+    pub fn device_mut(&mut self) -> &mut BusDevice<D> {
+        &mut self.device
+    }
+
+    /// Detaches the drive.
+    pub fn into_inner(self) -> D {
+        self.device.into_inner()
+    }
+
+    /// Enables or disables JiffyDOS. It is off by default, as on a stock
+    /// drive.
+    pub fn set_jiffy(&mut self, enabled: bool) {
+        self.jiffy = enabled;
+    }
+
+    /// Enables or disables fast serial mode and burst transfers, as on a
+    /// 1571 or 1581. It is off by default.
+    ///
+    /// Burst commands go to the drive's
+    /// [`burst_command`](VirtualDrive::burst_command); when it has no burst
+    /// mode they run on channel 15 as usual.
+    pub fn set_burst(&mut self, enabled: bool) {
+        self.burst = enabled;
+    }
+
+    /// Advances the device to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl IecPins, now: u64) {
+        let lines = pins.read();
+        self.step(lines, now);
+        pins.write(self.output());
+    }
+
+    fn output(&self) -> Lines {
+        let (clk, data) = match &self.state {
+            DeviceState::Answering(sender) | DeviceState::BurstTalking { sender, .. } => {
+                let (srq, data) = sender.output();
+                return Lines {
+                    data,
+                    srq,
+                    ..Lines::default()
+                };
+            }
+            DeviceState::FastListening(receiver) => (false, receiver.output()),
+            DeviceState::BurstReady { level } => (false, level.is_none()),
+            DeviceState::Idle => (false, false),
+            DeviceState::Attention(receiver) | DeviceState::Listening(receiver) => {
+                (false, receiver.output())
+            }
+            DeviceState::Turnaround => (false, true),
+            DeviceState::TalkStart { .. } => (true, false),
+            DeviceState::Talking(sender) => sender.output(),
+            DeviceState::JiffyListening(receiver) => (false, receiver.output()),
+            DeviceState::JiffyTalking(sender) => sender.output(),
+        };
+        Lines {
+            clk,
+            data,
+            ..Lines::default()
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) {
+        if lines.atn
+            && !matches!(
+                self.state,
+                DeviceState::Attention(_) | DeviceState::Answering(_)
+            )
+        {
+            // ATN ends any burst transfer.
+            self.host_fast = false;
+            self.write = None;
+            self.reply.clear();
+            self.state = DeviceState::Attention(self.command_receiver(now));
+            return;
+        }
+        if lines.atn && lines.srq && matches!(self.state, DeviceState::Attention(_)) {
+            self.host_fast = true;
+        }
+        match &mut self.state {
+            DeviceState::Idle => {}
+            DeviceState::Attention(_) if !lines.atn => {
+                self.state = match self.device.role() {
+                    Role::Listener => self.data_receiver(now),
+                    Role::Talker => DeviceState::Turnaround,
+                    Role::Idle => self.burst_transfer(now),
+                };
+            }
+            DeviceState::Attention(receiver) => match receiver.step(lines, now) {
+                Step::Done((byte, _)) => {
+                    let probed = receiver.probed;
+                    let mut answer = false;
+                    match BusCommand::from_byte(byte) {
+                        Some(BusCommand::Listen(device) | BusCommand::Talk(device))
+                            if device == self.device.address() =>
+                        {
+                            self.fast = probed;
+                            self.serial = self.burst && self.host_fast;
+                            self.command.clear();
+                            answer = self.serial;
+                        }
+                        Some(BusCommand::Unlisten) => {
+                            self.fast = false;
+                            self.run_command();
+                        }
+                        Some(BusCommand::Untalk) => self.fast = false,
+                        _ => {}
+                    }
+                    self.device.attention(byte);
+                    self.state = if answer {
+                        DeviceState::Answering(FastSender::new(fast::ANSWER))
+                    } else {
+                        DeviceState::Attention(self.command_receiver(now))
+                    };
+                }
+                Step::Failed(_) => self.state = DeviceState::Attention(self.command_receiver(now)),
+                Step::Pending => {}
+            },
+            DeviceState::Listening(receiver) => match receiver.step(lines, now) {
+                Step::Done((byte, _)) => {
+                    self.device.receive(byte);
+                    self.state = self.data_receiver(now);
+                }
+                Step::Failed(_) => self.state = self.data_receiver(now),
+                Step::Pending => {}
+            },
+            DeviceState::JiffyListening(receiver) => match receiver.step(lines, now) {
+                Step::Done((byte, _)) => {
+                    self.device.receive(byte);
+                    self.state = self.data_receiver(now);
+                }
+                Step::Failed(_) => self.state = self.data_receiver(now),
+                Step::Pending => {}
+            },
+            DeviceState::Turnaround if !lines.clk => {
+                self.state = DeviceState::TalkStart { since: now };
+            }
+            DeviceState::Turnaround => {}
+            DeviceState::TalkStart { since } if now - *since >= TALK_DELAY => self.talk(now),
+            DeviceState::TalkStart { .. } => {}
+            DeviceState::Talking(sender) => match sender.step(lines, now) {
+                Step::Done(()) if sender.eoi => self.state = DeviceState::Idle,
+                Step::Done(()) => self.talk(now),
+                Step::Failed(_) => self.state = DeviceState::Idle,
+                Step::Pending => {}
+            },
+            DeviceState::JiffyTalking(sender) => match sender.step(lines, now) {
+                Step::Done(()) if sender.eoi => self.state = DeviceState::Idle,
+                Step::Done(()) => self.talk(now),
+                Step::Failed(_) => self.state = DeviceState::Idle,
+                Step::Pending => {}
+            },
+            DeviceState::Answering(sender) => {
+                if let Step::Done(()) = sender.step(lines, now) {
+                    self.state = DeviceState::Attention(self.command_receiver(now));
+                }
+            }
+            DeviceState::FastListening(receiver) => {
+                if let Step::Done(byte) = receiver.step(lines, now) {
+                    if let Some(write) = &mut self.write {
+                        write.data.push(byte);
+                        self.finish_write();
+                        self.state = self.burst_transfer(now);
+                    } else {
+                        self.listened(byte);
+                        self.state = self.data_receiver(now);
+                    }
+                }
+            }
+            DeviceState::BurstReady { level: None } if !lines.clk => {
+                self.state = DeviceState::BurstReady { level: Some(false) };
+            }
+            DeviceState::BurstReady { level: Some(level) } if lines.clk != *level => {
+                self.state = match self.reply.pop_front() {
+                    Some(byte) => DeviceState::BurstTalking {
+                        sender: FastSender::new(byte),
+                        level: lines.clk,
+                    },
+                    None => DeviceState::Idle,
+                };
+            }
+            DeviceState::BurstReady { .. } => {}
+            DeviceState::BurstTalking { sender, level } => {
+                if let Step::Done(()) = sender.step(lines, now) {
+                    let level = *level;
+                    self.state = self.burst_transfer(now);
+                    if let DeviceState::BurstReady { .. } = self.state {
+                        self.state = DeviceState::BurstReady { level: Some(level) };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the state for the burst transfer after a command: receiving
+    /// the sectors of a write or sending the reply.
+    fn burst_transfer(&self, now: u64) -> DeviceState {
+        if self.write.is_some() {
+            DeviceState::FastListening(FastReceiver::new().handshaking(now))
+        } else if !self.reply.is_empty() {
+            DeviceState::BurstReady { level: None }
+        } else {
+            DeviceState::Idle
+        }
+    }
+
+    /// Takes a data byte received fast.
+    fn listened(&mut self, byte: u8) {
+        if self.device.secondary() == Some(COMMAND_CHANNEL) {
+            self.command.push(byte);
+        } else {
+            self.device.receive(byte);
+        }
+    }
+
+    /// Runs the bytes held back for channel 15 as a burst command, or hands
+    /// them on to channel 15 if they are none or the drive has no burst
+    /// mode.
+    fn run_command(&mut self) {
+        let bytes = std::mem::take(&mut self.command);
+        if let Ok(Command::Burst(burst)) = command::parse(&bytes) {
+            if let BurstCommand::Write { count, .. } = burst {
+                self.write = Some(BurstWrite {
+                    bytes,
+                    command: burst,
+                    data: Vec::new(),
+                    length: usize::from(count) * SECTOR_SIZE,
+                });
+                self.finish_write();
+                return;
+            }
+            if let Some(reply) = self.device.drive_mut().burst_command(&burst, &[]) {
+                self.reply = reply.into();
+                return;
+            }
+        }
+        for byte in bytes {
+            self.device.receive(byte);
+        }
+    }
+
+    /// Runs the burst write once all its sectors have arrived.
+    fn finish_write(&mut self) {
+        let Some(write) = self.write.take_if(|write| write.data.len() >= write.length) else {
+            return;
+        };
+        let drive = self.device.drive_mut();
+        match drive.burst_command(&write.command, &write.data) {
+            Some(reply) => self.reply = reply.into(),
+            None => {
+                let _ = drive.open(COMMAND_CHANNEL, &write.bytes);
+            }
+        }
+    }
+
+    /// Returns the receiver for bytes under ATN.
+    fn command_receiver(&self, now: u64) -> Receiver {
+        let receiver = Receiver::new(now);
+        if self.jiffy {
+            receiver.probing(self.device.address())
+        } else {
+            receiver
+        }
+    }
+
+    /// Returns the state for receiving the next data byte.
+    fn data_receiver(&self, now: u64) -> DeviceState {
+        if self.serial {
+            DeviceState::FastListening(FastReceiver::new().handshaking(now))
+        } else if self.fast {
+            DeviceState::JiffyListening(JiffyReceiver::new(jiffy::TO_DRIVE))
+        } else {
+            DeviceState::Listening(Receiver::new(now))
+        }
+    }
+
+    /// Starts sending the next byte, or falls silent when the channel has
+    /// nothing, which the host sees as a timeout.
+    fn talk(&mut self, now: u64) {
+        self.state = match self.device.send() {
+            Some((byte, eoi)) if self.fast && !self.serial => {
+                DeviceState::JiffyTalking(JiffySender::new(byte, eoi, jiffy::FROM_DRIVE))
+            }
+            Some((byte, eoi)) => DeviceState::Talking(Sender::new(byte, eoi, now)),
+            None => DeviceState::Idle,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::Drive;
+    use crate::wedge::Wedge;
+
+    fn run(bus: &mut WiredBus, host: &mut IecHost, devices: &mut [IecDevice<Drive<D64>>]) {
+        let mut now = 0;
+        while !host.is_idle() {
+            host.poll(&mut bus.port(0), now);
+            for (index, device) in devices.iter_mut().enumerate() {
+                device.poll(&mut bus.port(index + 1), now);
+            }
+            now += 10;
+            assert!(now < 10_000_000, "bus hung");
+        }
+    }
+
+    fn drive() -> IecDevice<Drive<D64>> {
+        let mut wedge = Wedge::mount(D64::new(35));
+        wedge.command("N:SERIAL,01").unwrap();
+        wedge.save("PROG", 0x0801, &[0x0B, 0x08, 0x0A]).unwrap();
+        IecDevice::new(wedge.into_inner(), 8)
+    }
+
+    #[test]
+    fn loads_and_saves_over_the_bus() {
+        let mut bus = WiredBus::new();
+        let mut devices = [drive()];
+        for _ in 0..=devices.len() {
+            bus.attach();
+        }
+        let mut host = IecHost::new();
+
+        host.open(8, 0, b"PROG");
+        host.talk(8, 0);
+        host.untalk();
+        host.close(8, 0);
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), None);
+        assert_eq!(host.take_received(), [0x01, 0x08, 0x0B, 0x08, 0x0A]);
+
+        host.open(8, 1, b"COPY");
+        host.listen(8, 1);
+        host.send(&[0x00, 0xC0, 0xEA]);
+        host.unlisten();
+        host.close(8, 1);
+        host.listen(8, 15);
+        host.send(b"R:NEW=COPY");
+        host.unlisten();
+        host.talk(8, 15);
+        host.untalk();
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_received(), b"00, OK,00,00\r");
+        let mut wedge = Wedge::new(devices[0].device().drive().clone());
+        assert_eq!(wedge.load("NEW").unwrap(), (0xC000, vec![0xEA]));
+        assert_eq!(bus.lines(), Lines::default());
+    }
+
+    #[test]
+    fn reports_absent_devices_and_silent_talkers() {
+        let mut bus = WiredBus::new();
+        bus.attach();
+        let mut host = IecHost::new();
+        host.listen(8, 0);
+        host.unlisten();
+        run(&mut bus, &mut host, &mut []);
+        assert_eq!(host.take_error(), Some(BusError::DeviceNotPresent));
+        assert!(host.is_idle());
+
+        let mut devices = [drive()];
+        bus.attach();
+        host.open(8, 2, b"MISSING");
+        host.talk(8, 2);
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), Some(BusError::Timeout));
+
+        host.untalk();
+        host.talk(8, 15);
+        host.untalk();
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), None);
+        assert!(host.take_received().starts_with(b"62,"));
+    }
+}
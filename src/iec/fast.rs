@@ -0,0 +1,270 @@
+//! The fast serial protocol of the 1571 and 1581 and its burst transfers.
+//!
+//! A C128 in fast mode clocks bytes over SRQ instead of handshaking every
+//! bit on CLK: for each bit, MSB first, the sender pulls SRQ for 2µs with
+//! the bit on DATA (released meaning 1) and then releases SRQ for 2µs. The
+//! receiver samples DATA as SRQ is released.
+//!
+//! The computer announces itself by clocking a fast `$FF` at the start of
+//! every ATN sequence. A fast drive sent `LISTEN` or `TALK` for its own
+//! address after such an announcement answers with a fast `$00` while still
+//! holding DATA, and from then on up to the next `LISTEN` or `TALK` data
+//! bytes for the drive are sent fast. The drive keeps talking the standard
+//! protocol, so a computer or drive without fast mode never notices.
+//!
+//! Fast bytes for the drive are paced by DATA: the computer holds CLK, the
+//! drive pulls DATA while busy and releases it when ready, and the computer
+//! waits for DATA to be pulled again after the eighth bit as
+//! acknowledgement.
+//!
+//! Burst commands sent to channel 15 in fast mode are run by
+//! [`VirtualDrive::burst_command`](crate::drive::VirtualDrive::burst_command)
+//! once the computer unlistens. A sector write first takes its sectors as
+//! fast bytes. The reply follows as fast bytes too, one per change of the
+//! computer's CLK line after ATN is released.
+//!
+//! Every participant has to be polled at least every 1µs in fast mode.
+
+use super::{Lines, RESPONSE_TIMEOUT};
+use crate::bus::{BusError, Step};
+
+/// How long SRQ is pulled, and then released, for each bit.
+const HALF_BIT: u64 = 2;
+/// How long a listener holds DATA after a byte.
+const BUSY_TIME: u64 = 4;
+/// How long the computer keeps CLK steady before asking for the next byte.
+pub(super) const SETTLE: u64 = 10;
+
+/// The byte the computer announces fast mode with.
+pub(super) const ANNOUNCE: u8 = 0xFF;
+/// The byte a fast drive answers with.
+pub(super) const ANSWER: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendPhase {
+    /// Waiting to start, for the listener to release DATA if handshaking.
+    Ready,
+    /// Putting out bit `n`, with SRQ pulled in the first half.
+    Bit { n: u8, since: u64, pulled: bool },
+    /// Waiting for the listener to pull DATA.
+    Acknowledge { since: u64 },
+}
+
+/// The sender's half of one fast byte.
+#[derive(Debug, Clone)]
+pub(super) struct FastSender {
+    pub(super) byte: u8,
+    handshake: bool,
+    phase: SendPhase,
+}
+
+impl FastSender {
+    pub(super) fn new(byte: u8) -> Self {
+        FastSender {
+            byte,
+            handshake: false,
+            phase: SendPhase::Ready,
+        }
+    }
+
+    /// Paces the byte by the listener's DATA line.
+    pub(super) fn handshaking(self) -> Self {
+        FastSender {
+            handshake: true,
+            ..self
+        }
+    }
+
+    /// Returns whether SRQ and DATA are pulled.
+    pub(super) fn output(&self) -> (bool, bool) {
+        match self.phase {
+            SendPhase::Bit { n, pulled, .. } => (pulled, self.byte >> n & 1 == 0),
+            SendPhase::Ready | SendPhase::Acknowledge { .. } => (false, false),
+        }
+    }
+
+    pub(super) fn step(&mut self, lines: Lines, now: u64) -> Step<()> {
+        self.phase = match self.phase {
+            SendPhase::Ready if !self.handshake || !lines.data => SendPhase::Bit {
+                n: 7,
+                since: now,
+                pulled: true,
+            },
+            SendPhase::Bit { n, since, pulled } if now - since >= HALF_BIT => match (n, pulled) {
+                (n, true) => SendPhase::Bit {
+                    n,
+                    since: now,
+                    pulled: false,
+                },
+                (0, false) if self.handshake => SendPhase::Acknowledge { since: now },
+                (0, false) => return Step::Done(()),
+                (n, false) => SendPhase::Bit {
+                    n: n - 1,
+                    since: now,
+                    pulled: true,
+                },
+            },
+            SendPhase::Acknowledge { .. } if lines.data => return Step::Done(()),
+            SendPhase::Acknowledge { since } if now - since > RESPONSE_TIMEOUT => {
+                return Step::Failed(BusError::Timeout);
+            }
+            phase => phase,
+        };
+        Step::Pending
+    }
+}
+
+/// The receiver's half of one fast byte.
+#[derive(Debug, Clone)]
+pub(super) struct FastReceiver {
+    /// When DATA was pulled, while the listener is still busy.
+    busy: Option<u64>,
+    srq: bool,
+    value: u8,
+    count: u8,
+}
+
+impl FastReceiver {
+    pub(super) fn new() -> Self {
+        FastReceiver {
+            busy: None,
+            srq: false,
+            value: 0,
+            count: 0,
+        }
+    }
+
+    /// Holds DATA for a moment before the byte, which acknowledges the one
+    /// before, and releases it when ready.
+    pub(super) fn handshaking(self, now: u64) -> Self {
+        FastReceiver {
+            busy: Some(now),
+            ..self
+        }
+    }
+
+    /// Returns whether DATA is pulled.
+    pub(super) fn output(&self) -> bool {
+        self.busy.is_some()
+    }
+
+    pub(super) fn step(&mut self, lines: Lines, now: u64) -> Step<u8> {
+        let released = self.srq && !lines.srq;
+        self.srq = lines.srq;
+        if let Some(since) = self.busy {
+            if now - since >= BUSY_TIME {
+                self.busy = None;
+            }
+            return Step::Pending;
+        }
+        if released {
+            self.value = self.value << 1 | u8::from(!lines.data);
+            self.count += 1;
+            if self.count == 8 {
+                return Step::Done(self.value);
+            }
+        }
+        Step::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::BusError;
+    use crate::command::BurstCommand;
+    use crate::d64::D64;
+    use crate::drive::{Drive, VirtualDrive};
+    use crate::iec::{IecDevice, IecHost, WiredBus};
+    use crate::image::BlockDevice;
+    use crate::model::DriveModel;
+
+    /// Runs the host and one device at 1µs steps.
+    fn run(bus: &mut WiredBus, host: &mut IecHost, device: &mut IecDevice<Drive<D64>>) {
+        let mut now = 0;
+        while !host.is_idle() {
+            host.poll(&mut bus.port(0), now);
+            device.poll(&mut bus.port(1), now);
+            now += 1;
+            assert!(now < 10_000_000, "bus hung");
+        }
+    }
+
+    fn attach(model: DriveModel) -> (WiredBus, IecDevice<Drive<D64>>) {
+        let mut drive = Drive::with_model(D64::new(35), model);
+        drive.execute(b"N:BURST,01").unwrap();
+        let mut bus = WiredBus::new();
+        bus.attach();
+        bus.attach();
+        (bus, IecDevice::new(drive, 8))
+    }
+
+    #[test]
+    fn reads_sectors_and_falls_back() {
+        let mut expected = None;
+        for (host_fast, drive_fast) in [(true, true), (true, false), (false, true)] {
+            let (mut bus, mut device) = attach(DriveModel::C1571);
+            let reply = device.device_mut().drive_mut().burst(
+                &BurstCommand::Read {
+                    side: 0,
+                    track: 18,
+                    sector: 0,
+                    count: 1,
+                    ignore_errors: false,
+                },
+                &[],
+            );
+            let expected = expected.get_or_insert(reply);
+            device.set_burst(drive_fast);
+            let mut host = IecHost::new();
+            host.set_burst(host_fast);
+
+            host.listen(8, 15);
+            host.send(&[b'U', b'0', 0x00, 18, 0]);
+            host.unlisten();
+            if host_fast && drive_fast {
+                host.burst_receive(expected.len());
+            } else {
+                host.talk(8, 15);
+                host.untalk();
+            }
+            run(&mut bus, &mut host, &mut device);
+            assert_eq!(host.burst_active(), host_fast && drive_fast);
+            assert_eq!(host.take_error(), None);
+            let received = host.take_received();
+            assert_eq!(received, *expected);
+            assert_eq!(received[0], 0x11);
+            assert_eq!(received[1..3], [18, 1]);
+        }
+    }
+
+    #[test]
+    fn writes_sectors_in_burst_mode() {
+        let block: Vec<u8> = (0..=255).rev().collect();
+        let (mut bus, mut device) = attach(DriveModel::C1571);
+        device.set_burst(true);
+        let mut host = IecHost::new();
+        host.set_burst(true);
+
+        host.listen(8, 15);
+        host.send(&[b'U', b'0', 0x02, 1, 0]);
+        host.unlisten();
+        host.burst_send(&block);
+        host.burst_receive(1);
+        run(&mut bus, &mut host, &mut device);
+        assert_eq!(host.take_error(), None);
+        assert_eq!(host.take_received(), [0x11]);
+        let sector = device.device().drive().image().read_sector(1, 0).unwrap();
+        assert_eq!(sector[..], block[..]);
+
+        let (mut bus, mut device) = attach(DriveModel::C1541);
+        device.set_burst(true);
+        host.listen(8, 15);
+        host.send(&[b'U', b'0', 0x02, 1, 0]);
+        host.unlisten();
+        host.burst_send(&block);
+        host.burst_receive(1);
+        run(&mut bus, &mut host, &mut device);
+        assert_eq!(host.take_error(), Some(BusError::Timeout));
+        assert_eq!(device.device().drive().status().code, 31);
+    }
+}
@@ -0,0 +1,268 @@
+//! The JiffyDOS fast path of the serial bus.
+//!
+//! A JiffyDOS computer announces itself while sending the `LISTEN` or
+//! `TALK` byte under ATN: before bit 7 it holds CLK for 400µs with DATA
+//! released. A JiffyDOS drive recognising its own address in bits 0-6 pulls
+//! DATA for 100µs in that pause. If the computer saw the pulse, the data
+//! bytes up to `UNLISTEN` or `UNTALK` use the Jiffy transfer; anything else
+//! stays standard, so either side falls back when the other lacks Jiffy.
+//!
+//! A Jiffy byte moves as four bit pairs on CLK and DATA in fixed time
+//! windows instead of a handshake per bit:
+//!
+//! 1. The talker releases CLK when it has a byte.
+//! 2. The listener releases DATA when it is ready; this edge starts the
+//!    clock for both sides.
+//! 3. From 12µs on, the talker puts a bit pair on (CLK, DATA) every 11µs,
+//!    released meaning 1. Bytes for the drive travel as bits (4,5), (6,7),
+//!    (3,1), (2,0), bytes from the drive as (0,1), (2,3), (4,5), (6,7).
+//! 4. In the fifth window the talker pulls CLK, and DATA too on the last
+//!    byte. A released CLK there means the talker gave up.
+//! 5. The listener samples each window in its middle and then pulls DATA,
+//!    which the talker, holding CLK, takes as acknowledgement.
+//!
+//! Both sides have to be polled at least every 2µs during Jiffy transfers.
+
+use super::Lines;
+use crate::bus::{BusError, Step};
+
+/// How long a JiffyDOS computer pauses before bit 7 of an address.
+pub(super) const PROBE_PAUSE: u64 = 400;
+/// How long a JiffyDOS drive waits into the pause before answering.
+pub(super) const PROBE_DETECT: u64 = 200;
+/// How long a JiffyDOS drive pulls DATA to answer.
+pub(super) const PROBE_ANSWER: u64 = 100;
+
+/// Time from the listener's ready edge to the first window.
+const LEAD: u64 = 12;
+/// The length of a window.
+const WINDOW: u64 = 11;
+/// How long the talker waits for the acknowledgement.
+const ACK_TIMEOUT: u64 = 1000;
+
+/// The (CLK, DATA) bits of the four windows.
+pub(super) type Order = [(u8, u8); 4];
+
+/// Bit order of bytes sent to the drive.
+pub(super) const TO_DRIVE: Order = [(4, 5), (6, 7), (3, 1), (2, 0)];
+/// Bit order of bytes sent by the drive.
+pub(super) const FROM_DRIVE: Order = [(0, 1), (2, 3), (4, 5), (6, 7)];
+
+/// Returns whether `byte` sent under ATN carries the Jiffy probe.
+pub(super) fn is_address(byte: u8) -> bool {
+    matches!(byte & 0x60, 0x20 | 0x40) && byte & 0x1F != 0x1F
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendPhase {
+    /// CLK released, waiting for the listener's ready edge.
+    Ready,
+    /// Putting out window `n`, or the lead-in while `None`.
+    Window { start: u64, n: Option<u8> },
+    /// CLK pulled, waiting for the listener to pull DATA.
+    Acknowledge { since: u64 },
+}
+
+/// The talker's half of one Jiffy byte.
+#[derive(Debug, Clone)]
+pub(super) struct JiffySender {
+    pub(super) byte: u8,
+    pub(super) eoi: bool,
+    order: Order,
+    phase: SendPhase,
+}
+
+impl JiffySender {
+    pub(super) fn new(byte: u8, eoi: bool, order: Order) -> Self {
+        JiffySender {
+            byte,
+            eoi,
+            order,
+            phase: SendPhase::Ready,
+        }
+    }
+
+    /// Returns whether CLK and DATA are pulled.
+    pub(super) fn output(&self) -> (bool, bool) {
+        let pulled = |bit: u8| self.byte >> bit & 1 == 0;
+        match self.phase {
+            SendPhase::Ready | SendPhase::Window { n: None, .. } => (false, false),
+            SendPhase::Window { n: Some(4), .. } => (true, self.eoi),
+            SendPhase::Window { n: Some(n), .. } => {
+                let (clk, data) = self.order[n as usize];
+                (pulled(clk), pulled(data))
+            }
+            SendPhase::Acknowledge { .. } => (true, false),
+        }
+    }
+
+    pub(super) fn step(&mut self, lines: Lines, now: u64) -> Step<()> {
+        match self.phase {
+            SendPhase::Ready if !lines.data => {
+                self.phase = SendPhase::Window {
+                    start: now,
+                    n: None,
+                };
+            }
+            SendPhase::Window { start, .. } => {
+                let elapsed = now - start;
+                self.phase = match elapsed.checked_sub(LEAD).map(|t| t / WINDOW) {
+                    None => SendPhase::Window { start, n: None },
+                    Some(n @ 0..=4) => SendPhase::Window {
+                        start,
+                        n: Some(n as u8),
+                    },
+                    Some(_) => SendPhase::Acknowledge { since: now },
+                };
+            }
+            SendPhase::Acknowledge { .. } if lines.data => return Step::Done(()),
+            SendPhase::Acknowledge { since } if now - since > ACK_TIMEOUT => {
+                return Step::Failed(BusError::Timeout);
+            }
+            _ => {}
+        }
+        Step::Pending
+    }
+}
+
+/// The listener's half of one Jiffy byte.
+#[derive(Debug, Clone)]
+pub(super) struct JiffyReceiver {
+    order: Order,
+    /// The ready edge, once the talker has a byte.
+    start: Option<u64>,
+    window: u8,
+    value: u8,
+}
+
+impl JiffyReceiver {
+    pub(super) fn new(order: Order) -> Self {
+        JiffyReceiver {
+            order,
+            start: None,
+            window: 0,
+            value: 0,
+        }
+    }
+
+    /// Returns whether DATA is pulled.
+    pub(super) fn output(&self) -> bool {
+        self.start.is_none()
+    }
+
+    pub(super) fn step(&mut self, lines: Lines, now: u64) -> Step<(u8, bool)> {
+        let Some(start) = self.start else {
+            if !lines.clk {
+                self.start = Some(now);
+            }
+            return Step::Pending;
+        };
+        let sample = LEAD + WINDOW * u64::from(self.window) + WINDOW / 2;
+        if now - start < sample {
+            return Step::Pending;
+        }
+        if let Some(&(clk, data)) = self.order.get(self.window as usize) {
+            self.value |= u8::from(!lines.clk) << clk | u8::from(!lines.data) << data;
+            self.window += 1;
+            return Step::Pending;
+        }
+        if !lines.clk {
+            return Step::Failed(BusError::Timeout);
+        }
+        Step::Done((self.value, lines.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::d64::D64;
+    use crate::drive::{Drive, VirtualDrive};
+    use crate::iec::{IecDevice, IecHost, WiredBus};
+    use crate::wedge::Wedge;
+
+    /// Runs the host and one device at 1µs steps and returns the time taken.
+    fn run(bus: &mut WiredBus, host: &mut IecHost, device: &mut IecDevice<Drive<D64>>) -> u64 {
+        let mut now = 0;
+        while !host.is_idle() {
+            host.poll(&mut bus.port(0), now);
+            device.poll(&mut bus.port(1), now);
+            now += 1;
+            assert!(now < 10_000_000, "bus hung");
+        }
+        now
+    }
+
+    fn load(host_jiffy: bool, drive_jiffy: bool) -> (Vec<u8>, u64, bool) {
+        let mut wedge = Wedge::mount(D64::new(35));
+        wedge.command("N:JIFFY,01").unwrap();
+        let program: Vec<u8> = (0..=255).collect();
+        wedge.save("PROG", 0x0801, &program).unwrap();
+        let mut device = IecDevice::new(wedge.into_inner(), 8);
+        device.set_jiffy(drive_jiffy);
+        let mut host = IecHost::new();
+        host.set_jiffy(host_jiffy);
+        let mut bus = WiredBus::new();
+        bus.attach();
+        bus.attach();
+
+        host.open(8, 0, b"PROG");
+        let mut time = run(&mut bus, &mut host, &mut device);
+        host.talk(8, 0);
+        time += run(&mut bus, &mut host, &mut device);
+        let active = host.jiffy_active();
+        host.untalk();
+        host.close(8, 0);
+        run(&mut bus, &mut host, &mut device);
+        assert_eq!(host.take_error(), None);
+        (host.take_received(), time, active)
+    }
+
+    #[test]
+    fn loads_faster_and_falls_back() {
+        let (standard, standard_time, active) = load(false, false);
+        assert!(!active);
+        assert_eq!(standard.len(), 258);
+        assert_eq!(standard[2..], (0..=255).collect::<Vec<u8>>());
+
+        let (fast, fast_time, active) = load(true, true);
+        assert!(active);
+        assert_eq!(fast, standard);
+        assert!(fast_time * 3 < standard_time);
+
+        for (host, drive) in [(true, false), (false, true)] {
+            let (data, _, active) = load(host, drive);
+            assert!(!active);
+            assert_eq!(data, standard);
+        }
+    }
+
+    #[test]
+    fn saves_and_reads_status_over_jiffy() {
+        let mut device = IecDevice::new(Drive::new(D64::new(35)), 9);
+        device.set_jiffy(true);
+        let mut host = IecHost::new();
+        host.set_jiffy(true);
+        let mut bus = WiredBus::new();
+        bus.attach();
+        bus.attach();
+
+        host.listen(9, 15);
+        host.send(b"N:FAST,01");
+        host.unlisten();
+        host.open(9, 1, b"DATA,S,W");
+        host.listen(9, 1);
+        host.send(b"JIFFY\r");
+        host.unlisten();
+        host.close(9, 1);
+        host.talk(9, 15);
+        run(&mut bus, &mut host, &mut device);
+        assert!(host.jiffy_active());
+        host.untalk();
+        run(&mut bus, &mut host, &mut device);
+        assert!(!host.jiffy_active());
+        assert_eq!(host.take_error(), None);
+        assert_eq!(host.take_received(), b"00, OK,00,00\r");
+        let mut drive = device.into_inner();
+        assert_eq!(drive.load(b"DATA,S").unwrap(), b"JIFFY\r");
+    }
+}
@@ -0,0 +1,584 @@
+//! The IEEE-488 (GPIB) bus of the PET and its drives.
+//!
+//! The 2031, 4040, 8050 and 8250 sit on the parallel bus the PET inherited
+//! from lab equipment: eight data lines plus a three-wire handshake, all
+//! open-collector with the asserted state low. Each byte goes through the
+//! same steps, whatever the speed of the participants:
+//!
+//! 1. Each listener releases NRFD (not ready for data) when it is ready and
+//!    holds NDAC (not data accepted).
+//! 2. Once NRFD is released by every listener, the talker puts the byte on
+//!    DIO1-8, EOI on the last byte, and asserts DAV (data valid).
+//! 3. Each listener pulls NRFD, takes the byte and releases NDAC.
+//! 4. Once NDAC is released by every listener, the talker releases DAV, and
+//!    the listeners pull NDAC again.
+//!
+//! With ATN asserted every device listens for the commands of
+//! [`crate::bus`]. There is no turnaround: after `TALK` the computer simply
+//! becomes the listener. A talker that finds both NRFD and NDAC released
+//! has no listener, which the computer reports as device not present.
+//!
+//! [`IeeeHost`] and [`IeeeDevice`] are driven by `poll` with the time in
+//! microseconds, like their counterparts in [`crate::iec`], and see the
+//! lines through [`IeeePins`]. As the bus has no timing, polling often only
+//! makes transfers faster.
+
+use crate::bus::{self, BusDevice, BusError, Operation, Operations, Role, Step, WiredLines};
+use crate::drive::VirtualDrive;
+
+/// How long a talker accepts having no listener.
+const PRESENCE_TIMEOUT: u64 = 100;
+/// How long the PET waits for a talker or listener to go on, 64ms.
+const HANDSHAKE_TIMEOUT: u64 = 64_000;
+
+/// The state of the bus lines, `true` and set bits meaning asserted (low).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Lines {
+    /// DIO1-8, bit 0 being DIO1.
+    pub dio: u8,
+    /// Data valid, driven by the talker.
+    pub dav: bool,
+    /// Not ready for data, driven by the listeners.
+    pub nrfd: bool,
+    /// Not data accepted, driven by the listeners.
+    pub ndac: bool,
+    /// End or identify, marking the last byte.
+    pub eoi: bool,
+    /// Attention, driven by the computer.
+    pub atn: bool,
+    /// Interface clear, driven by the computer to reset every device.
+    pub ifc: bool,
+    /// Service request.
+    pub srq: bool,
+    /// Remote enable.
+    pub ren: bool,
+}
+
+impl WiredLines for Lines {
+    fn or(self, other: Lines) -> Lines {
+        Lines {
+            dio: self.dio | other.dio,
+            dav: self.dav || other.dav,
+            nrfd: self.nrfd || other.nrfd,
+            ndac: self.ndac || other.ndac,
+            eoi: self.eoi || other.eoi,
+            atn: self.atn || other.atn,
+            ifc: self.ifc || other.ifc,
+            srq: self.srq || other.srq,
+            ren: self.ren || other.ren,
+        }
+    }
+}
+
+/// Access to the bus lines from one participant.
+pub trait IeeePins {
+    /// Returns the state of the bus, including the lines this side pulls.
+    fn read(&mut self) -> Lines;
+
+    /// Sets the lines this side pulls.
+    fn write(&mut self, lines: Lines);
+}
+
+/// An emulated IEEE-488 bus joining any number of participants.
+pub type WiredBus = bus::WiredBus<Lines>;
+
+/// One participant's connection to a [`WiredBus`].
+pub type Port<'a> = bus::Port<'a, Lines>;
+
+impl IeeePins for Port<'_> {
+    fn read(&mut self) -> Lines {
+        self.lines()
+    }
+
+    fn write(&mut self, lines: Lines) {
+        self.pull(lines);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendPhase {
+    /// Waiting for the listeners to release NRFD.
+    Ready,
+    /// The byte on DIO, DAV still released.
+    Setup,
+    /// DAV asserted, waiting for the listeners to release NDAC.
+    Valid,
+}
+
+/// The talker's half of one byte.
+#[derive(Debug, Clone)]
+struct Sender {
+    byte: u8,
+    eoi: bool,
+    phase: SendPhase,
+    since: u64,
+}
+
+impl Sender {
+    fn new(byte: u8, eoi: bool, now: u64) -> Self {
+        Sender {
+            byte,
+            eoi,
+            phase: SendPhase::Ready,
+            since: now,
+        }
+    }
+
+    fn output(&self) -> Lines {
+        if self.phase == SendPhase::Ready {
+            return Lines::default();
+        }
+        Lines {
+            dio: self.byte,
+            dav: self.phase == SendPhase::Valid,
+            eoi: self.eoi,
+            ..Lines::default()
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Step<()> {
+        let elapsed = now.saturating_sub(self.since);
+        let next = match self.phase {
+            SendPhase::Ready | SendPhase::Valid
+                if !lines.nrfd && !lines.ndac && elapsed > PRESENCE_TIMEOUT =>
+            {
+                return Step::Failed(BusError::DeviceNotPresent);
+            }
+            SendPhase::Ready if !lines.nrfd && lines.ndac => SendPhase::Setup,
+            SendPhase::Setup => SendPhase::Valid,
+            SendPhase::Valid if !lines.ndac => return Step::Done(()),
+            SendPhase::Valid if elapsed > HANDSHAKE_TIMEOUT => {
+                return Step::Failed(BusError::Timeout);
+            }
+            _ => return Step::Pending,
+        };
+        self.phase = next;
+        self.since = now;
+        Step::Pending
+    }
+}
+
+/// The listener's half of one byte.
+#[derive(Debug, Clone)]
+struct Receiver {
+    /// The byte taken while DAV is asserted.
+    taken: Option<(u8, bool)>,
+    since: u64,
+}
+
+impl Receiver {
+    fn new(now: u64) -> Self {
+        Receiver {
+            taken: None,
+            since: now,
+        }
+    }
+
+    fn output(&self) -> Lines {
+        Lines {
+            nrfd: self.taken.is_some(),
+            ndac: self.taken.is_none(),
+            ..Lines::default()
+        }
+    }
+
+    fn step(&mut self, lines: Lines) -> Step<(u8, bool)> {
+        match self.taken {
+            None if lines.dav => self.taken = Some((lines.dio, lines.eoi)),
+            Some(taken) if !lines.dav => return Step::Done(taken),
+            _ => {}
+        }
+        Step::Pending
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+enum HostState {
+    #[default]
+    Idle,
+    Sending(Sender),
+    Receiving(Receiver),
+}
+
+/// The computer side of the bus.
+///
+/// It queues the same operations as [`crate::iec::IecHost`] and reports
+/// failures the same way.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::Drive;
+/// use cbm_dos::ieee488::{IeeeDevice, IeeeHost, WiredBus};
+/// use cbm_dos::model::DriveModel;
+///
+/// let mut bus = WiredBus::new();
+/// let (h, d) = (bus.attach(), bus.attach());
+/// let mut host = IeeeHost::new();
+/// let drive = Drive::with_model(D64::new(35), DriveModel::C2031);
+/// let mut drive = IeeeDevice::new(drive, 8);
+///
+/// host.talk(8, 15);
+/// host.untalk();
+/// let mut now = 0;
+/// while !host.is_idle() {
+///     host.poll(&mut bus.port(h), now);
+///     drive.poll(&mut bus.port(d), now);
+///     now += 1;
+/// }
+/// assert!(host.take_received().starts_with(b"73,"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IeeeHost {
+    queue: Operations,
+    state: HostState,
+    pending: Vec<u8>,
+    attention: bool,
+    received: Vec<u8>,
+    error: Option<BusError>,
+}
+
+impl IeeeHost {
+    /// Creates a host with all lines released.
+    pub fn new() -> Self {
+        IeeeHost::default()
+    }
+
+    /// Queues `LISTEN device` for data on `secondary`.
+    pub fn listen(&mut self, device: u8, secondary: u8) {
+        self.queue.listen(device, secondary);
+    }
+
+    /// Queues `UNLISTEN`.
+    pub fn unlisten(&mut self) {
+        self.queue.unlisten();
+    }
+
+    /// Queues `TALK device` on `secondary` and the reception of everything
+    /// the device sends up to EOI.
+    pub fn talk(&mut self, device: u8, secondary: u8) {
+        self.queue.talk(device, secondary);
+    }
+
+    /// Queues `UNTALK`.
+    pub fn untalk(&mut self) {
+        self.queue.untalk();
+    }
+
+    /// Queues opening `secondary` on `device` with `name`.
+    pub fn open(&mut self, device: u8, secondary: u8, name: &[u8]) {
+        self.queue.open(device, secondary, name);
+    }
+
+    /// Queues closing `secondary` on `device`.
+    pub fn close(&mut self, device: u8, secondary: u8) {
+        self.queue.close(device, secondary);
+    }
+
+    /// Queues data for the listening devices, with EOI on the last byte.
+    pub fn send(&mut self, data: &[u8]) {
+        self.queue.send(data);
+    }
+
+    /// Returns `true` once every queued operation has finished.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && matches!(self.state, HostState::Idle) && !self.attention
+    }
+
+    /// Returns and clears the bytes received so far.
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Returns and clears the error that stopped the queue.
+    pub fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+
+    /// Advances the host to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl IeeePins, now: u64) {
+        let lines = pins.read();
+        if let Err(error) = self.step(lines, now) {
+            self.queue.clear();
+            self.pending.clear();
+            self.state = HostState::Idle;
+            self.attention = false;
+            self.error = Some(error);
+        }
+        pins.write(self.output());
+    }
+
+    fn output(&self) -> Lines {
+        let lines = match &self.state {
+            HostState::Idle => Lines::default(),
+            HostState::Sending(sender) => sender.output(),
+            HostState::Receiving(receiver) => receiver.output(),
+        };
+        Lines {
+            atn: self.attention,
+            ..lines
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Result<(), BusError> {
+        match &mut self.state {
+            HostState::Idle => match self.queue.pop() {
+                Some(Operation::Attention(bytes)) => self.start(bytes, true, now),
+                Some(Operation::Send(bytes)) => self.start(bytes, false, now),
+                Some(Operation::Receive) => {
+                    self.attention = false;
+                    self.state = HostState::Receiving(Receiver::new(now));
+                }
+                None => self.attention = false,
+            },
+            HostState::Sending(sender) => match sender.step(lines, now) {
+                Step::Done(()) => self.next_byte(now),
+                Step::Failed(error) => return Err(error),
+                Step::Pending => {}
+            },
+            HostState::Receiving(receiver) => match receiver.step(lines) {
+                Step::Done((byte, eoi)) => {
+                    self.received.push(byte);
+                    self.state = if eoi {
+                        HostState::Idle
+                    } else {
+                        HostState::Receiving(Receiver::new(now))
+                    };
+                }
+                Step::Failed(error) => return Err(error),
+                Step::Pending if now - receiver.since > HANDSHAKE_TIMEOUT => {
+                    return Err(BusError::Timeout);
+                }
+                Step::Pending => {}
+            },
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, mut bytes: Vec<u8>, attention: bool, now: u64) {
+        bytes.reverse();
+        self.pending = bytes;
+        self.attention = attention;
+        self.next_byte(now);
+    }
+
+    /// Starts the next pending byte, or finishes the current operation.
+    /// ATN stays asserted until the next operation starts or the queue runs
+    /// empty.
+    fn next_byte(&mut self, now: u64) {
+        self.state = match self.pending.pop() {
+            Some(byte) => {
+                let eoi = !self.attention && self.pending.is_empty();
+                HostState::Sending(Sender::new(byte, eoi, now))
+            }
+            None => HostState::Idle,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DeviceState {
+    Idle,
+    /// ATN asserted: receiving commands.
+    Attention(Receiver),
+    Listening(Receiver),
+    Talking(Sender),
+}
+
+/// A drive attached to the IEEE-488 bus.
+///
+/// IFC returns the device to idle as if it had been sent `UNLISTEN` and
+/// `UNTALK`.
+#[derive(Debug, Clone)]
+pub struct IeeeDevice<D> {
+    device: BusDevice<D>,
+    state: DeviceState,
+}
+
+impl<D: VirtualDrive> IeeeDevice<D> {
+    /// Attaches `drive` under device number `address`.
+    pub fn new(drive: D, address: u8) -> Self {
+        IeeeDevice {
+            device: BusDevice::new(drive, address),
+            state: DeviceState::Idle,
+        }
+    }
+
+    /// Returns the command layer.
+    pub fn device(&self) -> &BusDevice<D> {
+        &self.device
+    }
+
+    /// Returns the command layer for modification.
+    pub fn device_mut(&mut self) -> &mut BusDevice<D> {
+        &mut self.device
+    }
+
+    /// Detaches the drive.
+    pub fn into_inner(self) -> D {
+        self.device.into_inner()
+    }
+
+    /// Advances the device to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl IeeePins, now: u64) {
+        let lines = pins.read();
+        self.step(lines, now);
+        pins.write(self.output());
+    }
+
+    fn output(&self) -> Lines {
+        match &self.state {
+            DeviceState::Idle => Lines::default(),
+            DeviceState::Attention(receiver) | DeviceState::Listening(receiver) => {
+                receiver.output()
+            }
+            DeviceState::Talking(sender) => sender.output(),
+        }
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) {
+        if lines.ifc {
+            self.device.attention(0x3F);
+            self.device.attention(0x5F);
+            self.state = DeviceState::Idle;
+            return;
+        }
+        if lines.atn && !matches!(self.state, DeviceState::Attention(_)) {
+            self.state = DeviceState::Attention(Receiver::new(now));
+            return;
+        }
+        match &mut self.state {
+            DeviceState::Idle => {}
+            DeviceState::Attention(receiver) => match receiver.step(lines) {
+                Step::Done((byte, _)) => {
+                    self.device.attention(byte);
+                    self.state = DeviceState::Attention(Receiver::new(now));
+                }
+                Step::Pending if !lines.atn && receiver.taken.is_none() => {
+                    match self.device.role() {
+                        Role::Listener => {
+                            self.state = DeviceState::Listening(Receiver::new(now));
+                        }
+                        Role::Talker => self.talk(now),
+                        Role::Idle => self.state = DeviceState::Idle,
+                    }
+                }
+                _ => {}
+            },
+            DeviceState::Listening(receiver) => {
+                if let Step::Done((byte, _)) = receiver.step(lines) {
+                    self.device.receive(byte);
+                    self.state = DeviceState::Listening(Receiver::new(now));
+                }
+            }
+            DeviceState::Talking(sender) => match sender.step(lines, now) {
+                Step::Done(()) if sender.eoi => self.state = DeviceState::Idle,
+                Step::Done(()) => self.talk(now),
+                Step::Failed(_) => self.state = DeviceState::Idle,
+                Step::Pending => {}
+            },
+        }
+    }
+
+    /// Starts sending the next byte, or falls silent when the channel has
+    /// nothing, which the host sees as a timeout.
+    fn talk(&mut self, now: u64) {
+        self.state = match self.device.send() {
+            Some((byte, eoi)) => DeviceState::Talking(Sender::new(byte, eoi, now)),
+            None => DeviceState::Idle,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::Drive;
+    use crate::wedge::Wedge;
+
+    fn run(bus: &mut WiredBus, host: &mut IeeeHost, devices: &mut [IeeeDevice<Drive<D64>>]) {
+        let mut now = 0;
+        while !host.is_idle() {
+            host.poll(&mut bus.port(0), now);
+            for (index, device) in devices.iter_mut().enumerate() {
+                device.poll(&mut bus.port(index + 1), now);
+            }
+            now += 5;
+            assert!(now < 10_000_000, "bus hung");
+        }
+    }
+
+    fn drive(address: u8) -> IeeeDevice<Drive<D64>> {
+        let mut wedge = Wedge::mount(D64::new(35));
+        wedge.command("N:PARALLEL,01").unwrap();
+        wedge.save("PROG", 0x0401, &[0x0B, 0x04, 0x0A]).unwrap();
+        IeeeDevice::new(wedge.into_inner(), address)
+    }
+
+    #[test]
+    fn transfers_files_between_two_drives() {
+        let mut bus = WiredBus::new();
+        let mut devices = [drive(8), drive(9)];
+        for _ in 0..=devices.len() {
+            bus.attach();
+        }
+        let mut host = IeeeHost::new();
+
+        host.open(8, 0, b"PROG");
+        host.talk(8, 0);
+        host.untalk();
+        host.close(8, 0);
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), None);
+        let program = host.take_received();
+        assert_eq!(program, [0x01, 0x04, 0x0B, 0x04, 0x0A]);
+
+        host.open(9, 1, b"COPY");
+        host.listen(9, 1);
+        host.send(&program);
+        host.unlisten();
+        host.close(9, 1);
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), None);
+        let mut wedge = Wedge::new(devices[1].device().drive().clone());
+        assert_eq!(
+            wedge.load("COPY").unwrap(),
+            (0x0401, vec![0x0B, 0x04, 0x0A])
+        );
+        assert!(
+            Wedge::new(devices[0].device().drive().clone())
+                .load("COPY")
+                .is_err()
+        );
+        assert_eq!(bus.lines(), Lines::default());
+    }
+
+    #[test]
+    fn reports_absent_devices_and_silent_talkers() {
+        let mut bus = WiredBus::new();
+        let mut devices = [drive(8)];
+        for _ in 0..=devices.len() {
+            bus.attach();
+        }
+        let mut host = IeeeHost::new();
+
+        host.listen(9, 15);
+        host.send(b"I");
+        host.unlisten();
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), Some(BusError::DeviceNotPresent));
+
+        host.open(8, 2, b"MISSING");
+        host.talk(8, 2);
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), Some(BusError::Timeout));
+
+        host.untalk();
+        host.talk(8, 15);
+        host.untalk();
+        run(&mut bus, &mut host, &mut devices);
+        assert_eq!(host.take_error(), None);
+        assert!(host.take_received().starts_with(b"62,"));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::error::DosError;
+use alloc::boxed::Box;
+use core::fmt;
+
+/// Size of a logical CBM DOS block in bytes.
+pub const SECTOR_SIZE: usize = 256;
+
+/// The contents of one logical block.
+pub type Sector = [u8; SECTOR_SIZE];
+
+/// Block-level access to a Commodore disk.
+///
+/// A `BlockDevice` exposes the logical 256-byte sectors of a disk addressed
+/// by track (starting at 1) and sector (starting at 0), its geometry and
+/// the read errors recorded for its sectors. The filesystem layer in
+/// [`crate::fs`] is written against this trait, so any backend that can
+/// produce sectors carries files.
+///
+/// [`crate::d64::D64`] holds the blocks as they are, [`crate::g64::G64`]
+/// decodes them from its GCR tracks, and `opencbm::RealDisk` (with the
+/// `opencbm` feature), the network images of `remote` and `http` and
+/// `backed::Backed` read them from a drive, a server or other storage.
+/// Wrappers such as [`ReadOnly`] and [`crate::overlay::Overlay`] stack on
+/// any of them.
+///
+/// Reading sector data never fails because of a recorded read error; devices
+/// that carry per-sector error information report it separately through
+/// [`BlockDevice::sector_error`], leaving it to the caller to decide whether
+/// the data is usable.
+pub trait BlockDevice {
+    /// Returns the number of tracks on the disk.
+    fn tracks(&self) -> u8;
+
+    /// Returns the number of sectors on `track`, or `0` if the track does not
+    /// exist.
+    fn sectors_per_track(&self, track: u8) -> u8;
+
+    /// Reads the sector at `track`/`sector`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::IllegalTrackSector`] if the location does not exist.
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError>;
+
+    /// Overwrites the sector at `track`/`sector` with `data`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::IllegalTrackSector`] if the location does not exist.
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError>;
+
+    /// Returns the read error recorded for a sector, if any.
+    ///
+    /// The default implementation reports no errors.
+    fn sector_error(&self, _track: u8, _sector: u8) -> Option<DosError> {
+        None
+    }
+
+    /// Returns `true` if `track`/`sector` exists on this disk.
+    fn contains(&self, track: u8, sector: u8) -> bool {
+        track >= 1 && track <= self.tracks() && sector < self.sectors_per_track(track)
+    }
+
+    /// Returns the total number of sectors on the disk.
+    fn total_sectors(&self) -> usize {
+        (1..=self.tracks())
+            .map(|t| self.sectors_per_track(t) as usize)
+            .sum()
+    }
+}
+
+/// A disk that can be mounted in a drive.
+///
+/// Besides its blocks, a drive has to know whether the disk takes writes
+/// at all. The virtual drive in [`crate::drive`] and the tools built on it
+/// are written against this trait, and every block device of the crate
+/// implements it.
+pub trait DiskImage: BlockDevice {
+    /// Returns `true` if every write to the image fails.
+    ///
+    /// A [`crate::drive::Drive`] treats such a disk as write protected.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+    fn tracks(&self) -> u8 {
+        (**self).tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        (**self).sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        (**self).read_sector(track, sector)
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        (**self).write_sector(track, sector, data)
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        (**self).sector_error(track, sector)
+    }
+}
+
+impl<T: DiskImage + ?Sized> DiskImage for Box<T> {
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+/// A disk image opened read-only.
+///
+/// Reads pass through to the image, every write fails with
+/// [`DosError::WriteProtect`]. The file system layer hands that error on
+/// before it changes anything, and a [`crate::drive::Drive`] holding the
+/// image reports 26, write protect on, for every command, file and job
+/// that would write, so an archive master cannot be changed by accident.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadOnly<I>(I);
+
+impl<I: DiskImage> ReadOnly<I> {
+    /// Opens `image` read-only.
+    pub fn new(image: I) -> Self {
+        ReadOnly(image)
+    }
+
+    /// Returns the image.
+    pub fn get_ref(&self) -> &I {
+        &self.0
+    }
+
+    /// Returns the image, writable again.
+    pub fn into_inner(self) -> I {
+        self.0
+    }
+}
+
+impl<I: BlockDevice> BlockDevice for ReadOnly<I> {
+    fn tracks(&self) -> u8 {
+        self.0.tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        self.0.sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        self.0.read_sector(track, sector)
+    }
+
+    fn write_sector(&mut self, _track: u8, _sector: u8, _data: &Sector) -> Result<(), DosError> {
+        Err(DosError::WriteProtect)
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.0.sector_error(track, sector)
+    }
+}
+
+impl<I: DiskImage> DiskImage for ReadOnly<I> {
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// An error raised while parsing or building an image container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The input length does not match any size the format allows.
+    InvalidSize(usize),
+    /// The input does not start with the signature of the format.
+    InvalidSignature,
+    /// A structure extends beyond the end of the input.
+    Truncated,
+    /// The input uses a feature of the format that is not supported.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::InvalidSize(size) => write!(f, "invalid image size: {size} bytes"),
+            ImageError::InvalidSignature => write!(f, "invalid image signature"),
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::Unsupported(feature) => write!(f, "unsupported feature: {feature}"),
+        }
+    }
+}
+
+impl core::error::Error for ImageError {}
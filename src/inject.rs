@@ -0,0 +1,189 @@
+//! Declarative read-error injection.
+//!
+//! An [`ErrorMap`] describes which sectors of a disk should fail to read and
+//! with which DOS error, e.g. "sector 18/4 returns 23, track 36 returns 21".
+//! The map can be written into the error block of a D64, laid over any
+//! [`DiskImage`] with [`WithErrors`], or rendered into a G64 whose tracks
+//! carry the physical defects that make a real drive report those errors.
+
+use crate::d64::{D64, error_to_byte};
+use crate::error::DosError;
+use crate::g64::G64;
+use crate::image::{BlockDevice, DiskImage, Sector};
+use std::collections::BTreeMap;
+
+/// Read errors assigned to sectors and whole tracks.
+///
+/// Errors given for a single sector take precedence over an error given for
+/// its track.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::error::DosError;
+/// use cbm_dos::image::BlockDevice;
+/// use cbm_dos::inject::ErrorMap;
+///
+/// let errors = ErrorMap::new()
+///     .sector(18, 4, DosError::DataChecksum)
+///     .track(36, DosError::NoSync);
+///
+/// let mut image = D64::new(40);
+/// errors.apply(&mut image).unwrap();
+/// assert_eq!(image.sector_error(18, 4), Some(DosError::DataChecksum));
+/// assert_eq!(image.sector_error(36, 16), Some(DosError::NoSync));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorMap {
+    sectors: BTreeMap<(u8, u8), DosError>,
+    tracks: BTreeMap<u8, DosError>,
+}
+
+impl ErrorMap {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `track`/`sector` report `error`.
+    pub fn sector(mut self, track: u8, sector: u8, error: DosError) -> Self {
+        self.sectors.insert((track, sector), error);
+        self
+    }
+
+    /// Makes every sector of `track` report `error`.
+    pub fn track(mut self, track: u8, error: DosError) -> Self {
+        self.tracks.insert(track, error);
+        self
+    }
+
+    /// Returns the error assigned to `track`/`sector`, if any.
+    pub fn error_at(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.sectors
+            .get(&(track, sector))
+            .or_else(|| self.tracks.get(&track))
+            .copied()
+    }
+
+    /// Returns `true` if no errors are assigned.
+    pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty() && self.tracks.is_empty()
+    }
+
+    /// Writes the map into the error block of `image`, creating the block if
+    /// the image has none. Sectors not mentioned keep their current entry.
+    ///
+    /// # Errors
+    /// - [`DosError::IllegalTrackSector`] if the map refers to a location
+    ///   outside the image.
+    /// - [`DosError::Syntax`] if an error has no error-block representation;
+    ///   only the read and write errors 20-29 and 74 do.
+    pub fn apply(&self, image: &mut D64) -> Result<(), DosError> {
+        let all = self
+            .sectors
+            .values()
+            .chain(self.tracks.values())
+            .copied();
+        for error in all {
+            if error_to_byte(error).is_none() {
+                return Err(DosError::Syntax);
+            }
+        }
+        for (&track, &error) in &self.tracks {
+            if image.sectors_per_track(track) == 0 {
+                return Err(DosError::IllegalTrackSector);
+            }
+            for sector in 0..image.sectors_per_track(track) {
+                image.set_sector_error(track, sector, Some(error))?;
+            }
+        }
+        for (&(track, sector), &error) in &self.sectors {
+            image.set_sector_error(track, sector, Some(error))?;
+        }
+        Ok(())
+    }
+
+    /// Renders `image` into a G64 whose tracks physically carry the errors
+    /// of this map, in addition to any errors `image` already reports.
+    pub fn to_g64<I: DiskImage + ?Sized>(&self, image: &I) -> G64 {
+        G64::from_image(&WithErrors { image, errors: self })
+    }
+}
+
+/// A disk image viewed with additional read errors.
+///
+/// Sector data passes through unchanged while [`BlockDevice::sector_error`]
+/// reports the errors of the map, falling back to those of the underlying
+/// image.
+#[derive(Debug)]
+pub struct WithErrors<'a, I: ?Sized> {
+    pub image: &'a I,
+    pub errors: &'a ErrorMap,
+}
+
+impl<I: BlockDevice + ?Sized> BlockDevice for WithErrors<'_, I> {
+    fn tracks(&self) -> u8 {
+        self.image.tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        self.image.sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        self.image.read_sector(track, sector)
+    }
+
+    fn write_sector(&mut self, _track: u8, _sector: u8, _data: &Sector) -> Result<(), DosError> {
+        Err(DosError::WriteProtect)
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.errors
+            .error_at(track, sector)
+            .or_else(|| self.image.sector_error(track, sector))
+    }
+}
+
+impl<I: DiskImage + ?Sized> DiskImage for WithErrors<'_, I> {
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GCR;
+    use crate::fs;
+    use crate::track::{HEADER_GAP, HEADER_LENGTH, SYNC_LENGTH};
+
+    #[test]
+    fn rejects_unrepresentable_errors() {
+        let mut image = D64::new(35);
+        let missing = ErrorMap::new().track(36, DosError::NoSync);
+        assert_eq!(missing.apply(&mut image), Err(DosError::IllegalTrackSector));
+        let invalid = ErrorMap::new().sector(1, 0, DosError::FileNotFound);
+        assert_eq!(invalid.apply(&mut image), Err(DosError::Syntax));
+    }
+
+    #[test]
+    fn g64_carries_physical_defects() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"PROTECTED", Some(*b"PR")).unwrap();
+        let g64 = ErrorMap::new()
+            .sector(18, 1, DosError::DataChecksum)
+            .track(20, DosError::NoSync)
+            .to_g64(&image);
+
+        let track = g64.track(18).unwrap();
+        let sector_length = SYNC_LENGTH * 2 + HEADER_LENGTH + HEADER_GAP + 325 + 17;
+        let data_start = sector_length + SYNC_LENGTH * 2 + HEADER_LENGTH + HEADER_GAP;
+        let block = GCR::new().decode(&track[data_start..data_start + 325]).unwrap();
+        let checksum = block[1..257].iter().fold(0, |a, b| a ^ b);
+        assert_ne!(block[257], checksum);
+
+        assert!(!g64.track(20).unwrap().contains(&0xFF));
+        assert!(g64.track(19).unwrap().starts_with(&[0xFF; 5]));
+    }
+}
@@ -0,0 +1,803 @@
+//! Checking the structures of a disk.
+//!
+//! [`check`] goes over everything DOS keeps on a disk and reports what
+//! does not add up, rather than stopping at the first problem as the
+//! file operations of [`crate::fs`] do: blocks the BAM gets wrong, blocks
+//! two files claim, chains that loop or leave the disk, directory slots
+//! DOS cannot make sense of, sectors with read errors and BAM bits for
+//! sectors a track does not have. Each [`Issue`] has a [`Severity`], and
+//! the report is written as JSON through [`ToJson`].
+//!
+//! Where validating rebuilds the whole BAM, [`IntegrityReport::repairs`]
+//! turns each BAM bit and free count the report finds wrong into a
+//! [`Repair`], and [`repair`] applies the ones the caller picks, so blocks
+//! a protection keeps allocated can stay so while lost blocks are
+//! allocated again.
+//!
+//! GEOS files are followed through their info block and, for VLIR files,
+//! the record chains of their index block.
+
+use crate::d64::sectors_per_track;
+use crate::error::DosError;
+use crate::fs::{
+    self, BAM_SECTOR, Bam, DIR_SECTOR, DIR_TRACK, DirEntry, DirSlot, ENTRIES_PER_SECTOR,
+    ENTRY_SIZE, FileType, GeosType, NAME_LENGTH,
+};
+use crate::image::{BlockDevice, Sector};
+use crate::json::{ToJson, Writer};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+
+/// How much an [`Issue`] matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Worth knowing, but DOS works with it, as with blocks copy
+    /// protections keep allocated.
+    Info,
+    /// DOS works with it for now, but a validate or a later write changes
+    /// the disk in a way that may not be wanted.
+    Warning,
+    /// Data is lost already or will be by the next write.
+    Error,
+}
+
+impl Severity {
+    /// Returns the name of the severity, as the JSON form writes it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// What a block belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Owner {
+    /// The BAM or the directory chain.
+    Directory,
+    /// The file of the entry at `slot`, with the padded `name` it has
+    /// there.
+    File {
+        name: [u8; NAME_LENGTH],
+        slot: DirSlot,
+    },
+}
+
+/// A problem found by [`check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// A block of the BAM, the directory or a file cannot be read.
+    Unreadable {
+        owner: Owner,
+        track: u8,
+        sector: u8,
+        error: DosError,
+    },
+    /// A chain leads to a sector beyond the geometry of the disk: the
+    /// link of block `from`, or the start stored in the directory if
+    /// `from` is `None`.
+    BadLink {
+        owner: Owner,
+        from: Option<(u8, u8)>,
+        to: (u8, u8),
+    },
+    /// A chain comes back to `track`/`sector`, which it went through
+    /// before.
+    Loop { owner: Owner, track: u8, sector: u8 },
+    /// A block belongs to two owners.
+    CrossLink {
+        track: u8,
+        sector: u8,
+        first: Owner,
+        second: Owner,
+    },
+    /// A directory slot has a type byte DOS does not know.
+    InvalidType { slot: DirSlot, byte: u8 },
+    /// A file was never closed, and validating the disk removes it.
+    Unclosed { owner: Owner },
+    /// A file has not as many blocks as its entry says.
+    BlockCount {
+        owner: Owner,
+        stored: u16,
+        counted: u16,
+    },
+    /// A block in use is marked free, so the next file written may take it.
+    FreeButUsed { track: u8, sector: u8 },
+    /// A block nothing uses is marked used.
+    UsedButFree { track: u8, sector: u8 },
+    /// The free count of a track is not that of its bitmap.
+    FreeCount { track: u8, stored: u8, counted: u8 },
+    /// The BAM marks a sector free that the track does not have.
+    BeyondTrack { track: u8, sector: u8 },
+    /// A sector has a read error, such as one recorded in the error block
+    /// of a D64; `used` tells if a file or the directory needs it.
+    SectorError {
+        track: u8,
+        sector: u8,
+        error: DosError,
+        used: bool,
+    },
+}
+
+impl Issue {
+    /// Returns how much the issue matters.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Issue::Unreadable { .. }
+            | Issue::BadLink { .. }
+            | Issue::Loop { .. }
+            | Issue::CrossLink { .. }
+            | Issue::InvalidType { .. }
+            | Issue::FreeButUsed { .. }
+            | Issue::SectorError { used: true, .. } => Severity::Error,
+            Issue::Unclosed { .. }
+            | Issue::BlockCount { .. }
+            | Issue::FreeCount { .. }
+            | Issue::BeyondTrack { .. }
+            | Issue::SectorError { used: false, .. } => Severity::Warning,
+            Issue::UsedButFree { .. } => Severity::Info,
+        }
+    }
+
+    /// Returns the name of the kind of issue, as the JSON form writes it.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Issue::Unreadable { .. } => "unreadable",
+            Issue::BadLink { .. } => "bad_link",
+            Issue::Loop { .. } => "loop",
+            Issue::CrossLink { .. } => "cross_link",
+            Issue::InvalidType { .. } => "invalid_type",
+            Issue::Unclosed { .. } => "unclosed",
+            Issue::BlockCount { .. } => "block_count",
+            Issue::FreeButUsed { .. } => "free_but_used",
+            Issue::UsedButFree { .. } => "used_but_free",
+            Issue::FreeCount { .. } => "free_count",
+            Issue::BeyondTrack { .. } => "beyond_track",
+            Issue::SectorError { .. } => "sector_error",
+        }
+    }
+}
+
+/// What [`check`] found, in the order it went over the disk: the
+/// directory and its files first, then the BAM, then read errors.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub issues: Vec<Issue>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Returns the severity of the worst issue, or `None` if there is none.
+    pub fn worst(&self) -> Option<Severity> {
+        self.issues.iter().map(Issue::severity).max()
+    }
+
+    /// Returns the issues of `severity`.
+    pub fn with_severity(&self, severity: Severity) -> impl Iterator<Item = &Issue> + '_ {
+        self.issues.iter().filter(move |i| i.severity() == severity)
+    }
+
+    /// Returns the repairs that bring the BAM in line with what the disk
+    /// uses, one for each issue of the BAM, in the order of the issues.
+    pub fn repairs(&self) -> Vec<Repair> {
+        self.issues.iter().filter_map(Repair::fixing).collect()
+    }
+}
+
+/// A change to the BAM that [`repair`] makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Repair {
+    /// Mark a block that is in use as used.
+    Allocate { track: u8, sector: u8 },
+    /// Mark a block that nothing uses as free.
+    Free { track: u8, sector: u8 },
+    /// Clear the bit of a sector the track does not have.
+    Clear { track: u8, sector: u8 },
+    /// Set the free count of a track to the free blocks of its bitmap.
+    Recount { track: u8 },
+}
+
+impl Repair {
+    /// Returns the repair for `issue`, if it is an issue of the BAM.
+    pub fn fixing(issue: &Issue) -> Option<Repair> {
+        match *issue {
+            Issue::FreeButUsed { track, sector } => Some(Repair::Allocate { track, sector }),
+            Issue::UsedButFree { track, sector } => Some(Repair::Free { track, sector }),
+            Issue::BeyondTrack { track, sector } => Some(Repair::Clear { track, sector }),
+            Issue::FreeCount { track, .. } => Some(Repair::Recount { track }),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the repair, as the JSON form writes it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Repair::Allocate { .. } => "allocate",
+            Repair::Free { .. } => "free",
+            Repair::Clear { .. } => "clear",
+            Repair::Recount { .. } => "recount",
+        }
+    }
+
+    /// Makes the change in `bam`.
+    ///
+    /// Allocating and freeing keep the free count of the track, so the
+    /// repairs can be applied in any order and any selection.
+    pub fn apply(self, bam: &mut Bam) {
+        match self {
+            Repair::Allocate { track, sector } => {
+                bam.allocate(track, sector);
+            }
+            Repair::Free { track, sector } => bam.free(track, sector),
+            Repair::Clear { track, sector } => {
+                let mut bytes = *bam.as_bytes();
+                bytes[4 * track as usize + 1 + sector as usize / 8] &= !(1 << (sector % 8));
+                *bam = Bam::from_sector(bytes);
+            }
+            Repair::Recount { track } => {
+                let counted = (0..sectors_per_track(track))
+                    .filter(|&s| bam.is_free(track, s))
+                    .count();
+                let mut bytes = *bam.as_bytes();
+                bytes[4 * track as usize] = counted as u8;
+                *bam = Bam::from_sector(bytes);
+            }
+        }
+    }
+}
+
+/// Applies `repairs` to the BAM of `image`.
+///
+/// # Errors
+/// Fails if the BAM cannot be read or written.
+pub fn repair<'a, I: BlockDevice + ?Sized>(
+    image: &mut I,
+    repairs: impl IntoIterator<Item = &'a Repair>,
+) -> Result<(), DosError> {
+    let mut bam = Bam::read(image)?;
+    for repair in repairs {
+        event!("repairing the BAM: {repair:?}");
+        repair.apply(&mut bam);
+    }
+    bam.write(image)
+}
+
+struct Checker<'a, I: ?Sized> {
+    image: &'a I,
+    owners: BTreeMap<(u8, u8), Owner>,
+    issues: Vec<Issue>,
+}
+
+impl<I: BlockDevice + ?Sized> Checker<'_, I> {
+    fn claim(&mut self, track: u8, sector: u8, owner: Owner) {
+        if let Some(&first) = self.owners.get(&(track, sector)) {
+            self.issues.push(Issue::CrossLink {
+                track,
+                sector,
+                first,
+                second: owner,
+            });
+        } else {
+            self.owners.insert((track, sector), owner);
+        }
+    }
+
+    /// Follows the chain at `track`/`sector`, claiming its blocks for
+    /// `owner`, and returns the blocks that could be read.
+    fn follow(&mut self, owner: Owner, mut track: u8, mut sector: u8) -> Vec<Sector> {
+        let mut seen = BTreeSet::new();
+        let mut blocks = Vec::new();
+        let mut from = None;
+        while track != 0 {
+            if !self.image.contains(track, sector) {
+                self.issues.push(Issue::BadLink {
+                    owner,
+                    from,
+                    to: (track, sector),
+                });
+                break;
+            }
+            if !seen.insert((track, sector)) {
+                self.issues.push(Issue::Loop {
+                    owner,
+                    track,
+                    sector,
+                });
+                break;
+            }
+            self.claim(track, sector, owner);
+            let data = match self.image.read_sector(track, sector) {
+                Ok(data) => data,
+                Err(error) => {
+                    self.issues.push(Issue::Unreadable {
+                        owner,
+                        track,
+                        sector,
+                        error,
+                    });
+                    break;
+                }
+            };
+            blocks.push(data);
+            from = Some((track, sector));
+            (track, sector) = (data[0], data[1]);
+        }
+        blocks
+    }
+
+    fn file(&mut self, entry: &DirEntry, geos: bool) {
+        let owner = Owner::File {
+            name: entry.name,
+            slot: entry.slot,
+        };
+        let mut counted = self.follow(owner, entry.track, entry.sector).len();
+        let geos = geos && GeosType::from_byte(entry.geos[0]) != GeosType::NonGeos;
+        if entry.file_type == FileType::Rel || geos {
+            counted += self
+                .follow(owner, entry.side_track, entry.side_sector)
+                .len();
+        }
+        if geos && entry.record_length == 1 {
+            let index = self.image.read_sector(entry.track, entry.sector);
+            for record in index.iter().flat_map(|index| index[2..].chunks(2)) {
+                if record[0] != 0 {
+                    counted += self.follow(owner, record[0], record[1]).len();
+                }
+            }
+        }
+        let counted = counted as u16;
+        if counted != entry.blocks {
+            self.issues.push(Issue::BlockCount {
+                owner,
+                stored: entry.blocks,
+                counted,
+            });
+        }
+    }
+}
+
+/// Returns the bit of the bitmap for `sector`, also for the sectors a track
+/// does not have, which [`Bam::is_free`] does not look at.
+fn bit(bam: &Bam, track: u8, sector: u8) -> bool {
+    bam.as_bytes()[4 * track as usize + 1 + sector as usize / 8] & (1 << (sector % 8)) != 0
+}
+
+/// Checks the BAM, the directory and every file of `image`.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::fs::{self, FileType};
+/// use cbm_dos::image::BlockDevice;
+/// use cbm_dos::integrity::{Issue, Severity, check};
+///
+/// let mut image = D64::new(35);
+/// fs::format(&mut image, b"CHECKED", Some(*b"01")).unwrap();
+/// let entry = fs::write_file(&mut image, b"FILE", FileType::Prg, &[1, 8]).unwrap();
+/// assert!(check(&image).is_clean());
+///
+/// let mut bam = fs::Bam::read(&image).unwrap();
+/// bam.free(entry.track, entry.sector);
+/// bam.write(&mut image).unwrap();
+/// let report = check(&image);
+/// assert_eq!(report.worst(), Some(Severity::Error));
+/// assert!(matches!(report.issues[0], Issue::FreeButUsed { .. }));
+/// ```
+pub fn check<I: BlockDevice + ?Sized>(image: &I) -> IntegrityReport {
+    let mut checker = Checker {
+        image,
+        owners: BTreeMap::new(),
+        issues: Vec::new(),
+    };
+    let bam = match Bam::read(image) {
+        Ok(bam) => bam,
+        Err(error) => {
+            checker.issues.push(Issue::Unreadable {
+                owner: Owner::Directory,
+                track: DIR_TRACK,
+                sector: BAM_SECTOR,
+                error,
+            });
+            return IntegrityReport {
+                issues: checker.issues,
+            };
+        }
+    };
+    checker.claim(DIR_TRACK, BAM_SECTOR, Owner::Directory);
+    let directory = checker.follow(Owner::Directory, DIR_TRACK, DIR_SECTOR);
+    let mut entries = Vec::new();
+    let mut at = (DIR_TRACK, DIR_SECTOR);
+    for data in &directory {
+        for index in 0..ENTRIES_PER_SECTOR {
+            let bytes = &data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            let slot = DirSlot {
+                track: at.0,
+                sector: at.1,
+                index: index as u8,
+            };
+            match DirEntry::parse(bytes, slot) {
+                Some(entry) => entries.push(entry),
+                None if bytes[2] != 0 => checker.issues.push(Issue::InvalidType {
+                    slot,
+                    byte: bytes[2],
+                }),
+                None => {}
+            }
+        }
+        at = (data[0], data[1]);
+    }
+    for entry in &entries {
+        if entry.closed {
+            checker.file(entry, bam.is_geos());
+        } else {
+            checker.issues.push(Issue::Unclosed {
+                owner: Owner::File {
+                    name: entry.name,
+                    slot: entry.slot,
+                },
+            });
+        }
+    }
+
+    for track in 1..=image.tracks().min(35) {
+        let sectors = image.sectors_per_track(track);
+        let mut counted = 0;
+        for sector in 0..sectors {
+            let free = bam.is_free(track, sector);
+            let used = checker.owners.contains_key(&(track, sector));
+            counted += u8::from(free);
+            match (free, used) {
+                (true, true) => checker.issues.push(Issue::FreeButUsed { track, sector }),
+                (false, false) => checker.issues.push(Issue::UsedButFree { track, sector }),
+                _ => {}
+            }
+        }
+        for sector in sectors..24 {
+            if bit(&bam, track, sector) {
+                checker.issues.push(Issue::BeyondTrack { track, sector });
+            }
+        }
+        if bam.free_on_track(track) != counted {
+            checker.issues.push(Issue::FreeCount {
+                track,
+                stored: bam.free_on_track(track),
+                counted,
+            });
+        }
+    }
+
+    for track in 1..=image.tracks() {
+        for sector in 0..image.sectors_per_track(track) {
+            if let Some(error) = image.sector_error(track, sector) {
+                checker.issues.push(Issue::SectorError {
+                    track,
+                    sector,
+                    error,
+                    used: checker.owners.contains_key(&(track, sector)),
+                });
+            }
+        }
+    }
+    IntegrityReport {
+        issues: checker.issues,
+    }
+}
+
+impl ToJson for Severity {
+    fn write_json(&self, json: &mut Writer) {
+        json.string(self.as_str());
+    }
+}
+
+/// The directory as `"directory"`, a file as an object with its name and
+/// the place of its entry.
+impl ToJson for Owner {
+    fn write_json(&self, json: &mut Writer) {
+        match self {
+            Owner::Directory => {
+                json.string("directory");
+            }
+            Owner::File { name, slot } => {
+                json.begin_object();
+                json.key("name").name(fs::trim_name(name));
+                json.field("entry", &[slot.track, slot.sector, slot.index])
+                    .end_object();
+            }
+        }
+    }
+}
+
+/// An object with the `severity` and `kind` of the issue and its fields,
+/// sectors as `[track, sector]`.
+impl ToJson for Issue {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("severity", &self.severity())
+            .field("kind", self.kind());
+        match self {
+            Issue::Unreadable {
+                owner,
+                track,
+                sector,
+                error,
+            } => {
+                json.field("owner", owner)
+                    .field("sector", &[*track, *sector])
+                    .field("error", error);
+            }
+            Issue::BadLink { owner, from, to } => {
+                json.field("owner", owner)
+                    .field("from", &from.map(|(t, s)| [t, s]))
+                    .field("to", &[to.0, to.1]);
+            }
+            Issue::Loop {
+                owner,
+                track,
+                sector,
+            } => {
+                json.field("owner", owner)
+                    .field("sector", &[*track, *sector]);
+            }
+            Issue::CrossLink {
+                track,
+                sector,
+                first,
+                second,
+            } => {
+                json.field("sector", &[*track, *sector])
+                    .field("owners", &[first, second]);
+            }
+            Issue::InvalidType { slot, byte } => {
+                json.field("entry", &[slot.track, slot.sector, slot.index])
+                    .field("byte", byte);
+            }
+            Issue::Unclosed { owner } => {
+                json.field("owner", owner);
+            }
+            Issue::BlockCount {
+                owner,
+                stored,
+                counted,
+            } => {
+                json.field("owner", owner)
+                    .field("stored", stored)
+                    .field("counted", counted);
+            }
+            Issue::FreeButUsed { track, sector }
+            | Issue::UsedButFree { track, sector }
+            | Issue::BeyondTrack { track, sector } => {
+                json.field("sector", &[*track, *sector]);
+            }
+            Issue::FreeCount {
+                track,
+                stored,
+                counted,
+            } => {
+                json.field("track", track)
+                    .field("stored", stored)
+                    .field("counted", counted);
+            }
+            Issue::SectorError {
+                track,
+                sector,
+                error,
+                used,
+            } => {
+                json.field("sector", &[*track, *sector])
+                    .field("error", error)
+                    .field("used", used);
+            }
+        }
+        json.end_object();
+    }
+}
+
+/// An object with the `repair` and its block as `[track, sector]`, or its
+/// track.
+impl ToJson for Repair {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object().field("repair", self.as_str());
+        match *self {
+            Repair::Allocate { track, sector }
+            | Repair::Free { track, sector }
+            | Repair::Clear { track, sector } => {
+                json.field("sector", &[track, sector]);
+            }
+            Repair::Recount { track } => {
+                json.field("track", &track);
+            }
+        }
+        json.end_object();
+    }
+}
+
+impl ToJson for IntegrityReport {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("worst", &self.worst())
+            .field("issues", &self.issues)
+            .end_object();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn formatted() -> D64 {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"CHECKED", Some(*b"01")).unwrap();
+        image
+    }
+
+    #[test]
+    fn finds_cross_links_loops_and_bad_bytes() {
+        let mut image = formatted();
+        let one = fs::write_file(&mut image, b"ONE", FileType::Prg, &[1; 600]).unwrap();
+        let two = fs::write_file(&mut image, b"TWO", FileType::Seq, &[2; 10]).unwrap();
+        assert!(check(&image).is_clean());
+
+        // TWO now starts on the second block of ONE, whose third block
+        // links back to its first.
+        let first = image.read_sector(one.track, one.sector).unwrap();
+        let (t2, s2) = (first[0], first[1]);
+        let mut third_at = image.read_sector(t2, s2).unwrap();
+        let (t3, s3) = (third_at[0], third_at[1]);
+        third_at = image.read_sector(t3, s3).unwrap();
+        third_at[0] = one.track;
+        third_at[1] = one.sector;
+        image.write_sector(t3, s3, &third_at).unwrap();
+        let mut moved = two.clone();
+        (moved.track, moved.sector) = (t2, s2);
+        fs::write_entry(&mut image, &moved).unwrap();
+
+        let mut directory = image.read_sector(18, 1).unwrap();
+        directory[2 * ENTRY_SIZE + 2] = 0x87;
+        image.write_sector(18, 1, &directory).unwrap();
+        image
+            .set_sector_error(1, 1, Some(DosError::DataChecksum))
+            .unwrap();
+
+        let report = check(&image);
+        let owner_one = Owner::File {
+            name: one.name,
+            slot: one.slot,
+        };
+        let owner_two = Owner::File {
+            name: moved.name,
+            slot: moved.slot,
+        };
+        assert!(report.issues.contains(&Issue::Loop {
+            owner: owner_one,
+            track: one.track,
+            sector: one.sector
+        }));
+        assert!(report.issues.contains(&Issue::CrossLink {
+            track: t2,
+            sector: s2,
+            first: owner_one,
+            second: owner_two
+        }));
+        assert!(report.issues.contains(&Issue::InvalidType {
+            slot: DirSlot {
+                track: 18,
+                sector: 1,
+                index: 2
+            },
+            byte: 0x87
+        }));
+        let error = Issue::SectorError {
+            track: 1,
+            sector: 1,
+            error: DosError::DataChecksum,
+            used: false,
+        };
+        assert!(report.issues.contains(&error));
+        assert_eq!(error.severity(), Severity::Warning);
+        assert_eq!(report.worst(), Some(Severity::Error));
+    }
+
+    #[test]
+    fn checks_the_bam_and_writes_json() {
+        let mut image = formatted();
+        let mut bam = Bam::read(&image).unwrap();
+        bam.allocate(5, 3);
+        let mut bytes = *bam.as_bytes();
+        bytes[4 * 7] += 1;
+        bytes[4 * 30 + 3] |= 0x80;
+        image.write_sector(18, 0, &bytes).unwrap();
+
+        let report = check(&image);
+        assert_eq!(
+            report.issues,
+            [
+                Issue::UsedButFree {
+                    track: 5,
+                    sector: 3
+                },
+                Issue::FreeCount {
+                    track: 7,
+                    stored: 22,
+                    counted: 21
+                },
+                Issue::BeyondTrack {
+                    track: 30,
+                    sector: 23
+                },
+            ]
+        );
+        assert_eq!(report.with_severity(Severity::Warning).count(), 2);
+        assert_eq!(
+            report.issues[1].to_json(),
+            r#"{"severity":"warning","kind":"free_count","track":7,"stored":22,"counted":21}"#
+        );
+        assert!(
+            report
+                .to_json()
+                .starts_with(r#"{"worst":"warning","issues":[{"#)
+        );
+    }
+
+    #[test]
+    fn repairs_what_the_caller_picks() {
+        let mut image = formatted();
+        let entry = fs::write_file(&mut image, b"LOST", FileType::Prg, &[7; 300]).unwrap();
+        let mut bam = Bam::read(&image).unwrap();
+        bam.free(entry.track, entry.sector);
+        bam.allocate(5, 3);
+        bam.allocate(5, 4);
+        let mut bytes = *bam.as_bytes();
+        bytes[4 * 7] += 1;
+        bytes[4 * 30 + 3] |= 0x80;
+        image.write_sector(18, 0, &bytes).unwrap();
+
+        let repairs = check(&image).repairs();
+        assert_eq!(
+            repairs,
+            [
+                Repair::Free {
+                    track: 5,
+                    sector: 3
+                },
+                Repair::Free {
+                    track: 5,
+                    sector: 4
+                },
+                Repair::Recount { track: 7 },
+                Repair::Allocate {
+                    track: entry.track,
+                    sector: entry.sector
+                },
+                Repair::Clear {
+                    track: 30,
+                    sector: 23
+                },
+            ]
+        );
+        assert_eq!(repairs[2].to_json(), r#"{"repair":"recount","track":7}"#);
+
+        // Block 5/4 stays allocated, as a protection may want it.
+        let keep = Repair::Free {
+            track: 5,
+            sector: 4,
+        };
+        repair(&mut image, repairs.iter().filter(|&&r| r != keep)).unwrap();
+        let report = check(&image);
+        assert_eq!(
+            report.issues,
+            [Issue::UsedButFree {
+                track: 5,
+                sector: 4
+            }]
+        );
+        assert_eq!(report.repairs(), [keep]);
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 664 - 2 - 1);
+    }
+}
@@ -0,0 +1,134 @@
+//! The 1541 controller job queue.
+//!
+//! The drive's DOS never touches the disk directly. It places a job code in
+//! one of five queue slots at `$0000`-`$0004` of drive RAM, the track and
+//! sector in the matching header slot at `$0006`-`$000F`, and waits for the
+//! disk controller to replace the job code with a return code. Slot `n`
+//! belongs to the buffer at `$0300 + n * $100`.
+
+use crate::error::DosError;
+use core::fmt;
+
+/// Address of the first job queue slot.
+pub const JOB_QUEUE: usize = 0x0000;
+/// Address of the first track/sector pair.
+pub const JOB_HEADERS: usize = 0x0006;
+/// Address where the controller keeps the track the head is on.
+pub const CURRENT_TRACK: usize = 0x0022;
+/// Address of the disk ID the DOS read when the disk was initialized.
+///
+/// The controller compares the ID in every sector header against it and
+/// fails with return code `$0B` (29, disk ID mismatch) if they differ.
+pub const DISK_ID: usize = 0x0012;
+
+/// A controller job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Job {
+    /// `$80`: read the sector into the buffer.
+    Read,
+    /// `$90`: write the buffer to the sector.
+    Write,
+    /// `$A0`: compare the buffer with the sector.
+    Verify,
+    /// `$B0`: move the head to the track.
+    Seek,
+    /// `$C0`: knock the head against the track 1 stop.
+    Bump,
+    /// `$D0`: jump to the code in the buffer.
+    Jump,
+    /// `$E0`: jump to the code in the buffer once the head is on the track.
+    Execute,
+}
+
+impl Job {
+    /// Decodes a job code, ignoring the drive-number bits.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code & 0xF0 {
+            0x80 => Some(Job::Read),
+            0x90 => Some(Job::Write),
+            0xA0 => Some(Job::Verify),
+            0xB0 => Some(Job::Seek),
+            0xC0 => Some(Job::Bump),
+            0xD0 => Some(Job::Jump),
+            0xE0 => Some(Job::Execute),
+            _ => None,
+        }
+    }
+
+    /// Returns the job code for drive 0.
+    pub fn code(self) -> u8 {
+        match self {
+            Job::Read => 0x80,
+            Job::Write => 0x90,
+            Job::Verify => 0xA0,
+            Job::Seek => 0xB0,
+            Job::Bump => 0xC0,
+            Job::Jump => 0xD0,
+            Job::Execute => 0xE0,
+        }
+    }
+}
+
+/// The value the controller leaves in a queue slot once a job is done.
+///
+/// Return codes share their numbering with the error bytes of D64 files:
+/// `$01` is success and `$02`-`$0B` correspond to DOS errors 20-29.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReturnCode(pub u8);
+
+impl ReturnCode {
+    /// The job completed successfully.
+    pub const OK: ReturnCode = ReturnCode(0x01);
+
+    /// Builds the return code reporting `error`.
+    ///
+    /// Errors the controller cannot produce map to `$02`, header not found.
+    pub fn from_error(error: DosError) -> Self {
+        ReturnCode(crate::d64::error_to_byte(error).unwrap_or(0x02))
+    }
+
+    /// Returns the DOS error this code stands for, or `None` on success.
+    pub fn error(self) -> Option<DosError> {
+        crate::d64::error_from_byte(self.0)
+    }
+
+    /// Returns `true` if the job succeeded.
+    pub fn is_ok(self) -> bool {
+        self.0 == 0x01
+    }
+}
+
+impl fmt::Display for ReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:02X}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_codes_round_trip() {
+        for job in [
+            Job::Read,
+            Job::Write,
+            Job::Verify,
+            Job::Seek,
+            Job::Bump,
+            Job::Jump,
+            Job::Execute,
+        ] {
+            assert_eq!(Job::from_code(job.code()), Some(job));
+            assert_eq!(Job::from_code(job.code() | 0x01), Some(job));
+        }
+        assert_eq!(Job::from_code(0x01), None);
+    }
+
+    #[test]
+    fn return_codes_map_to_dos_errors() {
+        assert_eq!(ReturnCode(0x05).error(), Some(DosError::DataChecksum));
+        assert_eq!(ReturnCode::from_error(DosError::DiskIdMismatch), ReturnCode(0x0B));
+        assert_eq!(ReturnCode::OK.error(), None);
+    }
+}
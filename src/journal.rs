@@ -0,0 +1,320 @@
+//! Undoable editing.
+//!
+//! A [`Journal`] wraps a mutable image and records every sector written
+//! to it with what the sector held before, so that an editor built on the
+//! crate can take back any change. Writes are grouped into steps: each
+//! write on its own, or the writes of a transaction, such as the
+//! directory and BAM blocks a file operation of [`crate::fs`] rewrites,
+//! which are then undone and redone as one.
+
+use crate::error::DosError;
+use crate::image::{BlockDevice, DiskImage, Sector};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A sector write recorded in a [`Journal`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub track: u8,
+    pub sector: u8,
+    pub before: Sector,
+    pub after: Sector,
+}
+
+/// The changes undone or redone as one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// What the step did, as given to [`Journal::begin`].
+    pub label: String,
+    /// The sectors written, each once, in the order first written.
+    pub changes: Vec<Change>,
+}
+
+/// A disk image that keeps a history of its writes.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::fs::{self, FileType};
+/// use cbm_dos::journal::Journal;
+///
+/// let mut disk = Journal::new(D64::new(35));
+/// disk.transaction("format", |d| fs::format(d, b"WORK", Some(*b"01")))
+///     .unwrap();
+/// disk.transaction("save", |d| {
+///     fs::write_file(d, b"PROGRAM", FileType::Prg, &[1, 8]).map(|_| ())
+/// })
+/// .unwrap();
+///
+/// disk.undo().unwrap();
+/// assert!(fs::find_file(&disk, b"PROGRAM").unwrap().is_none());
+/// disk.redo().unwrap();
+/// assert!(fs::find_file(&disk, b"PROGRAM").unwrap().is_some());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Journal<I> {
+    image: I,
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    open: Option<Step>,
+    depth: usize,
+}
+
+impl<I: BlockDevice> Journal<I> {
+    /// Starts a journal with an empty history.
+    pub fn new(image: I) -> Self {
+        Journal {
+            image,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            open: None,
+            depth: 0,
+        }
+    }
+
+    /// Returns the image.
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+
+    /// Returns the image, dropping the history.
+    pub fn into_inner(self) -> I {
+        self.image
+    }
+
+    /// Returns the steps that can be undone, the last one last.
+    pub fn history(&self) -> &[Step] {
+        &self.undo
+    }
+
+    /// Returns `true` if there is a step to undo.
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    /// Returns `true` if there is an undone step to redo.
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Opens a transaction: the writes until the matching
+    /// [`Journal::commit`] form one step.
+    ///
+    /// Transactions nest; the writes of inner ones belong to the step of
+    /// the outermost, which gives its label.
+    pub fn begin(&mut self, label: impl Into<String>) {
+        if self.depth == 0 {
+            self.open = Some(Step {
+                label: label.into(),
+                changes: Vec::new(),
+            });
+        }
+        self.depth += 1;
+    }
+
+    /// Closes the innermost transaction. Closing the outermost one adds
+    /// its step to the history, unless it wrote nothing.
+    pub fn commit(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            let step = self.open.take();
+            self.push(step);
+        }
+    }
+
+    /// Takes back the writes of the open transaction and closes it, with
+    /// every transaction it is nested in.
+    ///
+    /// # Errors
+    /// Returns the error of the first sector that cannot be restored.
+    pub fn rollback(&mut self) -> Result<(), DosError> {
+        self.depth = 0;
+        match self.open.take() {
+            Some(step) => restore(&mut self.image, &step),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `edit` as a transaction labelled `label`. If it fails, its
+    /// writes are rolled back and its error returned.
+    ///
+    /// # Errors
+    /// Returns the error of `edit`, or of the rollback after it.
+    pub fn transaction<T>(
+        &mut self,
+        label: impl Into<String>,
+        edit: impl FnOnce(&mut Self) -> Result<T, DosError>,
+    ) -> Result<T, DosError> {
+        self.begin(label);
+        match edit(self) {
+            Ok(value) => {
+                self.commit();
+                Ok(value)
+            }
+            Err(error) => {
+                self.rollback()?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Undoes the last step, returning its label, or `None` if there is
+    /// none. An open transaction is rolled back first.
+    ///
+    /// # Errors
+    /// Returns the error of the first sector that cannot be restored.
+    pub fn undo(&mut self) -> Result<Option<&str>, DosError> {
+        self.rollback()?;
+        let Some(step) = self.undo.pop() else {
+            return Ok(None);
+        };
+        restore(&mut self.image, &step)?;
+        self.redo.push(step);
+        Ok(self.redo.last().map(|s| s.label.as_str()))
+    }
+
+    /// Redoes the last step undone, returning its label, or `None` if
+    /// there is none. Redoing is no longer possible once a new step is
+    /// written.
+    ///
+    /// # Errors
+    /// Returns the error of the first sector that cannot be written.
+    pub fn redo(&mut self) -> Result<Option<&str>, DosError> {
+        self.rollback()?;
+        let Some(step) = self.redo.pop() else {
+            return Ok(None);
+        };
+        for change in &step.changes {
+            self.image
+                .write_sector(change.track, change.sector, &change.after)?;
+        }
+        self.undo.push(step);
+        Ok(self.undo.last().map(|s| s.label.as_str()))
+    }
+
+    fn push(&mut self, step: Option<Step>) {
+        if let Some(step) = step.filter(|s| !s.changes.is_empty()) {
+            self.undo.push(step);
+            self.redo.clear();
+        }
+    }
+}
+
+fn restore<I: BlockDevice>(image: &mut I, step: &Step) -> Result<(), DosError> {
+    for change in step.changes.iter().rev() {
+        image.write_sector(change.track, change.sector, &change.before)?;
+    }
+    Ok(())
+}
+
+impl<I: BlockDevice> BlockDevice for Journal<I> {
+    fn tracks(&self) -> u8 {
+        self.image.tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        self.image.sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        self.image.read_sector(track, sector)
+    }
+
+    /// Writes the sector and records the change, as a step of its own
+    /// unless a transaction is open.
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        let before = self.image.read_sector(track, sector)?;
+        self.image.write_sector(track, sector, data)?;
+        let single = self.open.is_none();
+        let step = self.open.get_or_insert_with(|| Step {
+            label: String::from("write sector"),
+            changes: Vec::new(),
+        });
+        let at = |c: &&mut Change| c.track == track && c.sector == sector;
+        match step.changes.iter_mut().find(at) {
+            Some(change) => change.after = *data,
+            None => step.changes.push(Change {
+                track,
+                sector,
+                before,
+                after: *data,
+            }),
+        }
+        if single {
+            let step = self.open.take();
+            self.push(step);
+        }
+        Ok(())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.image.sector_error(track, sector)
+    }
+}
+
+impl<I: DiskImage> DiskImage for Journal<I> {
+    fn is_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::{self, FileType};
+
+    fn formatted() -> Journal<D64> {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"WORK", Some(*b"01")).unwrap();
+        Journal::new(image)
+    }
+
+    #[test]
+    fn undoes_and_redoes_steps() {
+        let mut disk = formatted();
+        let original = disk.image().clone();
+        disk.write_sector(1, 0, &[1; 256]).unwrap();
+        disk.write_sector(1, 0, &[2; 256]).unwrap();
+        disk.transaction("save", |d| {
+            fs::write_file(d, b"FILE", FileType::Seq, b"TEXT").map(|_| ())
+        })
+        .unwrap();
+        assert_eq!(disk.history().len(), 3);
+        assert_eq!(disk.history()[2].label, "save");
+
+        assert_eq!(disk.undo(), Ok(Some("save")));
+        assert!(fs::find_file(&disk, b"FILE").unwrap().is_none());
+        assert_eq!(disk.undo(), Ok(Some("write sector")));
+        assert_eq!(disk.read_sector(1, 0), Ok([1; 256]));
+        assert_eq!(disk.redo(), Ok(Some("write sector")));
+        assert_eq!(disk.read_sector(1, 0), Ok([2; 256]));
+        disk.write_sector(2, 0, &[3; 256]).unwrap();
+        assert!(!disk.can_redo());
+        while disk.undo().unwrap().is_some() {}
+        assert_eq!(disk.into_inner(), original);
+    }
+
+    #[test]
+    fn rolls_back_failed_transactions() {
+        let mut disk = formatted();
+        let original = disk.image().clone();
+        let result = disk.transaction("copy", |d| {
+            fs::write_file(d, b"PART", FileType::Prg, &[1, 8])?;
+            d.begin("inner");
+            d.write_sector(5, 0, &[9; 256])?;
+            d.commit();
+            fs::rename(d, b"NEW", b"MISSING")
+        });
+        assert_eq!(result, Err(DosError::FileNotFound));
+        assert!(!disk.can_undo());
+        assert_eq!(disk.image(), &original);
+
+        disk.begin("empty");
+        disk.commit();
+        assert!(!disk.can_undo());
+    }
+}
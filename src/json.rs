@@ -0,0 +1,910 @@
+//! Machine-readable output and its way back.
+//!
+//! Tools that report on disks want their findings as JSON rather than as a
+//! listing. [`ToJson`] writes the metadata types of the crate, directories,
+//! entries, error statuses and drive geometries, through a small
+//! streaming [`Writer`], and is easy to implement for reports built on
+//! them.
+//!
+//! [`parse`] reads JSON text into a [`Value`], and [`FromJson`] reads the
+//! same types back from what [`ToJson`] wrote. What the output leaves out
+//! stays out: entries come back without their directory slot, the GEOS
+//! bytes of entries only where a GEOS disk shows them, and statuses with
+//! the standard message of their code. Numbers are integers, as the writer
+//! writes them.
+//!
+//! Names on disk may hold any byte, so they are written as
+//! [`petscii::to_host_name`] gives them, which
+//! [`petscii::from_host_name`] turns back into the same bytes.
+
+use crate::error::{DosError, DosStatus};
+use crate::fs::{self, DirEntry, DirSlot, Directory, FileType, GeosFile, GeosType};
+use crate::model::DriveModel;
+use crate::petscii;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Writes JSON text, taking care of commas and escapes.
+///
+/// Values are written one after the other: inside an object, each value
+/// follows a [`Writer::key`].
+#[derive(Debug, Default)]
+pub struct Writer {
+    out: String,
+    separate: bool,
+}
+
+impl Writer {
+    /// Returns a writer with no output yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the text written.
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn start_value(&mut self) {
+        if self.separate {
+            self.out.push(',');
+        }
+        self.separate = true;
+    }
+
+    /// Starts an object.
+    pub fn begin_object(&mut self) -> &mut Self {
+        self.start_value();
+        self.out.push('{');
+        self.separate = false;
+        self
+    }
+
+    /// Ends the innermost object.
+    pub fn end_object(&mut self) -> &mut Self {
+        self.out.push('}');
+        self.separate = true;
+        self
+    }
+
+    /// Starts an array.
+    pub fn begin_array(&mut self) -> &mut Self {
+        self.start_value();
+        self.out.push('[');
+        self.separate = false;
+        self
+    }
+
+    /// Ends the innermost array.
+    pub fn end_array(&mut self) -> &mut Self {
+        self.out.push(']');
+        self.separate = true;
+        self
+    }
+
+    /// Writes the key of the next value of an object.
+    pub fn key(&mut self, key: &str) -> &mut Self {
+        self.string(key);
+        self.out.push(':');
+        self.separate = false;
+        self
+    }
+
+    /// Writes a string.
+    pub fn string(&mut self, text: &str) -> &mut Self {
+        self.start_value();
+        self.out.push('"');
+        for c in text.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                c if c < ' ' => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+        self
+    }
+
+    /// Writes a name from disk, see the [module documentation](self).
+    pub fn name(&mut self, name: &[u8]) -> &mut Self {
+        self.string(&petscii::to_host_name(name))
+    }
+
+    /// Writes a number.
+    pub fn number(&mut self, number: impl Into<i64>) -> &mut Self {
+        self.start_value();
+        let _ = write!(self.out, "{}", number.into());
+        self
+    }
+
+    /// Writes `true` or `false`.
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.start_value();
+        self.out.push_str(if value { "true" } else { "false" });
+        self
+    }
+
+    /// Writes `null`.
+    pub fn null(&mut self) -> &mut Self {
+        self.start_value();
+        self.out.push_str("null");
+        self
+    }
+
+    /// Writes any value.
+    pub fn value<T: ToJson + ?Sized>(&mut self, value: &T) -> &mut Self {
+        value.write_json(self);
+        self
+    }
+
+    /// Writes a key and its value.
+    pub fn field<T: ToJson + ?Sized>(&mut self, key: &str, value: &T) -> &mut Self {
+        self.key(key).value(value)
+    }
+}
+
+/// A type with a JSON form.
+pub trait ToJson {
+    /// Writes the value to `json`.
+    fn write_json(&self, json: &mut Writer);
+
+    /// Returns the value as JSON text.
+    fn to_json(&self) -> String {
+        let mut json = Writer::new();
+        self.write_json(&mut json);
+        json.finish()
+    }
+}
+
+impl ToJson for bool {
+    fn write_json(&self, json: &mut Writer) {
+        json.bool(*self);
+    }
+}
+
+macro_rules! numbers {
+    ($($t:ty),*) => {$(
+        impl ToJson for $t {
+            fn write_json(&self, json: &mut Writer) {
+                json.number(*self);
+            }
+        }
+    )*};
+}
+
+numbers!(u8, u16, u32, i8, i16, i32, i64);
+
+impl ToJson for usize {
+    fn write_json(&self, json: &mut Writer) {
+        json.number(*self as i64);
+    }
+}
+
+impl ToJson for str {
+    fn write_json(&self, json: &mut Writer) {
+        json.string(self);
+    }
+}
+
+impl ToJson for String {
+    fn write_json(&self, json: &mut Writer) {
+        json.string(self);
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn write_json(&self, json: &mut Writer) {
+        match self {
+            Some(value) => value.write_json(json),
+            None => {
+                json.null();
+            }
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_array();
+        for value in self {
+            value.write_json(json);
+        }
+        json.end_array();
+    }
+}
+
+impl<T: ToJson, const N: usize> ToJson for [T; N] {
+    fn write_json(&self, json: &mut Writer) {
+        self[..].write_json(json);
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn write_json(&self, json: &mut Writer) {
+        self[..].write_json(json);
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn write_json(&self, json: &mut Writer) {
+        (**self).write_json(json);
+    }
+}
+
+/// The three letters of the listing.
+impl ToJson for FileType {
+    fn write_json(&self, json: &mut Writer) {
+        json.string(self.as_str());
+    }
+}
+
+impl ToJson for DirEntry {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object();
+        write_entry(self, json);
+        json.end_object();
+    }
+}
+
+fn write_entry(entry: &DirEntry, json: &mut Writer) {
+    json.key("name").name(entry.name());
+    json.field("type", &entry.file_type)
+        .field("closed", &entry.closed)
+        .field("locked", &entry.locked)
+        .field("blocks", &entry.blocks)
+        .field("start", &[entry.track, entry.sector]);
+    if entry.file_type == FileType::Rel {
+        json.field("record_length", &entry.record_length)
+            .field("side_sectors", &[entry.side_track, entry.side_sector]);
+    }
+}
+
+impl ToJson for GeosFile {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("type", self.file_type.as_str())
+            .field("vlir", &self.vlir)
+            .field("info", &[self.info_track, self.info_sector])
+            .field("date", &self.date)
+            .end_object();
+    }
+}
+
+/// Entries of GEOS disks carry what [`Directory::geos`] tells about them
+/// as `geos`.
+impl ToJson for Directory {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object();
+        json.key("name").name(fs::trim_name(&self.name));
+        json.key("id").name(&self.id);
+        json.key("dos_type").name(&self.dos_type);
+        json.field("blocks_free", &self.blocks_free)
+            .field("geos", &self.geos)
+            .key("entries")
+            .begin_array();
+        for entry in &self.entries {
+            json.begin_object();
+            write_entry(entry, json);
+            if let Some(geos) = self.geos(entry) {
+                json.field("geos", &geos);
+            }
+            json.end_object();
+        }
+        json.end_array().end_object();
+    }
+}
+
+impl ToJson for DosError {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("code", &self.code())
+            .field("message", self.message())
+            .end_object();
+    }
+}
+
+impl ToJson for DosStatus {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("code", &self.code)
+            .field("message", self.message)
+            .field("track", &self.track)
+            .field("sector", &self.sector)
+            .end_object();
+    }
+}
+
+/// The geometry of the model's disks, with the tracks grouped into zones
+/// of equal sector counts.
+impl ToJson for DriveModel {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("name", self.name())
+            .field("tracks", &self.tracks())
+            .field("sectors", &self.total_sectors())
+            .field("directory_track", &self.directory_track())
+            .field("drives", &self.drives())
+            .key("zones")
+            .begin_array();
+        let mut first = 1;
+        for track in 1..=self.tracks() {
+            let sectors = self.sectors_per_track(track);
+            if track == self.tracks() || self.sectors_per_track(track + 1) != sectors {
+                json.begin_object()
+                    .field("tracks", &[first, track])
+                    .field("sectors", &sectors)
+                    .end_object();
+                // Past the last zone of a 255 track disk it is not used.
+                first = track.saturating_add(1);
+            }
+        }
+        json.end_array().end_object();
+    }
+}
+
+/// An error reading JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonError {
+    /// The text is no JSON, or holds a number other than an integer, at
+    /// the byte offset given.
+    Syntax(usize),
+    /// The value has not the form of the type read, with the key or type
+    /// that was expected.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Syntax(offset) => write!(f, "invalid JSON at byte {offset}"),
+            JsonError::Invalid(what) => write!(f, "unexpected JSON value for {what}"),
+        }
+    }
+}
+
+impl core::error::Error for JsonError {}
+
+/// A JSON value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    /// The keys and values of an object, in the order of the text.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Returns the value of `key` if the value is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// How deep arrays and objects may nest, so that hostile input cannot
+/// exhaust the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Parses JSON text.
+///
+/// # Errors
+/// Returns [`JsonError::Syntax`] at the first byte that does not fit, or
+/// where arrays and objects nest deeper than 64 levels.
+pub fn parse(text: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        position: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_space();
+    if parser.position < parser.bytes.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax(self.position)
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, JsonError> {
+        let byte = self.peek().ok_or(self.error())?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        self.skip_space();
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &[u8], value: Value) -> Result<Value, JsonError> {
+        if !self.bytes[self.position..].starts_with(word) {
+            return Err(self.error());
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, JsonError> {
+        self.skip_space();
+        match self.peek().ok_or(self.error())? {
+            b'n' => self.literal(b"null", Value::Null),
+            b't' => self.literal(b"true", Value::Bool(true)),
+            b'f' => self.literal(b"false", Value::Bool(false)),
+            b'"' => self.string().map(Value::String),
+            b'-' | b'0'..=b'9' => self.number(),
+            b'[' | b'{' if depth >= MAX_DEPTH => Err(self.error()),
+            b'[' => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.skip_space();
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.skip_space();
+                    match self.next()? {
+                        b',' => {}
+                        b']' => return Ok(Value::Array(values)),
+                        _ => return Err(JsonError::Syntax(self.position - 1)),
+                    }
+                }
+            }
+            b'{' => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error());
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value(depth + 1)?));
+                    self.skip_space();
+                    match self.next()? {
+                        b',' => {}
+                        b'}' => return Ok(Value::Object(fields)),
+                        _ => return Err(JsonError::Syntax(self.position - 1)),
+                    }
+                }
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.position;
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        let digits = self.position;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.position += 1;
+        }
+        let leading_zero = self.bytes[digits] == b'0' && self.position > digits + 1;
+        if self.position == digits
+            || leading_zero
+            || matches!(self.peek(), Some(b'.' | b'e' | b'E'))
+        {
+            return Err(self.error());
+        }
+        // Only ASCII digits and a sign were taken.
+        let text = core::str::from_utf8(&self.bytes[start..self.position]).unwrap_or_default();
+        text.parse()
+            .map(Value::Number)
+            .map_err(|_| JsonError::Syntax(start))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.next()? as char)
+                .to_digit(16)
+                .ok_or(JsonError::Syntax(self.position - 1))?;
+            code = code << 4 | digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.position += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.position;
+            while self
+                .peek()
+                .is_some_and(|b| b != b'"' && b != b'\\' && b >= b' ')
+            {
+                self.position += 1;
+            }
+            // The text is a `str` and the run ends before an ASCII byte.
+            out.push_str(
+                core::str::from_utf8(&self.bytes[start..self.position]).unwrap_or_default(),
+            );
+            match self.next()? {
+                b'"' => return Ok(out),
+                b'\\' => {}
+                _ => return Err(JsonError::Syntax(self.position - 1)),
+            }
+            let escape = self.position;
+            let c = match self.next()? {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    let mut code = self.hex4()?;
+                    if (0xD800..0xDC00).contains(&code)
+                        && self.bytes[self.position..].starts_with(b"\\u")
+                    {
+                        self.position += 2;
+                        let low = self.hex4()?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(JsonError::Syntax(escape));
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    char::from_u32(code).ok_or(JsonError::Syntax(escape))?
+                }
+                _ => return Err(JsonError::Syntax(escape)),
+            };
+            out.push(c);
+        }
+    }
+}
+
+/// A type that can be read back from the JSON form [`ToJson`] gives it.
+pub trait FromJson: Sized {
+    /// Reads the value from `value`.
+    ///
+    /// # Errors
+    /// Returns [`JsonError::Invalid`] if `value` has another form.
+    fn read_json(value: &Value) -> Result<Self, JsonError>;
+
+    /// Reads the value from JSON text.
+    ///
+    /// # Errors
+    /// Returns the errors of [`parse`] and [`FromJson::read_json`].
+    fn from_json(text: &str) -> Result<Self, JsonError> {
+        Self::read_json(&parse(text)?)
+    }
+}
+
+/// Reads the value of `key` of the object `value`.
+fn read<T: FromJson>(value: &Value, key: &'static str) -> Result<T, JsonError> {
+    T::read_json(value.get(key).ok_or(JsonError::Invalid(key))?)
+}
+
+/// Reads a name from disk written by [`Writer::name`].
+fn read_name(value: &Value, key: &'static str) -> Result<Vec<u8>, JsonError> {
+    let name: String = read(value, key)?;
+    petscii::from_host_name(&name).ok_or(JsonError::Invalid(key))
+}
+
+/// Reads a name of exactly `N` bytes, such as a disk ID.
+fn read_bytes<const N: usize>(value: &Value, key: &'static str) -> Result<[u8; N], JsonError> {
+    read_name(value, key)?
+        .try_into()
+        .map_err(|_| JsonError::Invalid(key))
+}
+
+impl FromJson for bool {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Bool(value) => Ok(*value),
+            _ => Err(JsonError::Invalid("bool")),
+        }
+    }
+}
+
+macro_rules! read_numbers {
+    ($($t:ty),*) => {$(
+        impl FromJson for $t {
+            fn read_json(value: &Value) -> Result<Self, JsonError> {
+                match value {
+                    Value::Number(number) => {
+                        <$t>::try_from(*number).map_err(|_| JsonError::Invalid(stringify!($t)))
+                    }
+                    _ => Err(JsonError::Invalid(stringify!($t))),
+                }
+            }
+        }
+    )*};
+}
+
+read_numbers!(u8, u16, u32, i8, i16, i32, i64, usize);
+
+impl FromJson for String {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::String(text) => Ok(text.clone()),
+            _ => Err(JsonError::Invalid("string")),
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Option<T> {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::read_json(value).map(Some),
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Array(values) => values.iter().map(T::read_json).collect(),
+            _ => Err(JsonError::Invalid("array")),
+        }
+    }
+}
+
+impl<T: FromJson, const N: usize> FromJson for [T; N] {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        Vec::<T>::read_json(value)?
+            .try_into()
+            .map_err(|_| JsonError::Invalid("array"))
+    }
+}
+
+impl FromJson for FileType {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        let name = String::read_json(value)?;
+        (0..5)
+            .filter_map(FileType::from_byte)
+            .find(|file_type| file_type.as_str() == name)
+            .ok_or(JsonError::Invalid("file type"))
+    }
+}
+
+/// Entries come back with [`DirSlot::default`] as slot.
+impl FromJson for DirEntry {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        let file_type = read(value, "type")?;
+        let [track, sector] = read(value, "start")?;
+        let mut entry = DirEntry {
+            file_type,
+            closed: read(value, "closed")?,
+            locked: read(value, "locked")?,
+            name: fs::pad_name(&read_name(value, "name")?),
+            track,
+            sector,
+            side_track: 0,
+            side_sector: 0,
+            record_length: 0,
+            blocks: read(value, "blocks")?,
+            geos: [0; 6],
+            slot: DirSlot::default(),
+        };
+        if file_type == FileType::Rel {
+            entry.record_length = read(value, "record_length")?;
+            [entry.side_track, entry.side_sector] = read(value, "side_sectors")?;
+        }
+        Ok(entry)
+    }
+}
+
+/// Types GEOS does not define all show as `"Unknown"` and come back as
+/// the first of them.
+impl FromJson for GeosFile {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        let name: String = read(value, "type")?;
+        let file_type = (0..=u8::MAX)
+            .map(GeosType::from_byte)
+            .find(|file_type| file_type.as_str() == name)
+            .ok_or(JsonError::Invalid("type"))?;
+        let [info_track, info_sector] = read(value, "info")?;
+        Ok(GeosFile {
+            file_type,
+            vlir: read(value, "vlir")?,
+            info_track,
+            info_sector,
+            date: read(value, "date")?,
+        })
+    }
+}
+
+/// The entries of GEOS files get back the fields [`Directory::geos`]
+/// reads.
+impl FromJson for Directory {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        let Some(Value::Array(values)) = value.get("entries") else {
+            return Err(JsonError::Invalid("entries"));
+        };
+        let mut entries = Vec::with_capacity(values.len());
+        for value in values {
+            let mut entry = DirEntry::read_json(value)?;
+            if let Some(geos) = value.get("geos") {
+                let geos = GeosFile::read_json(geos)?;
+                entry.geos[0] = geos.file_type.to_byte();
+                entry.geos[1..].copy_from_slice(&geos.date);
+                entry.record_length = u8::from(geos.vlir);
+                entry.side_track = geos.info_track;
+                entry.side_sector = geos.info_sector;
+            }
+            entries.push(entry);
+        }
+        Ok(Directory {
+            name: fs::pad_name(&read_name(value, "name")?),
+            id: read_bytes(value, "id")?,
+            dos_type: read_bytes(value, "dos_type")?,
+            entries,
+            blocks_free: read(value, "blocks_free")?,
+            geos: read(value, "geos")?,
+        })
+    }
+}
+
+impl FromJson for DosError {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        DosError::from_code(read(value, "code")?).ok_or(JsonError::Invalid("code"))
+    }
+}
+
+/// The message is the one [`DosStatus::new`] gives the code.
+impl FromJson for DosStatus {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        Ok(DosStatus::new(
+            read(value, "code")?,
+            read(value, "track")?,
+            read(value, "sector")?,
+        ))
+    }
+}
+
+/// Models are told by their name, the rest follows from it.
+impl FromJson for DriveModel {
+    fn read_json(value: &Value) -> Result<Self, JsonError> {
+        let name: String = read(value, "name")?;
+        DriveModel::ALL
+            .into_iter()
+            .find(|model| model.name() == name)
+            .ok_or(JsonError::Invalid("name"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    #[test]
+    fn writes_directories() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"REPORT", Some(*b"JS")).unwrap();
+        fs::write_file(&mut image, b"A\"B", FileType::Prg, &[1, 8]).unwrap();
+        let dir = fs::read_directory(&image).unwrap();
+        assert_eq!(
+            dir.to_json(),
+            concat!(
+                r#"{"name":"REPORT","id":"JS","dos_type":"2A","blocks_free":663,"#,
+                r#""geos":false,"entries":[{"name":"A%22B","type":"PRG","closed":true,"#,
+                r#""locked":false,"blocks":1,"start":[17,0]}]}"#,
+            )
+        );
+        assert_eq!(
+            DosError::FileNotFound.to_json(),
+            r#"{"code":62,"message":"FILE NOT FOUND"}"#
+        );
+    }
+
+    #[test]
+    fn writes_geometries_and_escapes() {
+        let json = DriveModel::C1541.to_json();
+        assert!(json.starts_with(r#"{"name":"1541","tracks":35,"sectors":683,"#));
+        assert!(json.ends_with(concat!(
+            r#""zones":[{"tracks":[1,17],"sectors":21},{"tracks":[18,24],"sectors":19},"#,
+            r#"{"tracks":[25,30],"sectors":18},{"tracks":[31,35],"sectors":17}]}"#,
+        )));
+        assert_eq!("a\\\u{1}\n".to_json(), r#""a\\\u0001\n""#);
+        let values: Vec<Option<u8>> = alloc::vec![Some(1), None];
+        assert_eq!(values.to_json(), "[1,null]");
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"REPORT", Some(*b"JS")).unwrap();
+        fs::write_file(&mut image, b"A\"B\xFF", FileType::Prg, &[1, 8]).unwrap();
+        let mut dir = fs::read_directory(&image).unwrap();
+        dir.geos = true;
+        let mut rel = dir.entries[0].clone();
+        rel.file_type = FileType::Rel;
+        rel.record_length = 64;
+        rel.side_track = 19;
+        rel.side_sector = 3;
+        let mut geos = dir.entries[0].clone();
+        geos.geos = [GeosType::Application.to_byte(), 88, 6, 1, 12, 30];
+        geos.record_length = 1;
+        geos.side_track = 19;
+        geos.side_sector = 7;
+        dir.entries.extend([rel, geos]);
+        for entry in &mut dir.entries {
+            entry.slot = DirSlot::default();
+        }
+        assert_eq!(Directory::from_json(&dir.to_json()), Ok(dir));
+
+        let status = DosStatus::new(23, 18, 4);
+        assert_eq!(DosStatus::from_json(&status.to_json()), Ok(status));
+        assert_eq!(
+            DosError::from_json(&DosError::DiskFull.to_json()),
+            Ok(DosError::DiskFull)
+        );
+        for model in DriveModel::ALL {
+            assert_eq!(DriveModel::from_json(&model.to_json()), Ok(model));
+        }
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        assert_eq!(
+            parse(r#" {"a": [1, -2, "\u00e9\ud83d\ude00"]} "#),
+            Ok(Value::Object(alloc::vec![(
+                "a".into(),
+                Value::Array(alloc::vec![
+                    Value::Number(1),
+                    Value::Number(-2),
+                    Value::String("\u{e9}\u{1f600}".into()),
+                ]),
+            )]))
+        );
+        assert_eq!(parse("[1,]"), Err(JsonError::Syntax(3)));
+        assert_eq!(parse("1.5"), Err(JsonError::Syntax(1)));
+        assert_eq!(parse("01"), Err(JsonError::Syntax(2)));
+        assert_eq!(parse("\"a"), Err(JsonError::Syntax(2)));
+        assert_eq!(parse("true x"), Err(JsonError::Syntax(5)));
+        assert_eq!(parse(&"[".repeat(100)), Err(JsonError::Syntax(64)));
+        assert_eq!(u8::from_json("256"), Err(JsonError::Invalid("u8")));
+        assert_eq!(
+            DosStatus::from_json(r#"{"code":23,"track":18}"#),
+            Err(JsonError::Invalid("sector"))
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! LBR library archives.
+//!
+//! A library, the container many BBS archives still hold, is a plain text
+//! directory followed by the contents of its files, back to back:
+//!
+//! ```plaintext
+//! header   "DWB", space, number of files, space, CR
+//! entry    name, CR, type letter, CR, space, length, space, CR
+//! data     the contents of each file in the order of the directory
+//! ```
+//!
+//! Numbers are decimal in ASCII, names are PETSCII and the type letters are
+//! those of `OPEN`: `P`, `S`, `U`, `R` and `D`. [`Lbr::write_to`] unpacks
+//! a library straight onto a disk.
+
+use crate::error::DosError;
+use crate::fs::{self, DirEntry, FileType};
+use crate::image::{BlockDevice, ImageError};
+use alloc::format;
+use alloc::vec::Vec;
+
+/// The signature libraries start with.
+pub const SIGNATURE: &[u8] = b"DWB";
+
+const CR: u8 = 0x0D;
+
+/// A file in a library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LbrFile {
+    /// The name, without padding.
+    pub name: Vec<u8>,
+    pub file_type: FileType,
+    /// The contents, for programs with their load address.
+    pub data: Vec<u8>,
+}
+
+/// A library archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lbr {
+    /// The files, in the order of the directory.
+    pub files: Vec<LbrFile>,
+}
+
+fn type_letter(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Del => b'D',
+        FileType::Seq => b'S',
+        FileType::Prg => b'P',
+        FileType::Usr => b'U',
+        FileType::Rel => b'R',
+    }
+}
+
+/// Reads the lines of the directory, each up to its CR.
+struct Lines<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn next(&mut self) -> Result<&'a [u8], ImageError> {
+        let rest = self.bytes.get(self.at..).unwrap_or_default();
+        let end = rest
+            .iter()
+            .position(|&b| b == CR)
+            .ok_or(ImageError::Truncated)?;
+        self.at += end + 1;
+        Ok(&rest[..end])
+    }
+
+    fn number(&mut self) -> Result<usize, ImageError> {
+        let line = self.next()?;
+        core::str::from_utf8(line)
+            .ok()
+            .and_then(|text| text.trim_matches(' ').parse().ok())
+            .ok_or(ImageError::Unsupported("LBR number"))
+    }
+}
+
+impl Lbr {
+    /// Parses a library.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] unless the file starts with `DWB`.
+    /// - [`ImageError::Truncated`] if the directory or a file extends
+    ///   beyond the end.
+    /// - [`ImageError::Unsupported`] for a count or length that is not a
+    ///   number and a type letter DOS does not know.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let rest = bytes
+            .strip_prefix(SIGNATURE)
+            .ok_or(ImageError::InvalidSignature)?;
+        let mut lines = Lines {
+            bytes,
+            at: SIGNATURE.len(),
+        };
+        if rest.first() != Some(&b' ') {
+            return Err(ImageError::InvalidSignature);
+        }
+        let count = lines.number()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let name = lines.next()?;
+            let end = name
+                .iter()
+                .rposition(|&b| b != b' ' && b != fs::PAD)
+                .map_or(0, |i| i + 1);
+            let file_type = match lines.next()? {
+                b"D" => FileType::Del,
+                b"S" => FileType::Seq,
+                b"P" => FileType::Prg,
+                b"U" => FileType::Usr,
+                b"R" => FileType::Rel,
+                _ => return Err(ImageError::Unsupported("LBR file type")),
+            };
+            entries.push((name[..end].to_vec(), file_type, lines.number()?));
+        }
+        let mut at = lines.at;
+        let mut files = Vec::with_capacity(entries.len());
+        for (name, file_type, length) in entries {
+            let data = bytes.get(at..at + length).ok_or(ImageError::Truncated)?;
+            at += length;
+            files.push(LbrFile {
+                name,
+                file_type,
+                data: data.to_vec(),
+            });
+        }
+        Ok(Lbr { files })
+    }
+
+    /// Serializes the library.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("DWB {} \r", self.files.len()).into_bytes();
+        for file in &self.files {
+            bytes.extend_from_slice(&file.name);
+            bytes.extend_from_slice(&[CR, type_letter(file.file_type), CR]);
+            bytes.extend_from_slice(format!(" {} \r", file.data.len()).as_bytes());
+        }
+        for file in &self.files {
+            bytes.extend_from_slice(&file.data);
+        }
+        bytes
+    }
+
+    /// Creates a library of every file on `image` that can be read, in
+    /// the order of its directory. REL files are left out, as a library
+    /// holds no record length.
+    ///
+    /// # Errors
+    /// Fails if the directory cannot be read.
+    pub fn from_image<I: BlockDevice + ?Sized>(image: &I) -> Result<Self, DosError> {
+        let files = fs::read_directory(image)?
+            .entries
+            .iter()
+            .filter(|entry| entry.closed && entry.file_type != FileType::Rel)
+            .filter_map(|entry| {
+                Some(LbrFile {
+                    name: entry.name().to_vec(),
+                    file_type: entry.file_type,
+                    data: fs::read_file(image, entry).ok()?,
+                })
+            })
+            .collect();
+        Ok(Lbr { files })
+    }
+
+    /// Writes every file of the library to `image`, returning their
+    /// directory entries.
+    ///
+    /// # Errors
+    /// The errors of [`fs::write_file`], such as [`DosError::FileExists`]
+    /// and [`DosError::DiskFull`]. The files written before stay on the
+    /// disk.
+    pub fn write_to<I: BlockDevice + ?Sized>(
+        &self,
+        image: &mut I,
+    ) -> Result<Vec<DirEntry>, DosError> {
+        self.files
+            .iter()
+            .map(|file| fs::write_file(image, &file.name, file.file_type, &file.data))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    #[test]
+    fn reads_and_writes_libraries() {
+        let bytes = b"DWB 2 \rGAME\rP\r 4 \rREADME  \rS\r 3 \r\x01\x08\x60\x00ABC";
+        let lbr = Lbr::from_bytes(bytes).unwrap();
+        assert_eq!(
+            lbr.files,
+            [
+                LbrFile {
+                    name: b"GAME".to_vec(),
+                    file_type: FileType::Prg,
+                    data: vec![0x01, 0x08, 0x60, 0x00]
+                },
+                LbrFile {
+                    name: b"README".to_vec(),
+                    file_type: FileType::Seq,
+                    data: b"ABC".to_vec()
+                },
+            ]
+        );
+        assert_eq!(Lbr::from_bytes(&lbr.to_bytes()).unwrap(), lbr);
+        assert_eq!(
+            Lbr::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ImageError::Truncated)
+        );
+        assert_eq!(
+            Lbr::from_bytes(b"LBR 0 \r"),
+            Err(ImageError::InvalidSignature)
+        );
+        assert_eq!(
+            Lbr::from_bytes(b"DWB 1 \rX\rQ\r 0 \r"),
+            Err(ImageError::Unsupported("LBR file type"))
+        );
+        assert_eq!(Lbr::from_bytes(b"DWB 0 \r").unwrap(), Lbr::default());
+    }
+
+    #[test]
+    fn unpacks_onto_disks_and_back() {
+        let lbr = Lbr {
+            files: vec![
+                LbrFile {
+                    name: b"LOADER".to_vec(),
+                    file_type: FileType::Prg,
+                    data: (0..700).map(|i| i as u8).collect(),
+                },
+                LbrFile {
+                    name: b"NOTES".to_vec(),
+                    file_type: FileType::Usr,
+                    data: b"HELLO".to_vec(),
+                },
+            ],
+        };
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"UNPACKED", Some(*b"01")).unwrap();
+        let entries = lbr.write_to(&mut image).unwrap();
+        assert_eq!(entries[0].blocks, 3);
+        assert_eq!(entries[1].file_type, FileType::Usr);
+        assert_eq!(Lbr::from_image(&image).unwrap(), lbr);
+        assert_eq!(lbr.write_to(&mut image), Err(DosError::FileExists));
+    }
+}
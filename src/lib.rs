@@ -1,3 +1,138 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// Reports an event through [`trace`] with the `trace` feature. Without it
+/// the arguments are only type checked.
+#[cfg(feature = "trace")]
+macro_rules! event {
+    ($($arg:tt)+) => {
+        $crate::trace::emit($crate::trace::Kind::Message, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! event {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+
+/// Opens a span through [`trace`] with the `trace` feature, closed at the
+/// end of the enclosing block. Without it this is [`event!`].
+#[cfg(feature = "trace")]
+macro_rules! span {
+    ($($arg:tt)+) => {
+        let _span = $crate::trace::Span::enter(module_path!(), format_args!($($arg)+));
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! span {
+    ($($arg:tt)+) => {
+        event!($($arg)+)
+    };
+}
+
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod allocation;
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod backed;
+pub mod basic;
+#[cfg(feature = "std")]
+pub mod bus;
+pub mod catalog;
+pub mod command;
+#[cfg(feature = "std")]
+pub mod convert;
+pub mod cpm;
+pub mod d64;
+pub mod dat;
+pub mod diff;
+pub mod drive;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod fastload;
+#[cfg(feature = "std")]
+pub mod fixture;
+#[cfg(feature = "std")]
+pub mod flux;
+pub mod fs;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+pub mod g64;
+pub mod geos;
+pub mod hash;
+#[cfg(feature = "std")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod iec;
+#[cfg(feature = "std")]
+pub mod ieee488;
+pub mod image;
+#[cfg(feature = "std")]
+pub mod inject;
+pub mod integrity;
+pub mod job;
+pub mod journal;
+pub mod json;
+pub mod lbr;
+pub mod loads;
+pub mod lynx;
+pub mod merge;
+pub mod mfm;
+pub mod model;
+pub mod mount;
+pub mod nib;
+pub mod occupancy;
+pub mod open;
+#[cfg(feature = "opencbm")]
+pub mod opencbm;
+pub mod overlay;
+pub mod p00;
+#[cfg(feature = "std")]
+pub mod parallel;
+pub mod petscii;
+pub mod prg;
+pub mod protection;
+#[cfg(feature = "unverified-loaders")]
+pub mod rapidlok;
+pub mod registry;
+pub mod rel;
+pub mod reorder;
+#[cfg(feature = "std")]
+pub mod remote;
+pub mod salvage;
+#[cfg(feature = "sevenz")]
+pub mod sevenz;
+pub mod sector;
+pub mod seq;
+pub mod stats;
+pub mod t64;
+pub mod tap;
+#[cfg(feature = "std")]
+pub mod tcbm;
+pub mod timing;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod track;
+#[cfg(feature = "unverified-loaders")]
+pub mod vmax;
+#[cfg(feature = "unverified-loaders")]
+pub mod vorpal;
+#[cfg(feature = "std")]
+pub mod wedge;
+#[cfg(feature = "std")]
+pub mod xum1541;
+pub mod zip;
+pub mod zipcode;
+
 pub struct GCR {
     decode_mappings: [u8; 32], // Index by 5-bit value, store decoded nibble
     encode_mappings: [u8; 16], // Index by nibble 0..15, store 5-bit encoded value
@@ -44,9 +179,10 @@ impl GCR {
     /// # Example
     ///
     /// ```rust
+    /// use cbm_dos::GCR;
+    ///
     /// let gcr = GCR::new();
-    /// assert_eq!(gcr.decode_mappings[0b01010], 0); // Decodes "01010" to 0
-    /// assert_eq!(gcr.encode_mappings[0], 0b01010); // Encodes 0 to "01010"
+    /// assert_eq!(gcr.encode(&[0x00, 0x00, 0x00, 0x00]), vec![0x52, 0x94, 0xA5, 0x29, 0x4A]);
     /// ```
     pub fn new() -> Self {
         // Pre-compute lookup tables as arrays for O(1) access
@@ -83,11 +219,11 @@ impl GCR {
         }
     }
 
-    /// Decodes a 40-bit encoded value into a vector of bytes (maximum 4 bytes).
+    /// Decodes a 40-bit encoded value into 4 bytes.
     ///
     /// This function processes an encoded 40-bit quintuple value, where each 5-bit segment (quintuple)
     /// translates to its corresponding decoded nibble using a precomputed `decode_mappings` array.
-    /// The function decodes 8 quintuples (2 per byte) and returns a `Vec<u8>` containing the resulting bytes.
+    /// The function decodes 8 quintuples (2 per byte) and returns the resulting bytes.
     ///
     /// If any quintuple cannot be decoded (i.e., its mapping results in `0xFF`, which is treated as invalid),
     /// the function returns `None`.
@@ -97,7 +233,7 @@ impl GCR {
     ///   can be shifted and masked correctly during decoding.
     ///
     /// ### Returns
-    /// - `Option<Vec<u8>>`: A `Some` containing the decoded vector of up to 4 bytes if decoding is successful,
+    /// - `Option<[u8; 4]>`: A `Some` containing the decoded bytes if decoding is successful,
     ///   or `None` if any quin-tuple is invalid.
     ///
     /// ### Precondition
@@ -110,14 +246,14 @@ impl GCR {
     ///   2. Look up its corresponding nibble in `decode_mappings`.
     ///   3. Repeat for the second quintuple in the pair.
     ///   4. If either quintuple mapping results in an invalid value (`0xFF`), terminate early and return `None`.
-    ///   5. Combine the two valid decoded nibbles into a single byte and append to the result.
+    ///   5. Combine the two valid decoded nibbles into a single byte of the result.
     ///
     /// ### Example
-    /// ```rust
+    /// ```rust,ignore
     /// let decoder = MyDecoder::new();
     /// let encoded_value: u64 = 0b11110_00001_11110_00001_11110_00001_11110_00001; // Example encoded value
     /// let decoded = decoder.decode_quintuple(encoded_value);
-    /// assert_eq!(decoded, Some(vec![0xF1, 0xF1, 0xF1, 0xF1])); // Decoding successful
+    /// assert_eq!(decoded, Some([0xF1, 0xF1, 0xF1, 0xF1])); // Decoding successful
     ///
     /// let invalid_encoded_value: u64 = 0b11110_11110_11110_11110_11110_11110_11110_11111; // Invalid encoding
     /// let decoded = decoder.decode_quintuple(invalid_encoded_value);
@@ -125,28 +261,28 @@ impl GCR {
     /// ```
     ///
     /// ### Notes
-    /// - The function uses a pre-allocated vector (`Vec`) with a capacity of 4 to maximize efficiency and prevent resizing.
+    /// - The function returns an array rather than a vector, so decoding never allocates per quintuple.
     /// - The function assumes `QUINTUPLE_SIZE` is defined as a constant equal to 5 (5 bits per quintuple).
     /// - This function is particularly optimized for scenarios where the decoding process is executed frequently by utilizing
     ///   direct array lookups rather than more expensive structures like `HashMap`.
-    fn decode_quintuple(&self, encoded_value: u64) -> Option<Vec<u8>> {
-        let mut result = Vec::with_capacity(4); // Pre-allocate exact capacity
+    fn decode_quintuple(&self, encoded_value: u64) -> Option<[u8; 4]> {
+        let mut result = [0; 4];
 
         // Process 8 quintuples (40 bits total)
-        for j in 0..4 {
+        for (j, byte) in result.iter_mut().enumerate() {
             let shift_amount = START_PT - j * (QUINTUPLE_SIZE * 2);
 
             let decoded_nibble_high =
                 self.decode_mappings[((encoded_value >> shift_amount) & 0x1f) as usize];
             let decoded_nibble_low = self.decode_mappings
-                [((encoded_value >> shift_amount - QUINTUPLE_SIZE) & 0x1f) as usize];
+                [((encoded_value >> (shift_amount - QUINTUPLE_SIZE)) & 0x1f) as usize];
 
             // Skip invalid encodings
             if decoded_nibble_high == 0xFF || decoded_nibble_low == 0xFF {
                 return None;
             }
 
-            result.push(decoded_nibble_high << 4 | decoded_nibble_low);
+            *byte = decoded_nibble_high << 4 | decoded_nibble_low;
         }
 
         Some(result)
@@ -169,14 +305,16 @@ impl GCR {
     ///    method, which ensures efficient processing of chunks of size `QUINTUPLE_SIZE`.
     /// 2. For each chunk, it is converted into a 64-bit integer by padding the upper 3 bytes with zeros.
     /// 3. The method `decode_quintuple` (presumably implemented elsewhere in the code) is invoked with the 64-bit integer.
-    ///    - If `decode_quintuple` returns a valid result, the decoded data is appended to the result vector (`result`).
+    ///    - If `decode_quintuple` returns a valid result, the decoded bytes are appended to the result vector (`result`).
     ///    - If `decode_quintuple` fails for any chunk, the function returns `None`.
     /// 4. If all chunks are successfully decoded, the accumulated result is wrapped in `Some` and returned.
     ///
     /// # Example
     /// ```
-    /// let decoder = MyDecoder::new(); // Assuming a struct that implements the method
-    /// let encoded_data: &[u8] = &[/* encoded bytes */];
+    /// use cbm_dos::GCR;
+    ///
+    /// let decoder = GCR::new();
+    /// let encoded_data: &[u8] = &[0x52, 0x54, 0xB5, 0x29, 0x4B];
     /// if let Some(decoded_data) = decoder.decode(encoded_data) {
     ///     println!("Decoded data: {:?}", decoded_data);
     /// } else {
@@ -191,7 +329,7 @@ impl GCR {
     ///
     /// # Assumptions
     /// - The `QUINTUPLE_SIZE` constant is defined and is less than or equal to 5.
-    /// - The `decode_quintuple` function is implemented to correctly decode a `u64` value into a `Vec<u8>`.
+    /// - The `decode_quintuple` function is implemented to correctly decode a `u64` value into 4 bytes.
     pub fn decode(&self, value: &[u8]) -> Option<Vec<u8>> {
         let mut result: Vec<u8> = Vec::new();
         // Process chunks more efficiently using exact_chunks
@@ -210,6 +348,40 @@ impl GCR {
         Some(result)
     }
 
+    /// Decodes `value` into `out` without allocating.
+    ///
+    /// This is the heap-free counterpart of [`GCR::decode`] for embedded
+    /// targets: every complete 5-byte chunk of `value` yields 4 bytes in
+    /// `out`, and a remainder is ignored.
+    ///
+    /// # Returns
+    /// - `Some(n)`: the number of bytes written to `out`.
+    /// - `None`: if any chunk holds an invalid code, leaving `out` partly
+    ///   written.
+    ///
+    /// # Panics
+    /// Panics if `out` has less room than 4 bytes per chunk.
+    ///
+    /// # Example
+    /// ```
+    /// use cbm_dos::GCR;
+    ///
+    /// let mut out = [0; 4];
+    /// assert_eq!(GCR::new().decode_into(&[0x52, 0x54, 0xB5, 0x29, 0x4B], &mut out), Some(4));
+    /// assert_eq!(out, [0x08, 0x01, 0x00, 0x01]);
+    /// ```
+    pub fn decode_into(&self, value: &[u8], out: &mut [u8]) -> Option<usize> {
+        let chunks = value.chunks_exact(QUINTUPLE_SIZE);
+        let written = chunks.len() * 4;
+        for (chunk, target) in chunks.zip(out[..written].chunks_exact_mut(4)) {
+            let final_value = u64::from_be_bytes([
+                0, 0, 0, chunk[0], chunk[1], chunk[2], chunk[3], chunk[4],
+            ]);
+            target.copy_from_slice(&self.decode_quintuple(final_value)?);
+        }
+        Some(written)
+    }
+
     /// Encodes a 4-byte sequence into a 40-bit number using predefined mappings.
     ///
     /// This function takes a reference to a slice of 4 bytes (`decoded_value`)
@@ -248,7 +420,7 @@ impl GCR {
     ///   within the 64-bit result (`acc`), based on their sequence order.
     ///
     /// # Example
-    /// ```rust
+    /// ```rust,ignore
     /// // Assuming `QUINTUPLE_SIZE` is defined and `self.encode_mappings` is
     /// // already initialized correctly:
     /// let decoded_data: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
@@ -261,11 +433,11 @@ impl GCR {
     fn encode_quintuple(&self, decoded_value: &[u8]) -> u64 {
         let mut acc: u64 = 0;
 
-        for i in 0..4 {
+        for (i, byte) in decoded_value.iter().take(4).enumerate() {
             let shift_amount = START_PT - i * QUINTUPLE_SIZE * 2;
 
-            acc |= (self.encode_mappings[(decoded_value[i] >> 4) as usize] as u64) << shift_amount;
-            acc |= (self.encode_mappings[(decoded_value[i] & 0x0F) as usize] as u64)
+            acc |= (self.encode_mappings[(byte >> 4) as usize] as u64) << shift_amount;
+            acc |= (self.encode_mappings[(byte & 0x0F) as usize] as u64)
                 << (shift_amount - QUINTUPLE_SIZE);
         }
 
@@ -295,7 +467,9 @@ impl GCR {
     ///
     /// # Example
     /// ```rust
-    /// let encoder = Encoder::new();
+    /// use cbm_dos::GCR;
+    ///
+    /// let encoder = GCR::new();
     /// let input: &[u8] = &[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC];
     /// let output = encoder.encode(input);
     ///
@@ -323,6 +497,32 @@ impl GCR {
         }
         result
     }
+
+    /// Encodes `value` into `out` without allocating.
+    ///
+    /// This is the heap-free counterpart of [`GCR::encode`] for embedded
+    /// targets: every complete 4-byte chunk of `value` yields 5 bytes in
+    /// `out`, and a remainder is ignored.
+    ///
+    /// # Returns
+    /// The number of bytes written to `out`.
+    ///
+    /// # Panics
+    /// Panics if `out` has less room than 5 bytes per chunk.
+    pub fn encode_into(&self, value: &[u8], out: &mut [u8]) -> usize {
+        let chunks = value.chunks_exact(4);
+        let written = chunks.len() * QUINTUPLE_SIZE;
+        for (chunk, target) in chunks.zip(out[..written].chunks_exact_mut(QUINTUPLE_SIZE)) {
+            target.copy_from_slice(&self.encode_quintuple(chunk).to_be_bytes()[3..]);
+        }
+        written
+    }
+}
+
+impl Default for GCR {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
@@ -348,4 +548,19 @@ mod tests {
             vec![0x52, 0x54, 0xb5, 0x29, 0x4b, 0x9a, 0xa6, 0xa5, 0x29, 0x4a]
         );
     }
+
+    #[test]
+    fn codes_in_place() {
+        let gcr = GCR::new();
+        let data: Vec<u8> = (0..=255).collect();
+        let mut encoded = [0; 320];
+        assert_eq!(gcr.encode_into(&data[..255], &mut encoded), 315);
+        assert_eq!(encoded[..315], gcr.encode(&data)[..315]);
+        assert_eq!(gcr.encode_into(&data, &mut encoded), 320);
+        let mut decoded = [0; 256];
+        assert_eq!(gcr.decode_into(&encoded, &mut decoded), Some(256));
+        assert_eq!(decoded[..], data[..]);
+        encoded[7] = 0;
+        assert_eq!(gcr.decode_into(&encoded, &mut decoded), None);
+    }
 }
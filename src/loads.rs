@@ -0,0 +1,447 @@
+//! Programs that load other programs.
+//!
+//! Games and demos seldom fit in one file: a loader brings in the next
+//! part, by name or straight from a track and sector, and that part the
+//! one after it. [`references`] finds the loads the code of a program asks
+//! for; [`analyze_disk`] and [`analyze_tape`] follow them through the files
+//! of a dump and tell which parts are missing from it.
+//!
+//! Finding references is guesswork. Three patterns cover most loaders:
+//!
+//! - `LOAD` statements of BASIC programs with a quoted name,
+//! - machine code calling `SETNAM` (`JSR $FFBD`) after loading the length
+//!   and address of a name into the registers with `LDA #`, `LDX #` and
+//!   `LDY #`,
+//! - drive commands reading a block, `U1`, `UA` or `B-R` with channel,
+//!   drive, track and sector.
+//!
+//! Names built at run time and blocks read by a fast loader of the
+//! program's own go unnoticed.
+
+use crate::basic::{Dialect, Start};
+use crate::error::DosError;
+use crate::fs::{self, FileType};
+use crate::image::BlockDevice;
+use crate::prg::Prg;
+use crate::tap::extract::TapeFile;
+use alloc::vec::Vec;
+
+/// The call of the Kernal's `SETNAM`.
+const SETNAM: [u8; 3] = [0x20, 0xBD, 0xFF];
+/// How many bytes before the call are searched for the register loads.
+const SETNAM_WINDOW: usize = 12;
+const BLOCK_READS: [&[u8]; 3] = [b"U1", b"UA", b"B-R"];
+
+/// What a program asks to load.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Reference {
+    /// A file by name, maybe with wildcards, without drive number and
+    /// type; empty for the next file on a tape.
+    Name(Vec<u8>),
+    /// A block by track and sector.
+    Block(u8, u8),
+}
+
+/// The pattern a reference was found by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Via {
+    /// A `LOAD` statement.
+    Basic,
+    /// A call of `SETNAM`.
+    Setnam,
+    /// A drive command.
+    Command,
+}
+
+/// A reference in the code of a program.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Found {
+    pub reference: Reference,
+    pub via: Via,
+    /// Where in the body of the program it was found.
+    pub offset: usize,
+}
+
+/// Returns the references in `prg`, in the order they appear.
+pub fn references(prg: &Prg) -> Vec<Found> {
+    let mut found = Vec::new();
+    if matches!(prg.start(), Start::Basic | Start::Sys(_)) {
+        basic_loads(&prg.body, &mut found);
+    }
+    setnam_calls(prg, &mut found);
+    block_reads(&prg.body, &mut found);
+    found.sort_by_key(|found| found.offset);
+    found
+}
+
+/// Returns the file a name given to `LOAD` or `SETNAM` refers to: without
+/// drive number and what follows a comma. The directory and direct access
+/// buffers are no files.
+fn file_name(raw: &[u8]) -> Option<Vec<u8>> {
+    let name = match raw.iter().position(|&c| c == b':') {
+        Some(colon) => &raw[colon + 1..],
+        None => raw,
+    };
+    let end = name.iter().position(|&c| c == b',').unwrap_or(name.len());
+    match name[..end] {
+        [b'$' | b'#', ..] => None,
+        ref name => Some(name.to_vec()),
+    }
+}
+
+fn basic_loads(body: &[u8], found: &mut Vec<Found>) {
+    let Some(load) = Dialect::V2.token(b"LOAD") else {
+        return;
+    };
+    let mut at = 0;
+    while let Some(link) = body.get(at..at + 2)
+        && link != [0, 0]
+    {
+        let start = at + 4;
+        let Some(end) = body
+            .get(start..)
+            .and_then(|t| t.iter().position(|&c| c == 0))
+        else {
+            break;
+        };
+        let text = &body[start..start + end];
+        let mut quote = false;
+        for (i, &c) in text.iter().enumerate() {
+            quote ^= c == b'"';
+            if c != load.code || quote {
+                continue;
+            }
+            let spaces = text[i + 1..].iter().take_while(|&&c| c == b' ').count();
+            let from = i + 1 + spaces;
+            if text.get(from) != Some(&b'"') {
+                continue;
+            }
+            let name = &text[from + 1..];
+            let length = name.iter().position(|&c| c == b'"').unwrap_or(name.len());
+            if let Some(name) = file_name(&name[..length]) {
+                found.push(Found {
+                    reference: Reference::Name(name),
+                    via: Via::Basic,
+                    offset: start + from + 1,
+                });
+            }
+        }
+        at = start + end + 1;
+    }
+}
+
+fn setnam_calls(prg: &Prg, found: &mut Vec<Found>) {
+    let body = &prg.body;
+    for call in (0..body.len()).filter(|&i| body[i..].starts_with(&SETNAM)) {
+        let window = &body[call.saturating_sub(SETNAM_WINDOW)..call];
+        let (mut a, mut x, mut y) = (None, None, None);
+        for pair in window.windows(2) {
+            match pair[0] {
+                0xA9 => a = Some(pair[1]),
+                0xA2 => x = Some(pair[1]),
+                0xA0 => y = Some(pair[1]),
+                _ => {}
+            }
+        }
+        let (Some(length), Some(low), Some(high)) = (a, x, y) else {
+            continue;
+        };
+        let offset =
+            usize::from(u16::from_le_bytes([low, high])).wrapping_sub(usize::from(prg.load));
+        if let Some(name) = body.get(offset..offset + usize::from(length))
+            && let Some(name) = file_name(name)
+        {
+            found.push(Found {
+                reference: Reference::Name(name),
+                via: Via::Setnam,
+                offset: call,
+            });
+        }
+    }
+}
+
+/// Reads the four numbers of a block command, separated by spaces, commas
+/// or colons.
+fn block_numbers(text: &[u8]) -> Option<[u8; 4]> {
+    let mut numbers = [0; 4];
+    let mut rest = text;
+    for number in &mut numbers {
+        let skip = rest.iter().take_while(|c| b" ,:".contains(c)).count();
+        rest = &rest[skip..];
+        let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 || digits > 3 {
+            return None;
+        }
+        let value = rest[..digits]
+            .iter()
+            .fold(0u16, |value, &c| value * 10 + u16::from(c - b'0'));
+        *number = u8::try_from(value).ok()?;
+        rest = &rest[digits..];
+    }
+    Some(numbers)
+}
+
+fn block_reads(body: &[u8], found: &mut Vec<Found>) {
+    for offset in 0..body.len() {
+        let Some(command) = BLOCK_READS.iter().find(|c| body[offset..].starts_with(c)) else {
+            continue;
+        };
+        if let Some([_, _, track, sector]) = block_numbers(&body[offset + command.len()..]) {
+            found.push(Found {
+                reference: Reference::Block(track, sector),
+                via: Via::Command,
+                offset,
+            });
+        }
+    }
+}
+
+/// Where a reference leads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// The file of this index.
+    File(usize),
+    /// A block in use, but by none of the files: data a loader keeps
+    /// outside the directory.
+    Block,
+    /// Nothing the dump holds.
+    Missing,
+}
+
+/// A reference of a file and where it leads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Link {
+    /// The index of the file the reference was found in.
+    pub from: usize,
+    pub found: Found,
+    pub target: Target,
+}
+
+/// The references between the files of a dump.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Analysis {
+    /// The names of the files, without padding, which the indices of the
+    /// links refer to.
+    pub names: Vec<Vec<u8>>,
+    /// Every reference of every file, by file and then in order.
+    pub links: Vec<Link>,
+}
+
+impl Analysis {
+    /// Returns the references leading to nothing the dump holds.
+    pub fn missing(&self) -> impl Iterator<Item = &Link> {
+        self.links
+            .iter()
+            .filter(|link| link.target == Target::Missing)
+    }
+
+    /// Returns the chain of loads from the file `start`: it and every file
+    /// it loads directly or through others, in the order they are first
+    /// referenced.
+    pub fn chain(&self, start: usize) -> Vec<usize> {
+        let mut chain = Vec::from([start]);
+        let mut next = 0;
+        while let Some(&from) = chain.get(next) {
+            for link in self.links.iter().filter(|link| link.from == from) {
+                if let Target::File(to) = link.target
+                    && !chain.contains(&to)
+                {
+                    chain.push(to);
+                }
+            }
+            next += 1;
+        }
+        chain
+    }
+}
+
+fn links<F: Fn(usize, &Reference) -> Target>(programs: &[Prg], resolve: F) -> Vec<Link> {
+    let mut links = Vec::new();
+    for (from, prg) in programs.iter().enumerate() {
+        for found in references(prg) {
+            let target = resolve(from, &found.reference);
+            links.push(Link {
+                from,
+                found,
+                target,
+            });
+        }
+    }
+    links
+}
+
+/// Follows the references between the PRG files of a disk.
+///
+/// Names resolve to the first file they match, as `LOAD` finds it. A block
+/// resolves to the file whose chain holds it, to [`Target::Block`] if the
+/// BAM has it in use and to [`Target::Missing`] if it is free or not on
+/// the disk.
+///
+/// # Errors
+/// Fails if the directory or a file cannot be read.
+pub fn analyze_disk<I: BlockDevice + ?Sized>(image: &I) -> Result<Analysis, DosError> {
+    let bam = fs::Bam::read(image)?;
+    let mut names = Vec::new();
+    let mut programs = Vec::new();
+    let mut chains = Vec::new();
+    for entry in fs::read_directory(image)?.entries {
+        if entry.file_type != FileType::Prg || !entry.closed {
+            continue;
+        }
+        let Ok(prg) = Prg::from_bytes(&fs::read_file(image, &entry)?) else {
+            continue;
+        };
+        names.push(entry.name().to_vec());
+        programs.push(prg);
+        chains.push(fs::chain(image, entry.track, entry.sector)?);
+    }
+    let links = links(&programs, |_, reference| match reference {
+        Reference::Name(pattern) => names
+            .iter()
+            .position(|name| fs::matches(pattern, &fs::pad_name(name)))
+            .map_or(Target::Missing, Target::File),
+        &Reference::Block(track, sector) => {
+            match chains.iter().position(|c| c.contains(&(track, sector))) {
+                Some(file) => Target::File(file),
+                None if image.contains(track, sector) && !bam.is_free(track, sector) => {
+                    Target::Block
+                }
+                None => Target::Missing,
+            }
+        }
+    });
+    Ok(Analysis { names, links })
+}
+
+/// Follows the references between the programs of a tape.
+///
+/// As the Kernal searches a tape, a name resolves to the first program
+/// after the loading one whose name starts with it, an empty name to the
+/// program right after it. Blocks are never on a tape.
+pub fn analyze_tape(files: &[TapeFile]) -> Analysis {
+    let names: Vec<Vec<u8>> = files
+        .iter()
+        .map(|file| {
+            let end = file
+                .name
+                .iter()
+                .rposition(|&b| b != b' ')
+                .map_or(0, |i| i + 1);
+            file.name[..end].to_vec()
+        })
+        .collect();
+    let programs: Vec<Prg> = files.iter().map(|file| file.prg.clone()).collect();
+    let links = links(&programs, |from, reference| match reference {
+        Reference::Name(pattern) => files
+            .iter()
+            .enumerate()
+            .skip(from + 1)
+            .find(|(_, file)| file.name.starts_with(pattern))
+            .map_or(Target::Missing, |(to, _)| Target::File(to)),
+        Reference::Block(..) => Target::Missing,
+    });
+    Analysis { names, links }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::{self, BASIC_START};
+    use crate::d64::D64;
+    use crate::tap::extract::Loader;
+    use alloc::format;
+
+    /// Machine code at `$C000` calling `SETNAM` for `name` and `LOAD`.
+    fn loader(name: &[u8]) -> Prg {
+        let address = 0xC000u16 + 15;
+        let [low, high] = address.to_le_bytes();
+        let mut body = [0xA9, name.len() as u8, 0xA2, low, 0xA0, high].to_vec();
+        body.extend_from_slice(&SETNAM);
+        body.extend_from_slice(&[0x20, 0xD5, 0xFF, 0x4C, 0x00, 0x08]);
+        body.extend_from_slice(name);
+        Prg::new(0xC000, body)
+    }
+
+    #[test]
+    fn finds_references_in_code() {
+        let boot = basic::tokenize(
+            b"10 PRINT\"\x93LOADING\":LOAD\"0:LOADER,P\",8,1\r20 LOAD \"$\",8",
+            BASIC_START,
+            &Dialect::V2,
+        )
+        .unwrap();
+        let found = references(&Prg::from_bytes(&boot).unwrap());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].reference, Reference::Name(b"LOADER".to_vec()));
+        assert_eq!(found[0].via, Via::Basic);
+
+        let mut code = loader(b"PART*");
+        code.body.extend_from_slice(b"U1:2 0 18 5\0U1:2 0 ");
+        let found = references(&code);
+        assert_eq!(found[0].reference, Reference::Name(b"PART*".to_vec()));
+        assert_eq!(found[0].via, Via::Setnam);
+        assert_eq!(found[1].reference, Reference::Block(18, 5));
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn follows_chains_and_reports_missing_parts() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"GAME", Some(*b"GM")).unwrap();
+        let boot = basic::tokenize(b"10 LOAD\"LOADER\",8,1", BASIC_START, &Dialect::V2).unwrap();
+        fs::write_file(&mut image, b"BOOT", FileType::Prg, &boot).unwrap();
+        let part = fs::write_file(&mut image, b"PART1", FileType::Prg, &[0, 0x10, 1]).unwrap();
+        let mut code = loader(b"PART1");
+        let read = format!("B-R 2 0 20 0 U1 2 0 {} {}", part.track, part.sector);
+        code.body.extend_from_slice(read.as_bytes());
+        fs::write_file(&mut image, b"LOADER", FileType::Prg, &code.to_bytes()).unwrap();
+        fs::write_file(
+            &mut image,
+            b"OTHER",
+            FileType::Prg,
+            &loader(b"END").to_bytes(),
+        )
+        .unwrap();
+        let mut bam = fs::Bam::read(&image).unwrap();
+        bam.allocate(20, 0);
+        bam.write(&mut image).unwrap();
+
+        let analysis = analyze_disk(&image).unwrap();
+        assert_eq!(analysis.names[2], b"LOADER");
+        assert_eq!(analysis.chain(0), [0, 2, 1]);
+        let targets: Vec<Target> = analysis.links.iter().map(|l| l.target).collect();
+        assert_eq!(
+            targets[..4],
+            [
+                Target::File(2),
+                Target::File(1),
+                Target::Block,
+                Target::File(1)
+            ]
+        );
+        let missing: Vec<&Link> = analysis.missing().collect();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].found.reference, Reference::Name(b"END".to_vec()));
+
+        let tape = |name: &[u8], prg: Prg| {
+            let mut padded = [b' '; 16];
+            padded[..name.len()].copy_from_slice(name);
+            TapeFile {
+                name: padded,
+                loader: Loader::Kernal,
+                pulse: 0,
+                prg,
+                verified: true,
+            }
+        };
+        let files = [
+            tape(b"INTRO", loader(b"")),
+            tape(b"GAME", loader(b"INTRO")),
+            tape(b"INTRO2", Prg::new(0x0801, Vec::new())),
+        ];
+        let analysis = analyze_tape(&files);
+        assert_eq!(analysis.chain(0), [0, 1, 2]);
+        assert_eq!(analysis.names[0], b"INTRO");
+        assert_eq!(analysis.missing().count(), 0);
+    }
+}
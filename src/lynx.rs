@@ -0,0 +1,284 @@
+//! Lynx archives.
+//!
+//! Lynx, the archiver C64 BBSes passed much of their software around with,
+//! writes its archives as a program: a BASIC line telling the user to
+//! dissolve it, a text directory and the files block by block, each padded
+//! to whole disk blocks but the last:
+//!
+//! ```plaintext
+//! stub       a BASIC program, followed by CR
+//! signature  space, number of directory blocks, "  *LYNX", the version, CR
+//! count      space, number of files, space, CR
+//! entry      name padded to 16 bytes with shifted spaces, CR,
+//!            space, blocks, space, CR, type letter, CR,
+//!            space, last block index, space, CR
+//! files      from the end of the directory blocks on
+//! ```
+//!
+//! Blocks are 254 bytes, as on disk, counted from the load address of the
+//! archive on, and the last block index is that of the last byte of the
+//! last block plus one, as the link of a last block holds it. REL files,
+//! whose blocks Lynx mixes with their side sectors, are not supported.
+
+use crate::basic::{self, BASIC_START, Dialect};
+use crate::fs::{self, BLOCK_PAYLOAD, FileType, NAME_LENGTH};
+use crate::image::ImageError;
+use alloc::format;
+use alloc::vec::Vec;
+
+/// The word the signature line holds.
+pub const SIGNATURE: &[u8] = b"LYNX";
+
+const CR: u8 = 0x0D;
+
+/// A file in a Lynx archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LynxFile {
+    /// The name, without padding.
+    pub name: Vec<u8>,
+    pub file_type: FileType,
+    /// The contents, for programs with their load address.
+    pub data: Vec<u8>,
+}
+
+/// A Lynx archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lynx {
+    /// The files, in the order of the directory.
+    pub files: Vec<LynxFile>,
+}
+
+/// Returns the offset behind the BASIC program at the start of `bytes`.
+fn skip_stub(bytes: &[u8]) -> Result<usize, ImageError> {
+    let mut at = 2;
+    loop {
+        let link = bytes.get(at..at + 2).ok_or(ImageError::Truncated)?;
+        if link == [0, 0] {
+            return Ok(at + 2);
+        }
+        let end = bytes
+            .get(at + 4..)
+            .and_then(|line| line.iter().position(|&b| b == 0))
+            .ok_or(ImageError::Truncated)?;
+        at += 4 + end + 1;
+    }
+}
+
+/// Reads the lines of the directory, each up to its CR.
+struct Lines<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn next(&mut self) -> Result<&'a [u8], ImageError> {
+        let rest = self.bytes.get(self.at..).unwrap_or_default();
+        let end = rest
+            .iter()
+            .position(|&b| b == CR)
+            .ok_or(ImageError::Truncated)?;
+        self.at += end + 1;
+        Ok(&rest[..end])
+    }
+
+    fn number(&mut self) -> Result<usize, ImageError> {
+        leading_number(self.next()?).ok_or(ImageError::Unsupported("Lynx number"))
+    }
+}
+
+/// Parses the decimal number at the start of `line`, behind its spaces.
+fn leading_number(line: &[u8]) -> Option<usize> {
+    let line = &line[line.iter().position(|&b| b != b' ')?..];
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    core::str::from_utf8(&line[..digits]).ok()?.parse().ok()
+}
+
+impl Lynx {
+    /// Parses a Lynx archive.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] if the line behind the BASIC stub
+    ///   is not the signature.
+    /// - [`ImageError::Truncated`] if the directory or a file extends
+    ///   beyond the end.
+    /// - [`ImageError::Unsupported`] for numbers that are none, type
+    ///   letters DOS does not know and REL files.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut lines = Lines {
+            bytes,
+            at: skip_stub(bytes)?,
+        };
+        if bytes.get(lines.at) == Some(&CR) {
+            lines.at += 1;
+        }
+        let signature = lines.next()?;
+        if !signature.windows(SIGNATURE.len()).any(|w| w == SIGNATURE) {
+            return Err(ImageError::InvalidSignature);
+        }
+        let directory =
+            leading_number(signature).ok_or(ImageError::InvalidSignature)? * BLOCK_PAYLOAD;
+        let count = lines.number()?;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = fs::trim_name(lines.next()?).to_vec();
+            let blocks = lines.number()?;
+            let file_type = match lines.next()? {
+                b"D" => FileType::Del,
+                b"S" => FileType::Seq,
+                b"P" => FileType::Prg,
+                b"U" => FileType::Usr,
+                b"R" => return Err(ImageError::Unsupported("Lynx REL file")),
+                _ => return Err(ImageError::Unsupported("Lynx file type")),
+            };
+            let last = lines.number()?;
+            let length = (blocks.max(1) - 1) * BLOCK_PAYLOAD + last.saturating_sub(1);
+            entries.push((name, file_type, blocks, length));
+        }
+        let mut at = directory;
+        let mut files = Vec::with_capacity(count);
+        for (name, file_type, blocks, length) in entries {
+            let data = bytes.get(at..at + length).ok_or(ImageError::Truncated)?;
+            at += blocks * BLOCK_PAYLOAD;
+            files.push(LynxFile {
+                name,
+                file_type,
+                data: data.to_vec(),
+            });
+        }
+        Ok(Lynx { files })
+    }
+
+    /// Serializes the archive, with names cut to 16 bytes.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::Unsupported`] for REL files.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ImageError> {
+        let stub = basic::tokenize(
+            b"10 PRINT\"USE LYNX TO DISSOLVE THIS FILE\"",
+            BASIC_START,
+            &Dialect::V2,
+        )
+        .unwrap_or_else(|_| unreachable!());
+        let mut entries = format!(" {} \r", self.files.len()).into_bytes();
+        for file in &self.files {
+            let letter = match file.file_type {
+                FileType::Del => 'D',
+                FileType::Seq => 'S',
+                FileType::Prg => 'P',
+                FileType::Usr => 'U',
+                FileType::Rel => return Err(ImageError::Unsupported("Lynx REL file")),
+            };
+            let mut name = [fs::PAD; NAME_LENGTH];
+            let length = file.name.len().min(NAME_LENGTH);
+            name[..length].copy_from_slice(&file.name[..length]);
+            entries.extend_from_slice(&name);
+            let (blocks, last) = Self::blocks(file);
+            entries.extend(format!("\r {blocks} \r{letter}\r {last} \r").bytes());
+        }
+        // The signature counts the blocks it is in, which it may grow by.
+        let mut directory = 1;
+        let mut bytes = loop {
+            let mut bytes = stub.clone();
+            bytes.push(CR);
+            bytes.extend(format!(" {directory}  *LYNX XII  BY WILL CORLEY\r").bytes());
+            bytes.extend_from_slice(&entries);
+            let needed = bytes.len().div_ceil(BLOCK_PAYLOAD);
+            if needed <= directory {
+                break bytes;
+            }
+            directory = needed;
+        };
+        bytes.resize(directory * BLOCK_PAYLOAD, 0);
+        for (i, file) in self.files.iter().enumerate() {
+            bytes.extend_from_slice(&file.data);
+            if i + 1 < self.files.len() {
+                let (blocks, _) = Self::blocks(file);
+                bytes.resize(bytes.len() + blocks * BLOCK_PAYLOAD - file.data.len(), 0);
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the blocks of `file` and the index of its last byte in the
+    /// last block plus one.
+    fn blocks(file: &LynxFile) -> (usize, usize) {
+        let blocks = file.data.len().div_ceil(BLOCK_PAYLOAD).max(1);
+        (blocks, file.data.len() - (blocks - 1) * BLOCK_PAYLOAD + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Lynx {
+        Lynx {
+            files: vec![
+                LynxFile {
+                    name: b"INTRO".to_vec(),
+                    file_type: FileType::Prg,
+                    data: (0..BLOCK_PAYLOAD).map(|i| i as u8).collect(),
+                },
+                LynxFile {
+                    name: b"EMPTY".to_vec(),
+                    file_type: FileType::Seq,
+                    data: Vec::new(),
+                },
+                LynxFile {
+                    name: b"GAME".to_vec(),
+                    file_type: FileType::Prg,
+                    data: vec![0xEA; 600],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_archives() {
+        let lynx = archive();
+        let bytes = lynx.to_bytes().unwrap();
+        assert_eq!(&bytes[..2], [0x01, 0x08]);
+        assert_eq!(bytes.len(), 3 * BLOCK_PAYLOAD + 600);
+        assert_eq!(bytes[BLOCK_PAYLOAD..2 * BLOCK_PAYLOAD], lynx.files[0].data);
+        let text = &bytes[..BLOCK_PAYLOAD];
+        let start = text.windows(6).position(|w| w == b"INTRO\xA0").unwrap();
+        assert_eq!(&text[start + 16..start + 27], b"\r 1 \rP\r 255");
+        assert_eq!(Lynx::from_bytes(&bytes).unwrap(), lynx);
+
+        let mut rel = archive();
+        rel.files[1].file_type = FileType::Rel;
+        assert_eq!(
+            rel.to_bytes(),
+            Err(ImageError::Unsupported("Lynx REL file"))
+        );
+    }
+
+    #[test]
+    fn rejects_damaged_archives() {
+        let bytes = archive().to_bytes().unwrap();
+        assert_eq!(
+            Lynx::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ImageError::Truncated)
+        );
+        let mut other = bytes.clone();
+        let at = other[..BLOCK_PAYLOAD]
+            .windows(4)
+            .rposition(|w| w == SIGNATURE)
+            .unwrap();
+        other[at] = b'X';
+        assert_eq!(Lynx::from_bytes(&other), Err(ImageError::InvalidSignature));
+        assert_eq!(Lynx::from_bytes(&[0x01, 0x08]), Err(ImageError::Truncated));
+        let many = Lynx {
+            files: (0..20)
+                .map(|i| LynxFile {
+                    name: format!("FILE {i}").into_bytes(),
+                    file_type: FileType::Usr,
+                    data: vec![i; 10],
+                })
+                .collect(),
+        };
+        let bytes = many.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 * BLOCK_PAYLOAD + 19 * BLOCK_PAYLOAD + 10);
+        assert_eq!(Lynx::from_bytes(&bytes).unwrap(), many);
+    }
+}
@@ -0,0 +1,176 @@
+//! Repairing a disk from several dumps.
+//!
+//! A worn disk rarely reads the same twice: each dump of it loses other
+//! sectors. [`merge`] takes the sectors each dump reads without error and
+//! puts them together into one image, choosing by majority where the
+//! dumps read a sector differently, and tells which sectors none of them
+//! could read.
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::image::{BlockDevice, Sector};
+use alloc::vec::Vec;
+
+/// A sector the dumps read with different contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dispute {
+    pub track: u8,
+    pub sector: u8,
+    /// How many dumps read the contents chosen.
+    pub votes: usize,
+    /// How many dumps read the sector without error.
+    pub reads: usize,
+}
+
+/// The image merged from several dumps, with what could not be repaired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+    /// The merged image, with an error block if a sector is unreadable.
+    pub image: D64,
+    /// The sectors that no dump reads, with the error of the first dump
+    /// that has the sector, in track order.
+    pub unreadable: Vec<(u8, u8, DosError)>,
+    /// The sectors the dumps disagree on, in track order.
+    pub disputed: Vec<Dispute>,
+}
+
+impl Merge {
+    /// Whether every sector was read without error and without dispute.
+    pub fn is_clean(&self) -> bool {
+        self.unreadable.is_empty() && self.disputed.is_empty()
+    }
+}
+
+fn read(image: &dyn BlockDevice, track: u8, sector: u8) -> Result<Sector, DosError> {
+    match image.sector_error(track, sector) {
+        Some(error) => Err(error),
+        None => image.read_sector(track, sector),
+    }
+}
+
+/// Merges the dumps `sources` of one disk into a D64.
+///
+/// Each sector is taken from the dumps that read it without error, see
+/// [`BlockDevice::sector_error`]; where they read it differently, the
+/// contents most of them read win, the first dump's on a tie. A sector no
+/// dump reads keeps what the first dump gives for it, with its error in
+/// the error block. The image has 40 tracks if a dump has more than 35.
+///
+/// # Panics
+/// Panics if `sources` is empty.
+pub fn merge(sources: &[&dyn BlockDevice]) -> Merge {
+    assert!(!sources.is_empty(), "no dumps to merge");
+    let tracks = sources.iter().map(|s| s.tracks()).max().unwrap_or(35);
+    let mut image = D64::new(if tracks > 35 { 40 } else { 35 });
+    let mut unreadable = Vec::new();
+    let mut disputed = Vec::new();
+    for track in 1..=image.tracks() {
+        for sector in 0..image.sectors_per_track(track) {
+            let holders = sources.iter().filter(|s| s.contains(track, sector));
+            let mut votes: Vec<(Sector, usize)> = Vec::new();
+            let mut first = None;
+            for source in holders {
+                match read(*source, track, sector) {
+                    Ok(data) => match votes.iter_mut().find(|(d, _)| *d == data) {
+                        Some((_, count)) => *count += 1,
+                        None => votes.push((data, 1)),
+                    },
+                    Err(error) => {
+                        first.get_or_insert((*source, error));
+                    }
+                }
+            }
+            let mut best: Option<&(Sector, usize)> = None;
+            for vote in &votes {
+                if best.is_none_or(|b| vote.1 > b.1) {
+                    best = Some(vote);
+                }
+            }
+            let data = match (best, first) {
+                (Some((data, count)), _) => {
+                    if votes.len() > 1 {
+                        disputed.push(Dispute {
+                            track,
+                            sector,
+                            votes: *count,
+                            reads: votes.iter().map(|v| v.1).sum(),
+                        });
+                    }
+                    *data
+                }
+                (None, Some((source, error))) => {
+                    unreadable.push((track, sector, error));
+                    let _ = image.set_sector_error(track, sector, Some(error));
+                    source.read_sector(track, sector).unwrap_or([0; 256])
+                }
+                (None, None) => continue,
+            };
+            let _ = image.write_sector(track, sector, &data);
+        }
+    }
+    Merge {
+        image,
+        unreadable,
+        disputed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, FileType};
+
+    fn dump() -> D64 {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"WORN", Some(*b"01")).unwrap();
+        fs::write_file(&mut image, b"DATA", FileType::Seq, &[9; 2000]).unwrap();
+        image
+    }
+
+    #[test]
+    fn takes_good_sectors_from_each_dump() {
+        let disk = dump();
+        let mut a = disk.clone();
+        a.set_sector_error(17, 0, Some(DosError::DataBlockNotPresent))
+            .unwrap();
+        a.set_sector_error(1, 5, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut b = disk.clone();
+        b.set_sector_error(17, 1, Some(DosError::DataBlockNotPresent))
+            .unwrap();
+        b.set_sector_error(1, 5, Some(DosError::NoSync)).unwrap();
+        let merged = merge(&[&a, &b]);
+        assert_eq!(merged.unreadable, [(1, 5, DosError::DataChecksum)]);
+        assert!(merged.disputed.is_empty());
+        assert_eq!(
+            merged.image.sector_error(1, 5),
+            Some(DosError::DataChecksum)
+        );
+        assert_eq!(merged.image.sector_error(17, 0), None);
+        assert_eq!(merged.image.read_sector(17, 0), disk.read_sector(17, 0));
+        assert_eq!(merged.image.read_sector(17, 1), disk.read_sector(17, 1));
+        assert!(!merged.is_clean());
+    }
+
+    #[test]
+    fn outvotes_bad_reads() {
+        let disk = dump();
+        let mut bad = disk.clone();
+        let mut data = bad.read_sector(17, 0).unwrap();
+        data[100] ^= 0x10;
+        bad.write_sector(17, 0, &data).unwrap();
+        let merged = merge(&[&bad, &disk, &disk]);
+        assert_eq!(
+            merged.disputed,
+            [Dispute {
+                track: 17,
+                sector: 0,
+                votes: 2,
+                reads: 3,
+            }]
+        );
+        assert_eq!(merged.image.read_sector(17, 0), disk.read_sector(17, 0));
+        assert_eq!(merged.image.error_bytes(), None);
+        assert!(merge(&[&disk]).is_clean());
+    }
+}
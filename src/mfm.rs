@@ -0,0 +1,450 @@
+//! MFM encoding and the IBM System/34 track layout.
+//!
+//! The 1581 and the CP/M formats of the 1571 record their disks like a PC
+//! does: every data bit is preceded by a clock bit, set only between two
+//! zero data bits, and each track carries address marks in the format IBM
+//! introduced with the System/34:
+//!
+//! ```plaintext
+//! gap 4a   80 × $4E, 12 × $00, 3 × $C2*, $FC (index mark), 50 × $4E
+//! sector   12 × $00, 3 × $A1*, $FE, cylinder, head, sector, size, CRC,
+//!          22 × $4E, 12 × $00, 3 × $A1*, $FB, data, CRC, gap 3 × $4E
+//! gap 4b   $4E up to the end of the track
+//! ```
+//!
+//! The marked bytes are written with one clock bit left out, which no
+//! data can produce, so a reader finds them anywhere in the bitstream:
+//! `$A1` as the cells `$4489`, `$C2` as `$5224`. The size code `n` stands
+//! for `128 << n` data bytes. Both CRCs are CRC-16/CCITT, [`crc16`], over
+//! everything from the first `$A1` on.
+//!
+//! Tracks here are bitstreams of cells, MSB first, as [`crate::flux`]
+//! produces them at twice the data rate. [`Placement`] maps the logical
+//! sectors of a D81 onto the physical ones of the 1581, and [`encode_1581`]
+//! and [`read_1581`] master and read its tracks.
+
+use crate::error::DosError;
+use alloc::{vec, vec::Vec};
+
+/// The cells of `$A1` with a missing clock bit.
+pub const SYNC_A1: u16 = 0x4489;
+/// The cells of `$C2` with a missing clock bit.
+pub const SYNC_C2: u16 = 0x5224;
+/// The mark of an ID field.
+pub const ID_MARK: u8 = 0xFE;
+/// The mark of a data field.
+pub const DATA_MARK: u8 = 0xFB;
+/// The mark of a data field of a deleted sector.
+pub const DELETED_MARK: u8 = 0xF8;
+/// The index mark.
+pub const INDEX_MARK: u8 = 0xFC;
+/// The gap byte.
+pub const GAP_BYTE: u8 = 0x4E;
+
+/// Returns the CRC-16/CCITT of `data`, as the controller computes it:
+/// polynomial `$1021`, starting from `$FFFF`.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Appends the cells of `data` to `cells`, MSB first.
+///
+/// `last` is the data bit written before, which decides the first clock
+/// bit, and is updated to the last data bit.
+fn push_cells(cells: &mut Vec<u8>, data: &[u8], last: &mut bool) {
+    for &byte in data {
+        let mut word = 0u16;
+        for bit in (0..8).rev() {
+            let one = byte >> bit & 1 != 0;
+            let clock = !*last && !one;
+            word = word << 2 | u16::from(clock) << 1 | u16::from(one);
+            *last = one;
+        }
+        cells.extend_from_slice(&word.to_be_bytes());
+    }
+}
+
+/// Returns the MFM cells of `data`, two bytes for each byte.
+///
+/// `last` is the data bit written before, as the first clock bit depends
+/// on it.
+pub fn encode(data: &[u8], last: bool) -> Vec<u8> {
+    let mut cells = Vec::with_capacity(data.len() * 2);
+    push_cells(&mut cells, data, &mut { last });
+    cells
+}
+
+/// Returns the data bits of `cells`, one byte for every two, dropping the
+/// clock bits without checking them.
+pub fn decode(cells: &[u8]) -> Vec<u8> {
+    cells
+        .chunks_exact(2)
+        .map(|pair| data_bits(u16::from_be_bytes([pair[0], pair[1]])))
+        .collect()
+}
+
+/// Returns the data byte of 16 cells.
+fn data_bits(word: u16) -> u8 {
+    (0..8).fold(0, |byte, bit| {
+        byte << 1 | (word >> (14 - 2 * bit) & 1) as u8
+    })
+}
+
+/// The layout of the tracks of an MFM format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Format {
+    /// The number of sectors on each track.
+    pub sectors: u8,
+    /// The number of the first sector.
+    pub first_sector: u8,
+    /// The size code, `128 << size` bytes a sector.
+    pub size: u8,
+    /// The length of the gap after each data field.
+    pub gap3: usize,
+    /// The number of bytes in each track.
+    pub track_length: usize,
+}
+
+impl Format {
+    /// The 1581: ten sectors of 512 bytes, numbered from 1.
+    pub const C1581: Format = Format {
+        sectors: 10,
+        first_sector: 1,
+        size: 2,
+        gap3: 35,
+        track_length: 6250,
+    };
+
+    /// Returns the number of data bytes in a sector.
+    pub fn sector_size(&self) -> usize {
+        128 << self.size
+    }
+}
+
+/// Lays out a track of `sectors` in `format` and returns its cells.
+///
+/// # Panics
+/// Panics if `sectors` does not hold [`Format::sectors`] sectors of
+/// [`Format::sector_size`] bytes.
+pub fn encode_track(format: &Format, cylinder: u8, head: u8, sectors: &[Vec<u8>]) -> Vec<u8> {
+    assert_eq!(sectors.len(), usize::from(format.sectors), "sector count");
+    let mut cells = Vec::with_capacity(format.track_length * 2);
+    let mut last = false;
+    let marks = |cells: &mut Vec<u8>, last: &mut bool, sync: u16| {
+        for _ in 0..3 {
+            cells.extend_from_slice(&sync.to_be_bytes());
+        }
+        // Both marks end in a zero data bit.
+        *last = false;
+    };
+
+    push_cells(&mut cells, &[GAP_BYTE; 80], &mut last);
+    push_cells(&mut cells, &[0; 12], &mut last);
+    marks(&mut cells, &mut last, SYNC_C2);
+    push_cells(&mut cells, &[INDEX_MARK], &mut last);
+    push_cells(&mut cells, &[GAP_BYTE; 50], &mut last);
+    for (number, data) in (format.first_sector..).zip(sectors) {
+        assert_eq!(data.len(), format.sector_size(), "sector size");
+        let mut id = vec![
+            0xA1,
+            0xA1,
+            0xA1,
+            ID_MARK,
+            cylinder,
+            head,
+            number,
+            format.size,
+        ];
+        id.extend_from_slice(&crc16(&id).to_be_bytes());
+        push_cells(&mut cells, &[0; 12], &mut last);
+        marks(&mut cells, &mut last, SYNC_A1);
+        push_cells(&mut cells, &id[3..], &mut last);
+        push_cells(&mut cells, &[GAP_BYTE; 22], &mut last);
+
+        let mut field = vec![0xA1, 0xA1, 0xA1, DATA_MARK];
+        field.extend_from_slice(data);
+        field.extend_from_slice(&crc16(&field).to_be_bytes());
+        push_cells(&mut cells, &[0; 12], &mut last);
+        marks(&mut cells, &mut last, SYNC_A1);
+        push_cells(&mut cells, &field[3..], &mut last);
+        push_cells(&mut cells, &vec![GAP_BYTE; format.gap3], &mut last);
+    }
+    let rest = (format.track_length * 2).saturating_sub(cells.len()) / 2;
+    push_cells(&mut cells, &vec![GAP_BYTE; rest], &mut last);
+    cells
+}
+
+/// The result of reading a sector from an MFM track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorRead {
+    /// The data, unless no data field was found. With a CRC error it
+    /// holds what was read.
+    pub data: Option<Vec<u8>>,
+    /// The error the drive reports for the sector.
+    pub error: Option<DosError>,
+    /// Whether the data field carries the deleted data mark.
+    pub deleted: bool,
+}
+
+impl SectorRead {
+    fn failed(error: DosError) -> Self {
+        SectorRead {
+            data: None,
+            error: Some(error),
+            deleted: false,
+        }
+    }
+}
+
+/// Returns the cell offsets just behind every run of `$A1` marks.
+fn find_marks(cells: &[u8]) -> Vec<usize> {
+    let mut found = Vec::new();
+    let mut word = 0u16;
+    let mut run_end = None;
+    for bit in 0..cells.len() * 8 {
+        word = word << 1 | u16::from(cells[bit / 8] >> (7 - bit % 8) & 1);
+        if word == SYNC_A1 {
+            run_end = Some(bit + 1);
+        } else if run_end.is_some_and(|end| bit + 1 >= end + 16) {
+            found.extend(run_end.take());
+        }
+    }
+    found.extend(run_end);
+    found
+}
+
+/// Returns `count` data bytes from the cells starting at cell `bit`.
+fn read_bytes(cells: &[u8], bit: usize, count: usize) -> Option<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            let start = bit + 16 * i;
+            if start + 16 > cells.len() * 8 {
+                return None;
+            }
+            let word = (start..start + 16).fold(0u16, |word, b| {
+                word << 1 | u16::from(cells[b / 8] >> (7 - b % 8) & 1)
+            });
+            Some(data_bits(word))
+        })
+        .collect()
+}
+
+/// Reads sector `sector` of `cylinder` and `head` from the cells of a track.
+///
+/// The ID field is looked up behind every run of `$A1` marks, and the data
+/// field is read behind the next run. The DOS error codes result as the
+/// 1581 reports them:
+///
+/// - 20: no ID field carries the sector.
+/// - 27: the CRC of the ID field is wrong.
+/// - 22: no data field follows.
+/// - 23: the CRC of the data field is wrong.
+pub fn read_sector(cells: &[u8], cylinder: u8, head: u8, sector: u8) -> SectorRead {
+    let marks = find_marks(cells);
+    let found = marks.iter().enumerate().find_map(|(i, &bit)| {
+        let id = read_bytes(cells, bit, 7)?;
+        (id[0] == ID_MARK && id[1..4] == [cylinder, head, sector]).then_some((i, id))
+    });
+    let Some((index, id)) = found else {
+        return SectorRead::failed(DosError::HeaderNotFound);
+    };
+    let mut field = vec![0xA1; 3];
+    field.extend_from_slice(&id[..5]);
+    if crc16(&field).to_be_bytes() != id[5..7] {
+        return SectorRead::failed(DosError::HeaderChecksum);
+    }
+
+    let size = 128usize << (id[4] & 3);
+    let Some(data) = marks
+        .get(index + 1)
+        .and_then(|&bit| read_bytes(cells, bit, size + 3))
+        .filter(|data| matches!(data[0], DATA_MARK | DELETED_MARK))
+    else {
+        return SectorRead::failed(DosError::DataBlockNotPresent);
+    };
+    let mut field = vec![0xA1; 3];
+    field.extend_from_slice(&data[..=size]);
+    let error = (crc16(&field).to_be_bytes() != data[size + 1..]).then_some(DosError::DataChecksum);
+    SectorRead {
+        data: Some(data[1..=size].to_vec()),
+        error,
+        deleted: data[0] == DELETED_MARK,
+    }
+}
+
+/// The place of a logical 1581 sector on the MFM disk.
+///
+/// The DOS of the 1581 splits each track of the D81 into 40 logical
+/// sectors of 256 bytes, while the controller writes ten physical sectors
+/// of 512 bytes on each side. Logical sectors 0 to 19 lie on head 0,
+/// 20 to 39 on head 1, two to each physical sector. The ID fields carry
+/// the head numbers swapped, as the 1581 wires its heads the other way
+/// round than a PC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Placement {
+    /// The cylinder, the logical track minus one.
+    pub cylinder: u8,
+    /// The physical head.
+    pub head: u8,
+    /// The physical sector, from 1.
+    pub sector: u8,
+    /// The offset of the logical sector in the physical one, 0 or 256.
+    pub offset: usize,
+}
+
+impl Placement {
+    /// Returns the place of logical `sector` on `track` of a 1581 disk, or
+    /// `None` past the 80 tracks of 40 sectors.
+    pub fn of(track: u8, sector: u8) -> Option<Self> {
+        if !(1..=80).contains(&track) || sector >= 40 {
+            return None;
+        }
+        Some(Placement {
+            cylinder: track - 1,
+            head: sector / 20,
+            sector: sector % 20 / 2 + 1,
+            offset: usize::from(sector % 2) * 256,
+        })
+    }
+
+    /// Returns the logical track and sector at this place, the inverse of
+    /// [`Placement::of`].
+    pub fn logical(&self) -> Option<(u8, u8)> {
+        let valid = self.cylinder < 80
+            && self.head < 2
+            && (1..=10).contains(&self.sector)
+            && matches!(self.offset, 0 | 256);
+        valid.then(|| {
+            let sector = self.head * 20 + (self.sector - 1) * 2 + (self.offset / 256) as u8;
+            (self.cylinder + 1, sector)
+        })
+    }
+
+    /// Returns the head number the ID field of the sector carries.
+    pub fn id_head(&self) -> u8 {
+        self.head ^ 1
+    }
+}
+
+/// Returns the cells of one side of a 1581 track, from the 40 logical
+/// sectors of `track` as a D81 stores them, 10240 bytes.
+///
+/// # Panics
+/// Panics if `track` is not a 1581 track, if `head` is not 0 or 1, or if
+/// `data` does not hold 10240 bytes.
+pub fn encode_1581(track: u8, head: u8, data: &[u8]) -> Vec<u8> {
+    assert_eq!(data.len(), 40 * 256, "track size");
+    let first = Placement::of(track, head * 20).expect("1581 track and head");
+    let side = &data[usize::from(head) * 20 * 256..][..20 * 256];
+    let sectors: Vec<Vec<u8>> = side.chunks_exact(512).map(<[u8]>::to_vec).collect();
+    encode_track(&Format::C1581, first.cylinder, first.id_head(), &sectors)
+}
+
+/// Reads logical `sector` of `track` from the cells of the side it lies
+/// on, see [`Placement::of`].
+///
+/// The data holds the 256 bytes of the logical sector; the error is the
+/// one of the physical sector holding it.
+///
+/// # Panics
+/// Panics if `track` and `sector` lie outside a 1581 disk.
+pub fn read_1581(cells: &[u8], track: u8, sector: u8) -> SectorRead {
+    let place = Placement::of(track, sector).expect("1581 track and sector");
+    let read = read_sector(cells, place.cylinder, place.id_head(), place.sector);
+    SectorRead {
+        data: read
+            .data
+            .filter(|data| data.len() == 512)
+            .map(|data| data[place.offset..][..256].to_vec()),
+        ..read
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_bytes_and_checks() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[0xA1, 0xA1, 0xA1, 0xFB]), 0xE295);
+        assert_eq!(encode(&[0x00], false), [0xAA, 0xAA]);
+        assert_eq!(encode(&[0x00], true), [0x2A, 0xAA]);
+        assert_eq!(encode(&[0xA1], false), [0x44, 0xA9]);
+        assert_eq!(encode(&[GAP_BYTE], false), [0x92, 0x54]);
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&data, false)), data);
+        assert_eq!(data_bits(SYNC_A1), 0xA1);
+        assert_eq!(data_bits(SYNC_C2), 0xC2);
+    }
+
+    #[test]
+    fn reads_sectors_of_a_1581_track() {
+        let format = Format::C1581;
+        let sectors: Vec<Vec<u8>> = (0..10).map(|s| vec![s * 3; 512]).collect();
+        let mut cells = encode_track(&format, 39, 1, &sectors);
+        assert_eq!(cells.len(), format.track_length * 2);
+        for sector in 1..=10 {
+            let read = read_sector(&cells, 39, 1, sector);
+            assert_eq!(read.error, None);
+            assert_eq!(
+                read.data.as_deref(),
+                Some(&sectors[usize::from(sector) - 1][..])
+            );
+        }
+        assert_eq!(
+            read_sector(&cells, 39, 0, 1).error,
+            Some(DosError::HeaderNotFound)
+        );
+
+        // Flip a data bit in the middle of sector 5.
+        let bit = find_marks(&cells)[9] + 16 * 200 + 1;
+        cells[bit / 8] ^= 0x80 >> (bit % 8);
+        let read = read_sector(&cells, 39, 1, 5);
+        assert_eq!(read.error, Some(DosError::DataChecksum));
+        assert_ne!(read.data.as_deref(), Some(&sectors[4][..]));
+        assert_eq!(read_sector(&cells, 39, 1, 6).error, None);
+    }
+
+    #[test]
+    fn maps_d81_sectors_to_both_sides() {
+        let place = Placement::of(40, 25).unwrap();
+        assert_eq!(
+            (place.cylinder, place.head, place.sector, place.offset),
+            (39, 1, 3, 256)
+        );
+        assert_eq!(place.id_head(), 0);
+        assert_eq!(Placement::of(81, 0), None);
+        assert_eq!(Placement::of(1, 40), None);
+        for sector in 0..40 {
+            assert_eq!(
+                Placement::of(7, sector).unwrap().logical(),
+                Some((7, sector))
+            );
+        }
+
+        let data: Vec<u8> = (0..40 * 256)
+            .map(|i| (i / 256 * 7 + i % 256) as u8)
+            .collect();
+        let sides = [encode_1581(3, 0, &data), encode_1581(3, 1, &data)];
+        for sector in 0..40 {
+            let place = Placement::of(3, sector).unwrap();
+            let read = read_1581(&sides[usize::from(place.head)], 3, sector);
+            assert_eq!(read.error, None);
+            let start = usize::from(sector) * 256;
+            assert_eq!(read.data.as_deref(), Some(&data[start..start + 256]));
+        }
+        assert_eq!(
+            read_1581(&sides[0], 3, 20).error,
+            Some(DosError::HeaderNotFound)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Behaviour profiles of the Commodore and CMD drive families.
+//!
+//! The drives share the DOS command language, but differ in disk geometry,
+//! in the commands they implement and in details such as the power-up
+//! message. A [`DriveModel`] collects these differences.
+//!
+//! [`Drive`](crate::drive::Drive) takes the commands, status messages, DOS
+//! type, burst mode and number of units from its model. The geometry only
+//! describes the model's own disks, for reports such as [`crate::json`]:
+//! [`crate::fs`] keeps the 1541 layout, with the directory on track 18,
+//! whatever the model.
+
+use crate::command::Command;
+use crate::d64;
+use crate::error::message_for_code;
+use core::fmt;
+
+/// A drive model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DriveModel {
+    /// The original 1541.
+    #[default]
+    C1541,
+    /// The 1541-II, identical to the 1541 as far as the DOS is concerned.
+    C1541II,
+    /// The double-sided 1571 with burst mode.
+    C1571,
+    /// The 3.5" 1581 with burst mode.
+    C1581,
+    /// The IEEE-488 single drive 2031.
+    C2031,
+    /// The IEEE-488 dual drive 4040.
+    C4040,
+    /// The IEEE-488 dual drive 8250 for double-sided 1 MB disks.
+    C8250,
+    /// A CMD FD-2000/FD-4000 floppy drive.
+    CmdFd,
+    /// A CMD HD series hard drive.
+    CmdHd,
+}
+
+impl DriveModel {
+    /// Every model, in the order they are declared.
+    pub const ALL: [DriveModel; 9] = [
+        DriveModel::C1541,
+        DriveModel::C1541II,
+        DriveModel::C1571,
+        DriveModel::C1581,
+        DriveModel::C2031,
+        DriveModel::C4040,
+        DriveModel::C8250,
+        DriveModel::CmdFd,
+        DriveModel::CmdHd,
+    ];
+
+    /// Returns the name the model is commonly known by.
+    pub fn name(self) -> &'static str {
+        match self {
+            DriveModel::C1541 => "1541",
+            DriveModel::C1541II => "1541-II",
+            DriveModel::C1571 => "1571",
+            DriveModel::C1581 => "1581",
+            DriveModel::C2031 => "2031",
+            DriveModel::C4040 => "4040",
+            DriveModel::C8250 => "8250",
+            DriveModel::CmdFd => "CMD FD",
+            DriveModel::CmdHd => "CMD HD",
+        }
+    }
+
+    /// Returns the message of status 73, which the drive reports after
+    /// power-up and reset.
+    pub fn dos_version(self) -> &'static str {
+        match self {
+            DriveModel::C1541 | DriveModel::C1541II => "CBM DOS V2.6 1541",
+            DriveModel::C1571 => "CBM DOS V3.0 1571",
+            DriveModel::C1581 => "COPYRIGHT CBM DOS V10 1581",
+            DriveModel::C2031 => "CBM DOS V2.6 2031",
+            DriveModel::C4040 => "CBM DOS V2",
+            DriveModel::C8250 => "CBM DOS V2.7",
+            DriveModel::CmdFd => "CMD FD DOS V1.40",
+            DriveModel::CmdHd => "CMD HD DOS V1.92",
+        }
+    }
+
+    /// Returns the message the model reports for status `code`.
+    pub fn message(self, code: u8) -> &'static str {
+        match code {
+            73 => self.dos_version(),
+            77 if self.has_partitions() => "SELECTED PARTITION ILLEGAL",
+            _ => message_for_code(code),
+        }
+    }
+
+    /// Returns the DOS type the model writes into the header when
+    /// formatting, e.g. `2A` for the 1541.
+    pub fn dos_type(self) -> [u8; 2] {
+        match self {
+            DriveModel::C1541
+            | DriveModel::C1541II
+            | DriveModel::C1571
+            | DriveModel::C2031
+            | DriveModel::C4040 => *b"2A",
+            DriveModel::C8250 => *b"2C",
+            DriveModel::C1581 => *b"3D",
+            DriveModel::CmdFd | DriveModel::CmdHd => *b"1H",
+        }
+    }
+
+    /// Returns the number of tracks on a disk formatted by the model.
+    pub fn tracks(self) -> u8 {
+        match self {
+            DriveModel::C1541 | DriveModel::C1541II | DriveModel::C2031 | DriveModel::C4040 => 35,
+            DriveModel::C1571 => 70,
+            DriveModel::C1581 => 80,
+            DriveModel::C8250 => 154,
+            DriveModel::CmdFd => 81,
+            DriveModel::CmdHd => 255,
+        }
+    }
+
+    /// Returns the number of logical sectors on `track`, or `0` if the track
+    /// does not exist.
+    ///
+    /// CMD native partitions hold 256 sectors per track, so the count does
+    /// not fit a `u8`.
+    pub fn sectors_per_track(self, track: u8) -> u16 {
+        if track == 0 || track > self.tracks() {
+            return 0;
+        }
+        let sectors = match self {
+            DriveModel::C1571 if track > 35 => d64::sectors_per_track(track - 35),
+            DriveModel::C1541
+            | DriveModel::C1541II
+            | DriveModel::C1571
+            | DriveModel::C2031
+            | DriveModel::C4040 => d64::sectors_per_track(track),
+            DriveModel::C8250 => match (track - 1) % 77 + 1 {
+                1..=39 => 29,
+                40..=53 => 27,
+                54..=64 => 25,
+                _ => 23,
+            },
+            DriveModel::C1581 | DriveModel::CmdFd => 40,
+            DriveModel::CmdHd => return 256,
+        };
+        sectors as u16
+    }
+
+    /// Returns the total number of sectors on a disk.
+    pub fn total_sectors(self) -> usize {
+        (1..=self.tracks())
+            .map(|t| self.sectors_per_track(t) as usize)
+            .sum()
+    }
+
+    /// Returns the track holding the header and directory on the model's
+    /// own disks.
+    pub fn directory_track(self) -> u8 {
+        match self {
+            DriveModel::C1581 | DriveModel::CmdFd => 40,
+            DriveModel::C8250 => 39,
+            DriveModel::CmdHd => 1,
+            _ => 18,
+        }
+    }
+
+    /// Returns the number of drive units in the housing.
+    pub fn drives(self) -> u8 {
+        match self {
+            DriveModel::C4040 | DriveModel::C8250 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Returns `true` if the model understands the `U0` burst commands.
+    pub fn has_burst(self) -> bool {
+        matches!(
+            self,
+            DriveModel::C1571 | DriveModel::C1581 | DriveModel::CmdFd | DriveModel::CmdHd
+        )
+    }
+
+    /// Returns `true` if the model divides its media into partitions.
+    pub fn has_partitions(self) -> bool {
+        matches!(
+            self,
+            DriveModel::C1581 | DriveModel::CmdFd | DriveModel::CmdHd
+        )
+    }
+
+    /// Returns `true` if the model is connected through IEEE-488 rather
+    /// than the serial bus.
+    pub fn is_ieee(self) -> bool {
+        matches!(
+            self,
+            DriveModel::C2031 | DriveModel::C4040 | DriveModel::C8250
+        )
+    }
+
+    /// Returns `true` if the model implements `command`.
+    ///
+    /// Burst commands need a burst-capable drive and `D` a dual drive.
+    /// Subdirectories and the clock are CMD additions, and the SD2IEC
+    /// configuration commands belong to no model here. All other commands
+    /// are common to every model.
+    pub fn supports(self, command: &Command) -> bool {
+        match command {
+            Command::Burst(_) => self.has_burst(),
+            Command::Duplicate { .. } => self.drives() > 1,
+            Command::ChangeDirectory { .. }
+            | Command::MakeDirectory { .. }
+            | Command::RemoveDirectory { .. }
+            | Command::TimeRead(_)
+            | Command::TimeWrite { .. } => {
+                matches!(self, DriveModel::CmdFd | DriveModel::CmdHd)
+            }
+            Command::Config(_) | Command::EepromRead { .. } | Command::EepromWrite { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for DriveModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry_matches_the_formats() {
+        assert_eq!(DriveModel::C1541.total_sectors(), 683);
+        assert_eq!(DriveModel::C1571.total_sectors(), 1366);
+        assert_eq!(DriveModel::C1581.total_sectors(), 3200);
+        assert_eq!(DriveModel::C8250.total_sectors(), 4166);
+        assert_eq!(DriveModel::CmdHd.sectors_per_track(1), 256);
+        assert_eq!(DriveModel::C1541.sectors_per_track(36), 0);
+    }
+
+    #[test]
+    fn commands_and_messages_depend_on_model() {
+        let burst = Command::Burst(crate::command::BurstCommand::InquireDisk { side: 0 });
+        assert!(!DriveModel::C1541.supports(&burst));
+        assert!(DriveModel::C1571.supports(&burst));
+        let duplicate = Command::Duplicate {
+            target: 1,
+            source: 0,
+        };
+        assert!(DriveModel::C4040.supports(&duplicate));
+        assert!(!DriveModel::C1581.supports(&duplicate));
+        let time = Command::TimeRead(crate::command::TimeFormat::Ascii);
+        assert!(DriveModel::CmdHd.supports(&time));
+        assert!(!DriveModel::C1571.supports(&time));
+        assert_eq!(DriveModel::C1581.message(73), "COPYRIGHT CBM DOS V10 1581");
+        assert_eq!(DriveModel::C1541.message(62), "FILE NOT FOUND");
+    }
+}
@@ -0,0 +1,477 @@
+//! The files of a disk as a host filesystem sees them.
+//!
+//! A host filesystem wants names it can store, byte offsets into files
+//! and attributes beside the contents. [`Mount`] gives it that on top of
+//! the DOS layer: names through [`petscii::to_host_name`], files held in a
+//! buffer while they are open and written back to the disk when the last
+//! handle lets go, and the file type, load address and lock flag as
+//! extended attributes. The `fuse` module mounts one on Linux.
+
+use crate::error::DosError;
+use crate::fs::{self, BLOCK_PAYLOAD, DirEntry, FileType, NAME_LENGTH};
+use crate::image::DiskImage;
+use crate::petscii;
+use alloc::collections::BTreeMap;
+use alloc::{format, string::String, vec::Vec};
+
+/// The attribute holding the file type, `PRG`, `SEQ`, `USR` or `REL`.
+pub const TYPE_ATTRIBUTE: &str = "user.cbm.type";
+/// The attribute holding the load address of a file, four hex digits.
+pub const LOAD_ATTRIBUTE: &str = "user.cbm.load_address";
+/// The attribute holding `1` for a locked file and `0` for others.
+pub const LOCKED_ATTRIBUTE: &str = "user.cbm.locked";
+/// The attributes every file has.
+pub const ATTRIBUTES: [&str; 3] = [TYPE_ATTRIBUTE, LOAD_ATTRIBUTE, LOCKED_ATTRIBUTE];
+
+/// A file as the host sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The host name of the file.
+    pub name: String,
+    /// Its size in bytes, that of the buffer while it is open.
+    pub size: usize,
+    pub entry: DirEntry,
+}
+
+/// An open file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Buffer {
+    data: Vec<u8>,
+    handles: usize,
+    dirty: bool,
+}
+
+/// A disk mounted as a host filesystem.
+#[derive(Debug)]
+pub struct Mount<I> {
+    image: I,
+    open: BTreeMap<Vec<u8>, Buffer>,
+}
+
+/// Returns the name on disk of the host name `host`.
+fn disk_name(host: &str) -> Result<Vec<u8>, DosError> {
+    petscii::from_host_name(host)
+        .filter(|name| !name.is_empty() && name.len() <= NAME_LENGTH)
+        .ok_or(DosError::InvalidFilename)
+}
+
+/// Reads a file type as the type attribute holds it.
+fn parse_type(value: &[u8]) -> Option<FileType> {
+    [FileType::Seq, FileType::Prg, FileType::Usr]
+        .into_iter()
+        .find(|t| value.eq_ignore_ascii_case(t.as_str().as_bytes()))
+}
+
+impl<I: DiskImage> Mount<I> {
+    /// Mounts `image`.
+    pub fn new(image: I) -> Self {
+        Mount {
+            image,
+            open: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the image.
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+
+    /// Writes back every open file and returns the image.
+    ///
+    /// # Errors
+    /// Fails if an open file cannot be written back.
+    pub fn into_image(mut self) -> Result<I, DosError> {
+        let names: Vec<Vec<u8>> = self.open.keys().cloned().collect();
+        for name in names {
+            self.write_back(&name)?;
+        }
+        Ok(self.image)
+    }
+
+    fn find(&self, name: &[u8]) -> Result<DirEntry, DosError> {
+        fs::read_directory(&self.image)?
+            .entries
+            .into_iter()
+            .find(|entry| entry.closed && fs::same_name(name, &entry.name))
+            .ok_or(DosError::FileNotFound)
+    }
+
+    fn node(&self, entry: DirEntry) -> Result<Node, DosError> {
+        let size = match self.open.get(entry.name()) {
+            Some(buffer) => buffer.data.len(),
+            None => fs::read_file(&self.image, &entry)?.len(),
+        };
+        Ok(Node {
+            name: petscii::to_host_name(entry.name()),
+            size,
+            entry,
+        })
+    }
+
+    /// Returns the files of the disk, in the order of the directory.
+    ///
+    /// Files never closed are left out, as they hold nothing to read.
+    ///
+    /// # Errors
+    /// Fails if the directory or a file cannot be read.
+    pub fn list(&self) -> Result<Vec<Node>, DosError> {
+        fs::read_directory(&self.image)?
+            .entries
+            .into_iter()
+            .filter(|entry| entry.closed)
+            .map(|entry| self.node(entry))
+            .collect()
+    }
+
+    /// Returns the file named `host`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::InvalidFilename`] for a host name no file can
+    /// have and [`DosError::FileNotFound`] if there is no such file.
+    pub fn lookup(&self, host: &str) -> Result<Node, DosError> {
+        self.node(self.find(&disk_name(host)?)?)
+    }
+
+    /// Returns the number of blocks free.
+    ///
+    /// # Errors
+    /// Fails if the BAM cannot be read.
+    pub fn blocks_free(&self) -> Result<u16, DosError> {
+        Ok(fs::Bam::read(&self.image)?.blocks_free())
+    }
+
+    /// Opens the file named `host`, reading it into a buffer unless it is
+    /// open already.
+    ///
+    /// # Errors
+    /// Fails as [`Mount::lookup`] does, or if the file cannot be read.
+    pub fn open(&mut self, host: &str) -> Result<(), DosError> {
+        let name = disk_name(host)?;
+        if let Some(buffer) = self.open.get_mut(&name) {
+            buffer.handles += 1;
+            return Ok(());
+        }
+        let data = fs::read_file(&self.image, &self.find(&name)?)?;
+        let buffer = Buffer {
+            data,
+            handles: 1,
+            dirty: false,
+        };
+        self.open.insert(name, buffer);
+        Ok(())
+    }
+
+    /// Creates an empty file of `file_type` named `host` and opens it.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileTypeMismatch`] for REL files, which need a
+    /// record length, and fails as [`fs::write_file`] does.
+    pub fn create(&mut self, host: &str, file_type: FileType) -> Result<(), DosError> {
+        if matches!(file_type, FileType::Rel | FileType::Del) {
+            return Err(DosError::FileTypeMismatch);
+        }
+        let name = disk_name(host)?;
+        fs::write_file(&mut self.image, &name, file_type, &[])?;
+        let buffer = Buffer {
+            data: Vec::new(),
+            handles: 1,
+            dirty: false,
+        };
+        self.open.insert(name, buffer);
+        Ok(())
+    }
+
+    /// Reads up to `length` bytes at `offset` of the file named `host`.
+    ///
+    /// # Errors
+    /// Fails as [`Mount::lookup`] does, or if the file cannot be read.
+    pub fn read(&self, host: &str, offset: usize, length: usize) -> Result<Vec<u8>, DosError> {
+        let name = disk_name(host)?;
+        let read;
+        let data = match self.open.get(&name) {
+            Some(buffer) => &buffer.data,
+            None => {
+                read = fs::read_file(&self.image, &self.find(&name)?)?;
+                &read
+            }
+        };
+        let start = offset.min(data.len());
+        let end = offset.saturating_add(length).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn buffer(&mut self, host: &str) -> Result<&mut Buffer, DosError> {
+        if self.image.is_read_only() {
+            return Err(DosError::WriteProtect);
+        }
+        let name = disk_name(host)?;
+        let entry = self.find(&name)?;
+        if entry.locked {
+            return Err(DosError::WriteProtect);
+        }
+        if entry.file_type == FileType::Rel {
+            return Err(DosError::FileTypeMismatch);
+        }
+        self.open.get_mut(&name).ok_or(DosError::FileNotOpen)
+    }
+
+    /// Writes `data` at `offset` into the open file named `host`, filling
+    /// a gap before it with zeros.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileNotOpen`] unless the file is open,
+    /// [`DosError::WriteProtect`] if it is locked or the image
+    /// [read-only](DiskImage::is_read_only), and
+    /// [`DosError::FileTypeMismatch`] for REL files, whose records this
+    /// view does not know.
+    pub fn write(&mut self, host: &str, offset: usize, data: &[u8]) -> Result<(), DosError> {
+        let buffer = self.buffer(host)?;
+        let end = offset + data.len();
+        if buffer.data.len() < end {
+            buffer.data.resize(end, 0);
+        }
+        buffer.data[offset..end].copy_from_slice(data);
+        buffer.dirty = true;
+        Ok(())
+    }
+
+    /// Cuts or extends the file named `host` to `size` bytes.
+    ///
+    /// # Errors
+    /// Fails as [`Mount::write`] does, and as [`Mount::release`] does for
+    /// a file that was not open.
+    pub fn truncate(&mut self, host: &str, size: usize) -> Result<(), DosError> {
+        self.open(host)?;
+        let resized = self.buffer(host).map(|buffer| {
+            buffer.data.resize(size, 0);
+            buffer.dirty = true;
+        });
+        let released = self.release(host);
+        resized.and(released)
+    }
+
+    /// Writes the open file named `host` back to the disk if it changed.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DiskFull`] if the new contents do not fit, in
+    /// which case the disk keeps the old ones.
+    pub fn flush(&mut self, host: &str) -> Result<(), DosError> {
+        self.write_back(&disk_name(host)?)
+    }
+
+    fn write_back(&mut self, name: &[u8]) -> Result<(), DosError> {
+        let Some(buffer) = self.open.get(name).filter(|buffer| buffer.dirty) else {
+            return Ok(());
+        };
+        let entry = self.find(name)?;
+        let blocks = buffer.data.len().div_ceil(BLOCK_PAYLOAD).max(1);
+        if blocks > usize::from(self.blocks_free()? + entry.blocks) {
+            return Err(DosError::DiskFull);
+        }
+        let data = buffer.data.clone();
+        fs::scratch_entry(&mut self.image, &entry)?;
+        fs::write_file(&mut self.image, name, entry.file_type, &data)?;
+        if let Some(buffer) = self.open.get_mut(name) {
+            buffer.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Lets go of a handle of the file named `host`, writing the file back
+    /// when it was the last one.
+    ///
+    /// # Errors
+    /// Fails as [`Mount::flush`] does; the file stays open then.
+    pub fn release(&mut self, host: &str) -> Result<(), DosError> {
+        let name = disk_name(host)?;
+        let buffer = self.open.get_mut(&name).ok_or(DosError::FileNotOpen)?;
+        if buffer.handles > 1 {
+            buffer.handles -= 1;
+            return Ok(());
+        }
+        self.write_back(&name)?;
+        self.open.remove(&name);
+        Ok(())
+    }
+
+    /// Scratches the file named `host`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::WriteProtect`] for locked files.
+    pub fn remove(&mut self, host: &str) -> Result<(), DosError> {
+        let name = disk_name(host)?;
+        let entry = self.find(&name)?;
+        if entry.locked {
+            return Err(DosError::WriteProtect);
+        }
+        fs::scratch_entry(&mut self.image, &entry)?;
+        self.open.remove(&name);
+        Ok(())
+    }
+
+    /// Renames the file `from` to `to`, replacing a file named `to`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::WriteProtect`] if the file to replace is locked.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), DosError> {
+        let (old, new) = (disk_name(from)?, disk_name(to)?);
+        if fs::same_name(&new, &fs::pad_name(&old)) {
+            return Ok(());
+        }
+        self.find(&old)?;
+        if self.find(&new).is_ok() {
+            self.remove(to)?;
+        }
+        fs::rename(&mut self.image, &new, &old)?;
+        if let Some(buffer) = self.open.remove(&old) {
+            self.open.insert(new, buffer);
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the attribute `key` of the file named `host`,
+    /// `None` if the file has no such attribute.
+    ///
+    /// # Errors
+    /// Fails as [`Mount::read`] does.
+    pub fn attribute(&self, host: &str, key: &str) -> Result<Option<String>, DosError> {
+        let node = self.lookup(host)?;
+        Ok(match key {
+            TYPE_ATTRIBUTE => Some(String::from(node.entry.file_type.as_str())),
+            LOCKED_ATTRIBUTE => Some(String::from(if node.entry.locked { "1" } else { "0" })),
+            LOAD_ATTRIBUTE => match self.read(host, 0, 2)?[..] {
+                [low, high] => Some(format!("{:04X}", u16::from_le_bytes([low, high]))),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Sets the attribute `key` of the file named `host` to `value`:
+    /// changes the type, locks or unlocks the file or moves its load
+    /// address.
+    ///
+    /// # Errors
+    /// Returns [`DosError::Syntax`] for values the attribute cannot take,
+    /// [`DosError::CommandNotFound`] for other attributes, and fails as
+    /// [`Mount::write`] does when moving the load address.
+    pub fn set_attribute(&mut self, host: &str, key: &str, value: &[u8]) -> Result<(), DosError> {
+        let mut entry = self.find(&disk_name(host)?)?;
+        match key {
+            TYPE_ATTRIBUTE if entry.file_type != FileType::Rel => {
+                entry.file_type = parse_type(value).ok_or(DosError::Syntax)?;
+            }
+            LOCKED_ATTRIBUTE => {
+                entry.locked = match value {
+                    b"1" => true,
+                    b"0" => false,
+                    _ => return Err(DosError::Syntax),
+                };
+            }
+            LOAD_ATTRIBUTE => {
+                let digits = core::str::from_utf8(value).map_err(|_| DosError::Syntax)?;
+                let load = u16::from_str_radix(digits, 16).map_err(|_| DosError::Syntax)?;
+                self.open(host)?;
+                let written = self.write(host, 0, &load.to_le_bytes());
+                let released = self.release(host);
+                return written.and(released);
+            }
+            TYPE_ATTRIBUTE => return Err(DosError::FileTypeMismatch),
+            _ => return Err(DosError::CommandNotFound),
+        }
+        fs::write_entry(&mut self.image, &entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn mounted() -> Mount<D64> {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"MOUNT", Some(*b"MT")).unwrap();
+        fs::write_file(&mut image, b"GAME", FileType::Prg, &[0x01, 0x08, 0, 0]).unwrap();
+        fs::write_file(&mut image, b"A/B", FileType::Seq, b"TEXT").unwrap();
+        Mount::new(image)
+    }
+
+    #[test]
+    fn reads_and_writes_files_through_host_names() {
+        let mut mount = mounted();
+        let names: Vec<String> = mount.list().unwrap().into_iter().map(|n| n.name).collect();
+        assert_eq!(names, ["GAME", "A%2FB"]);
+        assert_eq!(mount.lookup("game").unwrap().size, 4);
+        assert_eq!(mount.read("A%2FB", 1, 10).unwrap(), b"EXT");
+        assert_eq!(mount.lookup("NONE"), Err(DosError::FileNotFound));
+
+        mount.open("A%2FB").unwrap();
+        mount.open("A%2FB").unwrap();
+        mount.write("A%2FB", 4, &[b'!'; 300]).unwrap();
+        mount.release("A%2FB").unwrap();
+        assert_eq!(
+            fs::read_directory(mount.image()).unwrap().entries[1].blocks,
+            1
+        );
+        assert_eq!(mount.lookup("A%2FB").unwrap().size, 304);
+        mount.release("A%2FB").unwrap();
+        let entry = &fs::read_directory(mount.image()).unwrap().entries[1];
+        assert_eq!((entry.blocks, entry.file_type), (2, FileType::Seq));
+
+        mount.create("new.txt", FileType::Usr).unwrap();
+        mount.write("new.txt", 0, b"HELLO").unwrap();
+        mount.truncate("NEW.TXT", 4).unwrap();
+        mount.release("NEW.TXT").unwrap();
+        mount.rename("NEW.TXT", "GAME").unwrap();
+        mount.remove("A%2FB").unwrap();
+        let image = mount.into_image().unwrap();
+        let dir = fs::read_directory(&image).unwrap();
+        assert_eq!(dir.entries.len(), 1);
+        assert_eq!(dir.entries[0].name(), b"GAME");
+        assert_eq!(fs::read_file(&image, &dir.entries[0]).unwrap(), b"HELL");
+    }
+
+    #[test]
+    fn exposes_types_load_addresses_and_locks() {
+        let mut mount = mounted();
+        assert_eq!(
+            mount.attribute("GAME", TYPE_ATTRIBUTE).unwrap().unwrap(),
+            "PRG"
+        );
+        assert_eq!(
+            mount.attribute("GAME", LOAD_ATTRIBUTE).unwrap().unwrap(),
+            "0801"
+        );
+        assert_eq!(mount.attribute("GAME", "user.other").unwrap(), None);
+
+        mount
+            .set_attribute("GAME", LOAD_ATTRIBUTE, b"C000")
+            .unwrap();
+        assert_eq!(mount.read("GAME", 0, 2).unwrap(), [0x00, 0xC0]);
+        mount
+            .set_attribute("A%2FB", TYPE_ATTRIBUTE, b"usr")
+            .unwrap();
+        assert_eq!(
+            mount.attribute("A%2FB", TYPE_ATTRIBUTE).unwrap().unwrap(),
+            "USR"
+        );
+        assert_eq!(
+            mount.set_attribute("A%2FB", TYPE_ATTRIBUTE, b"REL"),
+            Err(DosError::Syntax)
+        );
+
+        mount.set_attribute("GAME", LOCKED_ATTRIBUTE, b"1").unwrap();
+        assert_eq!(
+            mount.attribute("GAME", LOCKED_ATTRIBUTE).unwrap().unwrap(),
+            "1"
+        );
+        mount.open("GAME").unwrap();
+        assert_eq!(mount.write("GAME", 0, &[0]), Err(DosError::WriteProtect));
+        assert_eq!(mount.remove("GAME"), Err(DosError::WriteProtect));
+        assert_eq!(mount.rename("A%2FB", "GAME"), Err(DosError::WriteProtect));
+        assert_eq!(
+            mount.create("X:Y", FileType::Rel),
+            Err(DosError::FileTypeMismatch)
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! NIB files, the raw track dumps of nibtools.
+//!
+//! nibtools reads each half track as the drive delivers its bytes, more
+//! than a revolution of them, and stores them behind a header listing the
+//! half tracks read with their density:
+//!
+//! ```plaintext
+//! $0000  "MNIB-1541-RAW"      signature
+//! $000D  version
+//! $0010  half track, density  one pair per track dumped, up to a zero
+//! $0100  track data           $2000 bytes per track, in the order listed
+//! ```
+//!
+//! Half tracks are counted as nibtools does, 2 for track 1 and 3 for track
+//! 1.5, and the density holds the speed zone in its low two bits and the
+//! flags nibtools found in the others. [`Nib::to_g64`] cuts every track to
+//! one revolution, where it repeats, so the result reads like any G64.
+
+use crate::GCR;
+use crate::g64::G64;
+use crate::image::ImageError;
+use crate::timing::{SYNC_BITS, find_syncs};
+use crate::track::{self, HEADER_LENGTH, HEADER_MARK};
+use alloc::vec::Vec;
+
+/// The signature at the start of every NIB file.
+pub const SIGNATURE: &[u8] = b"MNIB-1541-RAW";
+/// The size of the header in front of the tracks.
+pub const HEADER_SIZE: usize = 0x100;
+/// The number of bytes stored for every track.
+pub const TRACK_SIZE: usize = 0x2000;
+
+const VERSION: u8 = 3;
+const TABLE: usize = 0x10;
+
+/// A half track of a NIB file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NibTrack {
+    /// The half track, 2 for track 1.
+    pub half_track: u8,
+    pub density: u8,
+    /// The bytes read, [`TRACK_SIZE`] of them in a file.
+    pub data: Vec<u8>,
+}
+
+/// A NIB file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Nib {
+    /// The tracks, in the order of the file.
+    pub tracks: Vec<NibTrack>,
+}
+
+impl Nib {
+    /// Parses a NIB file.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] unless the file starts with
+    ///   [`SIGNATURE`].
+    /// - [`ImageError::Truncated`] if the header or a track is cut short.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(ImageError::InvalidSignature);
+        }
+        let header = bytes.get(..HEADER_SIZE).ok_or(ImageError::Truncated)?;
+        let mut tracks = Vec::new();
+        for (i, entry) in header[TABLE..].chunks_exact(2).enumerate() {
+            if entry[0] == 0 {
+                break;
+            }
+            let start = HEADER_SIZE + i * TRACK_SIZE;
+            let data = bytes
+                .get(start..start + TRACK_SIZE)
+                .ok_or(ImageError::Truncated)?;
+            tracks.push(NibTrack {
+                half_track: entry[0],
+                density: entry[1],
+                data: data.to_vec(),
+            });
+        }
+        Ok(Nib { tracks })
+    }
+
+    /// Serializes the file, with tracks cut or padded with zeros to
+    /// [`TRACK_SIZE`] and at most as many as the header lists.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let tracks = &self.tracks[..self.tracks.len().min((HEADER_SIZE - TABLE) / 2 - 1)];
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + tracks.len() * TRACK_SIZE);
+        bytes.extend_from_slice(SIGNATURE);
+        bytes.push(VERSION);
+        bytes.resize(TABLE, 0);
+        for track in tracks {
+            bytes.extend_from_slice(&[track.half_track, track.density]);
+        }
+        bytes.resize(HEADER_SIZE, 0);
+        for track in tracks {
+            let start = bytes.len();
+            let length = track.data.len().min(TRACK_SIZE);
+            bytes.extend_from_slice(&track.data[..length]);
+            bytes.resize(start + TRACK_SIZE, 0);
+        }
+        bytes
+    }
+
+    /// Returns a G64 of the tracks, each cut to one revolution from the
+    /// sync of a header that repeats, or kept whole if none does.
+    pub fn to_g64(&self) -> G64 {
+        let mut g64 = G64::new();
+        for track in &self.tracks {
+            let Some(index) = usize::from(track.half_track).checked_sub(2) else {
+                continue;
+            };
+            let data = revolution(&track.data).unwrap_or_else(|| track.data.clone());
+            g64.set_half_track(index, data, track.density & 3);
+        }
+        g64
+    }
+}
+
+/// Returns one revolution of the bytes `data` read off a track: from the
+/// sync of the first header that is read again to that of the repeat.
+fn revolution(data: &[u8]) -> Option<Vec<u8>> {
+    let bits = data.len() * 8;
+    let syncs = find_syncs(data);
+    let gcr = GCR::new();
+    let header = |end: usize| track::read_bits(data, end, HEADER_LENGTH);
+    syncs.iter().enumerate().find_map(|(i, &(detected, end))| {
+        let start = detected.checked_sub(SYNC_BITS - 1)?;
+        let first = header(end);
+        if gcr.decode(&first)?[0] != HEADER_MARK {
+            return None;
+        }
+        let (_, repeat) = syncs[i + 1..]
+            .iter()
+            .find(|&&(_, later)| later > end && header(later) == first)?;
+        (repeat - end <= bits - start).then(|| track::read_bits(data, start, (repeat - end) / 8))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::error::DosError;
+    use crate::fs;
+    use crate::image::BlockDevice;
+
+    /// Dumps `g64` as nibtools would, starting every track `skew` bytes
+    /// into it and reading on beyond the end of the revolution.
+    fn dump(g64: &G64, skew: usize) -> Nib {
+        let tracks = (1..=g64.tracks())
+            .map(|track| {
+                let data = g64.track(track).unwrap();
+                NibTrack {
+                    half_track: track * 2,
+                    density: g64.speed_zone(track),
+                    data: data
+                        .iter()
+                        .cycle()
+                        .skip(skew)
+                        .take(TRACK_SIZE)
+                        .copied()
+                        .collect(),
+                }
+            })
+            .collect();
+        Nib { tracks }
+    }
+
+    #[test]
+    fn reads_dumps_as_g64() {
+        let mut d64 = D64::new(35);
+        fs::format(&mut d64, b"NIBBLED", Some(*b"NB")).unwrap();
+        d64.write_sector(17, 3, &[0x42; 256]).unwrap();
+        d64.set_sector_error(17, 3, Some(DosError::DataChecksum))
+            .unwrap();
+        let g64 = G64::from_image(&d64);
+        let nib = dump(&g64, 700);
+        let bytes = nib.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 35 * TRACK_SIZE);
+        assert_eq!(&bytes[TABLE..TABLE + 4], [2, 3, 4, 3]);
+        assert_eq!(Nib::from_bytes(&bytes).unwrap(), nib);
+
+        let read = nib.to_g64();
+        assert_eq!(read.tracks(), 35);
+        assert!(read.track(1).unwrap().len() < TRACK_SIZE);
+        assert_eq!(read.speed_zone(35), 0);
+        assert_eq!(read.read_sector(17, 3).unwrap(), [0x42; 256]);
+        assert_eq!(read.sector_error(17, 3), Some(DosError::DataChecksum));
+        assert_eq!(read.sector_error(17, 4), None);
+        assert_eq!(read.read_sector(18, 0), d64.read_sector(18, 0));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(
+            Nib::from_bytes(b"GCR-1541"),
+            Err(ImageError::InvalidSignature)
+        );
+        let nib = Nib {
+            tracks: vec![NibTrack {
+                half_track: 2,
+                density: 3,
+                data: vec![0x55; 100],
+            }],
+        };
+        let bytes = nib.to_bytes();
+        assert_eq!(
+            Nib::from_bytes(&bytes[..HEADER_SIZE + 10]),
+            Err(ImageError::Truncated)
+        );
+        assert_eq!(Nib::from_bytes(&bytes[..0x20]), Err(ImageError::Truncated));
+        // No sync, so the whole dump stays.
+        let g64 = Nib::from_bytes(&bytes).unwrap().to_g64();
+        assert_eq!(g64.track(1).unwrap().len(), TRACK_SIZE);
+        assert_eq!(g64.speed_zone(1), 3);
+    }
+}
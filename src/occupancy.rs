@@ -0,0 +1,220 @@
+//! Maps of what every block of a disk holds.
+//!
+//! A disk map, as tools draw it, shows each track as a row of sectors
+//! colored by their state: free, used by a file, the directory or nothing
+//! at all, and whether it has a read error. [`map`] gathers this in one
+//! pass over the directory and the chains of the files into a [`DiskMap`]
+//! small enough to send to a web front end: a [`TrackMap`] of bitmaps and
+//! owner indices per track, written as JSON through [`ToJson`].
+
+use crate::error::DosError;
+use crate::fs::{self, BAM_SECTOR, Bam, DIR_TRACK};
+use crate::image::DiskImage;
+use crate::json::{ToJson, Writer};
+use alloc::{vec, vec::Vec};
+
+/// The owner index of the BAM and the directory.
+pub const DIRECTORY: u16 = 0;
+
+/// The blocks of a track.
+///
+/// Bit `n` of a bitmap stands for sector `n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackMap {
+    pub track: u8,
+    pub sectors: u8,
+    /// The sectors the BAM marks used. Beyond track 35, which the BAM
+    /// does not cover, the sectors something uses.
+    pub allocated: u64,
+    /// The sectors with a read error.
+    pub bad: u64,
+    /// For each sector what uses it: [`DIRECTORY`] or the index of a file
+    /// in [`DiskMap::files`] plus one.
+    pub owners: Vec<Option<u16>>,
+}
+
+impl TrackMap {
+    /// Returns `true` if the BAM marks `sector` used.
+    pub fn is_allocated(&self, sector: u8) -> bool {
+        self.allocated & 1 << sector != 0
+    }
+
+    /// Returns `true` if `sector` has a read error.
+    pub fn is_bad(&self, sector: u8) -> bool {
+        self.bad & 1 << sector != 0
+    }
+
+    /// Returns the sectors the BAM marks used that nothing uses.
+    pub fn orphaned(&self) -> u64 {
+        self.owners
+            .iter()
+            .enumerate()
+            .filter(|(_, owner)| owner.is_none())
+            .fold(0, |bits, (s, _)| bits | 1 << s)
+            & self.allocated
+    }
+}
+
+/// What [`map`] found on a disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskMap {
+    /// The names of the files, without padding, in the order of the
+    /// directory; owner `i` is file `i - 1`.
+    pub files: Vec<Vec<u8>>,
+    /// The tracks from track 1 on.
+    pub tracks: Vec<TrackMap>,
+}
+
+impl DiskMap {
+    /// Returns the owner of `track`/`sector`, if the disk has the sector
+    /// and something uses it.
+    pub fn owner(&self, track: u8, sector: u8) -> Option<u16> {
+        let map = self.tracks.get(usize::from(track).checked_sub(1)?)?;
+        *map.owners.get(usize::from(sector))?
+    }
+}
+
+/// Maps the blocks of `image`.
+///
+/// A chain is followed until it leaves the disk, loops or reaches a block
+/// that cannot be read; a block two owners claim is shown with the first.
+///
+/// # Errors
+/// Fails if the BAM or the directory cannot be read.
+pub fn map<I: DiskImage + ?Sized>(image: &I) -> Result<DiskMap, DosError> {
+    let bam = Bam::read(image)?;
+    let directory = fs::read_directory(image)?;
+    let mut tracks: Vec<TrackMap> = (1..=image.tracks())
+        .map(|track| {
+            let sectors = image.sectors_per_track(track);
+            let mut map = TrackMap {
+                track,
+                sectors,
+                allocated: 0,
+                bad: 0,
+                owners: vec![None; usize::from(sectors)],
+            };
+            for sector in 0..sectors {
+                if track <= 35 && !bam.is_free(track, sector) {
+                    map.allocated |= 1 << sector;
+                }
+                if image.sector_error(track, sector).is_some() {
+                    map.bad |= 1 << sector;
+                }
+            }
+            map
+        })
+        .collect();
+
+    let mut claim = |track: u8, sector: u8, owner: u16| {
+        let map = &mut tracks[usize::from(track) - 1];
+        let slot = &mut map.owners[usize::from(sector)];
+        if slot.is_none() {
+            *slot = Some(owner);
+            if track > 35 {
+                map.allocated |= 1 << sector;
+            }
+        }
+    };
+    claim(DIR_TRACK, BAM_SECTOR, DIRECTORY);
+    let mut starts = vec![(DIRECTORY, DIR_TRACK, fs::DIR_SECTOR)];
+    let mut files = Vec::new();
+    for (i, entry) in directory.entries.iter().enumerate() {
+        let owner = i as u16 + 1;
+        files.push(entry.name().to_vec());
+        starts.push((owner, entry.track, entry.sector));
+        if entry.side_track != 0 {
+            starts.push((owner, entry.side_track, entry.side_sector));
+        }
+    }
+    for (owner, mut track, mut sector) in starts {
+        let mut seen = Vec::new();
+        while track != 0 && image.contains(track, sector) && !seen.contains(&(track, sector)) {
+            seen.push((track, sector));
+            claim(track, sector, owner);
+            let Ok(data) = image.read_sector(track, sector) else {
+                break;
+            };
+            (track, sector) = (data[0], data[1]);
+        }
+    }
+    Ok(DiskMap { files, tracks })
+}
+
+/// An object with the track, its number of sectors, the bitmaps as
+/// numbers and the owners as an array, `null` for sectors nothing uses.
+impl ToJson for TrackMap {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("track", &self.track)
+            .field("sectors", &self.sectors)
+            .key("allocated")
+            .number(self.allocated as i64)
+            .key("bad")
+            .number(self.bad as i64)
+            .field("owners", &self.owners)
+            .end_object();
+    }
+}
+
+/// An object with the names of the files and the tracks.
+impl ToJson for DiskMap {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object().key("files").begin_array();
+        for name in &self.files {
+            json.name(name);
+        }
+        json.end_array().field("tracks", &self.tracks).end_object();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::FileType;
+
+    #[test]
+    fn maps_files_and_the_directory() {
+        let mut image = D64::new(40);
+        fs::format(&mut image, b"MAPPED", Some(*b"01")).unwrap();
+        let one = fs::write_file(&mut image, b"ONE", FileType::Prg, &[1; 600]).unwrap();
+        fs::write_file(&mut image, b"TWO", FileType::Seq, &[2; 10]).unwrap();
+        image
+            .set_sector_error(17, 10, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut bam = Bam::read(&image).unwrap();
+        bam.allocate(5, 4);
+        bam.write(&mut image).unwrap();
+
+        let disk = map(&image).unwrap();
+        assert_eq!(disk.files, [b"ONE".to_vec(), b"TWO".to_vec()]);
+        assert_eq!(disk.tracks.len(), 40);
+        assert_eq!(disk.owner(18, 0), Some(DIRECTORY));
+        assert_eq!(disk.owner(18, 1), Some(DIRECTORY));
+        assert_eq!(disk.owner(18, 2), None);
+        for (t, s) in fs::chain(&image, one.track, one.sector).unwrap() {
+            assert_eq!(disk.owner(t, s), Some(1));
+        }
+        let track = &disk.tracks[16];
+        assert!(track.is_bad(10) && track.is_allocated(10));
+        assert_eq!(track.owners.iter().filter(|o| **o == Some(2)).count(), 1);
+        assert_eq!(disk.tracks[4].orphaned(), 1 << 4);
+        assert_eq!(disk.tracks[4].allocated, 1 << 4);
+        assert_eq!(disk.tracks[35].allocated, 0);
+        assert_eq!(disk.owner(41, 0), None);
+        assert_eq!(disk.owner(1, 21), None);
+    }
+
+    #[test]
+    fn writes_json() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"JSON", Some(*b"01")).unwrap();
+        fs::write_file(&mut image, b"A", FileType::Prg, &[0; 2]).unwrap();
+        let json = map(&image).unwrap().to_json();
+        assert!(json.starts_with(r#"{"files":["A"],"tracks":[{"track":1,"sectors":21,"#));
+        assert!(
+            json.contains(r#"{"track":17,"sectors":21,"allocated":1,"bad":0,"owners":[1,null,"#)
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Parsing of the filenames given when opening a channel.
+//!
+//! The filename of an `OPEN` selects what a secondary address is connected
+//! to: `#` or `#2` asks for a direct-access buffer, `$` for the directory
+//! and anything else names a file, optionally with a drive prefix, type and
+//! mode such as `@0:NAME,S,W`. The drive and host-side tools share this
+//! parser so they agree on how a string is read.
+
+use crate::command::strip_drive;
+use crate::error::DosError;
+use crate::fs::FileType;
+use alloc::vec::Vec;
+
+/// How a file is accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    /// `R`: read an existing file.
+    Read,
+    /// `W`: create a new file.
+    Write,
+    /// `A`: append to an existing file.
+    Append,
+    /// `M`: read a file that was not closed properly.
+    Modify,
+}
+
+/// A file named in an open string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpec {
+    pub drive: Option<u8>,
+    /// The name or pattern, without drive prefix.
+    pub name: Vec<u8>,
+    /// The type given with `,S`, `,P`, `,U` or `,L`.
+    pub file_type: Option<FileType>,
+    pub mode: Option<Mode>,
+    /// Set by a leading `@`: replace the file if it exists.
+    pub replace: bool,
+    /// The record length given with `,L`.
+    pub record_length: Option<u8>,
+}
+
+impl FileSpec {
+    /// Returns the mode, defaulting to the one implied by `channel`:
+    /// secondary address 1 writes, all others read.
+    pub fn mode_for(&self, channel: u8) -> Mode {
+        self.mode.unwrap_or(if channel == 1 {
+            Mode::Write
+        } else {
+            Mode::Read
+        })
+    }
+
+    /// Returns the file type, defaulting to PRG on secondary addresses 0 and
+    /// 1, where `LOAD` and `SAVE` do not give one.
+    pub fn type_for(&self, channel: u8) -> Option<FileType> {
+        self.file_type.or((channel <= 1).then_some(FileType::Prg))
+    }
+
+    /// Returns `true` if the name contains wildcards.
+    pub fn is_pattern(&self) -> bool {
+        self.name.iter().any(|&b| b == b'*' || b == b'?')
+    }
+}
+
+/// What an open string asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenRequest {
+    /// `#[n]`: a direct-access buffer, optionally a specific one.
+    Buffer(Option<u8>),
+    /// `$[d][:PATTERN,...][=T]`: the directory, limited to the entries
+    /// matching any of the patterns and, with `=T`, of that type.
+    Directory {
+        drive: Option<u8>,
+        patterns: Vec<Vec<u8>>,
+        file_type: Option<FileType>,
+    },
+    /// A file.
+    File(FileSpec),
+}
+
+/// Parses the filename of an `OPEN`.
+///
+/// # Errors
+/// - [`DosError::NoFileGiven`] if no name is given.
+/// - [`DosError::Syntax`] for an unknown type or mode letter, or `,L`
+///   without a record length.
+///
+/// # Example
+/// ```
+/// use cbm_dos::fs::FileType;
+/// use cbm_dos::open::{parse, Mode, OpenRequest};
+///
+/// let OpenRequest::File(spec) = parse(b"@0:NOTES,S,W").unwrap() else {
+///     unreachable!()
+/// };
+/// assert_eq!(spec.drive, Some(0));
+/// assert_eq!(spec.name, b"NOTES");
+/// assert_eq!(spec.file_type, Some(FileType::Seq));
+/// assert_eq!(spec.mode, Some(Mode::Write));
+/// assert!(spec.replace);
+/// ```
+pub fn parse(input: &[u8]) -> Result<OpenRequest, DosError> {
+    match input {
+        [] => Err(DosError::NoFileGiven),
+        [b'#', rest @ ..] => Ok(OpenRequest::Buffer(
+            core::str::from_utf8(rest)
+                .ok()
+                .and_then(|s| s.trim().parse().ok()),
+        )),
+        [b'$', rest @ ..] => parse_directory(rest),
+        _ => parse_file(input).map(OpenRequest::File),
+    }
+}
+
+fn parse_directory(input: &[u8]) -> Result<OpenRequest, DosError> {
+    let (drive, args) = match input.iter().position(|&b| b == b':') {
+        Some(colon) => (digit(&input[..colon]), &input[colon + 1..]),
+        None => (digit(input), &[][..]),
+    };
+    let (args, file_type) = match args.iter().position(|&b| b == b'=') {
+        Some(eq) => (&args[..eq], Some(type_letter(args.get(eq + 1).copied())?)),
+        None => (args, None),
+    };
+    let patterns = args
+        .split(|&b| b == b',')
+        .filter(|p| !p.is_empty())
+        .map(|p| strip_drive(p).1.to_vec())
+        .collect();
+    Ok(OpenRequest::Directory {
+        drive,
+        patterns,
+        file_type,
+    })
+}
+
+fn digit(input: &[u8]) -> Option<u8> {
+    input.iter().find(|b| b.is_ascii_digit()).map(|d| d - b'0')
+}
+
+fn type_letter(letter: Option<u8>) -> Result<FileType, DosError> {
+    match letter {
+        Some(b'D') => Ok(FileType::Del),
+        Some(b'S') => Ok(FileType::Seq),
+        Some(b'P') => Ok(FileType::Prg),
+        Some(b'U') => Ok(FileType::Usr),
+        Some(b'R' | b'L') => Ok(FileType::Rel),
+        _ => Err(DosError::Syntax),
+    }
+}
+
+fn parse_file(input: &[u8]) -> Result<FileSpec, DosError> {
+    let (replace, rest) = match input.strip_prefix(b"@") {
+        Some(rest) => (true, rest),
+        None => (false, input),
+    };
+    let (drive, rest) = strip_drive(rest);
+    let mut parts = rest.split(|&b| b == b',');
+    let name = parts.next().unwrap_or_default().to_vec();
+    if name.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    let mut spec = FileSpec {
+        drive,
+        name,
+        file_type: None,
+        mode: None,
+        replace,
+        record_length: None,
+    };
+    while let Some(part) = parts.next() {
+        match part.first() {
+            Some(b'S') => spec.file_type = Some(FileType::Seq),
+            Some(b'P') => spec.file_type = Some(FileType::Prg),
+            Some(b'U') => spec.file_type = Some(FileType::Usr),
+            Some(b'L') => {
+                spec.file_type = Some(FileType::Rel);
+                spec.record_length = part
+                    .get(1)
+                    .or_else(|| parts.next().and_then(|p| p.first()))
+                    .copied();
+                if spec.record_length.is_none() {
+                    return Err(DosError::Syntax);
+                }
+            }
+            Some(b'R') => spec.mode = Some(Mode::Read),
+            Some(b'W') => spec.mode = Some(Mode::Write),
+            Some(b'A') => spec.mode = Some(Mode::Append),
+            Some(b'M') => spec.mode = Some(Mode::Modify),
+            _ => return Err(DosError::Syntax),
+        }
+    }
+    Ok(spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_buffers_and_directories() {
+        assert_eq!(parse(b"#").unwrap(), OpenRequest::Buffer(None));
+        assert_eq!(parse(b"#2").unwrap(), OpenRequest::Buffer(Some(2)));
+        assert_eq!(
+            parse(b"$").unwrap(),
+            OpenRequest::Directory {
+                drive: None,
+                patterns: vec![],
+                file_type: None
+            }
+        );
+        assert_eq!(
+            parse(b"$0:A*,B?=P").unwrap(),
+            OpenRequest::Directory {
+                drive: Some(0),
+                patterns: vec![b"A*".to_vec(), b"B?".to_vec()],
+                file_type: Some(FileType::Prg)
+            }
+        );
+        assert_eq!(parse(b"$:*=X"), Err(DosError::Syntax));
+    }
+
+    #[test]
+    fn parses_files() {
+        let OpenRequest::File(spec) = parse(b"GAME").unwrap() else {
+            panic!("not a file");
+        };
+        assert_eq!(spec.mode_for(0), Mode::Read);
+        assert_eq!(spec.mode_for(1), Mode::Write);
+        assert_eq!(spec.type_for(1), Some(FileType::Prg));
+        assert_eq!(spec.type_for(2), None);
+
+        let OpenRequest::File(spec) = parse(b"DATA,L,\x20").unwrap() else {
+            panic!("not a file");
+        };
+        assert_eq!(spec.record_length, Some(0x20));
+        assert_eq!(spec.file_type, Some(FileType::Rel));
+
+        assert_eq!(parse(b"0:"), Err(DosError::NoFileGiven));
+        assert_eq!(parse(b"NAME,X"), Err(DosError::Syntax));
+        assert_eq!(parse(b"NAME,L"), Err(DosError::Syntax));
+    }
+}
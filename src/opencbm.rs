@@ -0,0 +1,382 @@
+//! Real drives through OpenCBM.
+//!
+//! With the `opencbm` feature the crate links against `libopencbm` and can
+//! reach drives on an XU1541, XUM1541/ZoomFloppy, XA1541 or any other
+//! adapter OpenCBM drives. [`Cbm`] is the open driver and [`RealDisk`] the
+//! disk in one drive, exposed as a [`DiskImage`] so the filesystem layer
+//! and the virtual drive work on it like on a file.
+//!
+//! Sectors move through a buffer channel with the `U1`, `U2` and `B-P`
+//! commands, as BASIC programs do it, so any drive with a CBM DOS works.
+//! Raw tracks cannot be read this way: they need drive code such as that
+//! of nibtools, whose transfers [`crate::parallel`] models.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+
+use crate::error::{DosError, DosStatus};
+use crate::image::{BlockDevice, DiskImage, SECTOR_SIZE, Sector};
+
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uchar, c_void};
+
+    #[cfg(windows)]
+    pub type CbmFile = *mut c_void;
+    #[cfg(not(windows))]
+    pub type CbmFile = c_int;
+
+    #[link(name = "opencbm")]
+    unsafe extern "C" {
+        pub fn cbm_driver_open_ex(handle: *mut CbmFile, adapter: *const c_char) -> c_int;
+        pub fn cbm_driver_close(handle: CbmFile);
+        pub fn cbm_open(
+            handle: CbmFile,
+            device: c_uchar,
+            secondary: c_uchar,
+            name: *const c_void,
+            length: usize,
+        ) -> c_int;
+        pub fn cbm_close(handle: CbmFile, device: c_uchar, secondary: c_uchar) -> c_int;
+        pub fn cbm_listen(handle: CbmFile, device: c_uchar, secondary: c_uchar) -> c_int;
+        pub fn cbm_talk(handle: CbmFile, device: c_uchar, secondary: c_uchar) -> c_int;
+        pub fn cbm_unlisten(handle: CbmFile) -> c_int;
+        pub fn cbm_untalk(handle: CbmFile) -> c_int;
+        pub fn cbm_raw_read(handle: CbmFile, buffer: *mut c_void, count: usize) -> c_int;
+        pub fn cbm_raw_write(handle: CbmFile, buffer: *const c_void, count: usize) -> c_int;
+        pub fn cbm_exec_command(
+            handle: CbmFile,
+            device: c_uchar,
+            command: *const c_void,
+            length: usize,
+        ) -> c_int;
+        pub fn cbm_device_status(
+            handle: CbmFile,
+            device: c_uchar,
+            buffer: *mut c_void,
+            length: usize,
+        ) -> c_int;
+    }
+}
+
+/// The secondary address [`RealDisk`] opens its buffer on.
+const BUFFER_CHANNEL: u8 = 2;
+
+/// The open OpenCBM driver, closed again when dropped.
+#[derive(Debug)]
+pub struct Cbm {
+    handle: ffi::CbmFile,
+}
+
+impl Cbm {
+    /// Opens the driver for `adapter`, or for the default adapter, like
+    /// `cbmctrl -@ adapter`.
+    ///
+    /// # Errors
+    /// Fails if the name contains a NUL byte or OpenCBM finds no adapter.
+    pub fn open(adapter: Option<&str>) -> io::Result<Self> {
+        let adapter = adapter
+            .map(CString::new)
+            .transpose()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let mut handle = std::mem::MaybeUninit::uninit();
+        let name = adapter.as_ref().map_or(std::ptr::null(), |a| a.as_ptr());
+        // SAFETY: `handle` is written by the driver on success and `name` is
+        // null or a NUL-terminated string outliving the call.
+        let result = unsafe { ffi::cbm_driver_open_ex(handle.as_mut_ptr(), name) };
+        if result != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "OpenCBM found no adapter",
+            ));
+        }
+        Ok(Cbm {
+            // SAFETY: the driver opened successfully and set the handle.
+            handle: unsafe { handle.assume_init() },
+        })
+    }
+
+    /// Sends `command` to channel 15 of `device`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer.
+    pub fn command(&self, device: u8, command: &[u8]) -> Result<(), DosError> {
+        // SAFETY: the pointer and length describe `command`.
+        let result = unsafe {
+            ffi::cbm_exec_command(self.handle, device, command.as_ptr().cast(), command.len())
+        };
+        check(result)
+    }
+
+    /// Reads the status of `device` from channel 15.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer.
+    pub fn status(&self, device: u8) -> Result<DosStatus, DosError> {
+        let mut buffer = [0u8; 64];
+        // SAFETY: the driver writes at most `buffer.len()` bytes.
+        let result = unsafe {
+            ffi::cbm_device_status(
+                self.handle,
+                device,
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+        check(result)?;
+        parse_status(&buffer).ok_or(DosError::DriveNotReady)
+    }
+
+    /// Opens `secondary` on `device` with `name`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer.
+    pub fn open_channel(&self, device: u8, secondary: u8, name: &[u8]) -> Result<(), DosError> {
+        // SAFETY: the pointer and length describe `name`.
+        let result = unsafe {
+            ffi::cbm_open(
+                self.handle,
+                device,
+                secondary,
+                name.as_ptr().cast(),
+                name.len(),
+            )
+        };
+        check(result)
+    }
+
+    /// Closes `secondary` on `device`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer.
+    pub fn close_channel(&self, device: u8, secondary: u8) -> Result<(), DosError> {
+        // SAFETY: plain values only.
+        check(unsafe { ffi::cbm_close(self.handle, device, secondary) })
+    }
+
+    /// Reads `buffer.len()` bytes from `secondary` of `device`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer
+    /// or sends fewer bytes.
+    pub fn read(&self, device: u8, secondary: u8, buffer: &mut [u8]) -> Result<(), DosError> {
+        // SAFETY: plain values only.
+        check(unsafe { ffi::cbm_talk(self.handle, device, secondary) })?;
+        // SAFETY: the driver writes at most `buffer.len()` bytes.
+        let read =
+            unsafe { ffi::cbm_raw_read(self.handle, buffer.as_mut_ptr().cast(), buffer.len()) };
+        // SAFETY: plain values only.
+        check(unsafe { ffi::cbm_untalk(self.handle) })?;
+        complete(read, buffer.len())
+    }
+
+    /// Writes `data` to `secondary` of `device`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::DriveNotReady`] if the device does not answer
+    /// or takes fewer bytes.
+    pub fn write(&self, device: u8, secondary: u8, data: &[u8]) -> Result<(), DosError> {
+        // SAFETY: plain values only.
+        check(unsafe { ffi::cbm_listen(self.handle, device, secondary) })?;
+        // SAFETY: the pointer and length describe `data`.
+        let written = unsafe { ffi::cbm_raw_write(self.handle, data.as_ptr().cast(), data.len()) };
+        // SAFETY: plain values only.
+        check(unsafe { ffi::cbm_unlisten(self.handle) })?;
+        complete(written, data.len())
+    }
+}
+
+impl Drop for Cbm {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from a successful open and is closed once.
+        unsafe { ffi::cbm_driver_close(self.handle) };
+    }
+}
+
+/// Maps an OpenCBM result to the error a computer sees for a silent
+/// device.
+fn check(result: std::ffi::c_int) -> Result<(), DosError> {
+    if result < 0 {
+        Err(DosError::DriveNotReady)
+    } else {
+        Ok(())
+    }
+}
+
+fn complete(count: std::ffi::c_int, expected: usize) -> Result<(), DosError> {
+    if usize::try_from(count) == Ok(expected) {
+        Ok(())
+    } else {
+        Err(DosError::DriveNotReady)
+    }
+}
+
+/// Parses a status line such as `00, OK,00,00`.
+fn parse_status(text: &[u8]) -> Option<DosStatus> {
+    let end = text
+        .iter()
+        .position(|&b| b == b'\r' || b == 0)
+        .unwrap_or(text.len());
+    let text = std::str::from_utf8(&text[..end]).ok()?;
+    let mut fields = text.split(',');
+    let code = fields.next()?.trim().parse().ok()?;
+    let _message = fields.next()?;
+    let track = fields.next()?.trim().parse().ok()?;
+    let sector = fields.next()?.trim().parse().ok()?;
+    Some(DosStatus::new(code, track, sector))
+}
+
+/// Returns the command moving `track`/`sector` between disk and buffer
+/// with `U1` or `U2`.
+fn block_command(command: u8, track: u8, sector: u8) -> Vec<u8> {
+    format!(
+        "U{} {BUFFER_CHANNEL} 0 {track} {sector}",
+        char::from(command)
+    )
+    .into_bytes()
+}
+
+/// The disk in a real drive.
+///
+/// The disk is assumed to have the 1541 layout with the given number of
+/// tracks. Every access goes to the drive; read errors the drive reports
+/// are kept and returned by [`sector_error`](BlockDevice::sector_error),
+/// while the data read is returned as the drive has it in its buffer.
+/// Failures of the adapter or the drive itself are reported as
+/// [`DosError::DriveNotReady`].
+#[derive(Debug)]
+pub struct RealDisk {
+    cbm: Cbm,
+    device: u8,
+    tracks: u8,
+    channel: Cell<bool>,
+    /// The outcome of the last access to each sector.
+    errors: RefCell<HashMap<(u8, u8), Option<DosError>>>,
+}
+
+impl RealDisk {
+    /// Uses the disk in `device` through `cbm`.
+    pub fn new(cbm: Cbm, device: u8, tracks: u8) -> Self {
+        RealDisk {
+            cbm,
+            device,
+            tracks,
+            channel: Cell::new(false),
+            errors: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the driver.
+    pub fn cbm(&self) -> &Cbm {
+        &self.cbm
+    }
+
+    /// Opens the buffer channel unless it is open.
+    fn buffer(&self) -> Result<(), DosError> {
+        if !self.channel.get() {
+            self.cbm.open_channel(self.device, BUFFER_CHANNEL, b"#")?;
+            self.channel.set(true);
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        if self.channel.replace(false) {
+            let _ = self.cbm.close_channel(self.device, BUFFER_CHANNEL);
+        }
+    }
+
+    /// Runs `U1` or `U2` and records the outcome for the sector.
+    fn transfer(&self, command: u8, track: u8, sector: u8) -> Result<(), DosError> {
+        self.cbm
+            .command(self.device, &block_command(command, track, sector))?;
+        let error = self.cbm.status(self.device)?.error();
+        self.errors.borrow_mut().insert((track, sector), error);
+        match error {
+            // Reads go on with whatever the drive has in its buffer.
+            Some(error) if command == b'2' || error == DosError::IllegalTrackSector => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for RealDisk {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl BlockDevice for RealDisk {
+    fn tracks(&self) -> u8 {
+        self.tracks
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        if track <= self.tracks {
+            crate::d64::sectors_per_track(track)
+        } else {
+            0
+        }
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        self.buffer()?;
+        self.transfer(b'1', track, sector)?;
+        self.cbm
+            .command(self.device, format!("B-P {BUFFER_CHANNEL} 0").as_bytes())?;
+        let mut data = [0; SECTOR_SIZE];
+        self.cbm.read(self.device, BUFFER_CHANNEL, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        if !self.contains(track, sector) {
+            return Err(DosError::IllegalTrackSector);
+        }
+        self.buffer()?;
+        self.cbm
+            .command(self.device, format!("B-P {BUFFER_CHANNEL} 0").as_bytes())?;
+        self.cbm.write(self.device, BUFFER_CHANNEL, data)?;
+        self.transfer(b'2', track, sector)
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        if !self.errors.borrow().contains_key(&(track, sector)) {
+            let _ = self.read_sector(track, sector);
+        }
+        self.errors
+            .borrow()
+            .get(&(track, sector))
+            .copied()
+            .flatten()
+    }
+}
+
+impl DiskImage for RealDisk {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_drive_status() {
+        let status = parse_status(b"23,READ ERROR,18,04\r").unwrap();
+        assert_eq!(status, DosStatus::new(23, 18, 4));
+        assert_eq!(status.error(), Some(DosError::DataChecksum));
+        assert_eq!(
+            parse_status(b"73,CBM DOS V2.6 1541,00,00\0\0"),
+            Some(DosStatus::new(73, 0, 0))
+        );
+        assert_eq!(parse_status(b"garbage"), None);
+    }
+
+    #[test]
+    fn spells_block_commands() {
+        assert_eq!(block_command(b'1', 18, 0), b"U1 2 0 18 0");
+        assert_eq!(block_command(b'2', 1, 20), b"U2 2 0 1 20");
+    }
+}
@@ -0,0 +1,185 @@
+//! Copy-on-write images.
+//!
+//! Preservation copies are to be read, never written. An [`Overlay`] lays
+//! a writable layer over such a base image: it reads as the base does,
+//! but every write goes into the layer, which holds the sectors written as
+//! the changes to the base. An emulator session or an experiment runs
+//! against the overlay, and its changes may be kept, written to a copy
+//! with [`Overlay::apply`] or [`Overlay::flatten`], or thrown away.
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::image::{BlockDevice, DiskImage, Sector};
+use alloc::collections::BTreeMap;
+
+/// A writable layer over a read-only disk image.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::fs::{self, FileType};
+/// use cbm_dos::overlay::Overlay;
+///
+/// let mut master = D64::new(35);
+/// fs::format(&mut master, b"MASTER", Some(*b"01")).unwrap();
+///
+/// let mut session = Overlay::new(&master);
+/// fs::write_file(&mut session, b"SAVED", FileType::Prg, &[1, 8]).unwrap();
+/// assert!(fs::find_file(&session, b"SAVED").unwrap().is_some());
+/// assert!(fs::find_file(&master, b"SAVED").unwrap().is_none());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Overlay<'a, I: ?Sized> {
+    base: &'a I,
+    changes: BTreeMap<(u8, u8), Sector>,
+}
+
+impl<'a, I: DiskImage + ?Sized> Overlay<'a, I> {
+    /// Lays an empty layer over `base`.
+    pub fn new(base: &'a I) -> Self {
+        Overlay {
+            base,
+            changes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the base image.
+    pub fn base(&self) -> &'a I {
+        self.base
+    }
+
+    /// Returns `true` if the overlay reads differently from its base.
+    pub fn is_modified(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    /// Returns the sectors that differ from the base, in track order, with
+    /// their new contents.
+    pub fn changes(&self) -> impl Iterator<Item = ((u8, u8), &Sector)> {
+        self.changes.iter().map(|(&at, data)| (at, data))
+    }
+
+    /// Makes `track`/`sector` read as in the base again.
+    pub fn revert(&mut self, track: u8, sector: u8) {
+        self.changes.remove(&(track, sector));
+    }
+
+    /// Throws away every change.
+    pub fn discard(&mut self) {
+        self.changes.clear();
+    }
+
+    /// Writes the changes to `target`, usually a copy of the base.
+    ///
+    /// # Errors
+    /// Returns the error of the first write that fails, leaving the changes
+    /// before it written.
+    pub fn apply<T: DiskImage + ?Sized>(&self, target: &mut T) -> Result<(), DosError> {
+        for (&(track, sector), data) in &self.changes {
+            target.write_sector(track, sector, data)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a D64 holding what the overlay reads, with the read errors
+    /// of the base in its error block.
+    ///
+    /// The D64 has 40 tracks if the base has more than 35; tracks beyond the
+    /// 40th are left out.
+    pub fn flatten(&self) -> D64 {
+        let mut image = D64::new(if self.tracks() > 35 { 40 } else { 35 });
+        for track in 1..=image.tracks() {
+            for sector in 0..self.sectors_per_track(track) {
+                if let Ok(data) = self.read_sector(track, sector) {
+                    let _ = image.write_sector(track, sector, &data);
+                }
+                if let Some(error) = self.sector_error(track, sector) {
+                    let _ = image.set_sector_error(track, sector, Some(error));
+                }
+            }
+        }
+        image
+    }
+}
+
+impl<I: BlockDevice + ?Sized> BlockDevice for Overlay<'_, I> {
+    fn tracks(&self) -> u8 {
+        self.base.tracks()
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        self.base.sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        match self.changes.get(&(track, sector)) {
+            Some(data) => Ok(*data),
+            None => self.base.read_sector(track, sector),
+        }
+    }
+
+    /// Records the write in the layer. Writing what the base holds drops
+    /// the change instead.
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        if self.base.read_sector(track, sector)? == *data {
+            self.changes.remove(&(track, sector));
+        } else {
+            self.changes.insert((track, sector), *data);
+        }
+        Ok(())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        self.base.sector_error(track, sector)
+    }
+}
+
+impl<I: DiskImage + ?Sized> DiskImage for Overlay<'_, I> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, FileType};
+
+    #[test]
+    fn records_writes_as_changes() {
+        let mut master = D64::new(35);
+        fs::format(&mut master, b"MASTER", Some(*b"01")).unwrap();
+        let pristine = master.clone();
+        let mut overlay = Overlay::new(&master);
+        assert_eq!(
+            overlay.write_sector(36, 0, &[0; 256]),
+            Err(DosError::IllegalTrackSector)
+        );
+        fs::write_file(&mut overlay, b"GAME", FileType::Prg, &[1, 8, 96]).unwrap();
+        let changed: alloc::vec::Vec<_> = overlay.changes().map(|(at, _)| at).collect();
+        assert_eq!(changed, [(17, 0), (18, 0), (18, 1)]);
+
+        let data = master.read_sector(17, 0).unwrap();
+        overlay.write_sector(17, 0, &data).unwrap();
+        assert_eq!(overlay.changes().count(), 2);
+        overlay.revert(18, 1);
+        assert_eq!(overlay.read_sector(18, 1), master.read_sector(18, 1));
+        overlay.discard();
+        assert!(!overlay.is_modified());
+        assert_eq!(master, pristine);
+    }
+
+    #[test]
+    fn flattens_into_a_copy() {
+        let mut master = D64::new(35);
+        fs::format(&mut master, b"MASTER", Some(*b"01")).unwrap();
+        master
+            .set_sector_error(20, 3, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut overlay = Overlay::new(&master);
+        fs::write_file(&mut overlay, b"SAVE", FileType::Seq, b"HI").unwrap();
+
+        let flat = overlay.flatten();
+        assert_eq!(flat.sector_error(20, 3), Some(DosError::DataChecksum));
+        assert_eq!(fs::read_directory(&flat), fs::read_directory(&overlay));
+        let mut copy = master.clone();
+        overlay.apply(&mut copy).unwrap();
+        assert_eq!(copy, flat);
+    }
+}
@@ -0,0 +1,130 @@
+//! PC64 files: P00, S00, U00, R00 and D00.
+//!
+//! The PC64 emulator kept each file of a disk as a host file with a small
+//! header, giving the type in the extension and the number in it telling
+//! files apart whose names, cut to 8.3, were the same:
+//!
+//! ```plaintext
+//! $00  signature, "C64File" and a zero byte
+//! $08  name, 16 bytes padded with zeros, and a zero byte
+//! $19  record length of a REL file, zero otherwise
+//! $1A  contents, for programs with their load address
+//! ```
+
+use crate::fs::FileType;
+use crate::image::ImageError;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The signature PC64 files start with.
+pub const SIGNATURE: &[u8] = b"C64File\0";
+/// The size of the header in front of the contents.
+pub const HEADER_SIZE: usize = 0x1A;
+
+/// A PC64 file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct P00 {
+    /// The name, without padding.
+    pub name: Vec<u8>,
+    pub record_length: u8,
+    pub data: Vec<u8>,
+}
+
+impl P00 {
+    /// Parses a PC64 file.
+    ///
+    /// # Errors
+    /// - [`ImageError::InvalidSignature`] unless the file starts with
+    ///   [`SIGNATURE`].
+    /// - [`ImageError::Truncated`] if the header is cut short.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(ImageError::InvalidSignature);
+        }
+        let header = bytes.get(..HEADER_SIZE).ok_or(ImageError::Truncated)?;
+        let name = &header[8..24];
+        let end = name.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        Ok(P00 {
+            name: name[..end].to_vec(),
+            record_length: header[0x19],
+            data: bytes[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Serializes the file, with the name cut to 16 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(SIGNATURE);
+        let mut name = [0; 17];
+        let length = self.name.len().min(16);
+        name[..length].copy_from_slice(&self.name[..length]);
+        bytes.extend_from_slice(&name);
+        bytes.push(self.record_length);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+/// Returns the file type a PC64 extension such as `P00` or `s01` stands
+/// for.
+pub fn file_type(extension: &str) -> Option<FileType> {
+    let mut chars = extension.chars();
+    let file_type = match chars.next()?.to_ascii_uppercase() {
+        'D' => FileType::Del,
+        'S' => FileType::Seq,
+        'P' => FileType::Prg,
+        'U' => FileType::Usr,
+        'R' => FileType::Rel,
+        _ => return None,
+    };
+    let digits: Vec<char> = chars.collect();
+    (digits.len() == 2 && digits.iter().all(char::is_ascii_digit)).then_some(file_type)
+}
+
+/// Returns the extension of the `number`th PC64 file of `file_type` with
+/// the same name, from `P00` to `P99`.
+pub fn extension(file_type: FileType, number: u8) -> String {
+    let letter = match file_type {
+        FileType::Del => 'D',
+        FileType::Seq => 'S',
+        FileType::Prg => 'P',
+        FileType::Usr => 'U',
+        FileType::Rel => 'R',
+    };
+    format!("{letter}{:02}", number.min(99))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_files() {
+        let file = P00 {
+            name: b"HELLO WORLD".to_vec(),
+            record_length: 0,
+            data: vec![0x01, 0x08, 0x60],
+        };
+        let bytes = file.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 3);
+        assert_eq!(&bytes[8..19], b"HELLO WORLD");
+        assert_eq!(bytes[24], 0);
+        assert_eq!(P00::from_bytes(&bytes).unwrap(), file);
+        assert_eq!(P00::from_bytes(&bytes[..20]), Err(ImageError::Truncated));
+        assert_eq!(
+            P00::from_bytes(b"C64 tape image file"),
+            Err(ImageError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn maps_extensions_to_types() {
+        assert_eq!(file_type("P00"), Some(FileType::Prg));
+        assert_eq!(file_type("s12"), Some(FileType::Seq));
+        assert_eq!(file_type("R0"), None);
+        assert_eq!(file_type("T64"), None);
+        assert_eq!(extension(FileType::Usr, 3), "U03");
+        assert_eq!(extension(FileType::Rel, 0), "R00");
+    }
+}
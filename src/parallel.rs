@@ -0,0 +1,629 @@
+//! Parallel cables between the user port and a drive.
+//!
+//! Speeders such as SpeedDOS and DolphinDOS, and the XP1541 cable used by
+//! nibtools and OpenCBM, wire the eight lines of the computer's user port
+//! to a spare port of the drive's VIA, plus one handshake line in each
+//! direction. Bytes then move whole: the sender puts a byte on the data
+//! lines and signals on its handshake line, the receiver takes the byte
+//! and signals back.
+//!
+//! The protocols differ in how they signal, see [`Handshake`]; which wires
+//! carry the handshake makes no difference to an emulated cable.
+//!
+//! On top of the byte transfer, [`ParallelHost`] and [`ParallelDevice`]
+//! speak a minimal track protocol of the kind tools like nibtools upload
+//! into the drive, moving raw GCR half tracks:
+//!
+//! ```plaintext
+//! 'R' half-track                        -> length (u16, little endian), GCR bytes
+//! 'W' half-track length (u16) GCR bytes -> status (0 or a DOS error code)
+//! ```
+//!
+//! Half tracks are counted as nibtools does, 2 for track 1 and 3 for track
+//! 1.5. Both ends have to be polled at least every 2µs.
+
+use std::collections::VecDeque;
+
+use crate::bus::BusError;
+use crate::error::DosError;
+use crate::g64::G64;
+
+/// How long a line holds a level, or a strobe pulse lasts.
+const PULSE: u64 = 4;
+/// How long a sender or a reading host waits for the other end.
+const TIMEOUT: u64 = 1000;
+
+/// Request byte for reading a half track.
+pub(crate) const READ_TRACK: u8 = b'R';
+/// Request byte for writing a half track.
+pub(crate) const WRITE_TRACK: u8 = b'W';
+
+/// How a cable signals that a byte is on the lines or has been taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Handshake {
+    /// Every change of the line's level signals once.
+    Toggle,
+    /// A short pulse signals, as the port's strobe lines do on their own
+    /// when the port is accessed.
+    Pulse,
+}
+
+/// The parallel protocols this module knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// SpeedDOS, which toggles its handshake lines in software.
+    SpeedDos,
+    /// DolphinDOS, which uses the strobe pulses of the port chips.
+    DolphinDos,
+    /// The XP1541 cable as driven by nibtools and OpenCBM.
+    Xp1541,
+}
+
+impl Protocol {
+    /// Returns how the protocol signals.
+    pub fn handshake(self) -> Handshake {
+        match self {
+            Protocol::SpeedDos | Protocol::Xp1541 => Handshake::Toggle,
+            Protocol::DolphinDos => Handshake::Pulse,
+        }
+    }
+}
+
+/// The state of the cable, `true` meaning a handshake line is asserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Lines {
+    /// The eight data lines, driven by the sending end.
+    pub data: u8,
+    /// The computer's handshake line.
+    pub computer: bool,
+    /// The drive's handshake line.
+    pub drive: bool,
+}
+
+/// Access to the cable from one end.
+pub trait ParallelPins {
+    /// Returns the state of the cable, including what this end drives.
+    fn read(&mut self) -> Lines;
+
+    /// Sets the lines this end drives; the others are ignored.
+    fn write(&mut self, lines: Lines);
+}
+
+/// An emulated cable between a computer and one drive.
+///
+/// # Example
+/// ```
+/// use cbm_dos::parallel::{Cable, Lines, ParallelPins};
+///
+/// let mut cable = Cable::new();
+/// cable.computer().write(Lines { data: 0x42, computer: true, drive: true });
+/// assert_eq!(cable.drive().read(), Lines { data: 0x42, computer: true, drive: false });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Cable {
+    computer: Lines,
+    drive: Lines,
+}
+
+impl Cable {
+    /// Creates a cable with all lines released.
+    pub fn new() -> Self {
+        Cable::default()
+    }
+
+    /// Returns the state of the cable.
+    pub fn lines(&self) -> Lines {
+        Lines {
+            data: self.computer.data | self.drive.data,
+            computer: self.computer.computer,
+            drive: self.drive.drive,
+        }
+    }
+
+    /// Returns the computer's end.
+    pub fn computer(&mut self) -> CableEnd<'_> {
+        CableEnd {
+            cable: self,
+            drive: false,
+        }
+    }
+
+    /// Returns the drive's end.
+    pub fn drive(&mut self) -> CableEnd<'_> {
+        CableEnd {
+            cable: self,
+            drive: true,
+        }
+    }
+}
+
+/// One end of a [`Cable`].
+#[derive(Debug)]
+pub struct CableEnd<'a> {
+    cable: &'a mut Cable,
+    drive: bool,
+}
+
+impl ParallelPins for CableEnd<'_> {
+    fn read(&mut self) -> Lines {
+        self.cable.lines()
+    }
+
+    fn write(&mut self, lines: Lines) {
+        if self.drive {
+            self.cable.drive = Lines {
+                computer: false,
+                ..lines
+            };
+        } else {
+            self.cable.computer = Lines {
+                drive: false,
+                ..lines
+            };
+        }
+    }
+}
+
+/// One end's handshake line, and what it last saw of the other end's.
+#[derive(Debug, Clone)]
+struct Handshaker {
+    handshake: Handshake,
+    level: bool,
+    /// When the line last changed to signal.
+    last: Option<u64>,
+    /// Signals not given yet.
+    queued: u8,
+    peer: bool,
+}
+
+impl Handshaker {
+    fn new(handshake: Handshake) -> Self {
+        Handshaker {
+            handshake,
+            level: false,
+            last: None,
+            queued: 0,
+            peer: false,
+        }
+    }
+
+    /// Signals as soon as the line has held its level long enough for the
+    /// other end to see it.
+    fn signal(&mut self, now: u64) {
+        self.queued += 1;
+        self.advance(now);
+    }
+
+    /// Returns whether the other end signalled since the last update.
+    fn update(&mut self, peer: bool, now: u64) -> bool {
+        self.advance(now);
+        let signalled = match self.handshake {
+            Handshake::Toggle => peer != self.peer,
+            Handshake::Pulse => peer && !self.peer,
+        };
+        self.peer = peer;
+        signalled
+    }
+
+    fn advance(&mut self, now: u64) {
+        let (hold, interval) = match self.handshake {
+            Handshake::Toggle => (u64::MAX, PULSE),
+            Handshake::Pulse => (PULSE, 2 * PULSE),
+        };
+        let elapsed = self.last.map_or(u64::MAX, |last| now - last);
+        if self.level && elapsed >= hold {
+            self.level = false;
+        }
+        if self.queued > 0 && elapsed >= interval {
+            self.level = !self.level;
+            self.last = Some(now);
+            self.queued -= 1;
+        }
+    }
+}
+
+/// A step of the computer's queue.
+#[derive(Debug, Clone)]
+enum Transfer {
+    Send(Vec<u8>),
+    Receive(usize),
+    /// A length followed by that many bytes.
+    ReceiveTrack,
+}
+
+#[derive(Debug, Clone)]
+enum HostState {
+    Idle,
+    /// Sending `byte`, waiting for the drive to take it.
+    Sending {
+        byte: u8,
+        since: u64,
+    },
+    /// Waiting for `remaining` bytes, which make up a track length while
+    /// `length` is set.
+    Receiving {
+        remaining: usize,
+        length: bool,
+        since: u64,
+    },
+}
+
+/// The computer end of a parallel cable.
+///
+/// Like the bus hosts it queues transfers, carries them out in
+/// [`poll`](ParallelHost::poll) and stops at the first failure, reported by
+/// [`take_error`](ParallelHost::take_error). [`send`](ParallelHost::send)
+/// and [`receive`](ParallelHost::receive) move plain bytes for custom drive
+/// code; [`read_track`](ParallelHost::read_track) and
+/// [`write_track`](ParallelHost::write_track) speak the track protocol of
+/// [`ParallelDevice`].
+///
+/// # Example
+/// ```
+/// use cbm_dos::g64::G64;
+/// use cbm_dos::parallel::{Cable, ParallelDevice, ParallelHost, Protocol};
+///
+/// let mut cable = Cable::new();
+/// let mut host = ParallelHost::new(Protocol::Xp1541);
+/// let mut drive = ParallelDevice::new(G64::new(), Protocol::Xp1541);
+///
+/// host.write_track(2, &[0xFF; 40]);
+/// host.read_track(2);
+/// let mut now = 0;
+/// while !host.is_idle() {
+///     host.poll(&mut cable.computer(), now);
+///     drive.poll(&mut cable.drive(), now);
+///     now += 1;
+/// }
+/// let received = host.take_received();
+/// assert_eq!(received[0], 0);
+/// assert_eq!(received[1..], [0xFF; 40]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParallelHost {
+    queue: VecDeque<Transfer>,
+    state: HostState,
+    line: Handshaker,
+    pending: VecDeque<u8>,
+    length: Vec<u8>,
+    received: Vec<u8>,
+    error: Option<BusError>,
+}
+
+impl ParallelHost {
+    /// Creates a host for `protocol` with all lines released.
+    pub fn new(protocol: Protocol) -> Self {
+        ParallelHost {
+            queue: VecDeque::new(),
+            state: HostState::Idle,
+            line: Handshaker::new(protocol.handshake()),
+            pending: VecDeque::new(),
+            length: Vec::new(),
+            received: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Queues bytes for the drive.
+    pub fn send(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.queue.push_back(Transfer::Send(data.to_vec()));
+        }
+    }
+
+    /// Queues the reception of `count` bytes from the drive.
+    pub fn receive(&mut self, count: usize) {
+        if count > 0 {
+            self.queue.push_back(Transfer::Receive(count));
+        }
+    }
+
+    /// Queues reading `half_track`. The GCR bytes are received without
+    /// their length, so a missing track adds nothing.
+    pub fn read_track(&mut self, half_track: u8) {
+        self.send(&[READ_TRACK, half_track]);
+        self.queue.push_back(Transfer::ReceiveTrack);
+    }
+
+    /// Queues writing `data` to `half_track` and the reception of the
+    /// drive's status byte.
+    ///
+    /// # Panics
+    /// Panics if `data` is longer than 65535 bytes.
+    pub fn write_track(&mut self, half_track: u8, data: &[u8]) {
+        let length = u16::try_from(data.len()).expect("track too long");
+        let mut request = vec![WRITE_TRACK, half_track];
+        request.extend_from_slice(&length.to_le_bytes());
+        request.extend_from_slice(data);
+        self.send(&request);
+        self.receive(1);
+    }
+
+    /// Returns `true` once every queued transfer has finished.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && matches!(self.state, HostState::Idle)
+    }
+
+    /// Returns and clears the bytes received so far.
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Returns and clears the error that stopped the queue.
+    pub fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+
+    /// Advances the host to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl ParallelPins, now: u64) {
+        let lines = pins.read();
+        if let Err(error) = self.step(lines, now) {
+            self.queue.clear();
+            self.pending.clear();
+            self.state = HostState::Idle;
+            self.error = Some(error);
+        }
+        let data = match self.state {
+            HostState::Sending { byte, .. } => byte,
+            _ => 0,
+        };
+        pins.write(Lines {
+            data,
+            computer: self.line.level,
+            drive: false,
+        });
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Result<(), BusError> {
+        let signalled = self.line.update(lines.drive, now);
+        match &mut self.state {
+            HostState::Idle => match self.queue.pop_front() {
+                Some(Transfer::Send(bytes)) => {
+                    self.pending = bytes.into();
+                    self.next_byte(now);
+                }
+                Some(Transfer::Receive(remaining)) => {
+                    self.state = HostState::Receiving {
+                        remaining,
+                        length: false,
+                        since: now,
+                    };
+                }
+                Some(Transfer::ReceiveTrack) => {
+                    self.state = HostState::Receiving {
+                        remaining: 2,
+                        length: true,
+                        since: now,
+                    };
+                }
+                None => {}
+            },
+            HostState::Sending { .. } if signalled => self.next_byte(now),
+            HostState::Receiving {
+                remaining,
+                length,
+                since,
+            } if signalled => {
+                self.line.signal(now);
+                *remaining -= 1;
+                *since = now;
+                if *length {
+                    self.length.push(lines.data);
+                } else {
+                    self.received.push(lines.data);
+                }
+                if *remaining == 0 && *length {
+                    let bytes = std::mem::take(&mut self.length);
+                    *remaining = usize::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+                    *length = false;
+                }
+                if *remaining == 0 {
+                    self.state = HostState::Idle;
+                }
+            }
+            HostState::Sending { since, .. } | HostState::Receiving { since, .. }
+                if now - *since > TIMEOUT =>
+            {
+                return Err(BusError::Timeout);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Puts the next pending byte on the lines, or finishes the transfer.
+    fn next_byte(&mut self, now: u64) {
+        self.state = match self.pending.pop_front() {
+            Some(byte) => {
+                self.line.signal(now);
+                HostState::Sending { byte, since: now }
+            }
+            None => HostState::Idle,
+        };
+    }
+}
+
+/// Raw track access for the drive end of a cable.
+pub trait RawTracks {
+    /// Returns the GCR bytes of `half_track`, or `None` if it holds none.
+    fn read_raw(&self, half_track: u8) -> Option<Vec<u8>>;
+
+    /// Replaces the GCR bytes of `half_track`.
+    ///
+    /// # Errors
+    /// Returns the error a drive would report for the write.
+    fn write_raw(&mut self, half_track: u8, data: &[u8]) -> Result<(), DosError>;
+}
+
+impl RawTracks for G64 {
+    fn read_raw(&self, half_track: u8) -> Option<Vec<u8>> {
+        let index = usize::from(half_track).checked_sub(2)?;
+        self.half_track(index).map(<[u8]>::to_vec)
+    }
+
+    fn write_raw(&mut self, half_track: u8, data: &[u8]) -> Result<(), DosError> {
+        let index = usize::from(half_track)
+            .checked_sub(2)
+            .ok_or(DosError::IllegalTrackSector)?;
+        let zone = crate::timing::speed_zone(half_track / 2);
+        self.set_half_track(index, data.to_vec(), zone);
+        Ok(())
+    }
+}
+
+/// The drive end of a parallel cable, answering track requests.
+#[derive(Debug, Clone)]
+pub struct ParallelDevice<T> {
+    tracks: T,
+    line: Handshaker,
+    request: Vec<u8>,
+    reply: VecDeque<u8>,
+    /// The byte on the lines, waiting for the computer to take it.
+    sending: Option<u8>,
+}
+
+impl<T: RawTracks> ParallelDevice<T> {
+    /// Attaches `tracks` through a cable speaking `protocol`.
+    pub fn new(tracks: T, protocol: Protocol) -> Self {
+        ParallelDevice {
+            tracks,
+            line: Handshaker::new(protocol.handshake()),
+            request: Vec::new(),
+            reply: VecDeque::new(),
+            sending: None,
+        }
+    }
+
+    /// Returns the tracks.
+    pub fn tracks(&self) -> &T {
+        &self.tracks
+    }
+
+    /// Returns the tracks for modification.
+    pub fn tracks_mut(&mut self) -> &mut T {
+        &mut self.tracks
+    }
+
+    /// Detaches the tracks.
+    pub fn into_inner(self) -> T {
+        self.tracks
+    }
+
+    /// Advances the drive to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl ParallelPins, now: u64) {
+        let lines = pins.read();
+        let signalled = self.line.update(lines.computer, now);
+        if self.sending.is_some() {
+            if signalled {
+                self.sending = None;
+            }
+        } else if signalled {
+            self.request.push(lines.data);
+            self.line.signal(now);
+            self.answer();
+        }
+        if self.sending.is_none()
+            && let Some(byte) = self.reply.pop_front()
+        {
+            self.sending = Some(byte);
+            self.line.signal(now);
+        }
+        pins.write(Lines {
+            data: self.sending.unwrap_or(0),
+            computer: false,
+            drive: self.line.level,
+        });
+    }
+
+    /// Carries out the request once it is complete.
+    fn answer(&mut self) {
+        match self.request[..] {
+            [READ_TRACK, half_track] => {
+                let data = self.tracks.read_raw(half_track).unwrap_or_default();
+                let length = data.len().min(usize::from(u16::MAX));
+                self.reply.extend((length as u16).to_le_bytes());
+                self.reply.extend(&data[..length]);
+            }
+            [WRITE_TRACK, half_track, low, high, ref data @ ..]
+                if data.len() == usize::from(u16::from_le_bytes([low, high])) =>
+            {
+                let status = match self.tracks.write_raw(half_track, data) {
+                    Ok(()) => 0,
+                    Err(error) => error.code(),
+                };
+                self.reply.push_back(status);
+            }
+            [READ_TRACK, ..] | [WRITE_TRACK, ..] => return,
+            _ => {}
+        }
+        self.request.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs;
+
+    /// Runs the host and the device at 1µs steps from `now` on.
+    fn run(
+        cable: &mut Cable,
+        host: &mut ParallelHost,
+        mut device: Option<&mut ParallelDevice<G64>>,
+        now: &mut u64,
+    ) {
+        let start = *now;
+        while !host.is_idle() {
+            host.poll(&mut cable.computer(), *now);
+            if let Some(device) = device.as_deref_mut() {
+                device.poll(&mut cable.drive(), *now);
+            }
+            *now += 1;
+            assert!(*now - start < 10_000_000, "cable hung");
+        }
+    }
+
+    #[test]
+    fn moves_tracks_with_every_protocol() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"PARALLEL", Some(*b"01")).unwrap();
+        let g64 = G64::from_image(&image);
+        for protocol in [Protocol::SpeedDos, Protocol::DolphinDos, Protocol::Xp1541] {
+            let mut cable = Cable::new();
+            let mut host = ParallelHost::new(protocol);
+            let mut device = ParallelDevice::new(g64.clone(), protocol);
+            let mut now = 0;
+
+            host.read_track(36);
+            run(&mut cable, &mut host, Some(&mut device), &mut now);
+            assert_eq!(host.take_error(), None);
+            let track = host.take_received();
+            assert_eq!(track, g64.track(18).unwrap());
+
+            host.write_track(5, &track);
+            run(&mut cable, &mut host, Some(&mut device), &mut now);
+            assert_eq!(host.take_received(), [0]);
+            assert_eq!(device.tracks().half_track(3), Some(&track[..]));
+        }
+    }
+
+    #[test]
+    fn reports_missing_tracks_and_absent_drives() {
+        let mut cable = Cable::new();
+        let mut host = ParallelHost::new(Protocol::DolphinDos);
+        let mut device = ParallelDevice::new(G64::new(), Protocol::DolphinDos);
+        let mut now = 0;
+        host.read_track(70);
+        host.write_track(1, &[0x55]);
+        host.send(&[0x00]);
+        run(&mut cable, &mut host, Some(&mut device), &mut now);
+        assert_eq!(host.take_error(), None);
+        assert_eq!(host.take_received(), [DosError::IllegalTrackSector.code()]);
+
+        host.read_track(2);
+        run(&mut cable, &mut host, None, &mut now);
+        assert_eq!(host.take_error(), Some(BusError::Timeout));
+        assert!(host.is_idle());
+    }
+}
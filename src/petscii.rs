@@ -0,0 +1,477 @@
+//! PETSCII and Unicode.
+//!
+//! The C64 shows text in one of two character sets: the unshifted set
+//! with upper case letters and graphics, the set it starts in, and the
+//! shifted set with lower and upper case letters. Either set gives the
+//! same PETSCII byte another character, so conversions take a
+//! [`Charset`].
+//!
+//! Letters, digits and punctuation become their Unicode counterparts, the
+//! shifted space `0xA0` a no-break space. Graphics characters have no
+//! counterparts everywhere, and become the code points C64 fonts such as
+//! C64 Pro Mono put them at in the private-use area: `U+E000` plus the
+//! screen code in the unshifted set, `U+E100` plus the screen code in the
+//! shifted set. Those fonts place every character there, and the
+//! conversion back accepts any of them. Control codes become the Unicode
+//! control characters of the same value.
+//!
+//! Every byte survives the round trip, except that PETSCII has two codes
+//! for some characters: `0x60`–`0x7F` repeat `0xC0`–`0xDF`, `0xE0`–`0xFE`
+//! repeat `0xA0`–`0xBE`, and `0xFF` is `0xDE`. These come back as the
+//! latter codes, the ones the keyboard types.
+//!
+//! Screen codes are what the VIC-II shows, and what directory art and
+//! some data on disk are stored as: the index of a character in the
+//! character ROM, with bit 7 for reverse video. PETSCII has no reverse
+//! characters but the controls [`RVS_ON`] and [`RVS_OFF`]; [`to_screen`]
+//! and [`from_screen`] convert text with them. Screen codes `0x80` and up
+//! become the private-use code points of the reverse characters.
+//!
+//! Names on disk can hold any byte, and [`to_host_name`] turns them into
+//! names any host filesystem takes, which [`from_host_name`] turns back
+//! into the same bytes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The control code that turns reverse video on.
+pub const RVS_ON: u8 = 0x12;
+/// The control code that turns reverse video off.
+pub const RVS_OFF: u8 = 0x92;
+/// The control code that ends a line, and reverse video with it.
+pub const RETURN: u8 = 0x0D;
+
+/// How [`render`] deals with control codes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Controls {
+    /// As the C64 prints: reverse video switches, between quotes control
+    /// codes show as reverse characters, and elsewhere the rest vanish.
+    #[default]
+    Print,
+    /// Every control code shows as `{$XX}` with its value in hex, so that
+    /// the cursor moves and colors of directory art stay visible.
+    Escape,
+}
+
+/// The character that starts an escaped byte in a host name.
+pub const ESCAPE: char = '%';
+
+/// A character set of the C64.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Charset {
+    /// Upper case letters and graphics.
+    #[default]
+    Unshifted,
+    /// Lower and upper case letters and fewer graphics.
+    Shifted,
+}
+
+impl Charset {
+    /// Returns the private-use code point of screen code 0.
+    pub fn private_use_base(self) -> u32 {
+        match self {
+            Charset::Unshifted => 0xE000,
+            Charset::Shifted => 0xE100,
+        }
+    }
+}
+
+/// Returns the screen code a printable PETSCII byte shows as, or `None` for
+/// a control code.
+pub fn to_screen_code(byte: u8) -> Option<u8> {
+    match byte {
+        0x20..=0x3F => Some(byte),
+        0x40..=0x5F => Some(byte - 0x40),
+        0x60..=0x7F => Some(byte - 0x20),
+        0xA0..=0xBF => Some(byte - 0x40),
+        0xC0..=0xFE => Some(byte - 0x80),
+        0xFF => Some(0x5E),
+        _ => None,
+    }
+}
+
+/// Returns the screen code PETSCII `byte` shows as between quotes, where
+/// the editor prints control codes as reverse characters instead of
+/// obeying them, as directory listings do.
+pub fn quoted_screen_code(byte: u8) -> u8 {
+    match byte {
+        0x00..=0x1F => byte + 0x80,
+        0x80..=0x9F => byte + 0x40,
+        _ => to_screen_code(byte).unwrap_or_default(),
+    }
+}
+
+/// Returns the PETSCII byte the keyboard types for screen code `code`, and
+/// whether the code is reversed.
+pub fn from_screen_code(code: u8) -> (u8, bool) {
+    let byte = match code & 0x7F {
+        code @ 0x00..=0x1F => code + 0x40,
+        code @ 0x20..=0x3F => code,
+        code @ 0x40..=0x5F => code + 0x80,
+        code => code + 0x40,
+    };
+    (byte, code & 0x80 != 0)
+}
+
+/// Returns the character of screen code `code` in `charset`.
+fn glyph(code: u8, charset: Charset) -> char {
+    match (charset, code) {
+        (_, 0x00) => '@',
+        (Charset::Unshifted, 0x01..=0x1A) => char::from(b'A' + code - 0x01),
+        (Charset::Shifted, 0x01..=0x1A) => char::from(b'a' + code - 0x01),
+        (_, 0x1B) => '[',
+        (_, 0x1C) => '£',
+        (_, 0x1D) => ']',
+        (_, 0x1E) => '↑',
+        (_, 0x1F) => '←',
+        (_, 0x20..=0x3F) => char::from(code),
+        (Charset::Shifted, 0x41..=0x5A) => char::from(b'A' + code - 0x41),
+        (Charset::Unshifted, 0x5E) => 'π',
+        (_, 0x60) => '\u{A0}',
+        _ => char::from_u32(charset.private_use_base() + u32::from(code)).unwrap_or('\u{FFFD}'),
+    }
+}
+
+/// Returns the screen code of `c` in `charset`.
+fn glyph_code(c: char, charset: Charset) -> Option<u8> {
+    let code = match (charset, c) {
+        (_, '@') => 0x00,
+        (Charset::Unshifted, 'A'..='Z') => c as u8 - b'A' + 0x01,
+        (Charset::Shifted, 'a'..='z') => c as u8 - b'a' + 0x01,
+        (_, '[') => 0x1B,
+        (_, '£') => 0x1C,
+        (_, ']') => 0x1D,
+        (_, '↑') => 0x1E,
+        (_, '←') => 0x1F,
+        (_, ' '..='?') => c as u8,
+        (Charset::Shifted, 'A'..='Z') => c as u8 - b'A' + 0x41,
+        (Charset::Unshifted, 'π') => 0x5E,
+        (_, '\u{A0}') => 0x60,
+        _ => {
+            let offset = u32::from(c).checked_sub(charset.private_use_base())?;
+            u8::try_from(offset).ok()?
+        }
+    };
+    Some(code)
+}
+
+/// Returns the character PETSCII `byte` stands for in `charset`.
+pub fn to_char(byte: u8, charset: Charset) -> char {
+    match to_screen_code(byte) {
+        Some(code) => glyph(code, charset),
+        None => char::from(byte),
+    }
+}
+
+/// Returns the PETSCII byte of `c` in `charset`, or `None` if the set
+/// lacks the character.
+///
+/// Letters must be in the case the set has: the unshifted set knows no
+/// `a`.
+pub fn from_char(c: char, charset: Charset) -> Option<u8> {
+    match c {
+        '\0'..='\x1F' | '\u{80}'..='\u{9F}' => Some(c as u8),
+        _ => match glyph_code(c, charset).map(from_screen_code)? {
+            (byte, false) => Some(byte),
+            (_, true) => None,
+        },
+    }
+}
+
+/// Returns the character screen code `code` shows in `charset`.
+pub fn screen_to_char(code: u8, charset: Charset) -> char {
+    glyph(code, charset)
+}
+
+/// Returns the screen code of `c` in `charset`, or `None` if the set lacks
+/// the character.
+pub fn char_to_screen(c: char, charset: Charset) -> Option<u8> {
+    glyph_code(c, charset)
+}
+
+/// Converts PETSCII text, such as a filename, to a string.
+pub fn decode(bytes: &[u8], charset: Charset) -> String {
+    bytes.iter().map(|&byte| to_char(byte, charset)).collect()
+}
+
+/// Converts a string to PETSCII, or returns `None` if `charset` lacks one
+/// of its characters.
+pub fn encode(text: &str, charset: Charset) -> Option<Vec<u8>> {
+    text.chars().map(|c| from_char(c, charset)).collect()
+}
+
+/// Converts a PETSCII name, such as a filename without its padding, to a
+/// name safe on host filesystems.
+///
+/// The name is in the unshifted set, so that its letters have one case
+/// and no filesystem ignoring case can take two names for one. Bytes the
+/// conversion back could not tell apart, control codes, the shifted space,
+/// characters some filesystems forbid, spaces and dots at the end, and the
+/// first letter of a device name such as `CON` are escaped as [`ESCAPE`]
+/// and two hex digits instead.
+pub fn to_host_name(name: &[u8]) -> String {
+    let end = name
+        .iter()
+        .rposition(|&byte| byte != b' ' && byte != b'.')
+        .map_or(0, |p| p + 1);
+    let device = is_device_name(name);
+    let mut host = String::with_capacity(name.len());
+    for (i, &byte) in name.iter().enumerate() {
+        let escape = i >= end
+            || (i == 0 && device)
+            || matches!(
+                byte,
+                0x00..=0x1F
+                    | 0x60..=0xA0
+                    | 0xE0..=0xFF
+                    | b'"'
+                    | b'%'
+                    | b'*'
+                    | b'/'
+                    | b':'
+                    | b'<'
+                    | b'>'
+                    | b'?'
+            );
+        if escape {
+            host.push(ESCAPE);
+            for digit in [byte >> 4, byte & 0x0F] {
+                host.push(char::from(b"0123456789ABCDEF"[usize::from(digit)]));
+            }
+        } else {
+            host.push(to_char(byte, Charset::Unshifted));
+        }
+    }
+    host
+}
+
+/// Returns whether `name` is, up to an extension, a name Windows reserves
+/// for a device.
+fn is_device_name(name: &[u8]) -> bool {
+    let stem = name.split(|&byte| byte == b'.').next().unwrap_or_default();
+    match stem {
+        b"CON" | b"PRN" | b"AUX" | b"NUL" => true,
+        [b'C', b'O', b'M', digit] | [b'L', b'P', b'T', digit] => (b'1'..=b'9').contains(digit),
+        _ => false,
+    }
+}
+
+/// Converts a host name back to the PETSCII name [`to_host_name`] made it
+/// from, or returns `None` if it is not one.
+///
+/// Lower case letters stand for the upper case ones, which lets names
+/// created on the host in any case through.
+pub fn from_host_name(host: &str) -> Option<Vec<u8>> {
+    let mut name = Vec::with_capacity(host.len());
+    let mut chars = host.chars();
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            ESCAPE => {
+                let high = chars.next()?.to_digit(16)?;
+                let low = chars.next()?.to_digit(16)?;
+                (high << 4 | low) as u8
+            }
+            'a'..='z' => c.to_ascii_uppercase() as u8,
+            _ => from_char(c, Charset::Unshifted)?,
+        };
+        name.push(byte);
+    }
+    Some(name)
+}
+
+/// Renders a line of PETSCII text as `PRINT` and `LIST` show it.
+///
+/// Reverse characters become their private-use code points.
+pub fn render(text: &[u8], charset: Charset, controls: Controls) -> String {
+    let mut line = String::with_capacity(text.len());
+    let (mut quote, mut reverse) = (false, 0);
+    for &byte in text {
+        quote ^= byte == b'"';
+        let code = match (to_screen_code(byte), controls) {
+            (Some(code), _) => code | reverse,
+            (None, Controls::Escape) => {
+                let digits = b"0123456789ABCDEF";
+                line.push_str("{$");
+                line.push(char::from(digits[usize::from(byte >> 4)]));
+                line.push(char::from(digits[usize::from(byte & 0x0F)]));
+                line.push('}');
+                continue;
+            }
+            (None, Controls::Print) if quote => quoted_screen_code(byte),
+            (None, Controls::Print) => {
+                match byte {
+                    RVS_ON => reverse = 0x80,
+                    RVS_OFF | RETURN => reverse = 0,
+                    _ => {}
+                }
+                continue;
+            }
+        };
+        line.push(glyph(code, charset));
+    }
+    line
+}
+
+/// Converts PETSCII text to the screen codes it prints as.
+///
+/// [`RVS_ON`] and [`RVS_OFF`] switch reverse video, [`RETURN`] switches
+/// it off; other control codes print nothing.
+pub fn to_screen(bytes: &[u8]) -> Vec<u8> {
+    let mut reverse = 0;
+    let mut codes = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            RVS_ON => reverse = 0x80,
+            RVS_OFF | RETURN => reverse = 0,
+            _ => codes.extend(to_screen_code(byte).map(|code| code | reverse)),
+        }
+    }
+    codes
+}
+
+/// Converts screen codes to PETSCII text, switching reverse video with
+/// [`RVS_ON`] and [`RVS_OFF`] where it changes.
+pub fn from_screen(codes: &[u8]) -> Vec<u8> {
+    let mut reverse = false;
+    let mut bytes = Vec::with_capacity(codes.len());
+    for &code in codes {
+        let (byte, reversed) = from_screen_code(code);
+        if reversed != reverse {
+            reverse = reversed;
+            bytes.push(if reverse { RVS_ON } else { RVS_OFF });
+        }
+        bytes.push(byte);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_text_in_both_sets() {
+        let name = b"HELLO, \xC1\xD3 \x5C\xDE\xA0";
+        assert_eq!(
+            decode(name, Charset::Unshifted),
+            "HELLO, \u{E041}\u{E053} £π\u{A0}"
+        );
+        assert_eq!(decode(name, Charset::Shifted), "hello, AS £\u{E15E}\u{A0}");
+        assert_eq!(
+            encode("hello, AS £\u{E15E}\u{A0}", Charset::Shifted).unwrap(),
+            name
+        );
+        assert_eq!(
+            encode("HELLO", Charset::Shifted).unwrap(),
+            b"\xC8\xC5\xCC\xCC\xCF"
+        );
+        assert_eq!(encode("hello", Charset::Unshifted), None);
+        assert_eq!(encode("\u{E108}I", Charset::Shifted).unwrap(), b"\x48\xC9");
+        assert_eq!(encode("\u{E108}", Charset::Unshifted), None);
+        assert_eq!(encode("\u{E088}", Charset::Unshifted), None);
+        assert_eq!(to_char(0x0D, Charset::Unshifted), '\r');
+    }
+
+    #[test]
+    fn round_trips_every_byte() {
+        for charset in [Charset::Unshifted, Charset::Shifted] {
+            for byte in 0..=0xFF {
+                let c = to_char(byte, charset);
+                let expected = match byte {
+                    0x60..=0x7F => byte + 0x60,
+                    0xE0..=0xFE => byte - 0x40,
+                    0xFF => 0xDE,
+                    _ => byte,
+                };
+                assert_eq!(from_char(c, charset), Some(expected), "{byte:#04X}");
+                if let Some(code) = to_screen_code(byte) {
+                    let font = char::from_u32(charset.private_use_base() + u32::from(code));
+                    assert_eq!(from_char(font.unwrap(), charset), Some(expected));
+                    assert_eq!(char_to_screen(c, charset), Some(code));
+                    assert_eq!(screen_to_char(code, charset), c);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn converts_screen_codes_with_reverse_video() {
+        // " HI " reversed, then "OK", then a reversed "A" cut short
+        // by the end of the line.
+        let text = b"\x12\xA0HI\xA0\x92OK\x12A\x0DB";
+        let codes = to_screen(text);
+        assert_eq!(codes, [0xE0, 0x88, 0x89, 0xE0, 0x0F, 0x0B, 0x81, 0x02]);
+        assert_eq!(from_screen(&codes), b"\x12\xA0HI\xA0\x92OK\x12A\x92B");
+        assert_eq!(to_screen(&from_screen(&codes)), codes);
+
+        assert_eq!(screen_to_char(0x81, Charset::Unshifted), '\u{E081}');
+        assert_eq!(char_to_screen('\u{E181}', Charset::Shifted), Some(0x81));
+        assert_eq!(from_char('\u{E081}', Charset::Unshifted), None);
+        assert_eq!(quoted_screen_code(0x93), 0xD3);
+        assert_eq!(quoted_screen_code(0x05), 0x85);
+        assert_eq!(quoted_screen_code(b'A'), 0x01);
+    }
+
+    #[test]
+    fn escapes_host_names_reversibly() {
+        let names: [&[u8]; 8] = [
+            b"GAME/INTRO",
+            b"100% \xD3\xD3",
+            b"CON",
+            b"LPT1.PRG",
+            b"NOTES. ",
+            b"\x12TITLE\x92\xA0,8,1",
+            b"\x61\xC1",
+            b"",
+        ];
+        let hosts = names.map(to_host_name);
+        assert_eq!(
+            hosts,
+            [
+                "GAME%2FINTRO",
+                "100%25 \u{E053}\u{E053}",
+                "%43ON",
+                "%4CPT1.PRG",
+                "NOTES%2E%20",
+                "%12TITLE%92%A0,8,1",
+                "%61\u{E041}",
+                "",
+            ]
+        );
+        for (name, host) in names.iter().zip(&hosts) {
+            assert_eq!(from_host_name(host).as_deref(), Some(*name));
+        }
+        assert_eq!(from_host_name("game%2fintro").unwrap(), b"GAME/INTRO");
+        assert_eq!(from_host_name("%4"), None);
+        assert_eq!(from_host_name("\u{E141}"), None);
+    }
+
+    #[test]
+    fn host_names_are_distinct_and_safe() {
+        let mut hosts = alloc::collections::BTreeSet::new();
+        for byte in 0..=0xFF {
+            for name in [[byte, b'X'], [b'X', byte]] {
+                let host = to_host_name(&name);
+                assert!(!host.contains(['/', '\\', ':', '*', '?', '"', '<', '>', '|']));
+                assert!(!host.ends_with([' ', '.']));
+                assert!(host.chars().all(|c| !c.is_control() && c != '\u{A0}'));
+                assert_eq!(from_host_name(&host).unwrap(), name);
+                hosts.insert(host.to_uppercase());
+            }
+        }
+        assert_eq!(hosts.len(), 2 * 256 - 1);
+    }
+
+    #[test]
+    fn renders_as_printed() {
+        let text = b"\x12\"DISK\" \x92X\"\x05\x93\"\x1C";
+        assert_eq!(
+            render(text, Charset::Unshifted, Controls::Print),
+            "\u{E0A2}\u{E084}\u{E089}\u{E093}\u{E08B}\u{E0A2}\u{E0A0}X\"\u{E085}\u{E0D3}\""
+        );
+        assert_eq!(
+            render(text, Charset::Shifted, Controls::Escape),
+            "{$12}\"disk\" {$92}x\"{$05}{$93}\"{$1C}"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! PRG files: a program behind its load address.
+//!
+//! Disks, tapes and archives all store programs as a load address, two
+//! bytes little endian, followed by the bytes to load there. [`Prg`] keeps
+//! the two apart, which the T64 archives and tape decoders and encoders
+//! hand programs in and out as.
+
+use crate::basic::Start;
+use crate::image::ImageError;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// A program and the address it loads to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Prg {
+    /// The load address.
+    pub load: u16,
+    /// The bytes loaded there.
+    pub body: Vec<u8>,
+}
+
+impl Prg {
+    /// Creates a program loading `body` to `load`.
+    pub fn new(load: u16, body: Vec<u8>) -> Self {
+        Prg { load, body }
+    }
+
+    /// Splits a PRG file into load address and body.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::InvalidSize`] if the file lacks the load
+    /// address.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        match bytes {
+            [low, high, body @ ..] => {
+                Ok(Prg::new(u16::from_le_bytes([*low, *high]), body.to_vec()))
+            }
+            _ => Err(ImageError::InvalidSize(bytes.len())),
+        }
+    }
+
+    /// Returns the PRG file: load address and body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.body.len() + 2);
+        bytes.extend_from_slice(&self.load.to_le_bytes());
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// Returns the address behind the last byte, `0x10000` for a program
+    /// loading up to the top of memory.
+    pub fn end(&self) -> u32 {
+        u32::from(self.load) + self.body.len() as u32
+    }
+
+    /// Returns whether the program fits below the top of memory.
+    pub fn fits(&self) -> bool {
+        self.end() <= 0x10000
+    }
+
+    /// Returns whether loading the program writes to any address of
+    /// `range`.
+    pub fn covers(&self, range: RangeInclusive<u16>) -> bool {
+        !self.body.is_empty()
+            && u32::from(self.load) <= u32::from(*range.end())
+            && self.end() > u32::from(*range.start())
+    }
+
+    /// Returns how the program is started.
+    pub fn start(&self) -> Start {
+        Start::of(&self.to_bytes())
+    }
+
+    /// Moves the program to load at `load`.
+    ///
+    /// A BASIC program gets its line links rewritten for the new address,
+    /// as `LOAD` does when it loads one elsewhere; machine code is moved
+    /// as it is.
+    pub fn relocate(&mut self, load: u16) {
+        let basic = matches!(self.start(), Start::Basic | Start::Sys(_));
+        self.load = load;
+        if basic {
+            self.relink();
+        }
+    }
+
+    /// Rewrites the line links of a BASIC program to follow its lines from
+    /// the load address, up to the end of the program or the first line
+    /// without its end.
+    pub fn relink(&mut self) {
+        let mut at = 0;
+        while let Some(link) = self.body.get(at..at + 2)
+            && link != [0, 0]
+        {
+            let Some(end) = self.body[at..].iter().skip(4).position(|&c| c == 0) else {
+                break;
+            };
+            let next = at + 4 + end + 1;
+            let address = (usize::from(self.load) + next) as u16;
+            self.body[at..at + 2].copy_from_slice(&address.to_le_bytes());
+            at = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::{self, BASIC_START, Dialect};
+
+    #[test]
+    fn splits_and_joins_files() {
+        let prg = Prg::from_bytes(&[0x00, 0xC0, 0xA9, 0x00, 0x60]).unwrap();
+        assert_eq!((prg.load, prg.end()), (0xC000, 0xC003));
+        assert_eq!(prg.to_bytes(), [0x00, 0xC0, 0xA9, 0x00, 0x60]);
+        assert_eq!(prg.start(), Start::MachineCode(0xC000));
+        assert!(prg.covers(0xC002..=0xCFFF) && !prg.covers(0xC003..=0xCFFF));
+        assert!(!prg.covers(0x0000..=0xBFFF));
+        assert_eq!(Prg::from_bytes(&[0x01]), Err(ImageError::InvalidSize(1)));
+
+        let top = Prg::new(0xFFFF, vec![0x00]);
+        assert!(top.fits() && top.covers(0xFFFF..=0xFFFF));
+        assert!(!Prg::new(0xFFFF, vec![0x00, 0x00]).fits());
+    }
+
+    #[test]
+    fn relinks_relocated_basic() {
+        let source = b"10 PRINT\r20 GOTO 10";
+        let c64 = basic::tokenize(source, BASIC_START, &Dialect::V2).unwrap();
+        let vic = basic::tokenize(source, 0x1001, &Dialect::V2).unwrap();
+        let mut prg = Prg::from_bytes(&c64).unwrap();
+        prg.relocate(0x1001);
+        assert_eq!(prg.to_bytes(), vic);
+
+        let mut code = Prg::new(0xC000, vec![0x01, 0x08, 0x0A, 0x00, 0x00]);
+        code.relocate(0x2000);
+        assert_eq!(code.body, [0x01, 0x08, 0x0A, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,419 @@
+//! Recognizing copy protections.
+//!
+//! Protected disks give themselves away by what a 1541 format never
+//! writes: tracks DOS cannot read, syncs shorter or fewer than its own,
+//! tracks longer than a revolution at their speed, tracks beyond 35,
+//! tracks at another speed than their zone, and headers that lie.
+//! [`features`] measures these for every track of a G64, which flux dumps
+//! become through [`crate::flux::pipeline`], and [`detect`] scores them
+//! against the marks the well-known schemes are known for:
+//!
+//! - V-MAX! loads from a standard track, track 20 on many titles, and
+//!   keeps its data on tracks of its own format with short syncs, which
+//!   the `vmax` module reads.
+//! - RapidLok keeps a key on track 36 and its data on tracks of its own
+//!   format, written at their own speed; the `rapidlok` module reads and
+//!   verifies them.
+//! - Vorpal fills its tracks with a few long blocks behind very few
+//!   syncs, which the `vorpal` module reads.
+//! - Datasoft disks are standard except for a few tracks longer than a
+//!   drive writes them.
+//! - PirateBusters disks are standard except for a few tracks whose
+//!   headers do not match their place.
+//!
+//! Each detection comes with a confidence and the evidence it rests on.
+//! These are heuristics: a disk may well carry the marks of a scheme it
+//! does not use, and the schemes come in versions these marks do not tell
+//! apart.
+//!
+//! The `vmax`, `rapidlok` and `vorpal` modules follow the descriptions of
+//! their loaders, in layouts that can be changed per version. They have not
+//! been checked against dumps of real disks, and their tests only read
+//! tracks they mastered themselves, so they are built only with the
+//! `unverified-loaders` feature.
+
+use crate::GCR;
+use crate::d64::sectors_per_track;
+use crate::fs::DIR_TRACK;
+use crate::g64::G64;
+use crate::timing::{DEFAULT_RPM, SYNC_BITS, find_syncs, speed_zone, track_capacity};
+use crate::track::{self, DATA_MARK, HEADER_MARK};
+use alloc::vec::Vec;
+
+/// Syncs shorter than this many bits are short: the 1541 writes 40, and
+/// the one bit a gap byte ends with runs into them.
+pub const SHORT_SYNC: usize = 20;
+
+/// What [`features`] measures on a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackFeatures {
+    /// The half track index, 0 for track 1.
+    pub half_track: usize,
+    /// The length in bytes.
+    pub length: usize,
+    /// The speed zone the G64 stores for the track.
+    pub zone: u8,
+    /// The number of sync marks.
+    pub syncs: usize,
+    /// The length in bits of the longest sync mark, 0 without one.
+    pub longest_sync: usize,
+    /// The number of headers that carry this track and pass their
+    /// checksum.
+    pub headers: usize,
+    /// The number of headers that carry another track or fail their
+    /// checksum.
+    pub bad_headers: usize,
+    /// The number of data blocks.
+    pub data_blocks: usize,
+    /// The number of blocks that are neither headers nor data blocks.
+    pub other_blocks: usize,
+}
+
+impl TrackFeatures {
+    /// Returns the track number, with half tracks rounded down.
+    pub fn track(&self) -> u8 {
+        (self.half_track / 2 + 1) as u8
+    }
+
+    /// Returns `true` for a half track between two tracks.
+    pub fn is_half(&self) -> bool {
+        self.half_track % 2 == 1
+    }
+
+    /// Returns `true` if the track is as a 1541 formats it: every sector
+    /// has its header and data block and there is nothing else.
+    pub fn is_standard(&self) -> bool {
+        !self.is_half()
+            && self.headers >= usize::from(sectors_per_track(self.track()))
+            && self.data_blocks >= self.headers
+            && self.bad_headers == 0
+            && self.other_blocks == 0
+    }
+
+    /// Returns `true` if the track holds nothing DOS can read: no header
+    /// for it at all, but blocks of another format.
+    pub fn is_foreign(&self) -> bool {
+        self.headers == 0 && self.other_blocks > 0
+    }
+
+    /// Returns `true` if the track is longer than a revolution at its
+    /// speed by more than the 4% a fast drive leaves.
+    pub fn is_long(&self) -> bool {
+        let capacity = track_capacity(self.zone & 3, DEFAULT_RPM);
+        self.length > capacity + capacity / 25
+    }
+
+    /// Returns `true` if the track is recorded at another speed than the
+    /// 1541 uses for it.
+    pub fn is_off_zone(&self) -> bool {
+        self.zone != speed_zone(self.track())
+    }
+}
+
+/// Measures every track of `g64`, in the order of the half tracks.
+pub fn features(g64: &G64) -> Vec<TrackFeatures> {
+    let gcr = GCR::new();
+    (0..g64.half_tracks())
+        .filter_map(|index| {
+            let data = g64.half_track(index).filter(|d| !d.is_empty())?;
+            let track = (index / 2 + 1) as u8;
+            let bits = data.len() * 8;
+            let longest_sync = find_syncs(data)
+                .iter()
+                .map(|&(detected, end)| (end + bits - detected) % bits + SYNC_BITS - 1)
+                .max()
+                .unwrap_or(0);
+            let mut features = TrackFeatures {
+                half_track: index,
+                length: data.len(),
+                zone: g64.half_track_zone(index),
+                syncs: 0,
+                longest_sync,
+                headers: 0,
+                bad_headers: 0,
+                data_blocks: 0,
+                other_blocks: 0,
+            };
+            for block in track::blocks(data) {
+                features.syncs += 1;
+                let decoded = block.get(..10).and_then(|b| gcr.decode(b));
+                match decoded {
+                    Some(header) if header[0] == HEADER_MARK => {
+                        let sum = header[2] ^ header[3] ^ header[4] ^ header[5];
+                        if header[3] == track && header[1] == sum {
+                            features.headers += 1;
+                        } else {
+                            features.bad_headers += 1;
+                        }
+                    }
+                    Some(data) if data[0] == DATA_MARK => features.data_blocks += 1,
+                    _ if block.is_empty() => features.syncs -= 1,
+                    _ => features.other_blocks += 1,
+                }
+            }
+            Some(features)
+        })
+        .collect()
+}
+
+/// A copy protection scheme [`detect`] knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Scheme {
+    VMax,
+    RapidLok,
+    Vorpal,
+    Datasoft,
+    PirateBusters,
+}
+
+impl Scheme {
+    /// Returns the name the scheme is known by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Scheme::VMax => "V-MAX!",
+            Scheme::RapidLok => "RapidLok",
+            Scheme::Vorpal => "Vorpal",
+            Scheme::Datasoft => "Datasoft",
+            Scheme::PirateBusters => "PirateBusters",
+        }
+    }
+}
+
+/// What a detection rests on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Evidence {
+    /// The directory track is standard.
+    StandardDirectory,
+    /// The track is standard among foreign ones, as a loader track is.
+    StandardTrack(u8),
+    /// The tracks hold no header DOS can read.
+    Foreign(Vec<u8>),
+    /// The foreign tracks have only syncs shorter than [`SHORT_SYNC`].
+    ShortSyncs(Vec<u8>),
+    /// The foreign tracks have at most three syncs.
+    FewSyncs(Vec<u8>),
+    /// The tracks are longer than a revolution at their speed.
+    LongTracks(Vec<u8>),
+    /// The tracks have headers of other tracks or with bad checksums.
+    BadHeaders(Vec<u8>),
+    /// The tracks are recorded at another speed than their zone.
+    OffZone(Vec<u8>),
+    /// The track beyond 35 holds data.
+    KeyTrack(u8),
+    /// Every other track is standard.
+    OtherwiseStandard,
+}
+
+/// A scheme found on a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub scheme: Scheme,
+    /// How sure the detection is, in percent.
+    pub confidence: u8,
+    pub evidence: Vec<Evidence>,
+}
+
+/// The lowest confidence [`detect`] reports.
+pub const THRESHOLD: u8 = 50;
+
+fn tracks(features: &[&TrackFeatures]) -> Vec<u8> {
+    features.iter().map(|f| f.track()).collect()
+}
+
+/// Scores the tracks of `g64` against every scheme and returns those
+/// reaching [`THRESHOLD`], the most likely first.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::g64::G64;
+/// use cbm_dos::protection::{Scheme, detect};
+///
+/// let mut g64 = G64::from_image(&D64::new(35));
+/// assert!(detect(&g64).is_empty());
+///
+/// // A track a sixth longer than it can be.
+/// let mut long = g64.track(5).unwrap().to_vec();
+/// long.extend(vec![0x55; long.len() / 6]);
+/// g64.set_track(5, long);
+/// assert_eq!(detect(&g64)[0].scheme, Scheme::Datasoft);
+/// ```
+pub fn detect(g64: &G64) -> Vec<Detection> {
+    let all = features(g64);
+    let full: Vec<&TrackFeatures> = all
+        .iter()
+        .filter(|f| !f.is_half() && f.track() <= 35)
+        .collect();
+    let at = |track: u8| full.iter().find(|f| f.track() == track);
+    let standard_directory = at(DIR_TRACK).is_some_and(|f| f.is_standard());
+    let foreign: Vec<&TrackFeatures> = full.iter().copied().filter(|f| f.is_foreign()).collect();
+    let rest_standard = |odd: &[&TrackFeatures]| {
+        full.iter()
+            .filter(|f| !odd.iter().any(|o| o.half_track == f.half_track))
+            .all(|f| f.is_standard())
+    };
+
+    let mut detections = Vec::new();
+    let mut push = |scheme, score: u32, evidence| {
+        let confidence = score.min(100) as u8;
+        if confidence >= THRESHOLD {
+            detections.push(Detection {
+                scheme,
+                confidence,
+                evidence,
+            });
+        }
+    };
+
+    if foreign.len() >= 5 {
+        let short: Vec<&TrackFeatures> = foreign
+            .iter()
+            .copied()
+            .filter(|f| f.longest_sync > 0 && f.longest_sync < SHORT_SYNC)
+            .collect();
+        let few: Vec<&TrackFeatures> = foreign.iter().copied().filter(|f| f.syncs <= 3).collect();
+        let mut base = Vec::from([Evidence::Foreign(tracks(&foreign))]);
+        if standard_directory {
+            base.push(Evidence::StandardDirectory);
+        }
+        let directory = if standard_directory { 20 } else { 0 };
+
+        if short.len() * 2 > foreign.len() {
+            let mut evidence = base.clone();
+            evidence.push(Evidence::ShortSyncs(tracks(&short)));
+            let mut score = 40 + directory;
+            if at(20).is_some_and(|f| f.is_standard()) {
+                evidence.push(Evidence::StandardTrack(20));
+                score += 30;
+            }
+            push(Scheme::VMax, score, evidence);
+        }
+
+        let key = all
+            .iter()
+            .find(|f| f.track() == 36 && !f.is_half() && f.syncs > 0);
+        let off_zone: Vec<&TrackFeatures> = foreign
+            .iter()
+            .copied()
+            .filter(|f| f.is_off_zone())
+            .collect();
+        if key.is_some() || !off_zone.is_empty() {
+            let mut evidence = base.clone();
+            let mut score = 20 + directory;
+            if key.is_some() {
+                evidence.push(Evidence::KeyTrack(36));
+                score += 40;
+            }
+            if off_zone.len() * 2 > foreign.len() {
+                evidence.push(Evidence::OffZone(tracks(&off_zone)));
+                score += 30;
+            }
+            push(Scheme::RapidLok, score, evidence);
+        }
+
+        if few.len() * 2 > foreign.len() {
+            let mut evidence = base;
+            evidence.push(Evidence::FewSyncs(tracks(&few)));
+            push(Scheme::Vorpal, 50 + directory, evidence);
+        }
+    }
+
+    let long: Vec<&TrackFeatures> = full
+        .iter()
+        .copied()
+        .filter(|f| f.is_long() && f.headers > 0)
+        .collect();
+    if (1..=6).contains(&long.len()) {
+        let mut evidence = Vec::from([Evidence::LongTracks(tracks(&long))]);
+        let mut score = 60;
+        if rest_standard(&long) {
+            evidence.push(Evidence::OtherwiseStandard);
+            score += 40;
+        }
+        push(Scheme::Datasoft, score, evidence);
+    }
+
+    let bad: Vec<&TrackFeatures> = full.iter().copied().filter(|f| f.bad_headers > 0).collect();
+    if (1..=4).contains(&bad.len()) && foreign.is_empty() {
+        let mut evidence = Vec::from([Evidence::BadHeaders(tracks(&bad))]);
+        let mut score = 60;
+        if rest_standard(&bad) {
+            evidence.push(Evidence::OtherwiseStandard);
+            score += 40;
+        }
+        push(Scheme::PirateBusters, score, evidence);
+    }
+
+    detections.sort_by(|a, b| {
+        b.confidence
+            .cmp(&a.confidence)
+            .then(a.scheme.cmp(&b.scheme))
+    });
+    detections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn standard() -> G64 {
+        G64::from_image(&D64::new(35))
+    }
+
+    /// A track of `blocks` blocks of a format of its own behind syncs of
+    /// `sync` bytes.
+    fn foreign(blocks: usize, sync: usize, length: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..blocks {
+            data.extend(core::iter::repeat_n(0xFF, sync));
+            data.extend(core::iter::repeat_n(0x49, length / blocks - sync - 8));
+            data.extend([0x55; 8]);
+        }
+        data.resize(length, 0x55);
+        data
+    }
+
+    #[test]
+    fn measures_standard_and_foreign_tracks() {
+        let mut g64 = standard();
+        let all = features(&g64);
+        assert_eq!(all.len(), 35);
+        assert_eq!(all[0].headers, 21);
+        assert!(all.iter().all(TrackFeatures::is_standard));
+        assert_eq!(all[17].longest_sync, 41);
+
+        g64.set_track(3, foreign(30, 2, 7692));
+        let three = &features(&g64)[2];
+        assert!(three.is_foreign() && !three.is_standard());
+        assert_eq!((three.syncs, three.longest_sync), (30, 17));
+        assert!(detect(&g64).is_empty());
+    }
+
+    #[test]
+    fn tells_schemes_apart() {
+        let mut vmax = standard();
+        let mut vorpal = standard();
+        for track in (1..=35).filter(|&t| t != 18 && t != 20) {
+            let length = track_capacity(speed_zone(track), DEFAULT_RPM);
+            vmax.set_track(track, foreign(24, 2, length));
+            vorpal.set_track(track, foreign(2, 6, length));
+        }
+        let found = detect(&vmax);
+        assert_eq!(found[0].scheme, Scheme::VMax);
+        assert_eq!(found[0].confidence, 90);
+        assert!(found[0].evidence.contains(&Evidence::StandardTrack(20)));
+        assert_eq!(detect(&vorpal)[0].scheme, Scheme::Vorpal);
+
+        let mut rapidlok = vorpal.clone();
+        rapidlok.set_half_track(70, foreign(1, 5, 6250), 0);
+        assert_eq!(detect(&rapidlok)[0].scheme, Scheme::RapidLok);
+
+        let mut busters = standard();
+        let sectors = [[0; 256]; 18];
+        busters.set_track(30, track::encode_track(31, &sectors, [0, 0], |_| None));
+        let found = detect(&busters);
+        assert_eq!(found[0].scheme, Scheme::PirateBusters);
+        assert_eq!(found[0].confidence, 100);
+    }
+}
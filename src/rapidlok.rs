@@ -0,0 +1,531 @@
+//! Reading and verifying RapidLok protected disks.
+//!
+//! RapidLok keeps its data on tracks of its own layout, written at their
+//! own speed, and checks a key on track 36 before it loads. Each of its
+//! tracks starts with an extra sector, a run of one byte the loader syncs
+//! to, followed by sectors of a header and a data block, each behind a
+//! sync and opened by a mark of its own:
+//!
+//! ```plaintext
+//! extra    sync, 20 × $7B or more
+//! header   sync, $75, track, sector, track ^ sector
+//! data     sync, $6B, 256 data bytes, checksum
+//! key      sync, $6B, count, count key bytes, checksum   (track 36 only)
+//! ```
+//!
+//! Behind the marks every byte is written as two five bit codes, and both
+//! checksums are the XOR of the data bytes. The loader finds its tracks by
+//! their position relative to each other: the extra sectors of successive
+//! tracks are expected at the same distance, which dumps that do not
+//! start each track at the index hole lose.
+//!
+//! The marks and the code differ between versions of the scheme, so a
+//! [`Layout`] carries them; [`Layout::default`] is the layout of the
+//! common versions. [`decode_track`] reads a track, [`read_key`] the key,
+//! and [`verify`] checks a G64 for what the loader would stumble over in
+//! an emulator.
+
+use crate::error::DosError;
+use crate::g64::G64;
+use crate::image::{SECTOR_SIZE, Sector};
+use crate::timing::{DEFAULT_RPM, track_capacity};
+use crate::track::{GAP_BYTE, read_bits};
+use crate::vmax::{DOS_CODES, Reader, Writer, behind_syncs};
+use alloc::vec::Vec;
+
+/// The half track the key is kept on.
+pub const KEY_HALF_TRACK: usize = 70;
+
+/// How far the distance between the extra sectors of two tracks may be
+/// off the usual one, as a fraction of a revolution.
+pub const SKEW_TOLERANCE: f64 = 0.05;
+
+/// The marks and the code of a RapidLok version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The byte the extra sector is made of.
+    pub extra: u8,
+    /// The fewest bytes of an extra sector.
+    pub min_extra: usize,
+    /// The mark of a header.
+    pub header: u8,
+    /// The mark of a data block and of the key.
+    pub data: u8,
+    /// The five bit codes of the nibbles 0 to 15.
+    pub codes: [u8; 16],
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            extra: 0x7B,
+            min_extra: 20,
+            header: 0x75,
+            data: 0x6B,
+            codes: DOS_CODES,
+        }
+    }
+}
+
+/// The extra sector of a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extra {
+    /// The bit offset its bytes start at.
+    pub bit: usize,
+    /// The number of its bytes.
+    pub length: usize,
+}
+
+/// The result of reading a sector of a RapidLok track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorRead {
+    /// The sector number of the header.
+    pub sector: u8,
+    /// The data, unless no data block follows the header. With a decoding
+    /// or checksum error it holds what was read.
+    pub data: Option<Sector>,
+    /// [`DosError::HeaderChecksum`], [`DosError::DataBlockNotPresent`],
+    /// [`DosError::ByteDecoding`] or [`DosError::DataChecksum`], as the
+    /// drive would report them for a DOS sector.
+    pub error: Option<DosError>,
+    /// The bit offset of the header mark.
+    pub bit: usize,
+}
+
+/// A track read by [`decode_track`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// The index of the half track, 0 for track 1.
+    pub half_track: usize,
+    /// The length of the track in bits.
+    pub bits: usize,
+    pub extra: Option<Extra>,
+    /// The sectors in the order of the syncs.
+    pub sectors: Vec<SectorRead>,
+}
+
+impl Track {
+    /// Returns the track number, rounded down for half tracks.
+    pub fn track(&self) -> u8 {
+        (self.half_track / 2 + 1) as u8
+    }
+
+    /// Returns where the extra sector starts, as a fraction of a
+    /// revolution from the start of the track.
+    pub fn angle(&self) -> Option<f64> {
+        self.extra.map(|e| e.bit as f64 / self.bits as f64)
+    }
+}
+
+/// The key on track 36.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    /// The key bytes, as far as they could be read.
+    pub data: Vec<u8>,
+    /// [`DosError::DataBlockNotPresent`], [`DosError::ByteDecoding`] or
+    /// [`DosError::DataChecksum`].
+    pub error: Option<DosError>,
+}
+
+/// What keeps a disk from loading.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Track 36 is missing or holds no key.
+    NoKey,
+    /// The key cannot be read.
+    Key(DosError),
+    /// No track is written in the layout.
+    NoTracks,
+    /// A track holds sectors but no extra sector.
+    NoExtra(u8),
+    /// A sector cannot be read.
+    Sector {
+        track: u8,
+        sector: u8,
+        error: DosError,
+    },
+    /// A sector below the highest one of its track is missing.
+    MissingSector { track: u8, sector: u8 },
+    /// A track holds more than a revolution at the speed the image gives
+    /// it.
+    TooLong { track: u8, length: usize },
+    /// The extra sector of a track is not where the one of the track
+    /// before leads the loader to expect it.
+    Skew {
+        track: u8,
+        /// The distance from the extra sector of the track before, as a
+        /// fraction of a revolution.
+        skew: f64,
+        /// The distance most tracks show.
+        expected: f64,
+    },
+}
+
+/// The result of [`verify`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub key: Option<Key>,
+    /// The tracks holding an extra sector or RapidLok sectors.
+    pub tracks: Vec<Track>,
+    pub problems: Vec<Problem>,
+}
+
+impl Verdict {
+    /// Returns `true` if nothing keeps the disk from loading.
+    pub fn passes(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Reads the RapidLok sectors of the circular track `data`, the half track
+/// `half_track`.
+///
+/// Headers carrying another track are skipped, as are the blocks of DOS
+/// sectors.
+pub fn decode_track(data: &[u8], half_track: usize, layout: &Layout) -> Track {
+    let number = (half_track / 2 + 1) as u8;
+    let bits = data.len() * 8;
+    let blocks = behind_syncs(data);
+    let mut track = Track {
+        half_track,
+        bits,
+        extra: None,
+        sectors: Vec::new(),
+    };
+    for (i, &(end, available)) in blocks.iter().enumerate() {
+        let bytes = read_bits(data, end, (available / 8).min(SECTOR_SIZE));
+        let length = bytes.iter().take_while(|&&b| b == layout.extra).count();
+        if length >= layout.min_extra {
+            track.extra.get_or_insert(Extra { bit: end, length });
+            continue;
+        }
+        if bytes.first() != Some(&layout.header) {
+            continue;
+        }
+        let mut reader = Reader::new(data, (end + 8) % bits, available - 8, &layout.codes);
+        let Some([header_track, sector, checksum]) = reader.bytes() else {
+            continue;
+        };
+        if header_track != number {
+            continue;
+        }
+        let mut read = SectorRead {
+            sector,
+            data: None,
+            error: None,
+            bit: end,
+        };
+        if !reader.valid || checksum != header_track ^ sector {
+            read.error = Some(DosError::HeaderChecksum);
+            track.sectors.push(read);
+            continue;
+        }
+        let (start, available) = blocks[(i + 1) % blocks.len()];
+        if available < 8 || read_bits(data, start, 1)[0] != layout.data {
+            read.error = Some(DosError::DataBlockNotPresent);
+            track.sectors.push(read);
+            continue;
+        }
+        let mut reader = Reader::new(data, (start + 8) % bits, available - 8, &layout.codes);
+        match reader.bytes::<SECTOR_SIZE>() {
+            Some(contents) => {
+                let sum = reader.byte();
+                read.data = Some(contents);
+                read.error = if !reader.valid {
+                    Some(DosError::ByteDecoding)
+                } else if sum != Some(contents.iter().fold(0, |acc, b| acc ^ b)) {
+                    Some(DosError::DataChecksum)
+                } else {
+                    None
+                };
+            }
+            None => read.error = Some(DosError::DataBlockNotPresent),
+        }
+        track.sectors.push(read);
+    }
+    track
+}
+
+/// Reads the key from track 36 of `g64`, if a block there carries the data
+/// mark.
+pub fn read_key(g64: &G64, layout: &Layout) -> Option<Key> {
+    let data = g64.half_track(KEY_HALF_TRACK).filter(|d| !d.is_empty())?;
+    let bits = data.len() * 8;
+    let (end, available) = behind_syncs(data)
+        .into_iter()
+        .find(|&(end, available)| available >= 8 && read_bits(data, end, 1)[0] == layout.data)?;
+    let mut reader = Reader::new(data, (end + 8) % bits, available - 8, &layout.codes);
+    let count = match reader.byte()? {
+        0 => 256,
+        n => usize::from(n),
+    };
+    let mut key = Key {
+        data: Vec::with_capacity(count),
+        error: None,
+    };
+    while key.data.len() < count {
+        match reader.byte() {
+            Some(byte) => key.data.push(byte),
+            None => break,
+        }
+    }
+    let sum = if key.data.len() == count {
+        reader.byte()
+    } else {
+        None
+    };
+    key.error = match sum {
+        None => Some(DosError::DataBlockNotPresent),
+        Some(_) if !reader.valid => Some(DosError::ByteDecoding),
+        Some(sum) if sum != key.data.iter().fold(0, |acc, b| acc ^ b) => {
+            Some(DosError::DataChecksum)
+        }
+        Some(_) => None,
+    };
+    Some(key)
+}
+
+/// Checks whether the loader of the RapidLok disk `g64` would find its key
+/// and read every sector of its tracks.
+///
+/// Besides what [`read_key`] and [`decode_track`] report, the tracks must
+/// fit a revolution at the speed the image gives them, and the extra
+/// sector of every full track must follow the one of the track before at
+/// the distance most tracks show, within [`SKEW_TOLERANCE`].
+pub fn verify(g64: &G64, layout: &Layout) -> Verdict {
+    let mut problems = Vec::new();
+    let key = read_key(g64, layout);
+    match &key {
+        None => problems.push(Problem::NoKey),
+        Some(Key {
+            error: Some(error), ..
+        }) => problems.push(Problem::Key(*error)),
+        Some(_) => {}
+    }
+
+    let tracks: Vec<Track> = (0..g64.half_tracks())
+        .filter(|&index| index != KEY_HALF_TRACK)
+        .filter_map(|index| {
+            let data = g64.half_track(index).filter(|d| !d.is_empty())?;
+            let track = decode_track(data, index, layout);
+            (track.extra.is_some() || !track.sectors.is_empty()).then_some(track)
+        })
+        .collect();
+    if tracks.is_empty() {
+        problems.push(Problem::NoTracks);
+    }
+    for track in &tracks {
+        let number = track.track();
+        let length = track.bits / 8;
+        let capacity = track_capacity(g64.half_track_zone(track.half_track), DEFAULT_RPM);
+        if length > capacity + capacity / 25 {
+            problems.push(Problem::TooLong {
+                track: number,
+                length,
+            });
+        }
+        if track.extra.is_none() {
+            problems.push(Problem::NoExtra(number));
+        }
+        let highest = track.sectors.iter().map(|s| s.sector).max();
+        for sector in
+            (0..highest.unwrap_or(0)).filter(|&s| track.sectors.iter().all(|r| r.sector != s))
+        {
+            problems.push(Problem::MissingSector {
+                track: number,
+                sector,
+            });
+        }
+        for read in &track.sectors {
+            if let Some(error) = read.error {
+                problems.push(Problem::Sector {
+                    track: number,
+                    sector: read.sector,
+                    error,
+                });
+            }
+        }
+    }
+
+    let angles: Vec<(u8, f64)> = tracks
+        .iter()
+        .filter(|t| t.half_track % 2 == 0)
+        .filter_map(|t| Some((t.track(), t.angle()?)))
+        .collect();
+    let skews: Vec<(u8, f64)> = angles
+        .windows(2)
+        .map(|pair| (pair[1].0, wrap(pair[1].1 - pair[0].1)))
+        .collect();
+    let mut sorted: Vec<f64> = skews.iter().map(|&(_, skew)| skew).collect();
+    sorted.sort_by(f64::total_cmp);
+    if let Some(&expected) = sorted.get(sorted.len() / 2) {
+        for &(track, skew) in &skews {
+            let off = wrap(skew - expected);
+            if off.min(1.0 - off) > SKEW_TOLERANCE {
+                problems.push(Problem::Skew {
+                    track,
+                    skew,
+                    expected,
+                });
+            }
+        }
+    }
+    Verdict {
+        key,
+        tracks,
+        problems,
+    }
+}
+
+/// Returns `turn`, between -1 and 1 revolutions, as from 0 to 1.
+fn wrap(turn: f64) -> f64 {
+    if turn < 0.0 { turn + 1.0 } else { turn }
+}
+
+/// Masters track `track` of `length` bytes holding `sectors` in `layout`,
+/// the extra sector of `extra` bytes first.
+pub fn encode_track(
+    track: u8,
+    sectors: &[Sector],
+    layout: &Layout,
+    extra: usize,
+    length: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(length);
+    out.extend([0xFF; 5]);
+    out.extend(core::iter::repeat_n(layout.extra, extra));
+    out.extend([GAP_BYTE; 4]);
+    for (number, data) in sectors.iter().enumerate() {
+        let mut header = Writer::new(&layout.codes);
+        for byte in [track, number as u8, track ^ number as u8] {
+            header.byte(byte);
+        }
+        out.extend([0xFF; 5]);
+        out.push(layout.header);
+        out.extend(header.finish());
+        out.extend([GAP_BYTE; 4]);
+        out.extend([0xFF; 5]);
+        out.push(layout.data);
+        out.extend(coded(layout, data, None));
+        out.extend([GAP_BYTE; 4]);
+    }
+    out.resize(length, GAP_BYTE);
+    out
+}
+
+/// Masters a key track of `length` bytes holding `key`, of 1 to 256 bytes.
+pub fn encode_key(key: &[u8], layout: &Layout, length: usize) -> Vec<u8> {
+    let key = &key[..key.len().min(256)];
+    let mut out = Vec::with_capacity(length);
+    out.extend([0xFF; 5]);
+    out.push(layout.data);
+    out.extend(coded(layout, key, Some(key.len() as u8)));
+    out.resize(length, GAP_BYTE);
+    out
+}
+
+/// Returns `data` coded, followed by its checksum and preceded by `count`.
+fn coded(layout: &Layout, data: &[u8], count: Option<u8>) -> Vec<u8> {
+    let mut writer = Writer::new(&layout.codes);
+    for &byte in count.iter().chain(data) {
+        writer.byte(byte);
+    }
+    writer.byte(data.iter().fold(0, |acc, b| acc ^ b));
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::timing::speed_zone;
+
+    /// A disk with RapidLok tracks 1 to 17, each turned by a tenth of a
+    /// revolution against the track before.
+    fn disk() -> G64 {
+        let layout = Layout::default();
+        let mut g64 = G64::from_image(&D64::new(35));
+        for track in 1..=17u8 {
+            let sectors: Vec<Sector> = (0..12u8).map(|s| [track ^ s; SECTOR_SIZE]).collect();
+            let length = track_capacity(speed_zone(track), DEFAULT_RPM);
+            let mut data = encode_track(track, &sectors, &layout, 30, length);
+            data.rotate_right(length * usize::from(track % 10) / 10);
+            g64.set_track(track, data);
+        }
+        g64.set_half_track(KEY_HALF_TRACK, encode_key(b"RAPIDLOK", &layout, 6250), 0);
+        g64
+    }
+
+    #[test]
+    fn reads_a_mastered_disk() {
+        let g64 = disk();
+        let verdict = verify(&g64, &Layout::default());
+        assert_eq!(verdict.problems, Vec::new());
+        assert!(verdict.passes());
+        assert_eq!(verdict.key.unwrap().data, b"RAPIDLOK");
+        assert_eq!(verdict.tracks.len(), 17);
+        let three = &verdict.tracks[2];
+        assert_eq!((three.track(), three.sectors.len()), (3, 12));
+        assert_eq!(three.extra.unwrap().length, 30);
+        assert_eq!(three.sectors[5].data, Some([3 ^ 5; SECTOR_SIZE]));
+        assert!((three.angle().unwrap() - 0.3).abs() < 0.001);
+
+        let standard = G64::from_image(&D64::new(35));
+        assert_eq!(
+            verify(&standard, &Layout::default()).problems,
+            [Problem::NoKey, Problem::NoTracks]
+        );
+    }
+
+    #[test]
+    fn tells_what_keeps_a_disk_from_loading() {
+        let layout = Layout::default();
+        let mut g64 = disk();
+        // Track 5 started at the index hole, as a careless dump would.
+        let mut five = g64.track(5).unwrap().to_vec();
+        let half = five.len() / 2;
+        five.rotate_left(half);
+        g64.set_track(5, five);
+        // Sector 2 of track 8 damaged and sector 3 of track 9 gone.
+        let mut eight = g64.track(8).unwrap().to_vec();
+        let bit = decode_track(&eight, 14, &layout)
+            .sectors
+            .iter()
+            .find(|s| s.sector == 2)
+            .unwrap()
+            .bit;
+        let data = (bit / 8 + 30) % eight.len();
+        eight[data] ^= 0x04;
+        g64.set_track(8, eight);
+        let mut nine = g64.track(9).unwrap().to_vec();
+        let bit = decode_track(&nine, 16, &layout)
+            .sectors
+            .iter()
+            .find(|s| s.sector == 3)
+            .unwrap()
+            .bit;
+        nine[bit / 8] = 0x55;
+        g64.set_track(9, nine);
+        g64.clear_half_track(KEY_HALF_TRACK);
+
+        let problems = verify(&g64, &layout).problems;
+        assert_eq!(problems[0], Problem::NoKey);
+        assert!(problems.contains(&Problem::Sector {
+            track: 8,
+            sector: 2,
+            error: DosError::DataChecksum
+        }));
+        assert!(problems.contains(&Problem::MissingSector {
+            track: 9,
+            sector: 3
+        }));
+        let skewed: Vec<u8> = problems
+            .iter()
+            .filter_map(|p| match p {
+                Problem::Skew { track, .. } => Some(*track),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(skewed, [5, 6]);
+    }
+}
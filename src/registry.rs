@@ -0,0 +1,204 @@
+//! Recognizing and opening disk images of any format.
+//!
+//! An [`ImageFormat`] tells a format by its contents and opens it as a
+//! [`DiskImage`]. A [`Registry`] holds the formats to try, the
+//! [standard](Registry::standard) D64 and G64 and any number of formats
+//! other crates register, so a niche container is mounted, catalogued and
+//! copied like the formats of this crate without changing it:
+//!
+//! ```
+//! use cbm_dos::d64::D64;
+//! use cbm_dos::image::{DiskImage, ImageError};
+//! use cbm_dos::registry::{ImageFormat, Registry};
+//!
+//! // A D64 behind a header of 16 bytes, as some archive tools write it.
+//! fn open(bytes: &[u8]) -> Result<Box<dyn DiskImage>, ImageError> {
+//!     Ok(Box::new(D64::from_bytes(&bytes[16..])?))
+//! }
+//!
+//! let mut registry = Registry::standard();
+//! registry.register(ImageFormat {
+//!     name: "Archived D64",
+//!     extensions: &["a64"],
+//!     sniff: |bytes| bytes.starts_with(b"ARCHIVE!"),
+//!     open,
+//! });
+//! let mut bytes = b"ARCHIVE!".to_vec();
+//! bytes.resize(16, 0);
+//! bytes.extend(D64::new(35).to_bytes());
+//! assert_eq!(registry.detect(&bytes).unwrap().name, "Archived D64");
+//! assert_eq!(registry.open(&bytes).unwrap().tracks(), 35);
+//! ```
+
+use crate::d64::{self, D64};
+use crate::g64::{self, G64};
+use crate::image::{DiskImage, ImageError, SECTOR_SIZE};
+use alloc::boxed::Box;
+use alloc::{vec, vec::Vec};
+
+/// Opens the bytes of an image as a [`DiskImage`].
+pub type Opener = fn(&[u8]) -> Result<Box<dyn DiskImage>, ImageError>;
+
+/// How a format is recognized and opened.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageFormat {
+    /// The name of the format.
+    pub name: &'static str,
+    /// The usual extensions of its files, in lower case.
+    pub extensions: &'static [&'static str],
+    /// Returns `true` if the bytes look like an image of the format.
+    pub sniff: fn(&[u8]) -> bool,
+    /// Opens an image of the format.
+    pub open: Opener,
+}
+
+impl ImageFormat {
+    /// D64 files, recognized by their size with or without error bytes.
+    pub const D64: ImageFormat = ImageFormat {
+        name: "D64",
+        extensions: &["d64"],
+        sniff: |bytes| {
+            [d64::SECTORS_35, d64::SECTORS_40]
+                .iter()
+                .any(|&n| bytes.len() == n * SECTOR_SIZE || bytes.len() == n * (SECTOR_SIZE + 1))
+        },
+        open: |bytes| Ok(Box::new(D64::from_bytes(bytes)?)),
+    };
+
+    /// G64 files, recognized by their signature.
+    pub const G64: ImageFormat = ImageFormat {
+        name: "G64",
+        extensions: &["g64"],
+        sniff: |bytes| bytes.starts_with(g64::SIGNATURE),
+        open: |bytes| Ok(Box::new(G64::from_bytes(bytes)?)),
+    };
+}
+
+/// The formats to recognize images by.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    formats: Vec<ImageFormat>,
+}
+
+impl Registry {
+    /// Creates a registry without formats.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry of the formats this crate opens.
+    pub fn standard() -> Self {
+        Registry {
+            formats: vec![ImageFormat::D64, ImageFormat::G64],
+        }
+    }
+
+    /// Adds `format`, replacing a format of the same name.
+    pub fn register(&mut self, format: ImageFormat) {
+        self.formats.retain(|known| known.name != format.name);
+        self.formats.push(format);
+    }
+
+    /// Returns the registered formats.
+    pub fn formats(&self) -> &[ImageFormat] {
+        &self.formats
+    }
+
+    /// Returns the format of the image `bytes`, if any format recognizes
+    /// it. Formats registered later are asked first, so a format with a
+    /// signature is preferred to one told by its size.
+    pub fn detect(&self, bytes: &[u8]) -> Option<&ImageFormat> {
+        self.formats
+            .iter()
+            .rev()
+            .find(|format| (format.sniff)(bytes))
+    }
+
+    /// Returns the format registered for the file extension `extension`,
+    /// in any case, preferring formats registered later.
+    pub fn by_extension(&self, extension: &str) -> Option<&ImageFormat> {
+        self.formats.iter().rev().find(|format| {
+            format
+                .extensions
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(extension))
+        })
+    }
+
+    /// Opens the image `bytes` with the format [`detect`](Self::detect)
+    /// finds.
+    ///
+    /// # Errors
+    /// - [`ImageError::Unsupported`] if no format recognizes the bytes.
+    /// - The error of the format otherwise.
+    pub fn open(&self, bytes: &[u8]) -> Result<Box<dyn DiskImage>, ImageError> {
+        let format = self
+            .detect(bytes)
+            .ok_or(ImageError::Unsupported("image format"))?;
+        (format.open)(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DosError;
+
+    #[test]
+    fn opens_the_standard_formats() {
+        let registry = Registry::standard();
+        let mut d64 = D64::new(40);
+        d64.set_sector_error(18, 1, Some(DosError::DataChecksum))
+            .unwrap();
+        let bytes = d64.to_bytes();
+        assert_eq!(registry.detect(&bytes).unwrap().name, "D64");
+        let image = registry.open(&bytes).unwrap();
+        assert_eq!(image.tracks(), 40);
+        assert_eq!(image.sector_error(18, 1), Some(DosError::DataChecksum));
+
+        let bytes = G64::from_image(&D64::new(35)).to_bytes();
+        assert_eq!(registry.detect(&bytes).unwrap().name, "G64");
+        assert_eq!(registry.open(&bytes).unwrap().total_sectors(), 683);
+        assert_eq!(registry.by_extension("G64").unwrap().name, "G64");
+
+        assert!(registry.detect(b"neither").is_none());
+        assert_eq!(
+            registry.open(b"neither").err(),
+            Some(ImageError::Unsupported("image format"))
+        );
+        assert!(Registry::new().open(&D64::new(35).to_bytes()).is_err());
+    }
+
+    #[test]
+    fn prefers_registered_formats() {
+        // A D64 of 35 tracks, told by its size like a D64 but opened
+        // read-only.
+        let locked = ImageFormat {
+            name: "Locked D64",
+            extensions: &["d64", "l64"],
+            sniff: |bytes| bytes.len() == d64::SECTORS_35 * SECTOR_SIZE,
+            open: |bytes| {
+                Ok(Box::new(crate::image::ReadOnly::new(D64::from_bytes(
+                    bytes,
+                )?)))
+            },
+        };
+        let mut registry = Registry::standard();
+        registry.register(locked);
+        let bytes = D64::new(35).to_bytes();
+        assert_eq!(registry.detect(&bytes).unwrap().name, "Locked D64");
+        assert!(registry.open(&bytes).unwrap().is_read_only());
+        assert_eq!(registry.by_extension("d64").unwrap().name, "Locked D64");
+        assert_eq!(
+            registry.detect(&D64::new(40).to_bytes()).unwrap().name,
+            "D64"
+        );
+
+        registry.register(ImageFormat {
+            sniff: |_| false,
+            ..locked
+        });
+        assert_eq!(registry.formats().len(), 3);
+        assert_eq!(registry.detect(&bytes).unwrap().name, "D64");
+    }
+}
@@ -0,0 +1,304 @@
+//! Relative files.
+//!
+//! A relative (REL) file stores fixed-length records in an ordinary block
+//! chain. Its side sectors list the data blocks so the drive can reach any
+//! record without following the chain:
+//!
+//! ```plaintext
+//! $00-$01  link to the next side sector
+//! $02      number of this side sector (0-5)
+//! $03      record length
+//! $04-$0F  track/sector of all six side sectors
+//! $10-$FF  track/sector of up to 120 data blocks
+//! ```
+//!
+//! Records are numbered from 1 and run on across block boundaries. Unused
+//! records hold `$FF` followed by zeros.
+
+use crate::error::DosError;
+use crate::fs::{self, BLOCK_PAYLOAD, Bam, DirEntry, DirSlot, FileType};
+use crate::image::{BlockDevice, SECTOR_SIZE};
+use alloc::vec::Vec;
+
+/// Maximum number of side sectors of a file.
+pub const MAX_SIDE_SECTORS: usize = 6;
+/// Number of data blocks one side sector lists.
+pub const BLOCKS_PER_SIDE_SECTOR: usize = 120;
+/// Maximum number of data blocks of a file.
+pub const MAX_DATA_BLOCKS: usize = MAX_SIDE_SECTORS * BLOCKS_PER_SIDE_SECTOR;
+/// First byte of a record that was never written.
+pub const EMPTY_RECORD: u8 = 0xFF;
+
+/// Offset of the list of data blocks in a side sector.
+pub const SIDE_HEADER: usize = 0x10;
+
+/// The records of a relative file held in memory.
+///
+/// # Example
+/// ```
+/// use cbm_dos::rel::RelativeFile;
+///
+/// let mut file = RelativeFile::new(100).unwrap();
+/// assert_eq!(file.records(), 2);
+/// file.expand(3).unwrap();
+/// assert_eq!(file.records(), 5);
+/// assert_eq!(file.record(5).unwrap()[..2], [0xFF, 0x00]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelativeFile {
+    record_length: u8,
+    data: Vec<u8>,
+}
+
+impl RelativeFile {
+    /// Creates a file of `record_length`-byte records holding as many empty
+    /// records as fit into its first block, as the drive does when a
+    /// relative file is opened for the first time.
+    ///
+    /// # Errors
+    /// Returns [`DosError::Syntax`] unless `record_length` is 1-254.
+    pub fn new(record_length: u8) -> Result<Self, DosError> {
+        if record_length == 0 || record_length as usize > BLOCK_PAYLOAD {
+            return Err(DosError::Syntax);
+        }
+        let mut file = RelativeFile {
+            record_length,
+            data: Vec::new(),
+        };
+        file.expand(1)?;
+        Ok(file)
+    }
+
+    /// Reads the records of the relative file `entry`.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileTypeMismatch`] if `entry` is not a relative
+    /// file.
+    pub fn read<I: BlockDevice + ?Sized>(image: &I, entry: &DirEntry) -> Result<Self, DosError> {
+        if entry.file_type != FileType::Rel || entry.record_length == 0 {
+            return Err(DosError::FileTypeMismatch);
+        }
+        let mut data = fs::read_file(image, entry)?;
+        let length = entry.record_length as usize;
+        data.truncate(data.len() / length * length);
+        Ok(RelativeFile {
+            record_length: entry.record_length,
+            data,
+        })
+    }
+
+    /// Returns the length of each record.
+    pub fn record_length(&self) -> u8 {
+        self.record_length
+    }
+
+    /// Returns the number of records in the file.
+    pub fn records(&self) -> u16 {
+        (self.data.len() / self.record_length as usize).min(u16::MAX as usize) as u16
+    }
+
+    /// Returns the number of data blocks the records occupy.
+    pub fn blocks(&self) -> usize {
+        self.data.len().div_ceil(BLOCK_PAYLOAD).max(1)
+    }
+
+    /// Returns record `number`, counting from 1.
+    pub fn record(&self, number: u16) -> Option<&[u8]> {
+        let range = self.range(number)?;
+        self.data.get(range)
+    }
+
+    /// Returns record `number` for modification.
+    pub fn record_mut(&mut self, number: u16) -> Option<&mut [u8]> {
+        let range = self.range(number)?;
+        self.data.get_mut(range)
+    }
+
+    /// Returns the records as one block of bytes.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Rebuilds a file from the bytes returned by [`RelativeFile::as_bytes`].
+    pub(crate) fn from_bytes(record_length: u8, data: Vec<u8>) -> Option<Self> {
+        (record_length != 0 && record_length as usize <= BLOCK_PAYLOAD).then_some(RelativeFile {
+            record_length,
+            data,
+        })
+    }
+
+    fn range(&self, number: u16) -> Option<core::ops::Range<usize>> {
+        let start = (number as usize).checked_sub(1)? * self.record_length as usize;
+        Some(start..start + self.record_length as usize)
+    }
+
+    /// Grows the file so that it holds at least `records` records.
+    ///
+    /// Like the drive, the last block needed is filled up with empty records
+    /// as well.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileTooLarge`] if the records do not fit into the
+    /// 720 data blocks the side sectors can address.
+    pub fn expand(&mut self, records: u16) -> Result<(), DosError> {
+        if records <= self.records() {
+            return Ok(());
+        }
+        let length = self.record_length as usize;
+        let blocks = (records as usize * length).div_ceil(BLOCK_PAYLOAD);
+        if blocks > MAX_DATA_BLOCKS {
+            return Err(DosError::FileTooLarge);
+        }
+        let total = (blocks * BLOCK_PAYLOAD / length).min(u16::MAX as usize);
+        self.data.truncate(self.records() as usize * length);
+        while self.data.len() < total * length {
+            self.data.push(EMPTY_RECORD);
+            self.data.resize(self.data.len() + length - 1, 0);
+        }
+        Ok(())
+    }
+
+    /// Stores the file on the disk as `name`, writing its data blocks, side
+    /// sectors and directory entry.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileExists`] if a file named `name` exists and
+    /// [`DosError::DiskFull`] if the disk has no room for the file.
+    pub fn write<I: BlockDevice + ?Sized>(
+        &self,
+        image: &mut I,
+        name: &[u8],
+    ) -> Result<DirEntry, DosError> {
+        fs::store_file(image, name, false, |image, bam| self.store(image, bam, name))
+    }
+
+    /// Stores the file on the disk in place of the file `name`, as the
+    /// drive does when a relative file it changed is closed, or as a new
+    /// file if there is none.
+    ///
+    /// The old file is released only once the new one is written, so if
+    /// it cannot be written the old file stays as it was.
+    ///
+    /// # Errors
+    /// Returns [`DosError::FileExists`] if the old file is locked and
+    /// [`DosError::DiskFull`] if the disk has no room for the file next to
+    /// the old one.
+    pub fn replace<I: BlockDevice + ?Sized>(
+        &self,
+        image: &mut I,
+        name: &[u8],
+    ) -> Result<DirEntry, DosError> {
+        fs::store_file(image, name, true, |image, bam| self.store(image, bam, name))
+    }
+
+    /// Writes the data blocks and side sectors, allocating them in `bam`,
+    /// and returns the entry without its slot.
+    fn store<I: BlockDevice + ?Sized>(
+        &self,
+        image: &mut I,
+        bam: &mut Bam,
+        name: &[u8],
+    ) -> Result<DirEntry, DosError> {
+        let blocks = self.blocks();
+        let sides = blocks.div_ceil(BLOCKS_PER_SIDE_SECTOR);
+        if blocks + sides > bam.blocks_free() as usize {
+            return Err(DosError::DiskFull);
+        }
+        let ((track, sector), _) = fs::write_chain(image, bam, &self.data)?;
+        let data_blocks = fs::chain(image, track, sector)?;
+
+        let mut last = *data_blocks.last().ok_or(DosError::DiskFull)?;
+        let mut side_sectors = Vec::with_capacity(sides);
+        for _ in 0..sides {
+            last = bam.next_free(last.0, last.1).ok_or(DosError::DiskFull)?;
+            bam.allocate(last.0, last.1);
+            side_sectors.push(last);
+        }
+        for (i, listed) in data_blocks.chunks(BLOCKS_PER_SIDE_SECTOR).enumerate() {
+            let mut block = [0u8; SECTOR_SIZE];
+            match side_sectors.get(i + 1) {
+                Some(&(t, s)) => block[..2].copy_from_slice(&[t, s]),
+                None => block[1] = (SIDE_HEADER + 2 * listed.len() - 1) as u8,
+            }
+            block[2] = i as u8;
+            block[3] = self.record_length;
+            for (j, &(t, s)) in side_sectors.iter().enumerate() {
+                block[4 + 2 * j..6 + 2 * j].copy_from_slice(&[t, s]);
+            }
+            for (j, &(t, s)) in listed.iter().enumerate() {
+                let at = SIDE_HEADER + 2 * j;
+                block[at..at + 2].copy_from_slice(&[t, s]);
+            }
+            let (t, s) = side_sectors[i];
+            image.write_sector(t, s, &block)?;
+        }
+
+        Ok(DirEntry {
+            file_type: FileType::Rel,
+            closed: true,
+            locked: false,
+            name: fs::pad_name(name),
+            track,
+            sector,
+            side_track: side_sectors[0].0,
+            side_sector: side_sectors[0].1,
+            record_length: self.record_length,
+            blocks: (blocks + sides) as u16,
+            geos: [0; 6],
+            slot: DirSlot::default(),
+        })
+    }
+}
+
+/// Returns the part of a record the drive sends when it is read: everything
+/// up to the last non-zero byte, but at least the first byte.
+pub fn record_contents(record: &[u8]) -> &[u8] {
+    let end = record.iter().rposition(|&b| b != 0).map_or(1, |i| i + 1);
+    &record[..end.min(record.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    #[test]
+    fn expands_in_whole_blocks() {
+        let mut file = RelativeFile::new(254).unwrap();
+        assert_eq!((file.records(), file.blocks()), (1, 1));
+        file.expand(720).unwrap();
+        assert_eq!(file.blocks(), 720);
+        assert_eq!(file.expand(721), Err(DosError::FileTooLarge));
+        assert_eq!(RelativeFile::new(0), Err(DosError::Syntax));
+        assert_eq!(record_contents(&[b'A', 0, b'B', 0, 0]), b"A\0B");
+        assert_eq!(record_contents(&[0, 0]), [0]);
+    }
+
+    #[test]
+    fn writes_side_sectors() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"REL", Some(*b"01")).unwrap();
+        let mut file = RelativeFile::new(64).unwrap();
+        file.expand(500).unwrap();
+        file.record_mut(500).unwrap()[..3].copy_from_slice(b"END");
+        let entry = file.write(&mut image, b"DATA").unwrap();
+        assert_eq!(file.blocks(), 126);
+        assert_eq!(entry.blocks, 128);
+
+        let first = image
+            .read_sector(entry.side_track, entry.side_sector)
+            .unwrap();
+        let second = image.read_sector(first[0], first[1]).unwrap();
+        assert_eq!((first[2], first[3], second[2]), (0, 64, 1));
+        assert_eq!(first[16..18], [entry.track, entry.sector]);
+        assert_eq!((second[0], second[1]), (0, 0x10 + 2 * 6 - 1));
+        assert_eq!(first[4..8], second[4..8]);
+
+        let found = fs::find_file(&image, b"DATA").unwrap().unwrap();
+        let read = RelativeFile::read(&image, &found).unwrap();
+        assert_eq!(read, file);
+        assert_eq!(&read.record(500).unwrap()[..3], b"END");
+        fs::validate(&mut image).unwrap();
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 664 - 128);
+    }
+}
@@ -0,0 +1,352 @@
+//! Disks and drives on other machines, over TCP.
+//!
+//! A dumping station runs an agent that [`serve`]s a [`Station`], an image
+//! or a real drive behind OpenCBM, on a socket. [`RemoteDisk`] is the other
+//! end: a [`DiskImage`] and [`RawTracks`] whose every access goes over the
+//! connection, so the filesystem layer, the virtual drive and the track
+//! tools work on the remote disk as on a local one.
+//!
+//! The protocol is a plain exchange of requests and replies, numbers in
+//! little endian, half tracks counted as nibtools does:
+//!
+//! ```plaintext
+//! 'G'                                     -> tracks, sectors of each track, raw tracks (0 or 1)
+//! 'R' track sector                        -> 256 bytes
+//! 'W' track sector 256 bytes              -> nothing
+//! 'E' track sector                        -> DOS error code of the sector, 0 for none
+//! 'r' half-track                          -> GCR bytes
+//! 'w' half-track length (u32) GCR bytes   -> nothing
+//! ```
+//!
+//! Each reply starts with a status byte: 0 followed by the length of the
+//! payload (u32) and the payload, a DOS error code, or [`NONE`] if the half
+//! track holds no data.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::g64::G64;
+use crate::image::{BlockDevice, DiskImage, SECTOR_SIZE, Sector};
+use crate::parallel::RawTracks;
+
+/// Request for the layout of the disk.
+pub const GEOMETRY: u8 = b'G';
+/// Request for reading a sector.
+pub const READ_SECTOR: u8 = b'R';
+/// Request for writing a sector.
+pub const WRITE_SECTOR: u8 = b'W';
+/// Request for the read error of a sector.
+pub const SECTOR_ERROR: u8 = b'E';
+/// Request for reading a raw half track.
+pub const READ_TRACK: u8 = b'r';
+/// Request for writing a raw half track.
+pub const WRITE_TRACK: u8 = b'w';
+
+/// Status of a successful reply.
+pub const OK: u8 = 0;
+/// Status of a reply for a half track without data.
+pub const NONE: u8 = 0xFF;
+
+/// What an agent serves: a disk, and its raw tracks if it has them.
+pub trait Station: DiskImage {
+    /// Returns the raw tracks of the disk, or `None` if only its sectors
+    /// can be reached.
+    fn raw_tracks(&mut self) -> Option<&mut dyn RawTracks> {
+        None
+    }
+}
+
+impl Station for D64 {}
+
+impl Station for G64 {
+    fn raw_tracks(&mut self) -> Option<&mut dyn RawTracks> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "opencbm")]
+impl Station for crate::opencbm::RealDisk {}
+
+fn read_u8(stream: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_payload(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut payload = vec![0; u32::from_le_bytes(length) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn write_payload(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(payload.len()).map_err(io::Error::other)?;
+    stream.write_all(&length.to_le_bytes())?;
+    stream.write_all(payload)
+}
+
+/// Answers the requests on `stream` from `station` until the other end
+/// closes the connection.
+///
+/// Requests the agent does not know are answered with
+/// [`DosError::InvalidCommand`], as are raw track writes to a station
+/// without raw tracks.
+///
+/// # Errors
+/// Returns the error of the connection.
+pub fn serve<S: Read + Write, T: Station + ?Sized>(
+    mut stream: S,
+    station: &mut T,
+) -> io::Result<()> {
+    loop {
+        let request = match read_u8(&mut stream) {
+            Ok(request) => request,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let reply: Result<Option<Vec<u8>>, DosError> = match request {
+            GEOMETRY => {
+                let tracks = station.tracks();
+                let mut layout = vec![tracks];
+                layout.extend((1..=tracks).map(|track| station.sectors_per_track(track)));
+                layout.push(u8::from(station.raw_tracks().is_some()));
+                Ok(Some(layout))
+            }
+            READ_SECTOR | WRITE_SECTOR | SECTOR_ERROR => {
+                let mut place = [0; 2];
+                stream.read_exact(&mut place)?;
+                let [track, sector] = place;
+                match request {
+                    READ_SECTOR => station
+                        .read_sector(track, sector)
+                        .map(|data| Some(data.to_vec())),
+                    WRITE_SECTOR => {
+                        let mut data = [0; SECTOR_SIZE];
+                        stream.read_exact(&mut data)?;
+                        station
+                            .write_sector(track, sector, &data)
+                            .map(|()| Some(Vec::new()))
+                    }
+                    _ => {
+                        let error = station.sector_error(track, sector);
+                        Ok(Some(vec![error.map_or(0, |error| error.code())]))
+                    }
+                }
+            }
+            READ_TRACK => {
+                let half_track = read_u8(&mut stream)?;
+                Ok(station
+                    .raw_tracks()
+                    .and_then(|tracks| tracks.read_raw(half_track)))
+            }
+            WRITE_TRACK => {
+                let half_track = read_u8(&mut stream)?;
+                let data = read_payload(&mut stream)?;
+                match station.raw_tracks() {
+                    Some(tracks) => tracks
+                        .write_raw(half_track, &data)
+                        .map(|()| Some(Vec::new())),
+                    None => Err(DosError::InvalidCommand),
+                }
+            }
+            _ => Err(DosError::InvalidCommand),
+        };
+        match reply {
+            Ok(Some(payload)) => {
+                stream.write_all(&[OK])?;
+                write_payload(&mut stream, &payload)?;
+            }
+            Ok(None) => stream.write_all(&[NONE])?,
+            Err(error) => stream.write_all(&[error.code()])?,
+        }
+        stream.flush()?;
+    }
+}
+
+/// A disk served by an agent on another machine.
+///
+/// The layout is fetched once on connecting. Failures of the connection
+/// are reported as [`DosError::DriveNotReady`], as a drive that stops
+/// answering would be, and raw half tracks that cannot be fetched read as
+/// empty.
+#[derive(Debug)]
+pub struct RemoteDisk<S> {
+    stream: RefCell<S>,
+    sectors: Vec<u8>,
+    raw: bool,
+}
+
+impl RemoteDisk<TcpStream> {
+    /// Connects to the agent at `address`.
+    ///
+    /// # Errors
+    /// Returns the error of the connection.
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        RemoteDisk::new(stream)
+    }
+}
+
+impl<S: Read + Write> RemoteDisk<S> {
+    /// Uses the agent at the other end of `stream`.
+    ///
+    /// # Errors
+    /// Returns the error of the connection, or [`io::ErrorKind::InvalidData`]
+    /// if the agent does not answer as one.
+    pub fn new(stream: S) -> io::Result<Self> {
+        let mut disk = RemoteDisk {
+            stream: RefCell::new(stream),
+            sectors: Vec::new(),
+            raw: false,
+        };
+        let layout = disk
+            .request(&[GEOMETRY])?
+            .ok()
+            .flatten()
+            .filter(|layout| layout.len() == usize::from(layout[0]) + 2)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no geometry"))?;
+        disk.sectors = layout[1..layout.len() - 1].to_vec();
+        disk.raw = layout[layout.len() - 1] != 0;
+        Ok(disk)
+    }
+
+    /// Returns whether the agent serves raw tracks.
+    pub fn has_raw_tracks(&self) -> bool {
+        self.raw
+    }
+
+    /// Returns the stream to the agent.
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// Sends `request` and returns the reply: the payload, `None` for
+    /// [`NONE`], or the DOS error.
+    fn request(&self, request: &[u8]) -> io::Result<Result<Option<Vec<u8>>, DosError>> {
+        let mut stream = self.stream.borrow_mut();
+        stream.write_all(request)?;
+        stream.flush()?;
+        Ok(match read_u8(&mut *stream)? {
+            OK => Ok(Some(read_payload(&mut *stream)?)),
+            NONE => Ok(None),
+            code => Err(DosError::from_code(code).unwrap_or(DosError::DriveNotReady)),
+        })
+    }
+
+    /// Sends `request` for a disk operation, mapping failures of the
+    /// connection to [`DosError::DriveNotReady`].
+    fn operation(&self, request: &[u8]) -> Result<Vec<u8>, DosError> {
+        match self.request(request) {
+            Ok(Ok(Some(payload))) => Ok(payload),
+            Ok(Ok(None)) | Err(_) => Err(DosError::DriveNotReady),
+            Ok(Err(error)) => Err(error),
+        }
+    }
+}
+
+impl<S: Read + Write> BlockDevice for RemoteDisk<S> {
+    fn tracks(&self) -> u8 {
+        self.sectors.len() as u8
+    }
+
+    fn sectors_per_track(&self, track: u8) -> u8 {
+        usize::from(track)
+            .checked_sub(1)
+            .and_then(|index| self.sectors.get(index))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Result<Sector, DosError> {
+        let data = self.operation(&[READ_SECTOR, track, sector])?;
+        data.try_into().map_err(|_| DosError::DriveNotReady)
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, data: &Sector) -> Result<(), DosError> {
+        let mut request = vec![WRITE_SECTOR, track, sector];
+        request.extend_from_slice(data);
+        self.operation(&request).map(|_| ())
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> Option<DosError> {
+        let code = self.operation(&[SECTOR_ERROR, track, sector]).ok()?;
+        DosError::from_code(*code.first()?)
+    }
+}
+
+impl<S: Read + Write> DiskImage for RemoteDisk<S> {}
+
+impl<S: Read + Write> RawTracks for RemoteDisk<S> {
+    fn read_raw(&self, half_track: u8) -> Option<Vec<u8>> {
+        self.request(&[READ_TRACK, half_track]).ok()?.ok()?
+    }
+
+    fn write_raw(&mut self, half_track: u8, data: &[u8]) -> Result<(), DosError> {
+        let length = u32::try_from(data.len()).map_err(|_| DosError::LongDataBlock)?;
+        let mut request = vec![WRITE_TRACK, half_track];
+        request.extend_from_slice(&length.to_le_bytes());
+        request.extend_from_slice(data);
+        self.operation(&request).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves `station` on a local socket and runs `client` against it.
+    fn with_agent<T: Station + Send>(station: &mut T, client: impl FnOnce(RemoteDisk<TcpStream>)) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let agent = scope.spawn(move || serve(listener.accept().unwrap().0, station));
+            client(RemoteDisk::connect(address).unwrap());
+            agent.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn works_on_a_remote_image() {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"REMOTE", Some(*b"RM")).unwrap();
+        image
+            .set_sector_error(1, 4, Some(DosError::DataChecksum))
+            .unwrap();
+        with_agent(&mut image, |mut disk| {
+            assert_eq!((disk.tracks(), disk.total_sectors()), (35, 683));
+            assert!(!disk.has_raw_tracks());
+            fs::write_file(&mut disk, b"HELLO", fs::FileType::Prg, b"WORLD").unwrap();
+            let entry = fs::find_file(&disk, b"HELLO").unwrap().unwrap();
+            assert_eq!(fs::read_file(&disk, &entry).unwrap(), b"WORLD");
+            assert_eq!(disk.sector_error(1, 4), Some(DosError::DataChecksum));
+            assert_eq!(disk.sector_error(1, 5), None);
+            assert_eq!(disk.read_sector(36, 0), Err(DosError::IllegalTrackSector));
+            assert_eq!(disk.read_raw(2), None);
+            assert_eq!(disk.write_raw(2, &[0x55]), Err(DosError::InvalidCommand));
+        });
+        let entry = fs::find_file(&image, b"HELLO").unwrap().unwrap();
+        assert_eq!(fs::read_file(&image, &entry).unwrap(), b"WORLD");
+    }
+
+    #[test]
+    fn moves_raw_tracks() {
+        let mut g64 = G64::from_image(&D64::new(35));
+        let track = g64.half_track(0).unwrap().to_vec();
+        with_agent(&mut g64, |mut disk| {
+            assert!(disk.has_raw_tracks());
+            assert_eq!(disk.read_raw(2), Some(track));
+            assert_eq!(disk.read_raw(3), None);
+            disk.write_raw(3, &[0xFF; 7000]).unwrap();
+        });
+        assert_eq!(g64.half_track(1), Some(&[0xFF; 7000][..]));
+    }
+}
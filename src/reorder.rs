@@ -0,0 +1,241 @@
+//! Sorting and reordering the directory.
+//!
+//! The order of the directory is the order `LOAD"$",8` lists the files in
+//! and the order many menus and `LOAD"*",8` pick them by, so collectors
+//! and menu authors arrange it with care. [`reorder`] moves the entries
+//! into an [`Order`], rewriting only the directory sectors whose contents
+//! change: the files, the BAM and the links of the directory chain stay as
+//! they are. [`Gaps`] decides where the empty slots go, scratched entries
+//! among them, whose names a later undelete may still need, and slots with
+//! a type DOS does not list.
+
+use crate::error::DosError;
+use crate::fs::{self, ENTRIES_PER_SECTOR, ENTRY_SIZE, FileType};
+use crate::image::BlockDevice;
+use alloc::vec::Vec;
+
+/// The order [`reorder`] puts the entries in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Order {
+    /// By name, as the bytes of the padded names compare.
+    Name,
+    /// By type, in the order of the type codes, DEL first and REL last,
+    /// and by name within a type.
+    Type,
+    /// The entries at these indices into the directory as
+    /// [`fs::read_directory`] gives it first, in this order, and the
+    /// others after them in the order they had.
+    Explicit(Vec<usize>),
+}
+
+/// Where [`reorder`] puts the empty slots of the directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Gaps {
+    /// The empty slots stay where they are and the entries move between
+    /// the slots that were in use.
+    #[default]
+    Keep,
+    /// The entries fill the first slots and the empty ones follow, in
+    /// the order they had.
+    Compact,
+}
+
+/// Reorders the entries of the directory of `image`, returning the number
+/// of directory sectors written.
+///
+/// The sort is stable: entries that compare equal keep their order.
+///
+/// # Errors
+/// - [`DosError::FileNotFound`] if an explicit index is beyond the last
+///   entry or given twice.
+/// - The errors of reading and writing the directory.
+pub fn reorder<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    order: &Order,
+    gaps: Gaps,
+) -> Result<usize, DosError> {
+    let sectors = fs::directory_sectors(image)?;
+    let slots: Vec<[u8; ENTRY_SIZE - 2]> = sectors
+        .iter()
+        .flat_map(|(_, _, data)| data.chunks(ENTRY_SIZE))
+        .map(|entry| entry[2..].try_into().unwrap())
+        .collect();
+    let (used, empty): (Vec<usize>, Vec<usize>) =
+        (0..slots.len()).partition(|&i| type_code(&slots[i]).is_some());
+
+    let mut entries = used.clone();
+    match order {
+        Order::Name => entries.sort_by(|&a, &b| name(&slots[a]).cmp(name(&slots[b]))),
+        Order::Type => entries.sort_by(|&a, &b| {
+            type_code(&slots[a])
+                .cmp(&type_code(&slots[b]))
+                .then_with(|| name(&slots[a]).cmp(name(&slots[b])))
+        }),
+        Order::Explicit(indices) => {
+            let mut first = Vec::with_capacity(indices.len());
+            for &index in indices {
+                let entry = *used.get(index).ok_or(DosError::FileNotFound)?;
+                if first.contains(&entry) {
+                    return Err(DosError::FileNotFound);
+                }
+                first.push(entry);
+            }
+            entries.retain(|entry| !first.contains(entry));
+            first.append(&mut entries);
+            entries = first;
+        }
+    }
+    let arranged: Vec<usize> = match gaps {
+        Gaps::Keep => {
+            let mut moved = entries.into_iter();
+            (0..slots.len())
+                .map(|i| match type_code(&slots[i]) {
+                    Some(_) => moved.next().unwrap_or(i),
+                    None => i,
+                })
+                .collect()
+        }
+        Gaps::Compact => entries.into_iter().chain(empty).collect(),
+    };
+
+    let mut written = 0;
+    for (n, (track, sector, data)) in sectors.iter().enumerate() {
+        let mut new = *data;
+        for index in 0..ENTRIES_PER_SECTOR {
+            let from = arranged[n * ENTRIES_PER_SECTOR + index];
+            new[index * ENTRY_SIZE + 2..(index + 1) * ENTRY_SIZE].copy_from_slice(&slots[from]);
+        }
+        if new != *data {
+            image.write_sector(*track, *sector, &new)?;
+            written += 1;
+        }
+    }
+    event!("reordered the directory by {order:?}, {written} sectors written");
+    Ok(written)
+}
+
+/// Returns the padded name of an entry without its first two bytes.
+fn name(entry: &[u8]) -> &[u8] {
+    &entry[3..3 + fs::NAME_LENGTH]
+}
+
+/// Returns the type code of an entry without its first two bytes, or
+/// `None` for an empty slot or one DOS does not list.
+fn type_code(entry: &[u8]) -> Option<u8> {
+    (entry[0] != 0)
+        .then(|| FileType::from_byte(entry[0]))
+        .flatten()
+        .map(FileType::to_byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn names<I: BlockDevice>(image: &I) -> Vec<Vec<u8>> {
+        fs::read_directory(image)
+            .unwrap()
+            .entries
+            .iter()
+            .map(|e| e.name().to_vec())
+            .collect()
+    }
+
+    /// A disk with ten files, two sectors of directory, and the third
+    /// slot scratched.
+    fn disk() -> D64 {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"SORTED", Some(*b"01")).unwrap();
+        for (name, file_type) in [
+            (&b"ZEBRA"[..], FileType::Prg),
+            (b"APPLE", FileType::Seq),
+            (b"GONE", FileType::Prg),
+            (b"MANGO", FileType::Usr),
+            (b"BANANA", FileType::Prg),
+            (b"KIWI", FileType::Seq),
+            (b"FIG", FileType::Prg),
+            (b"LIME", FileType::Prg),
+            (b"DATE", FileType::Rel),
+            (b"PEAR", FileType::Del),
+        ] {
+            fs::write_file(&mut image, name, file_type, &[0; 10]).unwrap();
+        }
+        fs::scratch(&mut image, b"GONE").unwrap();
+        image
+    }
+
+    #[test]
+    fn sorts_by_name_and_type() {
+        let mut image = disk();
+        let files = image.read_sector(17, 0).unwrap();
+        let bam = image.read_sector(18, 0).unwrap();
+        assert_eq!(reorder(&mut image, &Order::Name, Gaps::Keep).unwrap(), 2);
+        assert_eq!(
+            names(&image),
+            [
+                &b"APPLE"[..],
+                b"BANANA",
+                b"DATE",
+                b"FIG",
+                b"KIWI",
+                b"LIME",
+                b"MANGO",
+                b"PEAR",
+                b"ZEBRA"
+            ]
+        );
+        // The scratched slot is still the third, and nothing else changed.
+        let directory = image.read_sector(18, 1).unwrap();
+        assert_eq!(directory[2 * ENTRY_SIZE + 2], 0);
+        assert_eq!(&directory[2 * ENTRY_SIZE + 5..2 * ENTRY_SIZE + 9], b"GONE");
+        assert_eq!(image.read_sector(17, 0).unwrap(), files);
+        assert_eq!(image.read_sector(18, 0).unwrap(), bam);
+        assert_eq!(reorder(&mut image, &Order::Name, Gaps::Keep).unwrap(), 0);
+
+        reorder(&mut image, &Order::Type, Gaps::Compact).unwrap();
+        assert_eq!(
+            names(&image),
+            [
+                &b"PEAR"[..],
+                b"APPLE",
+                b"KIWI",
+                b"BANANA",
+                b"FIG",
+                b"LIME",
+                b"ZEBRA",
+                b"MANGO",
+                b"DATE"
+            ]
+        );
+        let entries = fs::read_directory(&image).unwrap().entries;
+        assert!(
+            entries
+                .iter()
+                .all(|e| e.slot.sector == 1 || e.slot.index == 0)
+        );
+        assert_eq!(fs::read_file(&image, &entries[8]).unwrap(), [0; 10]);
+    }
+
+    #[test]
+    fn moves_entries_to_an_explicit_order() {
+        let mut image = disk();
+        reorder(&mut image, &Order::Explicit(vec![8, 0]), Gaps::Compact).unwrap();
+        let names = names(&image);
+        assert_eq!(
+            names[..3],
+            [b"PEAR".to_vec(), b"ZEBRA".to_vec(), b"APPLE".to_vec()]
+        );
+        assert_eq!(names.len(), 9);
+        let directory = image.read_sector(18, 1).unwrap();
+        assert_eq!(directory[..2], [18, 4]);
+        assert_eq!(
+            reorder(&mut image, &Order::Explicit(vec![9]), Gaps::Keep),
+            Err(DosError::FileNotFound)
+        );
+        assert_eq!(
+            reorder(&mut image, &Order::Explicit(vec![1, 1]), Gaps::Keep),
+            Err(DosError::FileNotFound)
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Recovering files whose chains break off.
+//!
+//! A file whose write was cut short, or whose blocks were overwritten,
+//! links from a good block to one that cannot be part of it: a sector
+//! beyond the disk, a block of the chain again, a sector that cannot be
+//! read or one that was never written. Reading such a file fails, and
+//! everything before the break is lost with it. [`find_break`] locates the
+//! break and [`close_truncated`] closes the file at the last good block,
+//! the way DOS would have closed it there, optionally padding it back to
+//! the size the directory gave it.
+//!
+//! The blocks behind the break stay allocated in the BAM; checking the
+//! disk with [`crate::integrity`] afterwards lists them, with the repairs
+//! that free them.
+
+use crate::error::DosError;
+use crate::fs::{self, BLOCK_PAYLOAD, Bam, DirEntry};
+use crate::image::{BlockDevice, SECTOR_SIZE};
+use alloc::vec::Vec;
+
+/// Why a chain breaks off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Break {
+    /// The link leads to a sector the disk does not have.
+    OffDisk,
+    /// The link leads back to a block of the chain.
+    Loop,
+    /// The block linked to cannot be read.
+    Unreadable(DosError),
+    /// The block linked to holds nothing but zero bytes, as a block
+    /// never written does, or is a last block without a valid length.
+    Blank,
+}
+
+/// Where a chain breaks off, as [`find_break`] finds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    /// The good blocks, in the order of the chain.
+    pub kept: Vec<(u8, u8)>,
+    /// The sector the last good block links to, or the start of the file
+    /// if no block is good.
+    pub link: (u8, u8),
+    pub reason: Break,
+}
+
+impl Truncation {
+    /// Returns the number of data bytes in the good blocks.
+    pub fn length(&self) -> usize {
+        self.kept.len() * BLOCK_PAYLOAD
+    }
+}
+
+/// Follows the chain of `entry` and returns where it breaks off, or `None`
+/// if it ends in a valid last block.
+pub fn find_break<I: BlockDevice + ?Sized>(image: &I, entry: &DirEntry) -> Option<Truncation> {
+    let mut kept = Vec::new();
+    let mut link = (entry.track, entry.sector);
+    let reason = loop {
+        let (track, sector) = link;
+        if !image.contains(track, sector) {
+            break Break::OffDisk;
+        }
+        if kept.contains(&link) {
+            break Break::Loop;
+        }
+        let data = match image.read_sector(track, sector) {
+            Ok(data) => data,
+            Err(error) => break Break::Unreadable(error),
+        };
+        if data.iter().all(|&b| b == 0) || (data[0] == 0 && data[1] < 1) {
+            break Break::Blank;
+        }
+        kept.push(link);
+        if data[0] == 0 {
+            return None;
+        }
+        link = (data[0], data[1]);
+    };
+    event!(
+        "{} breaks off after {} blocks at {link:?}: {reason:?}",
+        entry.name().escape_ascii(),
+        kept.len()
+    );
+    Some(Truncation { kept, link, reason })
+}
+
+/// Closes the file of `entry` at the last good block of its chain and
+/// returns where the chain broke off, or `None` if it did not.
+///
+/// The last good block becomes the last block of the file, holding all its
+/// data bytes. With `pad`, blocks filled with that byte are appended until
+/// the file has the blocks its entry gives, so data at fixed offsets stays
+/// where the program expects it. The entry is closed and counts the blocks
+/// the file has now; `entry` is updated to match.
+///
+/// # Errors
+/// - [`DosError::IllegalTrackSector`] if not even the first block is good
+///   and there is nothing to pad.
+/// - [`DosError::DiskFull`] if there is no room for the padding.
+/// - The errors of reading and writing the blocks, the BAM and the entry.
+pub fn close_truncated<I: BlockDevice + ?Sized>(
+    image: &mut I,
+    entry: &mut DirEntry,
+    pad: Option<u8>,
+) -> Result<Option<Truncation>, DosError> {
+    let Some(truncation) = find_break(image, entry) else {
+        return Ok(None);
+    };
+    let mut bam = Bam::read(image)?;
+    let mut blocks = truncation.kept.clone();
+    let mut padding = Vec::new();
+    if let Some(byte) = pad {
+        let mut last = blocks.last().copied();
+        for _ in blocks.len()..usize::from(entry.blocks) {
+            let next = match last {
+                Some((track, sector)) => bam.next_free(track, sector),
+                None => bam.first_free(),
+            }
+            .ok_or(DosError::DiskFull)?;
+            bam.allocate(next.0, next.1);
+            padding.push(next);
+            last = Some(next);
+        }
+        blocks.extend(&padding);
+        let mut block = [byte; SECTOR_SIZE];
+        for (i, &(track, sector)) in padding.iter().enumerate() {
+            (block[0], block[1]) = padding.get(i + 1).copied().unwrap_or((0, 0xFF));
+            image.write_sector(track, sector, &block)?;
+        }
+    }
+    let Some(&first) = blocks.first() else {
+        return Err(DosError::IllegalTrackSector);
+    };
+    if let Some(&(track, sector)) = truncation.kept.last() {
+        let mut data = image.read_sector(track, sector)?;
+        (data[0], data[1]) = padding.first().copied().unwrap_or((0, 0xFF));
+        image.write_sector(track, sector, &data)?;
+    }
+    (entry.track, entry.sector) = first;
+    entry.blocks = blocks.len() as u16;
+    entry.closed = true;
+    fs::write_entry(image, entry)?;
+    bam.write(image)?;
+    Ok(Some(truncation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::FileType;
+    use crate::integrity::{self, Issue};
+
+    fn damaged(link: (u8, u8)) -> (D64, DirEntry, Vec<u8>) {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"SALVAGE", Some(*b"01")).unwrap();
+        let data: Vec<u8> = (0..5 * BLOCK_PAYLOAD).map(|i| (i % 251) as u8).collect();
+        let entry = fs::write_file(&mut image, b"BROKEN", FileType::Prg, &data).unwrap();
+        let third = fs::chain(&image, entry.track, entry.sector).unwrap()[2];
+        let mut block = image.read_sector(third.0, third.1).unwrap();
+        (block[0], block[1]) = link;
+        image.write_sector(third.0, third.1, &block).unwrap();
+        (image, entry, data)
+    }
+
+    #[test]
+    fn closes_a_file_at_the_last_good_block() {
+        let (mut image, mut entry, data) = damaged((40, 3));
+        assert!(fs::read_file(&image, &entry).is_err());
+        let found = find_break(&image, &entry).unwrap();
+        assert_eq!((found.kept.len(), found.link), (3, (40, 3)));
+        assert_eq!(found.reason, Break::OffDisk);
+        assert_eq!(found.length(), 3 * BLOCK_PAYLOAD);
+
+        let closed = close_truncated(&mut image, &mut entry, None).unwrap();
+        assert_eq!(closed, Some(found));
+        assert_eq!(entry.blocks, 3);
+        assert_eq!(
+            fs::read_file(&image, &entry).unwrap(),
+            data[..3 * BLOCK_PAYLOAD]
+        );
+        assert_eq!(fs::read_directory(&image).unwrap().entries, [entry.clone()]);
+        assert_eq!(find_break(&image, &entry), None);
+        assert_eq!(close_truncated(&mut image, &mut entry, None), Ok(None));
+        // The two blocks behind the break are left for a BAM repair.
+        let report = integrity::check(&image);
+        assert_eq!(report.issues.len(), 2);
+        assert!(matches!(report.issues[0], Issue::UsedButFree { .. }));
+    }
+
+    #[test]
+    fn pads_a_file_to_its_size() {
+        let (mut image, mut entry, data) = damaged((1, 0));
+        assert_eq!(find_break(&image, &entry).unwrap().reason, Break::Blank);
+        close_truncated(&mut image, &mut entry, Some(0xEA)).unwrap();
+        assert_eq!(entry.blocks, 5);
+        let read = fs::read_file(&image, &entry).unwrap();
+        assert_eq!(read.len(), data.len());
+        assert_eq!(read[..3 * BLOCK_PAYLOAD], data[..3 * BLOCK_PAYLOAD]);
+        assert!(read[3 * BLOCK_PAYLOAD..].iter().all(|&b| b == 0xEA));
+
+        let (mut image, mut entry, _) = damaged((0, 0));
+        let first = fs::chain(&image, entry.track, entry.sector).unwrap()[0];
+        let mut block = image.read_sector(first.0, first.1).unwrap();
+        (block[0], block[1]) = first;
+        image.write_sector(first.0, first.1, &block).unwrap();
+        assert_eq!(find_break(&image, &entry).unwrap().reason, Break::Loop);
+        let mut start = entry.clone();
+        (start.track, start.sector) = (36, 0);
+        assert_eq!(
+            close_truncated(&mut image, &mut start, None),
+            Err(DosError::IllegalTrackSector)
+        );
+        close_truncated(&mut image, &mut entry, None).unwrap();
+        assert_eq!(fs::read_file(&image, &entry).unwrap().len(), BLOCK_PAYLOAD);
+    }
+}
@@ -0,0 +1,444 @@
+//! Looking at and patching single sectors.
+//!
+//! A disk doctor shows a sector as bytes and as what DOS makes of them.
+//! [`SectorView`] holds a sector read from an image with the read error
+//! it has, renders it as a hex dump with the PETSCII characters beside
+//! it, and tells what the sector is: the BAM, a block of the directory
+//! chain or a block of a file, with the link to the block after it.
+//!
+//! Its patching methods change the bytes DOS relies on together: the
+//! link of a last block counts the bytes used, so changing the payload of
+//! one moves the count along; directory entries are stored in their slot
+//! whole; and the BAM is set from a [`Bam`], which keeps the free count of
+//! every track with its bitmap. Writing the view back to an image that
+//! stores GCR, such as a G64, encodes a data block with a checksum that
+//! matches the new bytes.
+
+use crate::error::DosError;
+use crate::fs::{
+    BAM_SECTOR, BLOCK_PAYLOAD, Bam, DIR_TRACK, DirEntry, DirSlot, ENTRIES_PER_SECTOR, ENTRY_SIZE,
+    directory_sectors,
+};
+use crate::image::{BlockDevice, SECTOR_SIZE, Sector};
+use crate::petscii::{self, Charset};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+/// The first two bytes of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Link {
+    /// The chain goes on at `track`/`sector`.
+    Next { track: u8, sector: u8 },
+    /// The block is the last of its chain and holds `used` bytes of
+    /// payload.
+    Last { used: u8 },
+}
+
+impl Link {
+    /// Reads the link stored in the first two bytes of a block.
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        match bytes {
+            [0, last] => Link::Last {
+                used: last.saturating_sub(1),
+            },
+            [track, sector] => Link::Next { track, sector },
+        }
+    }
+
+    /// Returns the two bytes that store the link.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Link::Next { track, sector } => [track, sector],
+            Link::Last { used } => [0, used.saturating_add(1)],
+        }
+    }
+}
+
+/// What a sector is to DOS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// The BAM and disk header at 18/0.
+    Bam,
+    /// A block of the directory chain.
+    Directory,
+    /// Any other block, taken for a block of a file.
+    Data,
+}
+
+/// A sector as DOS reads it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Contents<'a> {
+    Bam(Box<Bam>),
+    Directory {
+        next: Link,
+        /// The entries of the slots in use.
+        entries: Vec<DirEntry>,
+    },
+    File {
+        next: Link,
+        /// The payload the link counts: all of it for a block with a
+        /// next one.
+        payload: &'a [u8],
+    },
+}
+
+/// An error patching a [`SectorView`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    /// The bytes would reach past the end of the sector or of its
+    /// payload.
+    OutOfRange,
+    /// A directory sector has no slot of the given index.
+    NoSlot(u8),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::OutOfRange => write!(f, "patch does not fit the sector"),
+            PatchError::NoSlot(index) => write!(f, "no directory slot {index}"),
+        }
+    }
+}
+
+impl core::error::Error for PatchError {}
+
+/// A sector read from an image, to look at and patch.
+///
+/// Patches change the view only; [`SectorView::write`] stores it.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::fs::{self, FileType};
+/// use cbm_dos::sector::{Contents, SectorView};
+///
+/// let mut image = D64::new(35);
+/// fs::format(&mut image, b"DOCTOR", Some(*b"01")).unwrap();
+/// let entry = fs::write_file(&mut image, b"NOTE", FileType::Seq, b"HELLO").unwrap();
+///
+/// let mut view = SectorView::read(&image, entry.track, entry.sector).unwrap();
+/// view.patch_payload(5, b" WORLD").unwrap();
+/// assert!(matches!(view.contents(), Contents::File { payload: b"HELLO WORLD", .. }));
+/// view.write(&mut image).unwrap();
+/// assert_eq!(fs::read_file(&image, &entry).unwrap(), b"HELLO WORLD");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorView {
+    track: u8,
+    sector: u8,
+    data: Sector,
+    error: Option<DosError>,
+    role: Role,
+}
+
+impl SectorView {
+    /// Reads the sector at `track`/`sector` of `image` and tells its role
+    /// from where it is: a sector the directory chain passes through is
+    /// part of the directory.
+    ///
+    /// # Errors
+    /// Fails if the image has no such sector or cannot read it at all.
+    pub fn read<I: BlockDevice + ?Sized>(
+        image: &I,
+        track: u8,
+        sector: u8,
+    ) -> Result<Self, DosError> {
+        let data = image.read_sector(track, sector)?;
+        let role = if (track, sector) == (DIR_TRACK, BAM_SECTOR) {
+            Role::Bam
+        } else if directory_sectors(image)
+            .unwrap_or_default()
+            .iter()
+            .any(|&(t, s, _)| (t, s) == (track, sector))
+        {
+            Role::Directory
+        } else {
+            Role::Data
+        };
+        Ok(SectorView {
+            track,
+            sector,
+            data,
+            error: image.sector_error(track, sector),
+            role,
+        })
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    pub fn sector(&self) -> u8 {
+        self.sector
+    }
+
+    /// Returns the bytes of the sector, as patched.
+    pub fn data(&self) -> &Sector {
+        &self.data
+    }
+
+    /// Returns the error the image reported for the sector when it was
+    /// read.
+    pub fn error(&self) -> Option<DosError> {
+        self.error
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the link in the first two bytes.
+    pub fn link(&self) -> Link {
+        Link::from_bytes([self.data[0], self.data[1]])
+    }
+
+    /// Returns the sector as DOS reads it in its role.
+    pub fn contents(&self) -> Contents<'_> {
+        match self.role {
+            Role::Bam => Contents::Bam(Box::new(Bam::from_sector(self.data))),
+            Role::Directory => {
+                let entries = (0..ENTRIES_PER_SECTOR)
+                    .filter_map(|index| {
+                        let slot = DirSlot {
+                            track: self.track,
+                            sector: self.sector,
+                            index: index as u8,
+                        };
+                        let start = index * ENTRY_SIZE;
+                        DirEntry::parse(&self.data[start..start + ENTRY_SIZE], slot)
+                    })
+                    .collect();
+                Contents::Directory {
+                    next: self.link(),
+                    entries,
+                }
+            }
+            Role::Data => {
+                let next = self.link();
+                let used = match next {
+                    Link::Next { .. } => BLOCK_PAYLOAD,
+                    Link::Last { used } => usize::from(used).min(BLOCK_PAYLOAD),
+                };
+                Contents::File {
+                    next,
+                    payload: &self.data[2..2 + used],
+                }
+            }
+        }
+    }
+
+    /// Renders the sector as 16 lines of 16 bytes: the offset, the bytes
+    /// in hex and the characters they stand for in `charset`, with a dot
+    /// for each control code.
+    pub fn hexdump(&self, charset: Charset) -> Vec<String> {
+        self.data
+            .chunks(16)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let mut line = String::with_capacity(70);
+                let _ = write!(line, "{:02X}:", row * 16);
+                for byte in bytes {
+                    let _ = write!(line, " {byte:02X}");
+                }
+                line.push_str("  ");
+                line.extend(
+                    bytes
+                        .iter()
+                        .map(|&byte| match petscii::to_screen_code(byte) {
+                            Some(_) => petscii::to_char(byte, charset),
+                            None => '.',
+                        }),
+                );
+                line
+            })
+            .collect()
+    }
+
+    /// Overwrites the bytes at `offset`, links included, as they are.
+    ///
+    /// # Errors
+    /// Returns [`PatchError::OutOfRange`] if they reach past the sector.
+    pub fn patch(&mut self, offset: usize, bytes: &[u8]) -> Result<(), PatchError> {
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|&end| end <= SECTOR_SIZE);
+        let end = end.ok_or(PatchError::OutOfRange)?;
+        self.data[offset..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Sets the link to the next block, or makes the block the last with
+    /// `used` bytes of payload.
+    pub fn set_link(&mut self, link: Link) {
+        let [track, sector] = link.to_bytes();
+        self.data[0] = track;
+        self.data[1] = sector;
+    }
+
+    /// Overwrites the payload at `offset`, counting from the byte after
+    /// the link. A last block grows to hold the patch.
+    ///
+    /// # Errors
+    /// Returns [`PatchError::OutOfRange`] if the bytes reach past the 254
+    /// bytes of payload.
+    pub fn patch_payload(&mut self, offset: usize, bytes: &[u8]) -> Result<(), PatchError> {
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|&end| end <= BLOCK_PAYLOAD);
+        let end = end.ok_or(PatchError::OutOfRange)?;
+        self.data[2 + offset..2 + end].copy_from_slice(bytes);
+        if let Link::Last { used } = self.link()
+            && usize::from(used) < end
+        {
+            self.set_link(Link::Last { used: end as u8 });
+        }
+        Ok(())
+    }
+
+    /// Replaces the payload with `payload`, clearing the bytes after it. A
+    /// last block then holds just `payload`.
+    ///
+    /// # Errors
+    /// Returns [`PatchError::OutOfRange`] if `payload` is longer than 254
+    /// bytes.
+    pub fn set_payload(&mut self, payload: &[u8]) -> Result<(), PatchError> {
+        if payload.len() > BLOCK_PAYLOAD {
+            return Err(PatchError::OutOfRange);
+        }
+        self.data[2..2 + payload.len()].copy_from_slice(payload);
+        self.data[2 + payload.len()..].fill(0);
+        if let Link::Last { .. } = self.link() {
+            self.set_link(Link::Last {
+                used: payload.len() as u8,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stores `entry` in slot `index` of a directory sector. The slot
+    /// the entry names is ignored, and the link bytes of the first slot
+    /// are left alone.
+    ///
+    /// # Errors
+    /// Returns [`PatchError::NoSlot`] if `index` is 8 or more.
+    pub fn set_entry(&mut self, index: u8, entry: &DirEntry) -> Result<(), PatchError> {
+        if usize::from(index) >= ENTRIES_PER_SECTOR {
+            return Err(PatchError::NoSlot(index));
+        }
+        let start = usize::from(index) * ENTRY_SIZE;
+        entry.store(&mut self.data[start..start + ENTRY_SIZE]);
+        Ok(())
+    }
+
+    /// Replaces the sector with `bam`.
+    pub fn set_bam(&mut self, bam: &Bam) {
+        self.data = *bam.as_bytes();
+    }
+
+    /// Writes the sector to `image`, at the place it was read from.
+    ///
+    /// # Errors
+    /// Returns the error of the image.
+    pub fn write<I: BlockDevice + ?Sized>(&self, image: &mut I) -> Result<(), DosError> {
+        image.write_sector(self.track, self.sector, &self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::fs::{self, FileType};
+    use crate::g64::G64;
+
+    fn formatted() -> D64 {
+        let mut image = D64::new(35);
+        fs::format(&mut image, b"DOCTOR", Some(*b"01")).unwrap();
+        image
+    }
+
+    #[test]
+    fn interprets_sectors_by_role() {
+        let mut image = formatted();
+        let entry = fs::write_file(&mut image, b"LONG", FileType::Prg, &[7; 300]).unwrap();
+
+        let bam = SectorView::read(&image, 18, 0).unwrap();
+        let Contents::Bam(map) = bam.contents() else {
+            panic!("not the BAM");
+        };
+        assert_eq!(map.blocks_free(), 662);
+        let dump = bam.hexdump(Charset::Unshifted);
+        assert_eq!(dump.len(), 16);
+        assert!(dump[9].starts_with("90: 44 4F 43 54 4F 52 A0"));
+        assert!(
+            dump[9].ends_with("DOCTOR\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}\u{a0}")
+        );
+
+        let directory = SectorView::read(&image, 18, 1).unwrap();
+        let Contents::Directory { next, entries } = directory.contents() else {
+            panic!("not a directory sector");
+        };
+        assert_eq!(next, Link::Last { used: 254 });
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0], entry);
+
+        let first = SectorView::read(&image, entry.track, entry.sector).unwrap();
+        let Contents::File { next, payload } = first.contents() else {
+            panic!("not a file block");
+        };
+        assert_eq!(payload.len(), 254);
+        let Link::Next { track, sector } = next else {
+            panic!("no second block");
+        };
+        let last = SectorView::read(&image, track, sector).unwrap();
+        assert!(matches!(
+            last.contents(),
+            Contents::File {
+                next: Link::Last { used: 46 },
+                ..
+            }
+        ));
+        assert_eq!(last.role(), Role::Data);
+    }
+
+    #[test]
+    fn patches_keep_links_and_checksums() {
+        let mut image = formatted();
+        let entry = fs::write_file(&mut image, b"TEXT", FileType::Seq, b"ABC").unwrap();
+        image
+            .set_sector_error(entry.track, entry.sector, Some(DosError::DataChecksum))
+            .unwrap();
+        let mut g64 = G64::from_image(&image);
+        let mut view = SectorView::read(&g64, entry.track, entry.sector).unwrap();
+        assert_eq!(view.error(), Some(DosError::DataChecksum));
+        assert_eq!(view.patch_payload(253, b"XY"), Err(PatchError::OutOfRange));
+        view.patch_payload(1, b"XYZ").unwrap();
+        assert_eq!(view.link(), Link::Last { used: 4 });
+        view.write(&mut g64).unwrap();
+        assert_eq!(g64.sector_error(entry.track, entry.sector), None);
+        assert_eq!(fs::read_file(&g64, &entry).unwrap(), b"AXYZ");
+
+        view.set_payload(b"Q").unwrap();
+        assert_eq!(view.link(), Link::Last { used: 1 });
+        assert_eq!(view.data()[3], 0);
+
+        let mut directory = SectorView::read(&image, 18, 1).unwrap();
+        let mut renamed = entry.clone();
+        renamed.name = fs::pad_name(b"RENAMED");
+        directory.set_entry(0, &renamed).unwrap();
+        assert_eq!(directory.set_entry(8, &renamed), Err(PatchError::NoSlot(8)));
+        directory.write(&mut image).unwrap();
+        assert!(fs::find_file(&image, b"RENAMED").unwrap().is_some());
+
+        let mut bam = SectorView::read(&image, 18, 0).unwrap();
+        let mut map = Bam::read(&image).unwrap();
+        map.allocate(30, 0);
+        bam.set_bam(&map);
+        bam.write(&mut image).unwrap();
+        assert_eq!(Bam::read(&image).unwrap().blocks_free(), 662);
+    }
+}
@@ -0,0 +1,139 @@
+//! Text in SEQ files.
+//!
+//! A SEQ file holding text is PETSCII with a carriage return at the end of
+//! each line. Word processors keep their documents their own way, most in
+//! the screen codes their editor shows, with a character of their choice
+//! marking the end of a paragraph. A [`TextFormat`] describes either, and
+//! [`to_text`] and [`from_text`] convert between it and host text with line
+//! feeds.
+
+use crate::petscii::{self, Charset, RETURN};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// How a file stores text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextFormat {
+    /// Whether the bytes are screen codes rather than PETSCII.
+    pub screen_codes: bool,
+    /// The byte that ends a line.
+    pub line_end: u8,
+    /// The character set the text is written in.
+    pub charset: Charset,
+}
+
+impl TextFormat {
+    /// PETSCII in the shifted set, as files written with `PRINT#` are.
+    pub const PETSCII: TextFormat = TextFormat {
+        screen_codes: false,
+        line_end: RETURN,
+        charset: Charset::Shifted,
+    };
+
+    /// SpeedScript documents: screen codes, with the `←` SpeedScript shows
+    /// for a return. SpeedScript saves them as PRG files, whose load
+    /// address goes first.
+    pub const SPEEDSCRIPT: TextFormat = TextFormat {
+        screen_codes: true,
+        line_end: 0x1F,
+        charset: Charset::Shifted,
+    };
+
+    /// Easy Script documents: screen codes in the unshifted set, lines
+    /// ended as in SpeedScript.
+    pub const EASY_SCRIPT: TextFormat = TextFormat {
+        screen_codes: true,
+        line_end: 0x1F,
+        charset: Charset::Unshifted,
+    };
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        TextFormat::PETSCII
+    }
+}
+
+/// Converts the contents of a file to host text, line ends to line feeds.
+///
+/// Control codes become the Unicode control characters of the same value,
+/// and reverse screen codes the private-use code points; see [`petscii`].
+pub fn to_text(bytes: &[u8], format: &TextFormat) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            _ if byte == format.line_end => '\n',
+            _ if format.screen_codes => petscii::screen_to_char(byte, format.charset),
+            _ => petscii::to_char(byte, format.charset),
+        })
+        .collect()
+}
+
+/// Converts host text to the contents of a file, or returns `None` if the
+/// character set of `format` lacks a character of it.
+///
+/// Line feeds, with or without a carriage return before them, become line
+/// ends. In the unshifted set, lower case letters stand for its letters.
+pub fn from_text(text: &str, format: &TextFormat) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = match (c, format.charset) {
+            ('\r', _) if chars.peek() == Some(&'\n') => continue,
+            ('\n', _) => {
+                bytes.push(format.line_end);
+                continue;
+            }
+            ('a'..='z', Charset::Unshifted) => c.to_ascii_uppercase(),
+            _ => c,
+        };
+        let byte = if format.screen_codes {
+            petscii::char_to_screen(c, format.charset)?
+        } else {
+            petscii::from_char(c, format.charset)?
+        };
+        bytes.push(byte);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_petscii_files() {
+        let file = b"DEAR \xCDR. \xD3MITH,\r\rTHANKS \x12A LOT\x92.\r";
+        let text = to_text(file, &TextFormat::PETSCII);
+        assert_eq!(text, "dear Mr. Smith,\n\nthanks \u{12}a lot\u{92}.\n");
+        assert_eq!(from_text(&text, &TextFormat::PETSCII).unwrap(), file);
+        assert_eq!(
+            from_text(
+                "dear Mr. Smith,\r\n\r\nthanks \u{12}a lot\u{92}.\r\n",
+                &TextFormat::PETSCII
+            )
+            .unwrap(),
+            file
+        );
+        assert_eq!(from_text("\u{1F600}", &TextFormat::PETSCII), None);
+    }
+
+    #[test]
+    fn converts_word_processor_documents() {
+        // "Hi," in SpeedScript: screen codes, a paragraph end, a reverse "x".
+        let document = [0x48, 0x09, 0x2C, 0x1F, 0x98];
+        let text = to_text(&document, &TextFormat::SPEEDSCRIPT);
+        assert_eq!(text, "Hi,\n\u{E198}");
+        assert_eq!(
+            from_text(&text, &TextFormat::SPEEDSCRIPT).unwrap(),
+            document
+        );
+
+        let text = to_text(&[0x08, 0x09, 0x1F], &TextFormat::EASY_SCRIPT);
+        assert_eq!(text, "HI\n");
+        assert_eq!(
+            from_text("hi\n", &TextFormat::EASY_SCRIPT).unwrap(),
+            [0x08, 0x09, 0x1F]
+        );
+    }
+}
@@ -0,0 +1,1213 @@
+//! Reading 7z archives.
+//!
+//! Newer collections are as often packed with 7-Zip as zipped, usually
+//! solid, all images of a set in one stream. [`Archive::open`] reads the
+//! header of an archive, compressed or not, and [`Archive::data`] unpacks
+//! a member, checking its CRC-32. The members of a solid stream are
+//! unpacked together, and the last stream unpacked is kept, so reading
+//! them one after the other unpacks each stream once.
+//!
+//! Streams that are stored, LZMA or LZMA2 compressed are supported, which
+//! is what 7-Zip writes for anything but executables. Filter chains such
+//! as BCJ, other methods and encryption are not.
+
+use crate::hash::crc32;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+
+/// The signature 7z archives start with.
+pub const SIGNATURE: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+const SIGNATURE_HEADER_SIZE: usize = 32;
+
+// The property ids of the header.
+const END: u8 = 0x00;
+const HEADER: u8 = 0x01;
+const ARCHIVE_PROPERTIES: u8 = 0x02;
+const ADDITIONAL_STREAMS: u8 = 0x03;
+const MAIN_STREAMS: u8 = 0x04;
+const FILES: u8 = 0x05;
+const PACK_INFO: u8 = 0x06;
+const UNPACK_INFO: u8 = 0x07;
+const SUBSTREAMS_INFO: u8 = 0x08;
+const SIZE: u8 = 0x09;
+const CRC: u8 = 0x0A;
+const FOLDER: u8 = 0x0B;
+const UNPACK_SIZE: u8 = 0x0C;
+const UNPACK_STREAMS: u8 = 0x0D;
+const EMPTY_STREAM: u8 = 0x0E;
+const EMPTY_FILE: u8 = 0x0F;
+const NAME: u8 = 0x11;
+const ENCODED_HEADER: u8 = 0x17;
+
+/// An error reading an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SevenZipError {
+    /// The input does not start with the signature.
+    NotAnArchive,
+    /// A structure extends beyond the end of the input.
+    Truncated,
+    /// The archive uses a feature that is not supported.
+    Unsupported(&'static str),
+    /// The header or the compressed data is not valid.
+    Corrupt,
+    /// The header or a member does not match its CRC-32.
+    Checksum,
+}
+
+impl fmt::Display for SevenZipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SevenZipError::NotAnArchive => write!(f, "not a 7z archive"),
+            SevenZipError::Truncated => write!(f, "7z archive is truncated"),
+            SevenZipError::Unsupported(feature) => write!(f, "unsupported 7z feature: {feature}"),
+            SevenZipError::Corrupt => write!(f, "corrupt 7z data"),
+            SevenZipError::Checksum => write!(f, "7z member fails its checksum"),
+        }
+    }
+}
+
+impl core::error::Error for SevenZipError {}
+
+type Result<T> = core::result::Result<T, SevenZipError>;
+
+/// A member of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The path within the archive, with `/` between directories.
+    pub name: String,
+    /// The size of the unpacked data.
+    pub size: usize,
+    /// The CRC-32 of the unpacked data, if the archive holds one.
+    pub crc32: Option<u32>,
+    directory: bool,
+    /// The folder the data is in and its offset in the unpacked folder,
+    /// for members that are not empty.
+    stream: Option<(usize, usize)>,
+}
+
+impl Entry {
+    /// Whether the member is a directory rather than a file.
+    pub fn is_dir(&self) -> bool {
+        self.directory
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Coder {
+    id: Vec<u8>,
+    properties: Vec<u8>,
+}
+
+/// A stream of the archive as it is unpacked, 7-Zip's folder.
+#[derive(Debug, Clone, Default)]
+struct Folder {
+    coders: Vec<Coder>,
+    packed_streams: usize,
+    /// The packed data, as a range of the archive.
+    packed: (usize, usize),
+    size: usize,
+    crc32: Option<u32>,
+}
+
+/// The size and CRC-32 of a member stream.
+type Substream = (usize, Option<u32>);
+
+/// The streams a header describes and how they split into members.
+#[derive(Default)]
+struct Streams {
+    folders: Vec<Folder>,
+    /// The member streams, folder by folder.
+    substreams: Vec<Vec<Substream>>,
+}
+
+/// An opened 7z archive.
+#[derive(Debug)]
+pub struct Archive<'a> {
+    bytes: &'a [u8],
+    folders: Vec<Folder>,
+    entries: Vec<Entry>,
+    /// The folder unpacked last.
+    cache: RefCell<Option<(usize, Vec<u8>)>>,
+}
+
+impl<'a> Archive<'a> {
+    /// Reads the header of the archive `bytes`.
+    ///
+    /// # Errors
+    /// - [`SevenZipError::NotAnArchive`] without the [`SIGNATURE`].
+    /// - [`SevenZipError::Truncated`], [`SevenZipError::Corrupt`] or
+    ///   [`SevenZipError::Checksum`] for damaged archives.
+    /// - [`SevenZipError::Unsupported`] for headers compressed in a way
+    ///   that is not supported and external or additional streams.
+    pub fn open(bytes: &'a [u8]) -> Result<Self> {
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(SevenZipError::NotAnArchive);
+        }
+        let start = bytes
+            .get(..SIGNATURE_HEADER_SIZE)
+            .ok_or(SevenZipError::Truncated)?;
+        if crc32(&start[12..]) != u32_at(start, 8) {
+            return Err(SevenZipError::Checksum);
+        }
+        let offset = usize::try_from(u64_at(start, 12)).map_err(|_| SevenZipError::Truncated)?;
+        let size = usize::try_from(u64_at(start, 20)).map_err(|_| SevenZipError::Truncated)?;
+        let at = SIGNATURE_HEADER_SIZE
+            .checked_add(offset)
+            .ok_or(SevenZipError::Truncated)?;
+        let header = bytes
+            .get(at..at.checked_add(size).ok_or(SevenZipError::Truncated)?)
+            .ok_or(SevenZipError::Truncated)?;
+        if crc32(header) != u32_at(start, 28) {
+            return Err(SevenZipError::Checksum);
+        }
+        let mut header = header.to_vec();
+        loop {
+            let mut reader = Reader {
+                bytes: &header,
+                at: 1,
+            };
+            match header.first() {
+                Some(&HEADER) => {
+                    let (folders, entries) = read_header(&mut reader)?;
+                    return Ok(Archive {
+                        bytes,
+                        folders,
+                        entries,
+                        cache: RefCell::new(None),
+                    });
+                }
+                Some(&ENCODED_HEADER) => {
+                    let streams = read_streams(&mut reader)?;
+                    let folder = streams.folders.first().ok_or(SevenZipError::Corrupt)?;
+                    header = unpack(bytes, folder)?;
+                }
+                Some(_) => return Err(SevenZipError::Corrupt),
+                None => return Err(SevenZipError::Truncated),
+            }
+        }
+    }
+
+    /// The members, in the order of the archive.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Unpacks `entry`, a member of this archive.
+    ///
+    /// # Errors
+    /// Returns [`SevenZipError::Unsupported`] for methods other than
+    /// stored, LZMA and LZMA2, [`SevenZipError::Corrupt`] for invalid
+    /// compressed data and [`SevenZipError::Checksum`] if the data does
+    /// not match the header.
+    pub fn data(&self, entry: &Entry) -> Result<Vec<u8>> {
+        let Some((folder, offset)) = entry.stream else {
+            return Ok(Vec::new());
+        };
+        let mut cache = self.cache.borrow_mut();
+        if cache.as_ref().is_none_or(|(index, _)| *index != folder) {
+            let info = self.folders.get(folder).ok_or(SevenZipError::Corrupt)?;
+            *cache = Some((folder, unpack(self.bytes, info)?));
+        }
+        let unpacked = &cache.as_ref().unwrap_or_else(|| unreachable!()).1;
+        let data = unpacked
+            .get(offset..offset + entry.size)
+            .ok_or(SevenZipError::Corrupt)?;
+        if entry.crc32.is_some_and(|crc| crc32(data) != crc) {
+            return Err(SevenZipError::Checksum);
+        }
+        Ok(data.to_vec())
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | u64::from(u32_at(bytes, offset + 4)) << 32
+}
+
+/// Reads the fields of a header.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self.bytes.get(self.at).ok_or(SevenZipError::Truncated)?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.at.checked_add(count).ok_or(SevenZipError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.at..end)
+            .ok_or(SevenZipError::Truncated)?;
+        self.at = end;
+        Ok(bytes)
+    }
+
+    /// Reads a number, whose first byte tells in its leading ones how many
+    /// bytes follow and holds the highest bits.
+    fn number(&mut self) -> Result<u64> {
+        let first = self.byte()?;
+        let mut value = 0u64;
+        for i in 0..8 {
+            let mask = 0x80 >> i;
+            if first & mask == 0 {
+                let high = u64::from(first & (mask - 1));
+                return Ok(value | high << (8 * i));
+            }
+            value |= u64::from(self.byte()?) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    fn size(&mut self) -> Result<usize> {
+        usize::try_from(self.number()?).map_err(|_| SevenZipError::Corrupt)
+    }
+
+    /// Reads a count of things that each take at least a bit, so that a
+    /// damaged count cannot make the reader allocate more than the header.
+    fn count(&mut self) -> Result<usize> {
+        let count = self.size()?;
+        if count > (self.bytes.len() - self.at) * 8 {
+            return Err(SevenZipError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn bits(&mut self, count: usize) -> Result<Vec<bool>> {
+        let bytes = self.bytes(count.div_ceil(8))?;
+        Ok((0..count)
+            .map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect())
+    }
+
+    /// Reads the bits telling which of `count` things are set, all of them
+    /// if the first byte says so.
+    fn defined(&mut self, count: usize) -> Result<Vec<bool>> {
+        if self.byte()? != 0 {
+            return Ok(alloc::vec![true; count]);
+        }
+        self.bits(count)
+    }
+
+    fn digests(&mut self, count: usize) -> Result<Vec<Option<u32>>> {
+        let defined = self.defined(count)?;
+        defined
+            .into_iter()
+            .map(|defined| {
+                Ok(if defined {
+                    Some(u32_at(self.bytes(4)?, 0))
+                } else {
+                    None
+                })
+            })
+            .collect()
+    }
+
+    fn expect(&mut self, id: u8) -> Result<()> {
+        if self.byte()? != id {
+            return Err(SevenZipError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+fn read_header(reader: &mut Reader) -> Result<(Vec<Folder>, Vec<Entry>)> {
+    let mut id = reader.byte()?;
+    if id == ARCHIVE_PROPERTIES {
+        while reader.byte()? != END {
+            let size = reader.size()?;
+            reader.bytes(size)?;
+        }
+        id = reader.byte()?;
+    }
+    if id == ADDITIONAL_STREAMS {
+        return Err(SevenZipError::Unsupported("additional streams"));
+    }
+    let mut streams = Streams::default();
+    if id == MAIN_STREAMS {
+        streams = read_streams(reader)?;
+        id = reader.byte()?;
+    }
+    let mut entries = Vec::new();
+    if id == FILES {
+        entries = read_files(reader, &streams)?;
+        id = reader.byte()?;
+    }
+    if id != END {
+        return Err(SevenZipError::Corrupt);
+    }
+    Ok((streams.folders, entries))
+}
+
+fn read_streams(reader: &mut Reader) -> Result<Streams> {
+    let mut packed = Vec::new();
+    let mut folders = Vec::new();
+    let mut id = reader.byte()?;
+    if id == PACK_INFO {
+        let position = reader.size()?;
+        let count = reader.count()?;
+        let mut at = SIGNATURE_HEADER_SIZE
+            .checked_add(position)
+            .ok_or(SevenZipError::Corrupt)?;
+        loop {
+            match reader.byte()? {
+                END => break,
+                SIZE => {
+                    for _ in 0..count {
+                        let size = reader.size()?;
+                        packed.push((at, size));
+                        at = at.checked_add(size).ok_or(SevenZipError::Corrupt)?;
+                    }
+                }
+                CRC => {
+                    reader.digests(count)?;
+                }
+                _ => return Err(SevenZipError::Corrupt),
+            }
+        }
+        id = reader.byte()?;
+    }
+    if id == UNPACK_INFO {
+        folders = read_folders(reader, &packed)?;
+        id = reader.byte()?;
+    }
+    let mut substreams: Vec<Vec<Substream>> = folders
+        .iter()
+        .map(|folder| alloc::vec![(folder.size, folder.crc32)])
+        .collect();
+    if id == SUBSTREAMS_INFO {
+        substreams = read_substreams(reader, &folders)?;
+        id = reader.byte()?;
+    }
+    if id != END {
+        return Err(SevenZipError::Corrupt);
+    }
+    Ok(Streams {
+        folders,
+        substreams,
+    })
+}
+
+fn read_folders(reader: &mut Reader, packed: &[(usize, usize)]) -> Result<Vec<Folder>> {
+    reader.expect(FOLDER)?;
+    let count = reader.count()?;
+    if reader.byte()? != 0 {
+        return Err(SevenZipError::Unsupported("external folders"));
+    }
+    let mut folders = Vec::with_capacity(count);
+    let mut outputs = Vec::with_capacity(count);
+    let mut next_packed = 0;
+    for _ in 0..count {
+        let mut folder = Folder::default();
+        let (mut inputs, mut output_count) = (0, 0);
+        for _ in 0..reader.count()? {
+            let flags = reader.byte()?;
+            if flags & 0x80 != 0 {
+                return Err(SevenZipError::Unsupported("alternative methods"));
+            }
+            let id = reader.bytes(usize::from(flags & 0x0F))?.to_vec();
+            if flags & 0x10 != 0 {
+                inputs += reader.count()?;
+                output_count += reader.count()?;
+            } else {
+                inputs += 1;
+                output_count += 1;
+            }
+            let mut properties = Vec::new();
+            if flags & 0x20 != 0 {
+                let size = reader.size()?;
+                properties = reader.bytes(size)?.to_vec();
+            }
+            folder.coders.push(Coder { id, properties });
+        }
+        // All outputs but the one of the folder are bound to an input.
+        let bound = output_count.checked_sub(1).ok_or(SevenZipError::Corrupt)?;
+        let mut unbound = alloc::vec![true; output_count];
+        for _ in 0..bound {
+            reader.size()?;
+            let output = reader.size()?;
+            *unbound.get_mut(output).ok_or(SevenZipError::Corrupt)? = false;
+        }
+        folder.packed_streams = inputs.checked_sub(bound).ok_or(SevenZipError::Corrupt)?;
+        if folder.packed_streams > 1 {
+            for _ in 0..folder.packed_streams {
+                reader.size()?;
+            }
+        }
+        folder.packed = packed.get(next_packed).copied().unwrap_or_default();
+        next_packed += folder.packed_streams;
+        let main = unbound
+            .iter()
+            .position(|&unbound| unbound)
+            .ok_or(SevenZipError::Corrupt)?;
+        outputs.push((output_count, main));
+        folders.push(folder);
+    }
+    reader.expect(UNPACK_SIZE)?;
+    for (folder, &(output_count, main)) in folders.iter_mut().zip(&outputs) {
+        for output in 0..output_count {
+            let size = reader.size()?;
+            if output == main {
+                folder.size = size;
+            }
+        }
+    }
+    loop {
+        match reader.byte()? {
+            END => break,
+            CRC => {
+                let digests = reader.digests(count)?;
+                for (folder, crc) in folders.iter_mut().zip(digests) {
+                    folder.crc32 = crc;
+                }
+            }
+            _ => return Err(SevenZipError::Corrupt),
+        }
+    }
+    Ok(folders)
+}
+
+fn read_substreams(reader: &mut Reader, folders: &[Folder]) -> Result<Vec<Vec<Substream>>> {
+    let mut counts = alloc::vec![1; folders.len()];
+    let mut id = reader.byte()?;
+    if id == UNPACK_STREAMS {
+        for count in &mut counts {
+            *count = reader.count()?;
+        }
+        id = reader.byte()?;
+    }
+    let mut substreams = Vec::with_capacity(folders.len());
+    for (folder, &count) in folders.iter().zip(&counts) {
+        let mut sizes = Vec::with_capacity(count);
+        let mut left = folder.size;
+        for _ in 1..count {
+            let size = if id == SIZE { reader.size()? } else { 0 };
+            left = left.checked_sub(size).ok_or(SevenZipError::Corrupt)?;
+            sizes.push((size, None));
+        }
+        if count > 0 {
+            sizes.push((left, None));
+        }
+        substreams.push(sizes);
+    }
+    if id == SIZE {
+        id = reader.byte()?;
+    }
+    // Streams that are a folder of their own have the CRC of the folder.
+    let known = |folder: &Folder, count: usize| count == 1 && folder.crc32.is_some();
+    for (sizes, folder) in substreams.iter_mut().zip(folders) {
+        if known(folder, sizes.len()) {
+            sizes[0].1 = folder.crc32;
+        }
+    }
+    loop {
+        match id {
+            END => break,
+            CRC => {
+                let unknown = substreams
+                    .iter()
+                    .zip(folders)
+                    .filter(|(sizes, folder)| !known(folder, sizes.len()))
+                    .map(|(sizes, _)| sizes.len())
+                    .sum();
+                let mut digests = reader.digests(unknown)?.into_iter();
+                for (sizes, folder) in substreams.iter_mut().zip(folders) {
+                    if !known(folder, sizes.len()) {
+                        for size in sizes.iter_mut() {
+                            size.1 = digests.next().flatten();
+                        }
+                    }
+                }
+            }
+            _ => return Err(SevenZipError::Corrupt),
+        }
+        id = reader.byte()?;
+    }
+    Ok(substreams)
+}
+
+fn read_files(reader: &mut Reader, streams: &Streams) -> Result<Vec<Entry>> {
+    let count = reader.count()?;
+    let mut empty_stream = alloc::vec![false; count];
+    let mut empty_file = Vec::new();
+    let mut names = Vec::new();
+    loop {
+        let id = reader.byte()?;
+        if id == END {
+            break;
+        }
+        let size = reader.size()?;
+        let mut property = Reader {
+            bytes: reader.bytes(size)?,
+            at: 0,
+        };
+        match id {
+            EMPTY_STREAM => empty_stream = property.bits(count)?,
+            EMPTY_FILE => {
+                let empty = empty_stream.iter().filter(|&&empty| empty).count();
+                empty_file = property.bits(empty)?;
+            }
+            NAME => {
+                if property.byte()? != 0 {
+                    return Err(SevenZipError::Unsupported("external names"));
+                }
+                let units: Vec<u16> = property.bytes[1..]
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .collect();
+                names = units
+                    .split(|&unit| unit == 0)
+                    .take(count)
+                    .map(String::from_utf16_lossy)
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    let mut streams_left = streams
+        .substreams
+        .iter()
+        .enumerate()
+        .flat_map(|(folder, sizes)| {
+            sizes.iter().scan(0, move |offset, &(size, crc)| {
+                let stream = (folder, *offset, size, crc);
+                *offset += size;
+                Some(stream)
+            })
+        });
+    let mut empty_index = 0;
+    let mut entries = Vec::with_capacity(count);
+    for (i, &empty) in empty_stream.iter().enumerate() {
+        let name = names.get(i).cloned().unwrap_or_default();
+        if empty {
+            let file = empty_file.get(empty_index).copied().unwrap_or(false);
+            empty_index += 1;
+            entries.push(Entry {
+                name,
+                size: 0,
+                crc32: None,
+                directory: !file,
+                stream: None,
+            });
+        } else {
+            let (folder, offset, size, crc32) =
+                streams_left.next().ok_or(SevenZipError::Corrupt)?;
+            entries.push(Entry {
+                name,
+                size,
+                crc32,
+                directory: false,
+                stream: Some((folder, offset)),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Unpacks a folder.
+fn unpack(bytes: &[u8], folder: &Folder) -> Result<Vec<u8>> {
+    let [coder] = &folder.coders[..] else {
+        return Err(SevenZipError::Unsupported("filter chains"));
+    };
+    let (start, size) = folder.packed;
+    let packed = start
+        .checked_add(size)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or(SevenZipError::Truncated)?;
+    let data = match &coder.id[..] {
+        [0x00] => packed.to_vec(),
+        [0x03, 0x01, 0x01] => {
+            let &[properties, ..] = &coder.properties[..] else {
+                return Err(SevenZipError::Corrupt);
+            };
+            let mut decoder = Lzma::new(properties)?;
+            let mut out = Vec::with_capacity(folder.size.min(1 << 24));
+            decoder.decode(&mut RangeDecoder::new(packed)?, &mut out, 0, folder.size)?;
+            out
+        }
+        [0x21] => lzma2(packed, folder.size)?,
+        [0x06, 0xF1, 0x07, 0x01] => return Err(SevenZipError::Unsupported("encryption")),
+        _ => return Err(SevenZipError::Unsupported("compression method")),
+    };
+    if data.len() != folder.size || folder.crc32.is_some_and(|crc| crc32(&data) != crc) {
+        return Err(SevenZipError::Checksum);
+    }
+    Ok(data)
+}
+
+/// Unpacks LZMA2 data: chunks of LZMA data or stored bytes, each telling
+/// what of the state is reset before it.
+fn lzma2(data: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size.min(1 << 24));
+    let mut decoder: Option<Lzma> = None;
+    let mut start = 0;
+    let mut at = 0;
+    let field = |at: usize| -> Result<usize> {
+        let pair = data.get(at..at + 2).ok_or(SevenZipError::Corrupt)?;
+        Ok(usize::from(u16::from_be_bytes([pair[0], pair[1]])))
+    };
+    loop {
+        let control = *data.get(at).ok_or(SevenZipError::Corrupt)?;
+        match control {
+            0x00 => return Ok(out),
+            0x01 | 0x02 => {
+                if control == 0x01 {
+                    start = out.len();
+                }
+                let length = field(at + 1)? + 1;
+                let stored = data
+                    .get(at + 3..at + 3 + length)
+                    .ok_or(SevenZipError::Corrupt)?;
+                out.extend_from_slice(stored);
+                at += 3 + length;
+            }
+            0x80.. => {
+                let unpacked = (usize::from(control & 0x1F) << 16) + field(at + 1)? + 1;
+                let packed = field(at + 3)? + 1;
+                at += 5;
+                let reset = (control >> 5) & 3;
+                if reset == 3 {
+                    start = out.len();
+                }
+                if reset >= 2 {
+                    let properties = *data.get(at).ok_or(SevenZipError::Corrupt)?;
+                    decoder = Some(Lzma::new(properties)?);
+                    at += 1;
+                }
+                let lzma = decoder.as_mut().ok_or(SevenZipError::Corrupt)?;
+                if reset == 1 {
+                    lzma.reset();
+                }
+                let chunk = data.get(at..at + packed).ok_or(SevenZipError::Corrupt)?;
+                let end = out.len() + unpacked;
+                lzma.decode(&mut RangeDecoder::new(chunk)?, &mut out, start, end)?;
+                if out.len() != end {
+                    return Err(SevenZipError::Corrupt);
+                }
+                at += packed;
+            }
+            _ => return Err(SevenZipError::Corrupt),
+        }
+    }
+}
+
+const PROBABILITY_BITS: u32 = 11;
+const HALF: u16 = 1 << (PROBABILITY_BITS - 1);
+const STATES: usize = 12;
+
+/// The binary range decoder LZMA codes everything with.
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    at: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self> {
+        let head = data.get(..5).ok_or(SevenZipError::Corrupt)?;
+        if head[0] != 0 {
+            return Err(SevenZipError::Corrupt);
+        }
+        Ok(RangeDecoder {
+            data,
+            at: 5,
+            range: u32::MAX,
+            code: u32::from_be_bytes([head[1], head[2], head[3], head[4]]),
+        })
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        if self.range < 1 << 24 {
+            let byte = *self.data.get(self.at).ok_or(SevenZipError::Corrupt)?;
+            self.at += 1;
+            self.range <<= 8;
+            self.code = self.code << 8 | u32::from(byte);
+        }
+        Ok(())
+    }
+
+    /// Decodes a bit with the probability `probability` of it being zero,
+    /// which it adapts.
+    fn bit(&mut self, probability: &mut u16) -> Result<usize> {
+        let bound = (self.range >> PROBABILITY_BITS) * u32::from(*probability);
+        let bit = if self.code < bound {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> 5;
+            0
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *probability -= *probability >> 5;
+            1
+        };
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    /// Decodes `count` bits of even probability.
+    fn direct(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = u32::from(self.code >= self.range);
+            if bit == 1 {
+                self.code -= self.range;
+            }
+            value = value << 1 | bit;
+            self.normalize()?;
+        }
+        Ok(value)
+    }
+
+    /// Decodes a number of `bits` bits, highest first, with a tree of
+    /// probabilities.
+    fn tree(&mut self, probabilities: &mut [u16], bits: u32) -> Result<usize> {
+        let mut node = 1;
+        for _ in 0..bits {
+            node = node << 1 | self.bit(&mut probabilities[node])?;
+        }
+        Ok(node - (1 << bits))
+    }
+
+    /// Decodes a number of `bits` bits, lowest first, with a tree of
+    /// probabilities.
+    fn reverse(&mut self, probabilities: &mut [u16], bits: u32) -> Result<usize> {
+        let (mut node, mut value) = (1, 0);
+        for i in 0..bits {
+            let bit = self.bit(&mut probabilities[node])?;
+            node = node << 1 | bit;
+            value |= bit << i;
+        }
+        Ok(value)
+    }
+}
+
+/// The probabilities of match lengths.
+#[derive(Clone)]
+struct Lengths {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 8]; 16],
+    mid: [[u16; 8]; 16],
+    high: [u16; 256],
+}
+
+impl Lengths {
+    fn new() -> Self {
+        Lengths {
+            choice: HALF,
+            choice2: HALF,
+            low: [[HALF; 8]; 16],
+            mid: [[HALF; 8]; 16],
+            high: [HALF; 256],
+        }
+    }
+
+    fn decode(&mut self, rc: &mut RangeDecoder, position_state: usize) -> Result<usize> {
+        if rc.bit(&mut self.choice)? == 0 {
+            rc.tree(&mut self.low[position_state], 3)
+        } else if rc.bit(&mut self.choice2)? == 0 {
+            Ok(8 + rc.tree(&mut self.mid[position_state], 3)?)
+        } else {
+            Ok(16 + rc.tree(&mut self.high, 8)?)
+        }
+    }
+}
+
+/// The state of an LZMA decoder.
+struct Lzma {
+    literal_context: u32,
+    literal_position: u32,
+    position_bits: u32,
+    literals: Vec<u16>,
+    is_match: [u16; STATES << 4],
+    is_rep: [u16; STATES],
+    is_rep0: [u16; STATES],
+    is_rep1: [u16; STATES],
+    is_rep2: [u16; STATES],
+    is_rep0_long: [u16; STATES << 4],
+    slots: [[u16; 64]; 4],
+    distances: [u16; 115],
+    align: [u16; 16],
+    lengths: Lengths,
+    rep_lengths: Lengths,
+    state: usize,
+    reps: [usize; 4],
+}
+
+impl Lzma {
+    /// Creates a decoder for the properties byte of a stream, which packs
+    /// the numbers of literal context, literal position and position bits.
+    fn new(properties: u8) -> Result<Self> {
+        if properties >= 9 * 5 * 5 {
+            return Err(SevenZipError::Corrupt);
+        }
+        let properties = u32::from(properties);
+        let literal_context = properties % 9;
+        let literal_position = properties / 9 % 5;
+        Ok(Lzma {
+            literal_context,
+            literal_position,
+            position_bits: properties / 45,
+            literals: alloc::vec![HALF; 0x300 << (literal_context + literal_position)],
+            is_match: [HALF; STATES << 4],
+            is_rep: [HALF; STATES],
+            is_rep0: [HALF; STATES],
+            is_rep1: [HALF; STATES],
+            is_rep2: [HALF; STATES],
+            is_rep0_long: [HALF; STATES << 4],
+            slots: [[HALF; 64]; 4],
+            distances: [HALF; 115],
+            align: [HALF; 16],
+            lengths: Lengths::new(),
+            rep_lengths: Lengths::new(),
+            state: 0,
+            reps: [0; 4],
+        })
+    }
+
+    /// Resets the state and the probabilities, keeping the properties.
+    fn reset(&mut self) {
+        let properties =
+            (self.position_bits * 5 + self.literal_position) * 9 + self.literal_context;
+        *self = Lzma::new(properties as u8).unwrap_or_else(|_| unreachable!());
+    }
+
+    /// Decodes into `out` until it is `end` bytes long or the end marker,
+    /// with positions counted from `start`, where the dictionary begins.
+    fn decode(
+        &mut self,
+        rc: &mut RangeDecoder,
+        out: &mut Vec<u8>,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        while out.len() < end {
+            let position = out.len() - start;
+            let position_state = position & ((1 << self.position_bits) - 1);
+            let state = self.state;
+            if rc.bit(&mut self.is_match[state << 4 | position_state])? == 0 {
+                self.literal(rc, out, position)?;
+                continue;
+            }
+            let length = if rc.bit(&mut self.is_rep[state])? == 1 {
+                if position == 0 {
+                    return Err(SevenZipError::Corrupt);
+                }
+                if rc.bit(&mut self.is_rep0[state])? == 0 {
+                    if rc.bit(&mut self.is_rep0_long[state << 4 | position_state])? == 0 {
+                        self.state = if state < 7 { 9 } else { 11 };
+                        let byte = out[out.len() - self.reps[0] - 1];
+                        out.push(byte);
+                        continue;
+                    }
+                } else {
+                    let distance = if rc.bit(&mut self.is_rep1[state])? == 0 {
+                        self.reps[1]
+                    } else {
+                        let distance = if rc.bit(&mut self.is_rep2[state])? == 0 {
+                            self.reps[2]
+                        } else {
+                            let distance = self.reps[3];
+                            self.reps[3] = self.reps[2];
+                            distance
+                        };
+                        self.reps[2] = self.reps[1];
+                        distance
+                    };
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = distance;
+                }
+                self.state = if state < 7 { 8 } else { 11 };
+                self.rep_lengths.decode(rc, position_state)?
+            } else {
+                self.reps.copy_within(0..3, 1);
+                let length = self.lengths.decode(rc, position_state)?;
+                self.state = if state < 7 { 7 } else { 10 };
+                let distance = self.distance(rc, length)?;
+                if distance == u32::MAX {
+                    return Ok(());
+                }
+                self.reps[0] = distance as usize;
+                length
+            };
+            let distance = self.reps[0] + 1;
+            if distance > position {
+                return Err(SevenZipError::Corrupt);
+            }
+            for _ in 0..(length + 2).min(end - out.len()) {
+                let byte = out[out.len() - distance];
+                out.push(byte);
+            }
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, rc: &mut RangeDecoder, out: &mut Vec<u8>, position: usize) -> Result<()> {
+        let previous = if position > 0 { out[out.len() - 1] } else { 0 };
+        let context = (position & ((1 << self.literal_position) - 1)) << self.literal_context
+            | usize::from(previous) >> (8 - self.literal_context);
+        let probabilities = &mut self.literals[0x300 * context..0x300 * (context + 1)];
+        let mut symbol = 1;
+        if self.state >= 7 {
+            // After a match the literal is coded against the byte the
+            // match would have continued with, until they differ.
+            let distance = self.reps[0] + 1;
+            if distance > position {
+                return Err(SevenZipError::Corrupt);
+            }
+            let mut matched = usize::from(out[out.len() - distance]);
+            while symbol < 0x100 {
+                let matched_bit = (matched >> 7) & 1;
+                matched <<= 1;
+                let bit = rc.bit(&mut probabilities[((1 + matched_bit) << 8) + symbol])?;
+                symbol = symbol << 1 | bit;
+                if matched_bit != bit {
+                    break;
+                }
+            }
+        }
+        while symbol < 0x100 {
+            symbol = symbol << 1 | rc.bit(&mut probabilities[symbol])?;
+        }
+        out.push(symbol as u8);
+        self.state = match self.state {
+            0..4 => 0,
+            4..10 => self.state - 3,
+            _ => self.state - 6,
+        };
+        Ok(())
+    }
+
+    /// Decodes the distance of a match of `length`, less one:
+    /// [`u32::MAX`] is the end marker.
+    fn distance(&mut self, rc: &mut RangeDecoder, length: usize) -> Result<u32> {
+        let slot = rc.tree(&mut self.slots[length.min(3)], 6)? as u32;
+        if slot < 4 {
+            return Ok(slot);
+        }
+        let bits = (slot >> 1) - 1;
+        let base = (2 | (slot & 1)) << bits;
+        if slot < 14 {
+            let offset = (base - slot) as usize;
+            Ok(base + rc.reverse(&mut self.distances[offset..], bits)? as u32)
+        } else {
+            let high = rc.direct(bits - 4)? << 4;
+            Ok(base + high + rc.reverse(&mut self.align, 4)? as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a number the way headers hold them.
+    fn number(value: usize) -> Vec<u8> {
+        match value {
+            0..0x80 => vec![value as u8],
+            0x80..0x4000 => vec![0x80 | (value >> 8) as u8, value as u8],
+            _ => vec![0xC0 | (value >> 16) as u8, value as u8, (value >> 8) as u8],
+        }
+    }
+
+    /// Builds an archive of one folder packed with the coder `id` and
+    /// `properties`, holding `files` with sizes and directories.
+    fn build(
+        id: &[u8],
+        properties: &[u8],
+        packed: &[u8],
+        files: &[(&str, Option<&[u8]>)],
+    ) -> Vec<u8> {
+        let streams: Vec<&[u8]> = files.iter().filter_map(|(_, data)| *data).collect();
+        let size: usize = streams.iter().map(|data| data.len()).sum();
+        let mut header = vec![HEADER, MAIN_STREAMS, PACK_INFO, 0, 1, SIZE];
+        header.extend(number(packed.len()));
+        header.extend([END, UNPACK_INFO, FOLDER, 1, 0, 1]);
+        header.push(id.len() as u8 | if properties.is_empty() { 0 } else { 0x20 });
+        header.extend_from_slice(id);
+        if !properties.is_empty() {
+            header.extend(number(properties.len()));
+            header.extend_from_slice(properties);
+        }
+        header.push(UNPACK_SIZE);
+        header.extend(number(size));
+        header.extend([END, SUBSTREAMS_INFO, UNPACK_STREAMS]);
+        header.extend(number(streams.len()));
+        header.push(SIZE);
+        for data in &streams[..streams.len() - 1] {
+            header.extend(number(data.len()));
+        }
+        header.extend([CRC, 1]);
+        for data in &streams {
+            header.extend(crc32(data).to_le_bytes());
+        }
+        header.extend([END, END, FILES]);
+        header.extend(number(files.len()));
+        let empty: Vec<bool> = files.iter().map(|(_, data)| data.is_none()).collect();
+        if empty.contains(&true) {
+            let mut bits = vec![0u8; files.len().div_ceil(8)];
+            for (i, _) in empty.iter().enumerate().filter(|(_, empty)| **empty) {
+                bits[i / 8] |= 0x80 >> (i % 8);
+            }
+            header.push(EMPTY_STREAM);
+            header.extend(number(bits.len()));
+            header.extend(bits);
+        }
+        let mut names = vec![0];
+        for (name, _) in files {
+            names.extend(name.encode_utf16().chain([0]).flat_map(u16::to_le_bytes));
+        }
+        header.push(NAME);
+        header.extend(number(names.len()));
+        header.extend(names);
+        header.extend([END, END]);
+
+        let mut start = Vec::new();
+        start.extend((packed.len() as u64).to_le_bytes());
+        start.extend((header.len() as u64).to_le_bytes());
+        start.extend(crc32(&header).to_le_bytes());
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend([0, 4]);
+        bytes.extend(crc32(&start).to_le_bytes());
+        bytes.extend(start);
+        bytes.extend_from_slice(packed);
+        bytes.extend(header);
+        bytes
+    }
+
+    /// Moves the header of `archive` behind the packed streams, stored,
+    /// with an encoded header pointing to it as 7-Zip writes them.
+    fn encode(archive: &[u8]) -> Vec<u8> {
+        let header_at = SIGNATURE_HEADER_SIZE + u64_at(archive, 12) as usize;
+        let header = &archive[header_at..];
+        let mut encoded = vec![ENCODED_HEADER, PACK_INFO];
+        encoded.extend(number(header_at - SIGNATURE_HEADER_SIZE));
+        encoded.extend([1, SIZE]);
+        encoded.extend(number(header.len()));
+        encoded.extend([END, UNPACK_INFO, FOLDER, 1, 0, 1, 1, 0, UNPACK_SIZE]);
+        encoded.extend(number(header.len()));
+        encoded.extend([END, END]);
+        let mut start = Vec::new();
+        start.extend((archive.len() as u64 - SIGNATURE_HEADER_SIZE as u64).to_le_bytes());
+        start.extend((encoded.len() as u64).to_le_bytes());
+        start.extend(crc32(&encoded).to_le_bytes());
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend([0, 4]);
+        bytes.extend(crc32(&start).to_le_bytes());
+        bytes.extend(start);
+        bytes.extend_from_slice(&archive[SIGNATURE_HEADER_SIZE..]);
+        bytes.extend(encoded);
+        bytes
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The contents of the test archives: a text and the bytes 0 to 39.
+    fn contents() -> (Vec<u8>, Vec<u8>) {
+        (b"HELLO WORLD ".repeat(8), (0..40).collect())
+    }
+
+    #[test]
+    fn unpacks_solid_lzma_and_lzma2_archives() {
+        let (text, bytes) = contents();
+        // Both packed by Python's lzma module, LZMA with an end marker.
+        let lzma = hex(
+            "00241145cf72d90ec942285ca53cbc4a9c882a2f3d3825c7fe2cc9a3946b3cf1\
+             0bbf083500c8da4cba91c6dc5512c21ac94e36e1efd621586265ffffc5390000",
+        );
+        let chunks = hex(
+            "e0008700395d00241145cf72d90ec942285ca53cbc4a9c882a2f3d3825c7fe2c\
+             c9a3946b3cf10bbf083500c8da4cba91c6dc5512c21ac94e36e1efd62082e82400",
+        );
+        let files: [(&str, Option<&[u8]>); 3] = [
+            ("DISKS", None),
+            ("DISKS/README.TXT", Some(&text)),
+            ("DISKS/DATA.BIN", Some(&bytes)),
+        ];
+        for (id, properties, packed) in [
+            (&[0x03, 0x01, 0x01][..], &[0x5D, 0, 0x10, 0, 0][..], &lzma),
+            (&[0x21][..], &[0x0C][..], &chunks),
+        ] {
+            let archive = build(id, properties, packed, &files);
+            let archive = Archive::open(&archive).unwrap();
+            let entries = archive.entries();
+            assert_eq!(entries.len(), 3);
+            assert!(entries[0].is_dir() && !entries[1].is_dir());
+            assert_eq!(entries[1].name, "DISKS/README.TXT");
+            assert_eq!(entries[2].size, 40);
+            assert_eq!(archive.data(&entries[2]).unwrap(), bytes);
+            assert_eq!(archive.data(&entries[1]).unwrap(), text);
+            assert_eq!(archive.data(&entries[0]).unwrap(), []);
+        }
+        // An uncompressed LZMA2 chunk.
+        let stored: Vec<u8> = (0..40u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        let mut packed = vec![0x01, 0x00, 39];
+        packed.extend(&stored);
+        packed.push(0);
+        assert_eq!(lzma2(&packed, 40).unwrap(), stored);
+    }
+
+    #[test]
+    fn rejects_damaged_archives() {
+        let (text, _) = contents();
+        let files: [(&str, Option<&[u8]>); 1] = [("README.TXT", Some(&text))];
+        let bytes = build(&[0x00], &[], &text, &files);
+        let stored = Archive::open(&bytes).unwrap();
+        assert_eq!(stored.data(&stored.entries()[0]).unwrap(), text);
+        let encoded = encode(&bytes);
+        let stored = Archive::open(&encoded).unwrap();
+        assert_eq!(stored.entries()[0].name, "README.TXT");
+        assert_eq!(stored.data(&stored.entries()[0]).unwrap(), text);
+
+        assert_eq!(
+            Archive::open(b"PK\x03\x04").unwrap_err(),
+            SevenZipError::NotAnArchive
+        );
+        assert_eq!(
+            Archive::open(&bytes[..20]).unwrap_err(),
+            SevenZipError::Truncated
+        );
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Archive::open(&damaged).unwrap_err(),
+            SevenZipError::Checksum
+        );
+        let mut damaged = bytes.clone();
+        damaged[SIGNATURE_HEADER_SIZE] ^= 1;
+        let archive = Archive::open(&damaged).unwrap();
+        assert_eq!(
+            archive.data(&archive.entries()[0]),
+            Err(SevenZipError::Checksum)
+        );
+        // BCJ, which 7-Zip chains before LZMA for executables.
+        let bcj = build(&[0x03, 0x03, 0x01, 0x03], &[], &text, &files);
+        let archive = Archive::open(&bcj).unwrap();
+        assert_eq!(
+            archive.data(&archive.entries()[0]),
+            Err(SevenZipError::Unsupported("compression method"))
+        );
+    }
+}
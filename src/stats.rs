@@ -0,0 +1,222 @@
+//! Raw statistics of tracks.
+//!
+//! Before trusting a dump, its tracks are looked at as bitstreams: how
+//! many syncs a track has and how long they are, how much gap follows the
+//! blocks, how many sectors the headers announce against how many the
+//! track should carry, whether the length of the track fits the speed it
+//! is recorded at, and which bytes it is made of. [`analyze`] measures
+//! this for every track of a G64 and [`analyze_flux`] for a flux source,
+//! decoded in the speed zone of the 1541, as [`TrackStats`], which
+//! serialize to JSON for tools comparing dumps.
+
+use crate::GCR;
+use crate::d64::sectors_per_track;
+#[cfg(feature = "std")]
+use crate::flux::{FluxSource, read_track};
+use crate::g64::G64;
+use crate::json::{ToJson, Writer};
+use crate::timing::{DEFAULT_RPM, SYNC_BITS, find_syncs, speed_zone, track_capacity};
+use crate::track::{self, GAP_BYTE, HEADER_LENGTH, HEADER_MARK};
+use alloc::vec::Vec;
+
+/// What [`analyze`] measures on a half track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackStats {
+    /// The index of the half track, 0 for track 1.
+    pub half_track: usize,
+    /// The length of the track in bytes.
+    pub length: usize,
+    /// The speed zone the track is recorded at.
+    pub zone: u8,
+    /// The length in bits of each sync mark, in the order of the track.
+    pub syncs: Vec<usize>,
+    /// The gap bytes before each sync mark, the bytes after the block
+    /// before it that the drive does not read: after a header or data
+    /// block whatever follows it, after other blocks the gap bytes they
+    /// end with.
+    pub gaps: Vec<usize>,
+    /// The number of sectors whose headers carry this track and pass
+    /// their checksum.
+    pub sectors: usize,
+    /// The number of bytes of each value.
+    pub histogram: [u32; 256],
+}
+
+impl TrackStats {
+    /// Returns the track number, rounded down for half tracks.
+    pub fn track(&self) -> u8 {
+        (self.half_track / 2 + 1) as u8
+    }
+
+    /// Returns the number of sectors DOS writes on the track, 0 beyond
+    /// track 40.
+    pub fn expected_sectors(&self) -> u8 {
+        sectors_per_track(self.track())
+    }
+
+    /// Returns the speed zone whose revolution the length of the track
+    /// fits best.
+    pub fn implied_zone(&self) -> u8 {
+        (0..4)
+            .min_by_key(|&zone| self.length.abs_diff(track_capacity(zone, DEFAULT_RPM)))
+            .unwrap_or(self.zone)
+    }
+
+    /// Returns `true` if the track is recorded at another speed than the
+    /// 1541 writes it at, or its length fits another speed than it is
+    /// recorded at.
+    pub fn density_mismatch(&self) -> bool {
+        self.zone != speed_zone(self.track()) || self.implied_zone() != self.zone
+    }
+
+    /// Returns the share of bytes that are the gap byte `$55`, from 0 to 1.
+    pub fn gap_share(&self) -> f64 {
+        self.histogram[usize::from(GAP_BYTE)] as f64 / self.length.max(1) as f64
+    }
+}
+
+/// Measures `data`, half track `half_track` recorded at speed zone `zone`.
+pub fn track_stats(data: &[u8], half_track: usize, zone: u8) -> TrackStats {
+    let bits = data.len() * 8;
+    let track = (half_track / 2 + 1) as u8;
+    let found = find_syncs(data);
+    let blocks = track::blocks(data);
+    let syncs = found
+        .iter()
+        .map(|&(detected, end)| (end + bits - detected) % bits + SYNC_BITS - 1)
+        .collect();
+    let gaps = (0..found.len())
+        .map(|i| {
+            let (previous, block) = if i == 0 {
+                (found[found.len() - 1].1, &blocks[found.len() - 1])
+            } else {
+                (found[i - 1].1, &blocks[i - 1])
+            };
+            let start = (found[i].0 + bits - (SYNC_BITS - 1)) % bits;
+            ((start + bits - previous) % bits)
+                .div_ceil(8)
+                .saturating_sub(block.len())
+        })
+        .collect();
+    let gcr = GCR::new();
+    let mut sectors: Vec<u8> = blocks
+        .iter()
+        .filter(|block| block.len() == HEADER_LENGTH)
+        .filter_map(|block| gcr.decode(block))
+        .filter(|h| h[0] == HEADER_MARK && h[3] == track && h[1] == h[2] ^ h[3] ^ h[4] ^ h[5])
+        .map(|h| h[2])
+        .collect();
+    sectors.sort_unstable();
+    sectors.dedup();
+    let mut histogram = [0; 256];
+    for &byte in data {
+        histogram[usize::from(byte)] += 1;
+    }
+    TrackStats {
+        half_track,
+        length: data.len(),
+        zone,
+        syncs,
+        gaps,
+        sectors: sectors.len(),
+        histogram,
+    }
+}
+
+/// Measures every half track present in `g64`.
+pub fn analyze(g64: &G64) -> Vec<TrackStats> {
+    (0..g64.half_tracks())
+        .filter_map(|index| {
+            let data = g64.half_track(index).filter(|d| !d.is_empty())?;
+            Some(track_stats(data, index, g64.half_track_zone(index)))
+        })
+        .collect()
+}
+
+/// Measures every half track `source` holds flux for, decoded from the
+/// first revolution in the speed zone of the 1541.
+///
+/// # Errors
+/// Returns the error of the source.
+#[cfg(feature = "std")]
+pub fn analyze_flux(source: &mut impl FluxSource) -> std::io::Result<Vec<TrackStats>> {
+    let mut stats = Vec::new();
+    for index in 0..source.half_tracks() {
+        if let Some(data) = read_track(source, index)?.filter(|d| !d.is_empty()) {
+            let zone = speed_zone(index as u8 / 2 + 1);
+            stats.push(track_stats(&data, index, zone));
+        }
+    }
+    Ok(stats)
+}
+
+/// An object with the measures, the derived ones included, and the
+/// histogram as an array of 256 counts.
+impl ToJson for TrackStats {
+    fn write_json(&self, json: &mut Writer) {
+        json.begin_object()
+            .field("half_track", &self.half_track)
+            .field("track", &self.track())
+            .field("length", &self.length)
+            .field("zone", &self.zone)
+            .field("implied_zone", &self.implied_zone())
+            .field("density_mismatch", &self.density_mismatch())
+            .field("syncs", &self.syncs)
+            .field("gaps", &self.gaps)
+            .field("sectors", &self.sectors)
+            .field("expected_sectors", &self.expected_sectors())
+            .field("histogram", &self.histogram)
+            .end_object();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    #[test]
+    fn measures_standard_tracks() {
+        let g64 = G64::from_image(&D64::new(35));
+        let all = analyze(&g64);
+        assert_eq!(all.len(), 35);
+        let one = &all[0];
+        assert_eq!((one.track(), one.zone, one.length), (1, 3, 7692));
+        assert_eq!(one.syncs.len(), 42);
+        assert!(one.syncs.iter().all(|&bits| bits == 41));
+        assert_eq!(one.gaps[1], track::HEADER_GAP);
+        assert_eq!(one.sectors, 21);
+        assert_eq!(usize::from(one.expected_sectors()), one.sectors);
+        assert!(!one.density_mismatch());
+        assert_eq!(one.histogram.iter().sum::<u32>(), 7692);
+        assert!(all[34].histogram[0xFF] > 0);
+
+        let json = all[17].to_json();
+        assert!(json.starts_with(r#"{"half_track":34,"track":18,"length":7142,"zone":2,"#));
+        assert!(json.contains(r#""sectors":19,"expected_sectors":19,"histogram":["#));
+    }
+
+    #[test]
+    fn finds_what_does_not_fit() {
+        let mut g64 = G64::from_image(&D64::new(35));
+        let track = g64.track(31).unwrap().to_vec();
+        g64.set_half_track(60, track.clone(), 3);
+        let mut broken = track.clone();
+        broken.extend(core::iter::repeat_n(0x55, 600));
+        g64.set_track(32, broken);
+        let mut source = g64.clone();
+
+        let all = analyze(&g64);
+        assert!(all[30].density_mismatch());
+        assert_eq!(all[30].implied_zone(), 0);
+        let long = &all[31];
+        assert_eq!(long.sectors, 0);
+        assert_eq!(long.implied_zone(), 1);
+        assert!(long.density_mismatch());
+        assert!(long.gap_share() > all[30].gap_share());
+
+        let flux = analyze_flux(&mut source).unwrap();
+        assert_eq!(flux.len(), 35);
+        assert_eq!(flux[0], all[0]);
+    }
+}
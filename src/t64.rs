@@ -0,0 +1,474 @@
+//! T64 tape archives.
+//!
+//! Despite the name a T64 holds no tape signal but files, as an emulator
+//! loads them from tape:
+//!
+//! ```plaintext
+//! $00  signature, "C64 tape image file" padded with zeros, 32 bytes
+//! $20  version (u16)
+//! $22  number of directory entries (u16)
+//! $24  number of used entries (u16)
+//! $28  tape name, 24 bytes
+//! $40  directory entries of 32 bytes:
+//!      entry type, file type, start address (u16), end address (u16),
+//!      2 unused, offset of the data in the archive (u32), 4 unused, name
+//! ```
+//!
+//! Numbers are little endian. Many archives in circulation were written by
+//! tools that got the header wrong: end addresses of zero or `$C3C6`, used
+//! entry counts of zero, offsets that point anywhere. [`T64::from_bytes`]
+//! trusts the header; [`repair`] works out what the data says instead and
+//! reports every [`Fix`] it made.
+
+use crate::image::ImageError;
+use crate::prg::Prg;
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+/// The signature T64 archives are written with.
+pub const SIGNATURE: &[u8] = b"C64 tape image file";
+/// The size of the header in front of the directory.
+pub const HEADER_SIZE: usize = 0x40;
+/// The size of a directory entry.
+pub const ENTRY_SIZE: usize = 32;
+
+/// A file in a T64 archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct T64File {
+    /// The type of the entry, 1 for a normal tape file.
+    pub entry_type: u8,
+    /// The C64 file type, `$82` for a PRG.
+    pub file_type: u8,
+    /// The load address.
+    pub start: u16,
+    /// The name, padded as the archive has it.
+    pub name: [u8; 16],
+    /// The contents, without load address.
+    pub data: Vec<u8>,
+}
+
+impl T64File {
+    /// Creates a normal tape file of the program `prg`, named `name` cut
+    /// to 16 bytes and padded with spaces.
+    pub fn from_prg(name: &[u8], prg: &Prg) -> Self {
+        let mut padded = [b' '; 16];
+        let length = name.len().min(16);
+        padded[..length].copy_from_slice(&name[..length]);
+        T64File {
+            entry_type: 1,
+            file_type: 0x82,
+            start: prg.load,
+            name: padded,
+            data: prg.body.clone(),
+        }
+    }
+
+    /// Returns the address behind the last byte.
+    pub fn end(&self) -> u16 {
+        self.start.wrapping_add(self.data.len() as u16)
+    }
+
+    /// Returns the file as a program.
+    pub fn prg(&self) -> Prg {
+        Prg::new(self.start, self.data.clone())
+    }
+}
+
+/// A T64 archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct T64 {
+    /// The version, usually `$0100` or `$0101`.
+    pub version: u16,
+    /// The tape name, padded with spaces.
+    pub name: [u8; 24],
+    /// The files, in the order of the directory.
+    pub files: Vec<T64File>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// A directory entry as stored.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    slot: usize,
+    entry_type: u8,
+    file_type: u8,
+    start: u16,
+    end: u16,
+    offset: usize,
+    name: [u8; 16],
+}
+
+/// Checks the signature and returns the header.
+fn header(bytes: &[u8]) -> Result<&[u8], ImageError> {
+    if !bytes.starts_with(b"C64") {
+        return Err(ImageError::InvalidSignature);
+    }
+    bytes.get(..HEADER_SIZE).ok_or(ImageError::Truncated)
+}
+
+/// Returns the used entries among the first `slots` of the directory.
+fn entries(bytes: &[u8], slots: usize) -> Vec<Entry> {
+    bytes[HEADER_SIZE..]
+        .chunks_exact(ENTRY_SIZE)
+        .take(slots)
+        .enumerate()
+        .filter(|(_, entry)| entry[0] != 0)
+        .map(|(slot, entry)| {
+            let mut name = [0; 16];
+            name.copy_from_slice(&entry[16..32]);
+            Entry {
+                slot,
+                entry_type: entry[0],
+                file_type: entry[1],
+                start: u16_at(entry, 2),
+                end: u16_at(entry, 4),
+                offset: u32_at(entry, 8) as usize,
+                name,
+            }
+        })
+        .collect()
+}
+
+fn tape_name(header: &[u8]) -> [u8; 24] {
+    let mut name = [0; 24];
+    name.copy_from_slice(&header[0x28..0x40]);
+    name
+}
+
+impl T64 {
+    /// Parses a T64 archive, trusting its header.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::InvalidSignature`] unless the file starts with
+    /// `C64`, and [`ImageError::Truncated`] if the directory or a file
+    /// extends beyond its end.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let header = header(bytes)?;
+        let slots = usize::from(u16_at(header, 0x22));
+        if bytes.len() < HEADER_SIZE + slots * ENTRY_SIZE {
+            return Err(ImageError::Truncated);
+        }
+        let files = entries(bytes, slots)
+            .into_iter()
+            .map(|entry| {
+                let length = usize::from(entry.end.wrapping_sub(entry.start));
+                let data = bytes
+                    .get(entry.offset..entry.offset + length)
+                    .ok_or(ImageError::Truncated)?;
+                Ok(T64File {
+                    entry_type: entry.entry_type,
+                    file_type: entry.file_type,
+                    start: entry.start,
+                    name: entry.name,
+                    data: data.to_vec(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(T64 {
+            version: u16_at(header, 0x20),
+            name: tape_name(header),
+            files,
+        })
+    }
+
+    /// Serializes the archive, with a directory of exactly its files and
+    /// their data in that order behind it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let count = self.files.len() as u16;
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        bytes[0x20..0x22].copy_from_slice(&self.version.to_le_bytes());
+        bytes[0x22..0x24].copy_from_slice(&count.to_le_bytes());
+        bytes[0x24..0x26].copy_from_slice(&count.to_le_bytes());
+        bytes[0x28..0x40].copy_from_slice(&self.name);
+
+        let mut offset = HEADER_SIZE + self.files.len() * ENTRY_SIZE;
+        for file in &self.files {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = file.entry_type;
+            entry[1] = file.file_type;
+            entry[2..4].copy_from_slice(&file.start.to_le_bytes());
+            entry[4..6].copy_from_slice(&file.end().to_le_bytes());
+            entry[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[16..32].copy_from_slice(&file.name);
+            bytes.extend_from_slice(&entry);
+            offset += file.data.len();
+        }
+        for file in &self.files {
+            bytes.extend_from_slice(&file.data);
+        }
+        bytes
+    }
+}
+
+/// A defect [`repair`] corrected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fix {
+    /// The number of directory entries did not fit the directory.
+    DirectorySize { stored: u16, actual: u16 },
+    /// The number of used entries was wrong.
+    EntryCount { stored: u16, actual: u16 },
+    /// The offset of the entry in directory slot `slot` was outside the
+    /// archive, and was worked out from the sizes of the files.
+    Offset {
+        slot: usize,
+        stored: usize,
+        actual: usize,
+    },
+    /// The end address of the entry in `slot` was zero or lay beyond the
+    /// data in the archive, and was worked out from the data.
+    EndAddress {
+        slot: usize,
+        stored: u16,
+        actual: u16,
+    },
+    /// The entry in `slot` pointed outside the archive and was left out.
+    Removed { slot: usize },
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Fix::DirectorySize { stored, actual } => {
+                write!(f, "directory size {stored} corrected to {actual}")
+            }
+            Fix::EntryCount { stored, actual } => {
+                write!(f, "used entries {stored} corrected to {actual}")
+            }
+            Fix::Offset {
+                slot,
+                stored,
+                actual,
+            } => write!(f, "entry {slot}: offset {stored} corrected to {actual}"),
+            Fix::EndAddress {
+                slot,
+                stored,
+                actual,
+            } => write!(
+                f,
+                "entry {slot}: end address ${stored:04X} corrected to ${actual:04X}"
+            ),
+            Fix::Removed { slot } => write!(f, "entry {slot}: outside the archive, removed"),
+        }
+    }
+}
+
+/// Parses a T64 archive, correcting the header where the data contradicts
+/// it, and returns the archive with a list of what was corrected.
+///
+/// The directory is assumed to end where the first file starts if its size
+/// is missing or too large. An offset outside the archive is replaced by
+/// laying the files out one after the other behind the directory, if their
+/// sizes add up to the archive; otherwise the entry is removed. A file's
+/// size is taken from the distance to the next file where its end address
+/// is zero or lies beyond that.
+///
+/// # Errors
+/// Returns [`ImageError::InvalidSignature`] unless the file starts with
+/// `C64`, and [`ImageError::Truncated`] if it ends inside the header.
+pub fn repair(bytes: &[u8]) -> Result<(T64, Vec<Fix>), ImageError> {
+    let header = header(bytes)?;
+    let stored_slots = u16_at(header, 0x22);
+    let used = u16_at(header, 0x24);
+    let mut fixes = Vec::new();
+
+    // The directory: the stored size if it fits before the data, else as
+    // many slots as lie before the first plausible offset.
+    let room = (bytes.len() - HEADER_SIZE) / ENTRY_SIZE;
+    let first_data = bytes[HEADER_SIZE..]
+        .chunks_exact(ENTRY_SIZE)
+        .take(room)
+        .take_while(|entry| entry[0] != 0 || entry.iter().any(|&b| b != 0))
+        .map(|entry| u32_at(entry, 8) as usize)
+        .filter(|&offset| offset >= HEADER_SIZE + ENTRY_SIZE && offset < bytes.len())
+        .min()
+        .unwrap_or(bytes.len());
+    let fitting = (first_data - HEADER_SIZE) / ENTRY_SIZE;
+    let mut slots = usize::from(stored_slots);
+    if slots == 0 || slots > fitting {
+        slots = fitting.max(1).min(room);
+        fixes.push(Fix::DirectorySize {
+            stored: stored_slots,
+            actual: slots as u16,
+        });
+    }
+    let mut entries = entries(bytes, slots);
+    if usize::from(used) != entries.len() {
+        fixes.push(Fix::EntryCount {
+            stored: used,
+            actual: entries.len() as u16,
+        });
+    }
+
+    // Offsets must point behind the directory and into the archive.
+    let directory_end = HEADER_SIZE + slots * ENTRY_SIZE;
+    let valid = |offset: usize| offset >= directory_end && offset < bytes.len();
+    if entries.iter().any(|entry| !valid(entry.offset)) {
+        let lengths: Vec<usize> = entries
+            .iter()
+            .map(|entry| usize::from(entry.end.wrapping_sub(entry.start)))
+            .collect();
+        if directory_end + lengths.iter().sum::<usize>() == bytes.len() {
+            let mut offset = directory_end;
+            for (entry, length) in entries.iter_mut().zip(lengths) {
+                if entry.offset != offset {
+                    fixes.push(Fix::Offset {
+                        slot: entry.slot,
+                        stored: entry.offset,
+                        actual: offset,
+                    });
+                    entry.offset = offset;
+                }
+                offset += length;
+            }
+        } else {
+            entries.retain(|entry| {
+                let keep = valid(entry.offset);
+                if !keep {
+                    fixes.push(Fix::Removed { slot: entry.slot });
+                }
+                keep
+            });
+        }
+    }
+
+    // Each file can reach up to the next one.
+    let mut offsets: Vec<usize> = entries.iter().map(|entry| entry.offset).collect();
+    offsets.push(bytes.len());
+    offsets.sort_unstable();
+    let files = entries
+        .into_iter()
+        .map(|mut entry| {
+            let next = offsets
+                .iter()
+                .copied()
+                .find(|&offset| offset > entry.offset)
+                .unwrap_or(bytes.len());
+            let available = next - entry.offset;
+            let length = usize::from(entry.end.wrapping_sub(entry.start));
+            if entry.end == 0 || entry.end < entry.start || length > available {
+                let actual = entry.start.wrapping_add(available.min(0xFFFF) as u16);
+                fixes.push(Fix::EndAddress {
+                    slot: entry.slot,
+                    stored: entry.end,
+                    actual,
+                });
+                entry.end = actual;
+            }
+            let length = usize::from(entry.end.wrapping_sub(entry.start));
+            T64File {
+                entry_type: entry.entry_type,
+                file_type: entry.file_type,
+                start: entry.start,
+                name: entry.name,
+                data: bytes[entry.offset..entry.offset + length].to_vec(),
+            }
+        })
+        .collect();
+    Ok((
+        T64 {
+            version: u16_at(header, 0x20),
+            name: tape_name(header),
+            files,
+        },
+        fixes,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> T64 {
+        let file = |name: &[u8], start: u16, length: usize, fill: u8| {
+            T64File::from_prg(name, &Prg::new(start, vec![fill; length]))
+        };
+        T64 {
+            version: 0x0101,
+            name: *b"GAMES                   ",
+            files: vec![
+                file(b"FIRST", 0x0801, 300, 0xAA),
+                file(b"SECOND", 0xC000, 40, 0x55),
+            ],
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_archives() {
+        let t64 = archive();
+        let bytes = t64.to_bytes();
+        assert_eq!(bytes.len(), 0x40 + 2 * 32 + 340);
+        assert_eq!(u32_at(&bytes, 0x40 + 32 + 8), 0x40 + 64 + 300);
+        assert_eq!(T64::from_bytes(&bytes).unwrap(), t64);
+        assert_eq!(t64.files[1].prg().load, 0xC000);
+        assert_eq!(t64.files[1].name, *b"SECOND          ");
+        assert_eq!(repair(&bytes).unwrap(), (t64, Vec::new()));
+        assert_eq!(T64::from_bytes(&bytes[..400]), Err(ImageError::Truncated));
+        assert_eq!(T64::from_bytes(b"T64"), Err(ImageError::InvalidSignature));
+    }
+
+    #[test]
+    fn repairs_broken_headers() {
+        let t64 = archive();
+        let mut bytes = t64.to_bytes();
+        // No used entries, a zero end address and the infamous $C3C6.
+        bytes[0x24] = 0;
+        bytes[0x40 + 4..0x40 + 6].copy_from_slice(&[0, 0]);
+        bytes[0x60 + 4..0x60 + 6].copy_from_slice(&[0xC6, 0xC3]);
+        assert_eq!(T64::from_bytes(&bytes), Err(ImageError::Truncated));
+        let (repaired, fixes) = repair(&bytes).unwrap();
+        assert_eq!(repaired, t64);
+        assert_eq!(
+            fixes,
+            [
+                Fix::EntryCount {
+                    stored: 0,
+                    actual: 2
+                },
+                Fix::EndAddress {
+                    slot: 0,
+                    stored: 0,
+                    actual: 0x0801 + 300
+                },
+                Fix::EndAddress {
+                    slot: 1,
+                    stored: 0xC3C6,
+                    actual: 0xC000 + 40
+                },
+            ]
+        );
+
+        // A directory too large for the data and an offset pointing nowhere.
+        let mut bytes = t64.to_bytes();
+        bytes[0x22] = 30;
+        bytes[0x60 + 8..0x60 + 12].copy_from_slice(&0x10000u32.to_le_bytes());
+        let (repaired, fixes) = repair(&bytes).unwrap();
+        assert_eq!(repaired, t64);
+        assert_eq!(
+            fixes,
+            [
+                Fix::DirectorySize {
+                    stored: 30,
+                    actual: 2
+                },
+                Fix::Offset {
+                    slot: 1,
+                    stored: 0x10000,
+                    actual: 0x40 + 64 + 300
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! TAP files: the pulses of a Datasette tape.
+//!
+//! The Datasette records no bits but square waves, and a TAP file keeps
+//! the length of every pulse the tape delivers to the cassette port, in
+//! CPU cycles. How the pulses encode data is up to the loader; [`kernal`]
+//! decodes the format of the ROM routines, [`turbo`] those of turbo
+//! loaders, and [`extract`] runs both to move a tape to an archive or a
+//! disk.
+//!
+//! ```plaintext
+//! $00  "C64-TAPE-RAW"
+//! $0C  version
+//! $0D  machine
+//! $0E  video standard
+//! $0F  reserved
+//! $10  length of the pulse data (u32, little endian)
+//! $14  pulse data
+//! ```
+//!
+//! Each byte of pulse data is a pulse length divided by eight. A zero byte
+//! stands for a pause: in version 0 any pulse longer than 255 units, in
+//! versions 1 and 2 followed by the exact length in cycles in three bytes,
+//! little endian. Version 2 files, as the C16 and Plus/4 tools write them,
+//! hold half waves instead of pulses, the high and the low half apiece.
+//!
+//! Pulse lengths count the cycles of the machine the tape was recorded
+//! for. [`Tap::normalized`] turns them into whole pulses in cycles of a
+//! PAL C64, which the decoders expect, whatever the machine.
+
+pub mod extract;
+pub mod kernal;
+pub mod turbo;
+
+use crate::image::ImageError;
+use alloc::{vec, vec::Vec};
+
+/// The signature a TAP file starts with.
+pub const SIGNATURE: &[u8; 12] = b"C64-TAPE-RAW";
+/// The length of the header.
+pub const HEADER_SIZE: usize = 0x14;
+/// The clock of a PAL C64 in Hz, to which [`Tap::normalized`] scales.
+pub const REFERENCE_CLOCK: u64 = 985_248;
+
+/// The machine a tape was recorded on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Machine {
+    #[default]
+    C64,
+    Vic20,
+    /// The C16 and Plus/4.
+    C16,
+}
+
+impl Machine {
+    /// Returns the machine of a TAP header byte.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Machine::C64),
+            1 => Some(Machine::Vic20),
+            2 => Some(Machine::C16),
+            _ => None,
+        }
+    }
+
+    /// Returns the TAP header byte of the machine.
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the CPU clock of the machine in Hz.
+    pub fn clock(self, video: Video) -> u64 {
+        match (self, video) {
+            (Machine::C64, Video::Pal) => REFERENCE_CLOCK,
+            (Machine::C64, Video::Ntsc) => 1_022_730,
+            (Machine::Vic20, Video::Pal) => 1_108_405,
+            (Machine::Vic20, Video::Ntsc) => 1_022_727,
+            (Machine::C16, Video::Pal) => 886_724,
+            (Machine::C16, Video::Ntsc) => 894_886,
+        }
+    }
+}
+
+/// The video standard of the machine, which sets its clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Video {
+    #[default]
+    Pal,
+    Ntsc,
+}
+
+/// The pulses of a tape.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tap {
+    /// The version of the file format, 0 to 2.
+    pub version: u8,
+    /// The machine the tape was recorded on.
+    pub machine: Machine,
+    /// The video standard of the machine.
+    pub video: Video,
+    /// The pulse lengths in cycles of the machine, half waves in a
+    /// version 2 file.
+    pub pulses: Vec<u32>,
+}
+
+impl Tap {
+    /// Creates an empty version 1 tape for a PAL C64.
+    pub fn new() -> Self {
+        Tap {
+            version: 1,
+            ..Tap::default()
+        }
+    }
+
+    /// Parses a TAP file.
+    ///
+    /// # Errors
+    /// Returns [`ImageError::InvalidSignature`] without the signature,
+    /// [`ImageError::Unsupported`] for other versions than 0 to 2 and
+    /// unknown machines, and [`ImageError::Truncated`] if the pulse data
+    /// ends early.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(ImageError::InvalidSignature);
+        }
+        let header = bytes.get(..HEADER_SIZE).ok_or(ImageError::Truncated)?;
+        let version = header[0x0C];
+        if version > 2 {
+            return Err(ImageError::Unsupported("TAP version"));
+        }
+        let machine =
+            Machine::from_byte(header[0x0D]).ok_or(ImageError::Unsupported("TAP machine"))?;
+        let video = if header[0x0E] == 0 {
+            Video::Pal
+        } else {
+            Video::Ntsc
+        };
+        let length = u32::from_le_bytes([header[0x10], header[0x11], header[0x12], header[0x13]]);
+        let data = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + length as usize)
+            .ok_or(ImageError::Truncated)?;
+
+        let mut pulses = Vec::with_capacity(data.len());
+        let mut bytes = data.iter();
+        while let Some(&value) = bytes.next() {
+            let cycles = match (value, version) {
+                (0, 0) => 256 * 8,
+                (0, _) => {
+                    let mut long = [0; 4];
+                    for byte in &mut long[..3] {
+                        *byte = *bytes.next().ok_or(ImageError::Truncated)?;
+                    }
+                    u32::from_le_bytes(long)
+                }
+                (value, _) => u32::from(value) * 8,
+            };
+            pulses.push(cycles);
+        }
+        Ok(Tap {
+            version,
+            machine,
+            video,
+            pulses,
+        })
+    }
+
+    /// Serializes the tape into TAP file form.
+    ///
+    /// Pulse lengths are rounded to units of eight cycles; pauses in a
+    /// version 0 file lose their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.pulses.len());
+        for &cycles in &self.pulses {
+            match (cycles + 4) / 8 {
+                value @ 1..=255 => data.push(value as u8),
+                0 => data.push(1),
+                _ if self.version == 0 => data.push(0),
+                _ => {
+                    let long = cycles.min(0xFF_FFFF).to_le_bytes();
+                    data.extend_from_slice(&[0, long[0], long[1], long[2]]);
+                }
+            }
+        }
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        bytes[0x0C] = self.version;
+        bytes[0x0D] = self.machine.to_byte();
+        bytes[0x0E] = self.video as u8;
+        bytes[0x10..HEADER_SIZE].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    /// Returns whether the pulses are half waves.
+    pub fn is_halfwave(&self) -> bool {
+        self.version == 2
+    }
+
+    /// Returns the pulses as whole pulses in cycles of a PAL C64.
+    ///
+    /// Half waves are joined in pairs; a lone half wave at the end is
+    /// dropped.
+    pub fn normalized(&self) -> Vec<u32> {
+        let clock = self.machine.clock(self.video);
+        let scale =
+            |cycles: u64| (cycles * REFERENCE_CLOCK / clock).min(u64::from(u32::MAX)) as u32;
+        if self.is_halfwave() {
+            self.pulses
+                .chunks_exact(2)
+                .map(|pair| scale(u64::from(pair[0]) + u64::from(pair[1])))
+                .collect()
+        } else {
+            self.pulses
+                .iter()
+                .map(|&cycles| scale(u64::from(cycles)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_both_versions() {
+        let mut file = SIGNATURE.to_vec();
+        file.extend_from_slice(&[1, 0, 0, 0, 6, 0, 0, 0]);
+        file.extend_from_slice(&[0x30, 0x56, 0, 0x40, 0x42, 0x0F]);
+        let tap = Tap::from_bytes(&file).unwrap();
+        assert_eq!(tap.version, 1);
+        assert_eq!(tap.pulses, [0x180, 0x2B0, 0x0F4240]);
+        assert_eq!(tap.to_bytes(), file);
+
+        file[0x0C] = 0;
+        let tap = Tap::from_bytes(&file).unwrap();
+        assert_eq!(tap.pulses, [0x180, 0x2B0, 0x800, 0x200, 0x210, 0x78]);
+        assert_eq!(tap.to_bytes(), file);
+
+        file[0x0C] = 1;
+        assert_eq!(Tap::from_bytes(&file[..24]), Err(ImageError::Truncated));
+        file[0x10] = 4;
+        assert_eq!(Tap::from_bytes(&file[..23]), Err(ImageError::Truncated));
+        assert_eq!(
+            Tap::from_bytes(b"C64-TAPE-XXX"),
+            Err(ImageError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn normalizes_halfwaves_of_other_machines() {
+        let prg = crate::prg::Prg::new(0x1001, b"PLUS/4 PROGRAM".to_vec());
+        let c64 = kernal::master(b"HALF", &prg).unwrap();
+
+        // The same tape as a C16 would record it, in half waves.
+        let clock = Machine::C16.clock(Video::Pal);
+        let mut tap = Tap {
+            version: 2,
+            machine: Machine::C16,
+            video: Video::Pal,
+            pulses: Vec::new(),
+        };
+        for &cycles in &c64.pulses {
+            let half = (u64::from(cycles) * clock / REFERENCE_CLOCK / 2) as u32;
+            tap.pulses.extend([half, half]);
+        }
+        let bytes = tap.to_bytes();
+        assert_eq!(bytes[0x0C..0x0F], [2, 2, 0]);
+        let tap = Tap::from_bytes(&bytes).unwrap();
+        assert!(tap.is_halfwave());
+        let normalized = tap.normalized();
+        assert_eq!(normalized.len(), c64.pulses.len());
+        assert!(
+            normalized
+                .iter()
+                .zip(&c64.pulses)
+                .all(|(&a, &b)| a.abs_diff(b) < 16)
+        );
+
+        let records = kernal::records(&tap, &kernal::Thresholds::KERNAL);
+        let programs = kernal::programs(&records);
+        assert_eq!(programs.len(), 1);
+        assert_eq!(programs[0].1, prg);
+
+        let mut bytes = bytes;
+        bytes[0x0D] = 3;
+        assert_eq!(
+            Tap::from_bytes(&bytes),
+            Err(ImageError::Unsupported("TAP machine"))
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! From tape to disk in one call.
+//!
+//! [`extract`] runs a tape through the Kernal decoder and every turbo
+//! format of a [`Registry`] and returns the programs on it in the order
+//! they were recorded. [`to_t64`] packs them into an archive,
+//! [`to_d64`] saves them to a fresh disk.
+
+use crate::d64::D64;
+use crate::error::DosError;
+use crate::fs::{self, FileType, NAME_LENGTH};
+use crate::prg::Prg;
+use crate::t64::{T64, T64File};
+use crate::tap::Tap;
+use crate::tap::kernal::{self, HeaderKind, RecordKind, Thresholds};
+use crate::tap::turbo::Registry;
+use alloc::{format, vec::Vec};
+
+/// The loader a program was recorded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Loader {
+    /// The Kernal's own routines.
+    Kernal,
+    /// The turbo format of this name.
+    Turbo(&'static str),
+}
+
+/// A program recovered from a tape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeFile {
+    /// The name, padded with spaces; blank if the format stores none.
+    pub name: [u8; 16],
+    /// The loader the program was recorded for.
+    pub loader: Loader,
+    /// The normalized pulse the program starts at.
+    pub pulse: usize,
+    /// The program.
+    pub prg: Prg,
+    /// Whether the check bytes match the data.
+    pub verified: bool,
+}
+
+/// Returns every program on `tap`, decoded with `thresholds` in the Kernal
+/// format and in the formats of `registry`, in the order they are on the
+/// tape.
+///
+/// Data files are left out: they hold no program and have no load address.
+pub fn extract(tap: &Tap, thresholds: &Thresholds, registry: &Registry) -> Vec<TapeFile> {
+    let records = kernal::records(tap, thresholds);
+    let mut files: Vec<TapeFile> = records
+        .windows(2)
+        .filter_map(|pair| match (&pair[0].kind, &pair[1].kind) {
+            (RecordKind::Header(header), RecordKind::Program(data))
+                if header.kind != HeaderKind::DataFile =>
+            {
+                Some(TapeFile {
+                    name: header.name,
+                    loader: Loader::Kernal,
+                    pulse: pair[0].pulse,
+                    prg: header.prg(data),
+                    verified: pair[0].verified && pair[1].verified,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    files.extend(registry.decode(tap).into_iter().map(|file| TapeFile {
+        name: file.name.unwrap_or([b' '; 16]),
+        loader: Loader::Turbo(file.format),
+        pulse: file.pulse,
+        prg: file.prg(),
+        verified: file.verified,
+    }));
+    files.sort_by_key(|file| file.pulse);
+    files
+}
+
+/// Packs `files` into a T64 archive named `name`, cut to 24 bytes and
+/// padded with spaces.
+pub fn to_t64(files: &[TapeFile], name: &[u8]) -> T64 {
+    let mut padded = [b' '; 24];
+    let length = name.len().min(24);
+    padded[..length].copy_from_slice(&name[..length]);
+    T64 {
+        version: 0x0100,
+        name: padded,
+        files: files
+            .iter()
+            .map(|file| T64File::from_prg(&file.name, &file.prg))
+            .collect(),
+    }
+}
+
+/// Saves `files` as PRG files to a newly formatted 35-track disk.
+///
+/// Trailing spaces are dropped from the names. Files whose name is already
+/// on the disk get their number on the tape, counting from 1, appended to
+/// the name, so none is lost; files without a name are called `FILE` and
+/// their number.
+///
+/// # Errors
+/// Returns [`DosError::DiskFull`] if the files do not fit on the disk.
+pub fn to_d64(files: &[TapeFile], name: &[u8], id: [u8; 2]) -> Result<D64, DosError> {
+    let mut image = D64::new(35);
+    fs::format(&mut image, name, Some(id))?;
+    let mut names: Vec<Vec<u8>> = Vec::with_capacity(files.len());
+    for (number, file) in (1..).zip(files) {
+        let end = file
+            .name
+            .iter()
+            .rposition(|&b| b != b' ')
+            .map_or(0, |i| i + 1);
+        let mut name = match &file.name[..end] {
+            [] => b"FILE".to_vec(),
+            name => name.to_vec(),
+        };
+        if end == 0 || names.iter().any(|taken| fs::same_name(&name, taken)) {
+            let suffix = format!(" {number}");
+            name.truncate(NAME_LENGTH - suffix.len());
+            name.extend_from_slice(suffix.as_bytes());
+        }
+        fs::write_file(&mut image, &name, FileType::Prg, &file.prg.to_bytes())?;
+        names.push(name);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::BlockDevice;
+
+    #[test]
+    fn extracts_kernal_programs_to_archives_and_disks() {
+        let game = Prg::new(0x0801, (0..600u32).map(|i| i as u8).collect());
+        let intro = Prg::new(0xC000, b"\xA9\x00\x60".to_vec());
+        let mut tap = kernal::master(b"GAME", &game).unwrap();
+        kernal::write_program(&mut tap, b"GAME", &intro, HeaderKind::Program).unwrap();
+        kernal::write_program(&mut tap, b"", &intro, HeaderKind::Program).unwrap();
+        let tap = Tap::from_bytes(&tap.to_bytes()).unwrap();
+
+        let files = extract(&tap, &Thresholds::KERNAL, &Registry::standard());
+        assert_eq!(files.len(), 3);
+        assert!(
+            files
+                .iter()
+                .all(|f| f.verified && f.loader == Loader::Kernal)
+        );
+        assert!(files.windows(2).all(|pair| pair[0].pulse < pair[1].pulse));
+        assert_eq!(
+            (&files[0].name, &files[0].prg),
+            (b"GAME            ", &game)
+        );
+
+        let t64 = T64::from_bytes(&to_t64(&files, b"COMPILATION").to_bytes()).unwrap();
+        assert_eq!(&t64.name[..12], b"COMPILATION ");
+        assert_eq!(t64.files[1].prg(), intro);
+
+        let image = to_d64(&files, b"FROM TAPE", *b"TP").unwrap();
+        let dir = fs::read_directory(&image).unwrap();
+        let names: Vec<&[u8]> = dir.entries.iter().map(|e| e.name()).collect();
+        assert_eq!(names, [&b"GAME"[..], b"GAME 2", b"FILE 3"]);
+        assert!(dir.entries.iter().all(|e| e.file_type == FileType::Prg));
+        let saved = fs::read_file(&image, &dir.entries[0]).unwrap();
+        assert_eq!(Prg::from_bytes(&saved).unwrap(), game);
+        assert_eq!(image.tracks(), 35);
+    }
+
+    #[test]
+    fn reports_full_disks() {
+        let big = TapeFile {
+            name: *b"BIG             ",
+            loader: Loader::Turbo("Turbo Tape 64"),
+            pulse: 0,
+            prg: Prg::new(0x0801, alloc::vec![0; 0xF000]),
+            verified: true,
+        };
+        let files = [big.clone(), big.clone(), big.clone(), big];
+        assert_eq!(to_d64(&files, b"X", *b"XX").err(), Some(DosError::DiskFull));
+    }
+}
@@ -0,0 +1,653 @@
+//! The tape format of the Kernal ROM.
+//!
+//! The ROM routines write three pulse lengths, short, medium and long.
+//! Every byte starts with a long and a medium pulse, followed by its eight
+//! bits from the lowest on and an odd parity bit, each a pair of pulses:
+//! short–medium for a 0, medium–short for a 1. A long and a short pulse
+//! end a block.
+//!
+//! A block starts after a leader of short pulses with a countdown,
+//! `$89` to `$81`, then come the data bytes and their XOR as a check
+//! byte. The Kernal writes every block twice; the repeat counts down from
+//! `$09` to `$01`, so a damaged byte of one copy can be taken from the
+//! other.
+//!
+//! A program takes two pairs: a header of 192 bytes with its type, load
+//! address, end address and name, and the program itself. [`records`]
+//! reads them back from a tape, [`master`] and [`write_program`] write
+//! them with the Kernal's leaders.
+
+use crate::image::ImageError;
+use crate::prg::Prg;
+use crate::tap::{REFERENCE_CLOCK, Tap};
+use alloc::{vec, vec::Vec};
+
+/// The nominal length of a short pulse in cycles.
+pub const SHORT: u32 = 0x30 * 8;
+/// The nominal length of a medium pulse in cycles.
+pub const MEDIUM: u32 = 0x42 * 8;
+/// The nominal length of a long pulse in cycles.
+pub const LONG: u32 = 0x56 * 8;
+/// The size of a header block.
+pub const HEADER_SIZE: usize = 192;
+/// How many short pulses in a row make a leader.
+pub const MIN_LEADER: usize = 32;
+
+const COUNTDOWN: usize = 9;
+/// The clock of an NTSC C64 in Hz.
+const NTSC_CLOCK: u32 = 1_022_730;
+
+/// The three pulse lengths of the Kernal format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pulse {
+    Short,
+    Medium,
+    Long,
+}
+
+/// The bounds between the pulse lengths, in cycles.
+///
+/// Tapes recorded on a Datasette whose motor ran a little fast or slow
+/// decode with bounds moved accordingly: [`Thresholds::scaled`] moves them
+/// by a known factor, [`Thresholds::calibrated`] by what the leaders of a
+/// tape measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Thresholds {
+    /// Shorter pulses are noise.
+    pub min: u32,
+    /// The bound between short and medium pulses.
+    pub short_medium: u32,
+    /// The bound between medium and long pulses.
+    pub medium_long: u32,
+    /// Longer pulses are pauses.
+    pub max: u32,
+}
+
+impl Thresholds {
+    /// The bounds halfway between the nominal lengths.
+    pub const KERNAL: Thresholds = Thresholds {
+        min: 0x20 * 8,
+        short_medium: (SHORT + MEDIUM) / 2,
+        medium_long: (MEDIUM + LONG) / 2,
+        max: 0x70 * 8,
+    };
+
+    /// The bounds for tapes recorded on a PAL C64, the Kernal's own.
+    pub const PAL: Thresholds = Thresholds::KERNAL;
+
+    /// The bounds for tapes recorded on an NTSC C64, whose faster clock
+    /// makes every pulse about 4% shorter in PAL cycles.
+    pub const NTSC: Thresholds = Thresholds::KERNAL.scaled(REFERENCE_CLOCK as u32, NTSC_CLOCK);
+
+    /// Wide bounds for worn tapes, whose pulses spread far around their
+    /// nominal lengths.
+    pub const WORN: Thresholds = Thresholds::new(SHORT, MEDIUM, LONG, 0x1C * 8);
+
+    /// Returns bounds for pulses of the nominal lengths `short`, `medium`
+    /// and `long`: halfway between them, and `jitter` below the short and
+    /// above the long one.
+    pub const fn new(short: u32, medium: u32, long: u32, jitter: u32) -> Self {
+        Thresholds {
+            min: short.saturating_sub(jitter),
+            short_medium: (short + medium) / 2,
+            medium_long: (medium + long) / 2,
+            max: long + jitter,
+        }
+    }
+
+    /// Returns the bounds for pulses `numerator / denominator` times as
+    /// long, as a tape recorded that much slower delivers them.
+    pub const fn scaled(self, numerator: u32, denominator: u32) -> Self {
+        Thresholds {
+            min: scale(self.min, numerator, denominator),
+            short_medium: scale(self.short_medium, numerator, denominator),
+            medium_long: scale(self.medium_long, numerator, denominator),
+            max: scale(self.max, numerator, denominator),
+        }
+    }
+
+    /// Returns the bounds scaled to the speed of the tape whose
+    /// [normalized](Tap::normalized) pulses are `pulses`, measured on its
+    /// first leader of [`MIN_LEADER`] pulses these bounds take as short.
+    ///
+    /// A tape without such leader keeps the bounds as they are.
+    pub fn calibrated(&self, pulses: &[u32]) -> Self {
+        let short = |&cycles: &u32| self.classify(cycles) == Some(Pulse::Short);
+        let mut at = 0;
+        while at < pulses.len() {
+            let length = pulses[at..].iter().take_while(|c| short(c)).count();
+            if length >= MIN_LEADER {
+                let leader = &pulses[at..at + length];
+                let sum: u64 = leader.iter().map(|&c| u64::from(c)).sum();
+                return self.scaled((sum / length as u64) as u32, SHORT);
+            }
+            at += length + 1;
+        }
+        *self
+    }
+
+    /// Returns the pulse `cycles` long, or `None` for noise and pauses.
+    pub fn classify(&self, cycles: u32) -> Option<Pulse> {
+        match cycles {
+            c if c < self.min || c > self.max => None,
+            c if c < self.short_medium => Some(Pulse::Short),
+            c if c < self.medium_long => Some(Pulse::Medium),
+            _ => Some(Pulse::Long),
+        }
+    }
+}
+
+const fn scale(cycles: u32, numerator: u32, denominator: u32) -> u32 {
+    (cycles as u64 * numerator as u64 / denominator as u64) as u32
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds::KERNAL
+    }
+}
+
+/// One copy of a block as read from the tape.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    /// The pulse the leader of the block ended at.
+    pub pulse: usize,
+    /// Whether this is the repeated copy.
+    pub repeat: bool,
+    /// The data bytes, without countdown and check byte.
+    pub data: Vec<u8>,
+    /// The check byte as read.
+    pub checksum: u8,
+    /// The data bytes whose parity bit is wrong.
+    pub parity_errors: Vec<usize>,
+    /// Whether the block ended with the end-of-data marker.
+    pub complete: bool,
+}
+
+impl Block {
+    /// Returns whether every byte has the right parity and the check byte
+    /// matches the data.
+    pub fn is_valid(&self) -> bool {
+        self.complete && self.parity_errors.is_empty() && xor(&self.data) == self.checksum
+    }
+}
+
+fn xor(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum ^ byte)
+}
+
+/// What a byte position of a block holds.
+enum Read {
+    /// A byte, and whether its parity is right.
+    Byte(u8, bool),
+    /// The end-of-data marker.
+    End,
+    /// Anything else.
+    Invalid,
+}
+
+/// Reads the byte starting at `pulses[0]`.
+fn read_byte(pulses: &[Option<Pulse>]) -> Read {
+    use Pulse::{Long, Medium, Short};
+    match pulses {
+        [Some(Long), Some(Short), ..] => Read::End,
+        [Some(Long), Some(Medium), rest @ ..] if rest.len() >= 18 => {
+            let mut bits = rest[..18].chunks_exact(2).map(|pair| match pair {
+                [Some(Short), Some(Medium)] => Some(false),
+                [Some(Medium), Some(Short)] => Some(true),
+                _ => None,
+            });
+            let mut byte = 0;
+            for bit in 0..8 {
+                match bits.next().flatten() {
+                    Some(one) => byte |= u8::from(one) << bit,
+                    None => return Read::Invalid,
+                }
+            }
+            match bits.next().flatten() {
+                Some(parity) => Read::Byte(byte, parity != (byte.count_ones() % 2 == 1)),
+                None => Read::Invalid,
+            }
+        }
+        _ => Read::Invalid,
+    }
+}
+
+/// Finds every block in `pulses`.
+///
+/// A block is taken where a leader is followed by a full countdown; it
+/// ends at the end-of-data marker or at the first pulses that are neither.
+pub fn blocks(pulses: &[u32], thresholds: &Thresholds) -> Vec<Block> {
+    let pulses: Vec<Option<Pulse>> = pulses.iter().map(|&c| thresholds.classify(c)).collect();
+    let mut found = Vec::new();
+    let mut leader = 0;
+    let mut at = 0;
+    while at < pulses.len() {
+        if pulses[at] == Some(Pulse::Short) {
+            leader += 1;
+            at += 1;
+            continue;
+        }
+        if leader < MIN_LEADER {
+            leader = 0;
+            at += 1;
+            continue;
+        }
+        leader = 0;
+
+        let start = at;
+        let mut bytes = Vec::new();
+        let mut bad = Vec::new();
+        let mut complete = false;
+        loop {
+            match read_byte(&pulses[at..]) {
+                Read::Byte(byte, parity) => {
+                    if !parity {
+                        bad.push(bytes.len());
+                    }
+                    bytes.push(byte);
+                    at += 20;
+                }
+                Read::End => {
+                    complete = true;
+                    at += 2;
+                    break;
+                }
+                Read::Invalid => break,
+            }
+        }
+        let repeat = match bytes.first() {
+            Some(0x89) => false,
+            Some(0x09) => true,
+            _ => {
+                at = start + 1;
+                continue;
+            }
+        };
+        let first = bytes[0];
+        if bytes.len() <= COUNTDOWN || (0..COUNTDOWN).any(|i| bytes[i] != first - i as u8) {
+            at = start + 1;
+            continue;
+        }
+        let end = bytes.len() - 1;
+        found.push(Block {
+            pulse: start,
+            repeat,
+            data: bytes[COUNTDOWN..end].to_vec(),
+            checksum: bytes[end],
+            parity_errors: bad
+                .into_iter()
+                .filter(|i| (COUNTDOWN..end).contains(i))
+                .map(|i| i - COUNTDOWN)
+                .collect(),
+            complete,
+        });
+    }
+    found
+}
+
+/// The type of a header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeaderKind {
+    /// A program loaded to the start of BASIC unless a secondary address
+    /// of 1 is given, type 1.
+    RelocatableProgram,
+    /// A program always loaded to its address, type 3.
+    Program,
+    /// A sequential data file, type 4.
+    DataFile,
+}
+
+/// A header block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeHeader {
+    /// The type of the file.
+    pub kind: HeaderKind,
+    /// The load address.
+    pub start: u16,
+    /// The address behind the last byte.
+    pub end: u16,
+    /// The file name, padded with spaces.
+    pub name: [u8; 16],
+    /// The rest of the header, free for the program's own use.
+    pub body: Vec<u8>,
+}
+
+impl HeaderKind {
+    /// Returns the type byte of the header.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            HeaderKind::RelocatableProgram => 1,
+            HeaderKind::Program => 3,
+            HeaderKind::DataFile => 4,
+        }
+    }
+}
+
+impl TapeHeader {
+    /// Returns the header block, padded with spaces to [`HEADER_SIZE`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.kind.to_byte()];
+        bytes.extend_from_slice(&self.start.to_le_bytes());
+        bytes.extend_from_slice(&self.end.to_le_bytes());
+        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(&self.body);
+        bytes.resize(HEADER_SIZE, b' ');
+        bytes
+    }
+
+    /// Returns `data` as a program loading to the address of the header,
+    /// as many bytes of it as the header announces.
+    pub fn prg(&self, data: &[u8]) -> Prg {
+        let length = usize::from(self.end.wrapping_sub(self.start)).min(data.len());
+        Prg::new(self.start, data[..length].to_vec())
+    }
+}
+
+/// The contents of a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordKind {
+    /// The header of a program or data file.
+    Header(TapeHeader),
+    /// The program following a program header.
+    Program(Vec<u8>),
+    /// A block of a data file, type 2, without the type byte.
+    Data(Vec<u8>),
+    /// The end-of-tape marker, type 5.
+    EndOfTape,
+    /// A block of another type.
+    Unknown(Vec<u8>),
+}
+
+/// A block as recovered from both its copies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeRecord {
+    /// The contents.
+    pub kind: RecordKind,
+    /// The normalized pulse the first copy read starts at.
+    pub pulse: usize,
+    /// Whether the data passed parity and checksum.
+    pub verified: bool,
+}
+
+/// Recovers a block from a copy and its repeat, taking each byte from a
+/// copy that read it with the right parity.
+fn combine(first: &Block, repeat: Option<&Block>) -> (Vec<u8>, bool) {
+    let Some(repeat) = repeat.filter(|r| r.data.len() == first.data.len()) else {
+        return (first.data.clone(), first.is_valid());
+    };
+    if first.is_valid() {
+        return (first.data.clone(), true);
+    }
+    if repeat.is_valid() {
+        return (repeat.data.clone(), true);
+    }
+    let data: Vec<u8> = (0..first.data.len())
+        .map(|i| {
+            if first.parity_errors.contains(&i) {
+                repeat.data[i]
+            } else {
+                first.data[i]
+            }
+        })
+        .collect();
+    let unreadable = first
+        .parity_errors
+        .iter()
+        .any(|i| repeat.parity_errors.contains(i));
+    let checksum = if xor(&data) == first.checksum {
+        first.checksum
+    } else {
+        repeat.checksum
+    };
+    let verified = !unreadable && xor(&data) == checksum;
+    (data, verified)
+}
+
+fn parse(data: Vec<u8>, after_program_header: bool) -> RecordKind {
+    if after_program_header {
+        return RecordKind::Program(data);
+    }
+    let kind = match data.first() {
+        Some(1) => HeaderKind::RelocatableProgram,
+        Some(3) => HeaderKind::Program,
+        Some(4) => HeaderKind::DataFile,
+        Some(2) => return RecordKind::Data(data[1..].to_vec()),
+        Some(5) => return RecordKind::EndOfTape,
+        _ => return RecordKind::Unknown(data),
+    };
+    if data.len() < 21 {
+        return RecordKind::Unknown(data);
+    }
+    let mut name = [0; 16];
+    name.copy_from_slice(&data[5..21]);
+    RecordKind::Header(TapeHeader {
+        kind,
+        start: u16::from_le_bytes([data[1], data[2]]),
+        end: u16::from_le_bytes([data[3], data[4]]),
+        name,
+        body: data[21..].to_vec(),
+    })
+}
+
+/// Decodes the records of a tape in the Kernal format, from its
+/// [normalized](Tap::normalized) pulses.
+///
+/// Each copy is paired with the repeat following it; a repeat without its
+/// first copy stands on its own.
+pub fn records(tap: &Tap, thresholds: &Thresholds) -> Vec<TapeRecord> {
+    let blocks = blocks(&tap.normalized(), thresholds);
+    let mut records = Vec::new();
+    let mut program_header = false;
+    let mut i = 0;
+    while i < blocks.len() {
+        let first = &blocks[i];
+        let repeat = blocks
+            .get(i + 1)
+            .filter(|next| !first.repeat && next.repeat);
+        i += 1 + usize::from(repeat.is_some());
+        let (data, verified) = combine(first, repeat);
+        let kind = parse(data, program_header);
+        program_header = matches!(
+            &kind,
+            RecordKind::Header(header) if header.kind != HeaderKind::DataFile
+        );
+        records.push(TapeRecord {
+            kind,
+            pulse: first.pulse,
+            verified,
+        });
+    }
+    records
+}
+
+/// Returns every program on the tape, with the header it came with.
+pub fn programs(records: &[TapeRecord]) -> Vec<(TapeHeader, Prg)> {
+    records
+        .windows(2)
+        .filter_map(|pair| match (&pair[0].kind, &pair[1].kind) {
+            (RecordKind::Header(header), RecordKind::Program(data)) => {
+                Some((header.clone(), header.prg(data)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// How many short pulses lead a header, about ten seconds.
+pub const HEADER_LEADER: usize = 0x6A00;
+/// How many short pulses lead a program, about two seconds.
+pub const DATA_LEADER: usize = 0x1A00;
+/// How many short pulses lead the repeat of a block.
+pub const REPEAT_LEADER: usize = 0x4F;
+/// How many short pulses follow the repeat of a block.
+pub const TRAILER: usize = 0x4E;
+
+fn write_byte(pulses: &mut Vec<u32>, byte: u8) {
+    pulses.extend([LONG, MEDIUM]);
+    let parity = byte.count_ones().is_multiple_of(2);
+    for one in (0..8).map(|bit| byte >> bit & 1 != 0).chain([parity]) {
+        pulses.extend(if one {
+            [MEDIUM, SHORT]
+        } else {
+            [SHORT, MEDIUM]
+        });
+    }
+}
+
+/// Appends the pulses of `data` as the Kernal writes a block: `leader`
+/// short pulses, the block, the repeat and the trailer.
+pub fn write_block(pulses: &mut Vec<u32>, data: &[u8], leader: usize) {
+    for (countdown, leader) in [(0x80, leader), (0x00, REPEAT_LEADER)] {
+        pulses.extend(core::iter::repeat_n(SHORT, leader));
+        for count in (1..=COUNTDOWN as u8).rev() {
+            write_byte(pulses, countdown | count);
+        }
+        for &byte in data {
+            write_byte(pulses, byte);
+        }
+        write_byte(pulses, xor(data));
+        pulses.extend([LONG, SHORT]);
+    }
+    pulses.extend(core::iter::repeat_n(SHORT, TRAILER));
+}
+
+/// Appends a program to `tap`, as `SAVE` writes it: the header with `name`
+/// and the addresses of `prg`, then the program.
+///
+/// Names are cut to 16 bytes and padded with spaces.
+///
+/// # Errors
+/// Returns [`ImageError::InvalidSize`] with the size of the PRG file if
+/// the program would not end below `$FFFF`.
+pub fn write_program(
+    tap: &mut Tap,
+    name: &[u8],
+    prg: &Prg,
+    kind: HeaderKind,
+) -> Result<(), ImageError> {
+    let end = u16::try_from(prg.end()).map_err(|_| ImageError::InvalidSize(prg.body.len() + 2))?;
+    let mut padded = [b' '; 16];
+    let length = name.len().min(16);
+    padded[..length].copy_from_slice(&name[..length]);
+    let header = TapeHeader {
+        kind,
+        start: prg.load,
+        end,
+        name: padded,
+        body: Vec::new(),
+    };
+    write_block(&mut tap.pulses, &header.to_bytes(), HEADER_LEADER);
+    write_block(&mut tap.pulses, &prg.body, DATA_LEADER);
+    Ok(())
+}
+
+/// Returns a tape holding `prg` under `name`, to be loaded to its address.
+///
+/// # Errors
+/// Returns [`ImageError::InvalidSize`] if `prg` does not fit, see
+/// [`write_program`].
+pub fn master(name: &[u8], prg: &Prg) -> Result<Tap, ImageError> {
+    let mut tap = Tap::new();
+    write_program(&mut tap, name, prg, HeaderKind::Program)?;
+    Ok(tap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_pulses() {
+        let thresholds = Thresholds::KERNAL;
+        assert_eq!(thresholds.classify(SHORT), Some(Pulse::Short));
+        assert_eq!(thresholds.classify(MEDIUM + 40), Some(Pulse::Medium));
+        assert_eq!(thresholds.classify(LONG - 60), Some(Pulse::Long));
+        assert_eq!(thresholds.classify(100), None);
+        assert_eq!(thresholds.classify(256 * 8), None);
+
+        let mut pulses = vec![SHORT; 40];
+        write_byte(&mut pulses, 0x5A);
+        let classified: Vec<_> = pulses[40..]
+            .iter()
+            .map(|&c| thresholds.classify(c))
+            .collect();
+        assert!(matches!(read_byte(&classified), Read::Byte(0x5A, true)));
+    }
+
+    #[test]
+    fn calibrates_to_the_speed_of_a_tape() {
+        assert_eq!(
+            Thresholds::NTSC.classify(SHORT * 24 / 25),
+            Some(Pulse::Short)
+        );
+        assert_eq!(
+            Thresholds::NTSC.classify(MEDIUM * 24 / 25),
+            Some(Pulse::Medium)
+        );
+        for cycles in [SHORT - 200, LONG + 210] {
+            assert_eq!(Thresholds::KERNAL.classify(cycles), None);
+            assert!(Thresholds::WORN.classify(cycles).is_some());
+        }
+        assert_eq!(
+            Thresholds::new(SHORT, MEDIUM, LONG, 0).classify(SHORT - 1),
+            None
+        );
+
+        // A tape played back 18% slower than it was recorded.
+        let prg = Prg::new(0x0801, vec![0x55; 100]);
+        let mut tap = master(b"SLOW", &prg).unwrap();
+        for cycles in &mut tap.pulses {
+            *cycles = *cycles * 118 / 100;
+        }
+        assert!(programs(&records(&tap, &Thresholds::KERNAL)).is_empty());
+        let thresholds = Thresholds::KERNAL.calibrated(&tap.normalized());
+        assert_eq!(
+            thresholds,
+            Thresholds::KERNAL.scaled(SHORT * 118 / 100, SHORT)
+        );
+        assert_eq!(programs(&records(&tap, &thresholds))[0].1, prg);
+        assert_eq!(
+            Thresholds::KERNAL.calibrated(&[SHORT; 8]),
+            Thresholds::KERNAL
+        );
+    }
+
+    #[test]
+    fn masters_and_decodes_programs_from_damaged_copies() {
+        let prg = Prg::new(0x0801, (0..300u32).map(|i| (i * 7) as u8).collect());
+        let mut tap = master(b"GAME", &prg).unwrap();
+        write_block(&mut tap.pulses, &[5], 80);
+        let top = Prg::new(0xFFFF, vec![0]);
+        assert_eq!(master(b"GAME", &top), Err(ImageError::InvalidSize(3)));
+
+        // Swap the pulses of a bit in either copy of the program, in
+        // different bytes.
+        let clean = blocks(&tap.pulses, &Thresholds::KERNAL);
+        let first = clean[2].pulse + 20 * (9 + 10) + 2;
+        let second = clean[3].pulse + 20 * (9 + 20) + 2;
+        tap.pulses.swap(first, first + 1);
+        tap.pulses.swap(second, second + 1);
+
+        let tap = Tap::from_bytes(&tap.to_bytes()).unwrap();
+        let blocks = blocks(&tap.pulses, &Thresholds::KERNAL);
+        assert_eq!(blocks.len(), 6);
+        assert_eq!(blocks[2].parity_errors, [10]);
+        assert_eq!(blocks[3].parity_errors, [20]);
+        assert!(!blocks[2].is_valid() && blocks[3].repeat);
+
+        let records = records(&tap, &Thresholds::KERNAL);
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.verified));
+        assert_eq!(records[2].kind, RecordKind::EndOfTape);
+        let programs = programs(&records);
+        assert_eq!(programs.len(), 1);
+        let (header, decoded) = &programs[0];
+        assert_eq!(
+            (header.kind, &header.name),
+            (HeaderKind::Program, b"GAME            ")
+        );
+        assert_eq!((header.start, header.end), (0x0801, 0x0801 + 300));
+        assert_eq!(decoded, &prg);
+    }
+}
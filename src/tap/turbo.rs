@@ -0,0 +1,380 @@
+//! Turbo tape formats.
+//!
+//! Turbo loaders drop the Kernal's pulse pairs and repeated blocks and
+//! write one pulse per bit: a pulse shorter than the format's threshold is
+//! a 0, a longer one a 1. A block starts with a pilot, the same byte over
+//! and over, then a sync sequence that aligns the reader to the bytes.
+//! What follows depends on the format, see [`Layout`].
+//!
+//! The formats differ in threshold, bit order, pilot and sync, which a
+//! [`TurboFormat`] describes. A [`Registry`] holds the formats to look
+//! for, the [standard](Registry::standard) ones and any number of custom
+//! ones, and decodes a tape with all of them.
+
+use crate::prg::Prg;
+use crate::tap::Tap;
+use alloc::{vec, vec::Vec};
+
+/// How many pilot bytes in a row start a block.
+pub const MIN_PILOT: usize = 8;
+
+/// What a block holds after the sync sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// A header block, type 1 or 2, with load address, end address, one
+    /// spare byte and the name, followed by a data block, type 0, with the
+    /// data and its XOR, as Turbo Tape 64 writes them.
+    TurboTape,
+    /// Load address, end address, the data and its XOR in one block, as
+    /// Freeload writes them.
+    Addressed,
+}
+
+/// The encoding of a turbo format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TurboFormat {
+    /// The name of the format.
+    pub name: &'static str,
+    /// Pulses shorter than this many cycles are noise.
+    pub min: u32,
+    /// Shorter pulses are 0 bits, longer ones 1 bits.
+    pub threshold: u32,
+    /// Pulses longer than this many cycles end a block.
+    pub max: u32,
+    /// Whether bytes are sent from their highest bit on.
+    pub msb_first: bool,
+    /// The byte the pilot repeats.
+    pub pilot: u8,
+    /// The bytes following the pilot.
+    pub sync: &'static [u8],
+    /// The structure of the blocks.
+    pub layout: Layout,
+}
+
+impl TurboFormat {
+    /// Turbo Tape 64.
+    pub const TURBO_TAPE_64: TurboFormat = TurboFormat {
+        name: "Turbo Tape 64",
+        min: 0x10 * 8,
+        threshold: 0x107,
+        max: 0x40 * 8,
+        msb_first: true,
+        pilot: 0x02,
+        sync: &[0x09, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+        layout: Layout::TurboTape,
+    };
+
+    /// Freeload, used for many commercial releases.
+    pub const FREELOAD: TurboFormat = TurboFormat {
+        name: "Freeload",
+        min: 0x18 * 8,
+        threshold: 0x168,
+        max: 0x60 * 8,
+        msb_first: true,
+        pilot: 0x40,
+        sync: &[0x5A],
+        layout: Layout::Addressed,
+    };
+
+    /// Returns the bit a pulse of `cycles` stands for, or `None` outside
+    /// the format's pulses.
+    pub fn bit(&self, cycles: u32) -> Option<bool> {
+        (self.min..=self.max)
+            .contains(&cycles)
+            .then_some(cycles >= self.threshold)
+    }
+
+    fn shift(&self, byte: u8, bit: bool) -> u8 {
+        if self.msb_first {
+            byte << 1 | u8::from(bit)
+        } else {
+            byte >> 1 | u8::from(bit) << 7
+        }
+    }
+
+    /// Returns the byte of the eight pulses at the start of `pulses`.
+    fn byte(&self, pulses: &[u32]) -> Option<u8> {
+        let pulses = pulses.get(..8)?;
+        pulses
+            .iter()
+            .try_fold(0, |byte, &cycles| Some(self.shift(byte, self.bit(cycles)?)))
+    }
+
+    /// Finds the blocks in `pulses`: the pulse each starts at and its bytes
+    /// after the sync sequence.
+    pub fn blocks(&self, pulses: &[u32]) -> Vec<(usize, Vec<u8>)> {
+        let mut found = Vec::new();
+        let mut at = 0;
+        let mut register = 0u8;
+        let mut bits = 0;
+        while at < pulses.len() {
+            let Some(bit) = self.bit(pulses[at]) else {
+                bits = 0;
+                at += 1;
+                continue;
+            };
+            register = self.shift(register, bit);
+            bits += 1;
+            at += 1;
+            if bits < 8 || register != self.pilot {
+                continue;
+            }
+
+            // Aligned to a pilot byte.
+            let start = at - 8;
+            let mut pilots = 1;
+            while self.byte(&pulses[at..]) == Some(self.pilot) {
+                pilots += 1;
+                at += 8;
+            }
+            let synced = self.sync.iter().all(|&sync| {
+                let matches = self.byte(&pulses[at..]) == Some(sync);
+                if matches {
+                    at += 8;
+                }
+                matches
+            });
+            if pilots < MIN_PILOT || !synced {
+                bits = 0;
+                continue;
+            }
+            let mut bytes = Vec::new();
+            while let Some(byte) = self.byte(&pulses[at..]) {
+                bytes.push(byte);
+                at += 8;
+            }
+            found.push((start, bytes));
+            bits = 0;
+        }
+        found
+    }
+}
+
+/// A file recovered from a turbo tape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurboFile {
+    /// The name of the format it was written in.
+    pub format: &'static str,
+    /// The normalized pulse its first block starts at.
+    pub pulse: usize,
+    /// The name, if the format stores one.
+    pub name: Option<[u8; 16]>,
+    /// The load address.
+    pub start: u16,
+    /// The data.
+    pub data: Vec<u8>,
+    /// Whether the check byte matches the data.
+    pub verified: bool,
+}
+
+impl TurboFile {
+    /// Returns the file as a program.
+    pub fn prg(&self) -> Prg {
+        Prg::new(self.start, self.data.clone())
+    }
+}
+
+fn xor(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum ^ byte)
+}
+
+/// Splits `bytes` into `length` data bytes and the check byte following
+/// them, taking all but the last byte if `length` is unknown or too long.
+fn checked(bytes: &[u8], length: Option<usize>) -> (Vec<u8>, bool) {
+    let length = length
+        .filter(|&length| length < bytes.len())
+        .unwrap_or(bytes.len().saturating_sub(1));
+    let data = bytes[..length].to_vec();
+    let verified = bytes.get(length) == Some(&xor(&data));
+    (data, verified)
+}
+
+fn address(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+/// Decodes the files written in `format` on the pulses of a tape.
+fn decode(format: &TurboFormat, pulses: &[u32]) -> Vec<TurboFile> {
+    let mut files = Vec::new();
+    // The header waiting for its data: pulse, name, load and end address.
+    let mut header: Option<(usize, [u8; 16], u16, u16)> = None;
+    for (pulse, bytes) in format.blocks(pulses) {
+        match format.layout {
+            Layout::TurboTape => match bytes.first() {
+                Some(1 | 2) if bytes.len() >= 22 => {
+                    let mut name = [0; 16];
+                    name.copy_from_slice(&bytes[6..22]);
+                    header = Some((pulse, name, address(&bytes[1..]), address(&bytes[3..])));
+                }
+                Some(0) => {
+                    let (pulse, name, start, length) = match header.take() {
+                        Some((pulse, name, start, end)) => (
+                            pulse,
+                            Some(name),
+                            start,
+                            Some(usize::from(end.wrapping_sub(start))),
+                        ),
+                        None => (pulse, None, 0, None),
+                    };
+                    let (data, verified) = checked(&bytes[1..], length);
+                    files.push(TurboFile {
+                        format: format.name,
+                        pulse,
+                        name,
+                        start,
+                        data,
+                        verified,
+                    });
+                }
+                _ => {}
+            },
+            Layout::Addressed if bytes.len() >= 4 => {
+                let start = address(&bytes);
+                let length = usize::from(address(&bytes[2..]).wrapping_sub(start));
+                let (data, verified) = checked(&bytes[4..], Some(length));
+                files.push(TurboFile {
+                    format: format.name,
+                    pulse,
+                    name: None,
+                    start,
+                    data,
+                    verified,
+                });
+            }
+            Layout::Addressed => {}
+        }
+    }
+    files
+}
+
+/// The turbo formats to look for on a tape.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Registry {
+    formats: Vec<TurboFormat>,
+}
+
+impl Registry {
+    /// Creates a registry without formats.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry of the formats this module knows.
+    pub fn standard() -> Self {
+        Registry {
+            formats: vec![TurboFormat::TURBO_TAPE_64, TurboFormat::FREELOAD],
+        }
+    }
+
+    /// Adds `format`, replacing a format of the same name.
+    pub fn register(&mut self, format: TurboFormat) {
+        self.formats.retain(|known| known.name != format.name);
+        self.formats.push(format);
+    }
+
+    /// Returns the registered formats.
+    pub fn formats(&self) -> &[TurboFormat] {
+        &self.formats
+    }
+
+    /// Decodes the files on `tap` in every registered format, in the order
+    /// they are on the tape, from its [normalized](Tap::normalized) pulses.
+    pub fn decode(&self, tap: &Tap) -> Vec<TurboFile> {
+        let pulses = tap.normalized();
+        let mut files: Vec<TurboFile> = self
+            .formats
+            .iter()
+            .flat_map(|format| decode(format, &pulses))
+            .collect();
+        files.sort_by_key(|file| file.pulse);
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(format: &TurboFormat, pulses: &mut Vec<u32>, bytes: &[u8]) {
+        let low = (format.min + format.threshold) / 2;
+        let high = (format.threshold + format.max) / 2;
+        let mut block = vec![format.pilot; 64];
+        block.extend_from_slice(format.sync);
+        block.extend_from_slice(bytes);
+        for byte in block {
+            for bit in 0..8 {
+                let one = if format.msb_first {
+                    byte << bit & 0x80 != 0
+                } else {
+                    byte >> bit & 1 != 0
+                };
+                pulses.push(if one { high } else { low });
+            }
+        }
+        pulses.push(0x100 * 8);
+    }
+
+    #[test]
+    fn decodes_turbo_tape_64() {
+        let format = TurboFormat::TURBO_TAPE_64;
+        let program: Vec<u8> = (0..500u32).map(|i| (i * 13) as u8).collect();
+        let mut header = vec![1, 0x01, 0x08, 0xF5, 0x09, 0];
+        header.extend_from_slice(b"TURBO GAME      ");
+        header.resize(192, b' ');
+        let mut data = vec![0];
+        data.extend_from_slice(&program);
+        data.push(xor(&program));
+
+        let mut tap = Tap::new();
+        tap.pulses = vec![0x30 * 8; 500];
+        encode(&format, &mut tap.pulses, &header);
+        encode(&format, &mut tap.pulses, &data);
+        let tap = Tap::from_bytes(&tap.to_bytes()).unwrap();
+
+        let files = Registry::standard().decode(&tap);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].format, "Turbo Tape 64");
+        assert_eq!(files[0].name.as_ref(), Some(b"TURBO GAME      "));
+        assert!(files[0].verified);
+        assert_eq!(files[0].prg(), Prg::new(0x0801, program.clone()));
+    }
+
+    #[test]
+    fn decodes_registered_formats() {
+        let custom = TurboFormat {
+            name: "House loader",
+            min: 150,
+            threshold: 300,
+            max: 500,
+            msb_first: false,
+            pilot: 0xE3,
+            sync: &[0x3E, 0xA5],
+            layout: Layout::Addressed,
+        };
+        let mut tap = Tap::new();
+        encode(
+            &custom,
+            &mut tap.pulses,
+            &[0x00, 0xC0, 0x04, 0xC0, 1, 2, 3, 4, 4],
+        );
+        encode(
+            &TurboFormat::FREELOAD,
+            &mut tap.pulses,
+            &[0x00, 0x10, 0x02, 0x10, 7, 9, 0],
+        );
+        assert_eq!(Registry::new().decode(&tap), []);
+
+        let mut registry = Registry::standard();
+        registry.register(custom);
+        registry.register(custom);
+        assert_eq!(registry.formats().len(), 3);
+        let files = registry.decode(&tap);
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[0].format, files[0].start), ("House loader", 0xC000));
+        assert_eq!(files[0].data, [1, 2, 3, 4]);
+        assert!(files[0].verified);
+        assert_eq!((files[1].format, files[1].start), ("Freeload", 0x1000));
+        assert_eq!(files[1].data, [7, 9]);
+        assert!(!files[1].verified);
+    }
+}
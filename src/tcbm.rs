@@ -0,0 +1,543 @@
+//! The TCBM parallel interface of the 1551.
+//!
+//! The 1551 hangs off the Plus/4 and C16 expansion port through a 6523
+//! TIA, one cable per drive. There is no ATN line: each transfer starts
+//! with a code byte telling the drive what the byte that follows is.
+//!
+//! ```plaintext
+//! $81  command (LISTEN, TALK, UNLISTEN, UNTALK)
+//! $82  secondary address (DATA, OPEN, CLOSE)
+//! $83  data byte for the drive
+//! $84  data byte from the drive
+//! ```
+//!
+//! Both bytes go through the same four-phase handshake: the computer
+//! asserts DAV, the drive answers with ACK, the computer releases DAV, the
+//! drive releases ACK. With the second byte the drive also reports two
+//! status bits, `01` when it has nothing to send and `11` for EOI. The
+//! commands themselves are those of [`crate::bus`].
+
+use crate::bus::{BusCommand, BusDevice, BusError, Operation, Operations};
+use crate::drive::VirtualDrive;
+
+/// Code announcing a command byte.
+const COMMAND: u8 = 0x81;
+/// Code announcing a secondary address.
+const SECONDARY: u8 = 0x82;
+/// Code announcing a data byte for the drive.
+const DATA_OUT: u8 = 0x83;
+/// Code requesting a data byte from the drive.
+const DATA_IN: u8 = 0x84;
+
+/// Status bits for a transfer without problems.
+const STATUS_OK: u8 = 0b00;
+/// Status bits for a data request the drive could not serve.
+const STATUS_TIMEOUT: u8 = 0b01;
+/// Status bits marking the last byte.
+const STATUS_EOI: u8 = 0b11;
+
+/// How long the computer waits for the drive to acknowledge.
+const ACK_TIMEOUT: u64 = 1000;
+
+/// The state of the cable. `dav` is driven by the computer, `ack` and
+/// `status` by the drive, `data` by whichever side sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Lines {
+    /// The eight data lines.
+    pub data: u8,
+    /// Data valid from the computer.
+    pub dav: bool,
+    /// Acknowledge from the drive.
+    pub ack: bool,
+    /// The two status bits from the drive.
+    pub status: u8,
+}
+
+/// Access to the cable from one end.
+pub trait TcbmPins {
+    /// Returns the state of the cable, including what this end drives.
+    fn read(&mut self) -> Lines;
+
+    /// Sets the lines this end drives; the others are ignored.
+    fn write(&mut self, lines: Lines);
+}
+
+/// An emulated cable between a computer and one drive.
+#[derive(Debug, Clone, Default)]
+pub struct Cable {
+    computer: Lines,
+    drive: Lines,
+}
+
+impl Cable {
+    /// Creates a cable with all lines released.
+    pub fn new() -> Self {
+        Cable::default()
+    }
+
+    /// Returns the state of the cable.
+    pub fn lines(&self) -> Lines {
+        Lines {
+            data: self.computer.data | self.drive.data,
+            dav: self.computer.dav,
+            ack: self.drive.ack,
+            status: self.drive.status,
+        }
+    }
+
+    /// Returns the computer's end.
+    pub fn computer(&mut self) -> CableEnd<'_> {
+        CableEnd {
+            cable: self,
+            drive: false,
+        }
+    }
+
+    /// Returns the drive's end.
+    pub fn drive(&mut self) -> CableEnd<'_> {
+        CableEnd {
+            cable: self,
+            drive: true,
+        }
+    }
+}
+
+/// One end of a [`Cable`].
+#[derive(Debug)]
+pub struct CableEnd<'a> {
+    cable: &'a mut Cable,
+    drive: bool,
+}
+
+impl TcbmPins for CableEnd<'_> {
+    fn read(&mut self) -> Lines {
+        self.cable.lines()
+    }
+
+    fn write(&mut self, lines: Lines) {
+        if self.drive {
+            self.cable.drive = Lines {
+                dav: false,
+                status: lines.status & 0b11,
+                ..lines
+            };
+        } else {
+            self.cable.computer = Lines {
+                ack: false,
+                status: 0,
+                ..lines
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// DAV asserted with the code, waiting for ACK.
+    Code,
+    /// DAV released, waiting for ACK to drop.
+    CodeRelease,
+    /// DAV asserted with the byte or for the reply, waiting for ACK.
+    Byte,
+    /// DAV released after taking the reply, waiting for ACK to drop.
+    ByteRelease,
+}
+
+/// One code and byte exchange from the computer's side.
+#[derive(Debug, Clone)]
+struct Transfer {
+    code: u8,
+    byte: u8,
+    phase: Phase,
+    since: u64,
+    reply: (u8, u8),
+}
+
+impl Transfer {
+    fn new(code: u8, byte: u8, now: u64) -> Self {
+        Transfer {
+            code,
+            byte,
+            phase: Phase::Code,
+            since: now,
+            reply: (0, STATUS_OK),
+        }
+    }
+
+    fn output(&self) -> Lines {
+        match self.phase {
+            Phase::Code => Lines {
+                data: self.code,
+                dav: true,
+                ..Lines::default()
+            },
+            Phase::Byte if self.code != DATA_IN => Lines {
+                data: self.byte,
+                dav: true,
+                ..Lines::default()
+            },
+            Phase::Byte => Lines {
+                dav: true,
+                ..Lines::default()
+            },
+            Phase::CodeRelease | Phase::ByteRelease => Lines::default(),
+        }
+    }
+
+    /// Advances the exchange, returning the drive's byte and status bits
+    /// once it is complete.
+    fn step(&mut self, lines: Lines, now: u64) -> Result<Option<(u8, u8)>, BusError> {
+        let next = match self.phase {
+            Phase::Code | Phase::Byte if lines.ack => {
+                self.reply = (lines.data, lines.status);
+                if self.phase == Phase::Code {
+                    Phase::CodeRelease
+                } else {
+                    Phase::ByteRelease
+                }
+            }
+            Phase::Code if now - self.since > ACK_TIMEOUT => {
+                return Err(BusError::DeviceNotPresent);
+            }
+            Phase::Byte if now - self.since > ACK_TIMEOUT => return Err(BusError::Timeout),
+            Phase::CodeRelease if !lines.ack => Phase::Byte,
+            Phase::ByteRelease if !lines.ack => return Ok(Some(self.reply)),
+            _ => return Ok(None),
+        };
+        self.phase = next;
+        self.since = now;
+        Ok(None)
+    }
+}
+
+/// The computer side of the cable, the Plus/4's TIA.
+///
+/// It queues the same operations as [`crate::iec::IecHost`] and reports
+/// failures the same way; a read the drive answers with the timeout status
+/// fails with [`BusError::Timeout`].
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::drive::Drive;
+/// use cbm_dos::tcbm::{Cable, TcbmDevice, TcbmHost};
+///
+/// let mut cable = Cable::new();
+/// let mut host = TcbmHost::new();
+/// let mut drive = TcbmDevice::new(Drive::new(D64::new(35)), 8);
+///
+/// host.talk(8, 15);
+/// host.untalk();
+/// let mut now = 0;
+/// while !host.is_idle() {
+///     host.poll(&mut cable.computer(), now);
+///     drive.poll(&mut cable.drive());
+///     now += 1;
+/// }
+/// assert!(host.take_received().starts_with(b"73,"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TcbmHost {
+    queue: Operations,
+    transfer: Option<Transfer>,
+    pending: Vec<(u8, u8)>,
+    receiving: bool,
+    received: Vec<u8>,
+    error: Option<BusError>,
+}
+
+impl TcbmHost {
+    /// Creates a host with all lines released.
+    pub fn new() -> Self {
+        TcbmHost::default()
+    }
+
+    /// Queues `LISTEN device` for data on `secondary`.
+    pub fn listen(&mut self, device: u8, secondary: u8) {
+        self.queue.listen(device, secondary);
+    }
+
+    /// Queues `UNLISTEN`.
+    pub fn unlisten(&mut self) {
+        self.queue.unlisten();
+    }
+
+    /// Queues `TALK device` on `secondary` and the reception of everything
+    /// the device sends up to EOI.
+    pub fn talk(&mut self, device: u8, secondary: u8) {
+        self.queue.talk(device, secondary);
+    }
+
+    /// Queues `UNTALK`.
+    pub fn untalk(&mut self) {
+        self.queue.untalk();
+    }
+
+    /// Queues opening `secondary` on `device` with `name`.
+    pub fn open(&mut self, device: u8, secondary: u8, name: &[u8]) {
+        self.queue.open(device, secondary, name);
+    }
+
+    /// Queues closing `secondary` on `device`.
+    pub fn close(&mut self, device: u8, secondary: u8) {
+        self.queue.close(device, secondary);
+    }
+
+    /// Queues data for the listening device.
+    pub fn send(&mut self, data: &[u8]) {
+        self.queue.send(data);
+    }
+
+    /// Returns `true` once every queued operation has finished.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+            && self.transfer.is_none()
+            && self.pending.is_empty()
+            && !self.receiving
+    }
+
+    /// Returns and clears the bytes received so far.
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Returns and clears the error that stopped the queue.
+    pub fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+
+    /// Advances the host to `now` microseconds.
+    pub fn poll(&mut self, pins: &mut impl TcbmPins, now: u64) {
+        let lines = pins.read();
+        if let Err(error) = self.step(lines, now) {
+            self.queue.clear();
+            self.pending.clear();
+            self.transfer = None;
+            self.receiving = false;
+            self.error = Some(error);
+        }
+        pins.write(
+            self.transfer
+                .as_ref()
+                .map_or(Lines::default(), Transfer::output),
+        );
+    }
+
+    fn step(&mut self, lines: Lines, now: u64) -> Result<(), BusError> {
+        let Some(transfer) = &mut self.transfer else {
+            self.start(now);
+            return Ok(());
+        };
+        let Some((byte, status)) = transfer.step(lines, now)? else {
+            return Ok(());
+        };
+        self.transfer = None;
+        if self.receiving {
+            match status {
+                STATUS_TIMEOUT => return Err(BusError::Timeout),
+                STATUS_EOI => self.receiving = false,
+                _ => {}
+            }
+            self.received.push(byte);
+        }
+        Ok(())
+    }
+
+    /// Starts the next exchange, taking it from the queue if needed.
+    fn start(&mut self, now: u64) {
+        if self.receiving {
+            self.transfer = Some(Transfer::new(DATA_IN, 0, now));
+            return;
+        }
+        if self.pending.is_empty() {
+            match self.queue.pop() {
+                Some(Operation::Attention(bytes)) => {
+                    self.pending = bytes.into_iter().rev().map(|b| (code(b), b)).collect();
+                }
+                Some(Operation::Send(bytes)) => {
+                    self.pending = bytes.into_iter().rev().map(|b| (DATA_OUT, b)).collect();
+                }
+                Some(Operation::Receive) => self.receiving = true,
+                None => {}
+            }
+        }
+        if let Some((code, byte)) = self.pending.pop() {
+            self.transfer = Some(Transfer::new(code, byte, now));
+        }
+    }
+}
+
+/// Returns the code announcing the command byte `byte`.
+fn code(byte: u8) -> u8 {
+    match BusCommand::from_byte(byte) {
+        Some(
+            BusCommand::Listen(_) | BusCommand::Talk(_) | BusCommand::Unlisten | BusCommand::Untalk,
+        ) => COMMAND,
+        _ => SECONDARY,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceState {
+    /// Waiting for a code.
+    Idle,
+    /// Acknowledging `code`.
+    CodeTaken(u8),
+    /// Waiting for the byte that goes with `code`.
+    Waiting(u8),
+    /// Acknowledging the byte with the reply on the lines.
+    ByteTaken(Lines),
+}
+
+/// A drive attached through TCBM.
+#[derive(Debug, Clone)]
+pub struct TcbmDevice<D> {
+    device: BusDevice<D>,
+    state: DeviceState,
+}
+
+impl<D: VirtualDrive> TcbmDevice<D> {
+    /// Attaches `drive` under device number `address`.
+    pub fn new(drive: D, address: u8) -> Self {
+        TcbmDevice {
+            device: BusDevice::new(drive, address),
+            state: DeviceState::Idle,
+        }
+    }
+
+    /// Returns the command layer.
+    pub fn device(&self) -> &BusDevice<D> {
+        &self.device
+    }
+
+    /// Returns the command layer for modification.
+    pub fn device_mut(&mut self) -> &mut BusDevice<D> {
+        &mut self.device
+    }
+
+    /// Detaches the drive.
+    pub fn into_inner(self) -> D {
+        self.device.into_inner()
+    }
+
+    /// Advances the device. The handshake has no timing, so the drive only
+    /// reacts to the lines.
+    pub fn poll(&mut self, pins: &mut impl TcbmPins) {
+        let lines = pins.read();
+        self.state = match self.state {
+            DeviceState::Idle if lines.dav => DeviceState::CodeTaken(lines.data),
+            DeviceState::CodeTaken(code) if !lines.dav => DeviceState::Waiting(code),
+            DeviceState::Waiting(code) if lines.dav => {
+                DeviceState::ByteTaken(self.exchange(code, lines.data))
+            }
+            DeviceState::ByteTaken(_) if !lines.dav => DeviceState::Idle,
+            state => state,
+        };
+        pins.write(match self.state {
+            DeviceState::Idle | DeviceState::Waiting(_) => Lines::default(),
+            DeviceState::CodeTaken(_) => Lines {
+                ack: true,
+                ..Lines::default()
+            },
+            DeviceState::ByteTaken(reply) => reply,
+        });
+    }
+
+    /// Handles the byte following `code` and returns the acknowledgement.
+    fn exchange(&mut self, code: u8, byte: u8) -> Lines {
+        let (data, status) = match code {
+            COMMAND | SECONDARY => {
+                self.device.attention(byte);
+                (0, STATUS_OK)
+            }
+            DATA_OUT => {
+                self.device.receive(byte);
+                (0, STATUS_OK)
+            }
+            DATA_IN => match self.device.send() {
+                Some((byte, true)) => (byte, STATUS_EOI),
+                Some((byte, false)) => (byte, STATUS_OK),
+                None => (0, STATUS_TIMEOUT),
+            },
+            _ => (0, STATUS_OK),
+        };
+        Lines {
+            data,
+            ack: true,
+            status,
+            ..Lines::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::Drive;
+    use crate::wedge::Wedge;
+
+    fn run(cable: &mut Cable, host: &mut TcbmHost, device: Option<&mut TcbmDevice<Drive<D64>>>) {
+        let mut device = device;
+        let mut now = 0;
+        while !host.is_idle() {
+            host.poll(&mut cable.computer(), now);
+            if let Some(device) = device.as_mut() {
+                device.poll(&mut cable.drive());
+            }
+            now += 1;
+            assert!(now < 1_000_000, "cable hung");
+        }
+    }
+
+    fn drive() -> TcbmDevice<Drive<D64>> {
+        let mut wedge = Wedge::mount(D64::new(35));
+        wedge.command("N:PLUS4,01").unwrap();
+        wedge.save("PROG", 0x1001, &[0x0B, 0x10, 0x0A]).unwrap();
+        TcbmDevice::new(wedge.into_inner(), 8)
+    }
+
+    #[test]
+    fn loads_and_saves_over_the_cable() {
+        let mut cable = Cable::new();
+        let mut device = drive();
+        let mut host = TcbmHost::new();
+
+        host.open(8, 0, b"PROG");
+        host.talk(8, 0);
+        host.untalk();
+        host.close(8, 0);
+        host.open(8, 1, b"COPY");
+        host.listen(8, 1);
+        host.send(&[0x00, 0x30, 0x60]);
+        host.unlisten();
+        host.close(8, 1);
+        run(&mut cable, &mut host, Some(&mut device));
+        assert_eq!(host.take_error(), None);
+        assert_eq!(host.take_received(), [0x01, 0x10, 0x0B, 0x10, 0x0A]);
+        let mut wedge = Wedge::new(device.into_inner());
+        assert_eq!(wedge.load("COPY").unwrap(), (0x3000, vec![0x60]));
+        assert_eq!(cable.lines(), Lines::default());
+    }
+
+    #[test]
+    fn reports_missing_drives_and_files() {
+        let mut cable = Cable::new();
+        let mut host = TcbmHost::new();
+        host.listen(8, 15);
+        run(&mut cable, &mut host, None);
+        assert_eq!(host.take_error(), Some(BusError::DeviceNotPresent));
+
+        let mut device = drive();
+        host.open(8, 2, b"MISSING");
+        host.talk(8, 2);
+        run(&mut cable, &mut host, Some(&mut device));
+        assert_eq!(host.take_error(), Some(BusError::Timeout));
+        host.untalk();
+        host.talk(8, 15);
+        host.untalk();
+        run(&mut cable, &mut host, Some(&mut device));
+        assert_eq!(host.take_error(), None);
+        assert!(host.take_received().starts_with(b"62,"));
+    }
+}
@@ -0,0 +1,214 @@
+//! Rotation and byte timing of a 1541 track.
+//!
+//! The 1541 records its tracks with one of four bit rates ("speed zones"),
+//! all derived from its 16 MHz clock: the outermost zone 3 (tracks 1-17)
+//! uses 16 MHz / 13 / 4 ≈ 307.7 kbit/s and the innermost zone 0 (tracks 31
+//! and beyond) 16 MHz / 16 / 4 = 250 kbit/s. Since the disk turns at a fixed
+//! speed, the zone decides how many bytes fit onto one revolution.
+//!
+//! [`TrackTiming`] replays a raw track at a given rotation speed and answers
+//! where the head is at a given moment and when the next sync mark passes
+//! under it.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// The nominal rotation speed of a 1541.
+pub const DEFAULT_RPM: f64 = 300.0;
+
+/// Number of consecutive one bits the drive recognises as a sync mark.
+pub const SYNC_BITS: usize = 10;
+
+const DRIVE_CLOCK: u32 = 16_000_000;
+
+/// Returns the speed zone (0-3) the 1541 uses for `track`.
+pub fn speed_zone(track: u8) -> u8 {
+    match track {
+        0..=17 => 3,
+        18..=24 => 2,
+        25..=30 => 1,
+        _ => 0,
+    }
+}
+
+/// Returns the bit rate of a speed zone in bits per second.
+///
+/// # Panics
+/// Panics if `zone` is greater than 3.
+pub fn bit_rate(zone: u8) -> u32 {
+    assert!(zone <= 3, "invalid speed zone {zone}");
+    DRIVE_CLOCK / (16 - zone as u32) / 4
+}
+
+/// Returns how many bytes a track recorded in `zone` holds at `rpm`.
+///
+/// At 300 RPM this yields the familiar capacities of 7692, 7142, 6666 and
+/// 6250 bytes for zones 3 to 0.
+pub fn track_capacity(zone: u8, rpm: f64) -> usize {
+    (bit_rate(zone) as f64 * 60.0 / rpm / 8.0) as usize
+}
+
+/// A sync mark passing under the head.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncArrival {
+    /// Time at which the drive detects the sync, i.e. after the tenth one bit.
+    pub detected: Duration,
+    /// Time at which the first bit after the sync arrives.
+    pub end: Duration,
+    /// Bit offset within the track of the first bit after the sync.
+    pub bit: usize,
+}
+
+/// Timing of a raw track spinning under the head.
+///
+/// The track is treated as a loop of bytes that fills exactly one
+/// revolution, so the byte period follows from the track length and the
+/// rotation speed. Time zero is the moment the first byte of the track
+/// reaches the head.
+#[derive(Debug, Clone)]
+pub struct TrackTiming<'a> {
+    data: &'a [u8],
+    rpm: f64,
+    /// Bit offsets at which a sync is detected and at which it ends.
+    syncs: Vec<(usize, usize)>,
+}
+
+impl<'a> TrackTiming<'a> {
+    /// Prepares the timing of `data` spinning at `rpm`.
+    ///
+    /// # Panics
+    /// Panics if `data` is empty or `rpm` is not positive.
+    pub fn new(data: &'a [u8], rpm: f64) -> Self {
+        assert!(!data.is_empty(), "track must not be empty");
+        assert!(rpm > 0.0, "rotation speed must be positive");
+        TrackTiming {
+            data,
+            rpm,
+            syncs: find_syncs(data),
+        }
+    }
+
+    /// Returns the duration of one revolution.
+    pub fn revolution(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.rpm)
+    }
+
+    /// Returns the time it takes one bit to pass the head.
+    pub fn bit_period(&self) -> Duration {
+        self.revolution() / (self.bits() as u32)
+    }
+
+    /// Returns the time it takes one byte to pass the head.
+    pub fn byte_period(&self) -> Duration {
+        self.revolution() / (self.data.len() as u32)
+    }
+
+    fn bits(&self) -> usize {
+        self.data.len() * 8
+    }
+
+    fn bit_at(&self, time: Duration) -> usize {
+        let revolution = self.revolution().as_secs_f64();
+        let fraction = time.as_secs_f64() / revolution % 1.0;
+        ((fraction * self.bits() as f64) as usize).min(self.bits() - 1)
+    }
+
+    fn time_of_bit(&self, bit: usize) -> f64 {
+        bit as f64 * self.revolution().as_secs_f64() / self.bits() as f64
+    }
+
+    /// Returns the index and value of the byte under the head at `time`.
+    pub fn byte_at(&self, time: Duration) -> (usize, u8) {
+        let index = self.bit_at(time) / 8;
+        (index, self.data[index])
+    }
+
+    /// Returns the next sync mark detected strictly after `time`.
+    ///
+    /// Returns `None` if the track contains no sync mark, or consists of
+    /// nothing but one bits so that the sync never ends.
+    pub fn next_sync(&self, time: Duration) -> Option<SyncArrival> {
+        let now = time.as_secs_f64();
+        let revolution = self.revolution().as_secs_f64();
+        // Truncating rounds down, as times are never negative.
+        let turns = (now / revolution) as u64 as f64;
+        let base = turns * revolution;
+        let position = now - base;
+        let (offset, &(detected, end)) = self
+            .syncs
+            .iter()
+            .map(|s| (0.0, s))
+            .find(|&(_, &(d, _))| self.time_of_bit(d) > position)
+            .or_else(|| self.syncs.first().map(|s| (revolution, s)))?;
+        let detected_at = base + offset + self.time_of_bit(detected);
+        let mut end_at = base + offset + self.time_of_bit(end);
+        if end < detected {
+            end_at += revolution;
+        }
+        Some(SyncArrival {
+            detected: Duration::from_secs_f64(detected_at),
+            end: Duration::from_secs_f64(end_at),
+            bit: end,
+        })
+    }
+}
+
+/// Locates sync marks on a circular track.
+///
+/// Returns pairs of bit offsets: where the tenth consecutive one bit is read
+/// and where the first zero bit after the run appears.
+pub(crate) fn find_syncs(data: &[u8]) -> Vec<(usize, usize)> {
+    let bits = data.len() * 8;
+    let bit = |i: usize| data[(i % bits) / 8] & (0x80 >> (i % 8)) != 0;
+    let Some(start) = (0..bits).find(|&i| !bit(i)) else {
+        return Vec::new();
+    };
+    let mut syncs = Vec::new();
+    let mut run = 0;
+    for i in start + 1..=start + bits {
+        if bit(i) {
+            run += 1;
+        } else {
+            if run >= SYNC_BITS {
+                syncs.push(((i - run + SYNC_BITS - 1) % bits, i % bits));
+            }
+            run = 0;
+        }
+    }
+    syncs.sort_unstable();
+    syncs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zone_capacities() {
+        assert_eq!(speed_zone(1), 3);
+        assert_eq!(speed_zone(35), 0);
+        assert_eq!(bit_rate(0), 250_000);
+        let capacities: Vec<usize> = (0..4).map(|z| track_capacity(z, DEFAULT_RPM)).collect();
+        assert_eq!(capacities, vec![6250, 6666, 7142, 7692]);
+    }
+
+    #[test]
+    fn head_position_and_sync_arrival() {
+        let mut track = vec![0x55u8; 6250];
+        track[100..105].fill(0xFF);
+        let timing = TrackTiming::new(&track, DEFAULT_RPM);
+        assert_eq!(timing.byte_period(), Duration::from_micros(32));
+
+        assert_eq!(timing.byte_at(Duration::from_micros(50)), (1, 0x55));
+        assert_eq!(timing.byte_at(Duration::from_millis(200)), (0, 0x55));
+
+        // 0x55 ends in a one bit, so the run starts one bit before byte 100.
+        let sync = timing.next_sync(Duration::ZERO).unwrap();
+        assert_eq!(sync.bit, 105 * 8);
+        assert_eq!(sync.end, Duration::from_micros(105 * 32));
+        assert_eq!(sync.detected, Duration::from_micros(100 * 32 + 32));
+
+        let again = timing.next_sync(Duration::from_millis(5)).unwrap();
+        assert_eq!(again.end, Duration::from_micros(200_000 + 105 * 32));
+    }
+}
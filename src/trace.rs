@@ -0,0 +1,226 @@
+//! Following what the crate decides.
+//!
+//! With the `trace` feature the flux pipeline, the GCR decoder, the file
+//! system and the virtual drive report what they do as [`Event`]s: the
+//! half tracks a pipeline runs and the zone they end up in, every sector
+//! that cannot be read and why, the files written, scratched and renamed,
+//! and the commands a drive is sent with the status they leave. A program
+//! sees them by installing a subscriber with [`subscribe`], which may
+//! print them or hand them on to a logging framework.
+//!
+//! Some work is reported as a span: a [`Kind::Enter`] event, the events
+//! of the work, each one [`Event::depth`] deeper, and an [`Kind::Exit`]
+//! event. Spans nest per thread.
+//!
+//! Without a subscriber, reporting costs a check of an atomic flag; the
+//! messages are never formatted. Without the feature the crate does not
+//! report at all.
+//!
+//! # Example
+//! ```
+//! use cbm_dos::d64::D64;
+//! use cbm_dos::fs;
+//! use cbm_dos::trace;
+//!
+//! trace::subscribe(|event| {
+//!     eprintln!("{:indent$}{}: {}", "", event.target, event.message, indent = event.depth * 2);
+//! });
+//! let mut image = D64::new(35);
+//! fs::format(&mut image, b"TRACED", Some(*b"01")).unwrap();
+//! trace::unsubscribe();
+//! ```
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What an [`Event`] marks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// The start of a span.
+    Enter,
+    /// The end of a span, with the message it started with.
+    Exit,
+    /// Something decided or done.
+    Message,
+}
+
+/// A report of the crate.
+#[derive(Debug, Clone, Copy)]
+pub struct Event<'a> {
+    pub kind: Kind,
+    /// The module reporting, such as `cbm_dos::track`.
+    pub target: &'static str,
+    /// The number of spans open on the thread, not counting the one a
+    /// [`Kind::Enter`] or [`Kind::Exit`] event marks.
+    pub depth: usize,
+    pub message: fmt::Arguments<'a>,
+}
+
+type Subscriber = Box<dyn Fn(&Event<'_>) + Send + Sync>;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SUBSCRIBER: RwLock<Option<Subscriber>> = RwLock::new(None);
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Sends every event of every thread to `subscriber`, in place of the one
+/// installed before.
+pub fn subscribe(subscriber: impl Fn(&Event<'_>) + Send + Sync + 'static) {
+    *SUBSCRIBER.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(subscriber));
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Removes the subscriber, if any.
+pub fn unsubscribe() {
+    ENABLED.store(false, Ordering::Release);
+    *SUBSCRIBER.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Returns `true` if a subscriber is installed.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Sends an event to the subscriber. The crate reports through this, with
+/// the name of its module as `target`.
+pub fn emit(kind: Kind, target: &'static str, message: fmt::Arguments<'_>) {
+    if !enabled() {
+        return;
+    }
+    let depth = DEPTH.with(Cell::get);
+    let subscriber = SUBSCRIBER.read().unwrap_or_else(|e| e.into_inner());
+    if let Some(subscriber) = subscriber.as_ref() {
+        subscriber(&Event {
+            kind,
+            target,
+            depth,
+            message,
+        });
+    }
+}
+
+/// An open span, closed when dropped.
+#[derive(Debug)]
+#[must_use = "the span closes when dropped"]
+pub struct Span {
+    target: &'static str,
+    /// The message of the span, kept only if it was reported.
+    message: Option<String>,
+}
+
+impl Span {
+    /// Opens a span, reporting its start.
+    pub fn enter(target: &'static str, message: fmt::Arguments<'_>) -> Self {
+        if !enabled() {
+            return Span {
+                target,
+                message: None,
+            };
+        }
+        emit(Kind::Enter, target, message);
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Span {
+            target,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(message) = &self.message {
+            DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+            emit(Kind::Exit, self.target, format_args!("{message}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::drive::Drive;
+    use crate::error::DosError;
+    use crate::fs::{self, FileType};
+    use crate::image::BlockDevice;
+    use std::sync::Mutex;
+
+    thread_local! {
+        static SEEN: std::cell::RefCell<Vec<(Kind, &'static str, usize, String)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    /// Serializes the tests, as the subscriber is global.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` with a subscriber collecting the events of this thread.
+    fn collect(f: impl FnOnce()) -> Vec<(Kind, &'static str, usize, String)> {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        subscribe(|event| {
+            let seen = (
+                event.kind,
+                event.target,
+                event.depth,
+                event.message.to_string(),
+            );
+            SEEN.with(|s| s.borrow_mut().push(seen));
+        });
+        f();
+        unsubscribe();
+        SEEN.with(|s| s.take())
+    }
+
+    #[test]
+    fn reports_file_operations_and_commands() {
+        let mut image = D64::new(35);
+        let events = collect(|| {
+            fs::format(&mut image, b"LOG", Some(*b"01")).unwrap();
+            fs::write_file(&mut image, b"GAME\x01", FileType::Prg, &[1, 8]).unwrap();
+            let mut drive = Drive::new(image.clone());
+            let _ = drive.execute(b"S:GAME*");
+        });
+        let messages: Vec<_> = events.iter().map(|e| e.3.as_str()).collect();
+        assert!(messages.contains(&"writing GAME\\x01, 2 bytes"));
+        let enter = events
+            .iter()
+            .position(|e| e.0 == Kind::Enter && e.3 == "command S:GAME*")
+            .unwrap();
+        let (_, target, depth, _) = &events[enter];
+        assert_eq!(*target, "cbm_dos::drive");
+        assert!(
+            events[enter + 1..]
+                .iter()
+                .any(|e| e.2 == depth + 1 && e.3 == "scratched 1 files")
+        );
+        let exit = events.last().unwrap();
+        assert_eq!((exit.0, exit.2), (Kind::Exit, *depth));
+        assert_eq!(
+            events[events.len() - 2].3,
+            "status 01,FILES SCRATCHED,01,00"
+        );
+    }
+
+    #[test]
+    fn reports_why_sectors_cannot_be_read() {
+        let mut image = D64::new(35);
+        image
+            .set_sector_error(1, 3, Some(DosError::DataChecksum))
+            .unwrap();
+        let g64 = crate::g64::G64::from_image(&image);
+        let events = collect(|| {
+            assert_eq!(g64.sector_error(1, 3), Some(DosError::DataChecksum));
+        });
+        assert!(
+            events
+                .iter()
+                .any(|e| e.1 == "cbm_dos::track" && e.3 == "track 1 sector 3: DataChecksum")
+        );
+        assert!(collect(|| {}).is_empty());
+        emit(Kind::Message, "cbm_dos::trace", format_args!("unseen"));
+        assert!(SEEN.with(|s| s.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,688 @@
+//! GCR track layout of the 1541.
+//!
+//! A formatted track is a sequence of sectors, each consisting of
+//!
+//! 1. a sync mark (five `$FF` bytes),
+//! 2. a header block: `$08`, checksum, sector, track, ID2, ID1, `$0F`, `$0F`
+//!    (8 bytes, 10 after GCR encoding),
+//! 3. a header gap of nine `$55` bytes,
+//! 4. another sync mark,
+//! 5. a data block: `$07`, 256 data bytes, checksum, `$00`, `$00`
+//!    (260 bytes, 325 after GCR encoding),
+//! 6. an inter-sector gap whose length depends on the speed zone.
+//!
+//! The remainder of the revolution is filled with `$55`.
+//!
+//! [`read_sector`] goes the other way and searches a track the way the
+//! drive does, at bit level, so tracks whose sync marks do not end on a
+//! byte boundary are read as well.
+//!
+//! A raw track has no start of its own: a dump starts wherever the index
+//! hole or the reading tool happened to be. [`align`] rotates a track to an
+//! [`Origin`] found in the data, so dumps and revolutions of the same track
+//! start at the same bit and can be compared byte by byte.
+//!
+//! [`physical_order`] lists the sectors of a track in the order they pass
+//! the head and [`logical_order`] in the order of their numbers, each with
+//! the bit its header starts at, for timing loaders and mastering tracks.
+//! [`encode_interleaved`] masters a track the other way, with the sectors
+//! laid out by an [`Interleave`] rather than in the order of their numbers.
+
+use crate::GCR;
+use crate::error::DosError;
+use crate::fs::DIR_TRACK;
+use crate::image::{SECTOR_SIZE, Sector};
+use crate::timing::{DEFAULT_RPM, SYNC_BITS, find_syncs, speed_zone, track_capacity};
+use alloc::vec::Vec;
+
+/// Length of a sync mark written by the 1541 format routine.
+pub const SYNC_LENGTH: usize = 5;
+/// Length of the gap between header and data block.
+pub const HEADER_GAP: usize = 9;
+/// GCR-encoded length of a header block.
+pub const HEADER_LENGTH: usize = 10;
+/// GCR-encoded length of a data block.
+pub const DATA_LENGTH: usize = 325;
+/// Fill byte used for gaps.
+pub const GAP_BYTE: u8 = 0x55;
+
+/// First byte of a header block.
+pub const HEADER_MARK: u8 = 0x08;
+/// First byte of a data block.
+pub const DATA_MARK: u8 = 0x07;
+
+/// Returns the length of the gap following each data block in `zone`.
+pub fn sector_gap(zone: u8) -> usize {
+    [9, 12, 17, 8][zone as usize & 3]
+}
+
+/// Builds the GCR-encoded header block of a sector.
+///
+/// `id` is the disk ID in the order it appears in the BAM; the header stores
+/// it reversed.
+pub fn encode_header(track: u8, sector: u8, id: [u8; 2]) -> Vec<u8> {
+    let checksum = track ^ sector ^ id[0] ^ id[1];
+    GCR::new().encode(&[
+        HEADER_MARK,
+        checksum,
+        sector,
+        track,
+        id[1],
+        id[0],
+        0x0F,
+        0x0F,
+    ])
+}
+
+/// Builds the GCR-encoded data block of a sector.
+pub fn encode_data(data: &Sector) -> Vec<u8> {
+    GCR::new().encode(&data_block(data))
+}
+
+fn data_block(data: &Sector) -> Vec<u8> {
+    let mut block = Vec::with_capacity(SECTOR_SIZE + 4);
+    block.push(DATA_MARK);
+    block.extend_from_slice(data);
+    block.push(data.iter().fold(0, |acc, b| acc ^ b));
+    block.extend_from_slice(&[0, 0]);
+    block
+}
+
+/// The physical interleave a disk is mastered with: how many sector slots
+/// on from the last one each sector is written, per speed zone and for the
+/// directory track.
+///
+/// An interleave of 1 writes the sectors in the order of their numbers, as
+/// the 1541 formats; higher ones let a loader that spends time between
+/// sectors find the next one passing the head. A slot that is already taken
+/// moves the sector on to the next free one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Interleave {
+    /// The interleave of the tracks of each speed zone, by zone number: 3
+    /// for tracks 1-17 down to 0 for tracks 31 and up.
+    pub zones: [u8; 4],
+    /// The interleave of the directory track, in place of that of its zone.
+    pub directory: u8,
+}
+
+impl Interleave {
+    /// The layout the 1541 formats, every sector after the one before.
+    pub const STANDARD: Interleave = Interleave::uniform(1);
+
+    /// The same interleave on every track.
+    pub const fn uniform(interleave: u8) -> Self {
+        Interleave {
+            zones: [interleave; 4],
+            directory: interleave,
+        }
+    }
+
+    /// Returns the interleave of `track`.
+    pub fn of(&self, track: u8) -> u8 {
+        if track == DIR_TRACK {
+            self.directory
+        } else {
+            self.zones[usize::from(speed_zone(track))]
+        }
+    }
+}
+
+impl Default for Interleave {
+    fn default() -> Self {
+        Interleave::STANDARD
+    }
+}
+
+/// Returns the sectors of a track of `count` sectors in the order they are
+/// written with `interleave`, where 0 is taken for 1.
+///
+/// ```
+/// use cbm_dos::track::layout;
+///
+/// assert_eq!(layout(5, 1), [0, 1, 2, 3, 4]);
+/// assert_eq!(layout(5, 2), [0, 3, 1, 4, 2]);
+/// ```
+pub fn layout(count: u8, interleave: u8) -> Vec<u8> {
+    let count = usize::from(count);
+    let step = usize::from(interleave.max(1));
+    let mut slots: Vec<Option<u8>> = alloc::vec![None; count];
+    let mut slot = 0;
+    for sector in 0..count {
+        while slots[slot].is_some() {
+            slot = (slot + 1) % count;
+        }
+        slots[slot] = Some(sector as u8);
+        slot = (slot + step) % count;
+    }
+    slots.into_iter().flatten().collect()
+}
+
+/// Encodes a complete track.
+///
+/// `sectors` holds the data of each sector in order, `id` the disk ID and
+/// `error` the read error, if any, each sector should produce on a real
+/// drive. Errors are realised the way protection schemes and damaged disks
+/// produce them:
+///
+/// - 20: the header block mark is destroyed.
+/// - 21: the whole track is written without sync marks.
+/// - 22: the data block mark is destroyed.
+/// - 23: the data checksum is inverted.
+/// - 24: the data block contains invalid GCR codes.
+/// - 27: the header checksum is inverted.
+/// - 29: the header carries a different disk ID.
+///
+/// Errors without a physical representation, such as 26 (write protect),
+/// leave the sector intact.
+pub fn encode_track(
+    track: u8,
+    sectors: &[Sector],
+    id: [u8; 2],
+    error: impl Fn(u8) -> Option<DosError>,
+) -> Vec<u8> {
+    encode_interleaved(track, sectors, id, 1, error)
+}
+
+/// Encodes a complete track as [`encode_track`] does, with the sectors
+/// laid out in the order [`layout`] gives for `interleave`.
+pub fn encode_interleaved(
+    track: u8,
+    sectors: &[Sector],
+    id: [u8; 2],
+    interleave: u8,
+    error: impl Fn(u8) -> Option<DosError>,
+) -> Vec<u8> {
+    let gcr = GCR::new();
+    let zone = speed_zone(track);
+    let no_sync = (0..sectors.len() as u8).any(|s| error(s) == Some(DosError::NoSync));
+    let sync = if no_sync { GAP_BYTE } else { 0xFF };
+    let mut out = Vec::with_capacity(track_capacity(zone, DEFAULT_RPM));
+
+    for sector in layout(sectors.len() as u8, interleave) {
+        let data = &sectors[usize::from(sector)];
+        let error = error(sector);
+
+        let mut header_id = id;
+        if error == Some(DosError::DiskIdMismatch) {
+            header_id = [!id[0], !id[1]];
+        }
+        let mut checksum = track ^ sector ^ header_id[0] ^ header_id[1];
+        if error == Some(DosError::HeaderChecksum) {
+            checksum = !checksum;
+        }
+        let mark = if error == Some(DosError::HeaderNotFound) {
+            0x00
+        } else {
+            HEADER_MARK
+        };
+        let header = [
+            mark,
+            checksum,
+            sector,
+            track,
+            header_id[1],
+            header_id[0],
+            0x0F,
+            0x0F,
+        ];
+
+        let mut block = data_block(data);
+        match error {
+            Some(DosError::DataBlockNotPresent) => block[0] = 0x00,
+            Some(DosError::DataChecksum) => block[SECTOR_SIZE + 1] ^= 0xFF,
+            _ => {}
+        }
+        let mut encoded = gcr.encode(&block);
+        if error == Some(DosError::ByteDecoding) {
+            encoded[5..10].fill(0x00);
+        }
+
+        out.extend_from_slice(&[sync; SYNC_LENGTH]);
+        out.extend_from_slice(&gcr.encode(&header));
+        out.extend_from_slice(&[GAP_BYTE; HEADER_GAP]);
+        out.extend_from_slice(&[sync; SYNC_LENGTH]);
+        out.extend_from_slice(&encoded);
+        out.extend(core::iter::repeat_n(GAP_BYTE, sector_gap(zone)));
+    }
+    let capacity = track_capacity(zone, DEFAULT_RPM);
+    if out.len() < capacity {
+        out.resize(capacity, GAP_BYTE);
+    }
+    out
+}
+
+/// The result of reading a sector from a GCR track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorRead {
+    /// The data, unless no data block was found. With a data checksum or
+    /// decoding error it holds what was read.
+    pub data: Option<Sector>,
+    /// The error the drive reports for the sector.
+    pub error: Option<DosError>,
+    /// The disk ID in the sector header, in BAM order, if the header was
+    /// found.
+    pub header_id: Option<[u8; 2]>,
+    /// The bit offset of the data block following the sector header, if
+    /// the header was found.
+    pub data_bit: Option<usize>,
+}
+
+impl SectorRead {
+    fn failed(error: DosError) -> Self {
+        SectorRead {
+            data: None,
+            error: Some(error),
+            header_id: None,
+            data_bit: None,
+        }
+    }
+}
+
+/// Reads a sector from the GCR `data` of `track` like the drive does.
+///
+/// The header is looked up behind every sync mark, and the data block is
+/// read behind the next sync mark after it. `id` is the disk ID the drive
+/// expects, in BAM order; without one any ID is accepted. The DOS error
+/// codes result as follows:
+///
+/// - 21: the track holds no sync mark.
+/// - 20: no header carries the track and sector numbers.
+/// - 27: the header checksum is wrong.
+/// - 29: the header carries a different disk ID.
+/// - 22: the data block mark is missing.
+/// - 24: the data block contains invalid GCR codes.
+/// - 23: the data checksum is wrong.
+pub fn read_sector(data: &[u8], track: u8, sector: u8, id: Option<[u8; 2]>) -> SectorRead {
+    let syncs = find_syncs(data);
+    if syncs.is_empty() {
+        event!("track {track} sector {sector}: NoSync");
+        return SectorRead::failed(DosError::NoSync);
+    }
+    let gcr = GCR::new();
+    let found = syncs.iter().enumerate().find_map(|(i, &(_, end))| {
+        let header = gcr.decode(&read_bits(data, end, HEADER_LENGTH))?;
+        (header[0] == HEADER_MARK && header[2] == sector && header[3] == track)
+            .then_some((i, header))
+    });
+    let Some((index, header)) = found else {
+        event!(
+            "track {track} sector {sector}: HeaderNotFound after {} syncs",
+            syncs.len()
+        );
+        return SectorRead::failed(DosError::HeaderNotFound);
+    };
+    let header_id = [header[5], header[4]];
+    let header_error = if header[1] != header[2] ^ header[3] ^ header[4] ^ header[5] {
+        Some(DosError::HeaderChecksum)
+    } else if id.is_some_and(|id| id != header_id) {
+        Some(DosError::DiskIdMismatch)
+    } else {
+        None
+    };
+
+    let (_, start) = syncs[(index + 1) % syncs.len()];
+    if let Some(error) = header_error {
+        event!("track {track} sector {sector}: {error:?}, header ID {header_id:02X?}");
+        return SectorRead {
+            header_id: Some(header_id),
+            data_bit: Some(start),
+            ..SectorRead::failed(error)
+        };
+    }
+    let raw = read_bits(data, start, DATA_LENGTH);
+    let mut block = Vec::with_capacity(SECTOR_SIZE + 4);
+    let mut valid = true;
+    for chunk in raw.chunks(5) {
+        match gcr.decode(chunk) {
+            Some(bytes) => block.extend_from_slice(&bytes),
+            None => {
+                valid = false;
+                block.extend_from_slice(&[0; 4]);
+            }
+        }
+    }
+    let mut contents = [0u8; SECTOR_SIZE];
+    contents.copy_from_slice(&block[1..=SECTOR_SIZE]);
+    let error = if block[0] != DATA_MARK {
+        Some(DosError::DataBlockNotPresent)
+    } else if !valid {
+        Some(DosError::ByteDecoding)
+    } else if contents.iter().fold(0, |acc, b| acc ^ b) != block[SECTOR_SIZE + 1] {
+        Some(DosError::DataChecksum)
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        event!("track {track} sector {sector}: {error:?}");
+    }
+    SectorRead {
+        data: (error != Some(DosError::DataBlockNotPresent)).then_some(contents),
+        error,
+        header_id: Some(header_id),
+        data_bit: Some(start),
+    }
+}
+
+/// Overwrites the data block starting at bit `bit` of a track, as found by
+/// [`read_sector`], with `sector`.
+pub fn write_data(data: &mut [u8], bit: usize, sector: &Sector) {
+    let encoded = encode_data(sector);
+    let bits = data.len() * 8;
+    for (i, byte) in encoded.iter().enumerate() {
+        for b in 0..8 {
+            let position = (bit + i * 8 + b) % bits;
+            let mask = 0x80 >> (position % 8);
+            if byte & (0x80 >> b) != 0 {
+                data[position / 8] |= mask;
+            } else {
+                data[position / 8] &= !mask;
+            }
+        }
+    }
+}
+
+/// Where [`align`] starts a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// The start of the data as it is, which for a dump with index
+    /// information is the index hole.
+    Index,
+    /// The longest sync mark. Among syncs of the same
+    /// length the one after the longest stretch without a sync wins, which
+    /// on a formatted track is the sync of the first header after the tail
+    /// gap.
+    LongestSync,
+    /// The sync mark before the header of sector 0.
+    SectorZero,
+}
+
+/// Returns the bit offset of `origin` in the circular track `data`, or
+/// `None` if the track has no such place.
+///
+/// An origin at a sync mark lies [`SYNC_LENGTH`] bytes before its end,
+/// where the format routine starts writing it, so a track laid out by
+/// [`encode_track`] starts at its origin.
+pub fn find_origin(data: &[u8], origin: Origin) -> Option<usize> {
+    let bits = data.len() * 8;
+    let syncs = find_syncs(data);
+    let start = |&(detected, _): &(usize, usize)| (detected + bits - (SYNC_BITS - 1)) % bits;
+    let origin_at = |&(_, end): &(usize, usize)| (end + bits - SYNC_LENGTH * 8 % bits) % bits;
+    match origin {
+        Origin::Index => Some(0),
+        Origin::LongestSync => {
+            let longest = (0..syncs.len()).max_by_key(|&i| {
+                let length = (syncs[i].1 + bits - start(&syncs[i])) % bits;
+                let previous = syncs[(i + syncs.len() - 1) % syncs.len()].1;
+                let gap = (start(&syncs[i]) + bits - previous) % bits;
+                // The earliest sync wins a complete tie.
+                (length, gap, usize::MAX - i)
+            })?;
+            Some(origin_at(&syncs[longest]))
+        }
+        Origin::SectorZero => {
+            let gcr = GCR::new();
+            syncs
+                .iter()
+                .find(|&&(_, end)| {
+                    gcr.decode(&read_bits(data, end, HEADER_LENGTH))
+                        .is_some_and(|header| header[0] == HEADER_MARK && header[2] == 0)
+                })
+                .map(origin_at)
+        }
+    }
+}
+
+/// Returns `data` rotated to start at `origin`, or `None` if the track has
+/// no such place.
+pub fn align(data: &[u8], origin: Origin) -> Option<Vec<u8>> {
+    let bit = find_origin(data, origin)?;
+    Some(read_bits(data, bit, data.len()))
+}
+
+/// Returns the blocks of the circular track `data`: the bytes behind each
+/// sync mark, read from the bit the mark ends at, in the order of the marks.
+///
+/// Header and data blocks are cut to their length and other blocks lose
+/// the gap bytes at their end, so what is left is what the drive reads:
+/// two dumps of a disk have the same blocks, whatever their gaps, the
+/// lengths of their syncs and the bit their blocks start at.
+pub fn blocks(data: &[u8]) -> Vec<Vec<u8>> {
+    let bits = data.len() * 8;
+    let syncs = find_syncs(data);
+    let gcr = GCR::new();
+    (0..syncs.len())
+        .map(|i| {
+            let end = syncs[i].1;
+            let next = (syncs[(i + 1) % syncs.len()].0 + bits - (SYNC_BITS - 1)) % bits;
+            let mut block = read_bits(data, end, (next + bits - end) % bits / 8);
+            let mark = block
+                .get(..5)
+                .and_then(|bytes| gcr.decode(bytes))
+                .map(|b| b[0]);
+            match mark {
+                Some(HEADER_MARK) if block.len() >= HEADER_LENGTH => block.truncate(HEADER_LENGTH),
+                Some(DATA_MARK) if block.len() >= DATA_LENGTH => block.truncate(DATA_LENGTH),
+                _ => {
+                    let length = block
+                        .iter()
+                        .rposition(|&b| b != GAP_BYTE)
+                        .map_or(0, |i| i + 1);
+                    block.truncate(length);
+                }
+            }
+            block
+        })
+        .collect()
+}
+
+/// Where the header of a sector passes the head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Placement {
+    /// The sector number the header carries.
+    pub sector: u8,
+    /// The bit offset the header block starts at, behind its sync mark.
+    pub bit: usize,
+    /// The length of the track in bits.
+    pub bits: usize,
+}
+
+impl Placement {
+    /// Returns where the header starts, as a fraction of a revolution
+    /// from the start of the track.
+    pub fn angle(&self) -> f64 {
+        self.bit as f64 / self.bits.max(1) as f64
+    }
+}
+
+/// Returns the sectors of `track` on the circular track `data` in physical
+/// order, the order their headers pass the head from the start of the
+/// track.
+///
+/// A header counts if it has the header mark and carries `track`, whatever
+/// its checksum, as [`read_sector`] finds it; a sector whose header is
+/// written more than once is placed at the first. The 1541 formats a track
+/// in the order of the sector numbers, so this differs from
+/// [`logical_order`] on disks mastered with an [`Interleave`], or whose
+/// tracks start elsewhere than at sector 0.
+pub fn physical_order(data: &[u8], track: u8) -> impl Iterator<Item = Placement> {
+    let bits = data.len() * 8;
+    let gcr = GCR::new();
+    let mut placements: Vec<Placement> = Vec::new();
+    for (_, end) in find_syncs(data) {
+        let Some(header) = gcr.decode(&read_bits(data, end, HEADER_LENGTH)) else {
+            continue;
+        };
+        if header[0] == HEADER_MARK
+            && header[3] == track
+            && placements.iter().all(|p| p.sector != header[2])
+        {
+            placements.push(Placement {
+                sector: header[2],
+                bit: end,
+                bits,
+            });
+        }
+    }
+    placements.sort_by_key(|p| p.bit);
+    placements.into_iter()
+}
+
+/// Returns the sectors of `track` on the circular track `data` in logical
+/// order, the order of their numbers, where the headers
+/// [`physical_order`] finds are.
+pub fn logical_order(data: &[u8], track: u8) -> impl Iterator<Item = Placement> {
+    let mut placements: Vec<Placement> = physical_order(data, track).collect();
+    placements.sort_by_key(|p| p.sector);
+    placements.into_iter()
+}
+
+/// Reads `len` bytes from the circular track `data` starting at bit `bit`.
+pub(crate) fn read_bits(data: &[u8], bit: usize, len: usize) -> Vec<u8> {
+    let bits = data.len() * 8;
+    (0..len * 8)
+        .map(|i| {
+            let position = (bit + i) % bits;
+            (data[position / 8] >> (7 - position % 8)) & 1
+        })
+        .collect::<Vec<_>>()
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, b| acc << 1 | b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::sectors_per_track;
+
+    #[test]
+    fn blocks_have_expected_layout() {
+        let header = encode_header(18, 0, *b"01");
+        assert_eq!(header.len(), HEADER_LENGTH);
+        let decoded = GCR::new().decode(&header).unwrap();
+        assert_eq!(
+            decoded,
+            vec![0x08, 18 ^ b'0' ^ b'1', 0, 18, b'1', b'0', 0x0F, 0x0F]
+        );
+        assert_eq!(encode_data(&[0; SECTOR_SIZE]).len(), DATA_LENGTH);
+    }
+
+    #[test]
+    fn reads_back_errors_at_any_bit_offset() {
+        let sectors: Vec<Sector> = (0..21).map(|s| [s as u8; SECTOR_SIZE]).collect();
+        let errors = |s: u8| match s {
+            1 => Some(DosError::HeaderNotFound),
+            2 => Some(DosError::DataBlockNotPresent),
+            3 => Some(DosError::DataChecksum),
+            4 => Some(DosError::ByteDecoding),
+            5 => Some(DosError::HeaderChecksum),
+            6 => Some(DosError::DiskIdMismatch),
+            _ => None,
+        };
+        let encoded = encode_track(1, &sectors, *b"01", errors);
+        // Rotate the track by three bits so no block is byte aligned.
+        let bits = encoded.len() * 8;
+        let mut shifted = read_bits(&encoded, bits - 3, encoded.len());
+        for sector in 0..7 {
+            let read = read_sector(&shifted, 1, sector, Some(*b"01"));
+            assert_eq!(read.error, errors(sector), "sector {sector}");
+        }
+        let read = read_sector(&shifted, 1, 0, Some(*b"01"));
+        assert_eq!(read.data, Some([0; SECTOR_SIZE]));
+        assert_eq!(
+            read_sector(&shifted, 1, 3, None).data,
+            Some([3; SECTOR_SIZE])
+        );
+        assert_eq!(read_sector(&shifted, 1, 6, None).error, None);
+
+        write_data(&mut shifted, read.data_bit.unwrap(), &[0xEE; SECTOR_SIZE]);
+        assert_eq!(
+            read_sector(&shifted, 1, 0, None).data,
+            Some([0xEE; SECTOR_SIZE])
+        );
+        assert_eq!(
+            read_sector(&shifted, 1, 7, None).data,
+            Some([7; SECTOR_SIZE])
+        );
+
+        let unsynced = encode_track(1, &sectors, *b"01", |_| Some(DosError::NoSync));
+        assert_eq!(
+            read_sector(&unsynced, 1, 0, None).error,
+            Some(DosError::NoSync)
+        );
+    }
+
+    #[test]
+    fn tracks_fit_their_zone() {
+        for track in [1, 18, 25, 31] {
+            let sectors = vec![[0u8; SECTOR_SIZE]; sectors_per_track(track) as usize];
+            let encoded = encode_track(track, &sectors, *b"01", |_| None);
+            let zone = speed_zone(track);
+            assert_eq!(encoded.len(), track_capacity(zone, DEFAULT_RPM));
+        }
+    }
+
+    #[test]
+    fn aligns_dumps_to_the_same_origin() {
+        let sectors: Vec<Sector> = (0..21).map(|s| [s as u8; SECTOR_SIZE]).collect();
+        let encoded = encode_track(1, &sectors, *b"01", |_| None);
+        let bits = encoded.len() * 8;
+        let dump = read_bits(&encoded, 12_345, encoded.len());
+        assert_ne!(dump, encoded);
+
+        assert_eq!(align(&dump, Origin::Index), Some(dump.clone()));
+        assert_eq!(align(&dump, Origin::SectorZero), Some(encoded.clone()));
+        assert_eq!(find_origin(&encoded, Origin::SectorZero), Some(0));
+        assert_eq!(
+            align(&dump, Origin::LongestSync),
+            align(&encoded, Origin::LongestSync)
+        );
+        assert_eq!(find_origin(&encoded, Origin::LongestSync), Some(0));
+        assert_eq!(find_origin(&dump, Origin::LongestSync), Some(bits - 12_345));
+
+        let blank = vec![GAP_BYTE; 100];
+        assert_eq!(align(&blank, Origin::LongestSync), None);
+        assert_eq!(align(&blank, Origin::SectorZero), None);
+    }
+
+    #[test]
+    fn orders_sectors_physically_and_logically() {
+        let sectors: Vec<Sector> = (0..21).map(|s| [s as u8; SECTOR_SIZE]).collect();
+        let encoded = encode_track(1, &sectors, *b"01", |_| None);
+        let slot = 2 * SYNC_LENGTH + HEADER_LENGTH + HEADER_GAP + DATA_LENGTH + sector_gap(3);
+        // Mastered with an interleave of 2: 0, 11, 1, 12, ...
+        let mut mastered = Vec::new();
+        for i in 0..21 {
+            let sector = if i % 2 == 0 { i / 2 } else { 11 + i / 2 };
+            mastered.extend_from_slice(&encoded[sector * slot..(sector + 1) * slot]);
+        }
+        mastered.resize(encoded.len(), GAP_BYTE);
+        assert_eq!(
+            encode_interleaved(1, &sectors, *b"01", 2, |_| None),
+            mastered
+        );
+
+        let physical: Vec<u8> = physical_order(&mastered, 1).map(|p| p.sector).collect();
+        assert_eq!(&physical[..5], [0, 11, 1, 12, 2]);
+        assert_eq!(physical.len(), 21);
+        let logical: Vec<Placement> = logical_order(&mastered, 1).collect();
+        assert!(
+            logical
+                .iter()
+                .enumerate()
+                .all(|(i, p)| usize::from(p.sector) == i)
+        );
+        assert_eq!(logical[11].bit, (slot + SYNC_LENGTH) * 8);
+        assert!(logical[11].angle() < logical[1].angle());
+        assert_eq!(
+            read_sector(&mastered, 1, 12, None).data_bit,
+            Some(logical[12].bit + (HEADER_LENGTH + HEADER_GAP + SYNC_LENGTH) * 8)
+        );
+
+        // A dump starting elsewhere starts elsewhere in physical order.
+        let dump = read_bits(&encoded, slot * 8 * 5 + 100, encoded.len());
+        assert_eq!(physical_order(&dump, 1).next().unwrap().sector, 6);
+        assert_eq!(logical_order(&dump, 1).next().unwrap().sector, 0);
+        assert_eq!(physical_order(&dump, 2).count(), 0);
+    }
+}
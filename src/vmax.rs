@@ -0,0 +1,389 @@
+//! Reading the tracks of V-MAX! protected disks.
+//!
+//! V-MAX! keeps its loader's data on tracks DOS cannot read: instead of a
+//! header and a data block per sector, each sector is one block behind a
+//! short sync, opened by a run of fill bytes and an end mark and written
+//! through a 4-to-5 code of its own:
+//!
+//! ```plaintext
+//! sync     2 × $FF or more
+//! marks    3 × $49 or more, $EE
+//! block    sector, count, count data bytes, checksum, each byte coded
+//! gap      $55 up to the next sync
+//! ```
+//!
+//! A count of 0 stands for 256 bytes, and the checksum is the XOR of the
+//! data bytes. The marks and the code differ between versions of the
+//! scheme, so a [`Layout`] carries them: [`Layout::V2`] is the layout of
+//! version 2, which codes bytes like DOS. A disk of another version is
+//! read with a layout made for it.
+//!
+//! [`decode_track`] finds the blocks of a track and [`decode`] those of
+//! every track of a G64, with what [`Track::data`] extracts; the loader
+//! reads the same. [`encode_track`] masters a track, to repair one or to
+//! test with.
+
+use crate::error::DosError;
+use crate::g64::G64;
+use crate::timing::{SYNC_BITS, find_syncs};
+use crate::track::{GAP_BYTE, read_bits};
+use alloc::vec::Vec;
+
+/// The five bit codes DOS writes the nibbles 0 to 15 with, see
+/// [`crate::GCR`].
+pub const DOS_CODES: [u8; 16] = [
+    0b01010, 0b01011, 0b10010, 0b10011, 0b01110, 0b01111, 0b10110, 0b10111, 0b01001, 0b11001,
+    0b11010, 0b11011, 0b01101, 0b11101, 0b11110, 0b10101,
+];
+
+/// The marks and the code of a V-MAX! version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The fill byte that opens a block.
+    pub fill: u8,
+    /// The fewest fill bytes a block opens with.
+    pub min_fill: usize,
+    /// The byte that ends the fill.
+    pub end: u8,
+    /// The five bit codes of the nibbles 0 to 15.
+    pub codes: [u8; 16],
+}
+
+impl Layout {
+    /// The layout of version 2.
+    pub const V2: Layout = Layout {
+        fill: 0x49,
+        min_fill: 3,
+        end: 0xEE,
+        codes: DOS_CODES,
+    };
+}
+
+/// A block found on a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The sector number the block carries.
+    pub sector: u8,
+    /// The data, as far as it could be read.
+    pub data: Vec<u8>,
+    /// Why the data cannot be trusted: [`DosError::DataBlockNotPresent`]
+    /// if the next sync cuts the block short, [`DosError::ByteDecoding`]
+    /// for invalid codes and [`DosError::DataChecksum`] for a wrong
+    /// checksum.
+    pub error: Option<DosError>,
+    /// The bit offset of the sector number.
+    pub bit: usize,
+}
+
+impl Block {
+    /// Returns `true` if the block was read without error.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The blocks of a half track of a G64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// The index of the half track, 0 for track 1.
+    pub half_track: usize,
+    /// The blocks in the order of the syncs.
+    pub blocks: Vec<Block>,
+}
+
+impl Track {
+    /// Returns the track number, rounded down for half tracks.
+    pub fn track(&self) -> u8 {
+        (self.half_track / 2 + 1) as u8
+    }
+
+    /// Returns the data of the track, the valid blocks in the order of
+    /// their sector numbers, each sector once.
+    pub fn data(&self) -> Vec<u8> {
+        let mut valid: Vec<&Block> = self.blocks.iter().filter(|b| b.is_valid()).collect();
+        valid.sort_by_key(|b| b.sector);
+        valid.dedup_by_key(|b| b.sector);
+        valid.iter().flat_map(|b| b.data.iter().copied()).collect()
+    }
+
+    /// Returns `true` if the track holds the sectors from 0 up without a
+    /// gap and every block was read without error.
+    pub fn is_complete(&self) -> bool {
+        let mut sectors: Vec<u8> = self.blocks.iter().map(|b| b.sector).collect();
+        sectors.sort_unstable();
+        sectors.dedup();
+        !sectors.is_empty()
+            && sectors
+                .iter()
+                .enumerate()
+                .all(|(i, &s)| usize::from(s) == i)
+            && self.blocks.iter().all(Block::is_valid)
+    }
+}
+
+/// Returns the blocks of the circular track `data` written in `layout`.
+///
+/// Syncs not followed by the marks of the layout, such as those of the
+/// standard sectors of a loader track, are skipped.
+pub fn decode_track(data: &[u8], layout: &Layout) -> Vec<Block> {
+    let bits = data.len() * 8;
+    let mut blocks = Vec::new();
+    for (end, available) in behind_syncs(data) {
+        let marks = read_bits(data, end, available / 8);
+        let fill = marks.iter().take_while(|&&b| b == layout.fill).count();
+        if fill < layout.min_fill || marks.get(fill) != Some(&layout.end) {
+            continue;
+        }
+        let start = (end + (fill + 1) * 8) % bits;
+        let mut reader = Reader::new(data, start, available - (fill + 1) * 8, &layout.codes);
+        let Some([sector, count]) = reader.bytes() else {
+            continue;
+        };
+        let count = match count {
+            0 => 256,
+            n => usize::from(n),
+        };
+        let mut block = Block {
+            sector,
+            data: Vec::with_capacity(count),
+            error: None,
+            bit: start,
+        };
+        while block.data.len() < count {
+            match reader.byte() {
+                Some(byte) => block.data.push(byte),
+                None => break,
+            }
+        }
+        let checksum = if block.data.len() == count {
+            reader.byte()
+        } else {
+            None
+        };
+        block.error = match checksum {
+            None => Some(DosError::DataBlockNotPresent),
+            Some(_) if !reader.valid => Some(DosError::ByteDecoding),
+            Some(sum) if sum != block.data.iter().fold(0, |acc, b| acc ^ b) => {
+                Some(DosError::DataChecksum)
+            }
+            Some(_) => None,
+        };
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Returns the tracks of `g64` holding blocks written in `layout`.
+pub fn decode(g64: &G64, layout: &Layout) -> Vec<Track> {
+    (0..g64.half_tracks())
+        .filter_map(|half_track| {
+            let data = g64.half_track(half_track).filter(|d| !d.is_empty())?;
+            let blocks = decode_track(data, layout);
+            (!blocks.is_empty()).then_some(Track { half_track, blocks })
+        })
+        .collect()
+}
+
+/// Masters a track of `length` bytes holding `sectors` in `layout`, each
+/// behind a sync of `sync` bytes.
+///
+/// A sector holds 1 to 256 bytes, longer ones are cut, and the track is cut to
+/// `length` if they do not fit.
+pub fn encode_track(sectors: &[&[u8]], layout: &Layout, sync: usize, length: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(length);
+    for (number, data) in sectors.iter().enumerate() {
+        let data = &data[..data.len().min(256)];
+        out.extend(core::iter::repeat_n(0xFF, sync));
+        out.extend(core::iter::repeat_n(layout.fill, layout.min_fill));
+        out.push(layout.end);
+        let mut writer = Writer::new(&layout.codes);
+        writer.byte(number as u8);
+        writer.byte(data.len() as u8);
+        for &byte in data {
+            writer.byte(byte);
+        }
+        writer.byte(data.iter().fold(0, |acc, b| acc ^ b));
+        out.extend(writer.finish());
+        out.extend([GAP_BYTE; 4]);
+    }
+    out.resize(length, GAP_BYTE);
+    out
+}
+
+/// Returns where the syncs of the circular track `data` end, each with the
+/// number of bits up to the next sync.
+pub(crate) fn behind_syncs(data: &[u8]) -> Vec<(usize, usize)> {
+    let bits = data.len() * 8;
+    let syncs = find_syncs(data);
+    (0..syncs.len())
+        .map(|i| {
+            let end = syncs[i].1;
+            let next = (syncs[(i + 1) % syncs.len()].0 + bits - (SYNC_BITS - 1)) % bits;
+            match (next + bits - end) % bits {
+                0 => (end, bits),
+                n => (end, n),
+            }
+        })
+        .collect()
+}
+
+/// Reads bytes from a circular track, each written as two five bit codes.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    bit: usize,
+    /// The bits left before the next sync.
+    left: usize,
+    codes: &'a [u8; 16],
+    /// Cleared by the first invalid code.
+    pub(crate) valid: bool,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads from bit `bit` on, at most `left` bits, with the five bit
+    /// codes of the nibbles 0 to 15.
+    pub(crate) fn new(data: &'a [u8], bit: usize, left: usize, codes: &'a [u8; 16]) -> Self {
+        Reader {
+            data,
+            bit,
+            left,
+            codes,
+            valid: true,
+        }
+    }
+
+    fn code(&mut self) -> Option<u8> {
+        if self.left < 5 {
+            return None;
+        }
+        let bits = self.data.len() * 8;
+        let code = (0..5).fold(0, |acc, _| {
+            let bit = self.data[self.bit / 8] >> (7 - self.bit % 8) & 1;
+            self.bit = (self.bit + 1) % bits;
+            acc << 1 | bit
+        });
+        self.left -= 5;
+        match self.codes.iter().position(|&c| c == code) {
+            Some(nibble) => Some(nibble as u8),
+            None => {
+                self.valid = false;
+                Some(0)
+            }
+        }
+    }
+
+    /// Returns the next byte, unless the bits run out.
+    pub(crate) fn byte(&mut self) -> Option<u8> {
+        Some(self.code()? << 4 | self.code()?)
+    }
+
+    /// Returns the next `N` bytes, unless the bits run out.
+    pub(crate) fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let mut out = [0; N];
+        for byte in &mut out {
+            *byte = self.byte()?;
+        }
+        Some(out)
+    }
+}
+
+/// Collects bytes as two five bit codes each, MSB first.
+pub(crate) struct Writer<'a> {
+    codes: &'a [u8; 16],
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(codes: &'a [u8; 16]) -> Self {
+        Writer {
+            codes,
+            out: Vec::new(),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    pub(crate) fn byte(&mut self, byte: u8) {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            self.bits = self.bits << 5 | u32::from(self.codes[usize::from(nibble)]);
+            self.count += 5;
+            while self.count >= 8 {
+                self.count -= 8;
+                self.out.push((self.bits >> self.count) as u8);
+            }
+        }
+    }
+
+    /// Returns the bytes, the last one filled up with gap bits.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let pad = 8 - self.count;
+            self.out
+                .push((self.bits << pad) as u8 | GAP_BYTE & ((1 << pad) - 1));
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::timing::{DEFAULT_RPM, speed_zone, track_capacity};
+
+    fn sectors() -> Vec<Vec<u8>> {
+        (0..20u8)
+            .map(|s| (0..=s).map(|i| i.wrapping_mul(37) ^ s).collect())
+            .chain([vec![0xA5; 256]])
+            .collect()
+    }
+
+    #[test]
+    fn reads_what_it_masters() {
+        let sectors = sectors();
+        let slices: Vec<&[u8]> = sectors.iter().map(Vec::as_slice).collect();
+        let length = track_capacity(speed_zone(5), DEFAULT_RPM);
+        let mut g64 = G64::from_image(&D64::new(35));
+        g64.set_track(5, encode_track(&slices, &Layout::V2, 2, length));
+
+        let tracks = decode(&g64, &Layout::V2);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track(), 5);
+        assert!(tracks[0].is_complete());
+        assert_eq!(tracks[0].blocks[20].data.len(), 256);
+        assert_eq!(tracks[0].data(), sectors.concat());
+
+        let mut other = Layout::V2;
+        other.end = 0xEF;
+        assert!(decode(&g64, &other).is_empty());
+    }
+
+    #[test]
+    fn reports_damaged_blocks() {
+        let sectors = sectors();
+        let slices: Vec<&[u8]> = sectors.iter().map(Vec::as_slice).collect();
+        let mut data = encode_track(&slices[..3], &Layout::V2, 2, 7692);
+        // Turns the first data byte of sector 1 from 1 into 0, and the
+        // second one of sector 2 into invalid codes.
+        let second = decode_track(&data, &Layout::V2)[1].bit / 8;
+        data[second + 3] ^= 0x04;
+        let third = decode_track(&data, &Layout::V2)[2].bit / 8;
+        data[third + 4] = 0x00;
+
+        let blocks = decode_track(&data, &Layout::V2);
+        assert!(blocks[0].is_valid());
+        assert_eq!(blocks[1].error, Some(DosError::DataChecksum));
+        assert_eq!(blocks[2].error, Some(DosError::ByteDecoding));
+        let track = Track {
+            half_track: 0,
+            blocks,
+        };
+        assert!(!track.is_complete());
+        assert_eq!(track.data(), sectors[0]);
+
+        data.truncate(decode_track(&data, &Layout::V2)[0].bit / 8 + 4);
+        let cut = decode_track(&data, &Layout::V2);
+        assert_eq!(cut[0].error, Some(DosError::DataBlockNotPresent));
+    }
+}
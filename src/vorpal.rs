@@ -0,0 +1,241 @@
+//! Reading the tracks of Vorpal mastered disks.
+//!
+//! The Vorpal fastloader of Epyx titles reads a track in a few long
+//! blocks instead of a header and a data block per sector: behind each of
+//! the few syncs of a track, a mark opens a block of several sectors
+//! written back to back, without syncs or gaps between them:
+//!
+//! ```plaintext
+//! block    sync, $5A, count
+//! sector   sector, 256 data bytes, checksum    (count times)
+//! gap      $55 up to the next sync
+//! ```
+//!
+//! Behind the mark every byte is written as two five bit codes, and the
+//! checksum is the XOR of the data bytes. A sector that cannot be decoded
+//! ends its block, as the loader loses its place there. The mark and the
+//! code differ between titles, so a [`Layout`] carries them;
+//! [`Layout::default`] is the layout of the loader, see
+//! [`crate::protection`] for how far it is known to hold.
+//!
+//! [`decode_track`] reads the sectors of a track and [`decode`] those of
+//! every track of a G64, with what [`Track::data`] extracts.
+//! [`encode_track`] masters a track.
+
+use crate::error::DosError;
+use crate::g64::G64;
+use crate::image::{SECTOR_SIZE, Sector};
+use crate::track::{GAP_BYTE, read_bits};
+use crate::vmax::{DOS_CODES, Reader, Writer, behind_syncs};
+use alloc::vec::Vec;
+
+/// The mark and the code of a Vorpal title.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The mark that opens a block.
+    pub mark: u8,
+    /// The five bit codes of the nibbles 0 to 15.
+    pub codes: [u8; 16],
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            mark: 0x5A,
+            codes: DOS_CODES,
+        }
+    }
+}
+
+/// The result of reading a sector of a Vorpal block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorRead {
+    /// The sector number.
+    pub sector: u8,
+    /// The data, as far as it could be read.
+    pub data: Sector,
+    /// [`DosError::ByteDecoding`] or [`DosError::DataChecksum`].
+    pub error: Option<DosError>,
+    /// The bit offset of the sector number.
+    pub bit: usize,
+}
+
+/// The sectors of a half track of a G64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// The index of the half track, 0 for track 1.
+    pub half_track: usize,
+    /// The sectors in the order they follow the syncs.
+    pub sectors: Vec<SectorRead>,
+}
+
+impl Track {
+    /// Returns the track number, rounded down for half tracks.
+    pub fn track(&self) -> u8 {
+        (self.half_track / 2 + 1) as u8
+    }
+
+    /// Returns the data of the track, the valid sectors in the order of
+    /// their numbers, each one once.
+    pub fn data(&self) -> Vec<u8> {
+        let mut valid: Vec<&SectorRead> =
+            self.sectors.iter().filter(|s| s.error.is_none()).collect();
+        valid.sort_by_key(|s| s.sector);
+        valid.dedup_by_key(|s| s.sector);
+        valid.iter().flat_map(|s| s.data).collect()
+    }
+
+    /// Returns `true` if the track holds the sectors from 0 up without a
+    /// gap and every one was read without error.
+    pub fn is_complete(&self) -> bool {
+        let mut numbers: Vec<u8> = self.sectors.iter().map(|s| s.sector).collect();
+        numbers.sort_unstable();
+        numbers.dedup();
+        !numbers.is_empty()
+            && numbers
+                .iter()
+                .enumerate()
+                .all(|(i, &s)| usize::from(s) == i)
+            && self.sectors.iter().all(|s| s.error.is_none())
+    }
+}
+
+/// Returns the sectors of the circular track `data` written in `layout`.
+///
+/// Syncs not followed by the mark, such as those of DOS sectors, are
+/// skipped. A block cut short by the next sync yields the sectors read in
+/// full.
+pub fn decode_track(data: &[u8], layout: &Layout) -> Vec<SectorRead> {
+    let bits = data.len() * 8;
+    let mut sectors = Vec::new();
+    for (end, available) in behind_syncs(data) {
+        if available < 8 || read_bits(data, end, 1)[0] != layout.mark {
+            continue;
+        }
+        let mut reader = Reader::new(data, (end + 8) % bits, available - 8, &layout.codes);
+        let Some(count) = reader.byte() else {
+            continue;
+        };
+        for i in 0..usize::from(count) {
+            let bit = (end + 18 + i * (SECTOR_SIZE + 2) * 10) % bits;
+            let Some([sector]) = reader.bytes() else {
+                break;
+            };
+            let (Some(contents), Some(sum)) = (reader.bytes::<SECTOR_SIZE>(), reader.byte()) else {
+                break;
+            };
+            let error = if !reader.valid {
+                Some(DosError::ByteDecoding)
+            } else if sum != contents.iter().fold(0, |acc, b| acc ^ b) {
+                Some(DosError::DataChecksum)
+            } else {
+                None
+            };
+            sectors.push(SectorRead {
+                sector,
+                data: contents,
+                error,
+                bit,
+            });
+            if error == Some(DosError::ByteDecoding) {
+                break;
+            }
+        }
+    }
+    sectors
+}
+
+/// Returns the tracks of `g64` holding sectors written in `layout`.
+pub fn decode(g64: &G64, layout: &Layout) -> Vec<Track> {
+    (0..g64.half_tracks())
+        .filter_map(|half_track| {
+            let data = g64.half_track(half_track).filter(|d| !d.is_empty())?;
+            let sectors = decode_track(data, layout);
+            (!sectors.is_empty()).then_some(Track {
+                half_track,
+                sectors,
+            })
+        })
+        .collect()
+}
+
+/// Masters a track of `length` bytes holding `sectors` in `layout`, in
+/// blocks of up to `per_block` sectors.
+///
+/// The track is cut to `length` if the blocks do not fit.
+pub fn encode_track(
+    sectors: &[Sector],
+    layout: &Layout,
+    per_block: usize,
+    length: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(length);
+    for (block, chunk) in sectors.chunks(per_block.clamp(1, 255)).enumerate() {
+        let mut writer = Writer::new(&layout.codes);
+        writer.byte(chunk.len() as u8);
+        for (i, data) in chunk.iter().enumerate() {
+            writer.byte((block * per_block + i) as u8);
+            for &byte in data {
+                writer.byte(byte);
+            }
+            writer.byte(data.iter().fold(0, |acc, b| acc ^ b));
+        }
+        out.extend([0xFF; 5]);
+        out.push(layout.mark);
+        out.extend(writer.finish());
+        out.extend([GAP_BYTE; 8]);
+    }
+    out.resize(length, GAP_BYTE);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+    use crate::timing::{DEFAULT_RPM, speed_zone, track_capacity};
+
+    fn sectors(track: u8) -> Vec<Sector> {
+        (0..12u8)
+            .map(|s| core::array::from_fn(|i| (i as u8).wrapping_mul(s + 1) ^ track))
+            .collect()
+    }
+
+    #[test]
+    fn reads_what_it_masters() {
+        let layout = Layout::default();
+        let mut g64 = G64::from_image(&D64::new(35));
+        for track in [3, 30] {
+            let length = track_capacity(speed_zone(track), DEFAULT_RPM);
+            g64.set_track(track, encode_track(&sectors(track), &layout, 6, length));
+        }
+        let tracks = decode(&g64, &layout);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[1].track(), 30);
+        assert!(tracks.iter().all(Track::is_complete));
+        assert_eq!(tracks[0].data(), sectors(3).concat());
+        assert_eq!(tracks[1].sectors[7].sector, 7);
+    }
+
+    #[test]
+    fn stops_a_block_at_what_it_cannot_decode() {
+        let layout = Layout::default();
+        let mut data = encode_track(&sectors(5), &layout, 6, 7692);
+        let first = decode_track(&data, &layout);
+        // A wrong data byte in sector 1 and invalid codes in sector 3.
+        data[first[1].bit / 8 + 40] ^= 0x01;
+        data[first[3].bit / 8 + 40] = 0x00;
+
+        let read = decode_track(&data, &layout);
+        assert_eq!(read[1].error, Some(DosError::DataChecksum));
+        assert_eq!(read[3].error, Some(DosError::ByteDecoding));
+        let numbers: Vec<u8> = read.iter().map(|s| s.sector).collect();
+        assert_eq!(numbers, [0, 1, 2, 3, 6, 7, 8, 9, 10, 11]);
+        let track = Track {
+            half_track: 8,
+            sectors: read,
+        };
+        assert!(!track.is_complete());
+        assert_eq!(track.data().len(), 8 * SECTOR_SIZE);
+    }
+}
@@ -0,0 +1,185 @@
+//! A BASIC-prompt style facade over a drive.
+//!
+//! [`Wedge`] offers the handful of operations a user types at the prompt,
+//! `LOAD`, `SAVE`, `LOAD "$"` followed by `LIST`, and the DOS wedge's `@`
+//! commands, as single calls. It is meant for scripts and tests that want a
+//! disk to behave like a disk without juggling channels.
+
+use crate::drive::{COMMAND_CHANNEL, Drive, VirtualDrive};
+use crate::error::{DosError, DosStatus};
+use crate::image::DiskImage;
+
+/// Secondary address `SAVE` writes through.
+const SAVE_CHANNEL: u8 = 1;
+
+/// High-level access to a drive.
+///
+/// Names and commands are sent as their bytes; ASCII upper case and digits
+/// are the same in PETSCII.
+///
+/// # Example
+/// ```
+/// use cbm_dos::d64::D64;
+/// use cbm_dos::wedge::Wedge;
+///
+/// let mut wedge = Wedge::mount(D64::new(35));
+/// wedge.command("N:SCRIPTS,01").unwrap();
+/// wedge.save("HELLO", 0x0801, &[0x0B, 0x08]).unwrap();
+/// assert_eq!(wedge.load("HELLO").unwrap(), (0x0801, vec![0x0B, 0x08]));
+/// assert!(wedge.dir().unwrap().contains("\"HELLO\""));
+/// assert_eq!(wedge.status().to_string(), "00, OK,00,00");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Wedge<D> {
+    drive: D,
+}
+
+impl<I: DiskImage> Wedge<Drive<I>> {
+    /// Creates a wedge on a 1541 with `image` inserted.
+    pub fn mount(image: I) -> Self {
+        Wedge::new(Drive::new(image))
+    }
+}
+
+impl<D: VirtualDrive> Wedge<D> {
+    /// Creates a wedge talking to `drive`.
+    pub fn new(drive: D) -> Self {
+        Wedge { drive }
+    }
+
+    /// Returns the drive.
+    pub fn drive(&self) -> &D {
+        &self.drive
+    }
+
+    /// Returns the drive for direct channel access.
+    pub fn drive_mut(&mut self) -> &mut D {
+        &mut self.drive
+    }
+
+    /// Consumes the wedge and returns the drive.
+    pub fn into_inner(self) -> D {
+        self.drive
+    }
+
+    /// Loads a program, returning its load address and the bytes following
+    /// it. A file shorter than two bytes loads to the address its bytes
+    /// give, with missing bytes taken as zero, and no data.
+    ///
+    /// # Errors
+    /// Returns the error the drive reports, e.g. [`DosError::FileNotFound`].
+    pub fn load(&mut self, name: &str) -> Result<(u16, Vec<u8>), DosError> {
+        let mut data = self.drive.load(name.as_bytes())?;
+        let rest = data.split_off(data.len().min(2));
+        data.resize(2, 0);
+        Ok((u16::from_le_bytes([data[0], data[1]]), rest))
+    }
+
+    /// Saves `data` as a program loading to `address`.
+    ///
+    /// # Errors
+    /// Returns the error the drive reports, e.g. [`DosError::FileExists`]
+    /// unless the name starts with `@`.
+    pub fn save(&mut self, name: &str, address: u16, data: &[u8]) -> Result<(), DosError> {
+        self.drive.open(SAVE_CHANNEL, name.as_bytes())?;
+        let result = address
+            .to_le_bytes()
+            .iter()
+            .chain(data)
+            .try_for_each(|&b| self.drive.write_byte(SAVE_CHANNEL, b));
+        let closed = self.drive.close(SAVE_CHANNEL);
+        result.and(closed)
+    }
+
+    /// Returns the directory as `LIST` shows it after `LOAD "$"`, one line
+    /// per entry with the block count first.
+    ///
+    /// # Errors
+    /// Returns the error the drive reports when reading the directory.
+    pub fn dir(&mut self) -> Result<String, DosError> {
+        let listing = self.drive.load(b"$")?;
+        let mut text = String::new();
+        let mut lines = listing.get(2..).unwrap_or_default();
+        while let [link_lo, link_hi, lo, hi, rest @ ..] = lines {
+            if [*link_lo, *link_hi] == [0, 0] {
+                break;
+            }
+            let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            text.push_str(&u16::from_le_bytes([*lo, *hi]).to_string());
+            text.push(' ');
+            text.extend(rest[..end].iter().filter_map(|&b| match b {
+                0x12 => None,
+                0x20..=0x7E => Some(b as char),
+                _ => Some('?'),
+            }));
+            text.push('\n');
+            lines = rest.get(end + 1..).unwrap_or_default();
+        }
+        Ok(text)
+    }
+
+    /// Reads the error channel, which resets the status to `00, OK`.
+    pub fn status(&mut self) -> DosStatus {
+        let status = self.drive.status();
+        while let Some((_, eoi)) = self.drive.read_byte(COMMAND_CHANNEL) {
+            if eoi {
+                break;
+            }
+        }
+        status
+    }
+
+    /// Sends a DOS command like the wedge's `@` and returns the resulting
+    /// status, which includes informational codes such as `01, FILES
+    /// SCRATCHED`.
+    ///
+    /// # Errors
+    /// Returns the error the command failed with.
+    pub fn command(&mut self, command: &str) -> Result<DosStatus, DosError> {
+        self.drive.open(COMMAND_CHANNEL, command.as_bytes())?;
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d64::D64;
+
+    fn wedge() -> Wedge<Drive<D64>> {
+        let mut wedge = Wedge::mount(D64::new(35));
+        wedge.command("N:WEDGE,01").unwrap();
+        wedge
+    }
+
+    #[test]
+    fn saves_loads_and_lists() {
+        let mut wedge = wedge();
+        wedge.save("PROG", 0xC000, &[0xA9, 0x00, 0x60]).unwrap();
+        assert_eq!(wedge.load("PR*").unwrap(), (0xC000, vec![0xA9, 0x00, 0x60]));
+        assert_eq!(wedge.save("PROG", 0xC000, &[]), Err(DosError::FileExists));
+        assert_eq!(wedge.status().code, 63);
+        wedge.save("@:PROG", 0x0801, &[]).unwrap();
+        assert_eq!(wedge.load("PROG").unwrap(), (0x0801, vec![]));
+
+        let dir = wedge.dir().unwrap();
+        let lines: Vec<&str> = dir.lines().collect();
+        assert_eq!(lines[0], "0 \"WEDGE           \" 01 2A");
+        assert!(lines[1].starts_with("1    \"PROG\""));
+        assert!(lines[1].contains("PRG"));
+        assert_eq!(lines[2].trim_end(), "663 BLOCKS FREE.");
+    }
+
+    #[test]
+    fn commands_report_status() {
+        let mut wedge = wedge();
+        wedge.save("A", 0x0801, b"1").unwrap();
+        wedge.save("B", 0x0801, b"2").unwrap();
+        let status = wedge.command("S:*").unwrap();
+        assert_eq!(status.to_string(), "01,FILES SCRATCHED,02,00");
+        assert_eq!(wedge.status().code, 0);
+        assert_eq!(wedge.command("X"), Err(DosError::InvalidCommand));
+        assert_eq!(wedge.status().code, 31);
+        assert_eq!(wedge.load("A"), Err(DosError::FileNotFound));
+    }
+}
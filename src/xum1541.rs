@@ -0,0 +1,540 @@
+//! The xum1541 adapter protocol, as spoken by the ZoomFloppy.
+//!
+//! The xum1541 firmware turns USB transfers into IEC bus traffic and, with
+//! a parallel cable between adapter and drive, into the byte transfers
+//! nibtools uses for raw tracks. [`Xum1541`] implements the host side of
+//! its protocol in plain Rust, so neither OpenCBM nor any other C tooling
+//! is needed: the USB transfers themselves go through a [`Transport`],
+//! which for a real adapter is a few lines wrapping the device handle of
+//! a USB library, opened for [`VENDOR_ID`] and [`PRODUCT_ID`] with
+//! interface 0 claimed.
+//!
+//! Every request but the control requests is a four byte command on the
+//! bulk out endpoint:
+//!
+//! ```plaintext
+//! command  argument  length (u16, little endian)
+//! ```
+//!
+//! Reads then take the data from the bulk in endpoint. Writes send the
+//! data after the command and take a three byte status, which stays busy
+//! while the adapter is still clocking bytes onto the bus:
+//!
+//! ```plaintext
+//! status (1 busy, 2 ready, 3 error)  length written (u16, little endian)
+//! ```
+//!
+//! Raw tracks are moved with the request frames of [`crate::parallel`], so
+//! the drive has to run code answering them.
+
+use std::io;
+
+use crate::parallel::{READ_TRACK, WRITE_TRACK};
+
+/// The USB vendor id of xum1541 adapters.
+pub const VENDOR_ID: u16 = 0x16D0;
+/// The USB product id of xum1541 adapters.
+pub const PRODUCT_ID: u16 = 0x0504;
+/// The oldest firmware version speaking this protocol.
+pub const MIN_VERSION: u8 = 7;
+
+/// Vendor control request starting a session.
+const INIT: u8 = 1;
+/// Vendor control request resetting the IEC bus.
+const RESET: u8 = 2;
+/// Vendor control request ending a session.
+const SHUTDOWN: u8 = 3;
+
+const READ: u8 = 8;
+const WRITE: u8 = 9;
+const IEC_WAIT: u8 = 10;
+const IEC_POLL: u8 = 11;
+const IEC_SET_RELEASE: u8 = 12;
+
+/// Bytes with the standard serial protocol.
+const CBM: u8 = 0x10;
+/// Blocks over the parallel cable, with the nibtools handshake.
+const NIB: u8 = 0x60;
+/// Single bytes over the parallel cable.
+const NIB_COMMAND: u8 = 0x70;
+
+/// Write flag: become talker after sending under ATN.
+const WRITE_TALK: u8 = 0x01;
+/// Write flag: send the bytes under ATN.
+const WRITE_ATN: u8 = 0x02;
+
+const IO_BUSY: u8 = 1;
+const IO_READY: u8 = 2;
+const IO_ERROR: u8 = 3;
+
+/// The serial bus lines, as masks for [`Xum1541::poll`] and friends.
+pub mod line {
+    /// The DATA line.
+    pub const DATA: u8 = 0x01;
+    /// The CLK line.
+    pub const CLK: u8 = 0x02;
+    /// The ATN line.
+    pub const ATN: u8 = 0x04;
+    /// The RESET line.
+    pub const RESET: u8 = 0x08;
+    /// The SRQ line.
+    pub const SRQ: u8 = 0x10;
+}
+
+/// What the adapter's firmware supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Capabilities {
+    /// The firmware version.
+    pub version: u8,
+    /// The serial bus.
+    pub cbm: bool,
+    /// Parallel transfers to a drive with an XP1541 style cable.
+    pub nib: bool,
+    /// Fast transfers on SRQ to a 1571 or 1581.
+    pub srq: bool,
+    /// The IEEE-488 bus of PET drives.
+    pub ieee488: bool,
+    /// The datasette port of the ZoomTape.
+    pub tape: bool,
+}
+
+impl Capabilities {
+    fn parse(reply: &[u8]) -> Self {
+        let bits = reply.get(1).copied().unwrap_or(0);
+        Capabilities {
+            version: reply.first().copied().unwrap_or(0),
+            cbm: bits & 0x01 != 0,
+            nib: bits & 0x02 != 0,
+            srq: bits & 0x04 != 0,
+            ieee488: bits & 0x08 != 0,
+            tape: bits & 0x10 != 0,
+        }
+    }
+}
+
+/// The USB transfers of an opened adapter.
+pub trait Transport {
+    /// Sends vendor control request `request` and reads its reply into
+    /// `buffer`, returning the number of bytes read.
+    ///
+    /// # Errors
+    /// Returns the error of the USB transfer.
+    fn control(&mut self, request: u8, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes `data` to the bulk out endpoint, returning the number of
+    /// bytes written.
+    ///
+    /// # Errors
+    /// Returns the error of the USB transfer.
+    fn bulk_write(&mut self, data: &[u8]) -> io::Result<usize>;
+
+    /// Reads from the bulk in endpoint into `buffer`, returning the number
+    /// of bytes read.
+    ///
+    /// # Errors
+    /// Returns the error of the USB transfer.
+    fn bulk_read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+}
+
+/// An xum1541 adapter in session.
+///
+/// The IEC methods mirror those of OpenCBM: [`listen`](Xum1541::listen) or
+/// [`talk`](Xum1541::talk) a device, move bytes with
+/// [`write`](Xum1541::write) or [`read`](Xum1541::read), and
+/// [`unlisten`](Xum1541::unlisten) or [`untalk`](Xum1541::untalk) again.
+#[derive(Debug)]
+pub struct Xum1541<T: Transport> {
+    transport: T,
+    capabilities: Capabilities,
+}
+
+impl<T: Transport> Xum1541<T> {
+    /// Starts a session on `transport`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or the firmware is older than
+    /// [`MIN_VERSION`] or lacks the serial bus.
+    pub fn new(mut transport: T) -> io::Result<Self> {
+        let mut reply = [0; 8];
+        let count = transport.control(INIT, &mut reply)?;
+        let capabilities = Capabilities::parse(&reply[..count]);
+        if capabilities.version < MIN_VERSION || !capabilities.cbm {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported xum1541 firmware {}", capabilities.version),
+            ));
+        }
+        Ok(Xum1541 {
+            transport,
+            capabilities,
+        })
+    }
+
+    /// Returns what the firmware supports.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Ends the session and returns the transport.
+    ///
+    /// # Errors
+    /// Returns the error of the USB transfer.
+    pub fn shutdown(mut self) -> io::Result<T> {
+        self.transport.control(SHUTDOWN, &mut [])?;
+        Ok(self.transport)
+    }
+
+    /// Resets the devices on the bus.
+    ///
+    /// # Errors
+    /// Returns the error of the USB transfer.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.transport.control(RESET, &mut []).map(drop)
+    }
+
+    /// Sends `LISTEN device` and `secondary` as a data channel.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or no device answers.
+    pub fn listen(&mut self, device: u8, secondary: u8) -> io::Result<()> {
+        self.attention(&[0x20 | device, 0x60 | secondary], WRITE_ATN)
+    }
+
+    /// Sends `TALK device` and `secondary` as a data channel, then turns
+    /// the bus around.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or no device answers.
+    pub fn talk(&mut self, device: u8, secondary: u8) -> io::Result<()> {
+        self.attention(&[0x40 | device, 0x60 | secondary], WRITE_ATN | WRITE_TALK)
+    }
+
+    /// Sends `UNLISTEN`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn unlisten(&mut self) -> io::Result<()> {
+        self.attention(&[0x3F], WRITE_ATN)
+    }
+
+    /// Sends `UNTALK`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn untalk(&mut self) -> io::Result<()> {
+        self.attention(&[0x5F], WRITE_ATN)
+    }
+
+    /// Opens `secondary` on `device` with `name`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or no device answers.
+    pub fn open(&mut self, device: u8, secondary: u8, name: &[u8]) -> io::Result<()> {
+        self.attention(&[0x20 | device, 0xF0 | secondary], WRITE_ATN)?;
+        if !name.is_empty() {
+            self.write(name)?;
+        }
+        self.unlisten()
+    }
+
+    /// Closes `secondary` on `device`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or no device answers.
+    pub fn close(&mut self, device: u8, secondary: u8) -> io::Result<()> {
+        self.attention(&[0x20 | device, 0xE0 | secondary], WRITE_ATN)?;
+        self.unlisten()
+    }
+
+    /// Sends `data` to the listening devices, the last byte with EOI.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or the listener stops taking bytes.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let written = self.send(WRITE, CBM, data)?;
+        complete(written, data.len())
+    }
+
+    /// Reads up to `buffer.len()` bytes from the talker, returning how many
+    /// came before EOI.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.receive(CBM, buffer)
+    }
+
+    /// Returns the [`line`](mod@line)s currently pulled on the bus.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn poll(&mut self) -> io::Result<u8> {
+        self.command(IEC_POLL, 0, 0)?;
+        let mut lines = [0];
+        self.transport.bulk_read(&mut lines)?;
+        Ok(lines[0])
+    }
+
+    /// Pulls the [`line`](mod@line)s in `set` and releases those in `release`.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn set_release(&mut self, set: u8, release: u8) -> io::Result<()> {
+        self.command(IEC_SET_RELEASE, set, u16::from(release))
+    }
+
+    /// Waits until `line` is pulled, or released if `pulled` is false.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails.
+    pub fn wait(&mut self, line: u8, pulled: bool) -> io::Result<()> {
+        self.command(IEC_WAIT, line, u16::from(pulled))?;
+        self.status().map(drop)
+    }
+
+    /// Sends `byte` to the drive code over the parallel cable.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or the firmware lacks parallel
+    /// transfers.
+    pub fn parallel_write(&mut self, byte: u8) -> io::Result<()> {
+        self.require_nib()?;
+        let written = self.send(WRITE, NIB_COMMAND, &[byte])?;
+        complete(written, 1)
+    }
+
+    /// Reads a byte from the drive code over the parallel cable.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or the firmware lacks parallel
+    /// transfers.
+    pub fn parallel_read(&mut self) -> io::Result<u8> {
+        self.require_nib()?;
+        let mut byte = [0];
+        let count = self.receive(NIB_COMMAND, &mut byte)?;
+        complete(count, 1)?;
+        Ok(byte[0])
+    }
+
+    /// Reads the GCR bytes of `half_track`, counted as in
+    /// [`crate::parallel`], from the drive code.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails or the firmware lacks parallel
+    /// transfers.
+    pub fn read_track(&mut self, half_track: u8) -> io::Result<Vec<u8>> {
+        self.parallel_write(READ_TRACK)?;
+        self.parallel_write(half_track)?;
+        let length = u16::from_le_bytes([self.parallel_read()?, self.parallel_read()?]);
+        let mut data = vec![0; usize::from(length)];
+        if !data.is_empty() {
+            let count = self.receive(NIB, &mut data)?;
+            complete(count, data.len())?;
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to `half_track` through the drive code and returns
+    /// the drive's status byte, 0 or a DOS error code.
+    ///
+    /// # Errors
+    /// Fails if the transfer fails, the firmware lacks parallel transfers
+    /// or `data` is longer than 65535 bytes.
+    pub fn write_track(&mut self, half_track: u8, data: &[u8]) -> io::Result<u8> {
+        let length = u16::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "track too long"))?;
+        for byte in [WRITE_TRACK, half_track]
+            .into_iter()
+            .chain(length.to_le_bytes())
+        {
+            self.parallel_write(byte)?;
+        }
+        if !data.is_empty() {
+            let written = self.send(WRITE, NIB, data)?;
+            complete(written, data.len())?;
+        }
+        self.parallel_read()
+    }
+
+    fn require_nib(&self) -> io::Result<()> {
+        if self.capabilities.nib {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "firmware lacks parallel transfers",
+            ))
+        }
+    }
+
+    /// Sends bytes under ATN.
+    fn attention(&mut self, bytes: &[u8], flags: u8) -> io::Result<()> {
+        let written = self.send(WRITE, CBM | flags, bytes)?;
+        complete(written, bytes.len())
+    }
+
+    fn command(&mut self, command: u8, argument: u8, length: u16) -> io::Result<()> {
+        let [low, high] = length.to_le_bytes();
+        let written = self.transport.bulk_write(&[command, argument, low, high])?;
+        complete(written, 4)
+    }
+
+    /// Sends a write command with `data` and returns how much the adapter
+    /// got onto the bus.
+    fn send(&mut self, command: u8, argument: u8, data: &[u8]) -> io::Result<usize> {
+        let length = u16::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "transfer too long"))?;
+        self.command(command, argument, length)?;
+        let written = self.transport.bulk_write(data)?;
+        complete(written, data.len())?;
+        self.status()
+    }
+
+    fn receive(&mut self, argument: u8, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = u16::try_from(buffer.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "transfer too long"))?;
+        self.command(READ, argument, length)?;
+        // The adapter ends the transfer with a short packet at EOI.
+        self.transport.bulk_read(buffer)
+    }
+
+    /// Waits for the adapter to finish a write, returning the length it
+    /// reports.
+    fn status(&mut self) -> io::Result<usize> {
+        loop {
+            let mut status = [0; 3];
+            let count = self.transport.bulk_read(&mut status)?;
+            complete(count, 3)?;
+            match status[0] {
+                IO_BUSY => continue,
+                IO_READY => return Ok(usize::from(u16::from_le_bytes([status[1], status[2]]))),
+                IO_ERROR => return Err(io::Error::other("the adapter reported a bus error")),
+                status => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown adapter status {status}"),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Fails on a short transfer, which the adapter gives when a device
+/// stops answering.
+fn complete(count: usize, expected: usize) -> io::Result<()> {
+    if count == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("transferred {count} of {expected} bytes"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A transport recording what is written and replaying canned reads.
+    #[derive(Default)]
+    struct Script {
+        written: Vec<Vec<u8>>,
+        reads: VecDeque<Vec<u8>>,
+    }
+
+    impl Transport for Script {
+        fn control(&mut self, request: u8, buffer: &mut [u8]) -> io::Result<usize> {
+            assert_eq!(request, INIT);
+            buffer[..2].copy_from_slice(&[8, 0x03]);
+            Ok(2)
+        }
+
+        fn bulk_write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.written.push(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn bulk_read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let reply = self.reads.pop_front().expect("unexpected read");
+            buffer[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+    }
+
+    fn ready(length: u16) -> Vec<u8> {
+        let [low, high] = length.to_le_bytes();
+        vec![IO_READY, low, high]
+    }
+
+    #[test]
+    fn frames_serial_bus_requests() {
+        let mut script = Script::default();
+        script
+            .reads
+            .extend([ready(2), vec![IO_BUSY, 0, 0], ready(1)]);
+        script.reads.extend([ready(1), ready(2), b"00,".to_vec()]);
+        let mut adapter = Xum1541::new(script).unwrap();
+        assert!(adapter.capabilities().nib && !adapter.capabilities().srq);
+
+        adapter.open(8, 2, b"#").unwrap();
+        adapter.talk(8, 2).unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!(adapter.read(&mut buffer).unwrap(), 3);
+        let script = adapter.transport;
+        assert_eq!(
+            script.written,
+            [
+                vec![WRITE, CBM | WRITE_ATN, 2, 0],
+                vec![0x28, 0xF2],
+                vec![WRITE, CBM, 1, 0],
+                b"#".to_vec(),
+                vec![WRITE, CBM | WRITE_ATN, 1, 0],
+                vec![0x3F],
+                vec![WRITE, CBM | WRITE_ATN | WRITE_TALK, 2, 0],
+                vec![0x48, 0x62],
+                vec![READ, CBM, 8, 0],
+            ]
+        );
+
+        let mut script = Script::default();
+        script.reads.push_back(vec![IO_ERROR, 0, 0]);
+        let mut adapter = Xum1541::new(script).unwrap();
+        assert_eq!(
+            adapter.listen(8, 15).unwrap_err().kind(),
+            io::ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn moves_tracks_over_the_parallel_cable() {
+        let track: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut script = Script::default();
+        script
+            .reads
+            .extend([ready(1), ready(1), vec![0x2C], vec![0x01]]);
+        script.reads.push_back(track.clone());
+        let mut adapter = Xum1541::new(script).unwrap();
+        assert_eq!(adapter.read_track(36).unwrap(), track);
+
+        let mut script = std::mem::take(&mut adapter.transport);
+        let written: Vec<_> = script.written.drain(..).collect();
+        assert_eq!(written[0], [WRITE, NIB_COMMAND, 1, 0]);
+        assert_eq!(written[1], [READ_TRACK]);
+        assert_eq!(written[3], [36]);
+        assert_eq!(written[4], [READ, NIB_COMMAND, 1, 0]);
+        assert_eq!(written[5], written[4]);
+        assert_eq!(written[6], [READ, NIB, 0x2C, 0x01]);
+
+        script.reads.extend(std::iter::repeat_n(ready(1), 4));
+        script.reads.extend([ready(3), vec![0]]);
+        adapter.transport = script;
+        assert_eq!(adapter.write_track(2, &[0x55; 3]).unwrap(), 0);
+        let written = &adapter.transport.written;
+        assert_eq!(written[8], [WRITE, NIB, 3, 0]);
+        assert_eq!(written[9], [0x55; 3]);
+    }
+}
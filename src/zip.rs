@@ -0,0 +1,461 @@
+//! Reading ZIP archives.
+//!
+//! Most image collections are shipped and kept zipped, one image or one
+//! set of disks per archive. [`entries`] lists the members of an archive
+//! from its central directory, and [`Entry::data`] unpacks one, stored or
+//! deflated, checking its CRC-32. Encrypted members, ZIP64 archives and
+//! the rarer compression methods are not supported.
+//!
+//! Names are taken as UTF-8 whether or not the archive flags them so,
+//! which is what they are in practice; bytes that are no UTF-8 become
+//! U+FFFD.
+
+use crate::hash::crc32;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+const LOCAL_SIGNATURE: u32 = 0x0403_4B50;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4B50;
+const END_SIGNATURE: u32 = 0x0605_4B50;
+const END_SIZE: usize = 22;
+const LOCAL_SIZE: usize = 30;
+const CENTRAL_SIZE: usize = 46;
+
+/// The compression method of members that are stored as they are.
+pub const STORED: u16 = 0;
+/// The compression method of deflated members.
+pub const DEFLATED: u16 = 8;
+
+/// An error reading an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZipError {
+    /// No end of central directory record was found.
+    NotAnArchive,
+    /// A structure extends beyond the end of the input.
+    Truncated,
+    /// A header does not start with its signature.
+    InvalidSignature,
+    /// The archive uses a feature that is not supported.
+    Unsupported(&'static str),
+    /// The deflated data is not valid.
+    Corrupt,
+    /// The unpacked data does not match the stored CRC-32 or size.
+    Checksum,
+}
+
+impl fmt::Display for ZipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZipError::NotAnArchive => write!(f, "not a zip archive"),
+            ZipError::Truncated => write!(f, "zip archive is truncated"),
+            ZipError::InvalidSignature => write!(f, "invalid zip header signature"),
+            ZipError::Unsupported(feature) => write!(f, "unsupported zip feature: {feature}"),
+            ZipError::Corrupt => write!(f, "corrupt deflate data"),
+            ZipError::Checksum => write!(f, "zip member fails its checksum"),
+        }
+    }
+}
+
+impl core::error::Error for ZipError {}
+
+/// A member of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// The path within the archive, with `/` between directories.
+    pub name: String,
+    /// The compression method, [`STORED`] or [`DEFLATED`] for members this
+    /// module can unpack.
+    pub method: u16,
+    /// The CRC-32 of the unpacked data.
+    pub crc32: u32,
+    /// The size of the unpacked data.
+    pub size: usize,
+    /// The packed data.
+    pub packed: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Whether the member is a directory rather than a file.
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Unpacks the member.
+    ///
+    /// # Errors
+    /// Returns [`ZipError::Unsupported`] for methods other than stored and
+    /// deflated, [`ZipError::Corrupt`] for invalid deflated data and
+    /// [`ZipError::Checksum`] if the data does not match the directory.
+    pub fn data(&self) -> Result<Vec<u8>, ZipError> {
+        let data = match self.method {
+            STORED => self.packed.to_vec(),
+            DEFLATED => inflate(self.packed)?,
+            _ => return Err(ZipError::Unsupported("compression method")),
+        };
+        if data.len() != self.size || crc32(&data) != self.crc32 {
+            return Err(ZipError::Checksum);
+        }
+        Ok(data)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ZipError> {
+    let field = bytes.get(offset..offset + 2).ok_or(ZipError::Truncated)?;
+    Ok(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ZipError> {
+    let field = bytes.get(offset..offset + 4).ok_or(ZipError::Truncated)?;
+    Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Lists the members of the archive `bytes`, in the order of its central
+/// directory.
+///
+/// # Errors
+/// Returns [`ZipError::NotAnArchive`] if `bytes` has no end of central
+/// directory record, [`ZipError::Unsupported`] for ZIP64 archives and
+/// encrypted members, and [`ZipError::Truncated`] or
+/// [`ZipError::InvalidSignature`] for damaged archives.
+pub fn entries(bytes: &[u8]) -> Result<Vec<Entry<'_>>, ZipError> {
+    let lowest = bytes.len().saturating_sub(END_SIZE + usize::from(u16::MAX));
+    let end = (lowest..=bytes.len().saturating_sub(END_SIZE))
+        .rev()
+        .find(|&offset| u32_at(bytes, offset) == Ok(END_SIGNATURE))
+        .ok_or(ZipError::NotAnArchive)?;
+    let count = u16_at(bytes, end + 10)?;
+    let mut offset = u32_at(bytes, end + 16)? as usize;
+    if count == u16::MAX || offset == u32::MAX as usize {
+        return Err(ZipError::Unsupported("zip64"));
+    }
+    let mut entries = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        if u32_at(bytes, offset)? != CENTRAL_SIGNATURE {
+            return Err(ZipError::InvalidSignature);
+        }
+        if u16_at(bytes, offset + 8)? & 1 != 0 {
+            return Err(ZipError::Unsupported("encryption"));
+        }
+        let packed_size = u32_at(bytes, offset + 20)? as usize;
+        let name_length = usize::from(u16_at(bytes, offset + 28)?);
+        let extra_length = usize::from(u16_at(bytes, offset + 30)?);
+        let comment_length = usize::from(u16_at(bytes, offset + 32)?);
+        let name = bytes
+            .get(offset + CENTRAL_SIZE..offset + CENTRAL_SIZE + name_length)
+            .ok_or(ZipError::Truncated)?;
+        let local = u32_at(bytes, offset + 42)? as usize;
+        if u32_at(bytes, local)? != LOCAL_SIGNATURE {
+            return Err(ZipError::InvalidSignature);
+        }
+        let start = local
+            + LOCAL_SIZE
+            + usize::from(u16_at(bytes, local + 26)?)
+            + usize::from(u16_at(bytes, local + 28)?);
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(bytes, offset + 10)?,
+            crc32: u32_at(bytes, offset + 16)?,
+            size: u32_at(bytes, offset + 24)? as usize,
+            packed: bytes
+                .get(start..start + packed_size)
+                .ok_or(ZipError::Truncated)?,
+        });
+        offset += CENTRAL_SIZE + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order the lengths of the code length code are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, ZipError> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or(ZipError::Corrupt)?;
+            self.buffer |= u32::from(byte) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code: the number of codes of each length and the
+/// symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, ZipError> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(ZipError::Corrupt);
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = alloc::vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[usize::from(offsets[usize::from(length)])] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, ZipError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ZipError::Corrupt)
+    }
+}
+
+fn fixed() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    // The fixed codes are complete, so building them cannot fail.
+    let literals = Huffman::new(&lengths).unwrap_or_else(|_| unreachable!());
+    let distances = Huffman::new(&[5; 30]).unwrap_or_else(|_| unreachable!());
+    (literals, distances)
+}
+
+fn dynamic(bits: &mut Bits) -> Result<(Huffman, Huffman), ZipError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_count = bits.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[index] = bits.bits(3)? as u8;
+    }
+    let codes = Huffman::new(&code_lengths)?;
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match codes.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(ZipError::Corrupt)?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(core::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count || lengths[256] == 0 {
+        return Err(ZipError::Corrupt);
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Ok((literals, distances))
+}
+
+/// Unpacks raw deflated data, as ZIP members hold it.
+///
+/// # Errors
+/// Returns [`ZipError::Corrupt`] if the data is not valid or ends early.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, ZipError> {
+    let mut bits = Bits {
+        data,
+        position: 0,
+        buffer: 0,
+        count: 0,
+    };
+    let mut out = Vec::with_capacity(data.len() * 4);
+    loop {
+        let last = bits.bits(1)? == 1;
+        let (literals, distances) = match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let length = u16_at(data, bits.position).map_err(|_| ZipError::Corrupt)?;
+                let check = u16_at(data, bits.position + 2).map_err(|_| ZipError::Corrupt)?;
+                if check != !length {
+                    return Err(ZipError::Corrupt);
+                }
+                let length = usize::from(length);
+                let start = bits.position + 4;
+                let stored = data.get(start..start + length).ok_or(ZipError::Corrupt)?;
+                out.extend_from_slice(stored);
+                bits.position = start + length;
+                if last {
+                    return Ok(out);
+                }
+                continue;
+            }
+            1 => fixed(),
+            2 => dynamic(&mut bits)?,
+            _ => return Err(ZipError::Corrupt),
+        };
+        loop {
+            let symbol = usize::from(literals.decode(&mut bits)?);
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            }
+            if symbol == 256 {
+                break;
+            }
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(ZipError::Corrupt);
+            }
+            let length = usize::from(LENGTH_BASE[index])
+                + bits.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+            let index = usize::from(distances.decode(&mut bits)?);
+            if index >= DISTANCE_BASE.len() {
+                return Err(ZipError::Corrupt);
+            }
+            let distance = usize::from(DISTANCE_BASE[index])
+                + bits.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+            if distance > out.len() {
+                return Err(ZipError::Corrupt);
+            }
+            let start = out.len() - distance;
+            for i in 0..length {
+                out.push(out[start + i]);
+            }
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles an archive of `(name, method, packed, unpacked)` members.
+    fn archive(members: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let (mut out, mut central) = (Vec::new(), Vec::new());
+        for &(name, method, packed, unpacked) in members {
+            let mut header = Vec::new();
+            header.extend_from_slice(&[20, 0, 0, 0]);
+            header.extend_from_slice(&method.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&crc32(unpacked).to_le_bytes());
+            header.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(unpacked.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0; 2]);
+            central.extend_from_slice(&CENTRAL_SIGNATURE.to_le_bytes());
+            central.extend_from_slice(&[20, 0]);
+            central.extend_from_slice(&header);
+            central.extend_from_slice(&[0; 10]);
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&LOCAL_SIGNATURE.to_le_bytes());
+            out.extend_from_slice(&header);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(packed);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&END_SIGNATURE.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        let count = (members.len() as u16).to_le_bytes();
+        out.extend_from_slice(&[count[0], count[1], count[0], count[1]]);
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    #[test]
+    fn inflates_stored_fixed_and_dynamic_blocks() {
+        let fixed = [0xF3, 0x70, 0xF5, 0xF1, 0xF1, 0x57, 0xF0, 0x40, 0x90, 0x00];
+        assert_eq!(inflate(&fixed).unwrap(), b"HELLO HELLO HELLO");
+        let dynamic = [
+            0x15, 0xC8, 0x41, 0x11, 0x00, 0x40, 0x08, 0xC3, 0x40, 0x2B, 0xB5, 0x12, 0x5A, 0x38,
+            0xFF, 0x8E, 0x0E, 0x26, 0xAF, 0x0D, 0x8F, 0x42, 0x91, 0x41, 0xD8, 0xA4, 0xB8, 0x03,
+            0x83, 0xB7, 0x26, 0x9A, 0xF3, 0x07,
+        ];
+        assert_eq!(
+            inflate(&dynamic).unwrap(),
+            b"AGABA D CAA ACCADBAGABAAAFACACAEAD FABAA"
+        );
+        let stored = [0x01, 0x03, 0x00, 0xFC, 0xFF, 0x01, 0x08, 0x60];
+        assert_eq!(inflate(&stored).unwrap(), [0x01, 0x08, 0x60]);
+        assert_eq!(inflate(&fixed[..5]), Err(ZipError::Corrupt));
+        assert_eq!(
+            inflate(&[0x01, 0x03, 0x00, 0x00, 0x00]),
+            Err(ZipError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn lists_and_unpacks_members() {
+        let fixed = [0xF3, 0x70, 0xF5, 0xF1, 0xF1, 0x57, 0xF0, 0x40, 0x90, 0x00];
+        let bytes = archive(&[
+            ("GAMES/", STORED, b"", b""),
+            ("GAMES/HELLO.TXT", DEFLATED, &fixed, b"HELLO HELLO HELLO"),
+            ("RAW.BIN", STORED, &[1, 8, 0x60], &[1, 8, 0x60]),
+            ("BAD.BIN", STORED, &[1, 8, 0x61], &[1, 8, 0x60]),
+        ]);
+        let entries = entries(&bytes).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["GAMES/", "GAMES/HELLO.TXT", "RAW.BIN", "BAD.BIN"]);
+        assert!(entries[0].is_dir() && !entries[1].is_dir());
+        assert_eq!(entries[1].data().unwrap(), b"HELLO HELLO HELLO");
+        assert_eq!(entries[2].data().unwrap(), [1, 8, 0x60]);
+        assert_eq!(entries[3].data(), Err(ZipError::Checksum));
+        assert_eq!(
+            super::entries(b"PK no archive"),
+            Err(ZipError::NotAnArchive)
+        );
+        assert_eq!(
+            super::entries(&bytes[..bytes.len() - 30]),
+            Err(ZipError::NotAnArchive)
+        );
+    }
+}
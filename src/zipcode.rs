@@ -0,0 +1,364 @@
+//! ZipCode disk sets.
+//!
+//! ZipCode packs a disk into a few programs small enough for a BBS upload,
+//! named after the disk with the number of the part in front: `1!GAME`
+//! to `4!GAME` for the common four-file set, and `1!!GAME` to `6!!GAME`
+//! for the six-file variant that splits the whole disk, up to track 40,
+//! into six. Archives of ZipDisk sets hold several such disks side by
+//! side.
+//!
+//! Each part is a program whose body is a list of sector records:
+//!
+//! ```plaintext
+//! part 1   load address $03FE, disk ID, records
+//! part n   load address $0400, records
+//! record   track | mode << 6, sector, contents
+//!          mode 0: the 256 bytes of the sector
+//!          mode 1: one byte that fills the sector
+//!          mode 2: packed length, escape byte, packed bytes, in which
+//!                  escape, count, byte stands for count times byte
+//! ```
+//!
+//! As every record names its sector, the split of the tracks between the
+//! parts does not matter to [`unpack`]. [`detect`] finds the sets in a
+//! list of names and tells the variant of each.
+
+use crate::d64::{self, D64};
+use crate::image::{BlockDevice, ImageError, SECTOR_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The load address of the first part, which holds the disk ID.
+const FIRST_LOAD: [u8; 2] = [0xFE, 0x03];
+/// The load address of the other parts.
+const LOAD: [u8; 2] = [0x00, 0x04];
+
+/// The kinds of ZipCode sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Variant {
+    /// Four parts, `1!` to `4!`, for a 35-track disk.
+    Four,
+    /// Six parts, `1!!` to `6!!`, for a whole disk of up to 40 tracks.
+    Six,
+}
+
+impl Variant {
+    /// Returns the number of parts of a set.
+    pub fn parts(self) -> usize {
+        match self {
+            Variant::Four => 4,
+            Variant::Six => 6,
+        }
+    }
+
+    /// Returns the marker between the number of a part and the name.
+    pub fn marker(self) -> &'static str {
+        match self {
+            Variant::Four => "!",
+            Variant::Six => "!!",
+        }
+    }
+}
+
+/// The parts of one disk, as [`detect`] finds them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSet {
+    pub variant: Variant,
+    /// The name behind the prefixes.
+    pub name: String,
+    /// The indices of the parts into the names given to [`detect`], part
+    /// 1 first.
+    pub parts: Vec<usize>,
+}
+
+/// A disk reconstructed by [`unpack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unpacked {
+    pub image: D64,
+    /// The ID the disk was formatted with.
+    pub id: [u8; 2],
+    /// The sectors no part holds, left filled with zero bytes.
+    pub missing: Vec<(u8, u8)>,
+}
+
+/// Splits a name into the number of its part, its variant and the name
+/// behind the prefix.
+fn split(name: &str) -> Option<(usize, Variant, &str)> {
+    let digit = name.chars().next()?.to_digit(10)? as usize;
+    let rest = &name[1..];
+    let (variant, base) = match rest.strip_prefix("!!") {
+        Some(base) => (Variant::Six, base),
+        None => (Variant::Four, rest.strip_prefix('!')?),
+    };
+    (1..=variant.parts())
+        .contains(&digit)
+        .then_some((digit, variant, base))
+}
+
+/// Finds the complete ZipCode sets among `names`, in the order their first
+/// parts appear. Incomplete sets are left out.
+pub fn detect(names: &[&str]) -> Vec<FileSet> {
+    let mut sets: Vec<(FileSet, Vec<Option<usize>>)> = Vec::new();
+    for (index, name) in names.iter().enumerate() {
+        let Some((part, variant, base)) = split(name) else {
+            continue;
+        };
+        let position = match sets
+            .iter()
+            .position(|(set, _)| set.variant == variant && set.name == base)
+        {
+            Some(position) => position,
+            None => {
+                let set = FileSet {
+                    variant,
+                    name: String::from(base),
+                    parts: Vec::new(),
+                };
+                sets.push((set, alloc::vec![None; variant.parts()]));
+                sets.len() - 1
+            }
+        };
+        sets[position].1[part - 1].get_or_insert(index);
+    }
+    sets.into_iter()
+        .filter_map(|(mut set, parts)| {
+            set.parts = parts.into_iter().collect::<Option<_>>()?;
+            Some(set)
+        })
+        .collect()
+}
+
+/// Reads the records of a part from `at` on, adding them to `sectors`.
+fn records(
+    part: &[u8],
+    mut at: usize,
+    sectors: &mut Vec<(u8, u8, [u8; SECTOR_SIZE])>,
+) -> Result<(), ImageError> {
+    let byte = |at: usize| part.get(at).copied().ok_or(ImageError::Truncated);
+    while at < part.len() {
+        let (header, sector) = (byte(at)?, byte(at + 1)?);
+        let track = header & 0x3F;
+        if track == 0 || track > 40 || sector >= d64::sectors_per_track(track) {
+            return Err(ImageError::Unsupported("ZipCode sector"));
+        }
+        let mut data = [0; SECTOR_SIZE];
+        at += 2;
+        match header >> 6 {
+            0 => {
+                let raw = part
+                    .get(at..at + SECTOR_SIZE)
+                    .ok_or(ImageError::Truncated)?;
+                data.copy_from_slice(raw);
+                at += SECTOR_SIZE;
+            }
+            1 => {
+                data.fill(byte(at)?);
+                at += 1;
+            }
+            2 => {
+                let (length, escape) = (usize::from(byte(at)?), byte(at + 1)?);
+                let packed = part
+                    .get(at + 2..at + 2 + length)
+                    .ok_or(ImageError::Truncated)?;
+                let mut out = Vec::with_capacity(SECTOR_SIZE);
+                let mut bytes = packed.iter();
+                while let Some(&b) = bytes.next() {
+                    if b == escape {
+                        let (Some(&count), Some(&value)) = (bytes.next(), bytes.next()) else {
+                            return Err(ImageError::Truncated);
+                        };
+                        out.extend(core::iter::repeat_n(value, usize::from(count)));
+                    } else {
+                        out.push(b);
+                    }
+                }
+                if out.len() != SECTOR_SIZE {
+                    return Err(ImageError::Unsupported("ZipCode sector length"));
+                }
+                data.copy_from_slice(&out);
+                at += 2 + length;
+            }
+            _ => return Err(ImageError::Unsupported("ZipCode record mode")),
+        }
+        sectors.push((track, sector, data));
+    }
+    Ok(())
+}
+
+/// Reconstructs a disk from the contents of the parts of a set, part 1
+/// first.
+///
+/// The image has 40 tracks if a part holds a sector beyond track 35, and
+/// 35 otherwise. A sector held twice takes the contents of the last
+/// record.
+///
+/// # Errors
+/// - [`ImageError::Unsupported`] unless there are four or six parts, and
+///   for records ZipCode does not write.
+/// - [`ImageError::InvalidSignature`] if a part does not start with the
+///   load address of its place in the set.
+/// - [`ImageError::Truncated`] if a record extends beyond its part.
+pub fn unpack(parts: &[&[u8]]) -> Result<Unpacked, ImageError> {
+    if parts.len() != Variant::Four.parts() && parts.len() != Variant::Six.parts() {
+        return Err(ImageError::Unsupported("ZipCode part count"));
+    }
+    let mut id = [0; 2];
+    let mut sectors = Vec::new();
+    for (n, part) in parts.iter().enumerate() {
+        let start = if n == 0 {
+            if part.get(..2) != Some(&FIRST_LOAD[..]) {
+                return Err(ImageError::InvalidSignature);
+            }
+            id.copy_from_slice(part.get(2..4).ok_or(ImageError::Truncated)?);
+            4
+        } else {
+            if part.get(..2) != Some(&LOAD[..]) {
+                return Err(ImageError::InvalidSignature);
+            }
+            2
+        };
+        records(part, start, &mut sectors)?;
+    }
+    let tracks = if sectors.iter().any(|&(track, _, _)| track > 35) {
+        40
+    } else {
+        35
+    };
+    let mut image = D64::new(tracks);
+    let mut written = Vec::with_capacity(sectors.len());
+    for (track, sector, data) in &sectors {
+        // The sector was checked against the 40-track layout, so it fits.
+        image
+            .write_sector(*track, *sector, data)
+            .map_err(|_| ImageError::Unsupported("ZipCode sector"))?;
+        written.push((*track, *sector));
+    }
+    let missing = (1..=tracks)
+        .flat_map(|track| (0..d64::sectors_per_track(track)).map(move |sector| (track, sector)))
+        .filter(|ts| !written.contains(ts))
+        .collect();
+    event!(
+        "unpacked {} ZipCode sectors onto {tracks} tracks",
+        sectors.len()
+    );
+    Ok(Unpacked { image, id, missing })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Packs the tracks of `image` into parts of the given ranges, the first
+    /// sector of each track stored as it is, the second as a fill and the
+    /// others as runs.
+    fn pack(image: &D64, ranges: &[core::ops::RangeInclusive<u8>]) -> Vec<Vec<u8>> {
+        ranges
+            .iter()
+            .enumerate()
+            .map(|(n, range)| {
+                let mut part = if n == 0 {
+                    vec![0xFE, 0x03, b'Z', b'C']
+                } else {
+                    LOAD.to_vec()
+                };
+                for track in range.clone() {
+                    for sector in 0..d64::sectors_per_track(track) {
+                        let data = image.read_sector(track, sector).unwrap();
+                        match sector {
+                            0 => {
+                                part.extend([track, sector]);
+                                part.extend(data);
+                            }
+                            1 => part.extend([track | 0x40, sector, data[0]]),
+                            _ => {
+                                let packed = [data[0], 0xEE, 255, data[1]];
+                                part.extend([track | 0x80, sector, 4, 0xEE]);
+                                part.extend(packed);
+                            }
+                        }
+                    }
+                }
+                part
+            })
+            .collect()
+    }
+
+    fn disk(tracks: u8) -> D64 {
+        let mut image = D64::new(tracks);
+        for track in 1..=tracks {
+            for sector in 0..d64::sectors_per_track(track) {
+                let mut data = [track; SECTOR_SIZE];
+                match sector {
+                    0 => data = core::array::from_fn(|i| i as u8 ^ track),
+                    1 => {}
+                    _ => data[0] = sector,
+                }
+                image.write_sector(track, sector, &data).unwrap();
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn detects_the_sets_of_a_listing() {
+        let names = [
+            "3!GAME", "1!GAME", "README", "2!GAME", "4!GAME", "1!!DEMO", "2!!DEMO", "3!!DEMO",
+            "4!!DEMO", "5!!DEMO", "6!!DEMO", "1!HALF", "5!GAME",
+        ];
+        let sets = detect(&names);
+        assert_eq!(
+            sets,
+            [
+                FileSet {
+                    variant: Variant::Four,
+                    name: String::from("GAME"),
+                    parts: vec![1, 3, 0, 4]
+                },
+                FileSet {
+                    variant: Variant::Six,
+                    name: String::from("DEMO"),
+                    parts: vec![5, 6, 7, 8, 9, 10]
+                },
+            ]
+        );
+        assert_eq!(Variant::Six.marker(), "!!");
+    }
+
+    #[test]
+    fn unpacks_four_and_six_file_sets() {
+        let image = disk(35);
+        let parts = pack(&image, &[1..=8, 9..=16, 17..=25, 26..=35]);
+        let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+        let unpacked = unpack(&parts).unwrap();
+        assert_eq!(unpacked.image, image);
+        assert_eq!(unpacked.id, *b"ZC");
+        assert!(unpacked.missing.is_empty());
+
+        let image = disk(40);
+        let mut parts = pack(&image, &[1..=7, 8..=14, 15..=21, 22..=28, 29..=34, 35..=40]);
+        // Drop the last record, the run of track 40, sector 16.
+        let last = parts[5].len() - 8;
+        parts[5].truncate(last);
+        let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+        let unpacked = unpack(&parts).unwrap();
+        assert_eq!(unpacked.missing, [(40, 16)]);
+        assert_eq!(
+            unpacked.image.read_sector(40, 15),
+            image.read_sector(40, 15)
+        );
+
+        assert_eq!(
+            unpack(&parts[..5]),
+            Err(ImageError::Unsupported("ZipCode part count"))
+        );
+        let mut swapped = parts.clone();
+        swapped.swap(0, 1);
+        assert_eq!(unpack(&swapped), Err(ImageError::InvalidSignature));
+        let cut = &parts[1][..parts[1].len() - 1];
+        assert_eq!(
+            unpack(&[parts[0], cut, parts[2], parts[3], parts[4], parts[5]]),
+            Err(ImageError::Truncated)
+        );
+    }
+}